@@ -37,11 +37,7 @@ pub struct PeersConnectedHandler {
 
 impl Handler for PeersConnectedHandler {
 	fn get(&self, _req: Request<Body>) -> ResponseFuture {
-		let peers: Vec<PeerInfoDisplay> = w_fut!(&self.peers)
-			.connected_peers()
-			.iter()
-			.map(|p| p.info.clone().into())
-			.collect();
+		let peers: Vec<PeerInfoDisplay> = w_fut!(&self.peers).connected_peers_info();
 		json_response(&peers)
 	}
 }
@@ -65,7 +61,7 @@ impl Handler for PeerHandler {
 		if let Ok(ip_addr) = command.parse() {
 			peer_addr = PeerAddr::from_ip(ip_addr);
 		} else if let Ok(addr) = command.parse() {
-			peer_addr = PeerAddr(addr);
+			peer_addr = PeerAddr::Ip(addr);
 		} else {
 			return response(
 				StatusCode::BAD_REQUEST,
@@ -90,7 +86,7 @@ impl Handler for PeerHandler {
 				if let Ok(ip_addr) = a.parse() {
 					PeerAddr::from_ip(ip_addr)
 				} else if let Ok(addr) = a.parse() {
-					PeerAddr(addr)
+					PeerAddr::Ip(addr)
 				} else {
 					return response(
 						StatusCode::BAD_REQUEST,
@@ -101,7 +97,10 @@ impl Handler for PeerHandler {
 		};
 
 		match command {
-			"ban" => w_fut!(&self.peers).ban_peer(addr, ReasonForBan::ManualBan),
+			"ban" => {
+				let peers = w_fut!(&self.peers);
+				peers.ban_peer(addr, ReasonForBan::ManualBan, peers.ban_window())
+			}
 			"unban" => w_fut!(&self.peers).unban_peer(addr),
 			_ => return response(StatusCode::BAD_REQUEST, "invalid command"),
 		};
@@ -42,7 +42,7 @@ pub struct KernelDownloadHandler {
 
 impl Handler for KernelDownloadHandler {
 	fn post(&self, _req: Request<Body>) -> ResponseFuture {
-		if let Some(peer) = w_fut!(&self.peers).most_work_peer() {
+		if let Some(peer) = w_fut!(&self.peers).most_work_peer(p2p::Capabilities::UNKNOWN) {
 			match peer.send_kernel_data_request() {
 				Ok(_) => response(StatusCode::OK, "{}"),
 				Err(e) => response(
@@ -71,9 +71,12 @@ impl StatusHandler {
 		let head = w(&self.chain)?
 			.head()
 			.map_err(|e| ErrorKind::Internal(format!("can't get head: {}", e)))?;
+		let peers = w(&self.peers)?;
 		Ok(Status::from_tip_and_peers(
 			head,
-			w(&self.peers)?.peer_count(),
+			peers.peer_count(),
+			peers.peer_counts(),
+			peers.store_stats(),
 		))
 	}
 }
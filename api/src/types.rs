@@ -76,16 +76,27 @@ pub struct Status {
 	pub user_agent: String,
 	// The current number of connections
 	pub connections: u32,
+	// The connections by direction, peer capability and protocol version
+	pub peer_counts: p2p::PeerCounts,
+	// How many peers we have in store in each state
+	pub peer_store: p2p::StoreStats,
 	// The state of the current fork Tip
 	pub tip: Tip,
 }
 
 impl Status {
-	pub fn from_tip_and_peers(current_tip: chain::Tip, connections: u32) -> Status {
+	pub fn from_tip_and_peers(
+		current_tip: chain::Tip,
+		connections: u32,
+		peer_counts: p2p::PeerCounts,
+		peer_store: p2p::StoreStats,
+	) -> Status {
 		Status {
 			protocol_version: p2p::msg::ProtocolVersion::default().into(),
 			user_agent: p2p::msg::USER_AGENT.to_string(),
 			connections: connections,
+			peer_counts,
+			peer_store,
 			tip: Tip::from_tip(current_tip),
 		}
 	}
@@ -62,6 +62,9 @@ pub enum ErrorKind {
 	/// Block time is too old
 	#[fail(display = "Invalid Block Time")]
 	InvalidBlockTime,
+	/// Block time too far ahead of our clock
+	#[fail(display = "Future Block Time")]
+	FutureBlockTime,
 	/// Block height is invalid (not previous + 1)
 	#[fail(display = "Invalid Block Height")]
 	InvalidBlockHeight,
@@ -188,6 +191,15 @@ impl Error {
 			_ => true,
 		}
 	}
+
+	/// Whether the block may only be wrong by our own standards, like a
+	/// timestamp ahead of our clock, rather than against consensus
+	pub fn is_borderline(&self) -> bool {
+		match self.kind() {
+			ErrorKind::FutureBlockTime => true,
+			_ => false,
+		}
+	}
 }
 
 impl From<ErrorKind> for Error {
@@ -353,7 +353,7 @@ fn validate_header(header: &BlockHeader, ctx: &mut BlockContext<'_>) -> Result<(
 	{
 		// refuse blocks more than 12 blocks intervals in future (as in bitcoin)
 		// TODO add warning in p2p code if local time is too different from peers
-		return Err(ErrorKind::InvalidBlockTime.into());
+		return Err(ErrorKind::FutureBlockTime.into());
 	}
 
 	if !ctx.opts.contains(Options::SKIP_POW) {
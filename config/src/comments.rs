@@ -237,6 +237,17 @@ fn comments() -> HashMap<String, String> {
 		"port".to_string(),
 		"
 #The port on which to listen.
+
+#listen on all these addresses instead, ipv6 ones included
+#listen_addrs = [\"0.0.0.0:3414\", \"[::]:3414\"]
+
+#the address we tell our peers to reach us at, host and port above by
+#default, worth setting when listening on all interfaces
+#advertise_addr = \"1.2.3.4:3414\"
+
+#map our port on our NAT gateway (UPnP or NAT-PMP) so peers can reach us
+#from outside, advertising the address we get if none is set above
+#port_mapping = false
 "
 		.to_string(),
 	);
@@ -255,25 +266,162 @@ fn comments() -> HashMap<String, String> {
 #be specified as follows:
 #seeds = [\"192.168.0.1:3414\",\"192.168.0.2:3414\"]
 
+#If the seeding type is DNSSeed, the DNS names to resolve for peers when
+#we don't know enough of them yet, defaults to the seeds of the network
+#dns_seeds = [\"mainnet.seed.grin-tech.org\"]
+
 #hardcoded peer lists for allow/deny
 #will *only* connect to peers in allow list
 #peers_allow = [\"192.168.0.1:3414\", \"192.168.0.2:3414\"]
 #will *never* connect to peers in deny list
 #peers_deny = [\"192.168.0.3:3414\", \"192.168.0.4:3414\"]
-#a list of preferred peers to connect to
+#a list of preferred peers we always stay connected to, on top of our
+#outbound connections, even if banned
 #peers_preferred = [\"192.168.0.1:3414\",\"192.168.0.2:3414\"]
 
+#a file to merge peers from into our peer store at startup, one per line
+#(address, capabilities, state, last seen) as written to peers_export_file
+#peers_import_file = \"peers.txt\"
+
+#a file to write all the peers in our peer store to when stopping
+#peers_export_file = \"peers.txt\"
+
 #how long a banned peer should stay banned
 #ban_window = 10800
 
+#misbehavior score (decaying over time) past which a peer gets banned, a
+#malformed msg counts for 20, a block or tx failing our checks for 40
+#ban_score_threshold = 100
+
+#how long a discouraged peer stays discouraged: dialed and synced from only
+#without other peers to go to, its inbound connections taken only below half
+#our inbound maximum
+#discourage_window = 21600
+
+#misbehavior score past which a peer gets discouraged, short of a ban
+#discourage_score_threshold = 50
+
+#how many blocks failing validation a peer can send before it gets banned
+#invalid_block_strikes = 1
+
+#how many blocks refused for what may be our own fault, like a timestamp
+#ahead of our clock, a peer can send before it gets banned
+#borderline_block_strikes = 3
+
 #maximum number of peers
 #peer_max_count = 125
 
+#maximum number of peers connecting to us, the ones past that are refused
+#right away so they can't crowd out the connections we make
+#max_inbound = 117
+
+#what to do with a new peer connecting to us once we have max_inbound of
+#them: Netgroup evicts the youngest of the network group we have the most
+#peers from, Random evicts one at random, Reject refuses the newcomer. Our
+#fastest peers are never evicted, nor are our preferred peers
+#inbound_eviction = \"Netgroup\"
+
+#maximum number of peers we connect to
+#max_outbound = 16
+
 #preferred minimum number of peers (we'll actively keep trying to add peers
 #until we get to at least this number
 #peer_min_preferred_count = 8
 
+#how long (in seconds) a peer has to complete the handshake
+#handshake_timeout = 10
+
+#maximum number of handshakes in progress at the same time
+#max_inflight_handshakes = 32
+
+#maximum number of inbound connections from a single ip
+#max_inbound_per_ip = 3
+
+#maximum number of inbound connections from a single /24 (ipv4) or /48 (ipv6)
+#network
+#max_inbound_per_subnet = 8
+
+#ranges the inbound connections of aren't limited by ip or network (loopback
+#never is), for test setups
+#inbound_limit_exempt = [\"10.0.0.0/8\"]
+
+#how often (in seconds) we ping our peers to exchange difficulty and height
+#ping_interval = 10
+
+#how many peers get the blocks we relay pushed to them, the other peers
+#supporting it are only announced their hash and ask if they need them
+#block_fanout = 3
+
+#how many peers, picked at random for each, get the blocks and txs we relay
+#(outbound peers first), they relay them further to the others
+#relay_fanout = 8
+
+#how many bytes we queue at most for a peer to read, past that announcements
+#are dropped and a peer we can't even send requests to is disconnected
+#max_queued_bytes = 4194304
+
+#how many requests of each kind a peer may send us, the requests over the
+#limit are dropped and a peer that keeps at it is disconnected
+#header_requests_per_sec = 10
+#peer_addr_requests_per_min = 6
+#txhashset_requests_per_min = 2
+
+#how long (in seconds) we stay silent before pinging a peer, and how long we
+#wait for anything from it before closing the connection as dead
+#keepalive_interval = 60
+#idle_timeout = 300
+
+#how often (in seconds) we sweep our peers, dropping the ones that stopped
+#answering us
+#monitor_interval = 25
+
+#how long (in seconds) a peer has to send a block we asked for, a block
+#announced by several peers is only asked to the next one past that
+#block_request_timeout = 5
+
+#how many outbound connections we keep up, every few seconds we dial peers
+#from our store (or ask our peers for more) when we have fewer
+#outbound_target = 8
+
+#how many peers we dial at most at the same time
+#max_concurrent_dials = 4
+
+#how often (in seconds) we send each peer a few addresses of the peers we
+#recently connected to, unasked
+#gossip_interval = 600
+
+#how often (in seconds) we dial the preferred peers we're not connected to,
+#however often they failed us
+#preferred_redial_interval = 10
+
+#how many days a healthy peer in our store can go without us hearing from it
+#before it's marked defunct, only getting a chance again now and then
+#peer_demote_days = 3
+
+#how many days a defunct or never verified peer can go without us hearing
+#from it before it's deleted from our store
+#peer_expire_days = 14
+
+#how many peers we keep in our store at most, past that the worst ones go
+#first: banned ones whose ban is over, then the ones we only heard about,
+#then defunct ones, never our preferred peers or the ones we recently
+#connected to
+#peer_store_max = 20000
+
+#route all outbound connections through a SOCKS5 proxy (tor for instance),
+#required to reach onion addresses
+#[server.p2p_config.socks5_proxy]
+#proxy_addr = \"127.0.0.1:9050\"
+#auth = { username = \"user\", password = \"pass\" }
+
 # 15 = Bit flags for FULL_NODE
+# 31 = FULL_NODE and COMPACT_BLOCKS, relayed blocks are announced in compact
+# form to peers supporting it
+# 159 = FULL_NODE, COMPACT_BLOCKS and BLOCK_INV (the default), relayed blocks
+# are only pushed to a few peers, the others get their hash
+# 47 = FULL_NODE and ENCRYPTED, encrypts connections to peers supporting it
+# 79 = FULL_NODE and COMPRESSION, compresses large msgs (headers, peer addresses)
+# to peers supporting it, worth it on metered connections
 #This structure needs to be changed internally, to make it more configurable
 
 # A preferred dandelion_peer, mainly used for testing dandelion
@@ -72,12 +72,6 @@ pub const TESTING_MAX_BLOCK_WEIGHT: usize = 150;
 /// we're sure this peer is a stuck node, and we will kick out such kind of stuck peers.
 pub const STUCK_PEER_KICK_TIME: i64 = 2 * 3600 * 1000;
 
-/// If a peer's last seen time is 2 weeks ago we will forget such kind of defunct peers.
-const PEER_EXPIRATION_DAYS: i64 = 7 * 2;
-
-/// Constant that expresses defunct peer timeout in seconds to be used in checks.
-pub const PEER_EXPIRATION_REMOVE_TIME: i64 = PEER_EXPIRATION_DAYS * 24 * 3600;
-
 /// Trigger compaction check on average every day for all nodes.
 /// Randomized per node - roll the dice on every block to decide.
 /// Will compact the txhashset to remove pruned data.
@@ -23,20 +23,26 @@
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc};
 use std::{
-	cmp,
+	cmp, mem,
 	thread::{self, JoinHandle},
-	time,
+	time::{self, Instant},
 };
 
+use num::FromPrimitive;
+
+use crate::core::core::Block;
 use crate::core::ser;
-use crate::core::ser::FixedLength;
 use crate::msg::{
-	read_body, read_discard, read_header, read_item, write_to_buf, MsgHeader, MsgHeaderWrapper,
-	Type,
+	frame_body, is_droppable, is_streamed, peek_body, read_body_from, read_discard, read_header,
+	read_item, streamed_header, write_streamed, write_streamed_body, write_to_buf,
+	write_to_buf_compressed, BanReason, Checksum, ChecksumReader, MsgHeader, MsgHeaderWrapper,
+	PeerError, PeerErrorCode, PooledBuf, ProtocolVersion, Type, BODY_TIMEOUT, STREAM_CHUNK_SIZE,
 };
-use crate::types::Error;
+use crate::transport::SessionKeys;
+use crate::types::{Error, HandshakeFailure, ReasonForBan};
 use crate::util::read_write::{read_exact, write_all};
 use crate::util::{RateCounter, RwLock};
 
@@ -49,12 +55,27 @@ pub trait MessageHandler: Send + 'static {
 		writer: &'a mut dyn Write,
 		tracker: Arc<Tracker>,
 	) -> Result<Option<Response<'a>>, Error>;
+
+	/// What to send the peer when we haven't sent it anything in a while, to
+	/// keep the connection alive.
+	fn keepalive<'a>(&self, writer: &'a mut dyn Write) -> Result<Response<'a>, Error>;
+
+	/// What to send the peer every so often without it asking, if anything.
+	fn gossip<'a>(&self, writer: &'a mut dyn Write) -> Result<Option<Response<'a>>, Error>;
+
+	/// A response (or keepalive) of the provided type and length, header
+	/// included, was queued for the peer.
+	fn sent(&self, msg_type: Type, len: u64);
 }
 
 // Macro to simplify the boilerplate around async I/O error handling,
 // especially with WouldBlock kind of errors.
+// On errors breaking the loop, $on_break gets to look at the error first.
 macro_rules! try_break {
 	($inner:expr) => {
+		try_break!($inner, |_: &Error| ())
+	};
+	($inner:expr, $on_break:expr) => {
 		match $inner {
 			Ok(v) => Some(v),
 			Err(Error::Connection(ref e)) if e.kind() == io::ErrorKind::WouldBlock => None,
@@ -64,9 +85,10 @@ macro_rules! try_break {
 			| Err(Error::NoDandelionRelay) => None,
 			Err(ref e) => {
 				debug!("try_break: exit the loop: {:?}", e);
+				$on_break(e);
 				break;
-				}
 			}
+		}
 	};
 }
 
@@ -75,22 +97,72 @@ macro_rules! try_break {
 pub struct Message<'a> {
 	pub header: MsgHeader,
 	stream: &'a mut dyn Read,
+	// checksum of what was read with streaming_read so far
+	checksum: Option<Checksum>,
+	// the whole body must be in by then
+	deadline: Instant,
+	// start of the body already read with peek
+	peeked: Vec<u8>,
 }
 
 impl<'a> Message<'a> {
 	fn from_header(header: MsgHeader, stream: &'a mut dyn Read) -> Message<'a> {
-		Message { header, stream }
+		let checksum = header.checksum.map(|_| Checksum::new());
+		Message {
+			header,
+			stream,
+			checksum,
+			deadline: Instant::now() + BODY_TIMEOUT,
+			peeked: vec![],
+		}
 	}
 
 	/// Read the message body from the underlying connection
 	pub fn body<T: ser::Readable>(&mut self) -> Result<T, Error> {
-		read_body(&self.header, self.stream)
+		let peeked = mem::replace(&mut self.peeked, vec![]);
+		read_body_from(&self.header, peeked, self.stream, self.deadline)
+	}
+
+	/// Deserializes the start of the body only, to check it's worth reading
+	/// the rest. The body can still be read whole with `body` (or discarded)
+	/// afterwards, not with `streaming_read`.
+	pub fn peek<T: ser::Readable>(&mut self) -> Result<T, Error> {
+		debug_assert!(self.peeked.is_empty());
+		let (item, peeked) = peek_body(&self.header, self.stream, self.deadline)?;
+		self.peeked = peeked;
+		Ok(item)
+	}
+
+	/// Skips the message body, read off the connection and thrown away.
+	pub fn discard(&mut self) -> Result<(), Error> {
+		let left = self.header.msg_len - self.peeked.len() as u64;
+		read_discard(left, self.stream, self.deadline)
 	}
 
 	/// Read a single "thing" from the underlying connection.
 	/// Return the thing and the total bytes read.
 	pub fn streaming_read<T: ser::Readable>(&mut self) -> Result<(T, u64), Error> {
-		read_item(self.stream)
+		if Instant::now() >= self.deadline {
+			return Err(Error::Timeout);
+		}
+		match self.checksum {
+			Some(ref mut checksum) => read_item(&mut ChecksumReader {
+				inner: &mut *self.stream,
+				checksum,
+			}),
+			None => read_item(self.stream),
+		}
+	}
+
+	/// Once the whole body went through `streaming_read`, checks it against
+	/// the checksum of the header.
+	pub fn verify_checksum(&mut self) -> Result<(), Error> {
+		if let (Some(checksum), Some(expected)) = (self.checksum.take(), self.header.checksum) {
+			if checksum.finish() != expected {
+				return Err(Error::Corruption);
+			}
+		}
+		Ok(())
 	}
 
 	pub fn copy_attachment(&mut self, len: usize, writer: &mut dyn Write) -> Result<usize, Error> {
@@ -114,48 +186,60 @@ impl<'a> Message<'a> {
 /// Response to a `Message`.
 pub struct Response<'a> {
 	resp_type: Type,
-	body: Vec<u8>,
+	body: ResponseBody,
 	stream: &'a mut dyn Write,
 	attachment: Option<File>,
 }
 
+enum ResponseBody {
+	Buf(PooledBuf),
+	// framed as when sent, see `write_streamed`
+	Block(Block),
+}
+
 impl<'a> Response<'a> {
 	pub fn new<T: ser::Writeable>(
 		resp_type: Type,
 		body: T,
 		stream: &'a mut dyn Write,
 	) -> Result<Response<'a>, Error> {
-		let body = ser::ser_vec(&body)?;
+		let mut buf = PooledBuf::take();
+		ser::serialize(&mut *buf, &body)?;
 		Ok(Response {
 			resp_type,
-			body,
+			body: ResponseBody::Buf(buf),
 			stream,
 			attachment: None,
 		})
 	}
 
-	fn write(mut self, tracker: Arc<Tracker>) -> Result<(), Error> {
-		let mut msg = ser::ser_vec(&MsgHeader::new(self.resp_type, self.body.len() as u64))?;
-		msg.append(&mut self.body);
-		write_all(&mut self.stream, &msg[..], time::Duration::from_secs(10))?;
-		tracker.inc_sent(msg.len() as u64);
+	/// A block response, written out in chunks rather than serialized whole.
+	pub fn block(block: Block, stream: &'a mut dyn Write) -> Response<'a> {
+		Response {
+			resp_type: Type::Block,
+			body: ResponseBody::Block(block),
+			stream,
+			attachment: None,
+		}
+	}
 
-		if let Some(mut file) = self.attachment {
-			let mut buf = [0u8; 8000];
-			loop {
-				match file.read(&mut buf[..]) {
-					Ok(0) => break,
-					Ok(n) => {
-						write_all(&mut self.stream, &buf[..n], time::Duration::from_secs(10))?;
-						// Increase sent bytes "quietly" without incrementing the counter.
-						// (In a loop here for the single attachment).
-						tracker.inc_quiet_sent(n as u64);
-					}
-					Err(e) => return Err(From::from(e)),
-				}
+	// Serializes the msg to the writer the response was made with. What goes
+	// out in pieces after it is left to the outbox: the attachment, or the
+	// body of a block (only its header is written then).
+	fn write(mut self, version: ProtocolVersion, compress: bool) -> Result<Option<Tail>, Error> {
+		match self.body {
+			ResponseBody::Buf(body) => {
+				let (header, body) = frame_body(body, version, self.resp_type, compress)?;
+				self.stream.write_all(&header[..])?;
+				self.stream.write_all(&body[..])?;
+				Ok(self.attachment.map(Tail::File))
+			}
+			ResponseBody::Block(block) => {
+				let (header, body_len) = streamed_header(&block, version, self.resp_type)?;
+				self.stream.write_all(&header[..])?;
+				Ok(Some(Tail::Block(block, body_len)))
 			}
 		}
-		Ok(())
 	}
 
 	pub fn add_attachment(&mut self, file: File) {
@@ -163,24 +247,110 @@ impl<'a> Response<'a> {
 	}
 }
 
+// What goes out in pieces right after a response.
+enum Tail {
+	// an attached file, counted quietly like the attachments we receive
+	File(File),
+	// the body of a block and its length, serialized as it's written out
+	Block(Block, u64),
+}
+
 pub const SEND_CHANNEL_CAP: usize = 100;
 
+/// Max number of priority msgs waiting to be sent, on top of the
+/// SEND_CHANNEL_CAP other ones.
+pub const PRIORITY_CHANNEL_CAP: usize = 20;
+
+/// How many chunks of the body of a block response can be serialized ahead
+/// of the ones written out.
+const BODY_CHUNKS_AHEAD: usize = 4;
+
+// Small latency sensitive msgs, sent ahead of the bulk ones waiting. Msgs
+// can't be interleaved on the wire, so they still wait for the end of the
+// msg being written.
+fn is_priority(msg_type: Type) -> bool {
+	match msg_type {
+		Type::Ping
+		| Type::Pong
+		| Type::Header
+		| Type::BlockInv
+		| Type::TransactionKernel
+		| Type::BanReason
+		| Type::PeerError => true,
+		_ => false,
+	}
+}
+
+// The next msg to write, the priority ones first. Order is kept within each
+// channel.
+fn next_outgoing(
+	priority_rx: &mpsc::Receiver<Outgoing>,
+	send_rx: &mpsc::Receiver<Outgoing>,
+) -> Result<Outgoing, mpsc::TryRecvError> {
+	priority_rx.try_recv().or_else(|_| send_rx.try_recv())
+}
+
+/// Max number of messages of unknown type a peer can send us in a minute
+/// before we disconnect it.
+pub const MAX_UNKNOWN_MSGS_PER_MIN: u64 = 50;
+
+/// How long we try writing the last msg (the ban reason) before closing a
+/// connection. Best effort only, a peer that stopped reading doesn't get to
+/// delay its own eviction.
+pub const CLOSE_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// How long we keep writing out what's queued when closing a connection in
+/// good order. A hard cap, a peer that stopped reading only gets what it read
+/// by then (and no Disconnect if we were cut in the middle of a msg).
+pub const CLOSE_FLUSH_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// How long the peer can go without reading anything we're writing out (or a
+/// chunked msg without its next piece) before we give up on the connection.
+pub const WRITE_STALL_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How the connection thread should close the connection.
+pub enum Close {
+	/// Right away, writing the provided (serialized) msg first if any.
+	Now(Option<Vec<u8>>),
+	/// Once what's queued is written out, then the provided msg. New msgs are
+	/// refused meanwhile.
+	Flushed(Vec<u8>),
+}
+
 pub struct StopHandle {
-	/// Channel to close the connection
-	pub close_channel: mpsc::Sender<()>,
+	/// Channel to close the connection, optionally writing a last msg first
+	pub close_channel: mpsc::Sender<Close>,
 	// we need Option to take ownhership of the handle in stop()
 	peer_thread: Option<JoinHandle<()>>,
+	tracker: Arc<Tracker>,
 }
 
 impl StopHandle {
 	/// Schedule this connection to safely close via the async close_channel.
 	pub fn stop(&self) {
-		if self.close_channel.send(()).is_err() {
+		if self.close_channel.send(Close::Now(None)).is_err() {
 			debug!("peer's close_channel is disconnected, must be stopped already");
 			return;
 		}
 	}
 
+	/// Same as stop, writing the provided (serialized) msg right before
+	/// closing, on a best effort basis.
+	pub fn stop_with(&self, last: Vec<u8>) {
+		if self.close_channel.send(Close::Now(Some(last))).is_err() {
+			debug!("peer's close_channel is disconnected, must be stopped already");
+		}
+	}
+
+	/// Closes the connection in good order: stops taking new msgs, writes out
+	/// the queued ones (within CLOSE_FLUSH_TIMEOUT) and the provided one last.
+	pub fn close(&self, last: Vec<u8>) {
+		self.tracker.close_by(Instant::now() + CLOSE_FLUSH_TIMEOUT);
+		if self.close_channel.send(Close::Flushed(last)).is_err() {
+			debug!("peer's close_channel is disconnected, must be stopped already");
+		}
+	}
+
 	pub fn wait(&mut self) {
 		if let Some(peer_thread) = self.peer_thread.take() {
 			// wait only if other thread is calling us, eg shutdown
@@ -199,9 +369,29 @@ impl StopHandle {
 	}
 }
 
+/// Data for the connection thread to write out.
+pub enum Outgoing {
+	/// A whole msg.
+	Msg(Vec<u8>),
+	/// A piece of a msg sent in chunks. The pieces are written as they come,
+	/// nothing else goes in between until the `End` of the msg.
+	Chunk(Vec<u8>),
+	End,
+}
+
 pub struct ConnHandle {
 	/// Channel to allow sending data through the connection
-	pub send_channel: mpsc::SyncSender<Vec<u8>>,
+	pub send_channel: mpsc::SyncSender<Outgoing>,
+	/// Channel for the msgs going ahead of the others, see `is_priority`
+	pub priority_channel: mpsc::SyncSender<Outgoing>,
+	/// Protocol version negotiated with the peer, msgs are framed for it
+	pub version: ProtocolVersion,
+	/// Whether both sides support compression, large msgs get compressed
+	pub compress: bool,
+	/// How many bytes can wait in the channels for the peer to read them
+	pub max_queued: usize,
+	/// Keeps count of the bytes queued, shared with the connection thread
+	pub tracker: Arc<Tracker>,
 }
 
 impl ConnHandle {
@@ -209,11 +399,116 @@ impl ConnHandle {
 	where
 		T: ser::Writeable,
 	{
-		let buf = write_to_buf(body, msg_type)?;
+		if self.tracker.is_closing() {
+			return Err(Error::ConnectionClose);
+		}
+		if is_streamed(msg_type) {
+			return self.send_streamed(body, msg_type);
+		}
+		let buf = if self.compress {
+			write_to_buf_compressed(body, self.version, msg_type)?
+		} else {
+			write_to_buf(body, self.version, msg_type)?
+		};
 		let buf_len = buf.len();
-		self.send_channel.try_send(buf)?;
+		if !self.tracker.try_queue(buf_len, self.max_queued) {
+			return Err(Error::QueueFull);
+		}
+		let res = if is_priority(msg_type) {
+			self.priority_channel.try_send(Outgoing::Msg(buf))
+		} else {
+			self.send_channel.try_send(Outgoing::Msg(buf))
+		};
+		if let Err(e) = res {
+			self.tracker.dequeued(buf_len);
+			return Err(e.into());
+		}
 		Ok(buf_len as u64)
 	}
+
+	// Large msgs are handed to the connection thread in chunks, we never hold
+	// them whole. Only one msg can be sent at a time (the peer holds us behind
+	// a lock), or the chunks of two of them would get mixed.
+	fn send_streamed<T>(&self, body: T, msg_type: Type) -> Result<u64, Error>
+	where
+		T: ser::Writeable,
+	{
+		let mut sender = ChunkSender {
+			channel: &self.send_channel,
+			tracker: &self.tracker,
+			max_queued: self.max_queued,
+			started: false,
+			error: None,
+		};
+		let res = write_streamed(&mut sender, &body, self.version, msg_type);
+		// a failure halfway leaves the msg without its end, the connection
+		// thread gives up on it and closes the connection
+		if let Some(e) = sender.error.take() {
+			return Err(e);
+		}
+		let sent = res?;
+		self.send_channel
+			.send(Outgoing::End)
+			.map_err(|e| Error::Send(e.to_string()))?;
+		Ok(sent)
+	}
+}
+
+// Queues everything written as chunks on the send channel. The first chunk is
+// refused like any msg when the channel (or the queued bytes) is full, the
+// following ones wait for room so the msg doesn't end up cut.
+struct ChunkSender<'a> {
+	channel: &'a mpsc::SyncSender<Outgoing>,
+	tracker: &'a Tracker,
+	max_queued: usize,
+	started: bool,
+	error: Option<Error>,
+}
+
+impl<'a> Write for ChunkSender<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let chunk = Outgoing::Chunk(buf.to_vec());
+		let res = if self.started {
+			self.tracker.queued(buf.len());
+			self.channel
+				.send(chunk)
+				.map_err(|e| Error::Send(e.to_string()))
+		} else if self.tracker.try_queue(buf.len(), self.max_queued) {
+			self.channel.try_send(chunk).map_err(From::from)
+		} else {
+			self.error = Some(Error::QueueFull);
+			return Err(io::Error::new(io::ErrorKind::BrokenPipe, "send queue"));
+		};
+		match res {
+			Ok(()) => {
+				self.started = true;
+				Ok(buf.len())
+			}
+			Err(e) => {
+				self.tracker.dequeued(buf.len());
+				self.error = Some(e);
+				Err(io::Error::new(io::ErrorKind::BrokenPipe, "send channel"))
+			}
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// Msg types are numbered from 0 up, counted by type below this.
+const MAX_MSG_TYPES: usize = 32;
+
+fn msg_counts(counts: &[AtomicU64]) -> Vec<(Type, u64)> {
+	counts
+		.iter()
+		.enumerate()
+		.filter_map(|(i, count)| match count.load(Ordering::Relaxed) {
+			0 => None,
+			n => Type::from_u8(i as u8).map(|t| (t, n)),
+		})
+		.collect()
 }
 
 pub struct Tracker {
@@ -221,40 +516,188 @@ pub struct Tracker {
 	pub sent_bytes: Arc<RwLock<RateCounter>>,
 	/// Bytes we've received.
 	pub received_bytes: Arc<RwLock<RateCounter>>,
+	/// Messages of a type we don't know we've received (and skipped).
+	pub unknown_msgs: Arc<RwLock<RateCounter>>,
+	/// Bytes sent since the connection was opened, handshake included.
+	sent_total: AtomicU64,
+	/// Bytes received since the connection was opened, handshake included.
+	received_total: AtomicU64,
+	/// Bytes waiting to be written out to the peer.
+	queued_bytes: AtomicUsize,
+	/// Whether the connection thread exited, the connection is of no use.
+	closed: AtomicBool,
+	/// Whether it exited because the peer went silent for too long.
+	idle: AtomicBool,
+	/// Msgs sent and received, by type, the handshake left out.
+	msgs_sent: [AtomicU64; MAX_MSG_TYPES],
+	msgs_received: [AtomicU64; MAX_MSG_TYPES],
+	/// When closing in good order, until when we write out what's queued.
+	flush_deadline: RwLock<Option<Instant>>,
 }
 
 impl Tracker {
 	pub fn new() -> Tracker {
 		let received_bytes = Arc::new(RwLock::new(RateCounter::new()));
 		let sent_bytes = Arc::new(RwLock::new(RateCounter::new()));
+		let unknown_msgs = Arc::new(RwLock::new(RateCounter::new()));
 		Tracker {
 			received_bytes,
 			sent_bytes,
+			unknown_msgs,
+			sent_total: AtomicU64::new(0),
+			received_total: AtomicU64::new(0),
+			queued_bytes: AtomicUsize::new(0),
+			closed: AtomicBool::new(false),
+			idle: AtomicBool::new(false),
+			msgs_sent: Default::default(),
+			msgs_received: Default::default(),
+			flush_deadline: RwLock::new(None),
 		}
 	}
 
 	pub fn inc_received(&self, size: u64) {
 		self.received_bytes.write().inc(size);
+		self.received_total.fetch_add(size, Ordering::Relaxed);
 	}
 
 	pub fn inc_sent(&self, size: u64) {
 		self.sent_bytes.write().inc(size);
+		self.sent_total.fetch_add(size, Ordering::Relaxed);
 	}
 
 	pub fn inc_quiet_received(&self, size: u64) {
 		self.received_bytes.write().inc_quiet(size);
+		self.received_total.fetch_add(size, Ordering::Relaxed);
 	}
 
 	pub fn inc_quiet_sent(&self, size: u64) {
 		self.sent_bytes.write().inc_quiet(size);
+		self.sent_total.fetch_add(size, Ordering::Relaxed);
+	}
+
+	/// Bytes sent since the connection was opened.
+	pub fn sent_total(&self) -> u64 {
+		self.sent_total.load(Ordering::Relaxed)
+	}
+
+	/// Bytes received since the connection was opened.
+	pub fn received_total(&self) -> u64 {
+		self.received_total.load(Ordering::Relaxed)
+	}
+
+	pub fn inc_unknown(&self) {
+		self.unknown_msgs.write().inc(1);
+	}
+
+	pub fn inc_msg_sent(&self, msg_type: Type) {
+		if let Some(count) = self.msgs_sent.get(msg_type as usize) {
+			count.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	pub fn inc_msg_received(&self, msg_type: Type) {
+		if let Some(count) = self.msgs_received.get(msg_type as usize) {
+			count.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Msgs sent by type, the types we sent none of left out.
+	pub fn msgs_sent(&self) -> Vec<(Type, u64)> {
+		msg_counts(&self.msgs_sent)
+	}
+
+	/// Msgs received by type, the types we got none of left out.
+	pub fn msgs_received(&self) -> Vec<(Type, u64)> {
+		msg_counts(&self.msgs_received)
+	}
+
+	pub fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::Relaxed)
+	}
+
+	/// Whether we closed the connection as nothing came from the peer in a
+	/// while, dead or frozen.
+	pub fn is_idle(&self) -> bool {
+		self.idle.load(Ordering::Relaxed)
+	}
+
+	/// Whether we're closing the connection, no more msgs taken.
+	pub fn is_closing(&self) -> bool {
+		self.flush_deadline.read().is_some()
+	}
+
+	fn close_by(&self, deadline: Instant) {
+		let mut flush_deadline = self.flush_deadline.write();
+		if flush_deadline.is_none() {
+			*flush_deadline = Some(deadline);
+		}
+	}
+
+	// Whether we're closing and past the deadline to write out what's queued.
+	fn flush_expired(&self) -> bool {
+		match *self.flush_deadline.read() {
+			Some(deadline) => Instant::now() >= deadline,
+			None => false,
+		}
+	}
+
+	/// Bytes waiting to be written out to the peer.
+	pub fn queued_bytes(&self) -> usize {
+		self.queued_bytes.load(Ordering::Relaxed)
+	}
+
+	// Counts the bytes of a msg about to be queued, unless that would go over
+	// max. Racing senders can't both fit in the last of the room.
+	fn try_queue(&self, size: usize, max: usize) -> bool {
+		let mut queued = self.queued_bytes.load(Ordering::Relaxed);
+		loop {
+			if queued + size > max {
+				return false;
+			}
+			match self.queued_bytes.compare_exchange_weak(
+				queued,
+				queued + size,
+				Ordering::Relaxed,
+				Ordering::Relaxed,
+			) {
+				Ok(_) => return true,
+				Err(current) => queued = current,
+			}
+		}
+	}
+
+	fn queued(&self, size: usize) {
+		self.queued_bytes.fetch_add(size, Ordering::Relaxed);
+	}
+
+	fn dequeued(&self, size: usize) {
+		self.queued_bytes.fetch_sub(size, Ordering::Relaxed);
 	}
 }
 
+/// How a connection behaves once established, mostly from our config.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnOptions {
+	/// Whether msgs are compressed, as negotiated with the peer.
+	pub compress: bool,
+	/// How many bytes we queue at most for the peer to read.
+	pub max_queued: usize,
+	/// How long we stay silent before pinging the peer.
+	pub keepalive: time::Duration,
+	/// How long we wait for anything from the peer before closing.
+	pub idle_timeout: time::Duration,
+	/// How often we gossip to the peer.
+	pub gossip_interval: time::Duration,
+}
+
 /// Start listening on the provided connection and wraps it. Does not hang
 /// the current thread, instead just returns a future and the Connection
 /// itself.
 pub fn listen<H>(
 	stream: TcpStream,
+	version: ProtocolVersion,
+	opts: ConnOptions,
+	keys: Option<SessionKeys>,
 	tracker: Arc<Tracker>,
 	handler: H,
 ) -> io::Result<(ConnHandle, StopHandle)>
@@ -262,47 +705,99 @@ where
 	H: MessageHandler,
 {
 	let (send_tx, send_rx) = mpsc::sync_channel(SEND_CHANNEL_CAP);
+	let (priority_tx, priority_rx) = mpsc::sync_channel(PRIORITY_CHANNEL_CAP);
 	let (close_tx, close_rx) = mpsc::channel();
 
 	stream
 		.set_nonblocking(true)
 		.expect("Non-blocking IO not available.");
-	let peer_thread = poll(stream, handler, send_rx, close_rx, tracker)?;
+	let peer_thread = poll(
+		stream,
+		version,
+		opts,
+		keys,
+		handler,
+		(priority_rx, send_rx),
+		close_rx,
+		tracker.clone(),
+	)?;
 
 	Ok((
 		ConnHandle {
 			send_channel: send_tx,
+			priority_channel: priority_tx,
+			version,
+			compress: opts.compress,
+			max_queued: opts.max_queued,
+			tracker: tracker.clone(),
 		},
 		StopHandle {
 			close_channel: close_tx,
 			peer_thread: Some(peer_thread),
+			tracker,
 		},
 	))
 }
 
 fn poll<H>(
 	conn: TcpStream,
+	version: ProtocolVersion,
+	opts: ConnOptions,
+	keys: Option<SessionKeys>,
 	handler: H,
-	send_rx: mpsc::Receiver<Vec<u8>>,
-	close_rx: mpsc::Receiver<()>,
+	(priority_rx, send_rx): (mpsc::Receiver<Outgoing>, mpsc::Receiver<Outgoing>),
+	close_rx: mpsc::Receiver<Close>,
 	tracker: Arc<Tracker>,
 ) -> io::Result<JoinHandle<()>>
 where
 	H: MessageHandler,
 {
 	// Split out tcp stream out into separate reader/writer halves.
-	let mut reader = conn.try_clone().expect("clone conn for reader failed");
-	let mut writer = conn.try_clone().expect("clone conn for writer failed");
+	let reader = conn.try_clone().expect("clone conn for reader failed");
+	let writer = conn.try_clone().expect("clone conn for writer failed");
+
+	// and go through the encrypted transport if we negotiated it
+	let (mut reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match keys {
+		Some(keys) => {
+			let (reader, writer) = keys.wrap(reader, writer);
+			(Box::new(reader), Box::new(writer))
+		}
+		None => (Box::new(reader), Box::new(writer)),
+	};
+	let mut writer = FlushCapped {
+		inner: writer,
+		tracker: tracker.clone(),
+	};
 
 	thread::Builder::new()
 		.name("peer".to_string())
 		.spawn(move || {
 			let sleep_time = time::Duration::from_millis(5);
-			let mut retry_send = Err(());
+			let compress = opts.compress;
+			let mut outbox = Outbox::default();
+			// responses are serialized there, then handed to the outbox
+			let mut resp_buf = vec![];
+			let mut last_received = Instant::now();
+			let mut last_sent = Instant::now();
+			let mut last_gossip = Instant::now();
+			// what to write before closing, if anything
+			let mut last = None;
+			// closing in good order, writing out what's queued first
+			let mut flushing = false;
 			loop {
-				// check the read end
-				match try_break!(read_header(&mut reader, None)) {
+				// check the read end, unless in the middle of writing a msg out:
+				// a response would get in the middle of it
+				let header = if outbox.is_busy() {
+					None
+				} else {
+					try_break!(read_header(&mut reader, version, None), |e: &Error| {
+						last = last_words(e, version)
+					})
+				};
+				match header {
 					Some(MsgHeaderWrapper::Known(header)) => {
+						last_received = Instant::now();
+						debug_assert!(!outbox.is_busy());
 						let msg = Message::from_header(header, &mut reader);
 
 						trace!(
@@ -312,46 +807,148 @@ where
 						);
 
 						// Increase received bytes counter
-						tracker.inc_received(MsgHeader::LEN as u64 + msg.header.msg_len);
+						tracker.inc_received(version.header_len() as u64 + msg.header.msg_len);
+						tracker.inc_msg_received(msg.header.msg_type);
 
-						if let Some(Some(resp)) =
-							try_break!(handler.consume(msg, &mut writer, tracker.clone()))
-						{
-							try_break!(resp.write(tracker.clone()));
+						if let Some(Some(resp)) = try_break!(
+							handler.consume(msg, &mut resp_buf, tracker.clone()),
+							|e: &Error| last = last_words(e, version)
+						) {
+							let resp_type = resp.resp_type;
+							if let Some(tail) = try_break!(resp.write(version, compress)) {
+								if let Some(Some(sent)) = try_break!(
+									outbox.respond(
+										resp_type,
+										&mut resp_buf,
+										tail,
+										&tracker,
+										opts.max_queued
+									),
+									|e: &Error| last = last_words(e, version)
+								) {
+									tracker.inc_msg_sent(resp_type);
+									handler.sent(resp_type, sent);
+								}
+							}
 						}
 					}
-					Some(MsgHeaderWrapper::Unknown(msg_len)) => {
+					Some(MsgHeaderWrapper::Unknown(msg_len, msg_type)) => {
+						debug!(
+							"Received unknown message type {}, len {}, discarding.",
+							msg_type, msg_len
+						);
+						last_received = Instant::now();
 						// Increase received bytes counter
-						tracker.inc_received(MsgHeader::LEN as u64 + msg_len);
+						tracker.inc_received(version.header_len() as u64 + msg_len);
+						tracker.inc_unknown();
+
+						let deadline = last_received + BODY_TIMEOUT;
+						try_break!(read_discard(msg_len, &mut reader, deadline));
 
-						try_break!(read_discard(msg_len, &mut reader));
+						// newer peers may send a few we don't know, not a stream of them
+						let unknown = tracker.unknown_msgs.read().count_per_min();
+						if unknown > MAX_UNKNOWN_MSGS_PER_MIN {
+							debug!("Too many unknown messages, closing the connection.");
+							let err = PeerError::new(
+								PeerErrorCode::RateLimited,
+								"too many unknown messages".to_owned(),
+							);
+							last = write_to_buf(err, version, Type::PeerError).ok();
+							break;
+						}
+					}
+					None => {
+						if !outbox.is_busy() && last_received.elapsed() > opts.idle_timeout {
+							debug!("Nothing received for too long, peer unresponsive, closing.");
+							tracker.idle.store(true, Ordering::Relaxed);
+							break;
+						}
 					}
-					None => {}
 				}
 
-				// check the write end, use or_else so try_recv is lazily eval'd
-				let maybe_data = retry_send.or_else(|_| send_rx.try_recv());
-				retry_send = Err(());
-				if let Ok(data) = maybe_data {
-					let written = try_break!(write_all(
-						&mut writer,
-						&data[..],
-						std::time::Duration::from_secs(10)
-					)
-					.map_err(&From::from));
-					if written.is_none() {
-						retry_send = Ok(data);
+				// check the write end, as much as the socket takes
+				// whether there was nothing left to write
+				let mut drained = false;
+				match try_break!(
+					outbox.write(&mut writer, &priority_rx, &send_rx, &tracker),
+					|_: &Error| last = None
+				) {
+					Some(Written::Msg) => last_sent = Instant::now(),
+					Some(Written::Partly) | None => {}
+					Some(Written::Nothing) => {
+						drained = true;
+						// nothing to send for a while, let the peer know we're alive
+						if !flushing && last_sent.elapsed() >= opts.keepalive {
+							debug_assert!(!outbox.is_busy());
+							if let Some(resp) = try_break!(handler.keepalive(&mut resp_buf)) {
+								let resp_type = resp.resp_type;
+								try_break!(resp.write(version, compress));
+								if let Some(Some(sent)) = try_break!(outbox.respond(
+									resp_type,
+									&mut resp_buf,
+									None,
+									&tracker,
+									opts.max_queued
+								)) {
+									tracker.inc_msg_sent(resp_type);
+									handler.sent(resp_type, sent);
+								}
+							}
+							last_sent = Instant::now();
+						} else if !flushing && last_gossip.elapsed() >= opts.gossip_interval {
+							debug_assert!(!outbox.is_busy());
+							if let Some(Some(resp)) = try_break!(handler.gossip(&mut resp_buf)) {
+								let resp_type = resp.resp_type;
+								try_break!(resp.write(version, compress));
+								if let Some(Some(sent)) = try_break!(outbox.respond(
+									resp_type,
+									&mut resp_buf,
+									None,
+									&tracker,
+									opts.max_queued
+								)) {
+									tracker.inc_msg_sent(resp_type);
+									handler.sent(resp_type, sent);
+								}
+							}
+							last_gossip = Instant::now();
+						}
 					}
 				}
 
-				// check the close channel
-				if let Ok(_) = close_rx.try_recv() {
+				// done writing out what was queued before closing, or out of time
+				if flushing && (drained || tracker.flush_expired()) {
 					break;
 				}
 
+				// check the close channel
+				match close_rx.try_recv() {
+					Ok(Close::Now(last_msg)) => {
+						last = last_msg;
+						break;
+					}
+					Ok(Close::Flushed(last_msg)) => {
+						last = Some(last_msg);
+						flushing = true;
+					}
+					Err(_) => {}
+				}
+
 				thread::sleep(sleep_time);
 			}
 
+			// cut in the middle of a msg, anything more would be garbage
+			if outbox.is_busy() {
+				last = None;
+			}
+			if let Some(data) = last {
+				// past the flush deadline, still worth a try
+				let res = write_all(&mut writer.inner, &data[..], CLOSE_WRITE_TIMEOUT)
+					.and_then(|_| flush_all(&mut writer.inner, CLOSE_WRITE_TIMEOUT));
+				if let Err(e) = res {
+					debug!("Could not write the last msg before closing: {:?}", e);
+				}
+			}
 			debug!(
 				"Shutting down connection with {}",
 				conn.peer_addr()
@@ -359,5 +956,659 @@ where
 					.unwrap_or("?".to_owned())
 			);
 			let _ = conn.shutdown(Shutdown::Both);
+			tracker.closed.store(true, Ordering::Relaxed);
 		})
 }
+
+// Writes to the connection, failing once we're closing and past the deadline
+// to write out what's queued. A peer that stopped reading can't hold us.
+struct FlushCapped {
+	inner: Box<dyn Write + Send>,
+	tracker: Arc<Tracker>,
+}
+
+impl Write for FlushCapped {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.tracker.flush_expired() {
+			return Err(io::Error::new(
+				io::ErrorKind::TimedOut,
+				"closing, out of time to write",
+			));
+		}
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+// Flushes the writer within the timeout, as `write_all` writes.
+fn flush_all(writer: &mut dyn Write, timeout: time::Duration) -> io::Result<()> {
+	let deadline = Instant::now() + timeout;
+	loop {
+		match writer.flush() {
+			Ok(()) => return Ok(()),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+		if Instant::now() > deadline {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, "flushing stream"));
+		}
+		thread::sleep(time::Duration::from_micros(10));
+	}
+}
+
+// What to send a peer we're closing the connection on because of the
+// provided error, when it's the peer's fault: a PeerError when we have a code
+// for it, the ban reason otherwise.
+fn last_words(e: &Error, version: ProtocolVersion) -> Option<Vec<u8>> {
+	let code = match e {
+		Error::BadMessage => Some(PeerErrorCode::UnexpectedMessage),
+		Error::MsgLen => Some(PeerErrorCode::BadLength),
+		Error::Corruption => Some(PeerErrorCode::ChecksumMismatch),
+		Error::RateLimited => Some(PeerErrorCode::RateLimited),
+		Error::QueueFull => Some(PeerErrorCode::TooSlow),
+		_ => None,
+	};
+	if let Some(code) = code {
+		let err = PeerError::new(code, format!("{:?}", e));
+		return write_to_buf(err, version, Type::PeerError).ok();
+	}
+	let ban_reason = match e {
+		Error::WrongNetwork => ReasonForBan::WrongNetwork,
+		_ if HandshakeFailure::from(e) == HandshakeFailure::ProtocolViolation => {
+			ReasonForBan::ProtocolViolation
+		}
+		_ => return None,
+	};
+	let msg = BanReason {
+		ban_reason,
+		message: Some(format!("{:?}", e)),
+	};
+	write_to_buf(msg, version, Type::BanReason).ok()
+}
+
+// What a call to `Outbox::write` got done.
+#[derive(Debug, PartialEq)]
+enum Written {
+	/// Nothing queued, nothing in the middle of being written.
+	Nothing,
+	/// A msg was written out whole.
+	Msg,
+	/// Some of a msg, the socket (or the sender of a chunked msg) can't keep
+	/// up. The rest goes next time, before anything else.
+	Partly,
+}
+
+// The only writer of msgs to the socket, on the connection thread. Writes
+// what's queued as far as the socket takes it and resumes from there next
+// time, a msg (even chunked) always goes out whole and contiguous before the
+// next one starts.
+#[derive(Default)]
+struct Outbox {
+	// the msg (or chunk) being written, up to `written`
+	data: Vec<u8>,
+	written: usize,
+	// in the middle of a chunked msg, its pieces come before anything else
+	chunked: bool,
+	// the file attached to the response just queued, written out in pieces
+	// right after it
+	attachment: Option<File>,
+	// same for the body of a block response, serialized on its own thread
+	// and handed over in chunks
+	body: Option<mpsc::Receiver<Outgoing>>,
+	// last time we got anything written (or a chunk), to give up on a stuck
+	// peer
+	progress: Option<Instant>,
+}
+
+impl Outbox {
+	// Whether we're in the middle of a msg, nothing else can be written.
+	fn is_busy(&self) -> bool {
+		self.written < self.data.len()
+			|| self.chunked
+			|| self.attachment.is_some()
+			|| self.body.is_some()
+	}
+
+	// Takes a response (or keepalive) serialized in `buf`, and the body of a
+	// block following it if any, within the same cap as the msgs sent through
+	// the channels (the start of a streamed one, as with `send_streamed`).
+	// Returns its length, None when it didn't fit and can go unsent. One that
+	// can't is a QueueFull error, the peer can't keep up (see `Peer::send`).
+	fn respond(
+		&mut self,
+		resp_type: Type,
+		buf: &mut Vec<u8>,
+		tail: Option<Tail>,
+		tracker: &Tracker,
+		max_queued: usize,
+	) -> Result<Option<u64>, Error> {
+		debug_assert!(!self.is_busy());
+		let data = mem::replace(buf, vec![]);
+		let len = match tail {
+			Some(Tail::Block(_, body_len)) => data.len() + body_len as usize,
+			_ => data.len(),
+		};
+		let start = if is_streamed(resp_type) {
+			cmp::min(len, STREAM_CHUNK_SIZE)
+		} else {
+			len
+		};
+		if !tracker.try_queue(start, max_queued) {
+			if is_droppable(resp_type) {
+				debug!("Send queue full, {:?} response skipped", resp_type);
+				return Ok(None);
+			}
+			return Err(Error::QueueFull);
+		}
+		tracker.queued(len - start);
+		tracker.inc_sent(len as u64);
+		self.start(data);
+		match tail {
+			Some(Tail::File(file)) => self.attachment = Some(file),
+			Some(Tail::Block(block, _)) => self.body = Some(stream_body(block)?),
+			None => {}
+		}
+		Ok(Some(len as u64))
+	}
+
+	fn write(
+		&mut self,
+		writer: &mut dyn Write,
+		priority_rx: &mpsc::Receiver<Outgoing>,
+		send_rx: &mpsc::Receiver<Outgoing>,
+		tracker: &Tracker,
+	) -> Result<Written, Error> {
+		loop {
+			debug_assert!(self.written <= self.data.len());
+			if self.written < self.data.len() {
+				match writer.write(&self.data[self.written..]) {
+					Ok(0) => {
+						return Err(Error::Connection(io::Error::new(
+							io::ErrorKind::WriteZero,
+							"failed to write whole msg",
+						)));
+					}
+					Ok(n) => {
+						self.written += n;
+						self.progress = Some(Instant::now());
+					}
+					Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return self.stalled(),
+					Err(e) => return Err(e.into()),
+				}
+				continue;
+			}
+			if !self.data.is_empty() {
+				tracker.dequeued(self.data.len());
+				self.data = vec![];
+				self.written = 0;
+				if !self.chunked && self.attachment.is_none() && self.body.is_none() {
+					return Ok(Written::Msg);
+				}
+			}
+
+			if let Some(file) = self.attachment.as_mut() {
+				let mut data = vec![0u8; STREAM_CHUNK_SIZE];
+				let n = file.read(&mut data[..])?;
+				if n == 0 {
+					self.attachment = None;
+					return Ok(Written::Msg);
+				}
+				data.truncate(n);
+				tracker.queued(n);
+				// counted quietly, like the attachments we receive
+				tracker.inc_quiet_sent(n as u64);
+				self.start(data);
+				continue;
+			}
+
+			if let Some(body) = self.body.as_ref() {
+				match body.try_recv() {
+					Ok(Outgoing::Chunk(data)) => self.start(data),
+					Ok(Outgoing::End) => {
+						self.body = None;
+						return Ok(Written::Msg);
+					}
+					Err(mpsc::TryRecvError::Empty) => return self.stalled(),
+					Ok(Outgoing::Msg(_)) | Err(mpsc::TryRecvError::Disconnected) => {
+						return Err(Error::Send("block response cut short".to_owned()));
+					}
+				}
+				continue;
+			}
+
+			let next = if self.chunked {
+				send_rx.try_recv()
+			} else {
+				next_outgoing(priority_rx, send_rx)
+			};
+			match next {
+				Ok(Outgoing::Msg(_)) if self.chunked => {
+					return Err(Error::Send("msg in the middle of a chunked one".to_owned()));
+				}
+				Ok(Outgoing::Msg(data)) => self.start(data),
+				Ok(Outgoing::Chunk(data)) => {
+					self.chunked = true;
+					self.start(data);
+				}
+				Ok(Outgoing::End) if self.chunked => {
+					self.chunked = false;
+					return Ok(Written::Msg);
+				}
+				Ok(Outgoing::End) => {}
+				// waiting on the next piece
+				Err(_) if self.chunked => return self.stalled(),
+				// done once the writer doesn't hold anything back either (the
+				// encrypted transport seals frames whole)
+				Err(_) => {
+					return match writer.flush() {
+						Ok(()) => Ok(Written::Nothing),
+						Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.stalled(),
+						Err(e) => Err(e.into()),
+					};
+				}
+			}
+		}
+	}
+
+	fn start(&mut self, data: Vec<u8>) {
+		debug_assert!(self.written == self.data.len());
+		self.data = data;
+		self.written = 0;
+		self.progress = Some(Instant::now());
+	}
+
+	// Can't go further for now, fine unless it's been too long. What we
+	// wrote of the msg is of no use then.
+	fn stalled(&self) -> Result<Written, Error> {
+		match self.progress {
+			Some(at) if at.elapsed() > WRITE_STALL_TIMEOUT => Err(Error::Timeout),
+			_ => Ok(Written::Partly),
+		}
+	}
+}
+
+// Serializes the body of a block on its own thread, handed over in chunks as
+// the outbox takes them, so only a few of them are held at a time. The chunks
+// stop short of the `End` if serializing fails, and once the outbox is gone.
+fn stream_body(block: Block) -> Result<mpsc::Receiver<Outgoing>, Error> {
+	let (tx, rx) = mpsc::sync_channel(BODY_CHUNKS_AHEAD);
+	thread::Builder::new()
+		.name("peer-body".to_string())
+		.spawn(move || {
+			let mut sender = BodySender(&tx);
+			if write_streamed_body(&mut sender, &block).is_ok() {
+				let _ = tx.send(Outgoing::End);
+			}
+		})?;
+	Ok(rx)
+}
+
+struct BodySender<'a>(&'a mpsc::SyncSender<Outgoing>);
+
+impl<'a> Write for BodySender<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0
+			.send(Outgoing::Chunk(buf.to_vec()))
+			.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "outbox gone"))?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::core::core::{BlockHeader, TxKernel};
+	use crate::core::pow::Difficulty;
+	use crate::msg::Ping;
+	use std::io::{Seek, SeekFrom};
+
+	fn handle(
+		max_queued: usize,
+	) -> (
+		ConnHandle,
+		mpsc::Receiver<Outgoing>,
+		mpsc::Receiver<Outgoing>,
+	) {
+		let (send_tx, send_rx) = mpsc::sync_channel(SEND_CHANNEL_CAP);
+		let (priority_tx, priority_rx) = mpsc::sync_channel(PRIORITY_CHANNEL_CAP);
+		let handle = ConnHandle {
+			send_channel: send_tx,
+			priority_channel: priority_tx,
+			version: ProtocolVersion::default(),
+			compress: false,
+			max_queued,
+			tracker: Arc::new(Tracker::new()),
+		};
+		(handle, priority_rx, send_rx)
+	}
+
+	// a few chunks worth of kernels
+	fn block(fee: u64) -> Block {
+		let mut block = Block::with_header(BlockHeader::default());
+		*block.kernels_mut() = vec![
+			TxKernel {
+				fee,
+				..TxKernel::empty()
+			};
+			2_000
+		];
+		block
+	}
+
+	fn ping() -> Ping {
+		Ping {
+			total_difficulty: Difficulty::min(),
+			height: 0,
+			nonce: None,
+		}
+	}
+
+	// Takes at most `per_write` bytes at a time, and nothing every other time,
+	// like a socket whose buffer keeps filling up.
+	struct Trickle {
+		written: Vec<u8>,
+		per_write: usize,
+		ready: bool,
+	}
+
+	impl Trickle {
+		fn new(per_write: usize) -> Trickle {
+			Trickle {
+				written: vec![],
+				per_write,
+				ready: false,
+			}
+		}
+	}
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.ready = !self.ready;
+			if !self.ready {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, "trickle"));
+			}
+			let n = cmp::min(buf.len(), self.per_write);
+			self.written.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	// Writes whatever is queued the way the connection thread does.
+	fn drain(
+		writer: &mut dyn Write,
+		handle: &ConnHandle,
+		priority_rx: &mpsc::Receiver<Outgoing>,
+		send_rx: &mpsc::Receiver<Outgoing>,
+	) {
+		let mut outbox = Outbox::default();
+		while outbox
+			.write(writer, priority_rx, send_rx, &handle.tracker)
+			.unwrap() != Written::Nothing
+		{}
+	}
+
+	fn written_types(mut written: &[u8]) -> Vec<Type> {
+		let mut types = vec![];
+		while !written.is_empty() {
+			match read_header(&mut written, ProtocolVersion::default(), None).unwrap() {
+				MsgHeaderWrapper::Known(header) => {
+					types.push(header.msg_type);
+					let deadline = Instant::now() + BODY_TIMEOUT;
+					read_discard(header.msg_len, &mut written, deadline).unwrap();
+				}
+				MsgHeaderWrapper::Unknown(..) => panic!("unknown msg written"),
+			}
+		}
+		types
+	}
+
+	#[test]
+	fn priority_ahead_of_queued_blocks() {
+		let (handle, priority_rx, send_rx) = handle(usize::max_value());
+		handle.send(block(1), Type::Block).unwrap();
+		handle.send(block(2), Type::Block).unwrap();
+		handle.send(ping(), Type::Ping).unwrap();
+
+		let mut written = vec![];
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		assert_eq!(
+			written_types(&written),
+			vec![Type::Ping, Type::Block, Type::Block]
+		);
+	}
+
+	#[test]
+	fn priority_waits_for_block_in_flight() {
+		let (handle, priority_rx, send_rx) = handle(usize::max_value());
+		handle.send(block(1), Type::Block).unwrap();
+		handle.send(block(2), Type::Block).unwrap();
+
+		// the first block is already going out when the ping comes
+		let mut written = Trickle::new(10);
+		let mut outbox = Outbox::default();
+		let res = outbox.write(&mut written, &priority_rx, &send_rx, &handle.tracker);
+		assert_eq!(res.unwrap(), Written::Partly);
+		assert!(outbox.is_busy());
+		handle.send(ping(), Type::Ping).unwrap();
+		while outbox
+			.write(&mut written, &priority_rx, &send_rx, &handle.tracker)
+			.unwrap() != Written::Nothing
+		{}
+
+		assert_eq!(
+			written_types(&written.written),
+			vec![Type::Block, Type::Ping, Type::Block]
+		);
+	}
+
+	// A socket taking one byte at a time, when it takes any, still gets each
+	// msg whole and in one piece, chunked ones included.
+	#[test]
+	fn msgs_whole_through_partial_writes() {
+		let queue = || {
+			let (handle, priority_rx, send_rx) = handle(usize::max_value());
+			handle.send(block(1), Type::Block).unwrap();
+			handle.send(ping(), Type::Ping).unwrap();
+			handle.send(block(2), Type::Block).unwrap();
+			handle.send(ping(), Type::Ping).unwrap();
+			(handle, priority_rx, send_rx)
+		};
+
+		// all at once
+		let mut expected = vec![];
+		let (other, other_priority_rx, other_send_rx) = queue();
+		drain(&mut expected, &other, &other_priority_rx, &other_send_rx);
+
+		let (handle, priority_rx, send_rx) = queue();
+		let queued = handle.tracker.queued_bytes();
+		let mut written = Trickle::new(1);
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		assert_eq!(written.written.len(), queued);
+		assert_eq!(written.written, expected);
+		assert_eq!(
+			written_types(&written.written),
+			vec![Type::Ping, Type::Ping, Type::Block, Type::Block]
+		);
+		assert_eq!(handle.tracker.queued_bytes(), 0);
+	}
+
+	// A chunked msg whose next piece never comes is given up on, nothing
+	// else gets in the middle meanwhile.
+	#[test]
+	fn chunked_msg_cut_short() {
+		let (handle, priority_rx, send_rx) = handle(usize::max_value());
+		handle.tracker.queued(10);
+		handle
+			.send_channel
+			.send(Outgoing::Chunk(vec![0u8; 10]))
+			.unwrap();
+		handle.send(ping(), Type::Ping).unwrap();
+
+		let mut written = vec![];
+		let mut outbox = Outbox::default();
+		let res = outbox.write(&mut written, &priority_rx, &send_rx, &handle.tracker);
+		assert_eq!(res.unwrap(), Written::Partly);
+		assert_eq!(written, vec![0u8; 10]);
+
+		outbox.progress = Some(Instant::now() - WRITE_STALL_TIMEOUT * 2);
+		match outbox.write(&mut written, &priority_rx, &send_rx, &handle.tracker) {
+			Err(Error::Timeout) => {}
+			res => panic!("expected a timeout, got {:?}", res),
+		}
+		assert_eq!(written, vec![0u8; 10]);
+	}
+
+	#[test]
+	fn queued_bytes_capped() {
+		let (handle, priority_rx, send_rx) = handle(100_000);
+		handle.send(block(1), Type::Block).unwrap();
+		let queued = handle.tracker.queued_bytes();
+		assert!(queued > 100_000);
+
+		// the block got in whole, nothing else does until it's written
+		match handle.send(ping(), Type::Ping) {
+			Err(Error::QueueFull) => {}
+			res => panic!("expected a full queue, got {:?}", res),
+		}
+		match handle.send(block(2), Type::Block) {
+			Err(Error::QueueFull) => {}
+			res => panic!("expected a full queue, got {:?}", res),
+		}
+		assert_eq!(handle.tracker.queued_bytes(), queued);
+
+		let mut written = vec![];
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		assert_eq!(handle.tracker.queued_bytes(), 0);
+		handle.send(ping(), Type::Ping).unwrap();
+	}
+
+	// Responses count against the same cap, the ones the peer can do without
+	// are skipped when it's reached.
+	#[test]
+	fn responses_capped() {
+		let (handle, priority_rx, send_rx) = handle(100_000);
+		handle.send(block(1), Type::Block).unwrap();
+		let queued = handle.tracker.queued_bytes();
+
+		let mut outbox = Outbox::default();
+		let mut buf = vec![0u8; 1_000];
+		match outbox.respond(Type::Headers, &mut buf, None, &handle.tracker, 100_000) {
+			Err(Error::QueueFull) => {}
+			res => panic!("expected a full queue, got {:?}", res),
+		}
+		let mut buf = vec![0u8; 1_000];
+		let res = outbox.respond(Type::Block, &mut buf, None, &handle.tracker, 100_000);
+		assert_eq!(res.unwrap(), None);
+		assert_eq!(handle.tracker.queued_bytes(), queued);
+		assert!(!outbox.is_busy());
+
+		let mut written = vec![];
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		let mut buf = vec![0u8; 1_000];
+		let res = outbox.respond(Type::Headers, &mut buf, None, &handle.tracker, 100_000);
+		assert_eq!(res.unwrap(), Some(1_000));
+		assert!(buf.is_empty());
+		assert_eq!(handle.tracker.queued_bytes(), 1_000);
+		while outbox
+			.write(&mut written, &priority_rx, &send_rx, &handle.tracker)
+			.unwrap() != Written::Nothing
+		{}
+		assert_eq!(handle.tracker.queued_bytes(), 0);
+	}
+
+	// An attachment goes out right after its response, read from the file as
+	// the socket takes it, nothing in between.
+	#[test]
+	fn attachment_after_response() {
+		let (handle, priority_rx, send_rx) = handle(usize::max_value());
+		let mut file = tempfile::tempfile().unwrap();
+		file.write_all(&vec![1u8; 200_000]).unwrap();
+		file.seek(SeekFrom::Start(0)).unwrap();
+
+		let mut outbox = Outbox::default();
+		let mut buf = vec![7u8; 10];
+		let res = outbox.respond(
+			Type::TxHashSetArchive,
+			&mut buf,
+			Some(Tail::File(file)),
+			&handle.tracker,
+			usize::max_value(),
+		);
+		assert_eq!(res.unwrap(), Some(10));
+		handle.send(ping(), Type::Ping).unwrap();
+
+		let mut written = Trickle::new(1_000);
+		loop {
+			match outbox.write(&mut written, &priority_rx, &send_rx, &handle.tracker) {
+				Ok(Written::Msg) => break,
+				Ok(Written::Partly) => assert!(outbox.is_busy()),
+				res => panic!("expected the response written, got {:?}", res),
+			}
+		}
+		assert_eq!(written.written.len(), 200_010);
+		assert_eq!(&written.written[..10], &[7u8; 10][..]);
+		assert!(written.written[10..].iter().all(|b| *b == 1));
+		assert!(!outbox.is_busy());
+
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		assert_eq!(written_types(&written.written[200_010..]), vec![Type::Ping]);
+		assert_eq!(handle.tracker.queued_bytes(), 0);
+	}
+
+	// A block response goes out in chunks, the same bytes as when sent whole,
+	// and counts against the cap until it's written.
+	#[test]
+	fn block_response_streamed() {
+		let (handle, priority_rx, send_rx) = handle(usize::max_value());
+		let version = ProtocolVersion::default();
+		let expected = write_to_buf(&block(1), version, Type::Block).unwrap();
+
+		let mut buf = vec![];
+		let tail = Response::block(block(1), &mut buf)
+			.write(version, false)
+			.unwrap();
+		assert!(buf.len() < STREAM_CHUNK_SIZE);
+		let mut outbox = Outbox::default();
+		let res = outbox.respond(
+			Type::Block,
+			&mut buf,
+			tail,
+			&handle.tracker,
+			usize::max_value(),
+		);
+		assert_eq!(res.unwrap(), Some(expected.len() as u64));
+		assert_eq!(handle.tracker.queued_bytes(), expected.len());
+		handle.send(ping(), Type::Ping).unwrap();
+
+		let mut written = Trickle::new(10_000);
+		loop {
+			match outbox.write(&mut written, &priority_rx, &send_rx, &handle.tracker) {
+				Ok(Written::Msg) => break,
+				Ok(Written::Partly) => assert!(outbox.is_busy()),
+				res => panic!("expected the response written, got {:?}", res),
+			}
+		}
+		assert_eq!(written.written, expected);
+		assert!(!outbox.is_busy());
+
+		drain(&mut written, &handle, &priority_rx, &send_rx);
+		assert_eq!(
+			written_types(&written.written),
+			vec![Type::Block, Type::Ping]
+		);
+		assert_eq!(handle.tracker.queued_bytes(), 0);
+	}
+}
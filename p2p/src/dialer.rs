@@ -0,0 +1,205 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opening the outbound connections to our peers, either directly or through
+//! a SOCKS5 proxy (Tor for instance).
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::types::{Error, PeerAddr};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0;
+const AUTH_PASSWORD: u8 = 2;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const AUTH_PASSWORD_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Opens the connection handed to the handshake when we dial a peer.
+pub trait Dialer: Send + Sync {
+	/// Connects to the peer at the provided address. Returns the connection
+	/// and the address of the peer as we should know it.
+	fn dial(&self, addr: &PeerAddr, timeout: Duration) -> Result<(TcpStream, PeerAddr), Error>;
+}
+
+/// Connects straight to the peer, trying each of the addresses a DNS name
+/// resolves to in turn.
+pub struct Direct;
+
+impl Dialer for Direct {
+	fn dial(&self, addr: &PeerAddr, timeout: Duration) -> Result<(TcpStream, PeerAddr), Error> {
+		let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address to dial");
+		for sock_addr in addr.resolve()? {
+			match TcpStream::connect_timeout(&sock_addr, timeout) {
+				Ok(stream) => return Ok((stream, PeerAddr::Ip(sock_addr))),
+				Err(e) => last_err = e,
+			}
+		}
+		Err(Error::Connection(last_err))
+	}
+}
+
+/// Username and password to authenticate with a SOCKS5 proxy.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Socks5Auth {
+	pub username: String,
+	pub password: String,
+}
+
+/// Connects to peers through a SOCKS5 proxy. Names are resolved by the
+/// proxy, which is what makes onion addresses reachable.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Socks5 {
+	/// Address of the proxy
+	pub proxy_addr: SocketAddr,
+	/// Credentials, if the proxy requires them
+	pub auth: Option<Socks5Auth>,
+}
+
+impl Dialer for Socks5 {
+	fn dial(&self, addr: &PeerAddr, timeout: Duration) -> Result<(TcpStream, PeerAddr), Error> {
+		let mut stream = TcpStream::connect_timeout(&self.proxy_addr, timeout)
+			.map_err(|e| proxy_err(format!("couldn't reach {}: {}", self.proxy_addr, e)))?;
+		stream.set_read_timeout(Some(timeout))?;
+		stream.set_write_timeout(Some(timeout))?;
+
+		self.negotiate_auth(&mut stream)?;
+		connect_request(&mut stream, addr)?;
+
+		stream.set_read_timeout(None)?;
+		stream.set_write_timeout(None)?;
+		Ok((stream, addr.clone()))
+	}
+}
+
+impl Socks5 {
+	fn negotiate_auth(&self, stream: &mut TcpStream) -> Result<(), Error> {
+		let greeting = match self.auth {
+			Some(_) => vec![SOCKS_VERSION, 2, AUTH_NONE, AUTH_PASSWORD],
+			None => vec![SOCKS_VERSION, 1, AUTH_NONE],
+		};
+		stream.write_all(&greeting).map_err(proxy_io_err)?;
+
+		let mut reply = [0u8; 2];
+		stream.read_exact(&mut reply).map_err(proxy_io_err)?;
+		if reply[0] != SOCKS_VERSION {
+			return Err(proxy_err(format!("bad version {}", reply[0])));
+		}
+		match (reply[1], &self.auth) {
+			(AUTH_NONE, _) => Ok(()),
+			(AUTH_PASSWORD, Some(auth)) => {
+				let (user, pass) = (auth.username.as_bytes(), auth.password.as_bytes());
+				if user.is_empty() || user.len() > 255 || pass.len() > 255 {
+					return Err(proxy_err("username or password too long".to_string()));
+				}
+				let mut req = vec![AUTH_PASSWORD_VERSION, user.len() as u8];
+				req.extend_from_slice(user);
+				req.push(pass.len() as u8);
+				req.extend_from_slice(pass);
+				stream.write_all(&req).map_err(proxy_io_err)?;
+
+				let mut reply = [0u8; 2];
+				stream.read_exact(&mut reply).map_err(proxy_io_err)?;
+				if reply[1] != 0 {
+					return Err(proxy_err("authentication refused".to_string()));
+				}
+				Ok(())
+			}
+			(AUTH_UNACCEPTABLE, _) => Err(proxy_err("no acceptable auth method".to_string())),
+			(method, _) => Err(proxy_err(format!("unexpected auth method {}", method))),
+		}
+	}
+}
+
+/// Asks the proxy to connect to the peer and reads its reply, leaving the
+/// stream ready for the handshake.
+fn connect_request(stream: &mut TcpStream, addr: &PeerAddr) -> Result<(), Error> {
+	let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+	let port = match addr {
+		PeerAddr::Ip(sock_addr) => {
+			match sock_addr.ip() {
+				IpAddr::V4(ip) => {
+					req.push(ATYP_IPV4);
+					req.extend_from_slice(&ip.octets());
+				}
+				IpAddr::V6(ip) => {
+					req.push(ATYP_IPV6);
+					req.extend_from_slice(&ip.octets());
+				}
+			}
+			sock_addr.port()
+		}
+		PeerAddr::Dns(host, port) | PeerAddr::Onion(host, port) => {
+			if host.len() > 255 {
+				return Err(proxy_err(format!("host name too long: {}", host)));
+			}
+			req.push(ATYP_DOMAIN);
+			req.push(host.len() as u8);
+			req.extend_from_slice(host.as_bytes());
+			*port
+		}
+	};
+	req.extend_from_slice(&port.to_be_bytes());
+	stream.write_all(&req).map_err(proxy_io_err)?;
+
+	let mut reply = [0u8; 4];
+	stream.read_exact(&mut reply).map_err(proxy_io_err)?;
+	if reply[0] != SOCKS_VERSION {
+		return Err(proxy_err(format!("bad version {}", reply[0])));
+	}
+	match reply[1] {
+		0 => {}
+		// the proxy is fine, the peer is the one we couldn't reach
+		3 | 4 | 5 | 6 => {
+			return Err(Error::Connection(io::Error::new(
+				io::ErrorKind::ConnectionRefused,
+				format!("proxy couldn't reach {} (reply {})", addr, reply[1]),
+			)));
+		}
+		rep => {
+			return Err(proxy_err(format!(
+				"connect to {} failed (reply {})",
+				addr, rep
+			)))
+		}
+	}
+
+	// skip the address the proxy bound, we have no use for it
+	let bound_len = match reply[3] {
+		ATYP_IPV4 => 4,
+		ATYP_IPV6 => 16,
+		ATYP_DOMAIN => {
+			let mut len = [0u8; 1];
+			stream.read_exact(&mut len).map_err(proxy_io_err)?;
+			len[0] as usize
+		}
+		atyp => return Err(proxy_err(format!("unknown address type {}", atyp))),
+	};
+	let mut bound = vec![0u8; bound_len + 2];
+	stream.read_exact(&mut bound).map_err(proxy_io_err)?;
+	Ok(())
+}
+
+fn proxy_err(msg: String) -> Error {
+	Error::Proxy(msg)
+}
+
+fn proxy_io_err(e: io::Error) -> Error {
+	Error::Proxy(e.to_string())
+}
@@ -0,0 +1,209 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What happens to our peers, as it happens, for the applications embedding
+//! our server to follow their peers coming and going without polling the
+//! peer list. Each subscriber gets its own bounded queue, the oldest events
+//! making room for new ones when it's full, so a slow subscriber never holds
+//! up our peers.
+//!
+//! The events of a peer come in the order they happened, the events of
+//! different peers may not.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::msg::DisconnectReason;
+use crate::types::{PeerAddr, PeerInfo, ReasonForBan};
+use crate::util::{Condvar, Mutex, RwLock};
+
+/// Something that happened to one of our peers.
+#[derive(Clone, Debug)]
+pub struct PeerEvent {
+	pub addr: PeerAddr,
+	pub kind: PeerEventKind,
+}
+
+/// What happened to a peer, see `PeerEvent`.
+#[derive(Clone, Debug)]
+pub enum PeerEventKind {
+	/// Through the handshake and one of our peers now.
+	Connected(PeerInfo),
+	/// Not one of our peers anymore, with the reason we closed the connection
+	/// in good order. `DisconnectReason::None` when we didn't: the connection
+	/// dropped, or we dropped the peer for misbehaving or being banned.
+	Disconnected(DisconnectReason),
+	/// Banned, until the provided time (in seconds), connected or not.
+	Banned(ReasonForBan, i64),
+	/// Back to healthy in our store, its ban or discouragement over.
+	Healthy,
+	/// Stopped answering our pings, about to be dropped.
+	Unresponsive,
+}
+
+// The events of a subscriber not taken yet, at most `capacity` of them,
+// along with how many were dropped to make room for newer ones.
+struct Pending {
+	events: VecDeque<PeerEvent>,
+	dropped: u64,
+}
+
+struct Queue {
+	pending: Mutex<Pending>,
+	ready: Condvar,
+	capacity: usize,
+}
+
+/// Our end of a subscription to the events of our peers, see
+/// `Server::subscribe`. Dropping it ends the subscription.
+pub struct PeerEventReceiver {
+	queue: Arc<Queue>,
+}
+
+impl PeerEventReceiver {
+	/// The oldest event not taken yet, if any.
+	pub fn try_recv(&self) -> Option<PeerEvent> {
+		self.queue.pending.lock().events.pop_front()
+	}
+
+	/// The oldest event not taken yet, waiting for one for at most the
+	/// provided time.
+	pub fn recv_timeout(&self, timeout: Duration) -> Option<PeerEvent> {
+		let deadline = Instant::now() + timeout;
+		let mut pending = self.queue.pending.lock();
+		loop {
+			if let Some(event) = pending.events.pop_front() {
+				return Some(event);
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				return None;
+			}
+			self.queue.ready.wait_for(&mut pending, deadline - now);
+		}
+	}
+
+	/// How many events were dropped to make room for newer ones, since we
+	/// subscribed.
+	pub fn dropped(&self) -> u64 {
+		self.queue.pending.lock().dropped
+	}
+}
+
+/// Hands the events of our peers out to all the subscribers.
+pub struct PeerEvents {
+	subscribers: RwLock<Vec<Arc<Queue>>>,
+}
+
+impl PeerEvents {
+	pub fn new() -> PeerEvents {
+		PeerEvents {
+			subscribers: RwLock::new(vec![]),
+		}
+	}
+
+	/// A new subscriber, keeping up to `capacity` events it didn't take yet.
+	pub fn subscribe(&self, capacity: usize) -> PeerEventReceiver {
+		let queue = Arc::new(Queue {
+			pending: Mutex::new(Pending {
+				events: VecDeque::new(),
+				dropped: 0,
+			}),
+			ready: Condvar::new(),
+			capacity: capacity.max(1),
+		});
+		self.subscribers.write().push(queue.clone());
+		PeerEventReceiver { queue }
+	}
+
+	/// Queues the event for all the subscribers, forgetting about the ones
+	/// that went away. Never blocks on a subscriber.
+	pub fn emit(&self, addr: &PeerAddr, kind: PeerEventKind) {
+		let mut subscribers = self.subscribers.write();
+		if subscribers.is_empty() {
+			return;
+		}
+		subscribers.retain(|q| Arc::strong_count(q) > 1);
+		for queue in subscribers.iter() {
+			let mut pending = queue.pending.lock();
+			if pending.events.len() >= queue.capacity {
+				pending.events.pop_front();
+				pending.dropped += 1;
+			}
+			pending.events.push_back(PeerEvent {
+				addr: addr.clone(),
+				kind: kind.clone(),
+			});
+			queue.ready.notify_one();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn addr(port: u16) -> PeerAddr {
+		PeerAddr::Ip(format!("10.0.0.1:{}", port).parse().unwrap())
+	}
+
+	#[test]
+	fn drop_oldest() {
+		let events = PeerEvents::new();
+		let rx = events.subscribe(2);
+		for port in 1..4 {
+			events.emit(&addr(port), PeerEventKind::Healthy);
+		}
+		assert_eq!(rx.dropped(), 1);
+		assert_eq!(rx.try_recv().unwrap().addr, addr(2));
+		assert_eq!(rx.try_recv().unwrap().addr, addr(3));
+		assert!(rx.try_recv().is_none());
+		assert!(rx.recv_timeout(Duration::from_millis(10)).is_none());
+	}
+
+	#[test]
+	fn subscribers_apart() {
+		let events = PeerEvents::new();
+		let early = events.subscribe(10);
+		events.emit(&addr(1), PeerEventKind::Unresponsive);
+		let late = events.subscribe(10);
+		events.emit(&addr(2), PeerEventKind::Healthy);
+
+		assert_eq!(early.try_recv().unwrap().addr, addr(1));
+		assert_eq!(early.try_recv().unwrap().addr, addr(2));
+		assert_eq!(late.try_recv().unwrap().addr, addr(2));
+		assert!(late.try_recv().is_none());
+
+		// gone, forgotten
+		drop(late);
+		events.emit(&addr(3), PeerEventKind::Healthy);
+		assert_eq!(events.subscribers.read().len(), 1);
+		assert_eq!(early.try_recv().unwrap().addr, addr(3));
+	}
+
+	#[test]
+	fn recv_waits() {
+		let events = Arc::new(PeerEvents::new());
+		let rx = events.subscribe(10);
+		let emitter = events.clone();
+		let t = std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_millis(50));
+			emitter.emit(&addr(1), PeerEventKind::Healthy);
+		});
+		let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert_eq!(event.addr, addr(1));
+		t.join().unwrap();
+	}
+}
@@ -16,115 +16,364 @@ use crate::core::core::hash::Hash;
 use crate::core::pow::Difficulty;
 use crate::msg::{read_message, write_message, Hand, ProtocolVersion, Shake, Type, USER_AGENT};
 use crate::peer::Peer;
-use crate::types::{Capabilities, Direction, Error, P2PConfig, PeerAddr, PeerInfo, PeerLiveInfo};
-use crate::util::RwLock;
-use rand::{thread_rng, Rng};
-use std::collections::VecDeque;
-use std::net::{SocketAddr, TcpStream};
+use crate::transport::SessionKeys;
+use crate::types::{
+	canonical_addr, is_routable_ip, subnet, Capabilities, Direction, Error, IpRange, NodeId,
+	P2PConfig, PeerAddr, PeerInfo, PeerLimits, PeerLiveInfo, ProtocolObserver, SelfAddrs,
+};
+use crate::util::{Mutex, RwLock};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Local generated nonce for peer connecting.
 /// Used for self-connecting detection (on receiver side),
 /// nonce(s) in recent 100 connecting requests are saved
 const NONCES_CAP: usize = 100;
-/// Socket addresses of self, extracted from stream when a self-connecting is detected.
-/// Used in connecting request to avoid self-connecting request,
-/// 10 should be enough since most of servers don't have more than 10 IP addresses.
-const ADDRS_CAP: usize = 10;
+/// How long a generated nonce is kept around for self-connecting detection.
+/// Should comfortably exceed the time a Hand takes to reach us back through
+/// a slow NAT path.
+const NONCES_TTL: Duration = Duration::from_secs(300);
+/// Highest total difficulty a chain made of only its genesis block can have
+/// (mainnet genesis difficulty).
+const MAX_GENESIS_DIFFICULTY: u64 = 1 << 34;
+/// How many distinct peers have to claim our node id before we believe
+/// another node shares it, any single one could be echoing ours back at us.
+const ID_COLLISION_PEERS: usize = 3;
+/// How long a claim of our node id counts, we also pick a new id at most
+/// once in that long.
+const ID_COLLISION_WINDOW: Duration = Duration::from_secs(60 * 60);
 
 /// Handles the handshake negotiation when two peers connect and decides on
 /// protocol.
 pub struct Handshake {
 	/// Ring buffer of nonces sent to detect self connections without requiring
 	/// a node id.
-	nonces: Arc<RwLock<VecDeque<u64>>>,
-	/// Ring buffer of self addr(s) collected from PeerWithSelf detection (by nonce).
-	pub addrs: Arc<RwLock<VecDeque<PeerAddr>>>,
+	nonces: Arc<RwLock<NonceCache>>,
+	/// Self addr(s) collected from PeerWithSelf detection (by nonce) or from
+	/// peers reaching us at the address we advertise.
+	pub addrs: Arc<SelfAddrs>,
+	/// The limits on our connections, shared with our peers (see
+	/// `Server::update_limits`).
+	pub limits: Arc<RwLock<PeerLimits>>,
 	/// The genesis block header of the chain seen by this node.
 	/// We only want to connect to other nodes seeing the same chain (forks are
 	/// ok).
 	genesis: Hash,
 	config: P2PConfig,
+	/// Source of randomness for our nonces, lazily initialized to the OS rng
+	/// unless one is provided.
+	rng: Mutex<Option<Box<dyn RngCore + Send>>>,
+	/// Number of handshakes currently in progress.
+	in_flight: AtomicUsize,
+	/// Outcome counters of all handshakes, both directions.
+	stats: HandshakeStats,
+	/// Our persistent node id, sent in both hand and shake.
+	node_id: RwLock<NodeId>,
+	/// Connections of the handshakes in progress, shut down on cancel.
+	pending: Mutex<PendingConns>,
+	/// Watches the msgs exchanged with the peers we connect to, if set.
+	observer: RwLock<Option<Arc<dyn ProtocolObserver>>>,
+	/// Our inbound connections by ip and network, see `admit`.
+	inbound: Arc<Mutex<InboundCounts>>,
+	/// The peers recently claiming our node id, see `id_collision`.
+	id_collisions: Mutex<IdCollisions>,
 }
 
 impl Handshake {
 	/// Creates a new handshake handler
 	pub fn new(genesis: Hash, config: P2PConfig) -> Handshake {
+		Handshake::with_config(genesis, config, NONCES_CAP, NONCES_TTL)
+	}
+
+	/// Creates a new handshake handler, keeping at most `nonces_cap` of our
+	/// recent nonces for at most `nonces_ttl` each.
+	pub fn with_config(
+		genesis: Hash,
+		config: P2PConfig,
+		nonces_cap: usize,
+		nonces_ttl: Duration,
+	) -> Handshake {
 		Handshake {
-			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
-			addrs: Arc::new(RwLock::new(VecDeque::with_capacity(ADDRS_CAP))),
+			nonces: Arc::new(RwLock::new(NonceCache::new(nonces_cap, nonces_ttl))),
+			addrs: Arc::new(SelfAddrs::new()),
+			limits: Arc::new(RwLock::new(config.limits())),
 			genesis,
 			config,
+			rng: Mutex::new(None),
+			in_flight: AtomicUsize::new(0),
+			stats: HandshakeStats::default(),
+			node_id: RwLock::new(NodeId::random()),
+			pending: Mutex::new(PendingConns::default()),
+			observer: RwLock::new(None),
+			inbound: Arc::new(Mutex::new(InboundCounts::default())),
+			id_collisions: Mutex::new(IdCollisions::default()),
 		}
 	}
 
-	pub fn initiate(
+	/// Creates a new handshake handler generating its nonces with the
+	/// provided rng (mostly to make tests deterministic).
+	pub fn with_rng<R>(genesis: Hash, config: P2PConfig, rng: R) -> Handshake
+	where
+		R: RngCore + Send + 'static,
+	{
+		let hs = Handshake::new(genesis, config);
+		*hs.rng.lock() = Some(Box::new(rng));
+		hs
+	}
+
+	pub fn initiate<T>(
 		&self,
 		capab: Capabilities,
 		total_difficulty: Difficulty,
+		height: u64,
 		self_addr: PeerAddr,
-		conn: &mut TcpStream,
-	) -> Result<PeerInfo, Error> {
-		// prepare the first part of the handshake
-		let nonce = self.next_nonce();
-		let peer_addr = match conn.peer_addr() {
-			Ok(pa) => PeerAddr(pa),
-			Err(e) => return Err(Error::Connection(e)),
+		peer_addr: PeerAddr,
+		conn: &mut T,
+		is_banned: &dyn Fn(&PeerAddr) -> bool,
+	) -> Result<PeerInfo, Error>
+	where
+		T: HandshakeConn,
+	{
+		let res = match self.try_initiate(
+			capab,
+			total_difficulty,
+			height,
+			self_addr,
+			peer_addr,
+			conn,
+			is_banned,
+		) {
+			// whatever state the connection was left in, it's not handed out
+			_ if self.is_cancelled() => Err(Error::Cancelled),
+			res => res,
 		};
+		self.stats.record(&res);
+		res
+	}
+
+	fn try_initiate<T>(
+		&self,
+		capab: Capabilities,
+		total_difficulty: Difficulty,
+		height: u64,
+		self_addr: PeerAddr,
+		peer_addr: PeerAddr,
+		conn: &mut T,
+		is_banned: &dyn Fn(&PeerAddr) -> bool,
+	) -> Result<PeerInfo, Error>
+	where
+		T: HandshakeConn,
+	{
+		if is_banned(&peer_addr) {
+			debug!("initiate: peer {} is banned, not sending hand", peer_addr);
+			return Err(Error::Banned);
+		}
+
+		let _slot = match self.try_begin() {
+			Some(slot) => slot,
+			None => {
+				debug!(
+					"initiate: too many handshakes in progress, not dialing {}",
+					peer_addr
+				);
+				return Err(Error::TooManyHandshakes);
+			}
+		};
+		let _pending = self.track(conn)?;
+
+		// prepare the first part of the handshake
+		let nonce = self.next_nonce()?;
+		let our_node_id = self.node_id();
 
 		let hand = Hand {
 			version: ProtocolVersion::default(),
+			min_version: ProtocolVersion::min_supported(),
 			capabilities: capab,
 			nonce: nonce,
 			genesis: self.genesis,
 			total_difficulty: total_difficulty,
+			height: Some(height),
 			sender_addr: self_addr,
-			receiver_addr: peer_addr,
+			receiver_addr: peer_addr.clone(),
 			user_agent: USER_AGENT.to_string(),
+			node_id: Some(our_node_id),
+			extensions: vec![],
 		};
 
-		// write and read the handshake response
-		write_message(conn, hand, Type::Hand)?;
-		let shake: Shake = read_message(conn, Type::Shake)?;
+		// write and read the handshake response, all within the handshake deadline
+		let (shake, rtt, bytes): (Shake, Duration, (u64, u64)) = {
+			let mut stream = DeadlineStream::new(&mut *conn, self.config.handshake_timeout());
+			let start = Instant::now();
+			write_message(&mut stream, hand, ProtocolVersion::handshake(), Type::Hand)
+				.map_err(timeout_err)?;
+			let shake = read_message(&mut stream, ProtocolVersion::handshake(), Type::Shake)
+				.map_err(timeout_err)?;
+			(shake, start.elapsed(), (stream.sent, stream.received))
+		};
+		reset_timeouts(conn)?;
+
+		// the responder echoes our nonce back, if it instead echoes another nonce
+		// we generated we reached ourselves via some other route (peers running
+		// version 1 don't echo it)
+		if let Some(echoed) = shake.nonce {
+			if echoed != nonce {
+				if self.nonces.read().contains(&echoed) {
+					self.push_addr(peer_addr);
+					return Err(Error::PeerWithSelf);
+				}
+				debug!(
+					"initiate: nonce mismatch from {}, sent {}, received {}",
+					peer_addr, nonce, echoed
+				);
+				return Err(Error::BadMessage);
+			}
+		}
+
+		// we would have refused our own hand, so whoever answers with our id
+		// is another node sharing it, or pretending to
+		if shake.node_id == Some(self.node_id()) {
+			return Err(self.id_collision(&peer_addr));
+		}
+
 		if shake.genesis != self.genesis {
 			return Err(Error::GenesisMismatch {
 				us: self.genesis,
 				peer: shake.genesis,
 			});
 		}
+		if let Some(height) = shake.height {
+			check_chain_state(height, shake.total_difficulty)?;
+		}
+		let version = negotiate_version(
+			(ProtocolVersion::min_supported(), ProtocolVersion::default()),
+			(shake.min_version, shake.version),
+		)?;
+		let mut live_info = PeerLiveInfo::new(shake.total_difficulty, shake.height.unwrap_or(0));
+		live_info.handshake_rtt = Some(rtt);
+		live_info.handshake_bytes = bytes;
 		let peer_info = PeerInfo {
 			capabilities: shake.capabilities,
+			negotiated: negotiate_capabilities(capab, shake.capabilities),
 			user_agent: shake.user_agent,
 			addr: peer_addr,
-			version: shake.version,
-			live_info: Arc::new(RwLock::new(PeerLiveInfo::new(shake.total_difficulty))),
+			version,
+			live_info: Arc::new(RwLock::new(live_info)),
 			direction: Direction::Outbound,
+			our_addr_as_seen: shake.observed_addr,
+			node_id: shake.node_id,
+			our_node_id,
+			shake_sent: None,
+			inbound_slot: None,
 		};
 
 		// If denied then we want to close the connection
 		// (without providing our peer with any details why).
-		if Peer::is_denied(&self.config, peer_info.addr) {
+		if Peer::is_denied(&self.config, &peer_info.addr) {
 			return Err(Error::ConnectionClose);
 		}
 
 		debug!(
-			"Connected! Cumulative {} offered from {:?} {:?} {:?}",
+			"Connected! Cumulative {} at height {} offered from {:?} {:?} {:?}, protocol version {}",
 			shake.total_difficulty.to_num(),
+			shake.height.unwrap_or(0),
 			peer_info.addr,
 			peer_info.user_agent,
-			peer_info.capabilities
+			peer_info.capabilities,
+			peer_info.version,
 		);
-		// when more than one protocol version is supported, choosing should go here
 		Ok(peer_info)
 	}
 
-	pub fn accept(
+	/// Accepts the handshake of a peer that connected to us, from `peer_addr`
+	/// when it's known.
+	pub fn accept<T>(
 		&self,
 		capab: Capabilities,
 		total_difficulty: Difficulty,
-		conn: &mut TcpStream,
-	) -> Result<PeerInfo, Error> {
-		let hand: Hand = read_message(conn, Type::Hand)?;
+		height: u64,
+		peer_addr: Option<SocketAddr>,
+		conn: &mut T,
+		is_banned: &dyn Fn(&PeerAddr) -> bool,
+	) -> Result<PeerInfo, Error>
+	where
+		T: HandshakeConn,
+	{
+		let res = match self.try_accept(capab, total_difficulty, height, peer_addr, conn, is_banned)
+		{
+			_ if self.is_cancelled() => Err(Error::Cancelled),
+			res => res,
+		};
+		self.stats.record(&res);
+		res
+	}
+
+	fn try_accept<T>(
+		&self,
+		capab: Capabilities,
+		total_difficulty: Difficulty,
+		height: u64,
+		peer_addr: Option<SocketAddr>,
+		conn: &mut T,
+		is_banned: &dyn Fn(&PeerAddr) -> bool,
+	) -> Result<PeerInfo, Error>
+	where
+		T: HandshakeConn,
+	{
+		// refuse banned peers as early as possible, before reading anything
+		if let Some(addr) = peer_addr {
+			if is_banned(&PeerAddr::Ip(addr)) {
+				debug!("accept: peer {} is banned, dropping", addr);
+				return Err(Error::Banned);
+			}
+		}
+		// same for hosts and networks holding as many of our inbound
+		// connections as we give them
+		let inbound_slot = match peer_addr {
+			Some(addr) => self.admit(&addr)?,
+			None => None,
+		};
+
+		let _slot = match self.try_begin() {
+			Some(slot) => slot,
+			None => {
+				debug!(
+					"accept: too many handshakes in progress, dropping {:?}",
+					peer_addr
+				);
+				return Err(Error::TooManyHandshakes);
+			}
+		};
+		let _pending = self.track(conn)?;
+
+		let mut stream = DeadlineStream::new(&mut *conn, self.config.handshake_timeout());
+		let hand: Hand = read_message(&mut stream, ProtocolVersion::handshake(), Type::Hand)
+			.map_err(timeout_err)?;
+
+		// the peer may advertise an address other than the one it connected
+		// from, check again before we send our shake
+		let addr = resolve_peer_addr(&hand.sender_addr, peer_addr);
+		if !addr.is_routable() {
+			// most likely a port of 0, we can't fix that one
+			debug!(
+				"accept: peer {} advertised unroutable {}, won't gossip it",
+				addr, hand.sender_addr
+			);
+		}
+		if is_banned(&addr) || is_banned(&hand.sender_addr) {
+			debug!(
+				"accept: peer {} (advertised {}) is banned, dropping",
+				addr, hand.sender_addr
+			);
+			return Err(Error::Banned);
+		}
 
 		// all the reasons we could refuse this connection for
 		if hand.genesis != self.genesis {
@@ -133,73 +382,1213 @@ impl Handshake {
 				peer: hand.genesis,
 			});
 		} else {
-			// check the nonce to see if we are trying to connect to ourselves
-			let nonces = self.nonces.read();
-			let addr = resolve_peer_addr(hand.sender_addr, &conn);
-			if nonces.contains(&hand.nonce) {
+			// check the node id and the nonce to see if we are trying to connect
+			// to ourselves, the nonce alone for older peers not sending an id
+			let our_nonce = self.nonces.read().contains(&hand.nonce);
+			let our_id = hand.node_id == Some(self.node_id());
+			if our_id && !our_nonce {
+				// our own hands always carry a nonce we still hold, this is
+				// another node using our id, told apart from the others by where
+				// it connected from rather than what it advertises
+				let from = peer_addr.map(PeerAddr::Ip).unwrap_or_else(|| addr.clone());
+				return Err(self.id_collision(&from));
+			}
+			if our_id || our_nonce {
 				// save ip addresses of ourselves
-				let mut addrs = self.addrs.write();
-				addrs.push_back(addr);
-				if addrs.len() >= ADDRS_CAP {
-					addrs.pop_front();
-				}
+				self.push_addr(addr);
 				return Err(Error::PeerWithSelf);
 			}
 		}
+		if let Some(height) = hand.height {
+			check_chain_state(height, hand.total_difficulty)?;
+		}
+
+		// the peer reached us at the address we advertise in our own hands,
+		// remember it so we don't dial it once it gets back to us via gossip
+		let advertised = self.addrs.advertise_addr(&self.config);
+		if advertised
+			.ip_addr()
+			.map_or(true, |addr| !addr.ip().is_unspecified())
+			&& hand.receiver_addr == advertised
+			&& !self.addrs.contains(&hand.receiver_addr)
+		{
+			self.push_addr(hand.receiver_addr.clone());
+		}
+
+		let version = negotiate_version(
+			(ProtocolVersion::min_supported(), ProtocolVersion::default()),
+			(hand.min_version, hand.version),
+		)?;
 
 		// all good, keep peer info
-		let peer_info = PeerInfo {
+		let our_node_id = self.node_id();
+		let mut peer_info = PeerInfo {
 			capabilities: hand.capabilities,
+			negotiated: negotiate_capabilities(capab, hand.capabilities),
 			user_agent: hand.user_agent,
-			addr: resolve_peer_addr(hand.sender_addr, &conn),
-			version: hand.version,
-			live_info: Arc::new(RwLock::new(PeerLiveInfo::new(hand.total_difficulty))),
+			addr: addr.clone(),
+			version,
+			live_info: Arc::new(RwLock::new(PeerLiveInfo::new(
+				hand.total_difficulty,
+				hand.height.unwrap_or(0),
+			))),
 			direction: Direction::Inbound,
+			our_addr_as_seen: None,
+			node_id: hand.node_id,
+			our_node_id,
+			shake_sent: None,
+			inbound_slot,
 		};
 
 		// At this point we know the published ip and port of the peer
 		// so check if we are configured to explicitly allow or deny it.
 		// If denied then we want to close the connection
 		// (without providing our peer with any details why).
-		if Peer::is_denied(&self.config, peer_info.addr) {
+		if Peer::is_denied(&self.config, &peer_info.addr) {
 			return Err(Error::ConnectionClose);
 		}
 
 		// send our reply with our info
 		let shake = Shake {
-			version: ProtocolVersion::default(),
+			version,
+			min_version: ProtocolVersion::min_supported(),
 			capabilities: capab,
+			nonce: Some(hand.nonce),
 			genesis: self.genesis,
 			total_difficulty: total_difficulty,
+			height: Some(height),
+			observed_addr: Some(addr),
 			user_agent: USER_AGENT.to_string(),
+			node_id: Some(our_node_id),
+			extensions: vec![],
 		};
 
-		write_message(conn, shake, Type::Shake)?;
-		trace!("Success handshake with {}.", peer_info.addr);
+		write_message(
+			&mut stream,
+			shake,
+			ProtocolVersion::handshake(),
+			Type::Shake,
+		)
+		.map_err(timeout_err)?;
+		peer_info.shake_sent = Some(Instant::now());
+		peer_info.live_info.write().handshake_bytes = (stream.sent, stream.received);
+		reset_timeouts(conn)?;
+		trace!(
+			"Success handshake with {}, protocol version {}.",
+			peer_info.addr,
+			peer_info.version
+		);
 
-		// when more than one protocol version is supported, choosing should go here
 		Ok(peer_info)
 	}
 
+	/// The config peers are set up with once connected.
+	pub fn config(&self) -> &P2PConfig {
+		&self.config
+	}
+
+	/// The observer peers are set up with once connected, if any.
+	pub fn observer(&self) -> Option<Arc<dyn ProtocolObserver>> {
+		self.observer.read().clone()
+	}
+
+	/// Sets the observer of the peers connecting from now on.
+	pub fn set_observer(&self, observer: Option<Arc<dyn ProtocolObserver>>) {
+		*self.observer.write() = observer;
+	}
+
+	/// Number of handshakes currently in progress.
+	pub fn in_flight(&self) -> usize {
+		self.in_flight.load(Ordering::SeqCst)
+	}
+
+	/// Our node id.
+	pub fn node_id(&self) -> NodeId {
+		*self.node_id.read()
+	}
+
+	/// Sets our node id, usually the one persisted from a previous run.
+	pub fn set_node_id(&self, node_id: NodeId) {
+		*self.node_id.write() = node_id;
+	}
+
+	/// The peer claims our node id, the connection is dropped. We only pick a
+	/// new id once a few distinct peers did (most likely nodes with a data
+	/// directory copied from ours), and not more than once in a while.
+	fn id_collision(&self, peer_addr: &PeerAddr) -> Error {
+		if !self.id_collisions.lock().claimed(peer_addr) {
+			debug!("Peer {} uses our node id {}.", peer_addr, self.node_id());
+			return Error::NodeIdCollision;
+		}
+		let node_id = NodeId::random();
+		error!(
+			"Peers like {} use our node id {}, was the data directory copied? Switching to {}.",
+			peer_addr,
+			self.node_id(),
+			node_id
+		);
+		self.set_node_id(node_id);
+		Error::NodeIdCollision
+	}
+
+	/// Sets up the encrypted transport right after the Shake, if both sides
+	/// advertised it. Shares the handshake timeout.
+	pub(crate) fn encrypt<T>(
+		&self,
+		info: &PeerInfo,
+		conn: &mut T,
+	) -> Result<Option<SessionKeys>, Error>
+	where
+		T: HandshakeConn,
+	{
+		if !info.negotiated.contains(Capabilities::ENCRYPTED) {
+			return Ok(None);
+		}
+		let _pending = self.track(conn)?;
+		let keys = {
+			let mut stream = DeadlineStream::new(&mut *conn, self.config.handshake_timeout());
+			let keys = SessionKeys::exchange(&mut stream, info.direction).map_err(timeout_err)?;
+			let mut live_info = info.live_info.write();
+			live_info.handshake_bytes.0 += stream.sent;
+			live_info.handshake_bytes.1 += stream.received;
+			keys
+		};
+		reset_timeouts(conn)?;
+		debug!("encrypt: encrypted transport with {}", info.addr);
+		Ok(Some(keys))
+	}
+
+	/// Snapshot of the handshake outcome counters.
+	pub fn stats(&self) -> HandshakeCounts {
+		self.stats.snapshot()
+	}
+
+	/// Interrupts all the handshakes in progress by shutting their connection
+	/// down, they (and any handshake started later) fail with
+	/// `Error::Cancelled`.
+	pub fn cancel(&self) {
+		let mut pending = self.pending.lock();
+		pending.cancelled = true;
+		debug!("cancel: interrupting {} handshakes", pending.conns.len());
+		for shutdown in pending.conns.values() {
+			shutdown();
+		}
+	}
+
+	/// Whether we've been cancelled (shutting down).
+	pub fn is_cancelled(&self) -> bool {
+		self.pending.lock().cancelled
+	}
+
+	/// Keeps track of the connection of a handshake in progress so it can be
+	/// cancelled, until the returned guard is dropped.
+	fn track<T: HandshakeConn>(&self, conn: &T) -> Result<PendingConn<'_>, Error> {
+		let mut pending = self.pending.lock();
+		if pending.cancelled {
+			return Err(Error::Cancelled);
+		}
+		let id = pending.next_id;
+		pending.next_id += 1;
+		pending.conns.insert(id, conn.shutdown_handle()?);
+		Ok(PendingConn {
+			pending: &self.pending,
+			id,
+		})
+	}
+
+	/// Reserves one of the handshake slots, if any is left. The slot is
+	/// released when the returned guard is dropped, whichever way the
+	/// handshake ends.
+	fn try_begin(&self) -> Option<HandshakeSlot<'_>> {
+		let max = self.config.max_inflight_handshakes();
+		let mut current = self.in_flight.load(Ordering::SeqCst);
+		loop {
+			if current >= max {
+				return None;
+			}
+			match self.in_flight.compare_exchange(
+				current,
+				current + 1,
+				Ordering::SeqCst,
+				Ordering::SeqCst,
+			) {
+				Ok(_) => return Some(HandshakeSlot(&self.in_flight)),
+				Err(actual) => current = actual,
+			}
+		}
+	}
+
+	/// Counts one more inbound connection from the address, unless we hold as
+	/// many from its ip or its network (see `subnet`) as we take already.
+	/// Loopback and exempt ranges are neither limited nor counted. The
+	/// connection is counted until the returned slot is dropped.
+	fn admit(&self, addr: &SocketAddr) -> Result<Option<Arc<InboundSlot>>, Error> {
+		let ip = canonical_addr(addr).ip();
+		if self.config.inbound_limit_exempt(&ip) {
+			return Ok(None);
+		}
+		let net = subnet(&ip);
+		let limits = *self.limits.read();
+		let mut counts = self.inbound.lock();
+		let from_ip = counts.by_ip.get(&ip).cloned().unwrap_or(0);
+		if from_ip >= limits.max_inbound_per_ip as usize {
+			info!(
+				"accept: {} inbound connections from {} already, refusing",
+				from_ip, ip
+			);
+			return Err(Error::InboundLimit(ip.to_string()));
+		}
+		let from_net = counts.by_subnet.get(&net).cloned().unwrap_or(0);
+		if from_net >= limits.max_inbound_per_subnet as usize {
+			info!(
+				"accept: {} inbound connections from {} already, refusing {}",
+				from_net, net, ip
+			);
+			return Err(Error::InboundLimit(net.to_string()));
+		}
+		*counts.by_ip.entry(ip).or_insert(0) += 1;
+		*counts.by_subnet.entry(net).or_insert(0) += 1;
+		Ok(Some(Arc::new(InboundSlot {
+			counts: self.inbound.clone(),
+			ip,
+			net,
+		})))
+	}
+
+	/// How many inbound connections we hold from the ip, and from its
+	/// network.
+	pub fn inbound_from(&self, ip: &IpAddr) -> (usize, usize) {
+		let ip = canonical_addr(&SocketAddr::new(*ip, 0)).ip();
+		let counts = self.inbound.lock();
+		(
+			counts.by_ip.get(&ip).cloned().unwrap_or(0),
+			counts.by_subnet.get(&subnet(&ip)).cloned().unwrap_or(0),
+		)
+	}
+
+	/// Save one of our own addresses (detected via self connection) so we
+	/// stop dialing it
+	fn push_addr(&self, addr: PeerAddr) {
+		debug!("push_addr: {} is one of our addresses", addr);
+		self.addrs.insert(addr);
+	}
+
 	/// Generate a new random nonce and store it in our ring buffer
-	fn next_nonce(&self) -> u64 {
-		let nonce = thread_rng().gen();
+	fn next_nonce(&self) -> Result<u64, Error> {
+		let mut rng = self.rng.lock();
+		if rng.is_none() {
+			match OsRng::new() {
+				Ok(os_rng) => *rng = Some(Box::new(os_rng)),
+				Err(e) => {
+					error!("next_nonce: failed to initialize rng: {:?}", e);
+					return Err(Error::Internal);
+				}
+			}
+		}
+		let nonce = match rng.as_mut() {
+			Some(rng) => rng.next_u64(),
+			None => return Err(Error::Internal),
+		};
+		self.nonces.write().insert(nonce);
+		Ok(nonce)
+	}
+}
+
+/// A reserved handshake slot, released on drop.
+struct HandshakeSlot<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for HandshakeSlot<'a> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Our inbound connections by ip and by network.
+#[derive(Default)]
+struct InboundCounts {
+	by_ip: HashMap<IpAddr, usize>,
+	by_subnet: HashMap<IpRange, usize>,
+}
+
+/// One of our inbound connections, counted against the limits of its ip and
+/// network until dropped (along with the last copy of the peer info holding
+/// it, see `PeerInfo::inbound_slot`).
+pub struct InboundSlot {
+	counts: Arc<Mutex<InboundCounts>>,
+	ip: IpAddr,
+	net: IpRange,
+}
+
+impl fmt::Debug for InboundSlot {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "InboundSlot({})", self.ip)
+	}
+}
+
+impl Drop for InboundSlot {
+	fn drop(&mut self) {
+		let mut counts = self.counts.lock();
+		let gone = match counts.by_ip.get_mut(&self.ip) {
+			Some(n) => {
+				*n = n.saturating_sub(1);
+				*n == 0
+			}
+			None => false,
+		};
+		if gone {
+			counts.by_ip.remove(&self.ip);
+		}
+		let gone = match counts.by_subnet.get_mut(&self.net) {
+			Some(n) => {
+				*n = n.saturating_sub(1);
+				*n == 0
+			}
+			None => false,
+		};
+		if gone {
+			counts.by_subnet.remove(&self.net);
+		}
+	}
+}
+
+/// Connections of the handshakes in progress.
+#[derive(Default)]
+struct PendingConns {
+	cancelled: bool,
+	next_id: u64,
+	conns: HashMap<u64, ShutdownHandle>,
+}
+
+/// A tracked handshake connection, forgotten on drop.
+struct PendingConn<'a> {
+	pending: &'a Mutex<PendingConns>,
+	id: u64,
+}
+
+impl<'a> Drop for PendingConn<'a> {
+	fn drop(&mut self) {
+		self.pending.lock().conns.remove(&self.id);
+	}
+}
+
+/// Counters of handshake outcomes, to tell why a node struggles to find peers.
+#[derive(Default)]
+struct HandshakeStats {
+	attempts: AtomicUsize,
+	successes: AtomicUsize,
+	timeouts: AtomicUsize,
+	version_mismatches: AtomicUsize,
+	self_connections: AtomicUsize,
+	banned: AtomicUsize,
+	serialization_errors: AtomicUsize,
+	io_errors: AtomicUsize,
+	other_errors: AtomicUsize,
+}
+
+impl HandshakeStats {
+	fn record(&self, res: &Result<PeerInfo, Error>) {
+		self.attempts.fetch_add(1, Ordering::Relaxed);
+		let counter = match res {
+			Ok(_) => &self.successes,
+			Err(Error::Timeout) => &self.timeouts,
+			Err(Error::ProtocolMismatch { .. }) => &self.version_mismatches,
+			Err(Error::PeerWithSelf) => &self.self_connections,
+			Err(Error::Banned) => &self.banned,
+			Err(Error::Serialization(_)) | Err(Error::MsgLen) => &self.serialization_errors,
+			Err(Error::Connection(_)) => &self.io_errors,
+			Err(_) => &self.other_errors,
+		};
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> HandshakeCounts {
+		HandshakeCounts {
+			attempts: self.attempts.load(Ordering::Relaxed),
+			successes: self.successes.load(Ordering::Relaxed),
+			timeouts: self.timeouts.load(Ordering::Relaxed),
+			version_mismatches: self.version_mismatches.load(Ordering::Relaxed),
+			self_connections: self.self_connections.load(Ordering::Relaxed),
+			banned: self.banned.load(Ordering::Relaxed),
+			serialization_errors: self.serialization_errors.load(Ordering::Relaxed),
+			io_errors: self.io_errors.load(Ordering::Relaxed),
+			other_errors: self.other_errors.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Snapshot of the handshake outcome counters, inbound and outbound together.
+/// Every attempt is counted exactly once, either as a success or as one of the
+/// failure classes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HandshakeCounts {
+	pub attempts: usize,
+	pub successes: usize,
+	pub timeouts: usize,
+	/// No protocol version in common
+	pub version_mismatches: usize,
+	/// Reached ourselves (nonce check)
+	pub self_connections: usize,
+	pub banned: usize,
+	/// Malformed or truncated hand or shake
+	pub serialization_errors: usize,
+	/// Connection errors other than timeouts
+	pub io_errors: usize,
+	/// Genesis mismatch, too many handshakes, implausible chain and so on
+	pub other_errors: usize,
+}
+
+/// The distinct peers claiming our node id within ID_COLLISION_WINDOW, and
+/// when we last picked a new one because of them.
+#[derive(Default)]
+struct IdCollisions {
+	peers: HashMap<String, Instant>,
+	regenerated: Option<Instant>,
+}
+
+impl IdCollisions {
+	/// Counts a claim from the peer, returns whether enough distinct peers made
+	/// one to pick a new id. Claims are ignored for a while after that.
+	fn claimed(&mut self, peer_addr: &PeerAddr) -> bool {
+		if let Some(at) = self.regenerated {
+			if at.elapsed() < ID_COLLISION_WINDOW {
+				return false;
+			}
+		}
+		self.peers
+			.retain(|_, at| at.elapsed() < ID_COLLISION_WINDOW);
+		self.peers.insert(peer_addr.as_key(), Instant::now());
+		if self.peers.len() < ID_COLLISION_PEERS {
+			return false;
+		}
+		self.peers.clear();
+		self.regenerated = Some(Instant::now());
+		true
+	}
+}
+
+/// Nonces we recently sent, evicted both once we hold more than the capacity
+/// and once they are older than the ttl.
+struct NonceCache {
+	nonces: VecDeque<(u64, Instant)>,
+	cap: usize,
+	ttl: Duration,
+}
+
+impl NonceCache {
+	fn new(cap: usize, ttl: Duration) -> NonceCache {
+		NonceCache {
+			nonces: VecDeque::with_capacity(cap),
+			cap,
+			ttl,
+		}
+	}
+
+	/// Add a nonce, pruning expired entries and the oldest ones over capacity.
+	fn insert(&mut self, nonce: u64) {
+		self.prune();
+		self.nonces.push_back((nonce, Instant::now()));
+		while self.nonces.len() > self.cap {
+			self.nonces.pop_front();
+		}
+	}
+
+	fn contains(&self, nonce: &u64) -> bool {
+		self.nonces
+			.iter()
+			.any(|(n, t)| n == nonce && t.elapsed() < self.ttl)
+	}
+
+	fn prune(&mut self) {
+		while let Some(&(_, t)) = self.nonces.front() {
+			if t.elapsed() < self.ttl {
+				break;
+			}
+			self.nonces.pop_front();
+		}
+	}
+}
+
+/// Negotiate the protocol version to use with a peer given the (min, max)
+/// range of versions supported by each side. We pick the highest version
+/// supported by both, failing if the ranges do not overlap.
+pub fn negotiate_version(
+	ours: (ProtocolVersion, ProtocolVersion),
+	theirs: (ProtocolVersion, ProtocolVersion),
+) -> Result<ProtocolVersion, Error> {
+	let version = cmp::min(ours.1, theirs.1);
+	if version < ours.0 || version < theirs.0 {
+		return Err(Error::ProtocolMismatch { ours, theirs });
+	}
+	Ok(version)
+}
 
-		let mut nonces = self.nonces.write();
-		nonces.push_back(nonce);
-		if nonces.len() >= NONCES_CAP {
-			nonces.pop_front();
+/// Sanity check of the chain state claimed by a peer. Every block adds at
+/// least a difficulty of 1 so the total difficulty can't be lower than the
+/// height, and a chain of only a genesis block can't have accumulated more
+/// than a genesis difficulty.
+pub fn check_chain_state(height: u64, total_difficulty: Difficulty) -> Result<(), Error> {
+	let diff = total_difficulty.to_num();
+	if diff < height || (height == 0 && diff > MAX_GENESIS_DIFFICULTY) {
+		return Err(Error::ImplausibleChain {
+			height,
+			total_difficulty,
+		});
+	}
+	Ok(())
+}
+
+/// Capabilities both sides support, bits unknown to us are masked out.
+pub fn negotiate_capabilities(ours: Capabilities, theirs: Capabilities) -> Capabilities {
+	ours & theirs & Capabilities::all()
+}
+
+/// Resolve the correct peer_addr based on the address the peer connected from
+/// and the advertised address. DNS names and onion addresses are kept as
+/// advertised.
+fn resolve_peer_addr(advertised: &PeerAddr, peer_addr: Option<SocketAddr>) -> PeerAddr {
+	match (advertised, peer_addr) {
+		(PeerAddr::Ip(advertised), Some(actual)) => {
+			PeerAddr::Ip(resolve_advertised(advertised, &actual))
 		}
-		nonce
+		_ => advertised.clone(),
 	}
 }
 
-/// Resolve the correct peer_addr based on the connection and the advertised port.
-fn resolve_peer_addr(advertised: PeerAddr, conn: &TcpStream) -> PeerAddr {
-	let port = advertised.0.port();
-	if let Ok(addr) = conn.peer_addr() {
-		PeerAddr(SocketAddr::new(addr.ip(), port))
+/// Resolve the address a peer can be reached at from the address it
+/// advertised and the address it actually connected from. The advertised port
+/// is always trusted. The advertised IP is trusted unless it's unroutable (see
+/// `is_routable_ip`) or loopback, or it's private or link-local while the peer
+/// connected from a globally routable IP, in which case it's unroutable for
+/// everyone else and we use the connection IP instead.
+pub fn resolve_advertised(advertised: &SocketAddr, actual_peer: &SocketAddr) -> SocketAddr {
+	let ip = advertised.ip();
+	let substitute =
+		!is_routable_ip(&ip) || ip.is_loopback() || (is_local(&ip) && is_global(&actual_peer.ip()));
+	if substitute {
+		SocketAddr::new(actual_peer.ip(), advertised.port())
 	} else {
-		advertised
+		*advertised
+	}
+}
+
+/// Private or link-local address, only reachable from the local network.
+fn is_local(ip: &IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+		IpAddr::V6(ip) => {
+			let first = ip.segments()[0];
+			// link-local fe80::/10 and unique local fc00::/7
+			(first & 0xffc0) == 0xfe80 || (first & 0xfe00) == 0xfc00
+		}
+	}
+}
+
+/// Whether the address can be reached from anywhere on the internet.
+fn is_global(ip: &IpAddr) -> bool {
+	let special = match ip {
+		IpAddr::V4(ip) => ip.is_broadcast() || ip.is_documentation(),
+		IpAddr::V6(ip) => ip.is_multicast(),
+	};
+	!(ip.is_unspecified() || ip.is_loopback() || is_local(ip) || special)
+}
+
+/// A connection the handshake can run over, a TcpStream outside of tests.
+/// Besides reading and writing, the handshake bounds its reads and writes with
+/// its deadline and shuts the connection down from another thread to cancel.
+pub trait HandshakeConn: Read + Write {
+	/// Sets the read and write timeouts, `None` blocks indefinitely.
+	fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+	/// A handle shutting the connection down when called, unblocking any
+	/// pending read or write.
+	fn shutdown_handle(&self) -> io::Result<ShutdownHandle>;
+}
+
+/// Shuts a connection down, see `HandshakeConn::shutdown_handle`.
+pub type ShutdownHandle = Box<dyn Fn() + Send>;
+
+impl HandshakeConn for TcpStream {
+	fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+		self.set_read_timeout(timeout)?;
+		self.set_write_timeout(timeout)
+	}
+
+	fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+		let conn = self.try_clone()?;
+		Ok(Box::new(move || {
+			let _ = conn.shutdown(Shutdown::Both);
+		}))
+	}
+}
+
+/// Wraps the connection during the handshake so every read and write is bounded
+/// by the remaining time until the handshake deadline. A peer that stalls
+/// (or trickles bytes) cannot hold the connection open past the deadline.
+/// Also counts the bytes going through, they're part of what we exchange with
+/// the peer.
+struct DeadlineStream<'a, T> {
+	conn: &'a mut T,
+	deadline: Instant,
+	sent: u64,
+	received: u64,
+}
+
+impl<'a, T: HandshakeConn> DeadlineStream<'a, T> {
+	fn new(conn: &'a mut T, timeout: std::time::Duration) -> DeadlineStream<'a, T> {
+		DeadlineStream {
+			conn,
+			deadline: Instant::now() + timeout,
+			sent: 0,
+			received: 0,
+		}
+	}
+
+	fn remaining(&self) -> io::Result<std::time::Duration> {
+		let now = Instant::now();
+		if now >= self.deadline {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, "handshake"));
+		}
+		Ok(self.deadline - now)
+	}
+}
+
+// A blocking socket with a timeout set reports expiry as WouldBlock (or
+// TimedOut depending on platform), we want all of them to be a timeout.
+fn deadline_err(e: io::Error) -> io::Error {
+	match e.kind() {
+		io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "handshake"),
+		_ => e,
+	}
+}
+
+impl<'a, T: HandshakeConn> Read for DeadlineStream<'a, T> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let remaining = self.remaining()?;
+		self.conn.set_timeout(Some(remaining))?;
+		let n = self.conn.read(buf).map_err(deadline_err)?;
+		self.received += n as u64;
+		Ok(n)
+	}
+}
+
+impl<'a, T: HandshakeConn> Write for DeadlineStream<'a, T> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let remaining = self.remaining()?;
+		self.conn.set_timeout(Some(remaining))?;
+		let n = self.conn.write(buf).map_err(deadline_err)?;
+		self.sent += n as u64;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.conn.flush()
+	}
+}
+
+/// Surface an expired handshake deadline as a timeout.
+fn timeout_err(e: Error) -> Error {
+	match e {
+		Error::Connection(ref e) if e.kind() == io::ErrorKind::TimedOut => Error::Timeout,
+		e => e,
+	}
+}
+
+/// Clear the socket timeouts set during the handshake, the connection
+/// handles its own timeouts from here on.
+fn reset_timeouts<T: HandshakeConn>(conn: &mut T) -> Result<(), Error> {
+	conn.set_timeout(None)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::msg::{write_to_buf, MsgHeader};
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+	use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+	use std::thread;
+
+	// One end of an in-memory duplex pipe. Every write is a chunk read by the
+	// other end, an empty chunk marks the end of the stream.
+	struct PipeEnd {
+		tx: Sender<Vec<u8>>,
+		rx: Receiver<Vec<u8>>,
+		// sends into our own rx, to shut us down
+		wake: Sender<Vec<u8>>,
+		buf: Vec<u8>,
+		pos: usize,
+		timeout: Option<Duration>,
+	}
+
+	fn pipe() -> (PipeEnd, PipeEnd) {
+		let (tx_a, rx_b) = channel();
+		let (tx_b, rx_a) = channel();
+		let end = |tx: Sender<Vec<u8>>, rx, wake| PipeEnd {
+			tx,
+			rx,
+			wake,
+			buf: vec![],
+			pos: 0,
+			timeout: None,
+		};
+		let a = end(tx_a.clone(), rx_a, tx_b.clone());
+		let b = end(tx_b, rx_b, tx_a);
+		(a, b)
+	}
+
+	impl Read for PipeEnd {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			if self.pos == self.buf.len() {
+				let chunk = match self.timeout {
+					Some(timeout) => self.rx.recv_timeout(timeout),
+					None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+				};
+				match chunk {
+					Ok(ref chunk) if chunk.is_empty() => return Ok(0),
+					Ok(chunk) => {
+						self.buf = chunk;
+						self.pos = 0;
+					}
+					Err(RecvTimeoutError::Disconnected) => return Ok(0),
+					Err(RecvTimeoutError::Timeout) => {
+						return Err(io::ErrorKind::WouldBlock.into());
+					}
+				}
+			}
+			let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+			buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+			self.pos += n;
+			Ok(n)
+		}
+	}
+
+	impl Write for PipeEnd {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if buf.is_empty() {
+				return Ok(0);
+			}
+			self.tx
+				.send(buf.to_vec())
+				.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Drop for PipeEnd {
+		fn drop(&mut self) {
+			let _ = self.tx.send(vec![]);
+		}
+	}
+
+	impl HandshakeConn for PipeEnd {
+		fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+			self.timeout = timeout;
+			Ok(())
+		}
+
+		fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+			let wake = self.wake.clone();
+			Ok(Box::new(move || {
+				let _ = wake.send(vec![]);
+			}))
+		}
+	}
+
+	fn peer_sock_addr() -> SocketAddr {
+		"10.0.0.1:3414".parse().unwrap()
+	}
+
+	fn initiate_over(hs: &Handshake, conn: &mut PipeEnd) -> Result<PeerInfo, Error> {
+		hs.initiate(
+			Capabilities::UNKNOWN,
+			Difficulty::min(),
+			0,
+			PeerAddr::Ip(peer_sock_addr()),
+			PeerAddr::Ip("10.0.0.2:3414".parse().unwrap()),
+			conn,
+			&|_| false,
+		)
+	}
+
+	fn accept_over(
+		hs: Handshake,
+		mut conn: PipeEnd,
+	) -> thread::JoinHandle<Result<PeerInfo, Error>> {
+		thread::spawn(move || {
+			hs.accept(
+				Capabilities::UNKNOWN,
+				Difficulty::min(),
+				0,
+				Some("10.0.0.1:55123".parse().unwrap()),
+				&mut conn,
+				&|_| false,
+			)
+		})
+	}
+
+	// Plays the responder by hand, replying to the hand with the shake
+	// returned by `reply`, serialized and cut to `len` bytes if provided.
+	fn fake_responder<F>(mut conn: PipeEnd, reply: F, len: Option<usize>) -> thread::JoinHandle<()>
+	where
+		F: FnOnce(Shake) -> Shake + Send + 'static,
+	{
+		thread::spawn(move || {
+			let hand: Hand =
+				read_message(&mut conn, ProtocolVersion::handshake(), Type::Hand).unwrap();
+			let shake = Shake {
+				version: hand.version,
+				min_version: hand.min_version,
+				capabilities: Capabilities::UNKNOWN,
+				nonce: Some(hand.nonce),
+				genesis: hand.genesis,
+				total_difficulty: Difficulty::min(),
+				height: Some(0),
+				observed_addr: Some(hand.sender_addr),
+				user_agent: USER_AGENT.to_string(),
+				node_id: Some(NodeId::random()),
+				extensions: vec![],
+			};
+			let mut buf =
+				write_to_buf(reply(shake), ProtocolVersion::handshake(), Type::Shake).unwrap();
+			if let Some(len) = len {
+				buf.truncate(len);
+			}
+			conn.write_all(&buf).unwrap();
+		})
+	}
+
+	#[test]
+	fn pipe_handshake() {
+		let (mut a, b) = pipe();
+		let server = accept_over(Handshake::new(Hash::default(), P2PConfig::default()), b);
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+
+		let info = initiate_over(&client, &mut a).unwrap();
+		assert_eq!(info.direction, Direction::Outbound);
+		assert_eq!(info.addr, PeerAddr::Ip("10.0.0.2:3414".parse().unwrap()));
+		// private advertised address from a private one, kept as is
+		assert_eq!(info.our_addr_as_seen, Some(PeerAddr::Ip(peer_sock_addr())));
+
+		let info = server.join().unwrap().unwrap();
+		assert_eq!(info.direction, Direction::Inbound);
+		assert_eq!(info.addr, PeerAddr::Ip(peer_sock_addr()));
+		assert_eq!(client.stats().successes, 1);
+	}
+
+	// Handshakes with `server` from the provided address, client being our
+	// usual initiator.
+	fn accept_from(
+		server: &Arc<Handshake>,
+		client: &Handshake,
+		from: &str,
+	) -> Result<PeerInfo, Error> {
+		let (mut a, mut b) = pipe();
+		let server = server.clone();
+		let from = from.parse().unwrap();
+		let accepted = thread::spawn(move || {
+			server.accept(
+				Capabilities::UNKNOWN,
+				Difficulty::min(),
+				0,
+				Some(from),
+				&mut b,
+				&|_| false,
+			)
+		});
+		let _ = initiate_over(client, &mut a);
+		accepted.join().unwrap()
+	}
+
+	fn refused_for(res: Result<PeerInfo, Error>) -> String {
+		match res {
+			Err(Error::InboundLimit(prefix)) => prefix,
+			res => panic!("expected inbound limit, got {:?}", res.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn pipe_inbound_limits() {
+		let config = P2PConfig {
+			max_inbound_per_ip: Some(2),
+			max_inbound_per_subnet: Some(3),
+			..P2PConfig::default()
+		};
+		let server = Arc::new(Handshake::new(Hash::default(), config));
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+
+		let mut accepted = vec![];
+		for _ in 0..2 {
+			accepted.push(accept_from(&server, &client, "10.0.0.1:5000").unwrap());
+		}
+		let res = accept_from(&server, &client, "10.0.0.1:5000");
+		assert_eq!(refused_for(res), "10.0.0.1");
+
+		// same /24, the subnet fills up next
+		accepted.push(accept_from(&server, &client, "10.0.0.2:5000").unwrap());
+		let res = accept_from(&server, &client, "10.0.0.3:5000");
+		assert_eq!(refused_for(res), "10.0.0.0/24");
+		let res = accept_from(&server, &client, "[::ffff:10.0.0.3]:5000");
+		assert_eq!(refused_for(res), "10.0.0.0/24");
+		accept_from(&server, &client, "10.0.1.1:5000").unwrap();
+		assert_eq!(server.inbound_from(&"10.0.0.1".parse().unwrap()), (2, 3));
+
+		// a connection gone leaves room for another
+		accepted.remove(0);
+		assert_eq!(server.inbound_from(&"10.0.0.1".parse().unwrap()), (1, 2));
+		accepted.push(accept_from(&server, &client, "10.0.0.3:5000").unwrap());
+		assert_eq!(server.inbound_from(&"10.0.0.3".parse().unwrap()), (1, 3));
+
+		// loopback isn't limited
+		for _ in 0..3 {
+			accepted.push(accept_from(&server, &client, "127.0.0.1:5000").unwrap());
+		}
+		accepted.clear();
+		assert_eq!(server.inbound_from(&"10.0.0.1".parse().unwrap()), (0, 0));
+
+		// nor are the exempt ranges
+		let config = P2PConfig {
+			max_inbound_per_ip: Some(1),
+			inbound_limit_exempt: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+			..P2PConfig::default()
+		};
+		let server = Arc::new(Handshake::new(Hash::default(), config));
+		for _ in 0..3 {
+			accepted.push(accept_from(&server, &client, "10.0.0.1:5000").unwrap());
+		}
+		accepted.push(accept_from(&server, &client, "11.0.0.1:5000").unwrap());
+		let res = accept_from(&server, &client, "11.0.0.1:5000");
+		assert_eq!(refused_for(res), "11.0.0.1");
+	}
+
+	#[test]
+	fn pipe_bad_version() {
+		let (mut a, b) = pipe();
+		let responder = fake_responder(
+			b,
+			|shake| Shake {
+				version: ProtocolVersion(99),
+				min_version: ProtocolVersion(99),
+				..shake
+			},
+			None,
+		);
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+		match initiate_over(&client, &mut a) {
+			Err(Error::ProtocolMismatch { theirs, .. }) => {
+				assert_eq!(theirs, (ProtocolVersion(99), ProtocolVersion(99)));
+			}
+			res => panic!("expected protocol mismatch, got {:?}", res),
+		}
+		responder.join().unwrap();
+		assert_eq!(client.stats().version_mismatches, 1);
+	}
+
+	#[test]
+	fn pipe_bad_nonce() {
+		let (mut a, b) = pipe();
+		let responder = fake_responder(
+			b,
+			|shake| Shake {
+				nonce: shake.nonce.map(|n| n.wrapping_add(1)),
+				..shake
+			},
+			None,
+		);
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+		match initiate_over(&client, &mut a) {
+			Err(Error::BadMessage) => {}
+			res => panic!("expected bad message, got {:?}", res),
+		}
+		responder.join().unwrap();
+	}
+
+	// A responder running version 1 picks it and answers with the Shake of
+	// version 1, no nonce, height or observed address in it.
+	#[test]
+	fn pipe_v1_responder() {
+		let (mut a, b) = pipe();
+		let responder = fake_responder(
+			b,
+			|shake| Shake {
+				version: ProtocolVersion(1),
+				..shake
+			},
+			None,
+		);
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+		let info = initiate_over(&client, &mut a).unwrap();
+		assert_eq!(info.version, ProtocolVersion(1));
+		assert_eq!(info.our_addr_as_seen, None);
+		responder.join().unwrap();
+	}
+
+	#[test]
+	fn pipe_truncated_shake() {
+		let (mut a, b) = pipe();
+		// the header and a few bytes of the body, then the stream ends
+		let responder = fake_responder(b, |shake| shake, Some(MsgHeader::LEN + 8));
+		let client = Handshake::new(Hash::default(), P2PConfig::default());
+		match initiate_over(&client, &mut a) {
+			Err(Error::Connection(_)) => {}
+			res => panic!("expected connection error, got {:?}", res),
+		}
+		responder.join().unwrap();
+		assert_eq!(client.stats().io_errors, 1);
+	}
+
+	#[test]
+	fn pipe_garbage_bytes() {
+		let (mut a, b) = pipe();
+		let server = accept_over(Handshake::new(Hash::default(), P2PConfig::default()), b);
+		a.write_all(b"GET / HTTP/1.1\r\nHost: grin\r\n\r\n")
+			.unwrap();
+		match server.join().unwrap() {
+			Err(Error::WrongNetwork) => {}
+			res => panic!("expected wrong network, got {:?}", res),
+		}
+	}
+
+	fn resolve(advertised: &str, actual: &str) -> SocketAddr {
+		resolve_advertised(&advertised.parse().unwrap(), &actual.parse().unwrap())
+	}
+
+	#[test]
+	fn resolve_advertised_addr() {
+		// loopback and unspecified, use the connection ip with advertised port
+		assert_eq!(
+			resolve("127.0.0.1:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("0.0.0.0:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("[::]:3414", "[2001:db8::1]:55123"),
+			"[2001:db8::1]:3414".parse().unwrap()
+		);
+
+		// private over public, use the connection ip
+		assert_eq!(
+			resolve("192.168.1.10:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("10.0.0.2:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+
+		// private over private, we're on the same network, trust it
+		assert_eq!(
+			resolve("10.0.0.2:3414", "10.0.0.3:55123"),
+			"10.0.0.2:3414".parse().unwrap()
+		);
+
+		// public over public, trust the advertised address
+		assert_eq!(
+			resolve("5.6.7.8:3414", "1.2.3.4:55123"),
+			"5.6.7.8:3414".parse().unwrap()
+		);
+
+		// ipv6 link-local over public
+		assert_eq!(
+			resolve("[fe80::1]:3414", "[2a00:1450::1]:55123"),
+			"[2a00:1450::1]:3414".parse().unwrap()
+		);
+
+		// unroutable, use the connection ip, whatever it is
+		assert_eq!(
+			resolve("224.0.0.1:3414", "10.0.0.3:55123"),
+			"10.0.0.3:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("255.255.255.255:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("192.0.2.1:3414", "1.2.3.4:55123"),
+			"1.2.3.4:3414".parse().unwrap()
+		);
+		assert_eq!(
+			resolve("[ff02::1]:3414", "[2a00:1450::1]:55123"),
+			"[2a00:1450::1]:3414".parse().unwrap()
+		);
+
+		// a port of 0 can't be fixed, the address stays unroutable
+		assert_eq!(
+			resolve("1.2.3.4:0", "1.2.3.4:55123"),
+			"1.2.3.4:0".parse().unwrap()
+		);
+	}
+
+	#[test]
+	fn seeded_nonces() {
+		let hs = Handshake::with_rng(
+			Hash::default(),
+			P2PConfig::default(),
+			StdRng::seed_from_u64(42),
+		);
+		let mut expected = StdRng::seed_from_u64(42);
+
+		let n1 = hs.next_nonce().unwrap();
+		let n2 = hs.next_nonce().unwrap();
+		assert_eq!(n1, expected.next_u64());
+		assert_eq!(n2, expected.next_u64());
+
+		// both nonces are remembered for self connection detection
+		assert!(hs.nonces.read().contains(&n1));
+		assert!(hs.nonces.read().contains(&n2));
+		assert!(!hs.nonces.read().contains(&expected.next_u64()));
+	}
+
+	#[test]
+	fn nonce_cache_capacity() {
+		let mut cache = NonceCache::new(2, Duration::from_secs(60));
+		cache.insert(1);
+		cache.insert(2);
+		assert!(cache.contains(&1));
+		cache.insert(3);
+		assert!(!cache.contains(&1));
+		assert!(cache.contains(&2));
+		assert!(cache.contains(&3));
+		assert_eq!(cache.nonces.len(), 2);
+	}
+
+	#[test]
+	fn nonce_cache_ttl() {
+		let mut cache = NonceCache::new(10, Duration::from_millis(50));
+		cache.insert(1);
+		assert!(cache.contains(&1));
+		thread::sleep(Duration::from_millis(100));
+		assert!(!cache.contains(&1));
+
+		// expired entries are pruned on insert
+		cache.insert(2);
+		assert_eq!(cache.nonces.len(), 1);
+		assert!(cache.contains(&2));
+	}
+
+	#[test]
+	fn id_collisions_distinct_peers() {
+		let peer = |ip: &str| PeerAddr::Ip(format!("{}:3414", ip).parse().unwrap());
+		let mut collisions = IdCollisions::default();
+
+		// the same peer over and over is only one claim
+		for _ in 0..10 {
+			assert!(!collisions.claimed(&peer("1.2.3.4")));
+		}
+		assert!(!collisions.claimed(&peer("5.6.7.8")));
+		assert!(collisions.claimed(&peer("9.10.11.12")));
+
+		// and not again for a while, whoever claims it
+		for i in 0..10 {
+			assert!(!collisions.claimed(&peer(&format!("10.0.0.{}", i))));
+		}
+		assert!(collisions.peers.is_empty());
 	}
 }
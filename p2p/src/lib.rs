@@ -25,6 +25,8 @@ extern crate bitflags;
 
 #[macro_use]
 extern crate enum_primitive;
+#[macro_use]
+extern crate lazy_static;
 
 #[macro_use]
 extern crate grin_core as core;
@@ -37,21 +39,32 @@ extern crate serde_derive;
 extern crate log;
 
 mod conn;
+pub mod dialer;
+mod events;
 pub mod handshake;
 pub mod msg;
 mod peer;
 mod peers;
+pub mod portmap;
 mod protocol;
+pub mod seeds;
 mod serv;
 mod store;
+mod transport;
 pub mod types;
 
-pub use crate::conn::SEND_CHANNEL_CAP;
+pub use crate::conn::{MAX_UNKNOWN_MSGS_PER_MIN, PRIORITY_CHANNEL_CAP, SEND_CHANNEL_CAP};
+pub use crate::events::{PeerEvent, PeerEventKind, PeerEventReceiver};
 pub use crate::peer::Peer;
-pub use crate::peers::Peers;
-pub use crate::serv::{DummyAdapter, Server};
-pub use crate::store::{PeerData, State};
+pub use crate::peers::{Attempt, Peers, PeersImport, Sweep};
+pub use crate::protocol::{
+	PendingRequest, Protocol, RequestTracker, Requested, MAX_DROPPED_MSGS_PER_MIN,
+};
+pub use crate::serv::{DummyAdapter, Server, Stopping};
+pub use crate::store::{PeerData, PeerStore, SelfAddr, State, StoreStats};
 pub use crate::types::{
-	Capabilities, ChainAdapter, Direction, Error, P2PConfig, PeerAddr, PeerInfo, ReasonForBan,
-	Seeding, TxHashSetRead, MAX_BLOCK_HEADERS, MAX_LOCATORS, MAX_PEER_ADDRS,
+	BandwidthStats, Capabilities, ChainAdapter, Direction, Error, InboundEviction, NoopObserver,
+	Offense, P2PConfig, PeerAddr, PeerCounts, PeerInfo, PeerLimits, PeerStats, ProtocolObserver,
+	RateLimit, ReasonForBan, Seeding, TxHashSetRead, MAX_BLOCK_HEADERS, MAX_LOCATORS,
+	MAX_PEER_ADDRS,
 };
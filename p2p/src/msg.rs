@@ -14,20 +14,28 @@
 
 //! Message types that transit over the network and related serialization code.
 
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use num::FromPrimitive;
-use std::fmt;
-use std::io::{Read, Write};
-use std::time;
+use ring::digest;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+use std::{cmp, fmt, mem, thread, time};
 
 use crate::core::core::hash::Hash;
-use crate::core::core::BlockHeader;
+use crate::core::core::{BlockHeader, OutputIdentifier, TxKernelEntry};
 use crate::core::pow::Difficulty;
 use crate::core::ser::{self, FixedLength, Readable, Reader, StreamingReader, Writeable, Writer};
 use crate::core::{consensus, global};
 use crate::types::{
-	Capabilities, Error, PeerAddr, ReasonForBan, MAX_BLOCK_HEADERS, MAX_LOCATORS, MAX_PEER_ADDRS,
+	Capabilities, Error, NodeId, PeerAddr, ReasonForBan, MAX_BLOCK_HEADERS, MAX_HOST_LEN,
+	MAX_LOCATORS, MAX_PEER_ADDRS,
 };
-use crate::util::read_write::read_exact;
+use crate::util::read_write::{read_exact, write_all};
+use crate::util::secp::pedersen::RangeProof;
+use crate::util::Mutex;
 
 /// Our local node protocol version.
 /// We will increment the protocol version with every change to p2p msg serialization
@@ -37,15 +45,62 @@ use crate::util::read_write::read_exact;
 /// Note: A peer may disconnect and reconnect with an updated protocol version. Normally
 /// the protocol version will increase but we need to handle decreasing values also
 /// as a peer may rollback to previous version of the code.
-const PROTOCOL_VERSION: u32 = 1;
+///
+/// Version 2 adds a checksum of the body to msg headers.
+/// Version 3 moves the height and node id of the Shake to its extensions.
+const PROTOCOL_VERSION: u32 = 3;
+
+/// The oldest protocol version we are still able to speak. Peers advertise
+/// the range of versions they support during the handshake and we pick the
+/// highest version supported by both sides.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Hand and Shake are exchanged before any version is negotiated, their
+/// headers are always the ones of version 1 so older peers can read them.
+const HANDSHAKE_PROTOCOL_VERSION: u32 = 1;
+
+/// First protocol version with a checksum in msg headers.
+const CHECKSUM_PROTOCOL_VERSION: u32 = 2;
+
+/// First protocol version sending the height and node id of the Shake as
+/// extensions.
+const EXTENSIONS_PROTOCOL_VERSION: u32 = 3;
+
+/// Length of the body checksum in msg headers.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Size of the pieces large msg bodies are written and read in.
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Max number of msg serialization buffers kept around for reuse.
+pub const MAX_POOLED_BUFS: usize = 16;
+
+/// Buffers that grew larger than this are dropped after use rather than
+/// pooled, so we don't hold on to block sized allocations forever.
+pub const MAX_POOLED_BUF_CAPACITY: usize = 256 * 1024;
+
+/// Flag bit of the type byte of msg headers, set when the body is compressed.
+/// Only ever set on connections where both sides advertised
+/// `Capabilities::COMPRESSION`, to anyone else it's a msg type they don't know.
+pub const COMPRESSED_FLAG: u8 = 0b1000_0000;
+
+/// Bodies smaller than this go uncompressed, not worth it.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How long a peer gets to send us the whole body of a msg once its header
+/// arrived, however large the body.
+pub const BODY_TIMEOUT: time::Duration = time::Duration::from_secs(60);
 
 /// Grin's user agent with current version
 pub const USER_AGENT: &'static str = concat!("MW/Grin ", env!("CARGO_PKG_VERSION"));
 
+/// Maximum length (in bytes) of the user agent exchanged during the handshake
+pub const MAX_USER_AGENT_LEN: usize = 128;
+
 /// Magic numbers expected in the header of every message
-const OTHER_MAGIC: [u8; 2] = [73, 43];
-const FLOONET_MAGIC: [u8; 2] = [83, 59];
-const MAINNET_MAGIC: [u8; 2] = [97, 61];
+pub const OTHER_MAGIC: [u8; 2] = [73, 43];
+pub const FLOONET_MAGIC: [u8; 2] = [83, 59];
+pub const MAINNET_MAGIC: [u8; 2] = [97, 61];
 
 // Types of messages.
 // Note: Values here are *important* so we should only add new values at the
@@ -76,12 +131,31 @@ enum_from_primitive! {
 		TransactionKernel = 20,
 		KernelDataRequest = 21,
 		KernelDataResponse = 22,
+		GetBlockByHeight = 23,
+		PeerError = 24,
+		BlockInv = 25,
+		TransactionNotFound = 26,
+		Disconnect = 27,
 	}
 }
 
-/// Max theoretical size of a block filled with outputs.
-fn max_block_size() -> u64 {
-	(global::max_block_weight() / consensus::BLOCK_OUTPUT_WEIGHT * 708) as u64
+/// Max theoretical size of a block. Whatever mix of inputs, outputs and
+/// kernels fills it, a block can't be larger than its max weight times the
+/// largest serialized size per unit of weight.
+pub fn max_block_size() -> u64 {
+	let per_weight = |size: usize, weight: usize| ((size + weight - 1) / weight) as u64;
+	let max_per_weight = cmp::max(
+		per_weight(OutputIdentifier::LEN, consensus::BLOCK_INPUT_WEIGHT),
+		cmp::max(
+			per_weight(
+				OutputIdentifier::LEN + RangeProof::LEN,
+				consensus::BLOCK_OUTPUT_WEIGHT,
+			),
+			per_weight(TxKernelEntry::LEN, consensus::BLOCK_KERNEL_WEIGHT),
+		),
+	);
+	// the header, then the inputs, outputs and kernels counts
+	max_msg_size(Type::Header) + 3 * 8 + global::max_block_weight() as u64 * max_per_weight
 }
 
 // Max msg size when msg type is unknown.
@@ -89,16 +163,41 @@ fn default_max_msg_size() -> u64 {
 	max_block_size()
 }
 
+// Max size of a serialized peer address (tag, length prefixed host, port).
+const MAX_PEER_ADDR_SIZE: u64 = 1 + 8 + MAX_HOST_LEN as u64 + 2;
+
+// Max size of a serialized user agent (length prefix and bytes).
+const MAX_USER_AGENT_SIZE: u64 = 8 + MAX_USER_AGENT_LEN as u64;
+
+// Size of the fixed fields of the Hand: version, capabilities, nonce, total
+// difficulty and genesis, then the extensions.
+const HAND_FIXED_SIZE: u64 = 4 + 4 + 8 + 8 + 32 + MAX_EXTENSIONS_LEN as u64;
+
+// Size of the fixed fields of the Shake, at its largest in version 2:
+// versions, capabilities, nonce, total difficulty, height, genesis and node
+// id, then the extensions.
+const SHAKE_FIXED_SIZE: u64 = 4 + 4 + 4 + 8 + 8 + 8 + 32 + 16 + MAX_EXTENSIONS_LEN as u64;
+
+// Max msg length accepted in a header for each msg type. Hand and Shake are
+// fully bounded so they get no slack, other limits are 4x for now to leave
+// ourselves space to change things.
+fn max_msg_len(msg_type: Type) -> u64 {
+	match msg_type {
+		Type::Hand | Type::Shake => max_msg_size(msg_type),
+		_ => max_msg_size(msg_type) * 4,
+	}
+}
+
 // Max msg size for each msg type.
 fn max_msg_size(msg_type: Type) -> u64 {
 	match msg_type {
 		Type::Error => 0,
-		Type::Hand => 128,
-		Type::Shake => 88,
-		Type::Ping => 16,
-		Type::Pong => 16,
+		Type::Hand => HAND_FIXED_SIZE + MAX_USER_AGENT_SIZE + 2 * MAX_PEER_ADDR_SIZE,
+		Type::Shake => SHAKE_FIXED_SIZE + MAX_USER_AGENT_SIZE + MAX_PEER_ADDR_SIZE,
+		Type::Ping => 24,
+		Type::Pong => 24,
 		Type::GetPeerAddrs => 4,
-		Type::PeerAddrs => 4 + (1 + 16 + 2) * MAX_PEER_ADDRS as u64,
+		Type::PeerAddrs => 4 + MAX_PEER_ADDR_SIZE * MAX_PEER_ADDRS as u64,
 		Type::GetHeaders => 1 + 32 * MAX_LOCATORS as u64,
 		Type::Header => 365,
 		Type::Headers => 2 + 365 * MAX_BLOCK_HEADERS as u64,
@@ -115,10 +214,53 @@ fn max_msg_size(msg_type: Type) -> u64 {
 		Type::TransactionKernel => 32,
 		Type::KernelDataRequest => 0,
 		Type::KernelDataResponse => 8,
+		Type::GetBlockByHeight => 8,
+		Type::PeerError => 4 + 8 + MAX_PEER_ERROR_LEN as u64,
+		Type::BlockInv => 40,
+		Type::TransactionNotFound => 32,
+		Type::Disconnect => 4,
 	}
 }
 
-fn magic() -> [u8; 2] {
+// Whether a body of this type and length is worth compressing. Blocks and txs
+// are mostly commitments, proofs and signatures, there's nothing to gain.
+fn worth_compressing(msg_type: Type, len: usize) -> bool {
+	match msg_type {
+		Type::Block | Type::CompactBlock | Type::Transaction | Type::StemTransaction => false,
+		_ => len >= COMPRESSION_THRESHOLD,
+	}
+}
+
+/// Whether a msg can go unsent when the peer doesn't read fast enough, we push
+/// those without being asked and the peer can do without: pings and
+/// announcements. The others are requests the peer is expected to answer.
+pub fn is_droppable(msg_type: Type) -> bool {
+	match msg_type {
+		Type::Ping
+		| Type::Pong
+		| Type::Header
+		| Type::BlockInv
+		| Type::Block
+		| Type::CompactBlock
+		| Type::TransactionKernel
+		| Type::Transaction
+		| Type::StemTransaction
+		| Type::PeerAddrs => true,
+		_ => false,
+	}
+}
+
+/// Whether msgs of this type are large enough to be written out in chunks
+/// with `write_streamed` rather than serialized in memory first.
+pub fn is_streamed(msg_type: Type) -> bool {
+	match msg_type {
+		Type::Block => true,
+		_ => false,
+	}
+}
+
+/// Magic number of the network we're running on.
+pub fn magic() -> [u8; 2] {
 	match *global::CHAIN_TYPE.read() {
 		global::ChainTypes::Floonet => FLOONET_MAGIC,
 		global::ChainTypes::Mainnet => MAINNET_MAGIC,
@@ -132,17 +274,48 @@ fn magic() -> [u8; 2] {
 ///
 /// Note: We return a MsgHeaderWrapper here as we may encounter an unknown msg type.
 ///
+/// The magic number is checked before anything else so a peer from another
+/// network (or a stream that got out of sync) is reported as such. A length
+/// over the max of the msg type fails with `Error::MsgLen` before anything of
+/// the body is read, the connection gets closed on it.
+///
+/// From protocol version 2 on the header ends with the checksum of the body,
+/// it's checked once the body is read.
+///
 pub fn read_header(
 	stream: &mut dyn Read,
+	version: ProtocolVersion,
 	msg_type: Option<Type>,
 ) -> Result<MsgHeaderWrapper, Error> {
-	let mut head = vec![0u8; MsgHeader::LEN];
+	let mut head = vec![0u8; version.header_len()];
 	if Some(Type::Hand) == msg_type {
 		read_exact(stream, &mut head, time::Duration::from_millis(10), true)?;
 	} else {
 		read_exact(stream, &mut head, time::Duration::from_secs(10), false)?;
 	}
-	let header = ser::deserialize::<MsgHeaderWrapper>(&mut &head[..])?;
+	if head[..2] != magic() {
+		return Err(Error::WrongNetwork);
+	}
+	let mut header = match ser::deserialize::<MsgHeaderWrapper>(&mut &head[..MsgHeader::LEN]) {
+		Ok(header) => header,
+		// the declared length is over the limit, bail before reading the body
+		Err(ser::Error::TooLargeReadErr) => return Err(Error::MsgLen),
+		Err(e) => return Err(e.into()),
+	};
+	// streamed bodies are read as they come, never compressed
+	if let MsgHeaderWrapper::Known(ref header) = header {
+		if header.compressed && is_streamed(header.msg_type) {
+			return Err(Error::BadMessage);
+		}
+	}
+	// the body of an unknown msg is discarded, no need for its checksum
+	if let MsgHeaderWrapper::Known(ref mut header) = header {
+		if version.has_checksum() {
+			let mut checksum = [0u8; CHECKSUM_LEN];
+			checksum.copy_from_slice(&head[MsgHeader::LEN..]);
+			header.checksum = Some(checksum);
+		}
+	}
 	Ok(header)
 }
 
@@ -156,62 +329,421 @@ pub fn read_item<T: Readable>(stream: &mut dyn Read) -> Result<(T, u64), Error>
 	Ok((res, reader.total_bytes_read()))
 }
 
-/// Read a message body from the provided stream, always blocking
-/// until we have a result (or timeout).
-pub fn read_body<T: Readable>(h: &MsgHeader, stream: &mut dyn Read) -> Result<T, Error> {
-	let mut body = vec![0u8; h.msg_len as usize];
-	read_exact(stream, &mut body, time::Duration::from_secs(20), true)?;
+/// Read a message body from the provided stream, always blocking until we
+/// have a result or the deadline passed, then failing with `Error::Timeout`.
+/// Fails with `Error::Corruption` if the body doesn't match the checksum of
+/// the header.
+///
+/// Large bodies are read a chunk at a time, only the room for what arrived
+/// gets zeroed. Compressed bodies are inflated transparently.
+pub fn read_body<T: Readable>(
+	h: &MsgHeader,
+	stream: &mut dyn Read,
+	deadline: Instant,
+) -> Result<T, Error> {
+	read_body_from(h, vec![], stream, deadline)
+}
+
+/// Same as `read_body` when the start of the body was already read off the
+/// stream (see `peek_body`).
+pub fn read_body_from<T: Readable>(
+	h: &MsgHeader,
+	mut body: Vec<u8>,
+	stream: &mut dyn Read,
+	deadline: Instant,
+) -> Result<T, Error> {
+	let len = h.msg_len as usize;
+	body.reserve(
+		(cmp::min(h.msg_len, max_msg_len(h.msg_type)) as usize).saturating_sub(body.len()),
+	);
+	while body.len() < len {
+		let start = body.len();
+		body.resize(cmp::min(len, start + STREAM_CHUNK_SIZE), 0);
+		read_until(stream, &mut body[start..], deadline)?;
+	}
+	if let Some(checksum) = h.checksum {
+		if Checksum::of(&body) != checksum {
+			return Err(Error::Corruption);
+		}
+	}
+	if h.compressed {
+		body = decompress(h.msg_type, &body)?;
+	}
 	ser::deserialize(&mut &body[..]).map_err(From::from)
 }
 
+// Inflates a compressed body. The uncompressed length it declares is held to
+// the max of the msg type before inflating anything, and it has to inflate to
+// exactly that, so a small body can't blow up in memory.
+fn decompress(msg_type: Type, body: &[u8]) -> Result<Vec<u8>, Error> {
+	if body.len() < 8 {
+		return Err(ser::Error::CorruptedData.into());
+	}
+	let len: u64 = ser::deserialize(&mut &body[..8])?;
+	if len > max_msg_len(msg_type) {
+		return Err(Error::MsgLen);
+	}
+	let mut out = Vec::with_capacity(len as usize);
+	DeflateDecoder::new(&body[8..])
+		.take(len + 1)
+		.read_to_end(&mut out)
+		.map_err(|_| ser::Error::CorruptedData)?;
+	if out.len() as u64 != len {
+		return Err(ser::Error::CorruptedData.into());
+	}
+	Ok(out)
+}
+
 /// Read (an unknown) message from the provided stream and discard it.
-pub fn read_discard(msg_len: u64, stream: &mut dyn Read) -> Result<(), Error> {
-	let mut buffer = vec![0u8; msg_len as usize];
-	read_exact(stream, &mut buffer, time::Duration::from_secs(20), true)?;
+pub fn read_discard(msg_len: u64, stream: &mut dyn Read, deadline: Instant) -> Result<(), Error> {
+	let mut buffer = vec![0u8; cmp::min(msg_len as usize, STREAM_CHUNK_SIZE)];
+	let mut left = msg_len as usize;
+	while left > 0 {
+		let n = cmp::min(left, buffer.len());
+		read_until(stream, &mut buffer[..n], deadline)?;
+		left -= n;
+	}
+	Ok(())
+}
+
+/// Deserializes the start of a msg body only, reading off the stream no more
+/// than it takes. Returns what was read along, to hand over to
+/// `read_body_from` for the whole body, or to skip the rest of it with
+/// `read_discard`. Not for compressed bodies.
+pub fn peek_body<T: Readable>(
+	h: &MsgHeader,
+	stream: &mut dyn Read,
+	deadline: Instant,
+) -> Result<(T, Vec<u8>), Error> {
+	if h.compressed {
+		return Err(Error::BadMessage);
+	}
+	let mut reader = PrefixReader {
+		stream,
+		left: h.msg_len as usize,
+		read: vec![],
+		deadline,
+		error: None,
+	};
+	let res = ser::deserialize(&mut reader);
+	// ours (a timeout say) rather than as a deserialization failure
+	if let Some(e) = reader.error.take() {
+		return Err(e);
+	}
+	Ok((res?, reader.read))
+}
+
+// Reads the stream for `peek_body`, within the msg body, keeping what was
+// read.
+struct PrefixReader<'a> {
+	stream: &'a mut dyn Read,
+	left: usize,
+	read: Vec<u8>,
+	deadline: Instant,
+	error: Option<Error>,
+}
+
+impl<'a> Read for PrefixReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = cmp::min(buf.len(), self.left);
+		if n == 0 {
+			return Ok(0);
+		}
+		if let Err(e) = read_until(self.stream, &mut buf[..n], self.deadline) {
+			self.error = Some(e);
+			return Err(io::Error::new(io::ErrorKind::Other, "peek_body"));
+		}
+		self.read.extend_from_slice(&buf[..n]);
+		self.left -= n;
+		Ok(n)
+	}
+}
+
+/// Whether a body of this length is already too large for a valid msg of
+/// the type, as far as consensus goes (no slack, unlike the msg header
+/// check). Only blocks and txs are bounded that way.
+pub fn over_consensus_size(msg_type: Type, msg_len: u64) -> bool {
+	match msg_type {
+		Type::Block | Type::Transaction | Type::StemTransaction => msg_len > max_msg_size(msg_type),
+		_ => false,
+	}
+}
+
+// Blocks until the buffer is full like `read_exact`, against a deadline in
+// real time rather than a time spent waiting, which a peer trickling bytes
+// could stretch a lot.
+fn read_until(stream: &mut dyn Read, mut buf: &mut [u8], deadline: Instant) -> Result<(), Error> {
+	while !buf.is_empty() {
+		match stream.read(buf) {
+			Ok(0) => {
+				return Err(Error::Connection(io::Error::new(
+					io::ErrorKind::ConnectionAborted,
+					"read_until",
+				)));
+			}
+			Ok(n) => {
+				let tmp = buf;
+				buf = &mut tmp[n..];
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+			Err(e) => return Err(e.into()),
+		}
+		if !buf.is_empty() {
+			if Instant::now() >= deadline {
+				return Err(Error::Timeout);
+			}
+			thread::sleep(time::Duration::from_micros(10));
+		}
+	}
 	Ok(())
 }
 
 /// Reads a full message from the underlying stream.
-pub fn read_message<T: Readable>(stream: &mut dyn Read, msg_type: Type) -> Result<T, Error> {
-	match read_header(stream, Some(msg_type))? {
+pub fn read_message<T: Readable>(
+	stream: &mut dyn Read,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<T, Error> {
+	match read_header(stream, version, Some(msg_type))? {
 		MsgHeaderWrapper::Known(header) => {
 			if header.msg_type == msg_type {
-				read_body(&header, stream)
+				read_body(&header, stream, Instant::now() + BODY_TIMEOUT)
 			} else {
 				Err(Error::BadMessage)
 			}
 		}
-		MsgHeaderWrapper::Unknown(msg_len) => {
-			read_discard(msg_len, stream)?;
+		MsgHeaderWrapper::Unknown(msg_len, _) => {
+			read_discard(msg_len, stream, Instant::now() + BODY_TIMEOUT)?;
 			Err(Error::BadMessage)
 		}
 	}
 }
 
-pub fn write_to_buf<T: Writeable>(msg: T, msg_type: Type) -> Result<Vec<u8>, Error> {
-	// prepare the body first so we know its serialized length
-	let mut body_buf = vec![];
-	ser::serialize(&mut body_buf, &msg)?;
+lazy_static! {
+	static ref BUF_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(vec![]);
+}
+
+/// A buffer from a small pool shared by all connections, to serialize msg
+/// bodies in. Goes back to the pool when dropped, unless it grew too large
+/// or the pool is full already.
+pub struct PooledBuf(Vec<u8>);
+
+impl PooledBuf {
+	pub fn take() -> PooledBuf {
+		PooledBuf(BUF_POOL.lock().pop().unwrap_or_default())
+	}
+
+	/// Number of buffers currently waiting in the pool.
+	pub fn pooled() -> usize {
+		BUF_POOL.lock().len()
+	}
+}
+
+impl Drop for PooledBuf {
+	fn drop(&mut self) {
+		let mut buf = mem::replace(&mut self.0, vec![]);
+		if buf.capacity() > MAX_POOLED_BUF_CAPACITY {
+			return;
+		}
+		buf.clear();
+		let mut pool = BUF_POOL.lock();
+		if pool.len() < MAX_POOLED_BUFS {
+			pool.push(buf);
+		}
+	}
+}
+
+impl Deref for PooledBuf {
+	type Target = Vec<u8>;
 
-	// build and serialize the header using the body size
-	let mut msg_buf = vec![];
-	let blen = body_buf.len() as u64;
-	ser::serialize(&mut msg_buf, &MsgHeader::new(msg_type, blen))?;
-	msg_buf.append(&mut body_buf);
+	fn deref(&self) -> &Vec<u8> {
+		&self.0
+	}
+}
+
+impl DerefMut for PooledBuf {
+	fn deref_mut(&mut self) -> &mut Vec<u8> {
+		&mut self.0
+	}
+}
 
+/// Serializes a msg body once, in a pooled buffer, and its header after it
+/// as the body length (and checksum) is known by then.
+fn frame<T: Writeable>(
+	msg: &T,
+	version: ProtocolVersion,
+	msg_type: Type,
+	compress: bool,
+) -> Result<(Vec<u8>, PooledBuf), Error> {
+	let mut body = PooledBuf::take();
+	ser::serialize(&mut *body, msg)?;
+	frame_body(body, version, msg_type, compress)
+}
+
+/// Builds the header of a serialized msg body. Deflates the body first
+/// (prefixed with its uncompressed length) when asked to, if it's worth it
+/// and it does get smaller.
+pub fn frame_body(
+	body: PooledBuf,
+	version: ProtocolVersion,
+	msg_type: Type,
+	compress: bool,
+) -> Result<(Vec<u8>, PooledBuf), Error> {
+	let mut body = body;
+	let mut compressed = false;
+	if compress && worth_compressing(msg_type, body.len()) {
+		let mut packed = PooledBuf::take();
+		ser::serialize(&mut *packed, &(body.len() as u64))?;
+		let mut encoder = DeflateEncoder::new(&mut *packed, Compression::fast());
+		encoder.write_all(&body)?;
+		encoder.finish()?;
+		if packed.len() < body.len() {
+			body = packed;
+			compressed = true;
+		}
+	}
+	let mut header = MsgHeader::for_body(version, msg_type, &body);
+	header.compressed = compressed;
+	Ok((ser::ser_vec(&header)?, body))
+}
+
+pub fn write_to_buf<T: Writeable>(
+	msg: T,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<Vec<u8>, Error> {
+	let (mut msg_buf, body) = frame(&msg, version, msg_type, false)?;
+	msg_buf.extend_from_slice(&body);
+	Ok(msg_buf)
+}
+
+/// Same as `write_to_buf`, compressing the body when it's worth it. Only for
+/// connections where both sides advertised `Capabilities::COMPRESSION`.
+pub fn write_to_buf_compressed<T: Writeable>(
+	msg: T,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<Vec<u8>, Error> {
+	let (mut msg_buf, body) = frame(&msg, version, msg_type, true)?;
+	msg_buf.extend_from_slice(&body);
 	Ok(msg_buf)
 }
 
+/// Writes a msg, its header then its body, serialized only once. Returns the
+/// number of bytes written.
 pub fn write_message<T: Writeable>(
 	stream: &mut dyn Write,
 	msg: T,
+	version: ProtocolVersion,
 	msg_type: Type,
-) -> Result<(), Error> {
-	let buf = write_to_buf(msg, msg_type)?;
-	stream.write_all(&buf[..])?;
+) -> Result<u64, Error> {
+	let (header, body) = frame(&msg, version, msg_type, false)?;
+	stream.write_all(&header)?;
+	stream.write_all(&body)?;
+	Ok((header.len() + body.len()) as u64)
+}
+
+/// Writes a msg without ever holding its whole serialized body in memory.
+/// The body is serialized twice: once to get its length (and checksum) for
+/// the header, then for real through a buffer of `STREAM_CHUNK_SIZE` bytes,
+/// written out every time it fills. Returns the number of bytes written.
+pub fn write_streamed<T: Writeable>(
+	stream: &mut dyn Write,
+	msg: &T,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<u64, Error> {
+	let (head, body_len) = streamed_header(msg, version, msg_type)?;
+	write_all(stream, &head, time::Duration::from_secs(10))?;
+	write_streamed_body(stream, msg)?;
+	Ok(head.len() as u64 + body_len)
+}
+
+/// The header of a msg written with `write_streamed`, and the length of its
+/// body. The body is serialized to measure it, nothing of it is kept.
+pub fn streamed_header<T: Writeable>(
+	msg: &T,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<(Vec<u8>, u64), Error> {
+	let mut meter = BodyMeter {
+		len: 0,
+		checksum: Checksum::new(),
+	};
+	ser::serialize(&mut meter, msg)?;
+	let mut header = MsgHeader::new(msg_type, meter.len);
+	if version.has_checksum() {
+		header.checksum = Some(meter.checksum.finish());
+	}
+	Ok((ser::ser_vec(&header)?, meter.len))
+}
+
+/// The body of a msg, after its `streamed_header`, written out in pieces of
+/// `STREAM_CHUNK_SIZE` bytes.
+pub fn write_streamed_body<T: Writeable>(stream: &mut dyn Write, msg: &T) -> Result<(), Error> {
+	let mut chunks = ChunkedWriter {
+		inner: stream,
+		buf: Vec::with_capacity(STREAM_CHUNK_SIZE),
+	};
+	ser::serialize(&mut chunks, msg)?;
+	chunks.flush()?;
 	Ok(())
 }
 
+// Length and checksum of everything written, nothing is kept.
+struct BodyMeter {
+	len: u64,
+	checksum: Checksum,
+}
+
+impl Write for BodyMeter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.len += buf.len() as u64;
+		self.checksum.update(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// Gathers small writes in chunks, each chunk is written out whole when full
+// and the buffer reused for the next one.
+struct ChunkedWriter<'a> {
+	inner: &'a mut dyn Write,
+	buf: Vec<u8>,
+}
+
+impl<'a> ChunkedWriter<'a> {
+	fn write_chunk(&mut self) -> io::Result<()> {
+		write_all(self.inner, &self.buf, time::Duration::from_secs(10))?;
+		self.buf.clear();
+		// give whoever shares the thread with us a turn between chunks
+		thread::yield_now();
+		Ok(())
+	}
+}
+
+impl<'a> Write for ChunkedWriter<'a> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		let n = cmp::min(data.len(), STREAM_CHUNK_SIZE - self.buf.len());
+		self.buf.extend_from_slice(&data[..n]);
+		if self.buf.len() == STREAM_CHUNK_SIZE {
+			self.write_chunk()?;
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		if !self.buf.is_empty() {
+			self.write_chunk()?;
+		}
+		self.inner.flush()
+	}
+}
+
 /// A wrapper around a message header. If the header is for an unknown msg type
 /// then we will be unable to parse the msg itself (just a bunch of random bytes).
 /// But we need to know how many bytes to discard to discard the full message.
@@ -219,8 +751,9 @@ pub fn write_message<T: Writeable>(
 pub enum MsgHeaderWrapper {
 	/// A "known" msg type with deserialized msg header.
 	Known(MsgHeader),
-	/// An unknown msg type with corresponding msg size in bytes.
-	Unknown(u64),
+	/// An unknown msg type with corresponding msg size in bytes and the type
+	/// byte as received.
+	Unknown(u64, u8),
 }
 
 /// Header of any protocol message, used to identify incoming messages.
@@ -231,21 +764,47 @@ pub struct MsgHeader {
 	pub msg_type: Type,
 	/// Total length of the message in bytes.
 	pub msg_len: u64,
+	/// Checksum of the body, from protocol version 2 on.
+	pub checksum: Option<[u8; CHECKSUM_LEN]>,
+	/// Whether the body is compressed, see `COMPRESSED_FLAG`.
+	pub compressed: bool,
 }
 
 impl MsgHeader {
-	/// Creates a new message header.
+	/// Creates a new message header, without checksum.
 	pub fn new(msg_type: Type, len: u64) -> MsgHeader {
 		MsgHeader {
 			magic: magic(),
 			msg_type: msg_type,
 			msg_len: len,
+			checksum: None,
+			compressed: false,
 		}
 	}
+
+	// Flag bits of the type byte.
+	fn flags(&self) -> u8 {
+		if self.compressed {
+			COMPRESSED_FLAG
+		} else {
+			0
+		}
+	}
+
+	/// Creates the header of the provided body as the protocol version
+	/// expects it.
+	pub fn for_body(version: ProtocolVersion, msg_type: Type, body: &[u8]) -> MsgHeader {
+		let mut header = MsgHeader::new(msg_type, body.len() as u64);
+		if version.has_checksum() {
+			header.checksum = Some(Checksum::of(body));
+		}
+		header
+	}
 }
 
 impl FixedLength for MsgHeader {
-	// 2 magic bytes + 1 type byte + 8 bytes (msg_len)
+	// 2 magic bytes + 1 type byte + 8 bytes (msg_len), the checksum isn't
+	// counted as it depends on the protocol version
 	const LEN: usize = 2 + 1 + 8;
 }
 
@@ -255,13 +814,61 @@ impl Writeable for MsgHeader {
 			writer,
 			[write_u8, self.magic[0]],
 			[write_u8, self.magic[1]],
-			[write_u8, self.msg_type as u8],
+			[write_u8, self.msg_type as u8 | self.flags()],
 			[write_u64, self.msg_len]
 		);
+		if let Some(ref checksum) = self.checksum {
+			writer.write_fixed_bytes(checksum)?;
+		}
 		Ok(())
 	}
 }
 
+/// Checksum of a msg body, the first 4 bytes of its double SHA256. Only
+/// catches bodies mangled on the way, it's no protection against a peer
+/// sending us garbage on purpose.
+pub struct Checksum(digest::Context);
+
+impl Checksum {
+	pub fn new() -> Checksum {
+		Checksum(digest::Context::new(&digest::SHA256))
+	}
+
+	/// Checksum of a full body.
+	pub fn of(body: &[u8]) -> [u8; CHECKSUM_LEN] {
+		let mut checksum = Checksum::new();
+		checksum.update(body);
+		checksum.finish()
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		self.0.update(data);
+	}
+
+	pub fn finish(self) -> [u8; CHECKSUM_LEN] {
+		let first = self.0.finish();
+		let second = digest::digest(&digest::SHA256, first.as_ref());
+		let mut checksum = [0u8; CHECKSUM_LEN];
+		checksum.copy_from_slice(&second.as_ref()[..CHECKSUM_LEN]);
+		checksum
+	}
+}
+
+/// Adds everything read from the inner stream to a checksum, for bodies read
+/// in pieces.
+pub struct ChecksumReader<'a> {
+	pub inner: &'a mut dyn Read,
+	pub checksum: &'a mut Checksum,
+}
+
+impl<'a> Read for ChecksumReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.inner.read(buf)?;
+		self.checksum.update(&buf[..n]);
+		Ok(n)
+	}
+}
+
 impl Readable for MsgHeaderWrapper {
 	fn read(reader: &mut dyn Reader) -> Result<MsgHeaderWrapper, ser::Error> {
 		let m = magic();
@@ -274,10 +881,10 @@ impl Readable for MsgHeaderWrapper {
 
 		// Attempt to convert the msg type byte into one of our known msg type enum variants.
 		// Check the msg_len while we are at it.
-		match Type::from_u8(t) {
+		let compressed = t & COMPRESSED_FLAG != 0;
+		match Type::from_u8(t & !COMPRESSED_FLAG) {
 			Some(msg_type) => {
-				// TODO 4x the limits for now to leave ourselves space to change things.
-				let max_len = max_msg_size(msg_type) * 4;
+				let max_len = max_msg_len(msg_type);
 				if msg_len > max_len {
 					error!(
 						"Too large read {:?}, max_len: {}, msg_len: {}.",
@@ -290,6 +897,8 @@ impl Readable for MsgHeaderWrapper {
 					magic: m,
 					msg_type,
 					msg_len,
+					checksum: None,
+					compressed,
 				}))
 			}
 			None => {
@@ -303,7 +912,7 @@ impl Readable for MsgHeaderWrapper {
 					return Err(ser::Error::TooLargeReadErr);
 				}
 
-				Ok(MsgHeaderWrapper::Unknown(msg_len))
+				Ok(MsgHeaderWrapper::Unknown(msg_len, t))
 			}
 		}
 	}
@@ -318,6 +927,43 @@ impl Default for ProtocolVersion {
 	}
 }
 
+impl ProtocolVersion {
+	/// The oldest protocol version supported by our local node.
+	pub fn min_supported() -> ProtocolVersion {
+		ProtocolVersion(MIN_PROTOCOL_VERSION)
+	}
+
+	/// The version the headers of Hand and Shake go with.
+	pub fn handshake() -> ProtocolVersion {
+		ProtocolVersion(HANDSHAKE_PROTOCOL_VERSION)
+	}
+
+	/// Whether msg headers carry a checksum of the body.
+	pub fn has_checksum(&self) -> bool {
+		self.0 >= CHECKSUM_PROTOCOL_VERSION
+	}
+
+	/// Whether the Shake is laid out as in version 1, none of the fields
+	/// added since.
+	pub fn has_v1_shake(&self) -> bool {
+		self.0 < CHECKSUM_PROTOCOL_VERSION
+	}
+
+	/// Whether the Shake carries its height and node id as extensions.
+	pub fn has_extensions(&self) -> bool {
+		self.0 >= EXTENSIONS_PROTOCOL_VERSION
+	}
+
+	/// Length of msg headers in this version.
+	pub fn header_len(&self) -> usize {
+		if self.has_checksum() {
+			MsgHeader::LEN + CHECKSUM_LEN
+		} else {
+			MsgHeader::LEN
+		}
+	}
+}
+
 impl From<ProtocolVersion> for u32 {
 	fn from(v: ProtocolVersion) -> u32 {
 		v.0
@@ -343,11 +989,34 @@ impl Readable for ProtocolVersion {
 	}
 }
 
+/// Max size of the extension area of a Hand or Shake, count byte and entries
+/// included.
+pub const MAX_EXTENSIONS_LEN: usize = 4096;
+
+/// Extension tags of Hand and Shake, never reused for anything else once
+/// assigned. Each entry of the extension area is a tag, a u16 length and the
+/// value, unknown tags are kept as they are and known ones are only sent to
+/// peers whose version reads them:
+/// * 1, height (u64), in the Hand, in the Shake from version 3
+/// * 2, node id (16 bytes), in the Hand, in the Shake from version 3
+/// * 3, lowest protocol version supported (u32), in the Hand
+pub const EXT_HEIGHT: u8 = 1;
+pub const EXT_NODE_ID: u8 = 2;
+pub const EXT_MIN_VERSION: u8 = 3;
+
 /// First part of a handshake, sender advertises its version and
 /// characteristics.
+///
+/// The Hand goes out before we know the version of the peer, so it's laid out
+/// as in version 1, all that came after (lowest version, height and node id)
+/// in the extension area at the end, which peers running version 1 don't
+/// read. Their own Hands come without it: they only speak the version they
+/// advertise, and their height is unknown.
 pub struct Hand {
-	/// protocol version of the sender
+	/// highest protocol version supported by the sender
 	pub version: ProtocolVersion,
+	/// lowest protocol version supported by the sender
+	pub min_version: ProtocolVersion,
 	/// capabilities of the sender
 	pub capabilities: Capabilities,
 	/// randomly generated for each handshake, helps detect self
@@ -357,12 +1026,20 @@ pub struct Hand {
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may be needed
 	pub total_difficulty: Difficulty,
+	/// chain height of the sender, consistent with total_difficulty (older
+	/// peers don't send it)
+	pub height: Option<u64>,
 	/// network address of the sender
 	pub sender_addr: PeerAddr,
 	/// network address of the receiver
 	pub receiver_addr: PeerAddr,
 	/// name of version of the software
 	pub user_agent: String,
+	/// persistent id of the sender, helps detect self (older peers don't
+	/// send it)
+	pub node_id: Option<NodeId>,
+	/// extensions we don't know of
+	pub extensions: Vec<(u8, Vec<u8>)>,
 }
 
 impl Writeable for Hand {
@@ -376,9 +1053,17 @@ impl Writeable for Hand {
 		self.total_difficulty.write(writer)?;
 		self.sender_addr.write(writer)?;
 		self.receiver_addr.write(writer)?;
-		writer.write_bytes(&self.user_agent)?;
+		write_user_agent(writer, &self.user_agent)?;
 		self.genesis.write(writer)?;
-		Ok(())
+		let mut extensions = vec![(EXT_MIN_VERSION, ser::ser_vec(&self.min_version)?)];
+		if let Some(height) = self.height {
+			extensions.push((EXT_HEIGHT, ser::ser_vec(&height)?));
+		}
+		if let Some(node_id) = self.node_id {
+			extensions.push((EXT_NODE_ID, ser::ser_vec(&node_id)?));
+		}
+		extensions.extend_from_slice(&self.extensions);
+		write_extensions(writer, &extensions)
 	}
 }
 
@@ -386,49 +1071,113 @@ impl Readable for Hand {
 	fn read(reader: &mut dyn Reader) -> Result<Hand, ser::Error> {
 		let version = ProtocolVersion::read(reader)?;
 		let (capab, nonce) = ser_multiread!(reader, read_u32, read_u64);
-		let capabilities = Capabilities::from_bits_truncate(capab);
+		let capabilities = Capabilities::from_bits_preserve(capab);
 		let total_difficulty = Difficulty::read(reader)?;
 		let sender_addr = PeerAddr::read(reader)?;
 		let receiver_addr = PeerAddr::read(reader)?;
-		let ua = reader.read_bytes_len_prefix()?;
-		let user_agent = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
+		let user_agent = read_user_agent(reader)?;
 		let genesis = Hash::read(reader)?;
+		let mut extensions = read_extensions(reader)?;
+		let min_version = take_extension::<ProtocolVersion>(&mut extensions, EXT_MIN_VERSION, 4)?
+			.unwrap_or(version);
+		let height = take_extension::<u64>(&mut extensions, EXT_HEIGHT, 8)?;
+		let node_id = take_extension::<NodeId>(&mut extensions, EXT_NODE_ID, 16)?;
 		Ok(Hand {
 			version,
+			min_version,
 			capabilities,
 			nonce,
 			genesis,
 			total_difficulty,
+			height,
 			sender_addr,
 			receiver_addr,
 			user_agent,
+			node_id,
+			extensions,
 		})
 	}
 }
 
 /// Second part of a handshake, receiver of the first part replies with its own
 /// version and characteristics.
+///
+/// Laid out for the version it picked, which the sender of the Hand reads
+/// first. In version 1 it only carries the version, capabilities, total
+/// difficulty, user agent and genesis, the other fields are left out. From
+/// version 3 on, the height and node id are sent as extensions.
 pub struct Shake {
-	/// sender version
+	/// protocol version picked by the sender, the highest one both sides
+	/// support
 	pub version: ProtocolVersion,
+	/// lowest protocol version supported by the sender
+	pub min_version: ProtocolVersion,
 	/// sender capabilities
 	pub capabilities: Capabilities,
+	/// nonce received in the Hand, echoed back so the sender of the Hand can
+	/// check the Shake is a reply to its own request
+	pub nonce: Option<u64>,
 	/// genesis block of our chain, only connect to peers on the same chain
 	pub genesis: Hash,
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may be needed
 	pub total_difficulty: Difficulty,
+	/// chain height of the sender, consistent with total_difficulty (not in
+	/// version 1)
+	pub height: Option<u64>,
+	/// address the sender observed us connecting from (with the port we
+	/// advertised), lets nodes behind NAT learn their public address
+	pub observed_addr: Option<PeerAddr>,
 	/// name of version of the software
 	pub user_agent: String,
+	/// persistent id of the sender (older peers don't send it)
+	pub node_id: Option<NodeId>,
+	/// extensions we don't know of, only sent along a node id before version 3
+	pub extensions: Vec<(u8, Vec<u8>)>,
 }
 
 impl Writeable for Shake {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		self.version.write(writer)?;
-		writer.write_u32(self.capabilities.bits())?;
+		if self.version.has_v1_shake() {
+			writer.write_u32(self.capabilities.bits())?;
+			self.total_difficulty.write(writer)?;
+			write_user_agent(writer, &self.user_agent)?;
+			return self.genesis.write(writer);
+		}
+		// past version 1 these always go along
+		let missing = || ser::Error::CorruptedData;
+		let nonce = self.nonce.ok_or_else(missing)?;
+		let height = self.height.ok_or_else(missing)?;
+		let observed_addr = self.observed_addr.as_ref().ok_or_else(missing)?;
+		self.min_version.write(writer)?;
+		ser_multiwrite!(
+			writer,
+			[write_u32, self.capabilities.bits()],
+			[write_u64, nonce]
+		);
 		self.total_difficulty.write(writer)?;
-		writer.write_bytes(&self.user_agent)?;
+		if self.version.has_extensions() {
+			observed_addr.write(writer)?;
+			write_user_agent(writer, &self.user_agent)?;
+			self.genesis.write(writer)?;
+			let mut extensions = vec![(EXT_HEIGHT, ser::ser_vec(&height)?)];
+			if let Some(node_id) = self.node_id {
+				extensions.push((EXT_NODE_ID, ser::ser_vec(&node_id)?));
+			}
+			extensions.extend_from_slice(&self.extensions);
+			return write_extensions(writer, &extensions);
+		}
+		writer.write_u64(height)?;
+		observed_addr.write(writer)?;
+		write_user_agent(writer, &self.user_agent)?;
 		self.genesis.write(writer)?;
+		if let Some(node_id) = self.node_id {
+			node_id.write(writer)?;
+			if !self.extensions.is_empty() {
+				write_extensions(writer, &self.extensions)?;
+			}
+		}
 		Ok(())
 	}
 }
@@ -436,24 +1185,194 @@ impl Writeable for Shake {
 impl Readable for Shake {
 	fn read(reader: &mut dyn Reader) -> Result<Shake, ser::Error> {
 		let version = ProtocolVersion::read(reader)?;
+		if version.has_v1_shake() {
+			let capabilities = Capabilities::from_bits_preserve(reader.read_u32()?);
+			let total_difficulty = Difficulty::read(reader)?;
+			let user_agent = read_user_agent(reader)?;
+			let genesis = Hash::read(reader)?;
+			return Ok(Shake {
+				version,
+				min_version: version,
+				capabilities,
+				nonce: None,
+				genesis,
+				total_difficulty,
+				height: None,
+				observed_addr: None,
+				user_agent,
+				node_id: None,
+				extensions: vec![],
+			});
+		}
+		let min_version = ProtocolVersion::read(reader)?;
 
-		let capab = reader.read_u32()?;
-		let capabilities = Capabilities::from_bits_truncate(capab);
+		let (capab, nonce) = ser_multiread!(reader, read_u32, read_u64);
+		let capabilities = Capabilities::from_bits_preserve(capab);
 
 		let total_difficulty = Difficulty::read(reader)?;
-		let ua = reader.read_bytes_len_prefix()?;
-		let user_agent = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
-		let genesis = Hash::read(reader)?;
+		let (height, observed_addr, user_agent, genesis, node_id, extensions);
+		if version.has_extensions() {
+			observed_addr = PeerAddr::read(reader)?;
+			user_agent = read_user_agent(reader)?;
+			genesis = Hash::read(reader)?;
+			let mut exts = read_extensions(reader)?;
+			height = take_extension::<u64>(&mut exts, EXT_HEIGHT, 8)?
+				.ok_or(ser::Error::CorruptedData)?;
+			node_id = take_extension::<NodeId>(&mut exts, EXT_NODE_ID, 16)?;
+			extensions = exts;
+		} else {
+			height = reader.read_u64()?;
+			observed_addr = PeerAddr::read(reader)?;
+			user_agent = read_user_agent(reader)?;
+			genesis = Hash::read(reader)?;
+			node_id = read_node_id(reader);
+			extensions = match node_id {
+				Some(_) => read_extensions(reader)?,
+				None => vec![],
+			};
+		}
 		Ok(Shake {
 			version,
+			min_version,
 			capabilities,
+			nonce: Some(nonce),
 			genesis,
 			total_difficulty,
+			height: Some(height),
+			observed_addr: Some(observed_addr),
 			user_agent,
+			node_id,
+			extensions,
 		})
 	}
 }
 
+/// Read the trailing node id, if the peer sent one. This only works because
+/// each message body is read in its own vector and the id is the last element.
+fn read_node_id(reader: &mut dyn Reader) -> Option<NodeId> {
+	NodeId::read(reader).ok()
+}
+
+/// Write an extension area: the count of entries, then the tag, u16 length
+/// and value of each. Fails on duplicate tags or over MAX_EXTENSIONS_LEN.
+fn write_extensions<W: Writer>(
+	writer: &mut W,
+	extensions: &[(u8, Vec<u8>)],
+) -> Result<(), ser::Error> {
+	if extensions.len() > u8::MAX as usize {
+		return Err(ser::Error::CountError);
+	}
+	let mut size = 1;
+	for (i, (tag, value)) in extensions.iter().enumerate() {
+		if extensions[..i].iter().any(|(t, _)| t == tag) {
+			return Err(ser::Error::DuplicateError);
+		}
+		size += 3 + value.len();
+	}
+	if size > MAX_EXTENSIONS_LEN {
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	writer.write_u8(extensions.len() as u8)?;
+	for (tag, value) in extensions {
+		writer.write_u8(*tag)?;
+		writer.write_u16(value.len() as u16)?;
+		writer.write_fixed_bytes(value)?;
+	}
+	Ok(())
+}
+
+/// Read the trailing extension area, if the peer sent one (older peers
+/// don't). Like the node id this only works because it's the last element.
+/// Once there, a truncated entry, a duplicate tag or going over
+/// MAX_EXTENSIONS_LEN fails the whole message.
+fn read_extensions(reader: &mut dyn Reader) -> Result<Vec<(u8, Vec<u8>)>, ser::Error> {
+	let count = match reader.read_u8() {
+		Ok(count) => count,
+		Err(_) => return Ok(vec![]),
+	};
+	let mut size = 1;
+	let mut extensions: Vec<(u8, Vec<u8>)> = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		let (tag, len) = ser_multiread!(reader, read_u8, read_u16);
+		size += 3 + len as usize;
+		if size > MAX_EXTENSIONS_LEN {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		if extensions.iter().any(|(t, _)| *t == tag) {
+			return Err(ser::Error::DuplicateError);
+		}
+		extensions.push((tag, reader.read_fixed_bytes(len as usize)?));
+	}
+	Ok(extensions)
+}
+
+/// Removes a known extension from the ones read, its value must be exactly
+/// `len` bytes.
+fn take_extension<T: Readable>(
+	extensions: &mut Vec<(u8, Vec<u8>)>,
+	tag: u8,
+	len: usize,
+) -> Result<Option<T>, ser::Error> {
+	let i = match extensions.iter().position(|(t, _)| *t == tag) {
+		Some(i) => i,
+		None => return Ok(None),
+	};
+	let (_, value) = extensions.remove(i);
+	if value.len() != len {
+		return Err(ser::Error::CorruptedData);
+	}
+	ser::deserialize(&mut &value[..]).map(Some)
+}
+
+/// Write a user agent, truncated (on a char boundary) to MAX_USER_AGENT_LEN.
+fn write_user_agent<W: Writer>(writer: &mut W, user_agent: &str) -> Result<(), ser::Error> {
+	let mut end = cmp::min(user_agent.len(), MAX_USER_AGENT_LEN);
+	while !user_agent.is_char_boundary(end) {
+		end -= 1;
+	}
+	writer.write_bytes(&user_agent[..end].as_bytes())
+}
+
+/// Read a user agent, rejecting it before allocating anything if it exceeds
+/// MAX_USER_AGENT_LEN. Control characters (newlines etc.) are stripped so
+/// they never make it into our logs or peer store.
+fn read_user_agent(reader: &mut dyn Reader) -> Result<String, ser::Error> {
+	let len = reader.read_u64()?;
+	if len > MAX_USER_AGENT_LEN as u64 {
+		error!(
+			"read_user_agent: user agent too long, max_len: {}, len: {}",
+			MAX_USER_AGENT_LEN, len
+		);
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	let ua = reader.read_fixed_bytes(len as usize)?;
+	let ua = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
+	Ok(ua.chars().filter(|c| !c.is_control()).collect())
+}
+
+/// Announces a block by hash, peers that don't have it yet ask for it with
+/// GetBlock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockInv {
+	pub hash: Hash,
+	pub height: u64,
+}
+
+impl Writeable for BlockInv {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.hash.write(writer)?;
+		writer.write_u64(self.height)
+	}
+}
+
+impl Readable for BlockInv {
+	fn read(reader: &mut dyn Reader) -> Result<BlockInv, ser::Error> {
+		let hash = Hash::read(reader)?;
+		let height = reader.read_u64()?;
+		Ok(BlockInv { hash, height })
+	}
+}
+
 /// Ask for other peers addresses, required for network discovery.
 pub struct GetPeerAddrs {
 	/// Filters on the capabilities we'd like the peers to have
@@ -483,6 +1402,10 @@ pub struct PeerAddrs {
 
 impl Writeable for PeerAddrs {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		// more than the other side would ever read
+		if self.peers.len() > MAX_PEER_ADDRS as usize {
+			return Err(ser::Error::CountError);
+		}
 		writer.write_u32(self.peers.len() as u32)?;
 		for p in &self.peers {
 			p.write(writer)?;
@@ -507,34 +1430,6 @@ impl Readable for PeerAddrs {
 	}
 }
 
-/// We found some issue in the communication, sending an error back, usually
-/// followed by closing the connection.
-pub struct PeerError {
-	/// error code
-	pub code: u32,
-	/// slightly more user friendly message
-	pub message: String,
-}
-
-impl Writeable for PeerError {
-	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
-		ser_multiwrite!(writer, [write_u32, self.code], [write_bytes, &self.message]);
-		Ok(())
-	}
-}
-
-impl Readable for PeerError {
-	fn read(reader: &mut dyn Reader) -> Result<PeerError, ser::Error> {
-		let code = reader.read_u32()?;
-		let msg = reader.read_bytes_len_prefix()?;
-		let message = String::from_utf8(msg).map_err(|_| ser::Error::CorruptedData)?;
-		Ok(PeerError {
-			code: code,
-			message: message,
-		})
-	}
-}
-
 /// Serializable wrapper for the block locator.
 #[derive(Debug)]
 pub struct Locator {
@@ -543,6 +1438,9 @@ pub struct Locator {
 
 impl Writeable for Locator {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		if self.hashes.len() > MAX_LOCATORS as usize {
+			return Err(ser::Error::CountError);
+		}
 		writer.write_u8(self.hashes.len() as u8)?;
 		for h in &self.hashes {
 			h.write(writer)?
@@ -559,19 +1457,54 @@ impl Readable for Locator {
 		}
 		let mut hashes = Vec::with_capacity(len as usize);
 		for _ in 0..len {
-			hashes.push(Hash::read(reader)?);
+			let hash = Hash::read(reader)?;
+			// can't be from newest to oldest
+			if hashes.contains(&hash) {
+				return Err(ser::Error::CorruptedData);
+			}
+			hashes.push(hash);
 		}
 		Ok(Locator { hashes: hashes })
 	}
 }
 
-/// Serializable wrapper for a list of block headers.
+impl Locator {
+	/// Index of the first hash of the locator on our main chain, the one to
+	/// send headers from. `lookup` gives the height of the headers we know of
+	/// and whether they're on our main chain. We stop at the first hash on
+	/// our main chain, any header we knew of before must be higher up (the
+	/// locator goes from newest to oldest) or the whole locator is refused.
+	pub fn fork_point<F>(&self, lookup: F) -> Result<Option<usize>, Error>
+	where
+		F: Fn(&Hash) -> Option<(u64, bool)>,
+	{
+		let mut last_height = None;
+		for (i, hash) in self.hashes.iter().enumerate() {
+			if let Some((height, on_main_chain)) = lookup(hash) {
+				if last_height.map_or(false, |last| height >= last) {
+					return Err(Error::BadMessage);
+				}
+				if on_main_chain {
+					return Ok(Some(i));
+				}
+				last_height = Some(height);
+			}
+		}
+		Ok(None)
+	}
+}
+
+/// Serializable wrapper for a list of block headers, at most
+/// MAX_BLOCK_HEADERS of them.
 pub struct Headers {
 	pub headers: Vec<BlockHeader>,
 }
 
 impl Writeable for Headers {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		if self.headers.len() > MAX_BLOCK_HEADERS as usize {
+			return Err(ser::Error::CountError);
+		}
 		writer.write_u16(self.headers.len() as u16)?;
 		for h in &self.headers {
 			h.write(writer)?
@@ -580,18 +1513,38 @@ impl Writeable for Headers {
 	}
 }
 
+impl Readable for Headers {
+	fn read(reader: &mut dyn Reader) -> Result<Headers, ser::Error> {
+		let count = reader.read_u16()?;
+		if count as u32 > MAX_BLOCK_HEADERS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut headers = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			headers.push(BlockHeader::read(reader)?);
+		}
+		Ok(Headers { headers })
+	}
+}
+
 pub struct Ping {
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may be needed
 	pub total_difficulty: Difficulty,
 	/// total height
 	pub height: u64,
+	/// echoed back in the pong, to match it with the ping, older peers
+	/// don't send it
+	pub nonce: Option<u64>,
 }
 
 impl Writeable for Ping {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		self.total_difficulty.write(writer)?;
 		self.height.write(writer)?;
+		if let Some(nonce) = self.nonce {
+			writer.write_u64(nonce)?;
+		}
 		Ok(())
 	}
 }
@@ -600,9 +1553,12 @@ impl Readable for Ping {
 	fn read(reader: &mut dyn Reader) -> Result<Ping, ser::Error> {
 		let total_difficulty = Difficulty::read(reader)?;
 		let height = reader.read_u64()?;
+		// last, like the node id in the handshake
+		let nonce = reader.read_u64().ok();
 		Ok(Ping {
 			total_difficulty,
 			height,
+			nonce,
 		})
 	}
 }
@@ -613,12 +1569,17 @@ pub struct Pong {
 	pub total_difficulty: Difficulty,
 	/// height accumulated by sender
 	pub height: u64,
+	/// the nonce of the ping answered, if it had one
+	pub nonce: Option<u64>,
 }
 
 impl Writeable for Pong {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		self.total_difficulty.write(writer)?;
 		self.height.write(writer)?;
+		if let Some(nonce) = self.nonce {
+			writer.write_u64(nonce)?;
+		}
 		Ok(())
 	}
 }
@@ -627,23 +1588,39 @@ impl Readable for Pong {
 	fn read(reader: &mut dyn Reader) -> Result<Pong, ser::Error> {
 		let total_difficulty = Difficulty::read(reader)?;
 		let height = reader.read_u64()?;
+		let nonce = reader.read_u64().ok();
 		Ok(Pong {
 			total_difficulty,
 			height,
+			nonce,
 		})
 	}
 }
 
+/// Max length of the human readable message sent along a ban reason, so the
+/// whole msg fits in its max size.
+pub const MAX_BAN_MESSAGE_LEN: usize = 52;
+
+/// Sent right before closing a connection for cause, so the peer knows why.
 #[derive(Debug)]
 pub struct BanReason {
 	/// the reason for the ban
 	pub ban_reason: ReasonForBan,
+	/// Optional details, truncated to MAX_BAN_MESSAGE_LEN
+	pub message: Option<String>,
 }
 
 impl Writeable for BanReason {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		let ban_reason_i32 = self.ban_reason as i32;
 		ban_reason_i32.write(writer)?;
+		if let Some(ref message) = self.message {
+			let mut end = cmp::min(message.len(), MAX_BAN_MESSAGE_LEN);
+			while !message.is_char_boundary(end) {
+				end -= 1;
+			}
+			writer.write_bytes(&message[..end].as_bytes())?;
+		}
 		Ok(())
 	}
 }
@@ -657,7 +1634,147 @@ impl Readable for BanReason {
 
 		let ban_reason = ReasonForBan::from_i32(ban_reason_i32).ok_or(ser::Error::CorruptedData)?;
 
-		Ok(BanReason { ban_reason })
+		// older peers don't send any message
+		let message = match reader.read_u64() {
+			Ok(len) if len > MAX_BAN_MESSAGE_LEN as u64 => {
+				return Err(ser::Error::TooLargeReadErr);
+			}
+			Ok(len) => {
+				let message = reader.read_fixed_bytes(len as usize)?;
+				let message = String::from_utf8(message).map_err(|_| ser::Error::CorruptedData)?;
+				Some(message.chars().filter(|c| !c.is_control()).collect())
+			}
+			Err(_) => None,
+		};
+
+		Ok(BanReason {
+			ban_reason,
+			message,
+		})
+	}
+}
+
+/// Max length of the message of a PeerError.
+pub const MAX_PEER_ERROR_LEN: usize = 128;
+
+/// How many txs a peer may ask us for every second, past that it's scraping
+/// our pool and gets disconnected.
+pub const MAX_TX_REQUESTS_PER_SEC: u32 = 20;
+
+enum_from_primitive! {
+	/// How a peer broke the protocol, sent in a PeerError
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	pub enum PeerErrorCode {
+		UnexpectedMessage = 1,
+		BadLength = 2,
+		ChecksumMismatch = 3,
+		UnsolicitedBlock = 4,
+		RateLimited = 5,
+		TooSlow = 6,
+	}
+}
+
+/// Sent on a best effort basis right before disconnecting a peer for breaking
+/// the protocol, so nodes of different versions can tell why they don't get
+/// along. Codes we don't know of are kept as they are, the message is only
+/// ever meant for display.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerError {
+	pub code: u32,
+	/// Details, truncated to MAX_PEER_ERROR_LEN
+	pub message: String,
+}
+
+impl PeerError {
+	pub fn new(code: PeerErrorCode, message: String) -> PeerError {
+		PeerError {
+			code: code as u32,
+			message,
+		}
+	}
+
+	/// The error code, if one we know of.
+	pub fn code(&self) -> Option<PeerErrorCode> {
+		PeerErrorCode::from_u32(self.code)
+	}
+}
+
+impl Writeable for PeerError {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.code)?;
+		let mut end = cmp::min(self.message.len(), MAX_PEER_ERROR_LEN);
+		while !self.message.is_char_boundary(end) {
+			end -= 1;
+		}
+		writer.write_bytes(&self.message[..end].as_bytes())
+	}
+}
+
+impl Readable for PeerError {
+	fn read(reader: &mut dyn Reader) -> Result<PeerError, ser::Error> {
+		let code = reader.read_u32()?;
+		let len = reader.read_u64()?;
+		if len > MAX_PEER_ERROR_LEN as u64 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let message = reader.read_fixed_bytes(len as usize)?;
+		// untrusted, no control characters (newlines etc.) in our logs
+		let message = String::from_utf8_lossy(&message)
+			.chars()
+			.filter(|c| !c.is_control())
+			.collect();
+		Ok(PeerError { code, message })
+	}
+}
+
+enum_from_primitive! {
+	/// Why a peer is closing the connection in good order, sent in a
+	/// Disconnect
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	pub enum DisconnectReason {
+		/// Unspecified, or one we don't know of
+		None = 0,
+		Shutdown = 1,
+		TooManyPeers = 2,
+		/// Another connection with the peer is kept
+		Duplicate = 3,
+		/// Evicted to make room for a new peer
+		MakingRoom = 4,
+	}
+}
+
+/// Sent last when closing a connection in good order, after whatever was
+/// queued before. Unlike a BanReason or a PeerError nothing's wrong with the
+/// peer, it can connect again (how soon depends on the reason). Reasons we
+/// don't know of are kept as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disconnect {
+	pub reason: u32,
+}
+
+impl Disconnect {
+	pub fn new(reason: DisconnectReason) -> Disconnect {
+		Disconnect {
+			reason: reason as u32,
+		}
+	}
+
+	/// The reason, unspecified if one we don't know of.
+	pub fn reason(&self) -> DisconnectReason {
+		DisconnectReason::from_u32(self.reason).unwrap_or(DisconnectReason::None)
+	}
+}
+
+impl Writeable for Disconnect {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.reason)
+	}
+}
+
+impl Readable for Disconnect {
+	fn read(reader: &mut dyn Reader) -> Result<Disconnect, ser::Error> {
+		let reason = reader.read_u32()?;
+		Ok(Disconnect { reason })
 	}
 }
 
@@ -687,6 +1804,14 @@ impl Readable for TxHashSetRequest {
 	}
 }
 
+/// Largest txhashset archive we're willing to download, anything announcing
+/// more is refused before we write any of it to disk.
+pub const MAX_TXHASHSET_ARCHIVE_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// How long a peer gets to send us a whole txhashset archive, it gets much more
+/// than BODY_TIMEOUT but not to trickle it forever.
+pub const TXHASHSET_ARCHIVE_TIMEOUT: time::Duration = time::Duration::from_secs(10 * 60);
+
 /// Response to a txhashset archive request, must include a zip stream of the
 /// archive after the message body.
 pub struct TxHashSetArchive {
@@ -745,3 +1870,353 @@ impl Readable for KernelDataResponse {
 		Ok(KernelDataResponse { bytes })
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::core::core::{TransactionBody, TxKernel};
+
+	struct UserAgent(String);
+
+	impl Writeable for UserAgent {
+		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+			write_user_agent(writer, &self.0)
+		}
+	}
+
+	impl Readable for UserAgent {
+		fn read(reader: &mut dyn Reader) -> Result<UserAgent, ser::Error> {
+			Ok(UserAgent(read_user_agent(reader)?))
+		}
+	}
+
+	// Raw encoding of a user agent, bypassing the truncation on write.
+	fn raw_user_agent(ua: &str) -> Vec<u8> {
+		let mut vec = ser::ser_vec(&(ua.len() as u64)).unwrap();
+		vec.extend_from_slice(ua.as_bytes());
+		vec
+	}
+
+	#[test]
+	fn user_agent_too_long() {
+		let vec = raw_user_agent(&"a".repeat(MAX_USER_AGENT_LEN + 1));
+		let res: Result<UserAgent, _> = ser::deserialize(&mut &vec[..]);
+		assert_eq!(res.err(), Some(ser::Error::TooLargeReadErr));
+	}
+
+	#[test]
+	fn user_agent_control_chars() {
+		let vec = raw_user_agent("MW/Grin\n2.0.0\r");
+		let ua: UserAgent = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(ua.0, "MW/Grin2.0.0");
+	}
+
+	#[test]
+	fn user_agent_multibyte_boundary() {
+		// 64 two-byte chars, exactly at the limit
+		let exact = "\u{e9}".repeat(MAX_USER_AGENT_LEN / 2);
+		let vec = raw_user_agent(&exact);
+		let ua: UserAgent = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(ua.0, exact);
+
+		// one byte over, truncation on write must not split a char
+		let over = format!("a{}", exact);
+		let vec = ser::ser_vec(&UserAgent(over)).unwrap();
+		let ua: UserAgent = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(ua.0.len(), MAX_USER_AGENT_LEN - 1);
+		assert!(ua.0.starts_with('a'));
+	}
+
+	fn max_hand() -> Hand {
+		let host = "a".repeat(MAX_HOST_LEN);
+		Hand {
+			version: ProtocolVersion::default(),
+			min_version: ProtocolVersion::min_supported(),
+			capabilities: Capabilities::FULL_NODE,
+			nonce: 42,
+			genesis: Hash::from_vec(&vec![1]),
+			total_difficulty: Difficulty::min(),
+			height: Some(0),
+			sender_addr: PeerAddr::Dns(host.clone(), 3414),
+			receiver_addr: PeerAddr::Onion(host, 3414),
+			user_agent: "a".repeat(MAX_USER_AGENT_LEN),
+			node_id: Some(NodeId::random()),
+			// the lowest version, height and node id take some of the area
+			extensions: vec![(200, vec![0; MAX_EXTENSIONS_LEN - 1 - 7 - 11 - 19 - 3])],
+		}
+	}
+
+	#[test]
+	fn handshake_max_size() {
+		let hand = max_hand();
+		let vec = ser::ser_vec(&hand).unwrap();
+		assert_eq!(vec.len() as u64, max_msg_size(Type::Hand));
+		assert_eq!(max_msg_len(Type::Hand), max_msg_size(Type::Hand));
+
+		// before the height and node id moved to the extensions
+		let mut shake = Shake {
+			version: ProtocolVersion(2),
+			min_version: hand.min_version,
+			capabilities: hand.capabilities,
+			nonce: Some(hand.nonce),
+			genesis: hand.genesis,
+			total_difficulty: hand.total_difficulty,
+			height: hand.height,
+			observed_addr: Some(hand.sender_addr),
+			user_agent: hand.user_agent,
+			node_id: hand.node_id,
+			extensions: vec![(200, vec![0; MAX_EXTENSIONS_LEN - 1 - 3])],
+		};
+		let vec = ser::ser_vec(&shake).unwrap();
+		assert_eq!(vec.len() as u64, max_msg_size(Type::Shake));
+		assert_eq!(max_msg_len(Type::Shake), max_msg_size(Type::Shake));
+
+		// after, they take some of the extension area
+		shake.version = ProtocolVersion::default();
+		assert!(ser::ser_vec(&shake).is_err());
+		shake.extensions[0]
+			.1
+			.truncate(MAX_EXTENSIONS_LEN - 1 - 3 - 11 - 19);
+		let vec = ser::ser_vec(&shake).unwrap();
+		assert_eq!(vec.len() as u64, max_msg_size(Type::Shake) - 8 - 16);
+	}
+
+	#[test]
+	fn handshake_over_max_size() {
+		for msg_type in vec![Type::Hand, Type::Shake] {
+			for len in vec![max_msg_size(msg_type) + 1, u64::max_value()] {
+				// only the header is there, the body would never be read
+				let head = ser::ser_vec(&MsgHeader::new(msg_type, len)).unwrap();
+				let mut stream = std::io::Cursor::new(head);
+				match read_header(&mut stream, ProtocolVersion::handshake(), Some(msg_type)) {
+					Err(Error::MsgLen) => {}
+					res => panic!("expected msg len error, got {:?}", res.is_ok()),
+				}
+				assert_eq!(stream.position(), MsgHeader::LEN as u64);
+			}
+		}
+	}
+
+	#[test]
+	fn msg_len_boundaries() {
+		let mut types = 0;
+		for t in 0..=u8::max_value() {
+			let msg_type = match Type::from_u8(t) {
+				Some(msg_type) => msg_type,
+				None => continue,
+			};
+			types += 1;
+			let max_len = max_msg_len(msg_type);
+
+			let head = ser::ser_vec(&MsgHeader::new(msg_type, max_len)).unwrap();
+			match read_header(&mut &head[..], ProtocolVersion(1), None) {
+				Ok(MsgHeaderWrapper::Known(header)) => assert_eq!(header.msg_len, max_len),
+				_ => panic!("{:?} of max len {} not accepted", msg_type, max_len),
+			}
+
+			let head = ser::ser_vec(&MsgHeader::new(msg_type, max_len + 1)).unwrap();
+			match read_header(&mut &head[..], ProtocolVersion(1), None) {
+				Err(Error::MsgLen) => {}
+				_ => panic!("{:?} over max len {} accepted", msg_type, max_len),
+			}
+		}
+		assert_eq!(types, Type::Disconnect as u8 + 1);
+	}
+
+	// A block filled with kernels, the densest there is, still fits.
+	#[test]
+	fn max_block_fits() {
+		let kernels = global::max_block_weight() / consensus::BLOCK_KERNEL_WEIGHT;
+		let body = TransactionBody {
+			inputs: vec![],
+			outputs: vec![],
+			kernels: vec![TxKernel::empty(); kernels],
+		};
+		let mut block = ser::ser_vec(&BlockHeader::default()).unwrap();
+		block.extend_from_slice(&ser::ser_vec(&body).unwrap());
+		let len = block.len() as u64;
+		assert!(len <= max_msg_size(Type::Block));
+
+		let head = ser::ser_vec(&MsgHeader::new(Type::Block, len)).unwrap();
+		match read_header(&mut &head[..], ProtocolVersion(1), None) {
+			Ok(MsgHeaderWrapper::Known(header)) => assert_eq!(header.msg_len, len),
+			_ => panic!("block of {} bytes not accepted", len),
+		}
+	}
+
+	fn ping() -> Ping {
+		Ping {
+			total_difficulty: Difficulty::min(),
+			height: 42,
+		}
+	}
+
+	#[test]
+	fn checksum_round_trip() {
+		let version = ProtocolVersion::default();
+		assert!(version.has_checksum());
+		let buf = write_to_buf(ping(), version, Type::Ping).unwrap();
+		assert_eq!(buf.len(), MsgHeader::LEN + CHECKSUM_LEN + 16);
+		let received: Ping = read_message(&mut &buf[..], version, Type::Ping).unwrap();
+		assert_eq!(received.height, 42);
+
+		// older peers get the header they know
+		let old = ProtocolVersion(1);
+		let buf = write_to_buf(ping(), old, Type::Ping).unwrap();
+		assert_eq!(buf.len(), MsgHeader::LEN + 16);
+		let received: Ping = read_message(&mut &buf[..], old, Type::Ping).unwrap();
+		assert_eq!(received.height, 42);
+	}
+
+	#[test]
+	fn checksum_body_corrupted() {
+		let version = ProtocolVersion::default();
+		let buf = write_to_buf(ping(), version, Type::Ping).unwrap();
+		for i in version.header_len()..buf.len() {
+			let mut corrupted = buf.clone();
+			corrupted[i] ^= 1;
+			match read_message::<Ping>(&mut &corrupted[..], version, Type::Ping) {
+				Err(Error::Corruption) => {}
+				res => panic!("flipped body byte {} not caught, got {:?}", i, res.is_ok()),
+			}
+		}
+	}
+
+	#[test]
+	fn checksum_len_corrupted() {
+		// a few msgs in a row, a longer length takes bytes of the next one in
+		// and a shorter one leaves part of the body out
+		let version = ProtocolVersion::default();
+		let mut buf = vec![];
+		for _ in 0..4 {
+			buf.append(&mut write_to_buf(ping(), version, Type::Ping).unwrap());
+		}
+		// flipping bits of the low (big endian) length byte of 16
+		for bit in 0..6 {
+			let mut corrupted = buf.clone();
+			corrupted[MsgHeader::LEN - 1] ^= 1 << bit;
+			match read_message::<Ping>(&mut &corrupted[..], version, Type::Ping) {
+				Err(Error::Corruption) => {}
+				res => panic!("flipped len bit {} not caught, got {:?}", bit, res.is_ok()),
+			}
+		}
+		// and over the max of the type it doesn't get to the body
+		let mut corrupted = buf.clone();
+		corrupted[MsgHeader::LEN - 1] ^= 1 << 6;
+		match read_message::<Ping>(&mut &corrupted[..], version, Type::Ping) {
+			Err(Error::MsgLen) => {}
+			res => panic!("expected msg len error, got {:?}", res.is_ok()),
+		}
+	}
+
+	// Hands out what it has then nothing more, like a peer gone silent.
+	struct Stalled(io::Cursor<Vec<u8>>);
+
+	impl Read for Stalled {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			match self.0.read(buf)? {
+				0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled")),
+				n => Ok(n),
+			}
+		}
+	}
+
+	#[test]
+	fn body_deadline() {
+		let version = ProtocolVersion::default();
+		let mut buf = write_to_buf(ping(), version, Type::Ping).unwrap();
+		// the header and half the body
+		buf.truncate(version.header_len() + 8);
+		let mut stream = Stalled(io::Cursor::new(buf));
+
+		let header = match read_header(&mut stream, version, None) {
+			Ok(MsgHeaderWrapper::Known(header)) => header,
+			_ => panic!("expected a ping header"),
+		};
+		let start = Instant::now();
+		let timeout = time::Duration::from_millis(200);
+		match read_body::<Ping>(&header, &mut stream, start + timeout) {
+			Err(Error::Timeout) => {}
+			res => panic!("expected a timeout, got {:?}", res.is_ok()),
+		}
+		let elapsed = start.elapsed();
+		assert!(elapsed >= timeout);
+		assert!(elapsed < time::Duration::from_secs(2));
+
+		// same for a body we'd discard
+		let start = Instant::now();
+		match read_discard(
+			16,
+			&mut Stalled(io::Cursor::new(vec![0; 8])),
+			start + timeout,
+		) {
+			Err(Error::Timeout) => {}
+			res => panic!("expected a timeout, got {:?}", res.is_ok()),
+		}
+	}
+
+	// Only so many buffers are kept, and none of the large ones.
+	#[test]
+	fn buf_pool_capped() {
+		let bufs: Vec<_> = (0..MAX_POOLED_BUFS + 4)
+			.map(|_| {
+				let mut buf = PooledBuf::take();
+				buf.extend_from_slice(&[1; 100]);
+				buf
+			})
+			.collect();
+		drop(bufs);
+		assert!(PooledBuf::pooled() <= MAX_POOLED_BUFS);
+
+		let mut large = PooledBuf::take();
+		large.resize(MAX_POOLED_BUF_CAPACITY + 1, 0);
+		drop(large);
+		for _ in 0..MAX_POOLED_BUFS {
+			let buf = PooledBuf::take();
+			assert!(buf.is_empty());
+			assert!(buf.capacity() <= MAX_POOLED_BUF_CAPACITY);
+		}
+	}
+
+	fn locator(n: u8) -> Locator {
+		Locator {
+			hashes: (0..n).map(|i| Hash::from_vec(&vec![i; 32])).collect(),
+		}
+	}
+
+	// We stop looking at the first hash on our main chain.
+	#[test]
+	fn locator_fork_point() {
+		let loc = locator(10);
+		let looked_up = std::cell::Cell::new(0);
+		let fork_point = loc.fork_point(|hash| {
+			looked_up.set(looked_up.get() + 1);
+			match hash.as_bytes()[0] {
+				// a fork we know of, then our main chain
+				3 => Some((20, false)),
+				4 => Some((16, true)),
+				5 => Some((8, true)),
+				_ => None,
+			}
+		});
+		assert_eq!(fork_point.unwrap(), Some(4));
+		assert_eq!(looked_up.get(), 5);
+
+		assert_eq!(loc.fork_point(|_| None).unwrap(), None);
+	}
+
+	// Known headers going up rather than down, the locator is refused.
+	#[test]
+	fn locator_unordered() {
+		let loc = locator(10);
+		let fork_point = loc.fork_point(|hash| match hash.as_bytes()[0] {
+			2 => Some((8, false)),
+			4 => Some((16, true)),
+			_ => None,
+		});
+		match fork_point {
+			Err(Error::BadMessage) => {}
+			res => panic!("expected the locator refused, got {:?}", res),
+		}
+	}
+}
@@ -13,12 +13,14 @@
 // limitations under the License.
 
 use crate::util::{Mutex, RwLock};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::net::{Shutdown, TcpStream};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::chain;
 use crate::conn;
@@ -28,17 +30,28 @@ use crate::core::ser::Writeable;
 use crate::core::{core, global};
 use crate::handshake::Handshake;
 use crate::msg::{
-	self, BanReason, GetPeerAddrs, KernelDataRequest, Locator, Ping, TxHashSetRequest, Type,
+	self, BanReason, BlockInv, Disconnect, DisconnectReason, GetPeerAddrs, KernelDataRequest,
+	Locator, PeerError, PeerErrorCode, Ping, TxHashSetRequest, Type,
 };
-use crate::protocol::Protocol;
+use crate::protocol::{PendingRequest, Protocol, RequestTracker, Requested};
+use crate::transport::SessionKeys;
 use crate::types::{
-	Capabilities, ChainAdapter, Error, NetAdapter, P2PConfig, PeerAddr, PeerInfo, ReasonForBan,
-	TxHashSetRead,
+	notify, Capabilities, ChainAdapter, Error, NetAdapter, Offense, P2PConfig, PeerAddr, PeerInfo,
+	PeerStats, ProtocolObserver, ReasonForBan, TxHashSetRead,
 };
 use chrono::prelude::{DateTime, Utc};
+use lru_cache::LruCache;
 
 const MAX_TRACK_SIZE: usize = 30;
+/// Block hashes we remember a peer knows about, recently seen ones are kept.
+const MAX_KNOWN_BLOCKS: usize = 2_000;
+/// Tx kernel hashes we remember a peer knows about.
+const MAX_KNOWN_TXS: usize = 5_000;
+/// Blocks and txs a peer asked us for while we didn't have them yet.
+const MAX_WANTED: usize = 100;
 const MAX_PEER_MSG_PER_MIN: u64 = 500;
+/// Pings a peer can leave unanswered in a row before we drop it.
+const MAX_UNANSWERED_PINGS: u32 = 3;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// Remind: don't mix up this 'State' with that 'State' in p2p/src/store.rs,
@@ -55,11 +68,15 @@ pub struct Peer {
 	// set of all hashes known to this peer (so no need to send)
 	tracking_adapter: TrackingAdapter,
 	tracker: Arc<conn::Tracker>,
+	// requests sent and not answered yet
+	requests: Arc<RequestTracker>,
 	send_handle: Mutex<conn::ConnHandle>,
 	// we need a special lock for stop operation, can't reuse handle mutex for that
 	// because it may be locked by different reasons, so we should wait for that, close
 	// mutex can be taken only during shutdown, it happens once
 	stop_handle: Mutex<conn::StopHandle>,
+	// watches what we send the peer, if set
+	observer: Option<Arc<dyn ProtocolObserver>>,
 }
 
 impl fmt::Debug for Peer {
@@ -70,12 +87,40 @@ impl fmt::Debug for Peer {
 
 impl Peer {
 	// Only accept and connect can be externally used to build a peer
-	fn new(info: PeerInfo, conn: TcpStream, adapter: Arc<dyn NetAdapter>) -> std::io::Result<Peer> {
+	fn new(
+		info: PeerInfo,
+		conn: TcpStream,
+		keys: Option<SessionKeys>,
+		hs: &Handshake,
+		adapter: Arc<dyn NetAdapter>,
+	) -> Result<Peer, Error> {
+		let config = hs.config();
+		let observer = hs.observer();
 		let state = Arc::new(RwLock::new(State::Connected));
 		let tracking_adapter = TrackingAdapter::new(adapter);
-		let handler = Protocol::new(Arc::new(tracking_adapter.clone()), info.clone());
+		let requests = Arc::new(RequestTracker::new());
+		let handler = Protocol::for_version(
+			info.version,
+			Arc::new(tracking_adapter.clone()),
+			info.clone(),
+			requests.clone(),
+			config.rate_limits(),
+			observer.clone(),
+		)?;
 		let tracker = Arc::new(conn::Tracker::new());
-		let (sendh, stoph) = conn::listen(conn, tracker.clone(), handler)?;
+		// the handshake counts, not as msgs though
+		let (sent, received) = info.live_info.read().handshake_bytes;
+		tracker.inc_quiet_sent(sent);
+		tracker.inc_quiet_received(received);
+		let opts = conn::ConnOptions {
+			compress: info.negotiated.contains(Capabilities::COMPRESSION),
+			max_queued: config.max_queued_bytes(),
+			keepalive: config.keepalive_interval(),
+			idle_timeout: config.idle_timeout(),
+			gossip_interval: config.gossip_interval(),
+		};
+		let (sendh, stoph) =
+			conn::listen(conn, info.version, opts, keys, tracker.clone(), handler)?;
 		let send_handle = Mutex::new(sendh);
 		let stop_handle = Mutex::new(stoph);
 		Ok(Peer {
@@ -83,8 +128,10 @@ impl Peer {
 			state,
 			tracking_adapter,
 			tracker,
+			requests,
 			send_handle,
 			stop_handle,
+			observer,
 		})
 	}
 
@@ -92,13 +139,23 @@ impl Peer {
 		mut conn: TcpStream,
 		capab: Capabilities,
 		total_difficulty: Difficulty,
+		height: u64,
 		hs: &Handshake,
 		adapter: Arc<dyn NetAdapter>,
 	) -> Result<Peer, Error> {
 		debug!("accept: handshaking from {:?}", conn.peer_addr());
-		let info = hs.accept(capab, total_difficulty, &mut conn);
+		let info = hs
+			.accept(
+				capab,
+				total_difficulty,
+				height,
+				conn.peer_addr().ok(),
+				&mut conn,
+				&|addr| adapter.is_banned(addr.clone()),
+			)
+			.and_then(|info| Ok((hs.encrypt(&info, &mut conn)?, info)));
 		match info {
-			Ok(info) => Ok(Peer::new(info, conn, adapter)?),
+			Ok((keys, info)) => Peer::new(info, conn, keys, hs, adapter),
 			Err(e) => {
 				debug!(
 					"accept: handshaking from {:?} failed with error: {:?}",
@@ -115,16 +172,28 @@ impl Peer {
 
 	pub fn connect(
 		mut conn: TcpStream,
+		peer_addr: PeerAddr,
 		capab: Capabilities,
 		total_difficulty: Difficulty,
+		height: u64,
 		self_addr: PeerAddr,
 		hs: &Handshake,
 		adapter: Arc<dyn NetAdapter>,
 	) -> Result<Peer, Error> {
 		debug!("connect: handshaking with {:?}", conn.peer_addr());
-		let info = hs.initiate(capab, total_difficulty, self_addr, &mut conn);
+		let info = hs
+			.initiate(
+				capab,
+				total_difficulty,
+				height,
+				self_addr,
+				peer_addr,
+				&mut conn,
+				&|addr| adapter.is_banned(addr.clone()),
+			)
+			.and_then(|info| Ok((hs.encrypt(&info, &mut conn)?, info)));
 		match info {
-			Ok(info) => Ok(Peer::new(info, conn, adapter)?),
+			Ok((keys, info)) => Peer::new(info, conn, keys, hs, adapter),
 			Err(e) => {
 				debug!(
 					"connect: handshaking with {:?} failed with error: {:?}",
@@ -139,9 +208,9 @@ impl Peer {
 		}
 	}
 
-	pub fn is_denied(config: &P2PConfig, peer_addr: PeerAddr) -> bool {
+	pub fn is_denied(config: &P2PConfig, peer_addr: &PeerAddr) -> bool {
 		if let Some(ref denied) = config.peers_deny {
-			if denied.contains(&peer_addr) {
+			if denied.contains(peer_addr) {
 				debug!(
 					"checking peer allowed/denied: {:?} explicitly denied",
 					peer_addr
@@ -150,7 +219,7 @@ impl Peer {
 			}
 		}
 		if let Some(ref allowed) = config.peers_allow {
-			if allowed.contains(&peer_addr) {
+			if allowed.contains(peer_addr) {
 				debug!(
 					"checking peer allowed/denied: {:?} explicitly allowed",
 					peer_addr
@@ -170,9 +239,27 @@ impl Peer {
 		false
 	}
 
-	/// Whether this peer is currently connected.
+	/// Whether the peer knows about this block already, it sent it to us or
+	/// we sent (or announced) it.
+	pub fn knows_block(&self, h: Hash) -> bool {
+		self.tracking_adapter.knows_block(h)
+	}
+
+	/// Same as `knows_block` for a tx, by kernel hash.
+	pub fn knows_tx(&self, kernel_hash: Hash) -> bool {
+		self.tracking_adapter.knows_tx(kernel_hash)
+	}
+
+	/// Whether the peer asked us for this block or tx (by kernel hash) before
+	/// we had it, and should get it as soon as we do.
+	pub fn wants(&self, h: Hash) -> bool {
+		self.tracking_adapter.wants(h)
+	}
+
+	/// Whether this peer is currently connected. A peer whose connection
+	/// went dead (it stalled or hung up on us) isn't.
 	pub fn is_connected(&self) -> bool {
-		State::Connected == *self.state.read()
+		State::Connected == *self.state.read() && !self.tracker.is_closed()
 	}
 
 	/// Whether this peer has been banned.
@@ -199,12 +286,66 @@ impl Peer {
 		rec.count_per_min() > MAX_PEER_MSG_PER_MIN || sent.count_per_min() > MAX_PEER_MSG_PER_MIN
 	}
 
+	/// Whether the peer stopped answering our pings. A peer busy sending us
+	/// something large (a txhashset) may answer late, it's given a pass as
+	/// long as we receive anything from it. Also true once the connection
+	/// was closed after the peer went silent.
+	pub fn is_unresponsive(&self) -> bool {
+		self.tracker.is_idle()
+			|| (self.info.unanswered_pings() >= MAX_UNANSWERED_PINGS
+				&& self.tracker.received_bytes.read().bytes_per_min() == 0)
+	}
+
 	/// Number of bytes sent to the peer
 	pub fn last_min_sent_bytes(&self) -> Option<u64> {
 		let sent_bytes = self.tracker.sent_bytes.read();
 		Some(sent_bytes.bytes_per_min())
 	}
 
+	/// A snapshot of the peer and our connection with it. Bandwidth is since
+	/// we connected, handshake included.
+	pub fn stats(&self) -> PeerStats {
+		let by_name = |counts: Vec<(Type, u64)>| {
+			counts
+				.into_iter()
+				.map(|(t, n)| (format!("{:?}", t), n))
+				.collect::<BTreeMap<_, _>>()
+		};
+		let live_info = self.info.live_info.read();
+		PeerStats {
+			addr: self.info.addr.clone(),
+			version: self.info.version,
+			capabilities: self.info.capabilities,
+			direction: self.info.direction,
+			user_agent: self.info.user_agent.clone(),
+			total_difficulty: live_info.total_difficulty,
+			height: live_info.height,
+			sent_bytes: self.tracker.sent_total(),
+			received_bytes: self.tracker.received_total(),
+			sent_rate: self.last_min_sent_bytes().unwrap_or(0) / 60,
+			recv_rate: self.last_min_received_bytes().unwrap_or(0) / 60,
+			msgs_sent: by_name(self.tracker.msgs_sent()),
+			msgs_received: by_name(self.tracker.msgs_received()),
+			outstanding_requests: self.requests.pending(),
+			queued_bytes: self.tracker.queued_bytes(),
+			// kept by Peers, see Peers::connected_stats
+			misbehavior: 0,
+			discouraged: false,
+			last_seen: live_info.last_seen,
+			preferred: false,
+			rtt_ms: live_info
+				.rtt_estimate
+				.or(live_info.ping_rtt)
+				.map(|rtt| rtt.as_millis() as u64),
+			max_rtt_ms: live_info.rtt_max.map(|rtt| rtt.as_millis() as u64),
+		}
+	}
+
+	/// Number of bytes waiting to be sent to the peer
+	pub fn queued_bytes(&self) -> usize {
+		self.tracker.queued_bytes()
+	}
+
 	/// Number of bytes received from the peer
 	pub fn last_min_received_bytes(&self) -> Option<u64> {
 		let received_bytes = self.tracker.received_bytes.read();
@@ -223,10 +364,35 @@ impl Peer {
 	}
 
 	/// Send a msg with given msg_type to our peer via the connection.
+	// A peer too slow to read what we send it doesn't get the msgs it can do
+	// without (see msg::is_droppable), it's disconnected when it can't even
+	// get the others.
 	fn send<T: Writeable>(&self, msg: T, msg_type: Type) -> Result<(), Error> {
-		let bytes = self.send_handle.lock().send(msg, msg_type)?;
-		self.tracker.inc_sent(bytes);
-		Ok(())
+		let res = self.send_handle.lock().send(msg, msg_type);
+		if let Err(ref e) = res {
+			notify(&self.observer, |o| o.on_error(&self.info.addr, e));
+		}
+		match res {
+			Ok(bytes) => {
+				self.tracker.inc_sent(bytes);
+				self.tracker.inc_msg_sent(msg_type);
+				notify(&self.observer, |o| {
+					o.on_send(msg_type, bytes, &self.info.addr)
+				});
+				Ok(())
+			}
+			Err(Error::QueueFull) if !msg::is_droppable(msg_type) => {
+				debug!(
+					"Send queue of {} full ({} bytes), can't send {:?}",
+					self.info.addr,
+					self.queued_bytes(),
+					msg_type
+				);
+				self.stop_with_error(PeerErrorCode::TooSlow, "too slow".to_owned());
+				Err(Error::QueueFull)
+			}
+			Err(e) => Err(e),
+		}
 	}
 
 	/// Send a ping to the remote peer, providing our local difficulty and
@@ -235,22 +401,19 @@ impl Peer {
 		let ping_msg = Ping {
 			total_difficulty,
 			height,
+			nonce: Some(self.info.ping_sent()),
 		};
-		self.send(ping_msg, msg::Type::Ping)
-	}
-
-	/// Send the ban reason before banning
-	pub fn send_ban_reason(&self, ban_reason: ReasonForBan) -> Result<(), Error> {
-		let ban_reason_msg = BanReason { ban_reason };
-		self.send(ban_reason_msg, msg::Type::BanReason).map(|_| ())
+		self.send(ping_msg, msg::Type::Ping)?;
+		Ok(())
 	}
 
 	/// Sends the provided block to the remote peer. The request may be dropped
 	/// if the remote peer is known to already have the block.
 	pub fn send_block(&self, b: &core::Block) -> Result<bool, Error> {
-		if !self.tracking_adapter.has_recv(b.hash()) {
+		if !self.tracking_adapter.knows_block(b.hash()) {
 			trace!("Send block {} to {}", b.hash(), self.info.addr);
 			self.send(b, msg::Type::Block)?;
+			self.tracking_adapter.push_block(b.hash());
 			Ok(true)
 		} else {
 			debug!(
@@ -263,9 +426,10 @@ impl Peer {
 	}
 
 	pub fn send_compact_block(&self, b: &core::CompactBlock) -> Result<bool, Error> {
-		if !self.tracking_adapter.has_recv(b.hash()) {
+		if !self.tracking_adapter.knows_block(b.hash()) {
 			trace!("Send compact block {} to {}", b.hash(), self.info.addr);
 			self.send(b, msg::Type::CompactBlock)?;
+			self.tracking_adapter.push_block(b.hash());
 			Ok(true)
 		} else {
 			debug!(
@@ -278,9 +442,10 @@ impl Peer {
 	}
 
 	pub fn send_header(&self, bh: &core::BlockHeader) -> Result<bool, Error> {
-		if !self.tracking_adapter.has_recv(bh.hash()) {
+		if !self.tracking_adapter.knows_block(bh.hash()) {
 			debug!("Send header {} to {}", bh.hash(), self.info.addr);
 			self.send(bh, msg::Type::Header)?;
+			self.tracking_adapter.push_block(bh.hash());
 			Ok(true)
 		} else {
 			debug!(
@@ -292,10 +457,14 @@ impl Peer {
 		}
 	}
 
+	/// Announces a tx by its kernel hash, the remote peer asks for the full
+	/// tx if it doesn't have it. Each tx is only announced once.
 	pub fn send_tx_kernel_hash(&self, h: Hash) -> Result<bool, Error> {
-		if !self.tracking_adapter.has_recv(h) {
+		if !self.tracking_adapter.knows_tx(h) {
 			debug!("Send tx kernel hash {} to {}", h, self.info.addr);
 			self.send(h, msg::Type::TransactionKernel)?;
+			// the peer knows about it now, either way
+			self.tracking_adapter.push_tx(h);
 			Ok(true)
 		} else {
 			debug!(
@@ -306,24 +475,38 @@ impl Peer {
 		}
 	}
 
+	/// Announces a block by hash, the remote peer asks for the full block if
+	/// it doesn't have it. Each block is only announced once.
+	pub fn send_block_inv(&self, h: Hash, height: u64) -> Result<bool, Error> {
+		if !self.tracking_adapter.knows_block(h) {
+			debug!("Send block inv {} to {}", h, self.info.addr);
+			self.send(BlockInv { hash: h, height }, msg::Type::BlockInv)?;
+			self.tracking_adapter.push_block(h);
+			Ok(true)
+		} else {
+			debug!(
+				"Not sending block inv {} to {} (already seen)",
+				h, self.info.addr
+			);
+			Ok(false)
+		}
+	}
+
 	/// Sends the provided transaction to the remote peer. The request may be
 	/// dropped if the remote peer is known to already have the transaction.
-	/// We support broadcast of lightweight tx kernel hash
-	/// so track known txs by kernel hash.
+	/// Peers supporting it only get the lightweight tx kernel hash, others
+	/// the full tx, so track known txs by kernel hash.
 	pub fn send_transaction(&self, tx: &core::Transaction) -> Result<bool, Error> {
 		let kernel = &tx.kernels()[0];
 
-		if self
-			.info
-			.capabilities
-			.contains(Capabilities::TX_KERNEL_HASH)
-		{
+		if self.info.negotiated.contains(Capabilities::TX_KERNEL_HASH) {
 			return self.send_tx_kernel_hash(kernel.hash());
 		}
 
-		if !self.tracking_adapter.has_recv(kernel.hash()) {
+		if !self.tracking_adapter.knows_tx(kernel.hash()) {
 			debug!("Send full tx {} to {}", tx.hash(), self.info.addr);
 			self.send(tx, msg::Type::Transaction)?;
+			self.tracking_adapter.push_tx(kernel.hash());
 			Ok(true)
 		} else {
 			debug!(
@@ -345,6 +528,8 @@ impl Peer {
 
 	/// Sends a request for block headers from the provided block locator
 	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
+		self.requests
+			.sent(msg::Type::GetHeaders, Requested::Headers(locator.clone()));
 		self.send(&Locator { hashes: locator }, msg::Type::GetHeaders)
 	}
 
@@ -360,17 +545,54 @@ impl Peer {
 	pub fn send_block_request(&self, h: Hash) -> Result<(), Error> {
 		debug!("Requesting block {} from peer {}.", h, self.info.addr);
 		self.tracking_adapter.push_req(h);
+		self.requests.sent(msg::Type::GetBlock, Requested::Block(h));
 		self.send(&h, msg::Type::GetBlock)
 	}
 
+	/// Sends a request for the block at the provided height on the peer's
+	/// main chain. The peer answers with the block, or nothing if its chain
+	/// isn't that long. What it sends back isn't trusted to be at that height,
+	/// see `TrackingAdapter::block_received`.
+	pub fn send_block_request_by_height(&self, height: u64) -> Result<(), Error> {
+		debug!(
+			"Requesting block at {} from peer {}.",
+			height, self.info.addr
+		);
+		self.tracking_adapter.push_req_height(height);
+		self.requests
+			.sent(msg::Type::GetBlockByHeight, Requested::BlockAt(height));
+		self.send(height, msg::Type::GetBlockByHeight)
+	}
+
 	/// Sends a request for a specific compact block by hash
 	pub fn send_compact_block_request(&self, h: Hash) -> Result<(), Error> {
 		debug!("Requesting compact block {} from {}", h, self.info.addr);
+		self.requests
+			.sent(msg::Type::GetCompactBlock, Requested::Block(h));
 		self.send(&h, msg::Type::GetCompactBlock)
 	}
 
+	/// Takes our requests the peer left unanswered for longer than `ttl`, to
+	/// ask someone else. Counted against the peer, see `request_strikes`.
+	pub fn expired_requests(&self, ttl: Duration) -> Vec<PendingRequest> {
+		self.requests.expired(ttl)
+	}
+
+	/// How many of our requests the peer is still to answer, it's serving
+	/// us as long as there's any.
+	pub fn pending_requests(&self) -> usize {
+		self.requests.pending()
+	}
+
+	/// How many of our requests the peer left unanswered, peers with less
+	/// are preferred.
+	pub fn request_strikes(&self) -> usize {
+		self.requests.strikes()
+	}
+
 	pub fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
 		trace!("Asking {} for more peers {:?}", self.info.addr, capab);
+		self.requests.peer_addrs_asked();
 		self.send(
 			&GetPeerAddrs {
 				capabilities: capab,
@@ -404,6 +626,71 @@ impl Peer {
 		}
 	}
 
+	/// Stops the peer, telling it why right before closing the connection.
+	/// Best effort only, the ban reason is written with a short timeout and
+	/// whatever is still queued to send is dropped.
+	pub fn stop_with_reason(&self, ban_reason: ReasonForBan, message: Option<String>) {
+		debug!(
+			"Stopping peer {:?}, reason {:?}",
+			self.info.addr, ban_reason
+		);
+		let msg = BanReason {
+			ban_reason,
+			message,
+		};
+		let last = match msg::write_to_buf(msg, self.info.version, Type::BanReason) {
+			Ok(last) => last,
+			Err(e) => {
+				error!("failed to serialize ban reason: {:?}", e);
+				return self.stop();
+			}
+		};
+		match self.stop_handle.try_lock() {
+			Some(handle) => handle.stop_with(last),
+			None => error!("can't get stop lock for peer"),
+		}
+	}
+
+	/// Stops the peer for breaking the protocol, sending it a PeerError right
+	/// before closing the connection. Best effort only, like stop_with_reason.
+	pub fn stop_with_error(&self, code: PeerErrorCode, message: String) {
+		debug!("Stopping peer {:?}, error {:?}", self.info.addr, code);
+		let err = PeerError::new(code, message);
+		let last = match msg::write_to_buf(err, self.info.version, Type::PeerError) {
+			Ok(last) => last,
+			Err(e) => {
+				error!("failed to serialize peer error: {:?}", e);
+				return self.stop();
+			}
+		};
+		match self.stop_handle.try_lock() {
+			Some(handle) => handle.stop_with(last),
+			None => error!("can't get stop lock for peer"),
+		}
+	}
+
+	/// Closes the connection in good order: nothing more is accepted to send,
+	/// what's already queued gets flushed (within `CLOSE_FLUSH_TIMEOUT`) and
+	/// the peer is told why we're leaving before the connection drops.
+	pub fn disconnect(&self, reason: DisconnectReason) {
+		debug!(
+			"Disconnecting peer {:?}, reason {:?}",
+			self.info.addr, reason
+		);
+		let msg = Disconnect::new(reason);
+		let last = match msg::write_to_buf(msg, self.info.version, Type::Disconnect) {
+			Ok(last) => last,
+			Err(e) => {
+				error!("failed to serialize disconnect: {:?}", e);
+				return self.stop();
+			}
+		};
+		match self.stop_handle.try_lock() {
+			Some(handle) => handle.close(last),
+			None => error!("can't get stop lock for peer"),
+		}
+	}
+
 	/// Waits until the peer's thread exit
 	pub fn wait(&self) {
 		debug!("Waiting for peer {:?} to stop", self.info.addr);
@@ -417,37 +704,54 @@ impl Peer {
 /// Adapter implementation that forwards everything to an underlying adapter
 /// but keeps track of the block and transaction hashes that were requested or
 /// received.
+/// Keeps track of what the peer knows about, whether it sent it to us or we
+/// sent it, so we don't send it again. Blocks and txs are tracked apart, the
+/// many txs don't push blocks out. Starts empty with every connection.
 #[derive(Clone)]
 struct TrackingAdapter {
 	adapter: Arc<dyn NetAdapter>,
-	known: Arc<RwLock<Vec<Hash>>>,
+	known_blocks: Arc<Mutex<LruCache<Hash, ()>>>,
+	known_txs: Arc<Mutex<LruCache<Hash, ()>>>,
+	wanted: Arc<Mutex<LruCache<Hash, ()>>>,
 	requested: Arc<RwLock<Vec<Hash>>>,
+	requested_heights: Arc<RwLock<Vec<u64>>>,
 }
 
 impl TrackingAdapter {
 	fn new(adapter: Arc<dyn NetAdapter>) -> TrackingAdapter {
 		TrackingAdapter {
 			adapter: adapter,
-			known: Arc::new(RwLock::new(Vec::with_capacity(MAX_TRACK_SIZE))),
+			known_blocks: Arc::new(Mutex::new(LruCache::new(MAX_KNOWN_BLOCKS))),
+			known_txs: Arc::new(Mutex::new(LruCache::new(MAX_KNOWN_TXS))),
+			wanted: Arc::new(Mutex::new(LruCache::new(MAX_WANTED))),
 			requested: Arc::new(RwLock::new(Vec::with_capacity(MAX_TRACK_SIZE))),
+			requested_heights: Arc::new(RwLock::new(Vec::with_capacity(MAX_TRACK_SIZE))),
 		}
 	}
 
-	fn has_recv(&self, hash: Hash) -> bool {
-		let known = self.known.read();
-		// may become too slow, an ordered set (by timestamp for eviction) may
-		// end up being a better choice
-		known.contains(&hash)
+	fn knows_block(&self, hash: Hash) -> bool {
+		self.known_blocks.lock().contains_key(&hash)
 	}
 
-	fn push_recv(&self, hash: Hash) {
-		let mut known = self.known.write();
-		if known.len() > MAX_TRACK_SIZE {
-			known.truncate(MAX_TRACK_SIZE);
-		}
-		if !known.contains(&hash) {
-			known.insert(0, hash);
-		}
+	fn push_block(&self, hash: Hash) {
+		self.known_blocks.lock().insert(hash, ());
+	}
+
+	fn knows_tx(&self, kernel_hash: Hash) -> bool {
+		self.known_txs.lock().contains_key(&kernel_hash)
+	}
+
+	fn push_tx(&self, kernel_hash: Hash) {
+		self.known_txs.lock().insert(kernel_hash, ());
+	}
+
+	// Blocks by hash, txs by kernel hash.
+	fn wants(&self, hash: Hash) -> bool {
+		self.wanted.lock().contains_key(&hash)
+	}
+
+	fn push_wanted(&self, hash: Hash) {
+		self.wanted.lock().insert(hash, ());
 	}
 
 	fn has_req(&self, hash: Hash) -> bool {
@@ -466,6 +770,29 @@ impl TrackingAdapter {
 			requested.insert(0, hash);
 		}
 	}
+
+	fn push_req_height(&self, height: u64) {
+		let mut requested = self.requested_heights.write();
+		if requested.len() > MAX_TRACK_SIZE {
+			requested.truncate(MAX_TRACK_SIZE);
+		}
+		if !requested.contains(&height) {
+			requested.insert(0, height);
+		}
+	}
+
+	/// Whether we asked for a block at this height, a request is only
+	/// answered once.
+	fn take_req_height(&self, height: u64) -> bool {
+		let mut requested = self.requested_heights.write();
+		match requested.iter().position(|h| *h == height) {
+			Some(pos) => {
+				requested.remove(pos);
+				true
+			}
+			None => false,
+		}
+	}
 }
 
 impl ChainAdapter for TrackingAdapter {
@@ -478,7 +805,11 @@ impl ChainAdapter for TrackingAdapter {
 	}
 
 	fn get_transaction(&self, kernel_hash: Hash) -> Option<core::Transaction> {
-		self.adapter.get_transaction(kernel_hash)
+		let tx = self.adapter.get_transaction(kernel_hash);
+		if tx.is_none() {
+			self.push_wanted(kernel_hash);
+		}
+		tx
 	}
 
 	fn tx_kernel_received(
@@ -486,23 +817,39 @@ impl ChainAdapter for TrackingAdapter {
 		kernel_hash: Hash,
 		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
-		self.push_recv(kernel_hash);
+		self.push_tx(kernel_hash);
 		self.adapter.tx_kernel_received(kernel_hash, peer_info)
 	}
 
+	fn block_inv_received(
+		&self,
+		hash: Hash,
+		height: u64,
+		peer_info: &PeerInfo,
+	) -> Result<bool, chain::Error> {
+		// announced (or sent) before, either way we asked already or the peer
+		// knows we have it
+		if self.knows_block(hash) {
+			return Ok(true);
+		}
+		self.push_block(hash);
+		self.adapter.block_inv_received(hash, height, peer_info)
+	}
+
 	fn transaction_received(
 		&self,
 		tx: core::Transaction,
 		stem: bool,
+		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
 		// Do not track the tx hash for stem txs.
 		// Otherwise we fail to handle the subsequent fluff or embargo expiration
 		// correctly.
 		if !stem {
 			let kernel = &tx.kernels()[0];
-			self.push_recv(kernel.hash());
+			self.push_tx(kernel.hash());
 		}
-		self.adapter.transaction_received(tx, stem)
+		self.adapter.transaction_received(tx, stem, peer_info)
 	}
 
 	fn block_received(
@@ -512,8 +859,11 @@ impl ChainAdapter for TrackingAdapter {
 		_was_requested: bool,
 	) -> Result<bool, chain::Error> {
 		let bh = b.hash();
-		self.push_recv(bh);
-		self.adapter.block_received(b, peer_info, self.has_req(bh))
+		self.push_block(bh);
+		// what the peer thinks is at the height we asked for doesn't matter,
+		// only a block actually at that height answers the request
+		let requested = self.has_req(bh) || self.take_req_height(b.header.height);
+		self.adapter.block_received(b, peer_info, requested)
 	}
 
 	fn compact_block_received(
@@ -521,7 +871,7 @@ impl ChainAdapter for TrackingAdapter {
 		cb: core::CompactBlock,
 		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
-		self.push_recv(cb.hash());
+		self.push_block(cb.hash());
 		self.adapter.compact_block_received(cb, peer_info)
 	}
 
@@ -530,7 +880,7 @@ impl ChainAdapter for TrackingAdapter {
 		bh: core::BlockHeader,
 		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
-		self.push_recv(bh.hash());
+		self.push_block(bh.hash());
 		self.adapter.header_received(bh, peer_info)
 	}
 
@@ -547,7 +897,15 @@ impl ChainAdapter for TrackingAdapter {
 	}
 
 	fn get_block(&self, h: Hash) -> Option<core::Block> {
-		self.adapter.get_block(h)
+		let b = self.adapter.get_block(h);
+		if b.is_none() {
+			self.push_wanted(h);
+		}
+		b
+	}
+
+	fn get_block_by_height(&self, height: u64) -> Option<core::Block> {
+		self.adapter.get_block_by_height(height)
 	}
 
 	fn kernel_data_read(&self) -> Result<File, chain::Error> {
@@ -595,19 +953,87 @@ impl ChainAdapter for TrackingAdapter {
 }
 
 impl NetAdapter for TrackingAdapter {
-	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr> {
-		self.adapter.find_peer_addrs(capab)
+	fn find_peer_addrs(&self, from: PeerAddr, capab: Capabilities) -> Vec<PeerAddr> {
+		self.adapter.find_peer_addrs(from, capab)
 	}
 
-	fn peer_addrs_received(&self, addrs: Vec<PeerAddr>) {
-		self.adapter.peer_addrs_received(addrs)
+	fn peer_addrs_received(&self, from: PeerAddr, addrs: Vec<PeerAddr>) {
+		self.adapter.peer_addrs_received(from, addrs)
+	}
+
+	fn gossip_addrs(&self, to: PeerAddr) -> Vec<PeerAddr> {
+		self.adapter.gossip_addrs(to)
 	}
 
 	fn peer_difficulty(&self, addr: PeerAddr, diff: Difficulty, height: u64) {
 		self.adapter.peer_difficulty(addr, diff, height)
 	}
 
+	fn ban_reason_received(&self, addr: PeerAddr, ban_reason: ReasonForBan) {
+		self.adapter.ban_reason_received(addr, ban_reason)
+	}
+
+	fn peer_error_received(&self, addr: PeerAddr, error: PeerError) {
+		self.adapter.peer_error_received(addr, error)
+	}
+
+	fn disconnect_received(&self, addr: PeerAddr, reason: DisconnectReason) {
+		self.adapter.disconnect_received(addr, reason)
+	}
+
 	fn is_banned(&self, addr: PeerAddr) -> bool {
 		self.adapter.is_banned(addr)
 	}
+
+	fn report_misbehavior(&self, addr: PeerAddr, offense: Offense) {
+		self.adapter.report_misbehavior(addr, offense)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::serv::DummyAdapter;
+
+	fn hash(i: usize) -> Hash {
+		(i as u64).hash()
+	}
+
+	#[test]
+	fn known_blocks_and_txs_apart() {
+		let tracking = TrackingAdapter::new(Arc::new(DummyAdapter {}));
+		tracking.push_block(hash(1));
+		tracking.push_tx(hash(2));
+		assert!(tracking.knows_block(hash(1)));
+		assert!(!tracking.knows_tx(hash(1)));
+		assert!(tracking.knows_tx(hash(2)));
+		assert!(!tracking.knows_block(hash(2)));
+
+		// a flood of txs doesn't push blocks out
+		for i in 0..MAX_KNOWN_TXS * 2 {
+			tracking.push_tx(hash(1_000_000 + i));
+		}
+		assert!(tracking.knows_block(hash(1)));
+	}
+
+	#[test]
+	fn known_txs_evicted_under_churn() {
+		let tracking = TrackingAdapter::new(Arc::new(DummyAdapter {}));
+		for i in 0..MAX_KNOWN_TXS {
+			tracking.push_tx(hash(i));
+		}
+		// seen again, now the most recent
+		assert!(tracking.knows_tx(hash(0)));
+
+		for i in MAX_KNOWN_TXS..MAX_KNOWN_TXS + 10 {
+			tracking.push_tx(hash(i));
+		}
+		assert!(tracking.knows_tx(hash(0)));
+		for i in 1..11 {
+			assert!(!tracking.knows_tx(hash(i)));
+		}
+		for i in 11..MAX_KNOWN_TXS + 10 {
+			assert!(tracking.knows_tx(hash(i)));
+		}
+	}
 }
@@ -13,46 +13,424 @@
 // limitations under the License.
 
 use crate::util::RwLock;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
 
 use crate::chain;
 use crate::core::core;
 use crate::core::core::hash::{Hash, Hashed};
-use crate::core::global;
 use crate::core::pow::Difficulty;
+use crate::core::ser::VerifySortedAndUnique;
+use crate::events::{PeerEventKind, PeerEventReceiver, PeerEvents};
+use crate::msg::{DisconnectReason, PeerError, PeerErrorCode};
 use crate::peer::Peer;
-use crate::store::{PeerData, PeerStore, State};
+use crate::store::{PeerData, PeerStore, State, StoreStats};
 use crate::types::{
-	Capabilities, ChainAdapter, Error, NetAdapter, P2PConfig, PeerAddr, PeerInfo, ReasonForBan,
-	TxHashSetRead, MAX_PEER_ADDRS,
+	netgroup, redial_backoff, redial_failures, BandwidthStats, Capabilities, ChainAdapter, Error,
+	InboundEviction, IpRange, NetAdapter, NodeId, Offense, P2PConfig, PeerAddr, PeerCounts,
+	PeerInfo, PeerInfoDisplay, PeerLimits, PeerStats, ReasonForBan, RetryPolicy, SelfAddrs,
+	TxHashSetRead, MAX_PEER_ADDRS, RECONNECT_DELAY,
 };
 use chrono::prelude::*;
 use chrono::Duration;
 
 const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
 
+/// An existing connection we haven't heard from (via ping/pong) for that
+/// long is considered half-dead and gets replaced by a new connection to the
+/// same peer.
+const DUPLICATE_STALE_SECS: i64 = 60;
+
+/// How many of our longest connected inbound peers we never evict to make room
+/// for a new one.
+const EVICTION_PROTECT_OLDEST: usize = 4;
+
+/// How many of our inbound peers with the lowest round-trip time we never
+/// evict to make room for a new one.
+const EVICTION_PROTECT_FASTEST: usize = 4;
+
+/// When fewer known peers than that have the capabilities asked for in a
+/// GetPeerAddrs, we top our reply up with other healthy peers.
+const MIN_FILTERED_PEER_ADDRS: usize = 8;
+
+/// A stored peer we connected to (or heard of) within that long is fresh
+/// enough to be given out, older ones only make up for a short reply.
+const FRESH_PEER_ADDR_SECS: i64 = 24 * 3600;
+
+/// How many new addresses a single peer can add to our store in an hour.
+const MAX_SAVED_ADDRS_PER_HOUR: usize = 256;
+
+/// How many addresses we gossip to a peer at most at a time.
+const MAX_GOSSIP_ADDRS: usize = 16;
+
+/// When we have peers we connected to before to dial, one dial in that many
+/// still goes to a peer we only heard about.
+const UNVERIFIED_DIAL_ODDS: f64 = 4.0;
+
+/// A peer we haven't heard its total difficulty from (via ping/pong) for that
+/// long may not be where it told us anymore, we don't sync from it.
+const STALE_DIFFICULTY_SECS: i64 = 3 * 60;
+
+/// A peer's misbehavior score goes down by a point every that many seconds,
+/// a hundred points an hour.
+const MISBEHAVIOR_DECAY_SECS: i64 = 36;
+
+/// Round-trip times within that much of each other are as good when picking
+/// who we relay to, still picked at random.
+const RTT_BUCKET: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How many of our outbound peers we save as anchors when we stop, dialed
+/// first when we start again.
+const MAX_ANCHORS: usize = 4;
+
+/// A peer we connected to within that long is never pruned from our store,
+/// see `Peers::prune_store`.
+const PRUNE_PROTECT_CONNECTED_SECS: i64 = 7 * 24 * 3600;
+
+/// How many nonces we try for the kernel short_ids of a block we relay to be
+/// unique, before relaying the full block instead.
+const COMPACT_BLOCK_TRIES: usize = 3;
+
+/// What came of merging peers from a file, see `Peers::load_from_file`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeersImport {
+	/// Peers saved, new to us or updated.
+	pub imported: usize,
+	/// Peers left out: unroutable, or banned by us already.
+	pub ignored: usize,
+	/// Lines that didn't read.
+	pub malformed: usize,
+}
+
+/// What came of a health sweep of our peers, see `Peers::sweep`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sweep {
+	/// Peers still connected once the sweep is done, inbound and outbound.
+	pub connected: usize,
+	pub inbound: usize,
+	pub outbound: usize,
+	/// Peers banned in our store.
+	pub banned: usize,
+	/// Peers dropped as unresponsive or with their connection gone.
+	pub dropped: usize,
+}
+
+/// What we go by to pick the peers we sync from.
+struct SyncRank {
+	difficulty: Difficulty,
+	// we picked it, inbound peers could all be someone else's
+	outbound: bool,
+	strikes: usize,
+	rtt: Option<std::time::Duration>,
+	last_seen: DateTime<Utc>,
+	// connected, responsive and not banned
+	usable: bool,
+	discouraged: bool,
+}
+
+impl SyncRank {
+	fn of(peer: &Peer, discouraged: bool) -> SyncRank {
+		SyncRank {
+			difficulty: peer.info.total_difficulty(),
+			outbound: peer.info.is_outbound(),
+			strikes: peer.request_strikes(),
+			rtt: peer.info.rtt(),
+			last_seen: peer.info.last_seen(),
+			usable: peer.is_connected() && !peer.is_banned() && !peer.is_unresponsive(),
+			discouraged,
+		}
+	}
+}
+
+// Keeps the usable peers we heard from within STALE_DIFFICULTY_SECS, with
+// more work than `than` if provided, the discouraged ones only if there are
+// no others, and sorts them: the most work first,
+// then the ones we dialed, then the fewest strikes, then the shortest
+// round-trip time (unknown last).
+// Peers ranking the same stay in the order provided.
+fn rank_for_sync<T>(
+	peers: Vec<(T, SyncRank)>,
+	than: Option<Difficulty>,
+	now: DateTime<Utc>,
+) -> Vec<T> {
+	let fresh_since = now - Duration::seconds(STALE_DIFFICULTY_SECS);
+	let mut peers = peers
+		.into_iter()
+		.filter(|(_, r)| r.usable && r.last_seen >= fresh_since)
+		.filter(|(_, r)| than.map_or(true, |than| r.difficulty > than))
+		.collect::<Vec<_>>();
+	if peers.iter().any(|(_, r)| !r.discouraged) {
+		peers.retain(|(_, r)| !r.discouraged);
+	}
+	peers.sort_by_key(|(_, r)| {
+		(
+			cmp::Reverse(r.difficulty),
+			!r.outbound,
+			r.strikes,
+			r.rtt.is_none(),
+			r.rtt,
+		)
+	});
+	peers.into_iter().map(|(p, _)| p).collect()
+}
+
+/// What we go by to pick the inbound peer we evict to make room for a new
+/// one.
+struct EvictionRank {
+	// None for the addresses other than ips
+	netgroup: Option<IpRange>,
+	connected_since: DateTime<Utc>,
+	rtt: Option<std::time::Duration>,
+	// still to answer one of our requests
+	serving: bool,
+}
+
+impl EvictionRank {
+	fn of(peer: &Peer) -> EvictionRank {
+		EvictionRank {
+			netgroup: peer.info.addr.ip_addr().map(|addr| netgroup(&addr.ip())),
+			connected_since: peer.info.first_seen(),
+			rtt: peer.info.rtt(),
+			serving: peer.pending_requests() > 0,
+		}
+	}
+}
+
+// Whether we take an inbound connection from a discouraged peer with that
+// many inbound peers already: only below half of our inbound maximum.
+fn admits_discouraged(inbound: u32, max_inbound: u32) -> bool {
+	inbound.saturating_mul(2) < max_inbound
+}
+
+// Picks the peer to evict, if any is left once we set aside our
+// EVICTION_PROTECT_OLDEST longest connected peers, our EVICTION_PROTECT_FASTEST
+// peers with the lowest round-trip time and the longest connected peer of
+// each network group: the youngest connection of the group with the most
+// peers left. Between groups as large, the one with the youngest connection.
+fn pick_eviction<T>(mut peers: Vec<(T, EvictionRank)>) -> Option<T> {
+	peers.sort_by_key(|(_, r)| r.connected_since);
+	let mut peers = peers.split_off(cmp::min(EVICTION_PROTECT_OLDEST, peers.len()));
+	peers.sort_by_key(|(_, r)| (r.rtt.is_none(), r.rtt));
+	let mut peers = peers.split_off(cmp::min(EVICTION_PROTECT_FASTEST, peers.len()));
+	peers.sort_by_key(|(_, r)| r.connected_since);
+	let mut seen = HashSet::new();
+	peers.retain(|(_, r)| !seen.insert(r.netgroup));
+
+	let mut groups: HashMap<Option<IpRange>, Vec<(T, EvictionRank)>> = HashMap::new();
+	for (p, r) in peers {
+		groups
+			.entry(r.netgroup)
+			.or_insert_with(Vec::new)
+			.push((p, r));
+	}
+	groups
+		.into_iter()
+		.map(|(_, group)| group)
+		.max_by_key(|group| (group.len(), group.last().map(|(_, r)| r.connected_since)))
+		.and_then(|mut group| group.pop())
+		.map(|(p, _)| p)
+}
+
+// Picks a random peer to evict among those left once we set aside the ones
+// serving one of our requests and our EVICTION_PROTECT_FASTEST peers with the
+// lowest known round-trip time.
+fn pick_random_eviction<T>(peers: Vec<(T, EvictionRank)>) -> Option<T> {
+	let (mut timed, untimed): (Vec<_>, Vec<_>) = peers
+		.into_iter()
+		.filter(|(_, r)| !r.serving)
+		.partition(|(_, r)| r.rtt.is_some());
+	timed.sort_by_key(|(_, r)| r.rtt);
+	let mut peers = timed.split_off(cmp::min(EVICTION_PROTECT_FASTEST, timed.len()));
+	peers.extend(untimed);
+	if peers.is_empty() {
+		return None;
+	}
+	let i = thread_rng().gen_range(0, peers.len());
+	Some(peers.swap_remove(i).0)
+}
+
+/// Misbehavior score of a peer as of the last offense, decaying since.
+struct Score {
+	points: u32,
+	at: DateTime<Utc>,
+}
+
+impl Score {
+	fn current(&self, now: DateTime<Utc>) -> u32 {
+		let decayed = (now - self.at).num_seconds().max(0) / MISBEHAVIOR_DECAY_SECS;
+		self.points
+			.saturating_sub(cmp::min(decayed, u32::max_value() as i64) as u32)
+	}
+
+	fn add(&mut self, weight: u32, now: DateTime<Utc>) -> u32 {
+		self.points = self.current(now).saturating_add(weight);
+		self.at = now;
+		self.points
+	}
+}
+
+/// New addresses a peer added to our store since the start of the hour.
+struct AddrsSaved {
+	since: DateTime<Utc>,
+	count: usize,
+}
+
+/// A block we asked a peer for. The other peers announcing it meanwhile
+/// wait their turn, in case it never comes.
+struct BlockRequest {
+	peer: PeerAddr,
+	compact: bool,
+	sent: Instant,
+	candidates: Vec<PeerAddr>,
+}
+
+fn send_block_request(peer: &Peer, h: Hash, compact: bool) -> Result<(), Error> {
+	if compact {
+		peer.send_compact_block_request(h)
+	} else {
+		peer.send_block_request(h)
+	}
+}
+
 pub struct Peers {
 	pub adapter: Arc<dyn ChainAdapter>,
 	store: PeerStore,
 	peers: RwLock<HashMap<PeerAddr, Arc<Peer>>>,
+	scores: RwLock<HashMap<PeerAddr, Score>>,
+	// bad blocks sent by each peer, until it gets banned for them
+	strikes: RwLock<HashMap<(PeerAddr, Offense), u32>>,
+	addrs_saved: RwLock<HashMap<PeerAddr, AddrsSaved>>,
+	// when we dial again the peers that closed on us in good order, see
+	// `disconnect_received`
+	reconnect_at: RwLock<HashMap<PeerAddr, DateTime<Utc>>>,
+	block_requests: RwLock<HashMap<Hash, BlockRequest>>,
+	self_addrs: Arc<SelfAddrs>,
 	config: P2PConfig,
+	limits: Arc<RwLock<PeerLimits>>,
+	// what we need and none of our peers have to sync from them
+	wanted: RwLock<Capabilities>,
+	// the peers we're connecting to, by key, see `start_attempt`
+	attempting: RwLock<HashSet<String>>,
+	events: PeerEvents,
+}
+
+/// Our attempt at connecting to a peer, see `Peers::start_attempt`. Over
+/// once dropped, whichever way it ended.
+pub struct Attempt<'a> {
+	peers: &'a Peers,
+	key: String,
+}
+
+impl<'a> Drop for Attempt<'a> {
+	fn drop(&mut self) {
+		self.peers.attempting.write().remove(&self.key);
+	}
 }
 
 impl Peers {
-	pub fn new(store: PeerStore, adapter: Arc<dyn ChainAdapter>, config: P2PConfig) -> Peers {
+	/// Our own addresses are shared with the handshake (which detects them),
+	/// the ones previously saved are restored. So are the limits on our
+	/// connections.
+	pub fn new(
+		store: PeerStore,
+		adapter: Arc<dyn ChainAdapter>,
+		config: P2PConfig,
+		self_addrs: Arc<SelfAddrs>,
+		limits: Arc<RwLock<PeerLimits>>,
+	) -> Peers {
+		match store.self_addrs() {
+			Ok(addrs) => self_addrs.load(addrs),
+			Err(e) => error!("Couldn't load our own addresses: {:?}", e),
+		}
 		Peers {
 			adapter,
 			store,
 			config,
+			limits,
 			peers: RwLock::new(HashMap::new()),
+			scores: RwLock::new(HashMap::new()),
+			strikes: RwLock::new(HashMap::new()),
+			addrs_saved: RwLock::new(HashMap::new()),
+			reconnect_at: RwLock::new(HashMap::new()),
+			block_requests: RwLock::new(HashMap::new()),
+			self_addrs,
+			wanted: RwLock::new(Capabilities::UNKNOWN),
+			attempting: RwLock::new(HashSet::new()),
+			events: PeerEvents::new(),
+		}
+	}
+
+	/// The limits on our connections right now.
+	pub fn limits(&self) -> PeerLimits {
+		*self.limits.read()
+	}
+
+	/// Replaces the limits on our connections, returning whether they were
+	/// lowered (see `PeerLimits::lowers`). The peers we have over the new
+	/// limits stay until the next `clean_peers`.
+	pub fn update_limits(&self, limits: PeerLimits) -> bool {
+		let mut current = self.limits.write();
+		let lowered = limits.lowers(&current);
+		*current = limits;
+		lowered
+	}
+
+	/// Subscribes to the events of our peers, see `PeerEvents`.
+	pub fn subscribe(&self, capacity: usize) -> PeerEventReceiver {
+		self.events.subscribe(capacity)
+	}
+
+	/// Starts an attempt at connecting to the peer, unless one is going on
+	/// already: a peer is only dialed once at a time, however many of us
+	/// decide to dial it. The attempt lasts until the connection is done
+	/// with the handshake or failed, dropping it.
+	pub fn start_attempt(&self, addr: &PeerAddr) -> Option<Attempt<'_>> {
+		let key = addr.as_key();
+		if !self.attempting.write().insert(key.clone()) {
+			return None;
+		}
+		Some(Attempt { peers: self, key })
+	}
+
+	/// Whether we're connecting to the peer right now, see `start_attempt`.
+	pub fn is_attempting(&self, addr: &PeerAddr) -> bool {
+		self.attempting.read().contains(&addr.as_key())
+	}
+
+	// How many more new addresses the peer can add to our store this hour.
+	fn addrs_allowance(&self, from: &PeerAddr) -> usize {
+		let now = Utc::now();
+		match self.addrs_saved.read().get(from) {
+			Some(saved) if saved.since + Duration::hours(1) > now => {
+				MAX_SAVED_ADDRS_PER_HOUR.saturating_sub(saved.count)
+			}
+			_ => MAX_SAVED_ADDRS_PER_HOUR,
+		}
+	}
+
+	fn inc_addrs_saved(&self, from: PeerAddr, count: usize) {
+		if count == 0 {
+			return;
 		}
+		let now = Utc::now();
+		let mut addrs_saved = self.addrs_saved.write();
+		addrs_saved.retain(|_, saved| saved.since + Duration::hours(1) > now);
+		addrs_saved
+			.entry(from)
+			.or_insert(AddrsSaved {
+				since: now,
+				count: 0,
+			})
+			.count += count;
 	}
 
 	/// Adds the peer to our internal peer mapping. Note that the peer is still
@@ -65,22 +443,119 @@ impl Peers {
 				return Err(Error::Timeout);
 			}
 		};
+		if let Some(existing) = peers.get(&peer.info.addr) {
+			if self.keep_existing(existing, &peer) {
+				debug!(
+					"add_connected: already connected to {} ({:?}), dropping new {:?} connection",
+					peer.info.addr, existing.info.direction, peer.info.direction
+				);
+				peer.disconnect(DisconnectReason::Duplicate);
+				return Err(Error::DuplicateConnection);
+			}
+			debug!(
+				"add_connected: replacing {:?} connection to {} with new {:?} one",
+				existing.info.direction, peer.info.addr, peer.info.direction
+			);
+			existing.disconnect(DisconnectReason::Duplicate);
+			self.events.emit(
+				&existing.info.addr,
+				PeerEventKind::Disconnected(DisconnectReason::Duplicate),
+			);
+		}
+		// connecting again doesn't lift a discouragement
+		let discouraged_until = self.discouraged_until(&peer.info.addr);
 		let peer_data = PeerData {
-			addr: peer.info.addr,
+			addr: peer.info.addr.clone(),
 			capabilities: peer.info.capabilities,
 			user_agent: peer.info.user_agent.clone(),
-			flags: State::Healthy,
+			flags: match discouraged_until {
+				Some(_) => State::Discouraged,
+				None => State::Healthy,
+			},
 			last_banned: 0,
 			ban_reason: ReasonForBan::None,
 			last_connected: Utc::now().timestamp(),
+			last_error: None,
+			last_attempted: Utc::now().timestamp(),
+			failures: 0,
+			banned_until: discouraged_until.unwrap_or(0),
+			last_seen: Utc::now().timestamp(),
+			last_direction: Some(peer.info.direction),
 		};
 		debug!("Saving newly connected peer {}.", peer_data.addr);
 		self.save_peer(&peer_data)?;
 		peers.insert(peer_data.addr, peer.clone());
+		self.events
+			.emit(&peer.info.addr, PeerEventKind::Connected(peer.info.clone()));
 
 		Ok(())
 	}
 
+	/// Makes room for a new inbound peer when we have as many inbound peers
+	/// as we take, evicting one of the others as configured (see
+	/// `InboundEviction`) so early comers can't keep their slots forever, and
+	/// telling it so. Our preferred peers are never evicted. Returns whether
+	/// we made room.
+	pub fn make_inbound_room(&self, newcomer: &PeerAddr) -> bool {
+		let candidates = self
+			.connected_peers()
+			.into_iter()
+			.filter(|p| !p.info.is_outbound() && p.info.addr != *newcomer)
+			.filter(|p| !self.config.is_preferred(&p.info.addr))
+			.map(|p| (p.info.addr.clone(), EvictionRank::of(&p)))
+			.collect::<Vec<_>>();
+		let picked = match self.config.inbound_eviction() {
+			InboundEviction::Netgroup => pick_eviction(candidates),
+			InboundEviction::Random => pick_random_eviction(candidates),
+			InboundEviction::Reject => None,
+		};
+		let addr = match picked {
+			Some(addr) => addr,
+			None => return false,
+		};
+		debug!("make_inbound_room: evicting {} for {}", addr, newcomer);
+		let mut peers = match self.peers.try_write_for(LOCK_TIMEOUT) {
+			Some(peers) => peers,
+			None => {
+				error!("make_inbound_room: failed to get peers lock");
+				return false;
+			}
+		};
+		if let Some(peer) = peers.remove(&addr) {
+			peer.disconnect(DisconnectReason::MakingRoom);
+			self.events.emit(
+				&addr,
+				PeerEventKind::Disconnected(DisconnectReason::MakingRoom),
+			);
+		}
+		true
+	}
+
+	/// Decides whether to keep an existing connection over a new one to the
+	/// same peer. Replaces existing connections that are gone or half-dead,
+	/// otherwise picks the connection both sides will agree on when we dialed
+	/// each other simultaneously.
+	fn keep_existing(&self, existing: &Peer, new: &Peer) -> bool {
+		if !existing.is_connected() {
+			return false;
+		}
+		let quiet = Utc::now() - existing.info.last_seen();
+		if quiet > Duration::seconds(DUPLICATE_STALE_SECS) {
+			return false;
+		}
+		if existing.info.direction == new.info.direction {
+			return true;
+		}
+		// by node ids, the same on both ends whatever addresses each of us
+		// sees, older peers not sending one keep the connection they have
+		match new.info.node_id {
+			Some(theirs) => {
+				keep_outbound(&new.info.our_node_id, &theirs) == existing.info.is_outbound()
+			}
+			None => true,
+		}
+	}
+
 	/// Add a peer as banned to block future connections, usually due to failed
 	/// handshake
 	pub fn add_banned(&self, addr: PeerAddr, ban_reason: ReasonForBan) -> Result<(), Error> {
@@ -92,8 +567,14 @@ impl Peers {
 			last_banned: Utc::now().timestamp(),
 			ban_reason,
 			last_connected: Utc::now().timestamp(),
+			last_error: None,
+			last_attempted: 0,
+			failures: 0,
+			banned_until: 0,
+			last_seen: Utc::now().timestamp(),
+			last_direction: None,
 		};
-		debug!("Banning peer {}.", addr);
+		debug!("Banning peer {}.", peer_data.addr);
 		self.save_peer(&peer_data)
 	}
 
@@ -126,6 +607,58 @@ impl Peers {
 		res
 	}
 
+	/// Bandwidth used with all the peers we're currently connected to, summed
+	/// up. Forgets about a peer as soon as it's gone.
+	pub fn stats(&self) -> BandwidthStats {
+		let mut total = BandwidthStats {
+			sent_bytes: 0,
+			received_bytes: 0,
+			sent_rate: 0,
+			recv_rate: 0,
+			last_seen: Utc.timestamp(0, 0),
+		};
+		for stats in self.connected_peers().iter().map(|p| p.stats()) {
+			total.sent_bytes += stats.sent_bytes;
+			total.received_bytes += stats.received_bytes;
+			total.sent_rate += stats.sent_rate;
+			total.recv_rate += stats.recv_rate;
+			total.last_seen = cmp::max(total.last_seen, stats.last_seen);
+		}
+		total
+	}
+
+	/// A stats snapshot of each of the peers we're connected to.
+	pub fn connected_stats(&self) -> Vec<PeerStats> {
+		self.connected_peers()
+			.iter()
+			.map(|p| PeerStats {
+				misbehavior: self.misbehavior(&p.info.addr),
+				discouraged: self.is_discouraged(p.info.addr.clone()),
+				preferred: self.config.is_preferred(&p.info.addr),
+				..p.stats()
+			})
+			.collect()
+	}
+
+	/// How many peers we're connected to, by capability, direction and
+	/// protocol version.
+	pub fn peer_counts(&self) -> PeerCounts {
+		PeerCounts::from_stats(&self.connected_stats())
+	}
+
+	/// A snapshot of all our connected peers, for the API. The peers are
+	/// looked at one by one once the list of them is taken, none of their
+	/// locks held longer than it takes to read from it.
+	pub fn connected_peers_info(&self) -> Vec<PeerInfoDisplay> {
+		self.connected_peers()
+			.iter()
+			.map(|p| PeerInfoDisplay {
+				stats: Some(p.stats().bandwidth()),
+				..p.info.clone().into()
+			})
+			.collect()
+	}
+
 	pub fn outgoing_connected_peers(&self) -> Vec<Arc<Peer>> {
 		self.connected_peers()
 			.into_iter()
@@ -155,23 +688,65 @@ impl Peers {
 		self.outgoing_connected_peers().len() as u32
 	}
 
-	// Return vec of connected peers that currently advertise more work
-	// (total_difficulty) than we do.
-	pub fn more_work_peers(&self) -> Result<Vec<Arc<Peer>>, chain::Error> {
-		let peers = self.connected_peers();
-		if peers.len() == 0 {
-			return Ok(vec![]);
-		}
+	/// Number of outbound peers currently connected to, our preferred peers
+	/// left out: they come on top of our outbound target.
+	pub fn unpreferred_outbound_count(&self) -> u32 {
+		self.outgoing_connected_peers()
+			.iter()
+			.filter(|p| !self.config.is_preferred(&p.info.addr))
+			.count() as u32
+	}
 
-		let total_difficulty = self.total_difficulty()?;
+	/// Number of inbound peers currently connected to.
+	pub fn peer_inbound_count(&self) -> u32 {
+		self.connected_peers()
+			.iter()
+			.filter(|p| !p.info.is_outbound())
+			.count() as u32
+	}
 
-		let mut max_peers = peers
+	// The connected peers worth syncing from, ranked, see `rank_for_sync`,
+	// among those with the required capabilities. With none of them we go
+	// looking for some, see `wanted_capabilities`.
+	fn sync_peers(&self, than: Option<Difficulty>, capab: Capabilities) -> Vec<Arc<Peer>> {
+		let peers = self
+			.connected_peers()
 			.into_iter()
-			.filter(|x| x.info.total_difficulty() > total_difficulty)
-			.collect::<Vec<_>>();
+			.map(|p| {
+				let rank = SyncRank::of(&p, self.is_discouraged(p.info.addr.clone()));
+				(p, rank)
+			})
+			.collect();
+		let (matching, others): (Vec<_>, Vec<_>) = rank_for_sync(peers, than, Utc::now())
+			.into_iter()
+			.partition(|p| p.info.capabilities.contains(capab));
+		let mut wanted = self.wanted.write();
+		if !matching.is_empty() {
+			wanted.remove(capab);
+		} else if !others.is_empty() && !wanted.contains(capab) {
+			info!(
+				"No peer with {:?} to sync from, {} without, looking for some.",
+				capab,
+				others.len()
+			);
+			wanted.insert(capab);
+		}
+		matching
+	}
+
+	/// Capabilities we need to sync and none of our connected peers have,
+	/// the peers having them are dialed first and asked for.
+	pub fn wanted_capabilities(&self) -> Capabilities {
+		*self.wanted.read()
+	}
 
-		max_peers.shuffle(&mut thread_rng());
-		Ok(max_peers)
+	/// Connected peers that currently advertise more work (total_difficulty)
+	/// than the provided difficulty, ours usually, and have the provided
+	/// capabilities. Only the responsive, not banned ones we recently heard
+	/// from (via ping/pong), the most work first, then the ones that left
+	/// the fewest of our requests unanswered, then the closest ones.
+	pub fn more_work_peers(&self, than: Difficulty, capab: Capabilities) -> Vec<Arc<Peer>> {
+		self.sync_peers(Some(than), capab)
 	}
 
 	// Return number of connected peers that currently advertise more/same work
@@ -190,10 +765,13 @@ impl Peers {
 			.count())
 	}
 
-	/// Returns single random peer with more work than us.
-	pub fn more_work_peer(&self) -> Option<Arc<Peer>> {
-		match self.more_work_peers() {
-			Ok(mut peers) => peers.pop(),
+	/// Returns the best peer with more work than us, see `more_work_peers`.
+	pub fn more_work_peer(&self, capab: Capabilities) -> Option<Arc<Peer>> {
+		match self.total_difficulty() {
+			Ok(total_difficulty) => self
+				.more_work_peers(total_difficulty, capab)
+				.into_iter()
+				.next(),
 			Err(e) => {
 				error!("failed to get more work peers: {:?}", e);
 				None
@@ -201,57 +779,205 @@ impl Peers {
 		}
 	}
 
-	/// Return vec of connected peers that currently have the most worked
-	/// branch, showing the highest total difficulty.
-	pub fn most_work_peers(&self) -> Vec<Arc<Peer>> {
-		let peers = self.connected_peers();
-		if peers.len() == 0 {
-			return vec![];
+	/// Return vec of connected peers with the provided capabilities that
+	/// currently have the most worked branch, showing the highest total
+	/// difficulty. Ranked the same as `more_work_peers`, the peers it leaves
+	/// out aren't considered at all.
+	pub fn most_work_peers(&self, capab: Capabilities) -> Vec<Arc<Peer>> {
+		let mut peers = self.sync_peers(None, capab);
+		if let Some(max) = peers.first().map(|p| p.info.total_difficulty()) {
+			peers.retain(|p| p.info.total_difficulty() == max);
 		}
+		peers
+	}
+
+	/// Returns the best peer with the most worked branch, showing the
+	/// highest total difficulty, among the most responsive ones.
+	pub fn most_work_peer(&self, capab: Capabilities) -> Option<Arc<Peer>> {
+		self.most_work_peers(capab).into_iter().next()
+	}
+
+	/// Returns a random peer among those with the most worked branch, to
+	/// spread our requests over them.
+	pub fn random_most_work_peer(&self, capab: Capabilities) -> Option<Arc<Peer>> {
+		self.most_work_peers(capab)
+			.choose(&mut thread_rng())
+			.cloned()
+	}
 
-		let max_total_difficulty = match peers.iter().map(|x| x.info.total_difficulty()).max() {
-			Some(v) => v,
-			None => return vec![],
+	/// Whether a peer is banned, lifting its ban if it's over.
+	pub fn is_banned(&self, peer_addr: PeerAddr) -> bool {
+		let peer = match self.store.get_peer(peer_addr.clone()) {
+			Ok(peer) if peer.flags == State::Banned => peer,
+			_ => return false,
+		};
+		let until = if peer.banned_until > 0 {
+			peer.banned_until
+		} else {
+			peer.last_banned + self.config.ban_window()
 		};
+		if Utc::now().timestamp() < until {
+			return true;
+		}
+		debug!("is_banned: ban of {} is over, unbanning", peer_addr);
+		if self.update_state(peer_addr.clone(), State::Healthy).is_ok() {
+			self.events.emit(&peer_addr, PeerEventKind::Healthy);
+		}
+		false
+	}
 
-		let mut max_peers = peers
-			.into_iter()
-			.filter(|x| x.info.total_difficulty() == max_total_difficulty)
-			.collect::<Vec<_>>();
+	/// Whether a peer is discouraged, lifting it if it's over. A discouraged
+	/// peer isn't refused but only gets a turn when there's no other peer to
+	/// go to: we dial it and sync from it only short of other candidates, and
+	/// take its inbound connections only below half our inbound maximum.
+	pub fn is_discouraged(&self, peer_addr: PeerAddr) -> bool {
+		self.discouraged_until(&peer_addr).is_some()
+	}
+
+	// When the discouragement of a peer is over, lifting it if it is already.
+	fn discouraged_until(&self, peer_addr: &PeerAddr) -> Option<i64> {
+		match self.store.get_peer(peer_addr.clone()) {
+			Ok(ref peer) if peer.flags == State::Discouraged => {
+				if Utc::now().timestamp() < peer.banned_until {
+					return Some(peer.banned_until);
+				}
+			}
+			_ => return None,
+		}
+		debug!(
+			"is_discouraged: discouragement of {} is over, lifting",
+			peer_addr
+		);
+		if self.update_state(peer_addr.clone(), State::Healthy).is_ok() {
+			self.events.emit(peer_addr, PeerEventKind::Healthy);
+		}
+		None
+	}
 
-		max_peers.shuffle(&mut thread_rng());
-		max_peers
+	/// Discourages a peer for the provided duration, see `is_discouraged`.
+	/// It stays connected if it is, a banned peer stays banned.
+	pub fn discourage_peer(&self, peer_addr: PeerAddr, duration: Duration) {
+		let until = (Utc::now() + duration).timestamp();
+		if let Err(e) = self.store.discourage(peer_addr.clone(), until) {
+			error!("Couldn't discourage {}: {:?}", peer_addr, e);
+			return;
+		}
+		debug!(
+			"discourage_peer: {} discouraged for {}s",
+			peer_addr,
+			duration.num_seconds()
+		);
 	}
 
-	/// Returns single random peer with the most worked branch, showing the
-	/// highest total difficulty.
-	pub fn most_work_peer(&self) -> Option<Arc<Peer>> {
-		self.most_work_peers().pop()
+	/// How long peers are discouraged for by default.
+	pub fn discourage_window(&self) -> Duration {
+		Duration::seconds(self.config.discourage_window())
 	}
 
-	pub fn is_banned(&self, peer_addr: PeerAddr) -> bool {
-		if let Ok(peer) = self.store.get_peer(peer_addr) {
-			return peer.flags == State::Banned;
+	/// Whether we take an inbound connection from a discouraged peer, with
+	/// as many inbound peers as we have now, see `admits_discouraged`.
+	pub fn admits_discouraged(&self) -> bool {
+		admits_discouraged(self.peer_inbound_count(), self.limits().max_inbound)
+	}
+
+	/// Counts an offense against a peer, discouraging it once its misbehavior
+	/// score goes over the discourage threshold and banning it once it goes
+	/// over the ban threshold. A bad block is a strike as well, the
+	/// peer gets banned for it once it's sent us as many as configured.
+	pub fn report_misbehavior(&self, peer_addr: PeerAddr, offense: Offense) {
+		if offense.is_bad_block() && self.strike(&peer_addr, offense) {
+			self.scores.write().remove(&peer_addr);
+			self.ban_peer(peer_addr, ReasonForBan::BadBlock, self.ban_window());
+			return;
 		}
-		false
+
+		let score = self
+			.scores
+			.write()
+			.entry(peer_addr.clone())
+			.or_insert(Score {
+				points: 0,
+				at: Utc::now(),
+			})
+			.add(offense.weight(), Utc::now());
+		debug!(
+			"report_misbehavior: {} {:?}, score now {}",
+			peer_addr, offense, score
+		);
+		if score >= self.config.ban_score_threshold() {
+			self.scores.write().remove(&peer_addr);
+			self.ban_peer(
+				peer_addr,
+				ReasonForBan::ProtocolViolation,
+				self.ban_window(),
+			);
+		} else if score >= self.config.discourage_score_threshold()
+			&& !self.is_discouraged(peer_addr.clone())
+		{
+			self.discourage_peer(peer_addr, self.discourage_window());
+		}
+	}
+
+	// Counts a bad block against the peer, whether it's reached the number
+	// of strikes it gets banned at (forgetting them if so).
+	fn strike(&self, peer_addr: &PeerAddr, offense: Offense) -> bool {
+		let limit = match offense {
+			Offense::BorderlineBlock => self.config.borderline_block_strikes(),
+			_ => self.config.invalid_block_strikes(),
+		};
+		let mut strikes = self.strikes.write();
+		let key = (peer_addr.clone(), offense);
+		let count = {
+			let count = strikes.entry(key.clone()).or_insert(0);
+			*count += 1;
+			*count
+		};
+		debug!(
+			"strike: {} {:?}, {} out of {}",
+			peer_addr, offense, count, limit
+		);
+		if count < limit {
+			return false;
+		}
+		strikes.retain(|(addr, _), _| addr != peer_addr);
+		true
 	}
 
-	/// Ban a peer, disconnecting it if we're currently connected
-	pub fn ban_peer(&self, peer_addr: PeerAddr, ban_reason: ReasonForBan) {
-		if let Err(e) = self.update_state(peer_addr, State::Banned) {
+	/// The misbehavior score of a peer, as of now.
+	pub fn misbehavior(&self, peer_addr: &PeerAddr) -> u32 {
+		self.scores
+			.read()
+			.get(peer_addr)
+			.map(|s| s.current(Utc::now()))
+			.unwrap_or(0)
+	}
+
+	/// How long peers are banned for by default.
+	pub fn ban_window(&self) -> Duration {
+		Duration::seconds(self.config.ban_window())
+	}
+
+	/// Ban a peer for the provided duration, disconnecting it if we're
+	/// currently connected
+	pub fn ban_peer(&self, peer_addr: PeerAddr, ban_reason: ReasonForBan, duration: Duration) {
+		let until = (Utc::now() + duration).timestamp();
+		if let Err(e) = self.store.ban(peer_addr.clone(), ban_reason, until) {
 			error!("Couldn't ban {}: {:?}", peer_addr, e);
 			return;
 		}
+		debug!(
+			"ban_peer: {} banned for {}s ({:?})",
+			peer_addr,
+			duration.num_seconds(),
+			ban_reason
+		);
+		self.events
+			.emit(&peer_addr, PeerEventKind::Banned(ban_reason, until));
 
-		if let Some(peer) = self.get_connected_peer(peer_addr) {
+		if let Some(peer) = self.get_connected_peer(peer_addr.clone()) {
 			debug!("Banning peer {}", peer_addr);
-			// setting peer status will get it removed at the next clean_peer
-			match peer.send_ban_reason(ban_reason) {
-				Err(e) => error!("failed to send a ban reason to{}: {:?}", peer_addr, e),
-				Ok(_) => debug!("ban reason {:?} was sent to {}", ban_reason, peer_addr),
-			};
 			peer.set_banned();
-			peer.stop();
+			peer.stop_with_reason(ban_reason, None);
 
 			let mut peers = match self.peers.try_write_for(LOCK_TIMEOUT) {
 				Some(peers) => peers,
@@ -260,18 +986,173 @@ impl Peers {
 					return;
 				}
 			};
-			peers.remove(&peer.info.addr);
+			if peers.remove(&peer.info.addr).is_some() {
+				self.events.emit(&peer_addr, gone());
+			}
+		}
+	}
+
+	/// Applies the retry policy of the error we failed to connect to a peer
+	/// with: back off, never dial it again or ban it.
+	pub fn connect_failed(&self, peer_addr: PeerAddr, e: &Error) {
+		match e.retry_policy() {
+			RetryPolicy::RetrySoon => {}
+			RetryPolicy::RetryLater(base) => {
+				let mut peer_data = match self.get_peer(peer_addr.clone()) {
+					Ok(peer_data) => peer_data,
+					Err(_) => PeerData {
+						addr: peer_addr.clone(),
+						capabilities: Capabilities::UNKNOWN,
+						user_agent: "".to_string(),
+						flags: State::Defunct,
+						last_banned: 0,
+						ban_reason: ReasonForBan::None,
+						last_connected: Utc::now().timestamp(),
+						last_error: None,
+						last_attempted: 0,
+						failures: 0,
+						banned_until: 0,
+						last_seen: Utc::now().timestamp(),
+						last_direction: None,
+					},
+				};
+				// a longer wait to begin with skips ahead in the schedule
+				peer_data.failures =
+					cmp::max(peer_data.failures.saturating_add(1), redial_failures(base));
+				peer_data.last_attempted = Utc::now().timestamp();
+				if peer_data.flags != State::Banned && peer_data.flags != State::Discouraged {
+					peer_data.flags = State::Defunct;
+				}
+				debug!(
+					"connect_failed: {} failed {} times ({:?}), next attempt in {}s",
+					peer_addr,
+					peer_data.failures,
+					e,
+					redial_backoff(peer_data.failures).as_secs()
+				);
+				if let Err(e) = self.save_peer(&peer_data) {
+					error!("connect_failed: couldn't save {}: {:?}", peer_addr, e);
+				}
+			}
+			RetryPolicy::Never => {
+				debug!(
+					"connect_failed: {} ({:?}), never dialing again",
+					peer_addr, e
+				);
+				if self
+					.update_state(peer_addr.clone(), State::Incompatible)
+					.is_err()
+				{
+					let _ = self.save_peer(&PeerData {
+						addr: peer_addr,
+						capabilities: Capabilities::UNKNOWN,
+						user_agent: "".to_string(),
+						flags: State::Incompatible,
+						last_banned: 0,
+						ban_reason: ReasonForBan::None,
+						last_connected: Utc::now().timestamp(),
+						last_error: None,
+						last_attempted: Utc::now().timestamp(),
+						failures: 1,
+						banned_until: 0,
+						last_seen: Utc::now().timestamp(),
+						last_direction: None,
+					});
+				}
+			}
+			RetryPolicy::Ban => {
+				debug!("connect_failed: {} ({:?}), banning", peer_addr, e);
+				let _ = self.add_banned(peer_addr, ReasonForBan::BadHandshake);
+			}
+		}
+	}
+
+	/// Whether it's worth dialing a peer now, given how our previous attempts
+	/// failed.
+	pub fn can_dial(&self, peer_addr: &PeerAddr) -> bool {
+		if self.self_addrs.contains(peer_addr) {
+			return false;
+		}
+		match self.get_peer(peer_addr.clone()) {
+			Ok(ref peer) if peer.flags == State::Incompatible => return false,
+			_ => {}
+		}
+		if self.is_banned(peer_addr.clone()) {
+			return false;
+		}
+		match self.redial_at(peer_addr) {
+			Some(at) => at <= Utc::now(),
+			None => true,
+		}
+	}
+
+	/// Peers from our store worth dialing now, the ones that failed us the
+	/// least first. Leaves out the banned, the ones we're backing off from and
+	/// the ones we're already connected or connecting to. Peers we connected
+	/// to before come first, the ones we only heard about make up for the
+	/// rest and now and then get a turn anyway, the discouraged ones only
+	/// make up for too few of both. Among them, the ones with the
+	/// capabilities we want (see `wanted_capabilities`) come first.
+	pub fn dial_candidates(&self, count: usize) -> Vec<PeerAddr> {
+		let wanted = self.wanted_capabilities();
+		let dialable = |state: State| {
+			let mut peers = self.find_peers(state, Capabilities::UNKNOWN, usize::max_value());
+			if !wanted.is_empty() {
+				peers.sort_by_key(|p| !p.capabilities.contains(wanted));
+			}
+			peers
+				.into_iter()
+				.map(|p| p.addr)
+				.filter(|addr| self.can_dial(addr) && !self.is_known(addr.clone()))
+				.filter(|addr| !self.is_attempting(addr))
+		};
+		let mut candidates = dialable(State::Healthy).take(count).collect::<Vec<_>>();
+		let unverified = if candidates.len() < count {
+			count - candidates.len()
+		} else if count > 0 && thread_rng().gen_bool(1.0 / UNVERIFIED_DIAL_ODDS) {
+			1
+		} else {
+			0
+		};
+		let unverified = dialable(State::Unverified)
+			.take(unverified)
+			.collect::<Vec<_>>();
+		candidates.truncate(count - unverified.len());
+		candidates.extend(unverified);
+		if candidates.len() < count {
+			let discouraged = dialable(State::Discouraged)
+				.take(count - candidates.len())
+				.collect::<Vec<_>>();
+			candidates.extend(discouraged);
 		}
+		candidates
+	}
+
+	/// When we'll dial a peer again after failing to connect to it, backing
+	/// off longer with every consecutive failure, or after it closed the
+	/// connection in good order. None if neither happened since we last
+	/// connected to it.
+	pub fn redial_at(&self, peer_addr: &PeerAddr) -> Option<DateTime<Utc>> {
+		let backoff = match self.get_peer(peer_addr.clone()) {
+			Ok(ref peer) if peer.failures > 0 => {
+				let backoff = redial_backoff(peer.failures).as_secs() as i64;
+				Some(Utc.timestamp(peer.last_attempted, 0) + Duration::seconds(backoff))
+			}
+			_ => None,
+		};
+		let reconnect = self.reconnect_at.read().get(peer_addr).cloned();
+		cmp::max(backoff, reconnect)
 	}
 
 	/// Unban a peer, checks if it exists and banned then unban
 	pub fn unban_peer(&self, peer_addr: PeerAddr) {
 		debug!("unban_peer: peer {}", peer_addr);
-		match self.get_peer(peer_addr) {
-			Ok(_) => {
-				if self.is_banned(peer_addr) {
-					if let Err(e) = self.update_state(peer_addr, State::Healthy) {
-						error!("Couldn't unban {}: {:?}", peer_addr, e);
+		match self.get_peer(peer_addr.clone()) {
+			Ok(peer) => {
+				if peer.flags == State::Banned {
+					match self.update_state(peer_addr.clone(), State::Healthy) {
+						Ok(()) => self.events.emit(&peer_addr, PeerEventKind::Healthy),
+						Err(e) => error!("Couldn't unban {}: {:?}", peer_addr, e),
 					}
 				} else {
 					error!("Couldn't unban {}: peer is not banned", peer_addr);
@@ -281,18 +1162,25 @@ impl Peers {
 		};
 	}
 
-	fn broadcast<F>(&self, obj_name: &str, num_peers: u32, inner: F) -> u32
+	fn broadcast<F>(&self, obj_name: &str, peers: Vec<Arc<Peer>>, num_peers: u32, inner: F) -> u32
 	where
 		F: Fn(&Peer) -> Result<bool, Error>,
 	{
 		let mut count = 0;
 
-		// Iterate over our connected peers.
+		// Iterate over the provided peers.
 		// Try our best to send to at most num_peers peers.
-		for p in self.connected_peers().iter() {
+		for p in peers.iter() {
 			match inner(&p) {
 				Ok(true) => count += 1,
 				Ok(false) => (),
+				// what we relay can be dropped for a peer too slow to read it,
+				// the peer is only let go when a msg it can't do without doesn't
+				// fit (see `Peer::send`)
+				Err(Error::QueueFull) => debug!(
+					"Send queue of {:?} full, {:?} skipped",
+					&p.info.addr, obj_name
+				),
 				Err(e) => {
 					debug!(
 						"Error sending {:?} to peer {:?}: {:?}",
@@ -307,7 +1195,9 @@ impl Peers {
 						}
 					};
 					p.stop();
-					peers.remove(&p.info.addr);
+					if peers.remove(&p.info.addr).is_some() {
+						self.events.emit(&p.info.addr, gone());
+					}
 				}
 			}
 
@@ -318,14 +1208,45 @@ impl Peers {
 		count
 	}
 
+	/// Who gets a block or tx (by kernel hash) we relay, picked anew for each:
+	/// `relay_fanout` of the peers that don't know about it yet, at random
+	/// but outbound ones first (an attacker can't just connect to us to get
+	/// in), then the ones with a shorter round-trip time by RTT_BUCKET, plus
+	/// whoever asked us for it before we had it. Multi-hop relay takes it to
+	/// the others. Never the peer we got it `from`, nor the ones that already
+	/// sent or announced it to us.
+	pub fn relay_targets<F>(&self, h: Hash, from: Option<&PeerAddr>, knows: F) -> Vec<Arc<Peer>>
+	where
+		F: Fn(&Peer) -> bool,
+	{
+		let (mut targets, mut others): (Vec<_>, Vec<_>) = self
+			.connected_peers()
+			.into_iter()
+			.filter(|p| Some(&p.info.addr) != from && !knows(p))
+			.partition(|p| p.wants(h));
+		// already shuffled, the sort is stable
+		others.sort_by_key(|p| {
+			let rtt = p
+				.info
+				.rtt()
+				.map(|rtt| rtt.as_millis() / RTT_BUCKET.as_millis());
+			(!p.info.is_outbound(), rtt.is_none(), rtt)
+		});
+		others.truncate(self.limits().relay_fanout as usize);
+		targets.append(&mut others);
+		targets
+	}
+
 	/// Broadcasts the provided compact block to PEER_MAX_COUNT of our peers.
 	/// This is only used when initially broadcasting a newly mined block
 	/// from a mining node so we want to broadcast it far and wide.
 	/// A peer implementation may drop the broadcast request
 	/// if it knows the remote peer already has the block.
 	pub fn broadcast_compact_block(&self, b: &core::CompactBlock) {
-		let num_peers = self.config.peer_max_count();
-		let count = self.broadcast("compact block", num_peers, |p| p.send_compact_block(b));
+		let num_peers = self.limits().peer_max_count;
+		let count = self.broadcast("compact block", self.connected_peers(), num_peers, |p| {
+			p.send_compact_block(b)
+		});
 		debug!(
 			"broadcast_compact_block: {}, {} at {}, to {} peers, done.",
 			b.hash(),
@@ -335,6 +1256,55 @@ impl Peers {
 		);
 	}
 
+	/// Relays a block to its `relay_targets`. The block is pushed to
+	/// `block_fanout` of the peers supporting announcements, the rest of them
+	/// are only announced its hash and ask for it if they need it. Peers that
+	/// can rebuild it from their pool get the compact block. Up to
+	/// PEER_PREFERRED_COUNT peers not supporting announcements get the compact
+	/// block or the header first. The peer we got the block `from`, if any,
+	/// is left out. Peers get the full block instead of a compact one that
+	/// would be refused, two of its kernels sharing a short_id.
+	pub fn broadcast_block(&self, b: &core::Block, from: Option<&PeerAddr>) {
+		let cb = compact_block(b);
+		let hash = b.hash();
+		let fanout = self.config.block_fanout();
+		let num_legacy = self.config.peer_min_preferred_count();
+		let (pushed, legacy) = (Cell::new(0), Cell::new(0));
+		let targets = self.relay_targets(hash, from, |p| p.knows_block(hash));
+		let count = self.broadcast("block", targets, self.limits().peer_max_count, |p| {
+			let compact = p.info.negotiated.contains(Capabilities::COMPACT_BLOCKS);
+			if p.info.negotiated.contains(Capabilities::BLOCK_INV) {
+				if pushed.get() >= fanout {
+					return p.send_block_inv(hash, b.header.height);
+				}
+				let sent = match cb {
+					Some(ref cb) if compact => p.send_compact_block(cb)?,
+					_ => p.send_block(b)?,
+				};
+				pushed.set(pushed.get() + sent as u32);
+				Ok(sent)
+			} else {
+				if legacy.get() >= num_legacy {
+					return Ok(false);
+				}
+				let sent = match cb {
+					Some(ref cb) if compact => p.send_compact_block(cb)?,
+					None if compact => p.send_block(b)?,
+					_ => p.send_header(&b.header)?,
+				};
+				legacy.set(legacy.get() + sent as u32);
+				Ok(sent)
+			}
+		});
+		debug!(
+			"broadcast_block: {}, {} at {}, to {} peers, done.",
+			b.hash(),
+			b.header.pow.total_difficulty,
+			b.header.height,
+			count,
+		);
+	}
+
 	/// Broadcasts the provided header to PEER_PREFERRED_COUNT of our peers.
 	/// We may be connected to PEER_MAX_COUNT peers so we only
 	/// want to broadcast to a random subset of peers.
@@ -342,7 +1312,9 @@ impl Peers {
 	/// if it knows the remote peer already has the header.
 	pub fn broadcast_header(&self, bh: &core::BlockHeader) {
 		let num_peers = self.config.peer_min_preferred_count();
-		let count = self.broadcast("header", num_peers, |p| p.send_header(bh));
+		let count = self.broadcast("header", self.connected_peers(), num_peers, |p| {
+			p.send_header(bh)
+		});
 		debug!(
 			"broadcast_header: {}, {} at {}, to {} peers, done.",
 			bh.hash(),
@@ -352,14 +1324,17 @@ impl Peers {
 		);
 	}
 
-	/// Broadcasts the provided transaction to PEER_PREFERRED_COUNT of our
-	/// peers. We may be connected to PEER_MAX_COUNT peers so we only
-	/// want to broadcast to a random subset of peers.
+	/// Relays the provided transaction to its `relay_targets`, leaving out
+	/// the peer we got it `from`, if any.
 	/// A peer implementation may drop the broadcast request
 	/// if it knows the remote peer already has the transaction.
-	pub fn broadcast_transaction(&self, tx: &core::Transaction) {
-		let num_peers = self.config.peer_max_count();
-		let count = self.broadcast("transaction", num_peers, |p| p.send_transaction(tx));
+	pub fn broadcast_transaction(&self, tx: &core::Transaction, from: Option<&PeerAddr>) {
+		let num_peers = self.limits().peer_max_count;
+		let kernel_hash = tx.kernels()[0].hash();
+		let targets = self.relay_targets(kernel_hash, from, |p| p.knows_tx(kernel_hash));
+		let count = self.broadcast("transaction", targets, num_peers, |p| {
+			p.send_transaction(tx)
+		});
 		debug!(
 			"broadcast_transaction: {} to {} peers, done.",
 			tx.hash(),
@@ -368,10 +1343,20 @@ impl Peers {
 	}
 
 	/// Ping all our connected peers. Always automatically expects a pong back
-	/// or disconnects. This acts as a liveness test.
+	/// or disconnects. This acts as a liveness test. When we last heard from
+	/// them is saved along the way.
 	pub fn check_all(&self, total_difficulty: Difficulty, height: u64) {
 		for p in self.connected_peers().iter() {
+			let _ = self
+				.store
+				.update_last_seen(p.info.addr.clone(), p.info.last_seen().timestamp());
 			if let Err(e) = p.send_ping(total_difficulty, height) {
+				// a ping is dropped for a peer too slow to read it, going
+				// unanswered if it stays that way
+				if let Error::QueueFull = e {
+					debug!("Send queue of {:?} full, ping skipped", &p.info.addr);
+					continue;
+				}
 				debug!("Error pinging peer {:?}: {:?}", &p.info.addr, e);
 				let mut peers = match self.peers.try_write_for(LOCK_TIMEOUT) {
 					Some(peers) => peers,
@@ -381,7 +1366,9 @@ impl Peers {
 					}
 				};
 				p.stop();
-				peers.remove(&p.info.addr);
+				if peers.remove(&p.info.addr).is_some() {
+					self.events.emit(&p.info.addr, gone());
+				}
 			}
 		}
 	}
@@ -397,6 +1384,17 @@ impl Peers {
 		}
 	}
 
+	/// How many peers we have in store in each state
+	pub fn store_stats(&self) -> StoreStats {
+		match self.store.stats() {
+			Ok(stats) => stats,
+			Err(e) => {
+				error!("store_stats failed: {:?}", e);
+				StoreStats::default()
+			}
+		}
+	}
+
 	/// Find peers in store (not necessarily connected) and return their data
 	pub fn find_peers(&self, state: State, cap: Capabilities, count: usize) -> Vec<PeerData> {
 		match self.store.find_peers(state, cap, count) {
@@ -418,9 +1416,156 @@ impl Peers {
 		self.store.exists_peer(peer_addr).map_err(From::from)
 	}
 
-	/// Saves updated information about a peer
-	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
-		self.store.save_peer(p).map_err(From::from)
+	/// Saves our node id
+	pub fn save_node_id(&self, node_id: &NodeId) -> Result<(), Error> {
+		self.store.save_node_id(node_id).map_err(From::from)
+	}
+
+	/// Saves our own addresses, if we found new ones since the last time.
+	pub fn save_self_addrs(&self) {
+		if let Some(addrs) = self.self_addrs.changed() {
+			if let Err(e) = self.store.save_self_addrs(&addrs) {
+				error!("Couldn't save our own addresses: {:?}", e);
+			}
+		}
+	}
+
+	/// Writes all the peers we have in store to a file, one per line: the
+	/// address, capabilities (bits), state and last seen time. Returns how
+	/// many were written.
+	pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<usize, Error> {
+		let peers = self.store.all_peers()?;
+		let mut file = BufWriter::new(File::create(path)?);
+		writeln!(file, "# address capabilities state last_seen")?;
+		for p in &peers {
+			writeln!(
+				file,
+				"{} {} {:?} {}",
+				p.addr,
+				p.capabilities.bits(),
+				p.flags,
+				p.last_seen
+			)?;
+		}
+		file.flush()?;
+		Ok(peers.len())
+	}
+
+	/// Merges the peers in a file written by `save_to_file` into our store.
+	/// Unroutable addresses are left out and a peer we banned stays banned.
+	/// Otherwise a ban or a discouragement in the file is applied (for our
+	/// own window), and the state and capabilities in the file win when it
+	/// heard from the peer more recently than we did. Lines that don't read
+	/// are skipped, and counted.
+	pub fn load_from_file<P: AsRef<Path>>(&self, path: P) -> Result<PeersImport, Error> {
+		let file = BufReader::new(File::open(path)?);
+		let mut import = PeersImport::default();
+		for line in file.lines() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let (addr, capabilities, state, last_seen) = match parse_peer_line(line) {
+				Some(entry) => entry,
+				None => {
+					debug!("load_from_file: skipping malformed line {:?}", line);
+					import.malformed += 1;
+					continue;
+				}
+			};
+			if !addr.is_routable() {
+				import.ignored += 1;
+				continue;
+			}
+			match self.store.get_peer(addr.clone()) {
+				Ok(ref peer) if peer.flags == State::Banned => {
+					import.ignored += 1;
+					continue;
+				}
+				_ if state == State::Banned => {
+					let ban_window = Duration::seconds(self.config.ban_window());
+					self.ban_peer(addr, ReasonForBan::ManualBan, ban_window);
+				}
+				_ if state == State::Discouraged => {
+					self.discourage_peer(addr, self.discourage_window());
+				}
+				Ok(mut peer) => {
+					if last_seen > peer.last_seen {
+						peer.flags = state;
+						peer.capabilities = capabilities;
+						peer.last_seen = last_seen;
+					} else if peer.capabilities == Capabilities::UNKNOWN {
+						peer.capabilities = capabilities;
+					}
+					self.store.save_peer(&peer)?;
+				}
+				Err(_) => self.store.save_peer(&PeerData {
+					addr,
+					capabilities,
+					user_agent: "".to_string(),
+					flags: state,
+					last_banned: 0,
+					ban_reason: ReasonForBan::None,
+					last_connected: last_seen,
+					last_error: None,
+					last_attempted: 0,
+					failures: 0,
+					banned_until: 0,
+					last_seen,
+					last_direction: None,
+				})?,
+			}
+			import.imported += 1;
+		}
+		Ok(import)
+	}
+
+	/// Saves the outbound peers we're connected to and doing well with as our
+	/// anchors, dialed first when we start again, see `take_anchors`. Our
+	/// longest connections first, at most `MAX_ANCHORS` of them. Preferred
+	/// peers are dialed anyway and left out, inbound ones are never anchors.
+	pub fn save_anchors(&self) {
+		let mut peers = self
+			.outgoing_connected_peers()
+			.into_iter()
+			.filter(|p| p.is_connected() && !p.is_banned() && !p.is_unresponsive())
+			.filter(|p| !self.config.is_preferred(&p.info.addr))
+			.collect::<Vec<_>>();
+		peers.sort_by_key(|p| p.info.first_seen());
+		let anchors = peers
+			.iter()
+			.take(MAX_ANCHORS)
+			.map(|p| p.info.addr.clone())
+			.collect::<Vec<_>>();
+		debug!("save_anchors: {:?}", anchors);
+		if let Err(e) = self.store.save_anchors(&anchors) {
+			error!("Couldn't save our anchors: {:?}", e);
+		}
+	}
+
+	/// The anchors saved when we last stopped, see `save_anchors`. They're
+	/// forgotten once taken, the ones we connect to again being saved again
+	/// when we stop.
+	pub fn take_anchors(&self) -> Vec<PeerAddr> {
+		let anchors = match self.store.anchors() {
+			Ok(anchors) => anchors,
+			Err(e) => {
+				error!("Couldn't read our anchors: {:?}", e);
+				return vec![];
+			}
+		};
+		if !anchors.is_empty() {
+			if let Err(e) = self.store.save_anchors(&vec![]) {
+				error!("Couldn't clear our anchors: {:?}", e);
+			}
+		}
+		anchors
+	}
+
+	/// Saves updated information about a peer
+	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
+		self.store.save_peer(p).map_err(From::from)
 	}
 
 	/// Updates the state of a peer in store
@@ -430,11 +1575,100 @@ impl Peers {
 			.map_err(From::from)
 	}
 
+	/// Asks the peer for a block, compact or full, unless another peer was
+	/// asked for it already and still has time to send it. The peer is then
+	/// kept as a candidate to ask next. Returns whether the request was sent.
+	pub fn request_block(&self, h: Hash, peer: &Peer, compact: bool) -> Result<bool, Error> {
+		let addr = &peer.info.addr;
+		let mut requests = self.block_requests.write();
+		if let Some(req) = requests.get_mut(&h) {
+			if req.peer != *addr
+				&& req.sent.elapsed() < self.config.block_request_timeout()
+				&& self.is_connected(&req.peer)
+			{
+				debug!(
+					"request_block: {} asked to {} already, {} waits its turn",
+					h, req.peer, addr
+				);
+				if !req.candidates.contains(addr) {
+					req.candidates.push(addr.clone());
+				}
+				return Ok(false);
+			}
+		}
+		send_block_request(peer, h, compact)?;
+		let candidates = requests
+			.remove(&h)
+			.map(|req| req.candidates)
+			.unwrap_or_default()
+			.into_iter()
+			.filter(|a| a != addr)
+			.collect();
+		requests.insert(
+			h,
+			BlockRequest {
+				peer: addr.clone(),
+				compact,
+				sent: Instant::now(),
+				candidates,
+			},
+		);
+		Ok(true)
+	}
+
+	/// Asks the next candidate for the blocks the peers we asked didn't send
+	/// in time (or can't anymore, gone). Blocks nobody else announced are
+	/// forgotten.
+	pub fn reissue_block_requests(&self) {
+		let timeout = self.config.block_request_timeout();
+		self.block_requests.write().retain(|h, req| {
+			if req.sent.elapsed() < timeout && self.is_connected(&req.peer) {
+				return true;
+			}
+			while !req.candidates.is_empty() {
+				let addr = req.candidates.remove(0);
+				let peer = match self.get_connected_peer(addr.clone()) {
+					Some(peer) if peer.is_connected() => peer,
+					_ => continue,
+				};
+				debug!(
+					"reissue_block_requests: no block {} from {}, asking {}",
+					h, req.peer, addr
+				);
+				if send_block_request(&peer, *h, req.compact).is_ok() {
+					req.peer = addr;
+					req.sent = Instant::now();
+					return true;
+				}
+			}
+			debug!(
+				"reissue_block_requests: no block {} from {}, nobody else to ask",
+				h, req.peer
+			);
+			false
+		});
+	}
+
+	// The block came, whoever sent it, we stop waiting for it.
+	fn block_request_done(&self, h: Hash) {
+		self.block_requests.write().remove(&h);
+	}
+
+	fn is_connected(&self, addr: &PeerAddr) -> bool {
+		self.get_connected_peer(addr.clone())
+			.map_or(false, |peer| peer.is_connected())
+	}
+
 	/// Iterate over the peer list and prune all peers we have
 	/// lost connection to or have been deemed problematic.
 	/// Also avoid connected peer count getting too high.
 	pub fn clean_peers(&self, max_count: usize) {
 		let mut rm = vec![];
+		let mut abusive = vec![];
+
+		// scores decayed to nothing are forgotten
+		let now = Utc::now();
+		self.scores.write().retain(|_, s| s.current(now) > 0);
 
 		// build a list of peers to be cleaned up
 		{
@@ -449,6 +1683,15 @@ impl Peers {
 				if peer.is_banned() {
 					debug!("clean_peers {:?}, peer banned", peer.info.addr);
 					rm.push(peer.info.addr.clone());
+				} else if peer.is_unresponsive() {
+					debug!(
+						"clean_peers {:?}, unresponsive, {} pings unanswered",
+						peer.info.addr,
+						peer.info.unanswered_pings()
+					);
+					self.events
+						.emit(&peer.info.addr, PeerEventKind::Unresponsive);
+					rm.push(peer.info.addr.clone());
 				} else if !peer.is_connected() {
 					debug!("clean_peers {:?}, not connected", peer.info.addr);
 					rm.push(peer.info.addr.clone());
@@ -459,15 +1702,20 @@ impl Peers {
 							peer.info.addr, counts.0, counts.1,
 						);
 					}
-					let _ = self.update_state(peer.info.addr, State::Banned);
-					rm.push(peer.info.addr.clone());
+					let _ = self.update_state(peer.info.addr.clone(), State::Banned);
+					let until = Utc::now().timestamp() + self.config.ban_window();
+					self.events.emit(
+						&peer.info.addr,
+						PeerEventKind::Banned(ReasonForBan::None, until),
+					);
+					abusive.push(peer.info.addr.clone());
 				} else {
 					let (stuck, diff) = peer.is_stuck();
 					match self.adapter.total_difficulty() {
 						Ok(total_difficulty) => {
 							if stuck && diff < total_difficulty {
 								debug!("clean_peers {:?}, stuck peer", peer.info.addr);
-								let _ = self.update_state(peer.info.addr, State::Defunct);
+								let _ = self.update_state(peer.info.addr.clone(), State::Defunct);
 								rm.push(peer.info.addr.clone());
 							}
 						}
@@ -477,21 +1725,66 @@ impl Peers {
 			}
 		}
 
-		// ensure we do not still have too many connected peers
+		// ensure we do not still have too many connected peers, our preferred
+		// peers coming on top and never dropped, the ones that dialed us
+		// dropped before the ones we picked
+		let mut excess = vec![];
+		let preferred_count = self
+			.connected_peers()
+			.iter()
+			.filter(|p| self.config.is_preferred(&p.info.addr))
+			.count();
 		let excess_count = (self.peer_count() as usize)
-			.saturating_sub(rm.len())
-			.saturating_sub(max_count);
+			.saturating_sub(rm.len() + abusive.len())
+			.saturating_sub(max_count + preferred_count);
 		if excess_count > 0 {
 			// map peers to addrs in a block to bound how long we keep the read lock for
-			let mut addrs = self
+			let mut candidates = self
 				.connected_peers()
+				.into_iter()
+				.filter(|p| !self.config.is_preferred(&p.info.addr))
+				.collect::<Vec<_>>();
+			candidates.sort_by_key(|p| p.info.is_outbound());
+			let mut addrs = candidates
 				.iter()
 				.take(excess_count)
 				.map(|x| x.info.addr.clone())
 				.collect::<Vec<_>>();
-			rm.append(&mut addrs);
+			excess.append(&mut addrs);
+		}
+
+		// nor more inbound or outbound peers than we take, our limits may have
+		// been lowered since they connected (our preferred outbound peers
+		// aren't counted, as when dialing)
+		let limits = self.limits();
+		for &(outbound, max) in &[(false, limits.max_inbound), (true, limits.max_outbound)] {
+			let staying = self
+				.connected_peers()
+				.into_iter()
+				.filter(|p| p.info.is_outbound() == outbound)
+				.filter(|p| !outbound || !self.config.is_preferred(&p.info.addr))
+				.filter(|p| {
+					let addr = &p.info.addr;
+					!rm.contains(addr) && !abusive.contains(addr) && !excess.contains(addr)
+				})
+				.collect::<Vec<_>>();
+			let over = staying.len().saturating_sub(max as usize);
+			let mut addrs = staying
+				.iter()
+				.filter(|p| !self.config.is_preferred(&p.info.addr))
+				.take(over)
+				.map(|p| p.info.addr.clone())
+				.collect::<Vec<_>>();
+			excess.append(&mut addrs);
 		}
 
+		let gone = rm
+			.iter()
+			.chain(abusive.iter())
+			.chain(excess.iter())
+			.cloned()
+			.collect::<HashSet<_>>();
+
 		// now clean up peer map based on the list to remove
 		{
 			let mut peers = match self.peers.try_write_for(LOCK_TIMEOUT) {
@@ -502,22 +1795,143 @@ impl Peers {
 				}
 			};
 			for addr in rm {
-				let _ = peers.get(&addr).map(|peer| peer.stop());
-				peers.remove(&addr);
+				if let Some(peer) = peers.remove(&addr) {
+					peer.stop();
+					self.events.emit(&addr, gone());
+				}
 			}
+			for addr in abusive {
+				if let Some(peer) = peers.remove(&addr) {
+					peer.stop_with_error(
+						PeerErrorCode::RateLimited,
+						"too many messages".to_owned(),
+					);
+					self.events.emit(&addr, gone());
+				}
+			}
+			for addr in excess {
+				if let Some(peer) = peers.remove(&addr) {
+					peer.disconnect(DisconnectReason::TooManyPeers);
+					self.events.emit(
+						&addr,
+						PeerEventKind::Disconnected(DisconnectReason::TooManyPeers),
+					);
+				}
+			}
+		}
+
+		// the blocks asked to the peers gone go to the next candidates, see
+		// reissue_block_requests
+		for req in self.block_requests.write().values_mut() {
+			req.candidates.retain(|addr| !gone.contains(addr));
 		}
 	}
 
-	pub fn stop(&self) {
-		let mut peers = self.peers.write();
-		for peer in peers.values() {
+	/// Goes over all our peers: the ones that stopped answering us, or whose
+	/// connection is gone, are dropped, when we last heard from the others is
+	/// saved. Works on a snapshot of our peers, their lock only taken to
+	/// remove the ones dropped, the connections closed and the store written
+	/// to without holding it.
+	pub fn sweep(&self) -> Sweep {
+		let snapshot = match self.peers.try_read_for(LOCK_TIMEOUT) {
+			Some(peers) => peers.values().cloned().collect::<Vec<_>>(),
+			None => {
+				error!("sweep: can't get peers lock");
+				return Sweep::default();
+			}
+		};
+
+		let mut flagged = vec![];
+		for peer in snapshot {
+			if peer.is_unresponsive() {
+				debug!(
+					"sweep {:?}, unresponsive, {} pings unanswered",
+					peer.info.addr,
+					peer.info.unanswered_pings()
+				);
+				self.events
+					.emit(&peer.info.addr, PeerEventKind::Unresponsive);
+				flagged.push(peer);
+			} else if !peer.is_connected() {
+				debug!("sweep {:?}, not connected", peer.info.addr);
+				flagged.push(peer);
+			} else {
+				let _ = self
+					.store
+					.update_last_seen(peer.info.addr.clone(), peer.info.last_seen().timestamp());
+			}
+		}
+
+		let mut dropped = vec![];
+		if !flagged.is_empty() {
+			let mut peers = match self.peers.try_write_for(LOCK_TIMEOUT) {
+				Some(peers) => peers,
+				None => {
+					error!("sweep: failed to get peers lock");
+					return Sweep::default();
+				}
+			};
+			for peer in flagged {
+				// only the very peer we looked at, not one that connected again
+				// from the same address since
+				let same = peers
+					.get(&peer.info.addr)
+					.map_or(false, |p| Arc::ptr_eq(p, &peer));
+				if same {
+					peers.remove(&peer.info.addr);
+					dropped.push(peer);
+				}
+			}
+		}
+		for peer in &dropped {
 			peer.stop();
+			self.events.emit(&peer.info.addr, gone());
+		}
+		if !dropped.is_empty() {
+			let gone = dropped
+				.iter()
+				.map(|p| p.info.addr.clone())
+				.collect::<HashSet<_>>();
+			for req in self.block_requests.write().values_mut() {
+				req.candidates.retain(|addr| !gone.contains(addr));
+			}
+		}
+
+		let connected = self.connected_peers();
+		let outbound = connected.iter().filter(|p| p.info.is_outbound()).count();
+		Sweep {
+			connected: connected.len(),
+			inbound: connected.len() - outbound,
+			outbound,
+			banned: self.store_stats().banned,
+			dropped: dropped.len(),
 		}
-		for (_, peer) in peers.drain() {
+	}
+
+	pub fn stop(&self) {
+		for peer in self.disconnect_all() {
 			peer.wait();
 		}
 	}
 
+	/// Tells all our peers we're shutting down and forgets about them, saving
+	/// when we last heard from them first. Their connections close once what
+	/// we still had queued for them is flushed, see `Peer::disconnect`.
+	pub fn disconnect_all(&self) -> Vec<Arc<Peer>> {
+		let mut peers = self.peers.write();
+		for peer in peers.values() {
+			let _ = self
+				.store
+				.update_last_seen(peer.info.addr.clone(), peer.info.last_seen().timestamp());
+			peer.disconnect(DisconnectReason::Shutdown);
+			self.events.emit(
+				&peer.info.addr,
+				PeerEventKind::Disconnected(DisconnectReason::Shutdown),
+			);
+		}
+		peers.drain().map(|(_, peer)| peer).collect()
+	}
+
 	pub fn enough_peers(&self) -> bool {
 		self.peer_count() >= self.config.peer_min_preferred_count()
 	}
@@ -528,20 +1942,36 @@ impl Peers {
 			&& self.peer_outbound_count() >= self.config.peer_min_preferred_count() / 2
 	}
 
-	/// Removes those peers that seem to have expired
+	/// Marks defunct the healthy peers we haven't heard from in a while, so
+	/// the ones we have fresher news of are dialed first, then removes those
+	/// peers that seem to have expired.
 	pub fn remove_expired(&self) {
 		let now = Utc::now();
+		let demote_after = Duration::days(self.config.peer_demote_days() as i64);
+		let expire_after = Duration::days(self.config.peer_expire_days() as i64);
 
-		// Delete defunct peers from storage
+		for peer in self.find_peers(State::Healthy, Capabilities::UNKNOWN, usize::max_value()) {
+			let diff = now - Utc.timestamp(peer.last_seen, 0);
+			if diff > demote_after && !self.is_known(peer.addr.clone()) {
+				debug!(
+					"demoting peer {:?}: last seen {} days ago.",
+					peer.addr,
+					diff.num_days()
+				);
+				let _ = self.update_state(peer.addr, State::Defunct);
+			}
+		}
+
+		// Delete defunct peers, and the ones we never managed to connect to, from storage
 		let _ = self.store.delete_peers(|peer| {
-			let diff = now - Utc.timestamp(peer.last_connected, 0);
+			let diff = now - Utc.timestamp(peer.last_seen, 0);
 
-			let should_remove = peer.flags == State::Defunct
-				&& diff > Duration::seconds(global::PEER_EXPIRATION_REMOVE_TIME);
+			let should_remove = (peer.flags == State::Defunct || peer.flags == State::Unverified)
+				&& diff > expire_after;
 
 			if should_remove {
 				debug!(
-					"removing peer {:?}: last connected {} days {} hours {} minutes ago.",
+					"removing peer {:?}: last seen {} days {} hours {} minutes ago.",
 					peer.addr,
 					diff.num_days(),
 					diff.num_hours(),
@@ -552,6 +1982,83 @@ impl Peers {
 			should_remove
 		});
 	}
+
+	/// Prunes the worst of our stored peers while we have more than
+	/// `peer_store_max` of them (see `prune_order`), at most `batch` at a time
+	/// for a pass to never hold us up for long. Our preferred peers, the
+	/// provided anchors, the peers we're connected to and the ones we
+	/// connected to within PRUNE_PROTECT_CONNECTED_SECS are never pruned.
+	/// Returns how many peers were.
+	pub fn prune_store(&self, batch: usize, anchors: &[PeerAddr]) -> usize {
+		let peers = match self.store.all_peers() {
+			Ok(peers) => peers,
+			Err(e) => {
+				error!("prune_store: couldn't read our peers: {:?}", e);
+				return 0;
+			}
+		};
+		let count = peers.len();
+		let excess = count.saturating_sub(self.config.peer_store_max());
+		if excess == 0 {
+			return 0;
+		}
+
+		let now = Utc::now().timestamp();
+		let pruned = prune_order(peers, now, self.config.ban_window())
+			.into_iter()
+			.filter(|p| {
+				let recently_connected = now - p.last_connected < PRUNE_PROTECT_CONNECTED_SECS;
+				!recently_connected
+					&& !self.config.is_preferred(&p.addr)
+					&& !anchors.contains(&p.addr)
+					&& !self.is_known(p.addr.clone())
+			})
+			.take(cmp::min(excess, batch))
+			.map(|p| p.addr)
+			.collect::<Vec<_>>();
+		if let Err(e) = self.store.delete_addrs(&pruned) {
+			error!("prune_store: couldn't delete peers: {:?}", e);
+			return 0;
+		}
+		debug!(
+			"prune_store: {} peers in store, {} over, pruned {}",
+			count,
+			excess,
+			pruned.len()
+		);
+		pruned.len()
+	}
+}
+
+// Orders the stored peers we can prune, the first to go first: the banned
+// ones whose ban is over, then the ones we only heard about, the oldest
+// first, then the defunct ones, the ones that failed us the most first. The
+// others (healthy, discouraged and so on) are left out.
+fn prune_order(peers: Vec<PeerData>, now: i64, ban_window: i64) -> Vec<PeerData> {
+	let mut ranked = peers
+		.into_iter()
+		.filter_map(|p| {
+			let rank = match p.flags {
+				State::Banned => {
+					let until = if p.banned_until > 0 {
+						p.banned_until
+					} else {
+						p.last_banned + ban_window
+					};
+					if until > now {
+						return None;
+					}
+					(0, cmp::Reverse(0), until)
+				}
+				State::Unverified => (1, cmp::Reverse(0), p.last_seen),
+				State::Defunct => (2, cmp::Reverse(p.failures), p.last_seen),
+				_ => return None,
+			};
+			Some((rank, p))
+		})
+		.collect::<Vec<_>>();
+	ranked.sort_by_key(|(rank, _)| *rank);
+	ranked.into_iter().map(|(_, p)| p).collect()
 }
 
 impl ChainAdapter for Peers {
@@ -575,12 +2082,22 @@ impl ChainAdapter for Peers {
 		self.adapter.tx_kernel_received(kernel_hash, peer_info)
 	}
 
+	fn block_inv_received(
+		&self,
+		hash: Hash,
+		height: u64,
+		peer_info: &PeerInfo,
+	) -> Result<bool, chain::Error> {
+		self.adapter.block_inv_received(hash, height, peer_info)
+	}
+
 	fn transaction_received(
 		&self,
 		tx: core::Transaction,
 		stem: bool,
+		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
-		self.adapter.transaction_received(tx, stem)
+		self.adapter.transaction_received(tx, stem, peer_info)
 	}
 
 	fn block_received(
@@ -590,14 +2107,12 @@ impl ChainAdapter for Peers {
 		was_requested: bool,
 	) -> Result<bool, chain::Error> {
 		let hash = b.hash();
+		self.block_request_done(hash);
 		if !self.adapter.block_received(b, peer_info, was_requested)? {
 			// if the peer sent us a block that's intrinsically bad
-			// they are either mistaken or malevolent, both of which require a ban
-			debug!(
-				"Received a bad block {} from  {}, the peer will be banned",
-				hash, peer_info.addr,
-			);
-			self.ban_peer(peer_info.addr, ReasonForBan::BadBlock);
+			// they are either mistaken or malevolent, a strike against them
+			debug!("Received a bad block {} from  {}", hash, peer_info.addr);
+			self.report_misbehavior(peer_info.addr.clone(), Offense::InvalidBlock);
 			Ok(false)
 		} else {
 			Ok(true)
@@ -610,14 +2125,16 @@ impl ChainAdapter for Peers {
 		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
 		let hash = cb.hash();
+		// a full block may be asked right away if the compact one falls short
+		self.block_request_done(hash);
 		if !self.adapter.compact_block_received(cb, peer_info)? {
 			// if the peer sent us a block that's intrinsically bad
-			// they are either mistaken or malevolent, both of which require a ban
+			// they are either mistaken or malevolent, a strike against them
 			debug!(
-				"Received a bad compact block {} from  {}, the peer will be banned",
+				"Received a bad compact block {} from  {}",
 				hash, peer_info.addr
 			);
-			self.ban_peer(peer_info.addr, ReasonForBan::BadCompactBlock);
+			self.report_misbehavior(peer_info.addr.clone(), Offense::InvalidBlock);
 			Ok(false)
 		} else {
 			Ok(true)
@@ -632,7 +2149,11 @@ impl ChainAdapter for Peers {
 		if !self.adapter.header_received(bh, peer_info)? {
 			// if the peer sent us a block header that's intrinsically bad
 			// they are either mistaken or malevolent, both of which require a ban
-			self.ban_peer(peer_info.addr, ReasonForBan::BadBlockHeader);
+			self.ban_peer(
+				peer_info.addr.clone(),
+				ReasonForBan::BadBlockHeader,
+				self.ban_window(),
+			);
 			Ok(false)
 		} else {
 			Ok(true)
@@ -647,7 +2168,11 @@ impl ChainAdapter for Peers {
 		if !self.adapter.headers_received(headers, peer_info)? {
 			// if the peer sent us a block header that's intrinsically bad
 			// they are either mistaken or malevolent, both of which require a ban
-			self.ban_peer(peer_info.addr, ReasonForBan::BadBlockHeader);
+			self.ban_peer(
+				peer_info.addr.clone(),
+				ReasonForBan::BadBlockHeader,
+				self.ban_window(),
+			);
 			Ok(false)
 		} else {
 			Ok(true)
@@ -662,6 +2187,10 @@ impl ChainAdapter for Peers {
 		self.adapter.get_block(h)
 	}
 
+	fn get_block_by_height(&self, height: u64) -> Option<core::Block> {
+		self.adapter.get_block_by_height(height)
+	}
+
 	fn kernel_data_read(&self) -> Result<File, chain::Error> {
 		self.adapter.kernel_data_read()
 	}
@@ -689,7 +2218,11 @@ impl ChainAdapter for Peers {
 				"Received a bad txhashset data from {}, the peer will be banned",
 				peer_info.addr
 			);
-			self.ban_peer(peer_info.addr, ReasonForBan::BadTxHashSet);
+			self.ban_peer(
+				peer_info.addr.clone(),
+				ReasonForBan::BadTxHashSet,
+				self.ban_window(),
+			);
 			Ok(false)
 		} else {
 			Ok(true)
@@ -717,35 +2250,163 @@ impl ChainAdapter for Peers {
 
 impl NetAdapter for Peers {
 	/// Find good peers we know with the provided capability and return their
-	/// addresses.
-	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr> {
-		let peers = self.find_peers(State::Healthy, capab, MAX_PEER_ADDRS as usize);
-		trace!("find_peer_addrs: {} healthy peers picked", peers.len());
-		map_vec!(peers, |p| p.addr)
-	}
-
-	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, peer_addrs: Vec<PeerAddr>) {
-		trace!("Received {} peer addrs, saving.", peer_addrs.len());
-		for pa in peer_addrs {
-			if let Ok(e) = self.exists_peer(pa) {
+	/// addresses, for the requester.
+	/// Peers we're connected to (a random part of them, not to give all our
+	/// connections away) and stored peers we connected to recently come
+	/// first, stale stored peers only make up for a short reply. Healthy
+	/// peers having any of the requested capabilities come first, other
+	/// healthy peers only make up for too few of them.
+	fn find_peer_addrs(&self, from: PeerAddr, capab: Capabilities) -> Vec<PeerAddr> {
+		let usable = |addr: &PeerAddr| {
+			*addr != from
+				&& addr.is_routable()
+				&& !self.self_addrs.contains(addr)
+				&& !self.is_banned(addr.clone())
+		};
+		let matches = |c: &Capabilities| capab.is_empty() || c.intersects(capab);
+
+		// connected peers are shuffled already
+		let mut live = self
+			.connected_peers()
+			.into_iter()
+			.map(|p| (p.info.addr.clone(), p.info.capabilities))
+			.filter(|(addr, _)| usable(addr))
+			.collect::<Vec<_>>();
+		let live_addrs = live
+			.iter()
+			.map(|(addr, _)| addr.clone())
+			.collect::<HashSet<_>>();
+		live.truncate((live.len() * 2 + 2) / 3);
+
+		let fresh_since = Utc::now().timestamp() - FRESH_PEER_ADDR_SECS;
+		let (stored, stale): (Vec<_>, Vec<_>) = self
+			.find_peers(State::Healthy, Capabilities::UNKNOWN, usize::max_value())
+			.into_iter()
+			.filter(|p| usable(&p.addr) && !live_addrs.contains(&p.addr))
+			.map(|p| ((p.addr, p.capabilities), p.last_connected))
+			.partition(|(_, last_connected)| *last_connected >= fresh_since);
+		let fresh = live
+			.into_iter()
+			.chain(stored.into_iter().map(|(p, _)| p))
+			.collect::<Vec<_>>();
+		let stale = stale.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+
+		let (matching, others): (Vec<_>, Vec<_>) = fresh.into_iter().partition(|(_, c)| matches(c));
+		let (stale_matching, stale_others): (Vec<_>, Vec<_>) =
+			stale.into_iter().partition(|(_, c)| matches(c));
+		trace!(
+			"find_peer_addrs: {} fresh peers matching {:?}, {} others, {} stale",
+			matching.len(),
+			capab,
+			others.len(),
+			stale_matching.len() + stale_others.len(),
+		);
+		let mut peers = matching;
+		let mut stale = stale_matching;
+		if peers.len() < MIN_FILTERED_PEER_ADDRS {
+			peers.extend(others);
+			stale.extend(stale_others);
+		}
+		// stale peers never make up more than a third of the reply
+		if peers.len() < MIN_FILTERED_PEER_ADDRS {
+			let room = cmp::min(peers.len() / 2, MIN_FILTERED_PEER_ADDRS - peers.len());
+			peers.extend(stale.into_iter().take(room));
+		}
+		peers.truncate(MAX_PEER_ADDRS as usize);
+		// and the live ones don't stand out by coming first
+		peers.shuffle(&mut thread_rng());
+		peers.into_iter().map(|(addr, _)| addr).collect()
+	}
+
+	/// A list of peers has been received from one of our peers. Duplicates,
+	/// our own and unroutable addresses are skipped, and a peer only gets to
+	/// add so many new addresses to our store in an hour. They're unverified
+	/// until we connect to them.
+	fn peer_addrs_received(&self, from: PeerAddr, peer_addrs: Vec<PeerAddr>) {
+		trace!(
+			"Received {} peer addrs from {}, saving.",
+			peer_addrs.len(),
+			from
+		);
+		let allowance = self.addrs_allowance(&from);
+		let mut seen = HashSet::new();
+		let mut saved = 0;
+		for pa in peer_addrs.into_iter().take(MAX_PEER_ADDRS as usize) {
+			if !seen.insert(pa.clone()) {
+				continue;
+			}
+			if self.self_addrs.contains(&pa) {
+				trace!("Received our own address {}, skipping.", pa);
+				continue;
+			}
+			if !pa.is_routable() {
+				debug!("Received unroutable address {}, skipping.", pa);
+				continue;
+			}
+			if let Ok(e) = self.exists_peer(pa.clone()) {
 				if e {
 					continue;
 				}
 			}
+			if saved >= allowance {
+				debug!(
+					"{} added {} addresses this hour already, skipping the rest.",
+					from, MAX_SAVED_ADDRS_PER_HOUR
+				);
+				break;
+			}
 			let peer = PeerData {
 				addr: pa,
 				capabilities: Capabilities::UNKNOWN,
 				user_agent: "".to_string(),
-				flags: State::Healthy,
+				flags: State::Unverified,
 				last_banned: 0,
 				ban_reason: ReasonForBan::None,
-				last_connected: Utc::now().timestamp(),
+				// only heard of, not protected from pruning (see `prune_store`)
+				last_connected: 0,
+				last_error: None,
+				last_attempted: 0,
+				failures: 0,
+				banned_until: 0,
+				last_seen: Utc::now().timestamp(),
+				last_direction: None,
 			};
-			if let Err(e) = self.save_peer(&peer) {
-				error!("Could not save received peer address: {:?}", e);
+			match self.save_peer(&peer) {
+				Ok(()) => saved += 1,
+				Err(e) => error!("Could not save received peer address: {:?}", e),
 			}
 		}
+		self.inc_addrs_saved(from, saved);
+	}
+
+	/// A random few of the peers we're connected to or connected to within
+	/// the last day, never the recipient itself nor unroutable addresses.
+	fn gossip_addrs(&self, to: PeerAddr) -> Vec<PeerAddr> {
+		let usable = |addr: &PeerAddr| {
+			*addr != to
+				&& addr.is_routable()
+				&& !self.self_addrs.contains(addr)
+				&& !self.is_banned(addr.clone())
+		};
+		let fresh_since = Utc::now().timestamp() - FRESH_PEER_ADDR_SECS;
+		let live = self
+			.connected_peers()
+			.into_iter()
+			.map(|p| p.info.addr.clone());
+		let stored = self
+			.find_peers(State::Healthy, Capabilities::UNKNOWN, usize::max_value())
+			.into_iter()
+			.filter(|p| p.last_connected >= fresh_since)
+			.map(|p| p.addr);
+		let mut addrs = live
+			.chain(stored)
+			.filter(|addr| usable(addr))
+			.collect::<HashSet<_>>()
+			.into_iter()
+			.collect::<Vec<_>>();
+		addrs.shuffle(&mut thread_rng());
+		addrs.truncate(MAX_GOSSIP_ADDRS);
+		addrs
 	}
 
 	fn peer_difficulty(&self, addr: PeerAddr, diff: Difficulty, height: u64) {
@@ -754,11 +2415,445 @@ impl NetAdapter for Peers {
 		}
 	}
 
+	/// A bad block or header from us means either the peer or us are on a
+	/// fork, the rest is about how soon to dial it again.
+	fn ban_reason_received(&self, addr: PeerAddr, ban_reason: ReasonForBan) {
+		match ban_reason {
+			ReasonForBan::BadBlock
+			| ReasonForBan::BadCompactBlock
+			| ReasonForBan::BadBlockHeader
+			| ReasonForBan::BadTxHashSet
+			| ReasonForBan::FraudHeight => warn!(
+				"{} banned us for {:?}, we may be on a bad fork",
+				addr, ban_reason
+			),
+			_ => {}
+		}
+		self.connect_failed(addr, &Error::Disconnected(ban_reason));
+	}
+
+	fn peer_error_received(&self, addr: PeerAddr, error: PeerError) {
+		if let Err(e) = self.store.update_last_error(addr.clone(), error) {
+			debug!("Couldn't save last error of {}: {:?}", addr, e);
+		}
+	}
+
+	fn disconnect_received(&self, addr: PeerAddr, reason: DisconnectReason) {
+		match reason {
+			// nothing wrong with the peer, it stays healthy, we just give it
+			// some time to come back or make room
+			DisconnectReason::Shutdown | DisconnectReason::TooManyPeers => {
+				let now = Utc::now();
+				let mut reconnect_at = self.reconnect_at.write();
+				reconnect_at.retain(|_, at| *at > now);
+				reconnect_at.insert(
+					addr,
+					now + Duration::seconds(RECONNECT_DELAY.as_secs() as i64),
+				);
+			}
+			_ => self.connect_failed(addr, &Error::Closed(reason)),
+		}
+	}
+
 	fn is_banned(&self, addr: PeerAddr) -> bool {
-		if let Ok(peer) = self.get_peer(addr) {
-			peer.flags == State::Banned
-		} else {
-			false
+		Peers::is_banned(self, addr)
+	}
+
+	fn report_misbehavior(&self, addr: PeerAddr, offense: Offense) {
+		Peers::report_misbehavior(self, addr, offense)
+	}
+}
+
+// A peer we dropped without closing the connection in good order, or that
+// dropped us.
+fn gone() -> PeerEventKind {
+	PeerEventKind::Disconnected(DisconnectReason::None)
+}
+
+// The block in compact form, trying another nonce when two of its kernels
+// share a short_id. None when they still do after COMPACT_BLOCK_TRIES, as
+// with duplicate kernels.
+fn compact_block(b: &core::Block) -> Option<core::CompactBlock> {
+	(0..COMPACT_BLOCK_TRIES)
+		.map(|_| core::CompactBlock::from(b.clone()))
+		.find(|cb| cb.kern_ids().verify_sorted_and_unique().is_ok())
+}
+
+// Reads a line as written by `Peers::save_to_file`.
+fn parse_peer_line(line: &str) -> Option<(PeerAddr, Capabilities, State, i64)> {
+	let mut fields = line.split_whitespace();
+	let addr = fields.next()?.parse::<PeerAddr>().ok()?;
+	let capabilities = Capabilities::from_bits_truncate(fields.next()?.parse().ok()?);
+	let state = match fields.next()? {
+		"Healthy" => State::Healthy,
+		"Banned" => State::Banned,
+		"Defunct" => State::Defunct,
+		"Incompatible" => State::Incompatible,
+		"Unverified" => State::Unverified,
+		"Discouraged" => State::Discouraged,
+		_ => return None,
+	};
+	let last_seen = fields.next()?.parse().ok()?;
+	if fields.next().is_some() {
+		return None;
+	}
+	Some((addr, capabilities, state, last_seen))
+}
+
+/// Tie-break between an inbound and an outbound connection to the same peer,
+/// as happens when we dial each other at the same time. Both sides reach the
+/// same decision from the node ids exchanged in the handshake: the side with
+/// the smaller id keeps its outbound connection, the other side keeps the
+/// inbound one.
+pub fn keep_outbound(ours: &NodeId, theirs: &NodeId) -> bool {
+	ours < theirs
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn compact_block_unique_ids() {
+		let mut b = core::Block::with_header(core::BlockHeader::default());
+		*b.kernels_mut() = vec![core::TxKernel::empty()];
+		assert!(compact_block(&b).is_some());
+
+		// duplicate kernels, never unique whatever the nonce
+		*b.kernels_mut() = vec![core::TxKernel::empty(), core::TxKernel::empty()];
+		assert!(compact_block(&b).is_none());
+	}
+
+	#[test]
+	fn score_decays() {
+		let start = Utc::now();
+		let mut score = Score {
+			points: 0,
+			at: start,
+		};
+		assert_eq!(score.add(40, start), 40);
+		assert_eq!(score.add(40, start), 80);
+
+		// a point every MISBEHAVIOR_DECAY_SECS
+		let later = start + Duration::seconds(10 * MISBEHAVIOR_DECAY_SECS);
+		assert_eq!(score.current(later), 70);
+		assert_eq!(score.add(20, later), 90);
+
+		// ancient strikes are gone, never below nothing
+		let much_later = later + Duration::days(1);
+		assert_eq!(score.current(much_later), 0);
+		assert_eq!(score.add(40, much_later), 40);
+		assert_eq!(score.current(much_later - Duration::days(2)), 40);
+	}
+
+	fn rank(difficulty: u64, strikes: usize, rtt_ms: Option<u64>, now: DateTime<Utc>) -> SyncRank {
+		SyncRank {
+			difficulty: Difficulty::from_num(difficulty),
+			outbound: true,
+			strikes,
+			rtt: rtt_ms.map(std::time::Duration::from_millis),
+			last_seen: now,
+			usable: true,
+			discouraged: false,
+		}
+	}
+
+	#[test]
+	fn sync_ranking() {
+		let now = Utc::now();
+		let peers = vec![
+			("slow", rank(20, 0, Some(300), now)),
+			("unknown_rtt", rank(20, 0, None, now)),
+			("less_work", rank(15, 0, Some(10), now)),
+			("fast", rank(20, 0, Some(50), now)),
+			("struck", rank(20, 1, Some(5), now)),
+			("most_work", rank(30, 3, None, now)),
+		];
+		assert_eq!(
+			rank_for_sync(peers, None, now),
+			vec![
+				"most_work",
+				"fast",
+				"slow",
+				"unknown_rtt",
+				"struck",
+				"less_work"
+			]
+		);
+
+		// same rank, same order as provided
+		let peers = vec![
+			("b", rank(20, 0, Some(50), now)),
+			("a", rank(20, 0, Some(50), now)),
+		];
+		assert_eq!(rank_for_sync(peers, None, now), vec!["b", "a"]);
+
+		// the peers we dialed before the faster ones that dialed us, never
+		// before more work
+		let inbound = |difficulty, rtt_ms| SyncRank {
+			outbound: false,
+			..rank(difficulty, 0, Some(rtt_ms), now)
+		};
+		let peers = vec![
+			("inbound_fast", inbound(20, 5)),
+			("inbound_most_work", inbound(30, 100)),
+			("outbound_slow", rank(20, 0, Some(300), now)),
+		];
+		assert_eq!(
+			rank_for_sync(peers, None, now),
+			vec!["inbound_most_work", "outbound_slow", "inbound_fast"]
+		);
+	}
+
+	#[test]
+	fn sync_ranking_exclusions() {
+		let now = Utc::now();
+		let stale = SyncRank {
+			last_seen: now - Duration::seconds(STALE_DIFFICULTY_SECS + 1),
+			..rank(40, 0, Some(10), now)
+		};
+		let banned = SyncRank {
+			usable: false,
+			..rank(40, 0, Some(10), now)
+		};
+		let peers = vec![
+			("stale", stale),
+			("banned", banned),
+			("more", rank(21, 0, Some(100), now)),
+			("same", rank(20, 0, Some(10), now)),
+			("less", rank(10, 0, Some(10), now)),
+		];
+		assert_eq!(
+			rank_for_sync(peers, Some(Difficulty::from_num(20)), now),
+			vec!["more"]
+		);
+	}
+
+	#[test]
+	fn sync_ranking_discouraged() {
+		let now = Utc::now();
+		let discouraged = |difficulty| SyncRank {
+			discouraged: true,
+			..rank(difficulty, 0, Some(10), now)
+		};
+
+		// left out for any other peer, even with less work
+		let peers = vec![
+			("discouraged_most_work", discouraged(30)),
+			("less_work", rank(10, 2, None, now)),
+			("discouraged", discouraged(20)),
+		];
+		assert_eq!(rank_for_sync(peers, None, now), vec!["less_work"]);
+
+		// ranked as usual when there's nobody else
+		let peers = vec![
+			("discouraged", discouraged(20)),
+			("discouraged_most_work", discouraged(30)),
+		];
+		assert_eq!(
+			rank_for_sync(peers, None, now),
+			vec!["discouraged_most_work", "discouraged"]
+		);
+
+		// the others being excluded doesn't count
+		let banned = SyncRank {
+			usable: false,
+			..rank(40, 0, Some(10), now)
+		};
+		let peers = vec![("banned", banned), ("discouraged", discouraged(20))];
+		assert_eq!(rank_for_sync(peers, None, now), vec!["discouraged"]);
+	}
+
+	#[test]
+	fn discouraged_admission() {
+		// below half the maximum only
+		assert!(admits_discouraged(0, 8));
+		assert!(admits_discouraged(3, 8));
+		assert!(!admits_discouraged(4, 8));
+		assert!(!admits_discouraged(7, 8));
+		assert!(!admits_discouraged(8, 8));
+		// half of an odd maximum
+		assert!(admits_discouraged(2, 5));
+		assert!(!admits_discouraged(3, 5));
+		assert!(admits_discouraged(0, 1));
+		// never when we take no inbound peers at all
+		assert!(!admits_discouraged(0, 0));
+	}
+
+	fn evictable(ip: &str, mins: i64, rtt_ms: u64, now: DateTime<Utc>) -> EvictionRank {
+		EvictionRank {
+			netgroup: Some(netgroup(&ip.parse().unwrap())),
+			connected_since: now - Duration::minutes(mins),
+			rtt: Some(std::time::Duration::from_millis(rtt_ms)),
+			serving: false,
 		}
 	}
+
+	#[test]
+	fn eviction_dominant_group() {
+		let now = Utc::now();
+		// ten peers from 10.1.0.0/16, oldest first, and two other groups
+		let mut peers = (0..10)
+			.map(|i| {
+				let rtt = if i == 9 { 10 } else { 100 + i };
+				let ip = format!("10.1.0.{}", i);
+				(i, evictable(&ip, 20 - i as i64, rtt, now))
+			})
+			.collect::<Vec<_>>();
+		peers.push((10, evictable("20.1.0.1", 1, 500, now)));
+		peers.push((11, evictable("30.1.0.1", 2, 500, now)));
+
+		// 0 to 3 are the oldest, 9 and 4 to 6 the fastest, 7, 10 and 11 the
+		// oldest of their group
+		assert_eq!(pick_eviction(peers), Some(8));
+	}
+
+	#[test]
+	fn eviction_nothing_left() {
+		let now = Utc::now();
+		// everyone in a group of their own
+		let peers = (0..9)
+			.map(|i| {
+				let ip = format!("10.{}.0.1", i);
+				(i, evictable(&ip, 20 - i as i64, 100, now))
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(pick_eviction(peers), None);
+
+		// too few to evict any
+		let peers = (0..8)
+			.map(|i| (i, evictable("10.1.0.1", 20 - i as i64, 100, now)))
+			.collect::<Vec<_>>();
+		assert_eq!(pick_eviction(peers), None);
+
+		// one more than we protect in the same group, the youngest goes
+		let peers = (0..10)
+			.map(|i| (i, evictable("10.1.0.1", 20 - i as i64, 100, now)))
+			.collect::<Vec<_>>();
+		assert_eq!(pick_eviction(peers), Some(9));
+	}
+
+	#[test]
+	fn random_eviction_protected() {
+		let now = Utc::now();
+		// the four fastest and the ones serving us are kept, unknown
+		// round-trip times don't protect
+		let protected = || {
+			let mut peers = (0..4)
+				.map(|i| (i, evictable("10.1.0.1", 10, 10 + i as u64, now)))
+				.collect::<Vec<_>>();
+			for i in 4..7 {
+				peers.push((
+					i,
+					EvictionRank {
+						serving: true,
+						..evictable("10.1.0.1", 10, 500, now)
+					},
+				));
+			}
+			peers
+		};
+		for _ in 0..10 {
+			let mut peers = protected();
+			peers.push((
+				7,
+				EvictionRank {
+					rtt: None,
+					..evictable("10.1.0.1", 10, 100, now)
+				},
+			));
+			assert_eq!(pick_random_eviction(peers), Some(7));
+		}
+
+		// nor does being slower than the fastest four
+		let mut peers = protected();
+		peers.push((8, evictable("10.1.0.1", 10, 300, now)));
+		assert_eq!(pick_random_eviction(peers), Some(8));
+	}
+
+	#[test]
+	fn random_eviction_spread() {
+		let now = Utc::now();
+		let peers = || {
+			(0..10)
+				.map(|i| {
+					let ip = format!("10.{}.0.1", i);
+					(i, evictable(&ip, 20 - i as i64, 10 + 10 * i as u64, now))
+				})
+				.collect::<Vec<_>>()
+		};
+		let picked = (0..200)
+			.filter_map(|_| pick_random_eviction(peers()))
+			.collect::<HashSet<_>>();
+		// any but the four fastest
+		assert_eq!(picked, (4..10).collect::<HashSet<_>>());
+	}
+
+	#[test]
+	fn random_eviction_nothing_left() {
+		let now = Utc::now();
+		assert_eq!(pick_random_eviction::<u32>(vec![]), None);
+
+		// all fast or serving us
+		let mut peers = (0..4)
+			.map(|i| (i, evictable("10.1.0.1", 10, 10, now)))
+			.collect::<Vec<_>>();
+		peers.push((
+			4,
+			EvictionRank {
+				serving: true,
+				rtt: None,
+				..evictable("10.1.0.1", 10, 10, now)
+			},
+		));
+		assert_eq!(pick_random_eviction(peers), None);
+	}
+
+	fn stored(port: u16, flags: State, failures: u32, last_seen: i64) -> PeerData {
+		PeerData {
+			addr: PeerAddr::Ip(format!("10.2.0.1:{}", port).parse().unwrap()),
+			capabilities: Capabilities::UNKNOWN,
+			user_agent: "".to_string(),
+			flags,
+			last_banned: 0,
+			ban_reason: ReasonForBan::None,
+			last_connected: 0,
+			last_error: None,
+			last_attempted: 0,
+			failures,
+			banned_until: 0,
+			last_seen,
+			last_direction: None,
+		}
+	}
+
+	#[test]
+	fn pruning_order() {
+		let now = 1_000_000;
+		let banned = |port, until| PeerData {
+			banned_until: until,
+			..stored(port, State::Banned, 0, 0)
+		};
+		let peers = vec![
+			stored(1, State::Healthy, 0, 0),
+			stored(2, State::Defunct, 1, 10),
+			stored(3, State::Unverified, 0, 50),
+			banned(4, now + 1),
+			stored(5, State::Defunct, 5, 20),
+			stored(6, State::Unverified, 0, 30),
+			banned(7, now - 1),
+			stored(8, State::Discouraged, 0, 0),
+			stored(9, State::Defunct, 1, 5),
+			// banned before the ban window, long over
+			PeerData {
+				last_banned: now - 100,
+				..stored(10, State::Banned, 0, 0)
+			},
+		];
+		let order = prune_order(peers, now, 10)
+			.into_iter()
+			.map(|p| p.addr.port())
+			.collect::<Vec<_>>();
+		assert_eq!(order, vec![10, 7, 6, 3, 5, 9, 2]);
+	}
 }
@@ -0,0 +1,788 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mapping our p2p port on the NAT gateway in front of us, through UPnP IGD
+//! or NAT-PMP, so nodes at home can be reached by their peers too.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::types::{is_routable_ip, PeerAddr, SelfAddrs};
+use crate::util::Mutex;
+
+/// How long we ask our mappings to last for, renewed once half of it is over.
+pub const PORT_MAPPING_LEASE: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest lease we go by, whatever the gateway grants us.
+const MIN_LEASE: Duration = Duration::from_secs(2 * 60);
+
+/// How long we wait before trying again, once we lost a mapping we had.
+const REMAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a gateway has to answer each of our requests.
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(3);
+
+const NAT_PMP_PORT: u16 = 5351;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+const IGD_SERVICES: [&str; 2] = [
+	"urn:schemas-upnp-org:service:WANIPConnection:1",
+	"urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// A NAT gateway we can ask to forward a port to us.
+pub trait Gateway: Send + Sync {
+	/// What the gateway is, for our logs.
+	fn name(&self) -> &'static str;
+
+	/// Forwards the provided TCP port, the same on both sides, for the lease
+	/// asked for. Returns the lease granted, which may be shorter.
+	fn map(&self, port: u16, lease: Duration) -> io::Result<Duration>;
+
+	/// Stops forwarding the provided port.
+	fn unmap(&self, port: u16) -> io::Result<()>;
+
+	/// Our address on the other side of the gateway.
+	fn external_ip(&self) -> io::Result<IpAddr>;
+}
+
+/// Our gateways, tried in order: UPnP IGD first, then NAT-PMP.
+pub fn default_gateways() -> Vec<Box<dyn Gateway>> {
+	vec![Box::new(Upnp::new()), Box::new(NatPmp::new())]
+}
+
+// Where we stand with the mapping of our port.
+#[derive(Debug, Clone, PartialEq)]
+enum Mapping {
+	// not tried yet
+	Idle,
+	// forwarded by one of our gateways, renewed once due
+	Mapped {
+		gateway: usize,
+		external: PeerAddr,
+		renew_at: Instant,
+	},
+	// had one, lost it, tried again once due
+	Lost {
+		retry_at: Instant,
+	},
+	// no gateway would map our port to begin with, not bothering again
+	Unavailable,
+	// removed on shutdown
+	Removed,
+}
+
+/// Keeps our p2p port mapped on our gateway, renewing the lease while we're
+/// up. The external address we get is the one we advertise, unless one is
+/// configured. Failing to map it leaves us as reachable as we were before.
+pub struct PortMapper {
+	gateways: Vec<Box<dyn Gateway>>,
+	port: u16,
+	lease: Duration,
+	addrs: Arc<SelfAddrs>,
+	state: Mutex<Mapping>,
+}
+
+impl PortMapper {
+	/// Mapper of the provided port through the gateways provided, recording
+	/// the address we get in our own addresses.
+	pub fn new(gateways: Vec<Box<dyn Gateway>>, port: u16, addrs: Arc<SelfAddrs>) -> PortMapper {
+		PortMapper {
+			gateways,
+			port,
+			lease: PORT_MAPPING_LEASE,
+			addrs,
+			state: Mutex::new(Mapping::Idle),
+		}
+	}
+
+	/// Same mapper, asking for the provided lease.
+	pub fn with_lease(self, lease: Duration) -> PortMapper {
+		PortMapper { lease, ..self }
+	}
+
+	/// Maps our port if we haven't yet, renews our lease once half of it is
+	/// over and tries again a while after losing it. Nothing to do until one
+	/// of those is due.
+	pub fn refresh(&self, now: Instant) {
+		// gateways take their time answering, our external address can still
+		// be asked for meanwhile
+		let current = self.state.lock().clone();
+		let next = match current {
+			Mapping::Idle => match self.map_any(None, None, now) {
+				Some(mapped) => mapped,
+				None => {
+					warn!(
+						"Couldn't map port {} on any gateway, reachable from outside our NAT only if forwarded.",
+						self.port
+					);
+					Mapping::Unavailable
+				}
+			},
+			Mapping::Mapped {
+				gateway,
+				external,
+				renew_at,
+			} if now >= renew_at => match self.map_any(Some(gateway), Some(&external), now) {
+				Some(mapped) => mapped,
+				None => {
+					warn!(
+						"Lost the mapping of port {}, trying again in {}s.",
+						self.port,
+						REMAP_INTERVAL.as_secs()
+					);
+					self.addrs.set_mapped(None);
+					Mapping::Lost {
+						retry_at: now + REMAP_INTERVAL,
+					}
+				}
+			},
+			Mapping::Lost { retry_at } if now >= retry_at => match self.map_any(None, None, now) {
+				Some(mapped) => mapped,
+				None => Mapping::Lost {
+					retry_at: now + REMAP_INTERVAL,
+				},
+			},
+			_ => return,
+		};
+		let mut state = self.state.lock();
+		if *state == Mapping::Removed {
+			// removed while we were mapping it, not leaving it behind
+			if let Mapping::Mapped { gateway, .. } = next {
+				let _ = self.gateways[gateway].unmap(self.port);
+				self.addrs.set_mapped(None);
+			}
+			return;
+		}
+		*state = next;
+	}
+
+	/// Removes our mapping, if we have one, and stops maintaining it.
+	pub fn remove(&self) {
+		let mut state = self.state.lock();
+		if let Mapping::Mapped { gateway, .. } = *state {
+			match self.gateways[gateway].unmap(self.port) {
+				Ok(()) => info!(
+					"Removed the mapping of port {} on {}.",
+					self.port,
+					self.gateways[gateway].name()
+				),
+				Err(e) => debug!("Couldn't remove the mapping of port {}: {:?}", self.port, e),
+			}
+			self.addrs.set_mapped(None);
+		}
+		*state = Mapping::Removed;
+	}
+
+	/// The address our port is mapped at, if it is.
+	pub fn external_addr(&self) -> Option<PeerAddr> {
+		match *self.state.lock() {
+			Mapping::Mapped { ref external, .. } => Some(external.clone()),
+			_ => None,
+		}
+	}
+
+	// Maps our port through the first gateway that lets us, the provided
+	// one first if any (the one we're renewing our lease with). A gateway
+	// that's itself behind another NAT gives us an address nobody outside
+	// can reach, so we let go of that mapping and try the next one.
+	fn map_any(
+		&self,
+		first: Option<usize>,
+		current: Option<&PeerAddr>,
+		now: Instant,
+	) -> Option<Mapping> {
+		let mut order: Vec<usize> = first.into_iter().collect();
+		order.extend((0..self.gateways.len()).filter(|i| Some(*i) != first));
+		for i in order {
+			let gateway = &self.gateways[i];
+			let mapped = gateway
+				.map(self.port, self.lease)
+				.and_then(|granted| gateway.external_ip().map(|ip| (granted, ip)));
+			match mapped {
+				Ok((_, ip)) if !is_routable_ip(&ip) => {
+					debug!(
+						"Port {} mapped on {} at {}, not routable, dropping it.",
+						self.port,
+						gateway.name(),
+						ip
+					);
+					if let Err(e) = gateway.unmap(self.port) {
+						debug!("Couldn't remove the mapping of port {}: {:?}", self.port, e);
+					}
+				}
+				Ok((granted, ip)) => {
+					let external = PeerAddr::Ip(SocketAddr::new(ip, self.port));
+					if first == Some(i) && current == Some(&external) {
+						debug!("Renewed the mapping of port {}.", self.port);
+					} else {
+						info!(
+							"Port {} mapped on {}, reachable at {}.",
+							self.port,
+							gateway.name(),
+							external
+						);
+					}
+					self.addrs.set_mapped(Some(external.clone()));
+					return Some(Mapping::Mapped {
+						gateway: i,
+						external,
+						renew_at: now + cmp::max(granted, MIN_LEASE) / 2,
+					});
+				}
+				Err(e) => debug!(
+					"Couldn't map port {} on {}: {:?}",
+					self.port,
+					gateway.name(),
+					e
+				),
+			}
+		}
+		None
+	}
+}
+
+/// NAT-PMP (RFC 6886), asking the default gateway of the host.
+pub struct NatPmp {
+	gateway: Option<Ipv4Addr>,
+}
+
+impl NatPmp {
+	/// Client of our default gateway, if we can find it.
+	pub fn new() -> NatPmp {
+		NatPmp {
+			gateway: default_gateway(),
+		}
+	}
+
+	/// Client of the provided gateway.
+	pub fn with_gateway(gateway: Ipv4Addr) -> NatPmp {
+		NatPmp {
+			gateway: Some(gateway),
+		}
+	}
+
+	// Sends the request, retransmitting it on a doubling timeout as the RFC
+	// asks, until we get the answer to it.
+	fn request(&self, req: &[u8], resp_len: usize) -> io::Result<Vec<u8>> {
+		let gateway = self.gateway.ok_or_else(|| other("no default gateway"))?;
+		let socket = UdpSocket::bind("0.0.0.0:0")?;
+		socket.connect(SocketAddrV4::new(gateway, NAT_PMP_PORT))?;
+		let mut timeout = Duration::from_millis(250);
+		let mut buf = [0; 16];
+		while timeout <= GATEWAY_TIMEOUT {
+			socket.send(req)?;
+			socket.set_read_timeout(Some(timeout))?;
+			match socket.recv(&mut buf) {
+				Ok(n) if n >= resp_len && buf[1] == req[1] + 128 => {
+					let result = u16::from_be_bytes([buf[2], buf[3]]);
+					if result != 0 {
+						return Err(other(&format!("result code {}", result)));
+					}
+					return Ok(buf[..n].to_vec());
+				}
+				Ok(_) => (),
+				Err(ref e)
+					if e.kind() == io::ErrorKind::WouldBlock
+						|| e.kind() == io::ErrorKind::TimedOut => {}
+				Err(e) => return Err(e),
+			}
+			timeout *= 2;
+		}
+		Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+	}
+
+	fn map_request(&self, port: u16, lease: Duration) -> io::Result<Duration> {
+		let mut req = vec![0, 2, 0, 0];
+		req.extend_from_slice(&port.to_be_bytes());
+		// suggested external port, none when removing
+		let external = if lease == Duration::from_secs(0) {
+			0
+		} else {
+			port
+		};
+		req.extend_from_slice(&external.to_be_bytes());
+		req.extend_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+		let resp = self.request(&req, 16)?;
+		let mapped = u16::from_be_bytes([resp[10], resp[11]]);
+		if external != 0 && mapped != port {
+			return Err(other(&format!("mapped to port {} instead", mapped)));
+		}
+		let granted = u32::from_be_bytes([resp[12], resp[13], resp[14], resp[15]]);
+		Ok(Duration::from_secs(granted as u64))
+	}
+}
+
+impl Gateway for NatPmp {
+	fn name(&self) -> &'static str {
+		"NAT-PMP"
+	}
+
+	fn map(&self, port: u16, lease: Duration) -> io::Result<Duration> {
+		self.map_request(port, lease)
+	}
+
+	fn unmap(&self, port: u16) -> io::Result<()> {
+		self.map_request(port, Duration::from_secs(0)).map(|_| ())
+	}
+
+	fn external_ip(&self) -> io::Result<IpAddr> {
+		let resp = self.request(&[0, 0], 12)?;
+		Ok(IpAddr::V4(Ipv4Addr::new(
+			resp[8], resp[9], resp[10], resp[11],
+		)))
+	}
+}
+
+// Our default IPv4 gateway, from the kernel routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+	let routes = fs::read_to_string("/proc/net/route").ok()?;
+	routes.lines().skip(1).find_map(|line| {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		if fields.len() < 3 || fields[1] != "00000000" {
+			return None;
+		}
+		let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+		Some(Ipv4Addr::from(u32::from_be(gateway)))
+	})
+}
+
+/// UPnP IGD, the first Internet gateway device answering our discovery.
+pub struct Upnp {
+	// found on first use
+	control: Mutex<Option<Control>>,
+}
+
+// Where to send the port mapping actions of a gateway device.
+#[derive(Clone)]
+struct Control {
+	addr: SocketAddr,
+	path: String,
+	service: &'static str,
+}
+
+impl Upnp {
+	pub fn new() -> Upnp {
+		Upnp {
+			control: Mutex::new(None),
+		}
+	}
+
+	fn control(&self) -> io::Result<Control> {
+		let mut control = self.control.lock();
+		if let Some(ref control) = *control {
+			return Ok(control.clone());
+		}
+		let found = discover()?;
+		*control = Some(found.clone());
+		Ok(found)
+	}
+
+	// Sends the provided action with its arguments. Returns the response body
+	// and our address as the gateway saw it.
+	fn action(&self, action: &str, args: &[(&str, String)]) -> io::Result<(String, IpAddr)> {
+		let control = self.control()?;
+		let args: String = args
+			.iter()
+			.map(|(name, value)| format!("<{}>{}</{}>", name, value, name))
+			.collect();
+		let body = format!(
+			"<?xml version=\"1.0\"?>\
+			 <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+			 s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+			 <s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>",
+			action, control.service, args, action
+		);
+		let headers = format!(
+			"Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n",
+			control.service, action
+		);
+		let (resp, local) = http("POST", control.addr, &control.path, &headers, &body)?;
+		Ok((resp, local.ip()))
+	}
+}
+
+impl Gateway for Upnp {
+	fn name(&self) -> &'static str {
+		"UPnP"
+	}
+
+	fn map(&self, port: u16, lease: Duration) -> io::Result<Duration> {
+		// our address on the gateway's side, where the port is forwarded to
+		let local = {
+			let control = self.control()?;
+			let conn = TcpStream::connect_timeout(&control.addr, GATEWAY_TIMEOUT)?;
+			conn.local_addr()?.ip()
+		};
+		self.action(
+			"AddPortMapping",
+			&[
+				("NewRemoteHost", "".to_string()),
+				("NewExternalPort", port.to_string()),
+				("NewProtocol", "TCP".to_string()),
+				("NewInternalPort", port.to_string()),
+				("NewInternalClient", local.to_string()),
+				("NewEnabled", "1".to_string()),
+				("NewPortMappingDescription", "grin".to_string()),
+				("NewLeaseDuration", lease.as_secs().to_string()),
+			],
+		)?;
+		Ok(lease)
+	}
+
+	fn unmap(&self, port: u16) -> io::Result<()> {
+		self.action(
+			"DeletePortMapping",
+			&[
+				("NewRemoteHost", "".to_string()),
+				("NewExternalPort", port.to_string()),
+				("NewProtocol", "TCP".to_string()),
+			],
+		)
+		.map(|_| ())
+	}
+
+	fn external_ip(&self) -> io::Result<IpAddr> {
+		let (resp, _) = self.action("GetExternalIPAddress", &[])?;
+		element(&resp, "NewExternalIPAddress")
+			.and_then(|ip| ip.trim().parse().ok())
+			.ok_or_else(|| other("no external address"))
+	}
+}
+
+// Finds an Internet gateway device through SSDP, then its port mapping
+// service in its description.
+fn discover() -> io::Result<Control> {
+	let socket = UdpSocket::bind("0.0.0.0:0")?;
+	socket.set_read_timeout(Some(GATEWAY_TIMEOUT))?;
+	let search = format!(
+		"M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+		SSDP_ADDR
+	);
+	socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+	let mut buf = [0; 2048];
+	let (n, _) = socket.recv_from(&mut buf)?;
+	let reply = String::from_utf8_lossy(&buf[..n]);
+	let location = reply
+		.lines()
+		.find_map(|line| {
+			let mut parts = line.splitn(2, ':');
+			match (parts.next(), parts.next()) {
+				(Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("location") => {
+					Some(value.trim().to_string())
+				}
+				_ => None,
+			}
+		})
+		.ok_or_else(|| other("no location in discovery reply"))?;
+	let (addr, path) = parse_url(&location).ok_or_else(|| other("bad location"))?;
+
+	let (desc, _) = http("GET", addr, &path, "", "")?;
+	for service in IGD_SERVICES.iter() {
+		if let Some(at) = desc.find(service) {
+			if let Some(control) = element(&desc[at..], "controlURL") {
+				let path = match parse_url(control.trim()) {
+					Some((_, path)) => path,
+					None if control.starts_with('/') => control.trim().to_string(),
+					None => format!("/{}", control.trim()),
+				};
+				return Ok(Control {
+					addr,
+					path,
+					service,
+				});
+			}
+		}
+	}
+	Err(other("no port mapping service"))
+}
+
+// The socket address and path of an http url.
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+	let url = url.trim();
+	if !url
+		.get(..7)
+		.map_or(false, |s| s.eq_ignore_ascii_case("http://"))
+	{
+		return None;
+	}
+	let rest = &url[7..];
+	let (host, path) = match rest.find('/') {
+		Some(i) => (&rest[..i], rest[i..].to_string()),
+		None => (rest, "/".to_string()),
+	};
+	let addr = if host.contains(':') {
+		host.parse().ok()?
+	} else {
+		SocketAddr::new(host.parse().ok()?, 80)
+	};
+	Some((addr, path))
+}
+
+// The text of the first element with the provided name.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+	let open = format!("<{}>", name);
+	let close = format!("</{}>", name);
+	let start = xml.find(&open)? + open.len();
+	let end = xml[start..].find(&close)? + start;
+	Some(&xml[start..end])
+}
+
+// A plain http/1.1 request, answered with a 200. Returns the response body
+// and our end of the connection.
+fn http(
+	method: &str,
+	addr: SocketAddr,
+	path: &str,
+	headers: &str,
+	body: &str,
+) -> io::Result<(String, SocketAddr)> {
+	let mut conn = TcpStream::connect_timeout(&addr, GATEWAY_TIMEOUT)?;
+	conn.set_read_timeout(Some(GATEWAY_TIMEOUT))?;
+	conn.set_write_timeout(Some(GATEWAY_TIMEOUT))?;
+	let local = conn.local_addr()?;
+	let req = format!(
+		"{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+		method,
+		path,
+		addr,
+		headers,
+		body.len(),
+		body
+	);
+	conn.write_all(req.as_bytes())?;
+	let mut resp = String::new();
+	conn.read_to_string(&mut resp)?;
+	let status = resp.lines().next().unwrap_or("");
+	if status.split_whitespace().nth(1) != Some("200") {
+		return Err(other(&format!("{} {}: {}", method, path, status)));
+	}
+	let body = match resp.find("\r\n\r\n") {
+		Some(i) => resp[i + 4..].to_string(),
+		None => String::new(),
+	};
+	Ok((body, local))
+}
+
+fn other(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, msg.to_string())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::types::P2PConfig;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	struct MockState {
+		up: AtomicBool,
+		ip: IpAddr,
+		granted: Duration,
+		calls: Mutex<Vec<&'static str>>,
+	}
+
+	struct MockGateway(Arc<MockState>);
+
+	impl Gateway for MockGateway {
+		fn name(&self) -> &'static str {
+			"mock"
+		}
+
+		fn map(&self, _port: u16, _lease: Duration) -> io::Result<Duration> {
+			self.0.calls.lock().push("map");
+			if self.0.up.load(Ordering::Relaxed) {
+				Ok(self.0.granted)
+			} else {
+				Err(other("down"))
+			}
+		}
+
+		fn unmap(&self, _port: u16) -> io::Result<()> {
+			self.0.calls.lock().push("unmap");
+			Ok(())
+		}
+
+		fn external_ip(&self) -> io::Result<IpAddr> {
+			Ok(self.0.ip)
+		}
+	}
+
+	fn gateway(up: bool, ip: &str) -> Arc<MockState> {
+		Arc::new(MockState {
+			up: AtomicBool::new(up),
+			ip: ip.parse().unwrap(),
+			granted: Duration::from_secs(600),
+			calls: Mutex::new(vec![]),
+		})
+	}
+
+	fn mapper(gateways: &[Arc<MockState>]) -> (PortMapper, Arc<SelfAddrs>) {
+		let addrs = Arc::new(SelfAddrs::new());
+		let gateways = gateways
+			.iter()
+			.map(|g| Box::new(MockGateway(g.clone())) as Box<dyn Gateway>)
+			.collect();
+		(PortMapper::new(gateways, 3414, addrs.clone()), addrs)
+	}
+
+	fn addr(s: &str) -> PeerAddr {
+		PeerAddr::Ip(s.parse().unwrap())
+	}
+
+	#[test]
+	fn maps_and_renews() {
+		let g = gateway(true, "1.2.3.4");
+		let (mapper, addrs) = mapper(&[g.clone()]);
+		let config = P2PConfig::default();
+		assert_eq!(addrs.advertise_addr(&config), addr("0.0.0.0:3414"));
+
+		let start = Instant::now();
+		mapper.refresh(start);
+		assert_eq!(mapper.external_addr(), Some(addr("1.2.3.4:3414")));
+		assert_eq!(addrs.advertise_addr(&config), addr("1.2.3.4:3414"));
+		assert!(addrs.contains(&addr("1.2.3.4:3414")));
+
+		// renewed once half the lease granted is over, not before
+		mapper.refresh(start + Duration::from_secs(299));
+		assert_eq!(g.calls.lock().len(), 1);
+		mapper.refresh(start + Duration::from_secs(300));
+		assert_eq!(g.calls.lock().len(), 2);
+		mapper.refresh(start + Duration::from_secs(599));
+		assert_eq!(g.calls.lock().len(), 2);
+
+		// a configured address still wins
+		let config = P2PConfig {
+			advertise_addr: Some(addr("5.6.7.8:3414")),
+			..P2PConfig::default()
+		};
+		assert_eq!(addrs.advertise_addr(&config), addr("5.6.7.8:3414"));
+	}
+
+	#[test]
+	fn falls_back_then_lost() {
+		let first = gateway(false, "1.2.3.4");
+		let second = gateway(true, "5.6.7.8");
+		let (mapper, addrs) = mapper(&[first.clone(), second.clone()]);
+		let config = P2PConfig::default();
+
+		let start = Instant::now();
+		mapper.refresh(start);
+		assert_eq!(mapper.external_addr(), Some(addr("5.6.7.8:3414")));
+
+		// renewing with the one we have first, the other if it fails
+		first.up.store(true, Ordering::Relaxed);
+		second.up.store(false, Ordering::Relaxed);
+		let renew = start + Duration::from_secs(300);
+		mapper.refresh(renew);
+		assert_eq!(*second.calls.lock(), vec!["map", "map"]);
+		assert_eq!(mapper.external_addr(), Some(addr("1.2.3.4:3414")));
+		assert_eq!(addrs.advertise_addr(&config), addr("1.2.3.4:3414"));
+
+		// all down, back to our listen address until we map it again
+		first.up.store(false, Ordering::Relaxed);
+		let lost = renew + Duration::from_secs(300);
+		mapper.refresh(lost);
+		assert_eq!(mapper.external_addr(), None);
+		assert_eq!(addrs.advertise_addr(&config), addr("0.0.0.0:3414"));
+
+		second.up.store(true, Ordering::Relaxed);
+		mapper.refresh(lost + REMAP_INTERVAL - Duration::from_secs(1));
+		assert_eq!(mapper.external_addr(), None);
+		mapper.refresh(lost + REMAP_INTERVAL);
+		assert_eq!(mapper.external_addr(), Some(addr("5.6.7.8:3414")));
+	}
+
+	#[test]
+	fn unavailable_not_retried() {
+		let g = gateway(false, "1.2.3.4");
+		let (mapper, addrs) = mapper(&[g.clone()]);
+
+		let start = Instant::now();
+		mapper.refresh(start);
+		assert_eq!(mapper.external_addr(), None);
+		assert!(addrs.is_empty());
+
+		g.up.store(true, Ordering::Relaxed);
+		mapper.refresh(start + Duration::from_secs(24 * 3600));
+		assert_eq!(mapper.external_addr(), None);
+		assert_eq!(g.calls.lock().len(), 1);
+	}
+
+	#[test]
+	fn unroutable_not_advertised() {
+		let private = gateway(true, "192.168.0.2");
+		let public = gateway(true, "5.6.7.8");
+		let (alone, alone_addrs) = mapper(&[private.clone()]);
+		alone.refresh(Instant::now());
+		assert_eq!(*private.calls.lock(), vec!["map", "unmap"]);
+		assert_eq!(alone.external_addr(), None);
+		assert!(alone_addrs.is_empty());
+
+		// the next gateway is tried instead
+		let (both, both_addrs) = mapper(&[private.clone(), public.clone()]);
+		both.refresh(Instant::now());
+		assert_eq!(both.external_addr(), Some(addr("5.6.7.8:3414")));
+		assert!(both_addrs.contains(&addr("5.6.7.8:3414")));
+		assert!(!both_addrs.contains(&addr("192.168.0.2:3414")));
+	}
+
+	#[test]
+	fn removed_on_stop() {
+		let g = gateway(true, "1.2.3.4");
+		let (mapper, addrs) = mapper(&[g.clone()]);
+		let config = P2PConfig::default();
+
+		let start = Instant::now();
+		mapper.refresh(start);
+		mapper.remove();
+		assert_eq!(*g.calls.lock(), vec!["map", "unmap"]);
+		assert_eq!(mapper.external_addr(), None);
+		assert_eq!(addrs.advertise_addr(&config), addr("0.0.0.0:3414"));
+
+		// and not mapped again
+		mapper.refresh(start + Duration::from_secs(3600));
+		assert_eq!(g.calls.lock().len(), 2);
+	}
+
+	#[test]
+	fn igd_description() {
+		assert_eq!(
+			parse_url("http://192.168.1.1:5000/rootDesc.xml"),
+			Some((
+				"192.168.1.1:5000".parse().unwrap(),
+				"/rootDesc.xml".to_string()
+			))
+		);
+		assert_eq!(
+			parse_url("HTTP://192.168.1.1"),
+			Some(("192.168.1.1:80".parse().unwrap(), "/".to_string()))
+		);
+		assert_eq!(parse_url("https://192.168.1.1/"), None);
+		assert_eq!(parse_url("http://"), None);
+		// whatever answered on the network, not cut in the middle of a char
+		assert_eq!(parse_url("http:/\u{e9}192.168.1.1/"), None);
+		assert_eq!(parse_url("http:/\u{fffd}"), None);
+
+		let desc =
+			"<service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+		            <controlURL>/ctl/IPConn</controlURL></service>";
+		assert_eq!(element(desc, "controlURL"), Some("/ctl/IPConn"));
+		assert_eq!(element(desc, "eventSubURL"), None);
+	}
+}
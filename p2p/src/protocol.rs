@@ -13,34 +13,399 @@
 // limitations under the License.
 
 use crate::conn::{Message, MessageHandler, Response, Tracker};
-use crate::core::core::{self, hash::Hash, CompactBlock};
+use crate::core::core::hash::{Hash, Hashed};
+use crate::core::core::{self, BlockHeader, CompactBlock};
+use crate::core::{consensus, ser};
 
 use crate::msg::{
-	BanReason, GetPeerAddrs, Headers, KernelDataResponse, Locator, PeerAddrs, Ping, Pong,
-	TxHashSetArchive, TxHashSetRequest, Type,
+	over_consensus_size, BanReason, BlockInv, Disconnect, GetPeerAddrs, Headers,
+	KernelDataResponse, Locator, PeerAddrs, PeerError, Ping, Pong, ProtocolVersion,
+	TxHashSetArchive, TxHashSetRequest, Type, MAX_TXHASHSET_ARCHIVE_SIZE, MAX_TX_REQUESTS_PER_SEC,
+	TXHASHSET_ARCHIVE_TIMEOUT,
 };
-use crate::types::{Error, NetAdapter, PeerInfo};
+use crate::types::{
+	notify, Error, NetAdapter, Offense, PeerInfo, ProtocolObserver, RateLimit, MAX_BLOCK_HEADERS,
+};
+use crate::util::{Mutex, RateCounter};
 use chrono::prelude::Utc;
 use rand::{thread_rng, Rng};
 use std::cmp;
 use std::fs::{self, File, OpenOptions};
 use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempfile::tempfile;
 
-pub struct Protocol {
-	adapter: Arc<dyn NetAdapter>,
-	peer_info: PeerInfo,
+/// Message handler for each protocol version we can speak, picked from the
+/// version negotiated during the handshake.
+pub enum Protocol {
+	V1(ProtocolV1),
 }
 
 impl Protocol {
-	pub fn new(adapter: Arc<dyn NetAdapter>, peer_info: PeerInfo) -> Protocol {
-		Protocol { adapter, peer_info }
+	/// Builds the handler for the negotiated protocol version. Fails rather
+	/// than falling back to another version we would then speak wrongly.
+	pub fn for_version(
+		version: ProtocolVersion,
+		adapter: Arc<dyn NetAdapter>,
+		peer_info: PeerInfo,
+		requests: Arc<RequestTracker>,
+		limits: Vec<RateLimit>,
+		observer: Option<Arc<dyn ProtocolObserver>>,
+	) -> Result<Protocol, Error> {
+		match version.0 {
+			// versions 2 and 3 only change msg headers and the Shake, the
+			// messages are the same
+			1 | 2 | 3 => Ok(Protocol::V1(ProtocolV1::new(
+				adapter, peer_info, requests, limits, observer,
+			))),
+			_ => Err(Error::UnsupportedProtocol(version)),
+		}
 	}
 }
 
 impl MessageHandler for Protocol {
 	fn consume<'a>(
+		&self,
+		msg: Message<'a>,
+		writer: &'a mut dyn Write,
+		tracker: Arc<Tracker>,
+	) -> Result<Option<Response<'a>>, Error> {
+		match self {
+			Protocol::V1(protocol) => protocol.consume(msg, writer, tracker),
+		}
+	}
+
+	fn keepalive<'a>(&self, writer: &'a mut dyn Write) -> Result<Response<'a>, Error> {
+		match self {
+			Protocol::V1(protocol) => protocol.keepalive(writer),
+		}
+	}
+
+	fn gossip<'a>(&self, writer: &'a mut dyn Write) -> Result<Option<Response<'a>>, Error> {
+		match self {
+			Protocol::V1(protocol) => protocol.gossip(writer),
+		}
+	}
+
+	fn sent(&self, msg_type: Type, len: u64) {
+		match self {
+			Protocol::V1(protocol) => protocol.sent(msg_type, len),
+		}
+	}
+}
+
+/// Max number of requests we keep track of per peer, the oldest ones are
+/// forgotten past that.
+const MAX_PENDING_REQUESTS: usize = 512;
+
+/// Max number of msgs over their rate limit a peer can send us in a minute
+/// (they're dropped) before we disconnect it.
+pub const MAX_DROPPED_MSGS_PER_MIN: u64 = 20;
+
+/// How far past both our tip and the height the peer told us it's at a block
+/// can be before we don't bother reading it.
+pub const MAX_BLOCK_HEIGHT_AHEAD: u64 = 10;
+
+/// How long a peer waits between two peer address lists it sends us without
+/// us asking, the ones coming sooner are dropped.
+const MIN_UNSOLICITED_ADDRS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a request we sent asks for, to match responses against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Requested {
+	/// A block, full or compact, by hash
+	Block(Hash),
+	/// The block at this height on the peer's main chain
+	BlockAt(u64),
+	/// Headers following the locator
+	Headers(Vec<Hash>),
+}
+
+/// A request sent to a peer and not answered yet.
+#[derive(Debug, Clone)]
+pub struct PendingRequest {
+	pub msg_type: Type,
+	pub requested: Requested,
+	pub sent: Instant,
+}
+
+/// Outstanding requests to a peer, filled as we send them and emptied as
+/// the responses come. Requests left unanswered for too long are handed
+/// over with `expired` to be asked to another peer, each one is a strike
+/// against the silent peer.
+///
+/// Blocks nobody asked for are fine (that's how they propagate), as are
+/// duplicate or late responses, they just don't answer anything.
+pub struct RequestTracker {
+	pending: Mutex<Vec<PendingRequest>>,
+	strikes: AtomicUsize,
+	// asked for peer addresses, not answered yet
+	addrs_asked: AtomicBool,
+}
+
+impl RequestTracker {
+	pub fn new() -> RequestTracker {
+		RequestTracker {
+			pending: Mutex::new(vec![]),
+			strikes: AtomicUsize::new(0),
+			addrs_asked: AtomicBool::new(false),
+		}
+	}
+
+	/// Records we're asking for peer addresses. Kept apart from the other
+	/// requests, nobody else can answer it and there's no harm if the peer
+	/// doesn't.
+	pub fn peer_addrs_asked(&self) {
+		self.addrs_asked.store(true, Ordering::Relaxed);
+	}
+
+	/// A list of peer addresses came in, returns whether we asked for it.
+	pub fn peer_addrs_received(&self) -> bool {
+		self.addrs_asked.swap(false, Ordering::Relaxed)
+	}
+
+	/// Records a request we're sending.
+	pub fn sent(&self, msg_type: Type, requested: Requested) {
+		let mut pending = self.pending.lock();
+		if pending.len() >= MAX_PENDING_REQUESTS {
+			pending.remove(0);
+		}
+		pending.push(PendingRequest {
+			msg_type,
+			requested,
+			sent: Instant::now(),
+		});
+	}
+
+	/// A block came in, returns whether it answered one of our requests,
+	/// by hash or height.
+	pub fn block_received(&self, hash: Hash, height: u64) -> bool {
+		self.answered(|r| *r == Requested::Block(hash) || *r == Requested::BlockAt(height))
+	}
+
+	/// A batch of headers came in, answering our oldest headers request.
+	pub fn headers_received(&self) -> bool {
+		self.answered(|r| match r {
+			Requested::Headers(_) => true,
+			_ => false,
+		})
+	}
+
+	fn answered<F>(&self, f: F) -> bool
+	where
+		F: Fn(&Requested) -> bool,
+	{
+		let mut pending = self.pending.lock();
+		match pending.iter().position(|p| f(&p.requested)) {
+			Some(pos) => {
+				pending.remove(pos);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Takes the requests sent more than `ttl` ago and still unanswered, a
+	/// strike for each. A response coming after that answers nothing.
+	pub fn expired(&self, ttl: Duration) -> Vec<PendingRequest> {
+		let mut pending = self.pending.lock();
+		let (expired, live) = pending
+			.drain(..)
+			.partition::<Vec<_>, _>(|p| p.sent.elapsed() >= ttl);
+		*pending = live;
+		self.strikes.fetch_add(expired.len(), Ordering::Relaxed);
+		expired
+	}
+
+	/// Number of requests not answered yet.
+	pub fn pending(&self) -> usize {
+		self.pending.lock().len()
+	}
+
+	/// A strike against the peer for misbehaving in some other way.
+	pub fn strike(&self) {
+		self.strikes.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// How many requests the peer left unanswered (or other strikes) so far.
+	pub fn strikes(&self) -> usize {
+		self.strikes.load(Ordering::Relaxed)
+	}
+}
+
+// The tokens left for the msgs of a type, refilled over time up to the limit.
+struct TokenBucket {
+	limit: RateLimit,
+	tokens: f64,
+	refilled: Instant,
+}
+
+impl TokenBucket {
+	fn new(limit: RateLimit) -> TokenBucket {
+		TokenBucket {
+			limit,
+			tokens: limit.count as f64,
+			refilled: Instant::now(),
+		}
+	}
+
+	// Takes a token, false if there's none left.
+	fn take(&mut self) -> bool {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.refilled).as_millis() as f64;
+		let period = cmp::max(self.limit.period.as_millis(), 1) as f64;
+		let count = self.limit.count as f64;
+		self.tokens = (self.tokens + elapsed / period * count).min(count);
+		self.refilled = now;
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+pub struct ProtocolV1 {
+	adapter: Arc<dyn NetAdapter>,
+	peer_info: PeerInfo,
+	// start of the current second and the txs asked for during it
+	tx_requests: Mutex<(Instant, u32)>,
+	requests: Arc<RequestTracker>,
+	// by msg type, the types without one aren't limited
+	buckets: Mutex<Vec<TokenBucket>>,
+	// msgs dropped for going over their limit
+	dropped: Mutex<RateCounter>,
+	// when we last took peer addresses the peer sent us unasked
+	unsolicited_addrs: Mutex<Option<Instant>>,
+	observer: Option<Arc<dyn ProtocolObserver>>,
+}
+
+impl ProtocolV1 {
+	pub fn new(
+		adapter: Arc<dyn NetAdapter>,
+		peer_info: PeerInfo,
+		requests: Arc<RequestTracker>,
+		limits: Vec<RateLimit>,
+		observer: Option<Arc<dyn ProtocolObserver>>,
+	) -> ProtocolV1 {
+		ProtocolV1 {
+			adapter,
+			peer_info,
+			tx_requests: Mutex::new((Instant::now(), 0)),
+			requests,
+			buckets: Mutex::new(limits.into_iter().map(TokenBucket::new).collect()),
+			dropped: Mutex::new(RateCounter::new()),
+			unsolicited_addrs: Mutex::new(None),
+			observer,
+		}
+	}
+
+	// Takes a token for a msg of the provided type, false when the peer went
+	// over the limit and the msg should be dropped. Fails (with a strike)
+	// once the peer keeps at it, more than MAX_DROPPED_MSGS_PER_MIN dropped.
+	fn within_rate_limit(&self, msg_type: Type) -> Result<bool, Error> {
+		let mut buckets = self.buckets.lock();
+		let bucket = match buckets.iter_mut().find(|b| b.limit.msg_type == msg_type) {
+			Some(bucket) => bucket,
+			None => return Ok(true),
+		};
+		if bucket.take() {
+			return Ok(true);
+		}
+		let mut dropped = self.dropped.lock();
+		dropped.inc(1);
+		if dropped.count_per_min() > MAX_DROPPED_MSGS_PER_MIN {
+			self.requests.strike();
+			self.adapter
+				.report_misbehavior(self.peer_info.addr.clone(), Offense::RateLimited);
+			return Err(Error::RateLimited);
+		}
+		Ok(false)
+	}
+
+	// Cheap checks on a block or tx before deserializing it whole: its length
+	// against what consensus allows and, for a block, its header against our
+	// tip. Failing one is a strike and the rest of the body is skipped.
+	fn worth_reading(&self, msg: &mut Message) -> Result<bool, Error> {
+		let msg_type = msg.header.msg_type;
+		if over_consensus_size(msg_type, msg.header.msg_len) {
+			debug!(
+				"handle_payload: {:?} from {} too large, msg_len: {}",
+				msg_type, self.peer_info.addr, msg.header.msg_len
+			);
+			self.requests.strike();
+			self.adapter
+				.report_misbehavior(self.peer_info.addr.clone(), Offense::UnsolicitedPayload);
+			msg.discard()?;
+			return Ok(false);
+		}
+		if msg_type == Type::Block && !msg.header.compressed {
+			let header: BlockHeader = msg.peek()?;
+			if let Err((why, offense)) = self.plausible_header(&header) {
+				debug!(
+					"handle_payload: block {} at {} from {} implausible, {}",
+					header.hash(),
+					header.height,
+					self.peer_info.addr,
+					why
+				);
+				self.requests.strike();
+				self.adapter
+					.report_misbehavior(self.peer_info.addr.clone(), offense);
+				msg.discard()?;
+				return Ok(false);
+			}
+		}
+		Ok(true)
+	}
+
+	// Whether we take the peer addresses the peer sent us unasked, at most
+	// once every MIN_UNSOLICITED_ADDRS_INTERVAL.
+	fn take_unsolicited_addrs(&self) -> bool {
+		let mut last = self.unsolicited_addrs.lock();
+		if last.map_or(false, |t| t.elapsed() < MIN_UNSOLICITED_ADDRS_INTERVAL) {
+			return false;
+		}
+		*last = Some(Instant::now());
+		true
+	}
+
+	fn plausible_header(&self, header: &BlockHeader) -> Result<(), (&'static str, Offense)> {
+		if header.height == 0 {
+			return Err(("genesis", Offense::FailedValidation));
+		}
+		// we may be the ones behind, or the peer may have told us a while ago
+		let tip = self.adapter.total_height().unwrap_or(0);
+		let known = cmp::max(tip, self.peer_info.height());
+		if header.height > known.saturating_add(MAX_BLOCK_HEIGHT_AHEAD) {
+			return Err(("too far ahead", Offense::FailedValidation));
+		}
+		// same limit as the chain puts on the future, our clock may be off
+		let future = chrono::Duration::seconds(12 * consensus::BLOCK_TIME_SEC as i64);
+		if header.timestamp > Utc::now() + future {
+			return Err(("too far in the future", Offense::BorderlineBlock));
+		}
+		Ok(())
+	}
+
+	// Counts a tx request, false once the peer asked for more than
+	// MAX_TX_REQUESTS_PER_SEC this second.
+	fn allow_tx_request(&self) -> bool {
+		let mut tx_requests = self.tx_requests.lock();
+		let now = Instant::now();
+		if now.duration_since(tx_requests.0) >= Duration::from_secs(1) {
+			*tx_requests = (now, 0);
+		}
+		tx_requests.1 += 1;
+		tx_requests.1 <= MAX_TX_REQUESTS_PER_SEC
+	}
+}
+
+impl ProtocolV1 {
+	fn handle<'a>(
 		&self,
 		mut msg: Message<'a>,
 		writer: &'a mut dyn Write,
@@ -48,10 +413,18 @@ impl MessageHandler for Protocol {
 	) -> Result<Option<Response<'a>>, Error> {
 		let adapter = &self.adapter;
 
+		// an inbound handshake only completes with the first message following
+		// our shake
+		if let Some(shake_sent) = self.peer_info.shake_sent {
+			if self.peer_info.handshake_rtt().is_none() {
+				self.peer_info.set_handshake_rtt(shake_sent.elapsed());
+			}
+		}
+
 		// If we received a msg from a banned peer then log and drop it.
 		// If we are getting a lot of these then maybe we are not cleaning
 		// banned peers up correctly?
-		if adapter.is_banned(self.peer_info.addr) {
+		if adapter.is_banned(self.peer_info.addr.clone()) {
 			debug!(
 				"handler: consume: peer {:?} banned, received: {:?}, dropping.",
 				self.peer_info.addr, msg.header.msg_type,
@@ -59,16 +432,30 @@ impl MessageHandler for Protocol {
 			return Ok(None);
 		}
 
+		if !self.within_rate_limit(msg.header.msg_type)? {
+			debug!(
+				"handler: consume: {:?} from {:?} over its rate limit, dropping.",
+				msg.header.msg_type, self.peer_info.addr,
+			);
+			msg.discard()?;
+			return Ok(None);
+		}
+
 		match msg.header.msg_type {
 			Type::Ping => {
 				let ping: Ping = msg.body()?;
-				adapter.peer_difficulty(self.peer_info.addr, ping.total_difficulty, ping.height);
+				adapter.peer_difficulty(
+					self.peer_info.addr.clone(),
+					ping.total_difficulty,
+					ping.height,
+				);
 
 				Ok(Some(Response::new(
 					Type::Pong,
 					Pong {
 						total_difficulty: adapter.total_difficulty()?,
 						height: adapter.total_height()?,
+						nonce: ping.nonce,
 					},
 					writer,
 				)?))
@@ -76,13 +463,62 @@ impl MessageHandler for Protocol {
 
 			Type::Pong => {
 				let pong: Pong = msg.body()?;
-				adapter.peer_difficulty(self.peer_info.addr, pong.total_difficulty, pong.height);
+				if !self.peer_info.pong_received(pong.nonce) {
+					debug!(
+						"handle_payload: pong from {} to no ping of ours",
+						self.peer_info.addr
+					);
+					self.requests.strike();
+					self.adapter
+						.report_misbehavior(self.peer_info.addr.clone(), Offense::UnsolicitedPong);
+					return Ok(None);
+				}
+				adapter.peer_difficulty(
+					self.peer_info.addr.clone(),
+					pong.total_difficulty,
+					pong.height,
+				);
 				Ok(None)
 			}
 
 			Type::BanReason => {
 				let ban_reason: BanReason = msg.body()?;
-				error!("handle_payload: BanReason {:?}", ban_reason);
+				warn!(
+					"handle_payload: {} is disconnecting us, reason {:?}, {}",
+					self.peer_info.addr,
+					ban_reason.ban_reason,
+					ban_reason
+						.message
+						.as_ref()
+						.map(|m| m.as_str())
+						.unwrap_or("")
+				);
+				adapter.ban_reason_received(self.peer_info.addr.clone(), ban_reason.ban_reason);
+				Ok(None)
+			}
+
+			Type::PeerError => {
+				let err: PeerError = msg.body()?;
+				warn!(
+					"handle_payload: {} is disconnecting us, error {} ({:?}), {}",
+					self.peer_info.addr,
+					err.code,
+					err.code(),
+					err.message
+				);
+				adapter.peer_error_received(self.peer_info.addr.clone(), err);
+				Ok(None)
+			}
+
+			Type::Disconnect => {
+				let disconnect: Disconnect = msg.body()?;
+				debug!(
+					"handle_payload: {} is closing the connection, reason {} ({:?})",
+					self.peer_info.addr,
+					disconnect.reason,
+					disconnect.reason()
+				);
+				adapter.disconnect_received(self.peer_info.addr.clone(), disconnect.reason());
 				Ok(None)
 			}
 
@@ -102,21 +538,36 @@ impl MessageHandler for Protocol {
 					"handle_payload: GetTransaction: {}, msg_len: {}",
 					h, msg.header.msg_len,
 				);
+				if !self.allow_tx_request() {
+					return Err(Error::RateLimited);
+				}
 				let tx = adapter.get_transaction(h);
 				if let Some(tx) = tx {
 					Ok(Some(Response::new(Type::Transaction, tx, writer)?))
 				} else {
-					Ok(None)
+					Ok(Some(Response::new(Type::TransactionNotFound, h, writer)?))
 				}
 			}
 
+			Type::TransactionNotFound => {
+				let h: Hash = msg.body()?;
+				debug!(
+					"handle_payload: tx {} not found by {}",
+					h, self.peer_info.addr
+				);
+				Ok(None)
+			}
+
 			Type::Transaction => {
 				debug!(
 					"handle_payload: received tx: msg_len: {}",
 					msg.header.msg_len
 				);
+				if !self.worth_reading(&mut msg)? {
+					return Ok(None);
+				}
 				let tx: core::Transaction = msg.body()?;
-				adapter.transaction_received(tx, false)?;
+				adapter.transaction_received(tx, false, &self.peer_info)?;
 				Ok(None)
 			}
 
@@ -125,8 +576,11 @@ impl MessageHandler for Protocol {
 					"handle_payload: received stem tx: msg_len: {}",
 					msg.header.msg_len
 				);
+				if !self.worth_reading(&mut msg)? {
+					return Ok(None);
+				}
 				let tx: core::Transaction = msg.body()?;
-				adapter.transaction_received(tx, true)?;
+				adapter.transaction_received(tx, true, &self.peer_info)?;
 				Ok(None)
 			}
 
@@ -140,7 +594,18 @@ impl MessageHandler for Protocol {
 
 				let bo = adapter.get_block(h);
 				if let Some(b) = bo {
-					return Ok(Some(Response::new(Type::Block, b, writer)?));
+					return Ok(Some(Response::block(b, writer)));
+				}
+				Ok(None)
+			}
+
+			Type::GetBlockByHeight => {
+				let height: u64 = msg.body()?;
+				trace!("handle_payload: GetBlockByHeight: {}", height);
+
+				// only ever answered from our current main chain
+				if let Some(b) = adapter.get_block_by_height(height) {
+					return Ok(Some(Response::block(b, writer)));
 				}
 				Ok(None)
 			}
@@ -150,10 +615,14 @@ impl MessageHandler for Protocol {
 					"handle_payload: received block: msg_len: {}",
 					msg.header.msg_len
 				);
+				if !self.worth_reading(&mut msg)? {
+					return Ok(None);
+				}
 				let b: core::Block = msg.body()?;
 
 				// we can't know at this level whether we requested the block or not,
 				// the boolean should be properly set in higher level adapter
+				self.requests.block_received(b.hash(), b.header.height);
 				adapter.block_received(b, &self.peer_info, false)?;
 				Ok(None)
 			}
@@ -175,6 +644,7 @@ impl MessageHandler for Protocol {
 				);
 				let b: core::CompactBlock = msg.body()?;
 
+				self.requests.block_received(b.hash(), b.header.height);
 				adapter.compact_block_received(b, &self.peer_info)?;
 				Ok(None)
 			}
@@ -182,7 +652,10 @@ impl MessageHandler for Protocol {
 			Type::GetHeaders => {
 				// load headers from the locator
 				let loc: Locator = msg.body()?;
-				let headers = adapter.locate_headers(&loc.hashes)?;
+				let mut headers = adapter.locate_headers(&loc.hashes)?;
+				// an empty batch if we don't know any of the locator, never more
+				// than a full one
+				headers.truncate(MAX_BLOCK_HEADERS as usize);
 
 				// serialize and send all the headers over
 				Ok(Some(Response::new(
@@ -192,6 +665,12 @@ impl MessageHandler for Protocol {
 				)?))
 			}
 
+			Type::BlockInv => {
+				let inv: BlockInv = msg.body()?;
+				adapter.block_inv_received(inv.hash, inv.height, &self.peer_info)?;
+				Ok(None)
+			}
+
 			// "header first" block propagation - if we have not yet seen this block
 			// we can go request it from some of our peers
 			Type::Header => {
@@ -200,36 +679,50 @@ impl MessageHandler for Protocol {
 				Ok(None)
 			}
 
+			// compressed headers can't be streamed, inflated in one go and
+			// bounded by the max size of the msg
+			Type::Headers if msg.header.compressed => {
+				let headers: Headers = msg.body()?;
+				self.requests.headers_received();
+				adapter.headers_received(&headers.headers, &self.peer_info)?;
+				Ok(None)
+			}
+
 			Type::Headers => {
 				let mut total_bytes_read = 0;
+				self.requests.headers_received();
 
 				// Read the count (u16) so we now how many headers to read.
 				let (count, bytes_read): (u16, _) = msg.streaming_read()?;
 				total_bytes_read += bytes_read;
+				if count as u32 > MAX_BLOCK_HEADERS {
+					return Err(Error::Serialization(ser::Error::TooLargeReadErr));
+				}
 
-				// Read chunks of headers off the stream and pass them off to the adapter.
-				let chunk_size = 32;
-				for chunk in (0..count).collect::<Vec<_>>().chunks(chunk_size) {
-					let mut headers = vec![];
-					for _ in chunk {
-						let (header, bytes_read) = msg.streaming_read()?;
-						headers.push(header);
-						total_bytes_read += bytes_read;
-					}
-					adapter.headers_received(&headers, &self.peer_info)?;
+				// Read the headers off the stream, the whole batch is handed over
+				// at once so a full one can have the next asked for right away,
+				// before it's validated.
+				let mut headers = Vec::with_capacity(count as usize);
+				for _ in 0..count {
+					let (header, bytes_read) = msg.streaming_read()?;
+					headers.push(header);
+					total_bytes_read += bytes_read;
 				}
 
 				// Now check we read the correct total number of bytes off the stream.
 				if total_bytes_read != msg.header.msg_len {
 					return Err(Error::MsgLen);
 				}
+				msg.verify_checksum()?;
 
+				adapter.headers_received(&headers, &self.peer_info)?;
 				Ok(None)
 			}
 
 			Type::GetPeerAddrs => {
 				let get_peers: GetPeerAddrs = msg.body()?;
-				let peers = adapter.find_peer_addrs(get_peers.capabilities);
+				let peers =
+					adapter.find_peer_addrs(self.peer_info.addr.clone(), get_peers.capabilities);
 				Ok(Some(Response::new(
 					Type::PeerAddrs,
 					PeerAddrs { peers },
@@ -238,8 +731,16 @@ impl MessageHandler for Protocol {
 			}
 
 			Type::PeerAddrs => {
+				if !self.requests.peer_addrs_received() && !self.take_unsolicited_addrs() {
+					debug!(
+						"handle_payload: unasked peer addrs from {} too soon, dropped",
+						self.peer_info.addr
+					);
+					msg.discard()?;
+					return Ok(None);
+				}
 				let peer_addrs: PeerAddrs = msg.body()?;
-				adapter.peer_addrs_received(peer_addrs.peers);
+				adapter.peer_addrs_received(self.peer_info.addr.clone(), peer_addrs.peers);
 				Ok(None)
 			}
 
@@ -324,6 +825,13 @@ impl MessageHandler for Protocol {
 					"handle_payload: txhashset archive for {} at {}. size={}",
 					sm_arch.hash, sm_arch.height, sm_arch.bytes,
 				);
+				if sm_arch.bytes > MAX_TXHASHSET_ARCHIVE_SIZE {
+					error!(
+						"handle_payload: txhashset archive of {} bytes, over {}",
+						sm_arch.bytes, MAX_TXHASHSET_ARCHIVE_SIZE,
+					);
+					return Err(Error::MsgLen);
+				}
 				if !self.adapter.txhashset_receive_ready() {
 					error!(
 						"handle_payload: txhashset archive received but SyncStatus not on TxHashsetDownload",
@@ -332,6 +840,7 @@ impl MessageHandler for Protocol {
 				}
 
 				let download_start_time = Utc::now();
+				let deadline = Instant::now() + TXHASHSET_ARCHIVE_TIMEOUT;
 				self.adapter
 					.txhashset_download_update(download_start_time, 0, sm_arch.bytes);
 
@@ -348,6 +857,9 @@ impl MessageHandler for Protocol {
 					let mut downloaded_size: usize = 0;
 					let mut request_size = cmp::min(48_000, total_size);
 					while request_size > 0 {
+						if Instant::now() > deadline {
+							return Err(Error::Timeout);
+						}
 						let size = msg.copy_attachment(request_size, &mut tmp_zip)?;
 						downloaded_size += size;
 						request_size = cmp::min(48_000, total_size - downloaded_size);
@@ -404,3 +916,142 @@ impl MessageHandler for Protocol {
 		}
 	}
 }
+
+impl MessageHandler for ProtocolV1 {
+	fn consume<'a>(
+		&self,
+		msg: Message<'a>,
+		writer: &'a mut dyn Write,
+		tracker: Arc<Tracker>,
+	) -> Result<Option<Response<'a>>, Error> {
+		let msg_type = msg.header.msg_type;
+		let len = self.peer_info.version.header_len() as u64 + msg.header.msg_len;
+		notify(&self.observer, |o| {
+			o.on_receive(msg_type, len, &self.peer_info.addr)
+		});
+		let res = self.handle(msg, writer, tracker);
+		if let Err(ref e) = res {
+			notify(&self.observer, |o| o.on_error(&self.peer_info.addr, e));
+			match e {
+				Error::Serialization(_) | Error::BadMessage => self
+					.adapter
+					.report_misbehavior(self.peer_info.addr.clone(), Offense::MalformedMessage),
+				_ => {}
+			}
+		}
+		res
+	}
+
+	fn sent(&self, msg_type: Type, len: u64) {
+		notify(&self.observer, |o| {
+			o.on_send(msg_type, len, &self.peer_info.addr)
+		});
+	}
+
+	// A ping, counted as unanswered until the pong like the ones we send
+	// periodically.
+	fn keepalive<'a>(&self, writer: &'a mut dyn Write) -> Result<Response<'a>, Error> {
+		let ping = Ping {
+			total_difficulty: self.adapter.total_difficulty()?,
+			height: self.adapter.total_height()?,
+			nonce: Some(self.peer_info.ping_sent()),
+		};
+		Response::new(Type::Ping, ping, writer)
+	}
+
+	// A few addresses of the peers we recently connected to, if we have any.
+	fn gossip<'a>(&self, writer: &'a mut dyn Write) -> Result<Option<Response<'a>>, Error> {
+		let peers = self.adapter.gossip_addrs(self.peer_info.addr.clone());
+		if peers.is_empty() {
+			return Ok(None);
+		}
+		trace!(
+			"Gossiping {} peer addrs to {}",
+			peers.len(),
+			self.peer_info.addr
+		);
+		Ok(Some(Response::new(
+			Type::PeerAddrs,
+			PeerAddrs { peers },
+			writer,
+		)?))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::thread;
+
+	fn hash(i: u8) -> Hash {
+		Hash::from_vec(&vec![i])
+	}
+
+	#[test]
+	fn unsolicited_block() {
+		let requests = RequestTracker::new();
+		requests.sent(Type::GetBlock, Requested::Block(hash(1)));
+		assert!(!requests.block_received(hash(2), 5));
+		assert!(requests.expired(Duration::from_secs(10)).is_empty());
+		assert_eq!(requests.strikes(), 0);
+		assert!(requests.block_received(hash(1), 4));
+	}
+
+	#[test]
+	fn duplicate_response() {
+		let requests = RequestTracker::new();
+		requests.sent(Type::GetBlockByHeight, Requested::BlockAt(3));
+		requests.sent(Type::GetHeaders, Requested::Headers(vec![hash(1)]));
+		assert!(requests.block_received(hash(1), 3));
+		assert!(!requests.block_received(hash(1), 3));
+		assert!(requests.headers_received());
+		assert!(!requests.headers_received());
+		assert!(requests.expired(Duration::from_millis(0)).is_empty());
+		assert_eq!(requests.strikes(), 0);
+	}
+
+	#[test]
+	fn response_after_retry() {
+		let requests = RequestTracker::new();
+		requests.sent(Type::GetBlock, Requested::Block(hash(1)));
+		thread::sleep(Duration::from_millis(20));
+		requests.sent(Type::GetBlock, Requested::Block(hash(2)));
+
+		let expired = requests.expired(Duration::from_millis(10));
+		assert_eq!(expired.len(), 1);
+		assert_eq!(expired[0].msg_type, Type::GetBlock);
+		assert_eq!(expired[0].requested, Requested::Block(hash(1)));
+		assert_eq!(requests.strikes(), 1);
+
+		// asked someone else since, answers nothing but isn't held against
+		// the peer either
+		assert!(!requests.block_received(hash(1), 1));
+		assert!(requests.block_received(hash(2), 2));
+		assert_eq!(requests.strikes(), 1);
+	}
+
+	#[test]
+	fn pending_requests_capped() {
+		let requests = RequestTracker::new();
+		for i in 0..=MAX_PENDING_REQUESTS {
+			requests.sent(Type::GetBlockByHeight, Requested::BlockAt(i as u64));
+		}
+		assert!(!requests.block_received(hash(1), 0));
+		assert!(requests.block_received(hash(1), MAX_PENDING_REQUESTS as u64));
+	}
+
+	#[test]
+	fn token_bucket_refill() {
+		let limit = RateLimit::new(Type::GetHeaders, 2, Duration::from_millis(100));
+		let mut bucket = TokenBucket::new(limit);
+		assert!(bucket.take());
+		assert!(bucket.take());
+		assert!(!bucket.take());
+
+		// refilled over the period, never past the limit
+		thread::sleep(Duration::from_millis(300));
+		assert!(bucket.take());
+		assert!(bucket.take());
+		assert!(!bucket.take());
+	}
+}
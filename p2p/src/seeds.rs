@@ -0,0 +1,171 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Getting our first peers from DNS seeds, names resolving to nodes known to
+//! be up, when our store doesn't have enough of them.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::Utc;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+use crate::peers::Peers;
+use crate::serv::Server;
+use crate::store::{PeerData, State};
+use crate::types::{Capabilities, PeerAddr, ReasonForBan};
+use crate::util::Mutex;
+
+/// Below this many healthy peers in our store we go to the DNS seeds.
+pub const DNS_SEED_MIN_PEERS: usize = 8;
+
+/// How long we wait before resolving our seeds again, while still short of
+/// peers.
+const RESEED_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Looks up the addresses behind a DNS seed.
+pub trait Resolver: Send + Sync {
+	/// All the addresses the name resolves to, with the provided port.
+	fn resolve(&self, name: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Goes through the system resolver, A and AAAA records alike.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+	fn resolve(&self, name: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+		Ok((name, port).to_socket_addrs()?.collect())
+	}
+}
+
+/// Resolves our DNS seeds and hands what they give us to the dialer, a few at
+/// a time, while our store is short of healthy peers.
+pub struct DnsSeeder {
+	names: Vec<String>,
+	port: u16,
+	resolver: Box<dyn Resolver>,
+	// resolved but not dialed yet
+	pending: Mutex<Vec<PeerAddr>>,
+	last_resolved: Mutex<Option<Instant>>,
+}
+
+impl DnsSeeder {
+	/// Seeder for the provided DNS names, the nodes behind them listening on
+	/// the provided port.
+	pub fn new(names: Vec<String>, port: u16, resolver: Box<dyn Resolver>) -> DnsSeeder {
+		DnsSeeder {
+			names,
+			port,
+			resolver,
+			pending: Mutex::new(vec![]),
+			last_resolved: Mutex::new(None),
+		}
+	}
+
+	/// Resolves all our seeds, in random order. A seed that fails to resolve
+	/// is skipped, unroutable and duplicate addresses are left out.
+	pub fn resolve(&self) -> Vec<PeerAddr> {
+		let mut seen = HashSet::new();
+		let mut addrs = vec![];
+		for name in &self.names {
+			debug!("Retrieving seed nodes from dns {}", name);
+			match self.resolver.resolve(name, self.port) {
+				Ok(resolved) => {
+					for addr in resolved.into_iter().map(PeerAddr::Ip) {
+						if !addr.is_routable() {
+							debug!("Seed {} gave unroutable address {}, skipping.", name, addr);
+						} else if seen.insert(addr.clone()) {
+							addrs.push(addr);
+						}
+					}
+				}
+				Err(e) => debug!("Failed to resolve seed {:?} got error {:?}", name, e),
+			}
+		}
+		addrs.shuffle(&mut thread_rng());
+		debug!("Retrieved seed addresses: {:?}", addrs);
+		addrs
+	}
+
+	/// Dials as many of the addresses from our seeds as we have dial slots
+	/// for, if our store is short of healthy peers. Seeds are resolved again
+	/// once all we got last time was dialed and enough time has passed. The
+	/// addresses are saved as defunct, until we manage to connect to them.
+	/// Returns how many dials were started.
+	pub fn seed(&self, server: &Arc<Server>) -> usize {
+		let mut pending = self.pending.lock();
+		if !needs_seeds(&server.peers) {
+			pending.clear();
+			return 0;
+		}
+
+		let mut last_resolved = self.last_resolved.lock();
+		if pending.is_empty() && last_resolved.map_or(true, |t| t.elapsed() >= RESEED_INTERVAL) {
+			*last_resolved = Some(Instant::now());
+			for addr in self.resolve() {
+				if let Ok(false) = server.peers.exists_peer(addr.clone()) {
+					if let Err(e) = server.peers.save_peer(&seed_peer(addr.clone())) {
+						error!("DnsSeeder: couldn't save seed {}: {:?}", addr, e);
+					}
+				}
+				pending.push(addr);
+			}
+		}
+
+		let mut count = 0;
+		while count < server.dial_slots() {
+			let addr = match pending.pop() {
+				Some(addr) => addr,
+				None => break,
+			};
+			if !server.peers.can_dial(&addr) || server.peers.is_known(addr.clone()) {
+				continue;
+			}
+			if Server::dial(server, addr) {
+				count += 1;
+			}
+		}
+		count
+	}
+}
+
+// Whether our store is short enough of healthy peers to dial our seeds.
+fn needs_seeds(peers: &Peers) -> bool {
+	peers
+		.find_peers(State::Healthy, Capabilities::UNKNOWN, DNS_SEED_MIN_PEERS)
+		.len() < DNS_SEED_MIN_PEERS
+}
+
+fn seed_peer(addr: PeerAddr) -> PeerData {
+	PeerData {
+		addr,
+		capabilities: Capabilities::UNKNOWN,
+		user_agent: "".to_string(),
+		flags: State::Defunct,
+		last_banned: 0,
+		ban_reason: ReasonForBan::None,
+		// only heard of, not protected from pruning (see `Peers::prune_store`)
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: Utc::now().timestamp(),
+		last_direction: None,
+	}
+}
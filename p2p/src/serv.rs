@@ -12,38 +12,86 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::chain;
 use crate::core::core;
 use crate::core::core::hash::Hash;
-use crate::core::global;
 use crate::core::pow::Difficulty;
-use crate::handshake::Handshake;
+use crate::dialer::Dialer;
+use crate::events::PeerEventReceiver;
+use crate::handshake::{Handshake, HandshakeCounts};
+use crate::msg::{DisconnectReason, PeerError};
 use crate::peer::Peer;
-use crate::peers::Peers;
+use crate::peers::{Peers, Sweep};
+use crate::portmap::{default_gateways, Gateway, PortMapper};
+use crate::seeds::DnsSeeder;
 use crate::store::PeerStore;
 use crate::types::{
-	Capabilities, ChainAdapter, Error, NetAdapter, P2PConfig, PeerAddr, PeerInfo, ReasonForBan,
-	TxHashSetRead,
+	Capabilities, ChainAdapter, Error, NetAdapter, NodeId, Offense, P2PConfig, PeerAddr, PeerInfo,
+	PeerLimits, ProtocolObserver, ReasonForBan, TxHashSetRead,
 };
-use crate::util::StopState;
+use crate::util::{Mutex, StopState};
 use chrono::prelude::{DateTime, Utc};
 
+/// How often we check we have enough outbound connections.
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often we ask our peers for peers with the capabilities we're missing.
+const SEEK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often we prune our store when it holds too many peers, and how many
+/// peers at most each time, see `Peers::prune_store`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+const PRUNE_BATCH: usize = 1000;
+
 /// P2P server implementation, handling bootstrapping to find and connect to
 /// peers, receiving connections from other peers and keep track of all of them.
 pub struct Server {
 	pub config: P2PConfig,
 	capabilities: Capabilities,
 	handshake: Arc<Handshake>,
+	dialer: Box<dyn Dialer>,
+	// outbound connections being attempted, see `dial`
+	dialing: AtomicUsize,
+	// our limits were lowered, the peers over them go on the next
+	// maintenance tick
+	limits_lowered: AtomicBool,
+	// when we last dialed each of our preferred peers
+	preferred_dialed: Mutex<HashMap<PeerAddr, Instant>>,
+	// when we last asked our peers for the capabilities we're missing
+	sought: Mutex<Option<Instant>>,
+	// the anchors saved when we last stopped, not dialed yet, next last
+	anchors: Mutex<Vec<PeerAddr>>,
 	pub peers: Arc<Peers>,
 	stop_state: Arc<StopState>,
+	// one per listen address, dropped when stopping, freeing our ports
+	// right away
+	listeners: Mutex<Vec<TcpListener>>,
+	// keeps our port mapped on our NAT gateway, if we're asked to
+	portmap: Option<Arc<PortMapper>>,
+}
+
+/// A server being stopped, see `Server::stop`.
+pub struct Stopping {
+	done: mpsc::Receiver<()>,
+}
+
+impl Stopping {
+	/// Waits until all our peers are gone and what we know of them saved, at
+	/// most the provided time. Whether it was all done in time.
+	pub fn wait(&self, timeout: Duration) -> bool {
+		self.done.recv_timeout(timeout).is_ok()
+	}
 }
 
 // TODO TLS
@@ -57,22 +105,96 @@ impl Server {
 		genesis: Hash,
 		stop_state: Arc<StopState>,
 	) -> Result<Server, Error> {
+		let store = PeerStore::new(db_root)?;
+		let handshake = Handshake::new(genesis, config.clone());
+		handshake.set_node_id(store.node_id()?);
+		let self_addrs = handshake.addrs.clone();
+		let portmap = if config.port_mapping() {
+			let mapper = PortMapper::new(default_gateways(), config.port, self_addrs.clone());
+			Some(Arc::new(mapper))
+		} else {
+			None
+		};
+		let limits = handshake.limits.clone();
+		let peers = Arc::new(Peers::new(
+			store,
+			adapter,
+			config.clone(),
+			self_addrs,
+			limits,
+		));
+		if let Some(ref path) = config.peers_import_file {
+			match peers.load_from_file(path) {
+				Ok(import) => info!(
+					"Imported {} peers from {}, {} ignored, {} malformed lines skipped.",
+					import.imported, path, import.ignored, import.malformed
+				),
+				Err(e) => error!("Couldn't import peers from {}: {:?}", path, e),
+			}
+		}
+		let mut anchors = peers.take_anchors();
+		anchors.reverse();
 		Ok(Server {
 			config: config.clone(),
 			capabilities: capab,
-			handshake: Arc::new(Handshake::new(genesis, config.clone())),
-			peers: Arc::new(Peers::new(PeerStore::new(db_root)?, adapter, config)),
+			handshake: Arc::new(handshake),
+			dialer: config.dialer(),
+			dialing: AtomicUsize::new(0),
+			limits_lowered: AtomicBool::new(false),
+			preferred_dialed: Mutex::new(HashMap::new()),
+			sought: Mutex::new(None),
+			anchors: Mutex::new(anchors),
+			peers,
 			stop_state,
+			listeners: Mutex::new(vec![]),
+			portmap,
 		})
 	}
 
-	/// Starts a new TCP server and listen to incoming connections. This is a
-	/// blocking call until the TCP server stops.
+	/// Replaces the dialer our outbound connections are opened with.
+	pub fn with_dialer(self, dialer: Box<dyn Dialer>) -> Server {
+		Server { dialer, ..self }
+	}
+
+	/// Maps our port through the provided gateways, whether port mapping is
+	/// configured or not.
+	pub fn with_gateways(self, gateways: Vec<Box<dyn Gateway>>) -> Server {
+		let mapper = PortMapper::new(gateways, self.config.port, self.handshake.addrs.clone());
+		Server {
+			portmap: Some(Arc::new(mapper)),
+			..self
+		}
+	}
+
+	/// Starts a new TCP server and listen to incoming connections, on each of
+	/// our listen addresses. An address we can't bind is skipped, as long as
+	/// we can bind another. This is a blocking call until the TCP server
+	/// stops.
 	pub fn listen(&self) -> Result<(), Error> {
-		// start TCP listener and handle incoming connections
-		let addr = SocketAddr::new(self.config.host, self.config.port);
-		let listener = TcpListener::bind(addr)?;
-		listener.set_nonblocking(true)?;
+		// start TCP listeners and handle incoming connections
+		{
+			let mut listeners = self.listeners.lock();
+			if self.stop_state.is_stopped() {
+				return Ok(());
+			}
+			let mut last_err = None;
+			for addr in self.config.listen_addrs() {
+				match bind(addr) {
+					Ok(listener) => {
+						info!("P2P server listening on {}", addr);
+						listeners.push(listener);
+					}
+					Err(e) => {
+						warn!("Couldn't listen on {}: {:?}", addr, e);
+						last_err = Some(e);
+					}
+				}
+			}
+			if listeners.is_empty() {
+				return Err(last_err.unwrap_or(Error::ConnectionClose));
+			}
+		}
+		self.start_port_mapping();
 
 		let sleep_time = Duration::from_millis(5);
 		loop {
@@ -82,28 +204,21 @@ impl Server {
 				continue;
 			}
 
-			match listener.accept() {
-				Ok((stream, peer_addr)) => {
-					let peer_addr = PeerAddr(peer_addr);
-
-					if self.check_undesirable(&stream) {
-						continue;
-					}
-					match self.handle_new_peer(stream) {
-						Err(Error::ConnectionClose) => debug!("shutting down, ignoring a new peer"),
-						Err(e) => {
-							debug!("Error accepting peer {}: {:?}", peer_addr.to_string(), e);
-							let _ = self.peers.add_banned(peer_addr, ReasonForBan::BadHandshake);
-						}
-						Ok(_) => {}
-					}
-				}
-				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-					// nothing to do, will retry in next iteration
-				}
-				Err(e) => {
-					debug!("Couldn't establish new client connection: {:?}", e);
+			// all our listeners feed the same handshakes, one connection from
+			// each at most per round
+			let accepted = {
+				let listeners = self.listeners.lock();
+				if listeners.is_empty() {
+					// stopped
+					break;
 				}
+				listeners
+					.iter()
+					.map(|listener| listener.accept())
+					.collect::<Vec<_>>()
+			};
+			for accepted in accepted {
+				self.handle_accepted(accepted);
 			}
 			if self.stop_state.is_stopped() {
 				break;
@@ -113,6 +228,101 @@ impl Server {
 		Ok(())
 	}
 
+	// Maps our port and keeps the mapping up until we stop, away from our
+	// listeners as gateways can be slow to answer.
+	fn start_port_mapping(&self) {
+		let portmap = match self.portmap {
+			Some(ref portmap) => portmap.clone(),
+			None => return,
+		};
+		let stop_state = self.stop_state.clone();
+		let _ = thread::Builder::new()
+			.name("p2p-portmap".to_string())
+			.spawn(move || {
+				while !stop_state.is_stopped() {
+					portmap.refresh(Instant::now());
+					thread::sleep(Duration::from_secs(1));
+				}
+			});
+	}
+
+	// Handles a new connection from one of our listeners.
+	fn handle_accepted(&self, accepted: io::Result<(TcpStream, SocketAddr)>) {
+		match accepted {
+			Ok((stream, peer_addr)) => {
+				let peer_addr = PeerAddr::Ip(peer_addr);
+
+				if self.check_undesirable(&stream) {
+					return;
+				}
+				match self.handle_new_peer(stream) {
+					Err(Error::ConnectionClose) => debug!("shutting down, ignoring a new peer"),
+					Err(Error::DuplicateConnection) => {
+						debug!("Already connected to {}, dropped duplicate.", peer_addr);
+					}
+					Err(Error::TooManyHandshakes) => {
+						debug!("Too many handshakes in progress, dropped {}.", peer_addr);
+					}
+					Err(Error::TooManyPeers) => {
+						// no one to evict for it or discouraged, see handle_new_peer
+					}
+					Err(Error::InboundLimit(_)) => {
+						// logged by the handshake, nothing to ban
+					}
+					Err(Error::Banned) => {
+						debug!("Peer {} banned, refused during handshake.", peer_addr);
+					}
+					Err(Error::PeerWithSelf) => {
+						// our own address was recorded by the handshake, nothing to ban
+						debug!("Connected to ourselves via {}, dropping.", peer_addr);
+					}
+					Err(Error::NodeIdCollision) => self.save_node_id(),
+					Err(Error::Cancelled) => {
+						debug!("Shutting down, handshake with {} cancelled.", peer_addr);
+					}
+					Err(e) => {
+						debug!("Error accepting peer {}: {:?}", peer_addr.to_string(), e);
+						let _ = self.peers.add_banned(peer_addr, ReasonForBan::BadHandshake);
+					}
+					Ok(_) => {}
+				}
+				self.peers.save_self_addrs();
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+				// nothing to do, will retry in next iteration
+			}
+			Err(e) => {
+				debug!("Couldn't establish new client connection: {:?}", e);
+			}
+		}
+	}
+
+	/// Sets the observer of the msgs exchanged with the peers connecting from
+	/// now on, see `ProtocolObserver`. None to stop observing new peers.
+	pub fn set_observer(&self, observer: Option<Arc<dyn ProtocolObserver>>) {
+		self.handshake.set_observer(observer);
+	}
+
+	/// Subscribes to what happens to our peers from now on: connecting,
+	/// disconnecting, getting banned and so on, see `PeerEventKind`. Up to
+	/// `capacity` events not taken yet are kept, the oldest ones dropped past
+	/// that. The events of a peer come in order, those of different peers
+	/// may not.
+	pub fn subscribe(&self, capacity: usize) -> PeerEventReceiver {
+		self.peers.subscribe(capacity)
+	}
+
+	/// Replaces the limits on our connections, taking effect right away for
+	/// the peers connecting and the ones we dial. Lowered limits don't drop
+	/// any peer on the spot, the ones over them go on the next maintenance
+	/// tick (see `maintain_outbound`).
+	pub fn update_limits(&self, limits: PeerLimits) {
+		info!("update_limits: {:?}", limits);
+		if self.peers.update_limits(limits) {
+			self.limits_lowered.store(true, Ordering::SeqCst);
+		}
+	}
+
 	/// Asks the server to connect to a new peer. Directly returns the peer if
 	/// we're already connected to the provided address.
 	pub fn connect(&self, addr: PeerAddr) -> Result<Arc<Peer>, Error> {
@@ -120,48 +330,95 @@ impl Server {
 			return Err(Error::ConnectionClose);
 		}
 
-		if Peer::is_denied(&self.config, addr) {
+		if Peer::is_denied(&self.config, &addr) {
 			debug!("connect_peer: peer {} denied, not connecting.", addr);
 			return Err(Error::ConnectionClose);
 		}
 
-		if global::is_production_mode() {
-			let hs = self.handshake.clone();
-			let addrs = hs.addrs.read();
-			if addrs.contains(&addr) {
-				debug!("connect: ignore connecting to PeerWithSelf, addr: {}", addr);
-				return Err(Error::PeerWithSelf);
+		if self.peers.is_banned(addr.clone()) {
+			if !self.config.is_preferred(&addr) {
+				debug!("connect_peer: peer {} banned, not connecting.", addr);
+				return Err(Error::Banned);
 			}
+			warn!(
+				"connect_peer: preferred peer {} banned, connecting anyway.",
+				addr
+			);
 		}
 
-		if let Some(p) = self.peers.get_connected_peer(addr) {
+		if self.handshake.addrs.contains(&addr) {
+			debug!("connect: ignore connecting to PeerWithSelf, addr: {}", addr);
+			return Err(Error::PeerWithSelf);
+		}
+
+		if let Some(p) = self.peers.get_connected_peer(addr.clone()) {
 			// if we're already connected to the addr, just return the peer
 			trace!("connect_peer: already connected {}", addr);
 			return Ok(p);
 		}
 
+		let max_outbound = self.peers.limits().max_outbound;
+		if !self.config.is_preferred(&addr)
+			&& self.peers.unpreferred_outbound_count() >= max_outbound
+		{
+			debug!(
+				"connect_peer: {} outbound peers already, not connecting to {}.",
+				max_outbound, addr
+			);
+			return Err(Error::TooManyPeers);
+		}
+
+		// held until we're done with the handshake, whichever way it goes
+		let _attempt = match self.peers.start_attempt(&addr) {
+			Some(attempt) => attempt,
+			None => {
+				debug!("connect_peer: connecting to {} already.", addr);
+				return Err(Error::AlreadyConnecting);
+			}
+		};
+
 		trace!(
 			"connect_peer: on {}:{}. connecting to {}",
 			self.config.host,
 			self.config.port,
 			addr
 		);
-		match TcpStream::connect_timeout(&addr.0, Duration::from_secs(10)) {
-			Ok(stream) => {
-				let addr = SocketAddr::new(self.config.host, self.config.port);
+		match self.dialer.dial(&addr, Duration::from_secs(10)) {
+			Ok((stream, peer_addr)) => {
 				let total_diff = self.peers.total_difficulty()?;
+				let total_height = self.peers.total_height()?;
 
-				let peer = Peer::connect(
+				let peer = match Peer::connect(
 					stream,
+					peer_addr,
 					self.capabilities,
 					total_diff,
-					PeerAddr(addr),
+					total_height,
+					self.handshake.addrs.advertise_addr(&self.config),
 					&self.handshake,
 					self.peers.clone(),
-				)?;
+				) {
+					Ok(peer) => peer,
+					Err(Error::NodeIdCollision) => {
+						self.save_node_id();
+						return Err(Error::NodeIdCollision);
+					}
+					Err(Error::PeerWithSelf) => {
+						self.peers.save_self_addrs();
+						return Err(Error::PeerWithSelf);
+					}
+					Err(e) => return Err(e),
+				};
 				let peer = Arc::new(peer);
-				self.peers.add_connected(peer.clone())?;
-				Ok(peer)
+				match self.peers.add_connected(peer.clone()) {
+					Ok(()) => Ok(peer),
+					// they dialed us at the same time, keep the connection we agreed on
+					Err(Error::DuplicateConnection) => self
+						.peers
+						.get_connected_peer(peer.info.addr.clone())
+						.ok_or(Error::DuplicateConnection),
+					Err(e) => Err(e),
+				}
 			}
 			Err(e) => {
 				trace!(
@@ -171,9 +428,256 @@ impl Server {
 					addr,
 					e
 				);
-				Err(Error::Connection(e))
+				Err(e)
+			}
+		}
+	}
+
+	/// Keeps up the outbound connections in the background, see
+	/// `maintain_outbound`, and our store from growing too big, see
+	/// `prune_store`, until we stop.
+	pub fn maintain_connections(server: Arc<Server>) -> io::Result<thread::JoinHandle<()>> {
+		thread::Builder::new()
+			.name("maintain_conns".to_string())
+			.spawn(move || {
+				let mut last = None;
+				let mut last_pruned = None;
+				while !server.stop_state.is_stopped() {
+					if server.stop_state.is_paused() {
+						thread::sleep(Duration::from_secs(1));
+						continue;
+					}
+					if last.map_or(true, |t: Instant| t.elapsed() >= MAINTAIN_INTERVAL) {
+						Server::maintain_outbound(&server);
+						last = Some(Instant::now());
+					}
+					if last_pruned.map_or(true, |t: Instant| t.elapsed() >= PRUNE_INTERVAL) {
+						server.prune_store();
+						last_pruned = Some(Instant::now());
+					}
+					thread::sleep(Duration::from_millis(100));
+				}
+			})
+	}
+
+	/// Starts the thread sweeping our peers every `monitor_interval`, see
+	/// `sweep_peers`.
+	pub fn monitor_peers(server: Arc<Server>) -> io::Result<thread::JoinHandle<()>> {
+		thread::Builder::new()
+			.name("monitor_peers".to_string())
+			.spawn(move || {
+				let interval = server.config.monitor_interval();
+				let mut last = None;
+				while !server.stop_state.is_stopped() {
+					if server.stop_state.is_paused() {
+						thread::sleep(Duration::from_secs(1));
+						continue;
+					}
+					if last.map_or(true, |t: Instant| t.elapsed() >= interval) {
+						Server::sweep_peers(&server);
+						last = Some(Instant::now());
+					}
+					thread::sleep(Duration::from_millis(100));
+				}
+			})
+	}
+
+	/// Drops our unresponsive peers and saves when we last heard from the
+	/// others, see `Peers::sweep`, dialing right away if we're left short of
+	/// outbound peers.
+	pub fn sweep_peers(server: &Arc<Server>) -> Sweep {
+		let sweep = server.peers.sweep();
+		let outbound = server.peers.unpreferred_outbound_count();
+		if sweep.dropped > 0 && outbound < server.peers.limits().outbound_target {
+			Server::maintain_outbound(server);
+		}
+		debug!(
+			"monitor_peers: {} connected ({} in, {} out), {} banned, {} dropped",
+			sweep.connected, sweep.inbound, sweep.outbound, sweep.banned, sweep.dropped,
+		);
+		sweep
+	}
+
+	/// Prunes the worst of the peers in our store if it holds too many of
+	/// them, PRUNE_BATCH at most, the anchors we didn't dial yet kept. Returns
+	/// how many were pruned.
+	pub fn prune_store(&self) -> usize {
+		let anchors = self.anchors.lock().clone();
+		self.peers.prune_store(PRUNE_BATCH, &anchors)
+	}
+
+	/// Drops the peers over our limits first if they were lowered since the
+	/// last tick, see `update_limits`. Then dials our preferred peers we're
+	/// not connected to, see `maintain_preferred`. Then dials peers when we have fewer outbound
+	/// connections than our target (preferred peers left out), at most
+	/// `max_concurrent_dials` at a time: our anchors first (see
+	/// `dial_anchors`), then peers from our store. With no one left to dial we
+	/// ask our peers for more addresses instead. Returns how many dials were
+	/// started.
+	pub fn maintain_outbound(server: &Arc<Server>) -> usize {
+		let limits = server.peers.limits();
+		if server.limits_lowered.swap(false, Ordering::SeqCst) {
+			server.peers.clean_peers(limits.peer_max_count as usize);
+		}
+		Server::seek_wanted(server);
+		let dialing = server.dialing.load(Ordering::SeqCst);
+		let preferred = Server::maintain_preferred(server);
+		let outbound = server.peers.unpreferred_outbound_count() as usize;
+		let needed = (limits.outbound_target as usize).saturating_sub(outbound + dialing);
+		let slots = server.dial_slots();
+		if needed == 0 || slots == 0 {
+			return preferred;
+		}
+
+		let wanted = cmp::min(needed, slots);
+		let anchors = Server::dial_anchors(server, wanted);
+		if anchors.len() == wanted {
+			return preferred + anchors.len();
+		}
+		let mut candidates = server.peers.dial_candidates(wanted);
+		candidates.retain(|addr| !anchors.contains(addr));
+		candidates.truncate(wanted - anchors.len());
+		if candidates.is_empty() && anchors.is_empty() {
+			debug!(
+				"maintain_outbound: {} outbound, no one to dial, asking our peers",
+				outbound
+			);
+			for p in server.peers.connected_peers() {
+				let _ = p.send_peer_request(Capabilities::PEER_LIST);
+			}
+			return preferred;
+		}
+
+		debug!(
+			"maintain_outbound: {} outbound ({} dialing), dialing {} more",
+			outbound,
+			dialing,
+			candidates.len()
+		);
+		let mut count = preferred + anchors.len();
+		for addr in candidates {
+			if Server::dial(server, addr) {
+				count += 1;
+			}
+		}
+		count
+	}
+
+	/// Dials up to `max` of the anchors saved when we last stopped, see
+	/// `Peers::save_anchors`, whether we're backing off from them or not. Each
+	/// is only dialed once, normal selection taking over if it fails us.
+	/// Returns the anchors dialed.
+	fn dial_anchors(server: &Arc<Server>, max: usize) -> Vec<PeerAddr> {
+		let mut anchors = server.anchors.lock();
+		let mut dialed = vec![];
+		while dialed.len() < max {
+			let addr = match anchors.pop() {
+				Some(addr) => addr,
+				None => break,
+			};
+			if server.peers.is_known(addr.clone())
+				|| server.peers.is_attempting(&addr)
+				|| server.peers.is_banned(addr.clone())
+			{
+				continue;
+			}
+			debug!("dial_anchors: dialing anchor {}", addr);
+			if Server::dial(server, addr.clone()) {
+				dialed.push(addr);
+			}
+		}
+		dialed
+	}
+
+	/// Asks our peers for the addresses of peers with the capabilities we
+	/// need to sync and none of them have, at most every `SEEK_INTERVAL`.
+	fn seek_wanted(server: &Arc<Server>) {
+		let wanted = server.peers.wanted_capabilities();
+		let mut sought = server.sought.lock();
+		if wanted.is_empty() || sought.map_or(false, |t| t.elapsed() < SEEK_INTERVAL) {
+			return;
+		}
+		debug!("seek_wanted: asking our peers for peers with {:?}", wanted);
+		*sought = Some(Instant::now());
+		for p in server.peers.connected_peers() {
+			let _ = p.send_peer_request(wanted);
+		}
+	}
+
+	/// Dials the preferred peers we're not connected to, each at most every
+	/// `preferred_redial_interval`, however often they failed us. Returns how
+	/// many dials were started.
+	fn maintain_preferred(server: &Arc<Server>) -> usize {
+		let preferred = match server.config.peers_preferred {
+			Some(ref preferred) => preferred,
+			None => return 0,
+		};
+		let interval = server.config.preferred_redial_interval();
+		let mut dialed = server.preferred_dialed.lock();
+		let mut count = 0;
+		for addr in preferred {
+			if server.peers.is_known(addr.clone())
+				|| dialed.get(addr).map_or(false, |t| t.elapsed() < interval)
+			{
+				continue;
+			}
+			debug!("maintain_preferred: dialing preferred peer {}", addr);
+			dialed.insert(addr.clone(), Instant::now());
+			if Server::dial(server, addr.clone()) {
+				count += 1;
 			}
 		}
+		count
+	}
+
+	/// How many more dials we can start before reaching
+	/// `max_concurrent_dials`.
+	pub(crate) fn dial_slots(&self) -> usize {
+		(self.config.max_concurrent_dials() as usize)
+			.saturating_sub(self.dialing.load(Ordering::SeqCst))
+	}
+
+	/// Connects to the peer in the background, asking it for more peers once
+	/// connected. Returns whether we could start dialing.
+	pub(crate) fn dial(server: &Arc<Server>, addr: PeerAddr) -> bool {
+		server.dialing.fetch_add(1, Ordering::SeqCst);
+		let server_c = server.clone();
+		let res = thread::Builder::new()
+			.name("peer_connect".to_string())
+			.spawn(move || {
+				match server_c.connect(addr.clone()) {
+					Ok(p) => {
+						let _ = p.send_peer_request(Capabilities::PEER_LIST);
+					}
+					// back off, give up on or ban the peer depending on why we failed
+					Err(e) => server_c.peers.connect_failed(addr, &e),
+				}
+				server_c.dialing.fetch_sub(1, Ordering::SeqCst);
+			});
+		if let Err(e) = res {
+			error!("dial: couldn't start dialing: {:?}", e);
+			server.dialing.fetch_sub(1, Ordering::SeqCst);
+			return false;
+		}
+		true
+	}
+
+	/// Dials the nodes behind our DNS seeds in the background while our store
+	/// is short of healthy peers, see `DnsSeeder::seed`, until we stop.
+	pub fn seed_from_dns(
+		server: Arc<Server>,
+		seeder: DnsSeeder,
+	) -> io::Result<thread::JoinHandle<()>> {
+		thread::Builder::new()
+			.name("dns_seed".to_string())
+			.spawn(move || {
+				while !server.stop_state.is_stopped() {
+					if !server.stop_state.is_paused() {
+						seeder.seed(&server);
+					}
+					thread::sleep(Duration::from_secs(1));
+				}
+			})
 	}
 
 	fn handle_new_peer(&self, stream: TcpStream) -> Result<(), Error> {
@@ -181,15 +685,42 @@ impl Server {
 			return Err(Error::ConnectionClose);
 		}
 		let total_diff = self.peers.total_difficulty()?;
+		let total_height = self.peers.total_height()?;
 
 		// accept the peer and add it to the server map
 		let peer = Peer::accept(
 			stream,
 			self.capabilities,
 			total_diff,
+			total_height,
 			&self.handshake,
 			self.peers.clone(),
 		)?;
+
+		// a discouraged peer only gets one of our spare inbound slots
+		if self.peers.is_discouraged(peer.info.addr.clone()) && !self.peers.admits_discouraged() {
+			debug!(
+				"{} inbound peers already, refusing discouraged {}.",
+				self.peers.peer_inbound_count(),
+				peer.info.addr
+			);
+			peer.disconnect(DisconnectReason::TooManyPeers);
+			return Err(Error::TooManyPeers);
+		}
+
+		// with as many inbound peers as we take, one has to go for the
+		// newcomer to stay
+		let max_inbound = self.peers.limits().max_inbound;
+		if self.peers.peer_inbound_count() >= max_inbound
+			&& !self.peers.make_inbound_room(&peer.info.addr)
+		{
+			debug!(
+				"{} inbound peers already, none to evict, refusing {}.",
+				max_inbound, peer.info.addr
+			);
+			peer.disconnect(DisconnectReason::TooManyPeers);
+			return Err(Error::TooManyPeers);
+		}
 		self.peers.add_connected(Arc::new(peer))?;
 		Ok(())
 	}
@@ -203,17 +734,21 @@ impl Server {
 	/// addresses (NAT), network distribution is improved if they choose
 	/// different sets of peers themselves. In addition, it prevent potential
 	/// duplicate connections, malicious or not.
+	/// Having as many inbound peers as we take isn't one of them, the newcomer
+	/// may displace one of them once through the handshake, see
+	/// `Peers::make_inbound_room`. Nor is the peer being discouraged, only
+	/// known for sure once through the handshake as well.
 	fn check_undesirable(&self, stream: &TcpStream) -> bool {
 		if let Ok(peer_addr) = stream.peer_addr() {
-			let peer_addr = PeerAddr(peer_addr);
-			if self.peers.is_banned(peer_addr) {
+			let peer_addr = PeerAddr::Ip(peer_addr);
+			if self.peers.is_banned(peer_addr.clone()) {
 				debug!("Peer {} banned, refusing connection.", peer_addr);
 				if let Err(e) = stream.shutdown(Shutdown::Both) {
 					debug!("Error shutting down conn: {:?}", e);
 				}
 				return true;
 			}
-			if self.peers.is_known(peer_addr) {
+			if self.peers.is_known(peer_addr.clone()) {
 				debug!("Peer {} already known, refusing connection.", peer_addr);
 				if let Err(e) = stream.shutdown(Shutdown::Both) {
 					debug!("Error shutting down conn: {:?}", e);
@@ -224,9 +759,60 @@ impl Server {
 		false
 	}
 
-	pub fn stop(&self) {
+	/// Persists the node id the handshake switched to after a collision.
+	fn save_node_id(&self) {
+		if let Err(e) = self.peers.save_node_id(&self.handshake.node_id()) {
+			error!("Couldn't save our new node id: {:?}", e);
+		}
+	}
+
+	/// Our node id, as sent to our peers during handshakes.
+	pub fn node_id(&self) -> NodeId {
+		self.handshake.node_id()
+	}
+
+	/// Outcome counters of the handshakes with our peers, both directions.
+	pub fn handshake_stats(&self) -> HandshakeCounts {
+		self.handshake.stats()
+	}
+
+	/// Stops the server: our listener is closed first so our port is free
+	/// again right away, the handshakes in progress are cancelled and all our
+	/// peers told we're shutting down, our outbound peers being saved as our
+	/// anchors first (see `Peers::save_anchors`). Returns once that's done,
+	/// our port mapping being removed, the peers getting what we still had
+	/// queued for them and what we know of them being saved in the
+	/// background, see `Stopping`.
+	pub fn stop(&self) -> Stopping {
 		self.stop_state.stop();
-		self.peers.stop();
+		self.listeners.lock().clear();
+		self.handshake.cancel();
+		self.peers.save_anchors();
+		let gone = self.peers.disconnect_all();
+
+		let (done, stopping) = mpsc::channel();
+		let peers = self.peers.clone();
+		let portmap = self.portmap.clone();
+		let export = self.config.peers_export_file.clone();
+		let _ = thread::Builder::new()
+			.name("p2p-stop".to_string())
+			.spawn(move || {
+				if let Some(portmap) = portmap {
+					portmap.remove();
+				}
+				for peer in gone {
+					peer.wait();
+				}
+				peers.save_self_addrs();
+				if let Some(path) = export {
+					match peers.save_to_file(&path) {
+						Ok(count) => info!("Exported {} peers to {}.", count, path),
+						Err(e) => error!("Couldn't export peers to {}: {:?}", path, e),
+					}
+				}
+				let _ = done.send(());
+			});
+		Stopping { done: stopping }
 	}
 
 	/// Pause means: stop all the current peers connection, only for tests.
@@ -238,6 +824,13 @@ impl Server {
 	}
 }
 
+// A non-blocking listener on the provided address.
+fn bind(addr: SocketAddr) -> Result<TcpListener, Error> {
+	let listener = TcpListener::bind(addr)?;
+	listener.set_nonblocking(true)?;
+	Ok(listener)
+}
+
 /// A no-op network adapter used for testing.
 pub struct DummyAdapter {}
 
@@ -255,10 +848,14 @@ impl ChainAdapter for DummyAdapter {
 	fn tx_kernel_received(&self, _h: Hash, _peer_info: &PeerInfo) -> Result<bool, chain::Error> {
 		Ok(true)
 	}
+	fn block_inv_received(&self, _: Hash, _: u64, _: &PeerInfo) -> Result<bool, chain::Error> {
+		Ok(true)
+	}
 	fn transaction_received(
 		&self,
 		_: core::Transaction,
 		_stem: bool,
+		_: &PeerInfo,
 	) -> Result<bool, chain::Error> {
 		Ok(true)
 	}
@@ -292,6 +889,9 @@ impl ChainAdapter for DummyAdapter {
 	fn get_block(&self, _: Hash) -> Option<core::Block> {
 		None
 	}
+	fn get_block_by_height(&self, _: u64) -> Option<core::Block> {
+		None
+	}
 	fn kernel_data_read(&self) -> Result<File, chain::Error> {
 		unimplemented!()
 	}
@@ -334,12 +934,19 @@ impl ChainAdapter for DummyAdapter {
 }
 
 impl NetAdapter for DummyAdapter {
-	fn find_peer_addrs(&self, _: Capabilities) -> Vec<PeerAddr> {
+	fn find_peer_addrs(&self, _: PeerAddr, _: Capabilities) -> Vec<PeerAddr> {
+		vec![]
+	}
+	fn peer_addrs_received(&self, _: PeerAddr, _: Vec<PeerAddr>) {}
+	fn gossip_addrs(&self, _: PeerAddr) -> Vec<PeerAddr> {
 		vec![]
 	}
-	fn peer_addrs_received(&self, _: Vec<PeerAddr>) {}
 	fn peer_difficulty(&self, _: PeerAddr, _: Difficulty, _: u64) {}
+	fn ban_reason_received(&self, _: PeerAddr, _: ReasonForBan) {}
+	fn peer_error_received(&self, _: PeerAddr, _: PeerError) {}
+	fn disconnect_received(&self, _: PeerAddr, _: DisconnectReason) {}
 	fn is_banned(&self, _: PeerAddr) -> bool {
 		false
 	}
+	fn report_misbehavior(&self, _: PeerAddr, _: Offense) {}
 }
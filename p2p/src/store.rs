@@ -20,13 +20,22 @@ use rand::seq::SliceRandom;
 use rand::thread_rng;
 
 use crate::core::ser::{self, Readable, Reader, Writeable, Writer};
-use crate::types::{Capabilities, PeerAddr, ReasonForBan};
+use crate::msg::PeerError;
+use crate::types::{Capabilities, Direction, NodeId, PeerAddr, ReasonForBan};
 use grin_store::{self, option_to_not_found, to_key, Error};
 
 const DB_NAME: &'static str = "peer";
 const STORE_SUBPATH: &'static str = "peers";
 
 const PEER_PREFIX: u8 = 'P' as u8;
+const NODE_ID_PREFIX: u8 = 'I' as u8;
+const SELF_ADDRS_PREFIX: u8 = 'S' as u8;
+const VERSION_PREFIX: u8 = 'V' as u8;
+const ANCHORS_PREFIX: u8 = 'A' as u8;
+
+/// Version of the layout of what we store, saved along with it. Stores from
+/// before we kept it are version 1.
+pub const SCHEMA_VERSION: u32 = 2;
 
 // Types of messages
 enum_from_primitive! {
@@ -35,6 +44,13 @@ enum_from_primitive! {
 		Healthy = 0,
 		Banned = 1,
 		Defunct = 2,
+		// never worth dialing again: on another network or chain, or ourselves
+		Incompatible = 3,
+		// heard about from another peer, never connected to
+		Unverified = 4,
+		// deprioritized for a while without being refused, see
+		// `Peers::discourage_peer`
+		Discouraged = 5,
 	}
 }
 
@@ -56,8 +72,47 @@ pub struct PeerData {
 	pub ban_reason: ReasonForBan,
 	/// Time when we last connected to this peer.
 	pub last_connected: i64,
+	/// How we broke the protocol, as the peer told us when it last
+	/// disconnected us.
+	pub last_error: Option<PeerError>,
+	/// Time when we last tried to connect to this peer, successfully or not.
+	pub last_attempted: i64,
+	/// How many times in a row we failed to connect to this peer.
+	pub failures: u32,
+	/// When the current ban or discouragement is over, 0 for the ban window
+	/// from the time the peer was last banned.
+	pub banned_until: i64,
+	/// Time when we last heard from this peer, any msg it sent us while
+	/// connected.
+	pub last_seen: i64,
+	/// Which side opened our last connection with this peer, if we ever
+	/// connected.
+	pub last_direction: Option<Direction>,
+}
+
+/// How many peers we have in store in each state.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+	pub healthy: usize,
+	pub banned: usize,
+	pub defunct: usize,
+	pub incompatible: usize,
+	pub unverified: usize,
+	pub discouraged: usize,
 }
 
+// How close in time (in seconds) peers need to have been seen to be picked
+// from in random order, the ones seen in more recent windows first.
+const RECENCY_WINDOW: i64 = 24 * 3600;
+
+// How much newer (in seconds) than what's in store the last seen time of a
+// connected peer needs to be for us to save it.
+const LAST_SEEN_RESOLUTION: i64 = 10 * 60;
+
+// Starts the fields added after the last connection time, records of version
+// 1 stores end right before it.
+const PEER_DATA_EXT: u8 = 0xff;
+
 impl Writeable for PeerData {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		self.addr.write(writer)?;
@@ -68,9 +123,37 @@ impl Writeable for PeerData {
 			[write_u8, self.flags as u8],
 			[write_i64, self.last_banned],
 			[write_i32, self.ban_reason as i32],
-			[write_i64, self.last_connected]
+			[write_i64, self.last_connected],
+			[write_u8, PEER_DATA_EXT],
+			[write_i64, self.last_attempted],
+			[write_u32, self.failures]
 		);
-		Ok(())
+		match self.last_error {
+			Some(ref last_error) => {
+				writer.write_u8(1)?;
+				last_error.write(writer)?;
+			}
+			None => writer.write_u8(0)?,
+		}
+		writer.write_i64(self.banned_until)?;
+		writer.write_i64(self.last_seen)?;
+		match self.last_direction {
+			Some(direction) => {
+				writer.write_u8(1)?;
+				writer.write_u8(direction as u8)
+			}
+			None => writer.write_u8(0),
+		}
+	}
+}
+
+// A peer record that may not read, so one bad record doesn't stop us from
+// going through all the others.
+struct StoredPeer(Result<PeerData, ser::Error>);
+
+impl Readable for StoredPeer {
+	fn read(reader: &mut dyn Reader) -> Result<StoredPeer, ser::Error> {
+		Ok(StoredPeer(PeerData::read(reader)))
 	}
 }
 
@@ -88,6 +171,40 @@ impl Readable for PeerData {
 			Err(_) => Utc::now().timestamp(),
 			Ok(lc) => lc,
 		};
+		// same for what comes after it, the extended fields are there whole
+		// or not at all
+		let (last_attempted, failures, last_error, banned_until, last_seen, last_direction) =
+			match reader.read_u8() {
+				Err(_) => (0, 0, None, 0, last_connected, None),
+				Ok(PEER_DATA_EXT) => {
+					let last_attempted = reader.read_i64()?;
+					let failures = reader.read_u32()?;
+					let last_error = match reader.read_u8()? {
+						0 => None,
+						1 => Some(PeerError::read(reader)?),
+						_ => return Err(ser::Error::CorruptedData),
+					};
+					let banned_until = reader.read_i64()?;
+					let last_seen = reader.read_i64()?;
+					let last_direction = match reader.read_u8()? {
+						0 => None,
+						1 => Some(
+							Direction::from_u8(reader.read_u8()?)
+								.ok_or(ser::Error::CorruptedData)?,
+						),
+						_ => return Err(ser::Error::CorruptedData),
+					};
+					(
+						last_attempted,
+						failures,
+						last_error,
+						banned_until,
+						last_seen,
+						last_direction,
+					)
+				}
+				Ok(_) => return Err(ser::Error::CorruptedData),
+			};
 
 		let user_agent = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
 		let capabilities = Capabilities::from_bits_truncate(capab);
@@ -102,48 +219,165 @@ impl Readable for PeerData {
 				last_banned: lb,
 				ban_reason,
 				last_connected,
+				last_error,
+				last_attempted,
+				failures,
+				banned_until,
+				last_seen,
+				last_direction,
 			}),
 			None => Err(ser::Error::CorruptedData),
 		}
 	}
 }
 
+/// One of our own addresses and when we stop considering it ours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfAddr {
+	pub addr: PeerAddr,
+	pub expires: i64,
+}
+
+impl Writeable for SelfAddr {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.addr.write(writer)?;
+		writer.write_i64(self.expires)
+	}
+}
+
+impl Readable for SelfAddr {
+	fn read(reader: &mut dyn Reader) -> Result<SelfAddr, ser::Error> {
+		let addr = PeerAddr::read(reader)?;
+		let expires = reader.read_i64()?;
+		Ok(SelfAddr { addr, expires })
+	}
+}
+
 /// Storage facility for peer data.
 pub struct PeerStore {
 	db: grin_store::Store,
 }
 
 impl PeerStore {
-	/// Instantiates a new peer store under the provided root path.
+	/// Instantiates a new peer store under the provided root path, upgrading
+	/// what's in it to our schema version first. A store written by a newer
+	/// version than ours is refused, we don't downgrade.
 	pub fn new(db_root: &str) -> Result<PeerStore, Error> {
 		let db = grin_store::Store::new(db_root, Some(DB_NAME), Some(STORE_SUBPATH), None)?;
-		Ok(PeerStore { db: db })
+		let store = PeerStore { db: db };
+		store.upgrade()?;
+		Ok(store)
+	}
+
+	/// Version of the layout of what's in store, see `SCHEMA_VERSION`.
+	pub fn schema_version(&self) -> Result<u32, Error> {
+		if let Some(version) = self.db.get_ser(&version_key()[..])? {
+			return Ok(version);
+		}
+		// nothing in store yet is as good as the current version
+		let key = to_key(PEER_PREFIX, &mut vec![]);
+		let empty = self.db.iter::<StoredPeer>(&key)?.next().is_none();
+		Ok(if empty { SCHEMA_VERSION } else { 1 })
+	}
+
+	// Migrates the store to our schema version if it's not there yet,
+	// saving the version. Returns how many records were rewritten.
+	fn upgrade(&self) -> Result<usize, Error> {
+		let version = self.schema_version()?;
+		if version > SCHEMA_VERSION {
+			return Err(Error::VersionErr(format!(
+				"peer store is version {}, only up to {} supported, refusing to downgrade",
+				version, SCHEMA_VERSION
+			)));
+		}
+		let migrated = if version < SCHEMA_VERSION {
+			self.migrate(version, SCHEMA_VERSION)?
+		} else {
+			0
+		};
+		if self.db.get_ser::<u32>(&version_key()[..])?.is_none() {
+			let batch = self.db.batch()?;
+			batch.put_ser(&version_key()[..], &SCHEMA_VERSION)?;
+			batch.commit()?;
+		}
+		Ok(migrated)
+	}
+
+	/// Upgrades the records in store from one schema version to another, one
+	/// version at a time, and saves the version reached. Returns how many
+	/// records were rewritten.
+	pub fn migrate(&self, from: u32, to: u32) -> Result<usize, Error> {
+		let mut migrated = 0;
+		for version in from..to {
+			migrated += match version {
+				1 => self.migrate_peers()?,
+				_ => {
+					return Err(Error::VersionErr(format!(
+						"no migration from peer store version {}",
+						version
+					)))
+				}
+			};
+			let batch = self.db.batch()?;
+			batch.put_ser(&version_key()[..], &(version + 1))?;
+			batch.commit()?;
+			info!(
+				"Peer store migrated to version {}, {} records rewritten.",
+				version + 1,
+				migrated
+			);
+		}
+		Ok(migrated)
+	}
+
+	// Rewrites all peer records in the current layout, the fields they didn't
+	// have yet filled with their defaults. Records that don't read are
+	// dropped.
+	fn migrate_peers(&self) -> Result<usize, Error> {
+		let key = to_key(PEER_PREFIX, &mut vec![]);
+		let records = self.db.iter::<StoredPeer>(&key)?.collect::<Vec<_>>();
+		let batch = self.db.batch()?;
+		let mut migrated = 0;
+		for (k, record) in records {
+			match record.0 {
+				Ok(peer) => {
+					batch.put_ser(&k[..], &peer)?;
+					migrated += 1;
+				}
+				Err(e) => {
+					warn!("Dropping corrupt peer record {:?}: {:?}", k, e);
+					batch.delete(&k[..])?;
+				}
+			}
+		}
+		batch.commit()?;
+		Ok(migrated)
 	}
 
 	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
 		debug!("save_peer: {:?} marked {:?}", p.addr, p.flags);
 
 		let batch = self.db.batch()?;
-		batch.put_ser(&peer_key(p.addr)[..], p)?;
+		batch.put_ser(&peer_key(&p.addr)[..], p)?;
 		batch.commit()
 	}
 
 	pub fn get_peer(&self, peer_addr: PeerAddr) -> Result<PeerData, Error> {
 		option_to_not_found(
-			self.db.get_ser(&peer_key(peer_addr)[..]),
+			self.db.get_ser(&peer_key(&peer_addr)[..]),
 			&format!("Peer at address: {}", peer_addr),
 		)
 	}
 
 	pub fn exists_peer(&self, peer_addr: PeerAddr) -> Result<bool, Error> {
-		self.db.exists(&peer_key(peer_addr)[..])
+		self.db.exists(&peer_key(&peer_addr)[..])
 	}
 
 	/// TODO - allow below added to avoid github issue reports
 	#[allow(dead_code)]
 	pub fn delete_peer(&self, peer_addr: PeerAddr) -> Result<(), Error> {
 		let batch = self.db.batch()?;
-		batch.delete(&peer_key(peer_addr)[..])?;
+		batch.delete(&peer_key(&peer_addr)[..])?;
 		batch.commit()
 	}
 
@@ -154,41 +388,242 @@ impl PeerStore {
 		count: usize,
 	) -> Result<Vec<PeerData>, Error> {
 		let mut peers = self
-			.db
-			.iter::<PeerData>(&to_key(PEER_PREFIX, &mut "".to_string().into_bytes()))?
-			.map(|(_, v)| v)
+			.all_peers()?
+			.into_iter()
 			.filter(|p| p.flags == state && p.capabilities.contains(cap))
 			.collect::<Vec<_>>();
+		// random among those that failed us the least, the ones we heard from
+		// recently first
+		let now = Utc::now().timestamp();
 		peers[..].shuffle(&mut thread_rng());
+		peers.sort_by_key(|p| (p.failures, (now - p.last_seen).max(0) / RECENCY_WINDOW));
 		Ok(peers.iter().take(count).cloned().collect())
 	}
 
-	/// List all known peers
+	/// List all known peers, skipping the records that don't read.
 	/// Used for /v1/peers/all api endpoint
 	pub fn all_peers(&self) -> Result<Vec<PeerData>, Error> {
 		let key = to_key(PEER_PREFIX, &mut "".to_string().into_bytes());
 		Ok(self
 			.db
-			.iter::<PeerData>(&key)?
-			.map(|(_, v)| v)
+			.iter::<StoredPeer>(&key)?
+			.filter_map(|(k, v)| match v.0 {
+				Ok(peer) => Some(peer),
+				Err(e) => {
+					warn!("Skipping corrupt peer record {:?}: {:?}", k, e);
+					None
+				}
+			})
 			.collect::<Vec<_>>())
 	}
 
+	/// Remembers the error the peer last disconnected us with.
+	pub fn update_last_error(&self, peer_addr: PeerAddr, error: PeerError) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+
+		let mut peer = option_to_not_found(
+			batch.get_ser::<PeerData>(&peer_key(&peer_addr)[..]),
+			&format!("Peer at address: {}", peer_addr),
+		)?;
+		peer.last_error = Some(error);
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
+	/// How many peers we have in each state.
+	pub fn stats(&self) -> Result<StoreStats, Error> {
+		let mut stats = StoreStats::default();
+		for peer in self.all_peers()? {
+			match peer.flags {
+				State::Healthy => stats.healthy += 1,
+				State::Banned => stats.banned += 1,
+				State::Defunct => stats.defunct += 1,
+				State::Incompatible => stats.incompatible += 1,
+				State::Unverified => stats.unverified += 1,
+				State::Discouraged => stats.discouraged += 1,
+			}
+		}
+		Ok(stats)
+	}
+
+	/// Remembers when we last heard from a connected peer, counting as the
+	/// last time we were connected to it too. Only saved when it's enough
+	/// newer than what we have.
+	pub fn update_last_seen(&self, peer_addr: PeerAddr, last_seen: i64) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+
+		let mut peer = option_to_not_found(
+			batch.get_ser::<PeerData>(&peer_key(&peer_addr)[..]),
+			&format!("Peer at address: {}", peer_addr),
+		)?;
+		if last_seen < peer.last_seen + LAST_SEEN_RESOLUTION {
+			return Ok(());
+		}
+		peer.last_seen = last_seen;
+		peer.last_connected = peer.last_connected.max(last_seen);
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
+	/// Counts one more failed attempt at connecting to a peer.
+	pub fn record_failure(&self, peer_addr: PeerAddr) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+
+		let mut peer = option_to_not_found(
+			batch.get_ser::<PeerData>(&peer_key(&peer_addr)[..]),
+			&format!("Peer at address: {}", peer_addr),
+		)?;
+		peer.failures = peer.failures.saturating_add(1);
+		peer.last_attempted = Utc::now().timestamp();
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
 	/// Convenience method to load a peer data, update its status and save it
 	/// back. If new state is Banned its last banned time will be updated too.
 	pub fn update_state(&self, peer_addr: PeerAddr, new_state: State) -> Result<(), Error> {
 		let batch = self.db.batch()?;
 
 		let mut peer = option_to_not_found(
-			batch.get_ser::<PeerData>(&peer_key(peer_addr)[..]),
+			batch.get_ser::<PeerData>(&peer_key(&peer_addr)[..]),
 			&format!("Peer at address: {}", peer_addr),
 		)?;
 		peer.flags = new_state;
 		if new_state == State::Banned {
 			peer.last_banned = Utc::now().timestamp();
 		}
+		peer.banned_until = 0;
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
+	/// Bans a peer until the provided time, saving it first if we never
+	/// heard of it before.
+	pub fn ban(
+		&self,
+		peer_addr: PeerAddr,
+		ban_reason: ReasonForBan,
+		until: i64,
+	) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+
+		let now = Utc::now().timestamp();
+		let mut peer = batch
+			.get_ser::<PeerData>(&peer_key(&peer_addr)[..])?
+			.unwrap_or(PeerData {
+				addr: peer_addr.clone(),
+				capabilities: Capabilities::UNKNOWN,
+				user_agent: "".to_string(),
+				flags: State::Banned,
+				last_banned: now,
+				ban_reason,
+				last_connected: now,
+				last_error: None,
+				last_attempted: 0,
+				failures: 0,
+				banned_until: until,
+				last_seen: now,
+				last_direction: None,
+			});
+		peer.flags = State::Banned;
+		peer.last_banned = now;
+		peer.ban_reason = ban_reason;
+		peer.banned_until = until;
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
+	/// Discourages a peer until the provided time, saving it first if we
+	/// never heard of it before. A banned peer stays banned.
+	pub fn discourage(&self, peer_addr: PeerAddr, until: i64) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+
+		let now = Utc::now().timestamp();
+		let mut peer = batch
+			.get_ser::<PeerData>(&peer_key(&peer_addr)[..])?
+			.unwrap_or(PeerData {
+				addr: peer_addr.clone(),
+				capabilities: Capabilities::UNKNOWN,
+				user_agent: "".to_string(),
+				flags: State::Discouraged,
+				last_banned: 0,
+				ban_reason: ReasonForBan::None,
+				last_connected: now,
+				last_error: None,
+				last_attempted: 0,
+				failures: 0,
+				banned_until: until,
+				last_seen: now,
+				last_direction: None,
+			});
+		if peer.flags == State::Banned {
+			return Ok(());
+		}
+		peer.flags = State::Discouraged;
+		peer.banned_until = until;
+
+		batch.put_ser(&peer_key(&peer_addr)[..], &peer)?;
+		batch.commit()
+	}
+
+	/// Our own node id, generated and saved the first time it's asked for.
+	pub fn node_id(&self) -> Result<NodeId, Error> {
+		let key = to_key(NODE_ID_PREFIX, &mut vec![]);
+		if let Some(node_id) = self.db.get_ser(&key[..])? {
+			return Ok(node_id);
+		}
+		let node_id = NodeId::random();
+		self.save_node_id(&node_id)?;
+		Ok(node_id)
+	}
+
+	/// Replaces our node id, after we found another node using it.
+	pub fn save_node_id(&self, node_id: &NodeId) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+		batch.put_ser(&to_key(NODE_ID_PREFIX, &mut vec![])[..], node_id)?;
+		batch.commit()
+	}
+
+	/// Our own addresses, as last saved.
+	pub fn self_addrs(&self) -> Result<Vec<SelfAddr>, Error> {
+		let key = to_key(SELF_ADDRS_PREFIX, &mut vec![]);
+		Ok(self.db.get_ser(&key[..])?.unwrap_or(vec![]))
+	}
+
+	/// Replaces our saved own addresses.
+	pub fn save_self_addrs(&self, addrs: &Vec<SelfAddr>) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+		batch.put_ser(&to_key(SELF_ADDRS_PREFIX, &mut vec![])[..], addrs)?;
+		batch.commit()
+	}
+
+	/// The outbound peers we were connected to when we last stopped.
+	pub fn anchors(&self) -> Result<Vec<PeerAddr>, Error> {
+		let key = to_key(ANCHORS_PREFIX, &mut vec![]);
+		Ok(self.db.get_ser(&key[..])?.unwrap_or(vec![]))
+	}
+
+	/// Replaces our saved anchors.
+	pub fn save_anchors(&self, anchors: &Vec<PeerAddr>) -> Result<(), Error> {
+		let batch = self.db.batch()?;
+		batch.put_ser(&to_key(ANCHORS_PREFIX, &mut vec![])[..], anchors)?;
+		batch.commit()
+	}
 
-		batch.put_ser(&peer_key(peer_addr)[..], &peer)?;
+	/// Deletes the peers with the provided addresses, in a single batch.
+	pub fn delete_addrs(&self, addrs: &[PeerAddr]) -> Result<(), Error> {
+		if addrs.is_empty() {
+			return Ok(());
+		}
+		let batch = self.db.batch()?;
+		for addr in addrs {
+			batch.delete(&peer_key(addr)[..])?;
+		}
 		batch.commit()
 	}
 
@@ -210,7 +645,7 @@ impl PeerStore {
 			let batch = self.db.batch()?;
 
 			for peer in to_remove {
-				batch.delete(&peer_key(peer.addr)[..])?;
+				batch.delete(&peer_key(&peer.addr)[..])?;
 			}
 
 			batch.commit()?;
@@ -220,7 +655,137 @@ impl PeerStore {
 	}
 }
 
+fn version_key() -> Vec<u8> {
+	to_key(VERSION_PREFIX, &mut vec![])
+}
+
 // Ignore the port unless ip is loopback address.
-fn peer_key(peer_addr: PeerAddr) -> Vec<u8> {
+fn peer_key(peer_addr: &PeerAddr) -> Vec<u8> {
 	to_key(PEER_PREFIX, &mut peer_addr.as_key().into_bytes())
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::fs;
+	use std::net::SocketAddr;
+
+	fn peer(i: u8) -> PeerData {
+		PeerData {
+			addr: PeerAddr::Ip(SocketAddr::new([1, 2, 3, i].into(), 3414)),
+			capabilities: Capabilities::PEER_LIST,
+			user_agent: "test".to_string(),
+			flags: State::Healthy,
+			last_banned: 0,
+			ban_reason: ReasonForBan::None,
+			last_connected: 1234,
+			last_error: None,
+			last_attempted: 0,
+			failures: 0,
+			banned_until: 0,
+			last_seen: 1234,
+			last_direction: None,
+		}
+	}
+
+	// PeerData as version 1 stores first had it, nothing after the last
+	// connection time.
+	struct V1(PeerData);
+
+	impl Writeable for V1 {
+		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+			self.0.addr.write(writer)?;
+			writer.write_u32(self.0.capabilities.bits())?;
+			writer.write_bytes(&self.0.user_agent)?;
+			writer.write_u8(self.0.flags as u8)?;
+			writer.write_i64(self.0.last_banned)?;
+			writer.write_i32(self.0.ban_reason as i32)?;
+			writer.write_i64(self.0.last_connected)
+		}
+	}
+
+	// A store as version 1 left it: peers in the old layout, one of them
+	// corrupt, and no version saved.
+	fn v1_store(db_root: &str) -> PeerStore {
+		let _ = fs::remove_dir_all(db_root);
+		let store = PeerStore::new(db_root).unwrap();
+		let batch = store.db.batch().unwrap();
+		batch.delete(&version_key()[..]).unwrap();
+		for i in 0..3 {
+			batch
+				.put_ser(&peer_key(&peer(i).addr)[..], &V1(peer(i)))
+				.unwrap();
+		}
+		batch
+			.put(&peer_key(&peer(9).addr)[..], &[0xde, 0xad])
+			.unwrap();
+		batch.commit().unwrap();
+		store
+	}
+
+	fn raw(store: &PeerStore, i: u8) -> Option<Vec<u8>> {
+		store.db.get(&peer_key(&peer(i).addr)[..]).unwrap()
+	}
+
+	#[test]
+	fn migrate_v1_once() {
+		let db_root = ".grin_peer_store_migrate";
+		let store = v1_store(db_root);
+		assert_eq!(store.schema_version().unwrap(), 1);
+
+		assert_eq!(store.upgrade().unwrap(), 3);
+		assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+		for i in 0..3 {
+			assert_eq!(raw(&store, i), Some(ser::ser_vec(&peer(i)).unwrap()));
+		}
+		assert_eq!(raw(&store, 9), None);
+
+		// nothing left to do, here or once opened anew
+		assert_eq!(store.upgrade().unwrap(), 0);
+		drop(store);
+		let store = PeerStore::new(db_root).unwrap();
+		assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+		let mut peers = store.all_peers().unwrap();
+		peers.sort_by_key(|p| p.addr.to_string());
+		assert_eq!(
+			peers.iter().map(|p| p.addr.clone()).collect::<Vec<_>>(),
+			(0..3).map(|i| peer(i).addr).collect::<Vec<_>>()
+		);
+		assert!(peers.iter().all(|p| p.last_seen == 1234 && p.failures == 0));
+	}
+
+	#[test]
+	fn corrupt_record_skipped() {
+		let db_root = ".grin_peer_store_corrupt";
+		let store = v1_store(db_root);
+		assert_eq!(store.all_peers().unwrap().len(), 3);
+		assert_eq!(
+			store
+				.find_peers(State::Healthy, Capabilities::UNKNOWN, 10)
+				.unwrap()
+				.len(),
+			3
+		);
+		assert!(store.get_peer(peer(9).addr).is_err());
+	}
+
+	#[test]
+	fn future_version_refused() {
+		let db_root = ".grin_peer_store_future";
+		let _ = fs::remove_dir_all(db_root);
+		{
+			let store = PeerStore::new(db_root).unwrap();
+			store.save_peer(&peer(0)).unwrap();
+			let batch = store.db.batch().unwrap();
+			batch
+				.put_ser(&version_key()[..], &(SCHEMA_VERSION + 1))
+				.unwrap();
+			batch.commit().unwrap();
+		}
+		match PeerStore::new(db_root) {
+			Err(Error::VersionErr(_)) => {}
+			Err(e) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("opened a store from the future"),
+		}
+	}
+}
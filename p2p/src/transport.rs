@@ -0,0 +1,447 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypted transport, used when both peers advertise the ENCRYPTED
+//! capability. Right after the Shake each side sends an ephemeral X25519
+//! public key, the shared secret gives one ChaCha20-Poly1305 key per
+//! direction. Traffic is then split in frames of a 2 bytes length followed by
+//! the sealed payload.
+//!
+//! Peers aren't authenticated (there is nothing to authenticate them
+//! against), this only keeps passive observers from reading our traffic.
+
+use std::io::{self, Read, Write};
+use std::{cmp, mem};
+
+use ring::aead::{self, OpeningKey, SealingKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::rand::SystemRandom;
+use ring::{digest, hkdf, hmac};
+
+use crate::types::{Direction, Error};
+
+const PUBLIC_KEY_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Max payload of a single frame, larger writes are split.
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+const INITIATOR_INFO: &[u8] = b"grin p2p initiator";
+const RESPONDER_INFO: &[u8] = b"grin p2p responder";
+
+/// Keys for both directions of an encrypted connection.
+pub struct SessionKeys {
+	sealing: SealingKey,
+	opening: OpeningKey,
+}
+
+impl SessionKeys {
+	/// Exchanges ephemeral keys with the peer over the provided (freshly
+	/// handshaken) stream and derives our session keys.
+	pub fn exchange<S>(stream: &mut S, direction: Direction) -> Result<SessionKeys, Error>
+	where
+		S: Read + Write,
+	{
+		let rng = SystemRandom::new();
+		let private_key =
+			EphemeralPrivateKey::generate(&X25519, &rng).map_err(|_| Error::Internal)?;
+		let mut ours = [0u8; PUBLIC_KEY_LEN];
+		private_key
+			.compute_public_key(&mut ours)
+			.map_err(|_| Error::Internal)?;
+
+		stream.write_all(&ours)?;
+		let mut theirs = [0u8; PUBLIC_KEY_LEN];
+		stream.read_exact(&mut theirs)?;
+
+		// both sides need to agree on who's who for the salt and the keys
+		let (initiator, responder) = match direction {
+			Direction::Outbound => (&ours, &theirs),
+			Direction::Inbound => (&theirs, &ours),
+		};
+		let mut salt = initiator.to_vec();
+		salt.extend_from_slice(responder);
+		let salt = hmac::SigningKey::new(&digest::SHA256, &salt);
+
+		let (to_responder, to_initiator) = agreement::agree_ephemeral(
+			private_key,
+			&X25519,
+			untrusted::Input::from(&theirs),
+			Error::BadMessage,
+			|shared| {
+				let mut to_responder = [0u8; KEY_LEN];
+				let mut to_initiator = [0u8; KEY_LEN];
+				hkdf::extract_and_expand(&salt, shared, INITIATOR_INFO, &mut to_responder);
+				hkdf::extract_and_expand(&salt, shared, RESPONDER_INFO, &mut to_initiator);
+				Ok((to_responder, to_initiator))
+			},
+		)?;
+
+		let (seal, open) = match direction {
+			Direction::Outbound => (to_responder, to_initiator),
+			Direction::Inbound => (to_initiator, to_responder),
+		};
+		Ok(SessionKeys {
+			sealing: SealingKey::new(&CHACHA20_POLY1305, &seal).map_err(|_| Error::Internal)?,
+			opening: OpeningKey::new(&CHACHA20_POLY1305, &open).map_err(|_| Error::Internal)?,
+		})
+	}
+
+	/// Wraps the read and write halves of a connection with our keys.
+	pub fn wrap<R, W>(self, reader: R, writer: W) -> (EncryptedReader<R>, EncryptedWriter<W>)
+	where
+		R: Read,
+		W: Write,
+	{
+		(
+			EncryptedReader {
+				inner: reader,
+				key: self.opening,
+				nonce: 0,
+				len: [0u8; 2],
+				frame: vec![],
+				filled: 0,
+				buf: vec![],
+				pos: 0,
+			},
+			EncryptedWriter {
+				inner: writer,
+				key: self.sealing,
+				nonce: 0,
+				sealed: vec![],
+				written: 0,
+			},
+		)
+	}
+}
+
+// Frames are numbered in each direction, the nonce is the frame number.
+fn nonce(count: u64) -> [u8; NONCE_LEN] {
+	let mut nonce = [0u8; NONCE_LEN];
+	nonce[4..].copy_from_slice(&count.to_le_bytes());
+	nonce
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// A single read, retried when interrupted. The end of the stream is an error,
+// we're always in the middle of a frame.
+fn read_some<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+	loop {
+		match inner.read(buf) {
+			Ok(0) => {
+				return Err(io::Error::new(
+					io::ErrorKind::ConnectionAborted,
+					"encrypted stream closed",
+				));
+			}
+			Ok(n) => return Ok(n),
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/// Decrypts the frames read from the underlying stream. Behaves like the
+/// (non-blocking) stream it wraps: WouldBlock until a whole frame is in, what
+/// arrived of it meanwhile is kept for the next read.
+pub struct EncryptedReader<R> {
+	inner: R,
+	key: OpeningKey,
+	nonce: u64,
+	// length and sealed payload of the frame being read, `filled` bytes of
+	// both in so far
+	len: [u8; 2],
+	frame: Vec<u8>,
+	filled: usize,
+	// plaintext of the last frame and how much of it was read already
+	buf: Vec<u8>,
+	pos: usize,
+}
+
+impl<R: Read> EncryptedReader<R> {
+	// Reads the next frame as far as the stream has it, WouldBlock until it's
+	// all in.
+	fn read_frame(&mut self) -> io::Result<()> {
+		while self.filled < 2 {
+			self.filled += read_some(&mut self.inner, &mut self.len[self.filled..])?;
+		}
+		if self.frame.is_empty() {
+			let frame_len = u16::from_be_bytes(self.len) as usize;
+			if frame_len <= TAG_LEN || frame_len > MAX_FRAME_LEN + TAG_LEN {
+				return Err(invalid_data("bad encrypted frame length"));
+			}
+			self.frame = vec![0u8; frame_len];
+		}
+		while self.filled < 2 + self.frame.len() {
+			self.filled += read_some(&mut self.inner, &mut self.frame[self.filled - 2..])?;
+		}
+
+		let mut frame = mem::replace(&mut self.frame, vec![]);
+		self.filled = 0;
+		let plain_len =
+			aead::open_in_place(&self.key, &nonce(self.nonce), &self.len, 0, &mut frame)
+				.map_err(|_| invalid_data("encrypted frame failed to authenticate"))?
+				.len();
+		self.nonce += 1;
+		frame.truncate(plain_len);
+		self.buf = frame;
+		self.pos = 0;
+		Ok(())
+	}
+}
+
+impl<R: Read> Read for EncryptedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		if self.pos == self.buf.len() {
+			self.read_frame()?;
+		}
+		let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+		buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+		self.pos += n;
+		Ok(n)
+	}
+}
+
+/// Encrypts everything written into frames. Behaves like the (non-blocking)
+/// stream it wraps: a frame is sealed whole and written out as far as the
+/// stream takes it, the next write is WouldBlock until the rest of it went
+/// out. `flush` gets the last frame out.
+pub struct EncryptedWriter<W> {
+	inner: W,
+	key: SealingKey,
+	nonce: u64,
+	// the last frame sealed, up to `written` out already
+	sealed: Vec<u8>,
+	written: usize,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+	// Writes out what's left of the last frame, WouldBlock when the stream
+	// can't take it all.
+	fn write_sealed(&mut self) -> io::Result<()> {
+		while self.written < self.sealed.len() {
+			match self.inner.write(&self.sealed[self.written..]) {
+				Ok(0) => {
+					return Err(io::Error::new(
+						io::ErrorKind::WriteZero,
+						"failed to write encrypted frame",
+					));
+				}
+				Ok(n) => self.written += n,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => return Err(e),
+			}
+		}
+		self.sealed.clear();
+		self.written = 0;
+		Ok(())
+	}
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		self.write_sealed()?;
+		let n = cmp::min(buf.len(), MAX_FRAME_LEN);
+		let len = ((n + TAG_LEN) as u16).to_be_bytes();
+
+		let mut frame = Vec::with_capacity(2 + n + TAG_LEN);
+		frame.extend_from_slice(&len);
+		frame.extend_from_slice(&buf[..n]);
+		frame.extend_from_slice(&[0u8; TAG_LEN]);
+		aead::seal_in_place(
+			&self.key,
+			&nonce(self.nonce),
+			&len,
+			&mut frame[2..],
+			TAG_LEN,
+		)
+		.map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to seal frame"))?;
+		self.nonce += 1;
+
+		// the frame is ours to write out now, whatever the stream takes of it
+		self.sealed = frame;
+		if let Err(e) = self.write_sealed() {
+			if e.kind() != io::ErrorKind::WouldBlock {
+				return Err(e);
+			}
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.write_sealed()?;
+		self.inner.flush()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::net::{TcpListener, TcpStream};
+	use std::thread;
+
+	fn session() -> (SessionKeys, SessionKeys, TcpStream, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let keys = SessionKeys::exchange(&mut conn, Direction::Inbound).unwrap();
+			(keys, conn)
+		});
+		let mut conn = TcpStream::connect(addr).unwrap();
+		let keys = SessionKeys::exchange(&mut conn, Direction::Outbound).unwrap();
+		let (server_keys, server_conn) = server.join().unwrap();
+		(keys, server_keys, conn, server_conn)
+	}
+
+	#[test]
+	fn encrypted_round_trip() {
+		let (keys, server_keys, conn, server_conn) = session();
+		let (_, mut writer) = keys.wrap(conn.try_clone().unwrap(), conn);
+		let (mut reader, _) = server_keys.wrap(server_conn.try_clone().unwrap(), server_conn);
+
+		// more than a frame, split on write
+		let msg: Vec<u8> = (0..3 * MAX_FRAME_LEN).map(|i| i as u8).collect();
+		let sent = msg.clone();
+		let _ = thread::spawn(move || writer.write_all(&sent).unwrap());
+
+		let mut received = vec![0u8; msg.len()];
+		reader.read_exact(&mut received).unwrap();
+		assert_eq!(received, msg);
+	}
+
+	#[test]
+	fn encrypted_tampered() {
+		let (keys, server_keys, conn, server_conn) = session();
+
+		// seal a frame but flip a payload bit on the way
+		let mut sealed = vec![];
+		{
+			let (_, mut writer) = keys.wrap(io::empty(), &mut sealed);
+			writer.write_all(b"ping").unwrap();
+		}
+		sealed[3] ^= 1;
+		let mut conn = conn;
+		conn.write_all(&sealed).unwrap();
+
+		let (mut reader, _) = server_keys.wrap(server_conn.try_clone().unwrap(), server_conn);
+		let mut buf = [0u8; 4];
+		let err = reader.read_exact(&mut buf).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	// Gives out (or takes) at most `per_call` bytes at a time, and nothing
+	// every other time, like a non-blocking socket.
+	struct Trickle {
+		data: Vec<u8>,
+		per_call: usize,
+		ready: bool,
+	}
+
+	impl Trickle {
+		fn new(data: Vec<u8>, per_call: usize) -> Trickle {
+			Trickle {
+				data,
+				per_call,
+				ready: false,
+			}
+		}
+
+		fn would_block(&mut self) -> bool {
+			self.ready = !self.ready;
+			!self.ready
+		}
+	}
+
+	impl Read for Trickle {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			if self.data.is_empty() || self.would_block() {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, "trickle"));
+			}
+			let n = cmp::min(cmp::min(buf.len(), self.per_call), self.data.len());
+			buf[..n].copy_from_slice(&self.data[..n]);
+			self.data.drain(..n);
+			Ok(n)
+		}
+	}
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if self.would_block() {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, "trickle"));
+			}
+			let n = cmp::min(buf.len(), self.per_call);
+			self.data.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	// Neither side holds the connection thread up on a slow socket, they give
+	// WouldBlock and pick up from there next time.
+	#[test]
+	fn encrypted_non_blocking() {
+		let (keys, server_keys, _, _) = session();
+		let msg: Vec<u8> = (0..3 * MAX_FRAME_LEN).map(|i| i as u8).collect();
+
+		let (_, mut writer) = keys.wrap(io::empty(), Trickle::new(vec![], 1_000));
+		let mut taken = 0;
+		let mut would_block = 0;
+		while taken < msg.len() {
+			match writer.write(&msg[taken..]) {
+				Ok(n) => taken += n,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => would_block += 1,
+				Err(e) => panic!("write failed: {:?}", e),
+			}
+		}
+		// the last frame is only out once flushed
+		loop {
+			match writer.flush() {
+				Ok(()) => break,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => would_block += 1,
+				Err(e) => panic!("flush failed: {:?}", e),
+			}
+		}
+		assert!(would_block > 0);
+		let sealed = writer.inner.data;
+		assert_eq!(sealed.len(), msg.len() + 3 * (2 + TAG_LEN));
+
+		let (mut reader, _) = server_keys.wrap(Trickle::new(sealed, 1_000), io::sink());
+		let mut received = vec![];
+		let mut buf = [0u8; 4_000];
+		would_block = 0;
+		while received.len() < msg.len() {
+			match reader.read(&mut buf) {
+				Ok(n) => received.extend_from_slice(&buf[..n]),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => would_block += 1,
+				Err(e) => panic!("read failed: {:?}", e),
+			}
+		}
+		assert!(would_block > 0);
+		assert_eq!(received, msg);
+	}
+}
@@ -13,16 +13,25 @@
 // limitations under the License.
 
 use crate::util::RwLock;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::From;
 use std::fs::File;
 use std::io::{self, Read};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::panic;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use chrono::prelude::*;
+use rand::{thread_rng, Rng};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
 
 use crate::chain;
 use crate::core::core;
@@ -30,7 +39,10 @@ use crate::core::core::hash::Hash;
 use crate::core::global;
 use crate::core::pow::Difficulty;
 use crate::core::ser::{self, Readable, Reader, Writeable, Writer};
-use crate::msg::ProtocolVersion;
+use crate::dialer::{Dialer, Direct, Socks5};
+use crate::handshake::InboundSlot;
+use crate::msg::{DisconnectReason, PeerError, ProtocolVersion, Type};
+use crate::store::SelfAddr;
 use grin_store;
 
 /// Maximum number of block headers a peer should ever send
@@ -49,12 +61,156 @@ pub const MAX_LOCATORS: u32 = 20;
 /// How long a banned peer should be banned for
 const BAN_WINDOW: i64 = 10800;
 
+/// Misbehavior score past which a peer gets banned
+const BAN_SCORE_THRESHOLD: u32 = 100;
+
+/// How long a discouraged peer should stay discouraged
+const DISCOURAGE_WINDOW: i64 = 6 * 3600;
+
+/// Misbehavior score past which a peer gets discouraged, short of a ban
+const DISCOURAGE_SCORE_THRESHOLD: u32 = 50;
+
+/// Blocks failing validation a peer can send us before it gets banned
+const INVALID_BLOCK_STRIKES: u32 = 1;
+
+/// Blocks we refused for what may be our own fault a peer can send us before
+/// it gets banned
+const BORDERLINE_BLOCK_STRIKES: u32 = 3;
+
 /// The max peer count
 const PEER_MAX_COUNT: u32 = 125;
 
+/// How many peers can connect to us at most, further connections are
+/// refused right away
+const MAX_INBOUND: u32 = 117;
+
+/// How many peers we connect to at most
+const MAX_OUTBOUND: u32 = 16;
+
 /// min preferred peer count
 const PEER_MIN_PREFERRED_COUNT: u32 = 8;
 
+/// How long (in seconds) we give a peer to complete the handshake
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// How many handshakes (inbound and outbound) we run at most concurrently
+const MAX_INFLIGHT_HANDSHAKES: usize = 32;
+
+/// How many inbound connections we take from a single ip
+const MAX_INBOUND_PER_IP: u32 = 3;
+
+/// How many inbound connections we take from a single /24 (ipv4) or /48
+/// (ipv6) network
+const MAX_INBOUND_PER_SUBNET: u32 = 8;
+
+/// How often (in seconds) we ping our peers
+const PING_INTERVAL: u64 = 10;
+
+/// How many peers supporting block announcements get a relayed block pushed
+/// to them, the others only get its hash
+const BLOCK_FANOUT: u32 = 3;
+
+/// How many peers (picked at random) a block or tx we relay goes to, the
+/// other peers get it from them
+const RELAY_FANOUT: u32 = 8;
+
+/// How many bytes we queue at most for a peer to read, past that it's too
+/// slow and we stop sending it what it can do without
+const MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+
+/// How many header requests a peer may send us every second
+const HEADER_REQUESTS_PER_SEC: u32 = 10;
+
+/// How many peer address requests a peer may send us every minute, our own
+/// peer monitoring asks every 20s
+const PEER_ADDR_REQUESTS_PER_MIN: u32 = 6;
+
+/// How many txhashset (or kernel data) requests a peer may send us every
+/// minute
+const TXHASHSET_REQUESTS_PER_MIN: u32 = 2;
+
+/// How long (in seconds) a connection can go without us sending anything
+/// before we ping the peer to keep it alive
+const KEEPALIVE_INTERVAL: u64 = 60;
+
+/// How long (in seconds) a connection can go without a single msg from the
+/// peer before we consider it dead
+const IDLE_TIMEOUT: u64 = 5 * 60;
+
+/// How often (in seconds) we sweep our peers, dropping the unresponsive ones
+const MONITOR_INTERVAL: u64 = 25;
+
+/// How long (in seconds) we give a peer to send the block we asked for
+/// before asking another peer that announced it
+const BLOCK_REQUEST_TIMEOUT: u64 = 5;
+
+/// How many outbound connections we keep up, dialing peers from our store
+/// when we're short
+const OUTBOUND_TARGET: u32 = 8;
+
+/// How many peers we're dialing at most at the same time to get there
+const MAX_CONCURRENT_DIALS: u32 = 4;
+
+/// How often (in seconds) we send each peer a few addresses of peers we
+/// recently connected to, without it asking
+const GOSSIP_INTERVAL: u64 = 10 * 60;
+
+/// How often (in seconds) we dial a preferred peer we're not connected to,
+/// whether it failed us or not
+const PREFERRED_REDIAL_INTERVAL: u64 = 10;
+
+/// How many days a healthy peer can go unseen before we stop dialing it
+/// first, marking it defunct
+const PEER_DEMOTE_DAYS: u64 = 3;
+
+/// How many days a defunct (or never verified) peer can go unseen before we
+/// forget about it
+const PEER_EXPIRE_DAYS: u64 = 14;
+
+/// How many peers we keep in our store at most, the worst ones pruned past
+/// that
+const PEER_STORE_MAX: usize = 20_000;
+
+/// How long we wait before redialing a peer that timed out or dropped the
+/// connection, the first step of our backoff schedule
+pub const REDIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long we wait before redialing a peer that closed the connection in
+/// good order as it was shutting down or full, not counted as a failure
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2 * 60);
+
+/// How long we wait before redialing a peer after each consecutive failure,
+/// the last step over and over once we get there
+const REDIAL_SCHEDULE: [u64; 6] = [30, 2 * 60, 10 * 60, 3600, 6 * 3600, 24 * 3600];
+
+/// How long we wait before redialing a peer that failed us that many times
+/// in a row. No wait before the first failure.
+pub fn redial_backoff(failures: u32) -> Duration {
+	if failures == 0 {
+		return Duration::from_secs(0);
+	}
+	let step = cmp::min(failures as usize, REDIAL_SCHEDULE.len()) - 1;
+	Duration::from_secs(REDIAL_SCHEDULE[step])
+}
+
+/// How many failures in a row get us to wait at least that long, for the
+/// failures that deserve a longer wait to begin with.
+pub fn redial_failures(wait: Duration) -> u32 {
+	(1..REDIAL_SCHEDULE.len() as u32)
+		.find(|n| redial_backoff(*n) >= wait)
+		.unwrap_or(REDIAL_SCHEDULE.len() as u32)
+}
+
+/// How long we wait before redialing a peer with no protocol version in common
+pub const REDIAL_VERSION_MISMATCH: Duration = Duration::from_secs(24 * 3600);
+
+/// How long we remember one of our own addresses, public ips change
+pub const SELF_ADDR_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// How many of our own addresses we remember at most, 10 should be enough
+/// since most of servers don't have more than 10 IP addresses.
+const SELF_ADDRS_CAP: usize = 10;
+
 #[derive(Debug)]
 pub enum Error {
 	Serialization(ser::Error),
@@ -73,6 +229,48 @@ pub enum Error {
 		us: Hash,
 		peer: Hash,
 	},
+	/// No protocol version is supported by both sides, ranges are (min, max).
+	ProtocolMismatch {
+		ours: (ProtocolVersion, ProtocolVersion),
+		theirs: (ProtocolVersion, ProtocolVersion),
+	},
+	/// The negotiated protocol version has no implementation
+	UnsupportedProtocol(ProtocolVersion),
+	/// Too many handshakes are already in progress
+	TooManyHandshakes,
+	/// We have as many inbound connections from the ip or network as we take
+	InboundLimit(String),
+	/// We have as many connections as we want in that direction
+	TooManyPeers,
+	/// We already have a live connection to this peer
+	DuplicateConnection,
+	/// We're connecting to this peer already
+	AlreadyConnecting,
+	/// Claimed height and total difficulty cannot both be true
+	ImplausibleChain {
+		height: u64,
+		total_difficulty: Difficulty,
+	},
+	/// Message header magic is not the one of our network
+	WrongNetwork,
+	/// Message body doesn't match the checksum of its header, mangled on the
+	/// way rather than sent like that on purpose
+	Corruption,
+	/// Another node uses our node id (copied data directory), we pick a new one
+	/// once a few did
+	NodeIdCollision,
+	/// The handshake was interrupted, we're shutting down
+	Cancelled,
+	/// Our SOCKS5 proxy failed us, nothing to do with the peer
+	Proxy(String),
+	/// The peer closed the connection, telling us why
+	Disconnected(ReasonForBan),
+	/// The peer closed the connection in good order, telling us why
+	Closed(DisconnectReason),
+	/// The peer sent requests faster than we serve them
+	RateLimited,
+	/// The peer doesn't read what we send fast enough, the msg wasn't queued
+	QueueFull,
 	Send(String),
 	PeerException,
 	Internal,
@@ -104,188 +302,1306 @@ impl<T> From<mpsc::TrySendError<T>> for Error {
 	}
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct PeerAddr(pub SocketAddr);
+impl Error {
+	/// What to do with a peer we failed to connect to with this error.
+	pub fn retry_policy(&self) -> RetryPolicy {
+		HandshakeFailure::from(self).retry_policy()
+	}
+}
+
+/// Why connecting to a peer failed, as far as deciding whether to try again
+/// goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeFailure {
+	/// Took too long, the peer or the link may just be slow
+	Timeout,
+	/// Couldn't connect or the connection dropped
+	Io,
+	/// Too many handshakes in progress or already connected
+	Busy,
+	/// Peer is on another network or chain
+	Incompatible,
+	/// No protocol version in common, until one of us upgrades
+	VersionMismatch,
+	/// We reached ourselves
+	SelfConnection,
+	/// We banned the peer already
+	Banned,
+	/// Peer sent garbage or broke the protocol
+	ProtocolViolation,
+	/// Nothing to do with the peer (shutting down, store errors...)
+	Local,
+}
 
+/// What to do with a peer after a failed connection attempt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryPolicy {
+	/// Try again whenever, nothing wrong with the peer
+	RetrySoon,
+	/// Wait at least that long (then backing off) before trying again
+	RetryLater(Duration),
+	/// Don't ever dial the peer again
+	Never,
+	/// Ban the peer
+	Ban,
+}
+
+impl HandshakeFailure {
+	pub fn retry_policy(&self) -> RetryPolicy {
+		match self {
+			HandshakeFailure::Timeout | HandshakeFailure::Io => {
+				RetryPolicy::RetryLater(REDIAL_BACKOFF)
+			}
+			HandshakeFailure::VersionMismatch => RetryPolicy::RetryLater(REDIAL_VERSION_MISMATCH),
+			HandshakeFailure::Incompatible => RetryPolicy::Never,
+			// our own addresses are kept away until they expire
+			HandshakeFailure::SelfConnection => RetryPolicy::RetrySoon,
+			HandshakeFailure::ProtocolViolation => RetryPolicy::Ban,
+			// the ban itself keeps the peer away
+			HandshakeFailure::Banned => RetryPolicy::RetrySoon,
+			HandshakeFailure::Busy | HandshakeFailure::Local => RetryPolicy::RetrySoon,
+		}
+	}
+}
+
+impl<'a> From<&'a Error> for HandshakeFailure {
+	fn from(e: &'a Error) -> HandshakeFailure {
+		match e {
+			Error::Timeout => HandshakeFailure::Timeout,
+			Error::Connection(_) | Error::Corruption => HandshakeFailure::Io,
+			Error::TooManyHandshakes
+			| Error::TooManyPeers
+			| Error::DuplicateConnection
+			| Error::AlreadyConnecting
+			| Error::InboundLimit(_) => HandshakeFailure::Busy,
+			Error::WrongNetwork
+			| Error::GenesisMismatch { .. }
+			| Error::Disconnected(ReasonForBan::WrongNetwork) => HandshakeFailure::Incompatible,
+			Error::Disconnected(_) => HandshakeFailure::Io,
+			Error::Closed(DisconnectReason::Duplicate) => HandshakeFailure::Busy,
+			Error::Closed(DisconnectReason::None) => HandshakeFailure::Local,
+			Error::Closed(_) => HandshakeFailure::Io,
+			Error::ProtocolMismatch { .. } | Error::UnsupportedProtocol(_) => {
+				HandshakeFailure::VersionMismatch
+			}
+			Error::PeerWithSelf => HandshakeFailure::SelfConnection,
+			Error::Banned => HandshakeFailure::Banned,
+			Error::Serialization(_)
+			| Error::BadMessage
+			| Error::MsgLen
+			| Error::ImplausibleChain { .. }
+			| Error::RateLimited => HandshakeFailure::ProtocolViolation,
+			Error::ConnectionClose
+			| Error::NodeIdCollision
+			| Error::Cancelled
+			| Error::Proxy(_)
+			| Error::Store(_)
+			| Error::Chain(_)
+			| Error::NoDandelionRelay
+			| Error::Send(_)
+			| Error::QueueFull
+			| Error::PeerException
+			| Error::Internal => HandshakeFailure::Local,
+		}
+	}
+}
+
+/// Our own addresses, detected through self connections or peers reaching us
+/// at the address we advertise. We never dial them nor save them from gossip,
+/// until they expire.
+pub struct SelfAddrs {
+	addrs: RwLock<HashMap<PeerAddr, DateTime<Utc>>>,
+	changed: AtomicBool,
+	// where our port is mapped on our NAT gateway, if it is
+	mapped: RwLock<Option<PeerAddr>>,
+}
+
+impl SelfAddrs {
+	pub fn new() -> SelfAddrs {
+		SelfAddrs {
+			addrs: RwLock::new(HashMap::new()),
+			changed: AtomicBool::new(false),
+			mapped: RwLock::new(None),
+		}
+	}
+
+	/// Records the address our port is mapped at on our NAT gateway, or that
+	/// we lost it. It's one of ours as well.
+	pub fn set_mapped(&self, addr: Option<PeerAddr>) {
+		if let Some(ref addr) = addr {
+			self.insert(addr.clone());
+		}
+		*self.mapped.write() = addr;
+	}
+
+	/// The address we advertise to our peers: the configured one if any,
+	/// else the one our port is mapped at, else our listen address.
+	pub fn advertise_addr(&self, config: &P2PConfig) -> PeerAddr {
+		if config.advertise_addr.is_none() {
+			if let Some(ref addr) = *self.mapped.read() {
+				return addr.clone();
+			}
+		}
+		config.advertise_addr()
+	}
+
+	/// Remembers one of our addresses for another SELF_ADDR_TTL, forgetting
+	/// the one closest to expiry if we have too many.
+	pub fn insert(&self, addr: PeerAddr) {
+		let now = Utc::now();
+		let mut addrs = self.addrs.write();
+		addrs.retain(|_, expires| *expires > now);
+		if !addrs.contains_key(&addr) && addrs.len() >= SELF_ADDRS_CAP {
+			let oldest = addrs
+				.iter()
+				.min_by_key(|(_, expires)| **expires)
+				.map(|(addr, _)| addr.clone());
+			if let Some(oldest) = oldest {
+				addrs.remove(&oldest);
+			}
+		}
+		let ttl = chrono::Duration::from_std(SELF_ADDR_TTL).unwrap();
+		addrs.insert(addr, now + ttl);
+		self.changed.store(true, Ordering::Relaxed);
+	}
+
+	/// Whether the address is one of ours.
+	pub fn contains(&self, addr: &PeerAddr) -> bool {
+		match self.addrs.read().get(addr) {
+			Some(expires) => *expires > Utc::now(),
+			None => false,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		let now = Utc::now();
+		self.addrs.read().values().filter(|e| **e > now).count()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Restores previously saved addresses, skipping the expired ones.
+	pub fn load(&self, entries: Vec<SelfAddr>) {
+		let now = Utc::now();
+		let mut addrs = self.addrs.write();
+		for entry in entries {
+			let expires = Utc.timestamp(entry.expires, 0);
+			if expires > now {
+				addrs.insert(entry.addr, expires);
+			}
+		}
+	}
+
+	/// All our current addresses, if they changed since the last call (so
+	/// they need saving).
+	pub fn changed(&self) -> Option<Vec<SelfAddr>> {
+		if !self.changed.swap(false, Ordering::Relaxed) {
+			return None;
+		}
+		let now = Utc::now();
+		Some(
+			self.addrs
+				.read()
+				.iter()
+				.filter(|(_, expires)| **expires > now)
+				.map(|(addr, expires)| SelfAddr {
+					addr: addr.clone(),
+					expires: expires.timestamp(),
+				})
+				.collect(),
+		)
+	}
+}
+
+/// Maximum length of a DNS name or onion address in a PeerAddr
+pub const MAX_HOST_LEN: usize = 255;
+
+/// Address of a peer. Either an ip socket address, a DNS name (with port)
+/// resolved when we dial it, or a Tor onion service.
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+	Ip(SocketAddr),
+	Dns(String, u16),
+	Onion(String, u16),
+}
+
+/// On the wire, tags 0 and 1 are the legacy encoding of an ipv4 or ipv6
+/// address so ip addresses are still understood by older peers. Ip addresses
+/// are written in their canonical form, an ipv6 address is its 16 bytes and
+/// port only.
 impl Writeable for PeerAddr {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
-		match self.0 {
-			SocketAddr::V4(sav4) => {
-				ser_multiwrite!(
-					writer,
-					[write_u8, 0],
-					[write_fixed_bytes, &sav4.ip().octets().to_vec()],
-					[write_u16, sav4.port()]
-				);
+		match self {
+			PeerAddr::Ip(addr) => match canonical_addr(addr) {
+				SocketAddr::V4(sav4) => {
+					ser_multiwrite!(
+						writer,
+						[write_u8, 0],
+						[write_fixed_bytes, &sav4.ip().octets().to_vec()],
+						[write_u16, sav4.port()]
+					);
+				}
+				SocketAddr::V6(sav6) => {
+					writer.write_u8(1)?;
+					for seg in &sav6.ip().segments() {
+						writer.write_u16(*seg)?;
+					}
+					writer.write_u16(sav6.port())?;
+				}
+			},
+			PeerAddr::Dns(host, port) => {
+				writer.write_u8(2)?;
+				writer.write_bytes(host)?;
+				writer.write_u16(*port)?;
+			}
+			PeerAddr::Onion(host, port) => {
+				writer.write_u8(3)?;
+				writer.write_bytes(host)?;
+				writer.write_u16(*port)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Readable for PeerAddr {
+	fn read(reader: &mut dyn Reader) -> Result<PeerAddr, ser::Error> {
+		match reader.read_u8()? {
+			0 => {
+				let ip = reader.read_fixed_bytes(4)?;
+				let port = reader.read_u16()?;
+				Ok(PeerAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
+					Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
+					port,
+				))))
+			}
+			1 => {
+				let ip = try_iter_map_vec!(0..8, |_| reader.read_u16());
+				let port = reader.read_u16()?;
+				let addr = SocketAddr::V6(SocketAddrV6::new(
+					Ipv6Addr::new(ip[0], ip[1], ip[2], ip[3], ip[4], ip[5], ip[6], ip[7]),
+					port,
+					0,
+					0,
+				));
+				Ok(PeerAddr::Ip(canonical_addr(&addr)))
+			}
+			2 => {
+				let host = read_host(reader)?;
+				let port = reader.read_u16()?;
+				Ok(PeerAddr::Dns(host, port))
 			}
-			SocketAddr::V6(sav6) => {
-				writer.write_u8(1)?;
-				for seg in &sav6.ip().segments() {
-					writer.write_u16(*seg)?;
+			3 => {
+				let host = read_host(reader)?;
+				let port = reader.read_u16()?;
+				Ok(PeerAddr::Onion(host, port))
+			}
+			_ => Err(ser::Error::CorruptedData),
+		}
+	}
+}
+
+/// Read a host name, checking its length before allocating anything.
+fn read_host(reader: &mut dyn Reader) -> Result<String, ser::Error> {
+	let len = reader.read_u64()?;
+	if len == 0 || len > MAX_HOST_LEN as u64 {
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	let bytes = reader.read_fixed_bytes(len as usize)?;
+	String::from_utf8(bytes).map_err(|_| ser::Error::CorruptedData)
+}
+
+impl std::hash::Hash for PeerAddr {
+	/// If loopback address then we care about ip and port.
+	/// If regular address then we only care about the ip and ignore the port.
+	/// DNS names and onion addresses are case insensitive.
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		match self {
+			PeerAddr::Ip(addr) => {
+				let addr = canonical_addr(addr);
+				if addr.ip().is_loopback() {
+					addr.hash(state);
+				} else {
+					addr.ip().hash(state);
+				}
+			}
+			PeerAddr::Dns(host, port) | PeerAddr::Onion(host, port) => {
+				host.to_ascii_lowercase().hash(state);
+				port.hash(state);
+			}
+		}
+	}
+}
+
+impl PartialEq for PeerAddr {
+	/// If loopback address then we care about ip and port.
+	/// If regular address then we only care about the ip and ignore the port.
+	/// A DNS name is never equal to an ip address, even one it resolves to.
+	fn eq(&self, other: &PeerAddr) -> bool {
+		match (self, other) {
+			(PeerAddr::Ip(a), PeerAddr::Ip(b)) => {
+				let (a, b) = (canonical_addr(a), canonical_addr(b));
+				if a.ip().is_loopback() {
+					a == b
+				} else {
+					a.ip() == b.ip()
+				}
+			}
+			(PeerAddr::Dns(h1, p1), PeerAddr::Dns(h2, p2))
+			| (PeerAddr::Onion(h1, p1), PeerAddr::Onion(h2, p2)) => p1 == p2 && h1.eq_ignore_ascii_case(h2),
+			_ => false,
+		}
+	}
+}
+
+impl Eq for PeerAddr {}
+
+impl std::fmt::Display for PeerAddr {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			PeerAddr::Ip(addr) => write!(f, "{}", addr),
+			PeerAddr::Dns(host, port) | PeerAddr::Onion(host, port) => {
+				write!(f, "{}:{}", host, port)
+			}
+		}
+	}
+}
+
+impl FromStr for PeerAddr {
+	type Err = String;
+
+	/// Parses an ip socket address, or else a "host:port" DNS name or onion
+	/// address.
+	fn from_str(s: &str) -> Result<PeerAddr, String> {
+		if let Ok(addr) = s.parse::<SocketAddr>() {
+			return Ok(PeerAddr::Ip(addr));
+		}
+		let mut parts = s.rsplitn(2, ':');
+		let port = parts.next().and_then(|p| p.parse::<u16>().ok());
+		match (parts.next(), port) {
+			(Some(host), Some(port)) if !host.is_empty() && host.len() <= MAX_HOST_LEN => {
+				if host.to_ascii_lowercase().ends_with(".onion") {
+					Ok(PeerAddr::Onion(host.to_string(), port))
+				} else {
+					Ok(PeerAddr::Dns(host.to_string(), port))
 				}
-				writer.write_u16(sav6.port())?;
 			}
+			_ => Err(format!("invalid peer address {}", s)),
+		}
+	}
+}
+
+/// Serialized as a string (as a plain socket address used to be) so config
+/// files and the api are unchanged for ip addresses.
+impl Serialize for PeerAddr {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for PeerAddr {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PeerAddr, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(de::Error::custom)
+	}
+}
+
+impl PeerAddr {
+	/// Convenient way of constructing a new peer_addr from an ip_addr
+	/// defaults to port 3414 on mainnet and 13414 on floonet.
+	pub fn from_ip(addr: IpAddr) -> PeerAddr {
+		let port = if global::is_floonet() { 13414 } else { 3414 };
+		PeerAddr::Ip(SocketAddr::new(addr, port))
+	}
+
+	/// The socket address, only for an ip address.
+	pub fn ip_addr(&self) -> Option<SocketAddr> {
+		match self {
+			PeerAddr::Ip(addr) => Some(*addr),
+			_ => None,
+		}
+	}
+
+	/// The advertised port, whatever the kind of address.
+	pub fn port(&self) -> u16 {
+		match self {
+			PeerAddr::Ip(addr) => addr.port(),
+			PeerAddr::Dns(_, port) | PeerAddr::Onion(_, port) => *port,
+		}
+	}
+
+	/// Socket addresses to dial to reach this peer. DNS names are resolved
+	/// here (possibly to multiple addresses), onion addresses can't be dialed
+	/// without a Tor proxy.
+	pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+		match self {
+			PeerAddr::Ip(addr) => Ok(vec![*addr]),
+			PeerAddr::Dns(host, port) => Ok((host.as_str(), *port).to_socket_addrs()?.collect()),
+			PeerAddr::Onion(_, _) => Err(io::Error::new(
+				io::ErrorKind::Other,
+				"onion addresses require a tor proxy",
+			)),
+		}
+	}
+
+	/// Whether the address makes sense to store and gossip, see
+	/// `is_routable`. DNS names and onion addresses can't be checked and are
+	/// assumed fine.
+	pub fn is_routable(&self) -> bool {
+		match self {
+			PeerAddr::Ip(addr) => is_routable(addr),
+			PeerAddr::Dns(_, _) | PeerAddr::Onion(_, _) => true,
+		}
+	}
+
+	/// If the ip is loopback then our key is "ip:port" (mainly for local usernet testing).
+	/// Otherwise we only care about the ip (we disallow multiple peers on the same ip address).
+	/// DNS names and onion addresses are keyed on their lowercase "host:port".
+	pub fn as_key(&self) -> String {
+		match self {
+			PeerAddr::Ip(addr) => {
+				let addr = canonical_addr(addr);
+				if addr.ip().is_loopback() {
+					format!("{}:{}", addr.ip(), addr.port())
+				} else {
+					format!("{}", addr.ip())
+				}
+			}
+			PeerAddr::Dns(host, port) | PeerAddr::Onion(host, port) => {
+				format!("{}:{}", host.to_ascii_lowercase(), port)
+			}
+		}
+	}
+}
+
+/// Whether a peer could possibly be reached at the address: the port isn't 0
+/// and the ip passes `is_routable_ip`.
+pub fn is_routable(addr: &SocketAddr) -> bool {
+	addr.port() != 0 && is_routable_ip(&addr.ip())
+}
+
+/// Whether the ip can be the one of a peer. Multicast, broadcast,
+/// documentation and 0.0.0.0/8 (or unspecified) ips never are. Loopback and
+/// private ips are reachable by some nodes at least, so they are fine.
+pub fn is_routable_ip(ip: &IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => is_routable_v4(ip),
+		IpAddr::V6(ip) => {
+			if let Some(v4) = ipv4_mapped(ip) {
+				// same rules as ipv4
+				return is_routable_v4(&v4);
+			}
+			let segments = ip.segments();
+			// documentation range is 2001:db8::/32
+			let documentation = segments[0] == 0x2001 && segments[1] == 0x0db8;
+			!(ip.is_unspecified() || ip.is_multicast() || documentation)
+		}
+	}
+}
+
+fn is_routable_v4(ip: &Ipv4Addr) -> bool {
+	!(ip.octets()[0] == 0 || ip.is_multicast() || ip.is_broadcast() || ip.is_documentation())
+}
+
+/// A range of ips, as "10.0.0.0/8" or "fd00::/8".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpRange {
+	ip: IpAddr,
+	prefix: u8,
+}
+
+impl IpRange {
+	/// Whether the ip is in the range, ipv4-mapped ipv6 ones counting as
+	/// ipv4.
+	pub fn contains(&self, ip: &IpAddr) -> bool {
+		let ip = canonical_ip(ip);
+		ip.is_ipv4() == self.ip.is_ipv4() && mask_ip(&ip, self.prefix) == self.ip
+	}
+}
+
+impl std::fmt::Display for IpRange {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}/{}", self.ip, self.prefix)
+	}
+}
+
+impl FromStr for IpRange {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<IpRange, String> {
+		let mut parts = s.splitn(2, '/');
+		let ip = parts.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+		let prefix = parts.next().and_then(|p| p.parse::<u8>().ok());
+		match (ip, prefix) {
+			(Some(ip), Some(prefix)) => {
+				let ip = canonical_ip(&ip);
+				let max = if ip.is_ipv4() { 32 } else { 128 };
+				if prefix > max {
+					return Err(format!("invalid ip range {}", s));
+				}
+				Ok(IpRange {
+					ip: mask_ip(&ip, prefix),
+					prefix,
+				})
+			}
+			_ => Err(format!("invalid ip range {}", s)),
+		}
+	}
+}
+
+impl Serialize for IpRange {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<IpRange, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(de::Error::custom)
+	}
+}
+
+/// The network the ip is part of as far as our inbound limits go, its /24
+/// for ipv4 and its /48 for ipv6.
+pub fn subnet(ip: &IpAddr) -> IpRange {
+	let ip = canonical_ip(ip);
+	let prefix = if ip.is_ipv4() { 24 } else { 48 };
+	IpRange {
+		ip: mask_ip(&ip, prefix),
+		prefix,
+	}
+}
+
+/// The broader network group the ip is part of, its /16 for ipv4 and its /32
+/// for ipv6. Peers from many groups are harder to all be run by the same
+/// party.
+pub fn netgroup(ip: &IpAddr) -> IpRange {
+	let ip = canonical_ip(ip);
+	let prefix = if ip.is_ipv4() { 16 } else { 32 };
+	IpRange {
+		ip: mask_ip(&ip, prefix),
+		prefix,
+	}
+}
+
+/// The ip with only its first `prefix` bits kept, ipv4-mapped ipv6 ones as
+/// ipv4.
+fn mask_ip(ip: &IpAddr, prefix: u8) -> IpAddr {
+	let prefix = prefix as u32;
+	match canonical_ip(ip) {
+		IpAddr::V4(ip) => {
+			let mask = u32::max_value().checked_shl(32 - prefix).unwrap_or(0);
+			IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+		}
+		IpAddr::V6(ip) => {
+			let mask = u128::max_value().checked_shl(128 - prefix).unwrap_or(0);
+			IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+		}
+	}
+}
+
+/// The ip, as plain ipv4 if it's an ipv4-mapped ipv6 one.
+fn canonical_ip(ip: &IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6(v6) => ipv4_mapped(v6).map_or(*ip, IpAddr::V4),
+		IpAddr::V4(_) => *ip,
+	}
+}
+
+/// The ipv4 address of an ipv4-mapped ipv6 one (::ffff:a.b.c.d).
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+	let segments = ip.segments();
+	if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+		Some(Ipv4Addr::new(
+			(segments[6] >> 8) as u8,
+			segments[6] as u8,
+			(segments[7] >> 8) as u8,
+			segments[7] as u8,
+		))
+	} else {
+		None
+	}
+}
+
+/// The one form of a socket address we serialize and compare: ipv4-mapped
+/// ipv6 addresses (as seen by dual stack sockets) are plain ipv4 ones, and
+/// the ipv6 flow info and scope id are zeroed as they only make sense on the
+/// host that set them.
+pub fn canonical_addr(addr: &SocketAddr) -> SocketAddr {
+	match addr {
+		SocketAddr::V4(_) => *addr,
+		SocketAddr::V6(sav6) => match ipv4_mapped(sav6.ip()) {
+			Some(v4) => SocketAddr::V4(SocketAddrV4::new(v4, sav6.port())),
+			None => SocketAddr::V6(SocketAddrV6::new(*sav6.ip(), sav6.port(), 0, 0)),
+		},
+	}
+}
+
+/// Random identifier of a node, persisted in the peer store so it survives
+/// restarts. Tells us we reached ourselves, whatever address we dialed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; 16]);
+
+impl NodeId {
+	/// Generates a new random node id.
+	pub fn random() -> NodeId {
+		let mut id = [0u8; 16];
+		thread_rng().fill(&mut id);
+		NodeId(id)
+	}
+}
+
+impl Writeable for NodeId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&self.0.to_vec())
+	}
+}
+
+impl Readable for NodeId {
+	fn read(reader: &mut dyn Reader) -> Result<NodeId, ser::Error> {
+		let bytes = reader.read_fixed_bytes(16)?;
+		let mut id = [0u8; 16];
+		id.copy_from_slice(&bytes);
+		Ok(NodeId(id))
+	}
+}
+
+impl std::fmt::Display for NodeId {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		for b in self.0.iter() {
+			write!(f, "{:02x}", b)?;
+		}
+		Ok(())
+	}
+}
+
+/// Configuration for the peer-to-peer server.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct P2PConfig {
+	pub host: IpAddr,
+	pub port: u16,
+
+	/// The addresses we listen on, host and port by default
+	pub listen_addrs: Option<Vec<SocketAddr>>,
+
+	/// The address we tell our peers to reach us at, host and port by default
+	pub advertise_addr: Option<PeerAddr>,
+
+	/// Whether we map our port on our NAT gateway, through UPnP or NAT-PMP,
+	/// advertising the address we get unless one is configured
+	pub port_mapping: Option<bool>,
+
+	/// Method used to get the list of seed nodes for initial bootstrap.
+	#[serde(default)]
+	pub seeding_type: Seeding,
+
+	/// The list of seed nodes, if using Seeding as a seed type
+	pub seeds: Option<Vec<PeerAddr>>,
+
+	/// The DNS names to get our first peers from, if using DNSSeed as a seed
+	/// type. Defaults to the seeds of the network we're on.
+	pub dns_seeds: Option<Vec<String>>,
+
+	/// Capabilities expose by this node, also conditions which other peers this
+	/// node will have an affinity toward when connection.
+	pub capabilities: Capabilities,
+
+	pub peers_allow: Option<Vec<PeerAddr>>,
+
+	pub peers_deny: Option<Vec<PeerAddr>>,
+
+	/// The list of preferred peers that we will try to connect to, always,
+	/// on top of our outbound target. Never dropped for having too many peers.
+	pub peers_preferred: Option<Vec<PeerAddr>>,
+
+	/// File to merge peers from into our store at startup, as written to
+	/// `peers_export_file`
+	pub peers_import_file: Option<String>,
+
+	/// File to write the peers in our store to when we stop
+	pub peers_export_file: Option<String>,
+
+	pub ban_window: Option<i64>,
+
+	/// Misbehavior score past which a peer gets banned, see `Offense`
+	pub ban_score_threshold: Option<u32>,
+
+	/// How long a discouraged peer stays discouraged, see
+	/// `Peers::discourage_peer`
+	pub discourage_window: Option<i64>,
+
+	/// Misbehavior score past which a peer gets discouraged, short of the
+	/// ban threshold
+	pub discourage_score_threshold: Option<u32>,
+
+	/// How many blocks failing validation get a peer banned
+	pub invalid_block_strikes: Option<u32>,
+
+	/// How many blocks refused for what may be our own fault, like a
+	/// timestamp ahead of our clock, get a peer banned
+	pub borderline_block_strikes: Option<u32>,
+
+	pub peer_max_count: Option<u32>,
+
+	/// How many peers can connect to us at most
+	pub max_inbound: Option<u32>,
+
+	/// What we do with a new inbound peer once we have as many as we take
+	pub inbound_eviction: Option<InboundEviction>,
+
+	/// How many peers we connect to at most
+	pub max_outbound: Option<u32>,
+
+	pub peer_min_preferred_count: Option<u32>,
+
+	pub dandelion_peer: Option<PeerAddr>,
+
+	/// How long (in seconds) a peer has to complete the full handshake
+	pub handshake_timeout: Option<u64>,
+
+	/// Maximum number of handshakes in progress at the same time
+	pub max_inflight_handshakes: Option<usize>,
+
+	/// Maximum number of inbound connections from a single ip
+	pub max_inbound_per_ip: Option<u32>,
+
+	/// Maximum number of inbound connections from a single /24 (ipv4) or /48
+	/// (ipv6) network
+	pub max_inbound_per_subnet: Option<u32>,
+
+	/// Ranges the inbound connections of aren't limited by ip or network,
+	/// loopback always being exempt
+	pub inbound_limit_exempt: Option<Vec<IpRange>>,
+
+	/// SOCKS5 proxy all our outbound connections go through, if any
+	pub socks5_proxy: Option<Socks5>,
+
+	/// How often (in seconds) we ping our peers, sharing our total difficulty
+	/// and height and learning theirs
+	pub ping_interval: Option<u64>,
+
+	/// How many peers supporting block announcements get the blocks we relay,
+	/// the others are only announced their hash
+	pub block_fanout: Option<u32>,
+
+	/// How many of our peers, picked at random for each, get the blocks and
+	/// txs we relay
+	pub relay_fanout: Option<u32>,
+
+	/// How many bytes we queue at most for a peer to read
+	pub max_queued_bytes: Option<usize>,
+
+	/// How many header requests a peer may send us every second
+	pub header_requests_per_sec: Option<u32>,
+
+	/// How many peer address requests a peer may send us every minute
+	pub peer_addr_requests_per_min: Option<u32>,
+
+	/// How many txhashset requests a peer may send us every minute
+	pub txhashset_requests_per_min: Option<u32>,
+
+	/// How long (in seconds) we stay silent before pinging a peer
+	pub keepalive_interval: Option<u64>,
+
+	/// How long (in seconds) we wait for anything from a peer before closing
+	/// the connection
+	pub idle_timeout: Option<u64>,
+
+	/// How often (in seconds) we sweep our peers, dropping the ones that
+	/// stopped answering us
+	pub monitor_interval: Option<u64>,
+
+	/// How long (in seconds) a peer has to send a block we asked for before
+	/// we ask another one
+	pub block_request_timeout: Option<u64>,
+
+	/// How many outbound connections we keep up
+	pub outbound_target: Option<u32>,
+
+	/// How many peers we dial at most at the same time
+	pub max_concurrent_dials: Option<u32>,
+
+	/// How often (in seconds) we gossip peer addresses to each peer
+	pub gossip_interval: Option<u64>,
+
+	/// How often (in seconds) we dial a preferred peer we're not connected to
+	pub preferred_redial_interval: Option<u64>,
+
+	/// How many days a healthy peer can go unseen before we mark it defunct
+	pub peer_demote_days: Option<u64>,
+
+	/// How many days a defunct or unverified peer can go unseen before we
+	/// delete it from our store
+	pub peer_expire_days: Option<u64>,
+
+	/// How many peers we keep in our store at most, the worst ones pruned past
+	/// that
+	pub peer_store_max: Option<usize>,
+}
+
+/// Default address for peer-to-peer connections.
+impl Default for P2PConfig {
+	fn default() -> P2PConfig {
+		let ipaddr = "0.0.0.0".parse().unwrap();
+		P2PConfig {
+			host: ipaddr,
+			port: 3414,
+			listen_addrs: None,
+			advertise_addr: None,
+			port_mapping: None,
+			capabilities: Capabilities::FULL_NODE
+				| Capabilities::COMPACT_BLOCKS
+				| Capabilities::BLOCK_INV,
+			seeding_type: Seeding::default(),
+			seeds: None,
+			dns_seeds: None,
+			peers_allow: None,
+			peers_deny: None,
+			peers_preferred: None,
+			peers_import_file: None,
+			peers_export_file: None,
+			ban_window: None,
+			ban_score_threshold: None,
+			discourage_window: None,
+			discourage_score_threshold: None,
+			invalid_block_strikes: None,
+			borderline_block_strikes: None,
+			peer_max_count: None,
+			max_inbound: None,
+			inbound_eviction: None,
+			max_outbound: None,
+			peer_min_preferred_count: None,
+			dandelion_peer: None,
+			handshake_timeout: None,
+			max_inflight_handshakes: None,
+			max_inbound_per_ip: None,
+			max_inbound_per_subnet: None,
+			inbound_limit_exempt: None,
+			socks5_proxy: None,
+			ping_interval: None,
+			block_fanout: None,
+			relay_fanout: None,
+			max_queued_bytes: None,
+			header_requests_per_sec: None,
+			peer_addr_requests_per_min: None,
+			txhashset_requests_per_min: None,
+			keepalive_interval: None,
+			idle_timeout: None,
+			monitor_interval: None,
+			block_request_timeout: None,
+			outbound_target: None,
+			max_concurrent_dials: None,
+			gossip_interval: None,
+			preferred_redial_interval: None,
+			peer_demote_days: None,
+			peer_expire_days: None,
+			peer_store_max: None,
+		}
+	}
+}
+
+/// How many msgs of a type a peer may send us: a burst of `count`, then
+/// `count` more every `period`. Past that they're dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+	pub msg_type: Type,
+	pub count: u32,
+	pub period: Duration,
+}
+
+impl RateLimit {
+	pub fn new(msg_type: Type, count: u32, period: Duration) -> RateLimit {
+		RateLimit {
+			msg_type,
+			count,
+			period,
+		}
+	}
+}
+
+/// Note certain fields are options just so they don't have to be
+/// included in grin-server.toml, but we don't want them to ever return none
+impl P2PConfig {
+	/// return ban window
+	pub fn ban_window(&self) -> i64 {
+		match self.ban_window {
+			Some(n) => n,
+			None => BAN_WINDOW,
+		}
+	}
+
+	/// return peer_max_count
+	pub fn peer_max_count(&self) -> u32 {
+		match self.peer_max_count {
+			Some(n) => n,
+			None => PEER_MAX_COUNT,
+		}
+	}
+
+	/// return max_inbound
+	pub fn max_inbound(&self) -> u32 {
+		match self.max_inbound {
+			Some(n) => n,
+			None => MAX_INBOUND,
+		}
+	}
+
+	/// return max_outbound
+	pub fn max_outbound(&self) -> u32 {
+		match self.max_outbound {
+			Some(n) => n,
+			None => MAX_OUTBOUND,
+		}
+	}
+
+	/// return peer_preferred_count
+	pub fn peer_min_preferred_count(&self) -> u32 {
+		match self.peer_min_preferred_count {
+			Some(n) => n,
+			None => PEER_MIN_PREFERRED_COUNT,
+		}
+	}
+
+	/// return handshake_timeout
+	pub fn handshake_timeout(&self) -> Duration {
+		match self.handshake_timeout {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(HANDSHAKE_TIMEOUT),
+		}
+	}
+
+	/// return max_inflight_handshakes
+	pub fn max_inflight_handshakes(&self) -> usize {
+		match self.max_inflight_handshakes {
+			Some(n) => n,
+			None => MAX_INFLIGHT_HANDSHAKES,
+		}
+	}
+
+	/// return max_inbound_per_ip
+	pub fn max_inbound_per_ip(&self) -> u32 {
+		match self.max_inbound_per_ip {
+			Some(n) => n,
+			None => MAX_INBOUND_PER_IP,
+		}
+	}
+
+	/// return inbound_eviction
+	pub fn inbound_eviction(&self) -> InboundEviction {
+		match self.inbound_eviction {
+			Some(e) => e,
+			None => InboundEviction::default(),
+		}
+	}
+
+	/// return max_inbound_per_subnet
+	pub fn max_inbound_per_subnet(&self) -> u32 {
+		match self.max_inbound_per_subnet {
+			Some(n) => n,
+			None => MAX_INBOUND_PER_SUBNET,
+		}
+	}
+
+	/// Whether the inbound connections from the ip aren't limited by ip or
+	/// network.
+	pub fn inbound_limit_exempt(&self, ip: &IpAddr) -> bool {
+		canonical_ip(ip).is_loopback()
+			|| self
+				.inbound_limit_exempt
+				.as_ref()
+				.map_or(false, |ranges| ranges.iter().any(|r| r.contains(ip)))
+	}
+
+	/// return ping_interval
+	pub fn ping_interval(&self) -> Duration {
+		match self.ping_interval {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(PING_INTERVAL),
+		}
+	}
+
+	/// return block_fanout
+	pub fn block_fanout(&self) -> u32 {
+		match self.block_fanout {
+			Some(n) => n,
+			None => BLOCK_FANOUT,
+		}
+	}
+
+	/// return ban_score_threshold
+	pub fn ban_score_threshold(&self) -> u32 {
+		match self.ban_score_threshold {
+			Some(n) => n,
+			None => BAN_SCORE_THRESHOLD,
+		}
+	}
+
+	/// return discourage_window
+	pub fn discourage_window(&self) -> i64 {
+		match self.discourage_window {
+			Some(n) => n,
+			None => DISCOURAGE_WINDOW,
+		}
+	}
+
+	/// return discourage_score_threshold
+	pub fn discourage_score_threshold(&self) -> u32 {
+		match self.discourage_score_threshold {
+			Some(n) => n,
+			None => DISCOURAGE_SCORE_THRESHOLD,
+		}
+	}
+
+	/// return invalid_block_strikes
+	pub fn invalid_block_strikes(&self) -> u32 {
+		match self.invalid_block_strikes {
+			Some(n) => n,
+			None => INVALID_BLOCK_STRIKES,
+		}
+	}
+
+	/// return borderline_block_strikes
+	pub fn borderline_block_strikes(&self) -> u32 {
+		match self.borderline_block_strikes {
+			Some(n) => n,
+			None => BORDERLINE_BLOCK_STRIKES,
 		}
-		Ok(())
 	}
-}
 
-impl Readable for PeerAddr {
-	fn read(reader: &mut dyn Reader) -> Result<PeerAddr, ser::Error> {
-		let v4_or_v6 = reader.read_u8()?;
-		if v4_or_v6 == 0 {
-			let ip = reader.read_fixed_bytes(4)?;
-			let port = reader.read_u16()?;
-			Ok(PeerAddr(SocketAddr::V4(SocketAddrV4::new(
-				Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
-				port,
-			))))
-		} else {
-			let ip = try_iter_map_vec!(0..8, |_| reader.read_u16());
-			let port = reader.read_u16()?;
-			Ok(PeerAddr(SocketAddr::V6(SocketAddrV6::new(
-				Ipv6Addr::new(ip[0], ip[1], ip[2], ip[3], ip[4], ip[5], ip[6], ip[7]),
-				port,
-				0,
-				0,
-			))))
+	/// return relay_fanout
+	pub fn relay_fanout(&self) -> u32 {
+		match self.relay_fanout {
+			Some(n) => n,
+			None => RELAY_FANOUT,
 		}
 	}
-}
 
-impl std::hash::Hash for PeerAddr {
-	/// If loopback address then we care about ip and port.
-	/// If regular address then we only care about the ip and ignore the port.
-	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-		if self.0.ip().is_loopback() {
-			self.0.hash(state);
-		} else {
-			self.0.ip().hash(state);
+	/// return max_queued_bytes
+	pub fn max_queued_bytes(&self) -> usize {
+		match self.max_queued_bytes {
+			Some(n) => n,
+			None => MAX_QUEUED_BYTES,
 		}
 	}
-}
 
-impl PartialEq for PeerAddr {
-	/// If loopback address then we care about ip and port.
-	/// If regular address then we only care about the ip and ignore the port.
-	fn eq(&self, other: &PeerAddr) -> bool {
-		if self.0.ip().is_loopback() {
-			self.0 == other.0
-		} else {
-			self.0.ip() == other.0.ip()
+	/// return header_requests_per_sec
+	pub fn header_requests_per_sec(&self) -> u32 {
+		match self.header_requests_per_sec {
+			Some(n) => n,
+			None => HEADER_REQUESTS_PER_SEC,
 		}
 	}
-}
-
-impl Eq for PeerAddr {}
 
-impl std::fmt::Display for PeerAddr {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		write!(f, "{}", self.0)
+	/// return peer_addr_requests_per_min
+	pub fn peer_addr_requests_per_min(&self) -> u32 {
+		match self.peer_addr_requests_per_min {
+			Some(n) => n,
+			None => PEER_ADDR_REQUESTS_PER_MIN,
+		}
 	}
-}
 
-impl PeerAddr {
-	/// Convenient way of constructing a new peer_addr from an ip_addr
-	/// defaults to port 3414 on mainnet and 13414 on floonet.
-	pub fn from_ip(addr: IpAddr) -> PeerAddr {
-		let port = if global::is_floonet() { 13414 } else { 3414 };
-		PeerAddr(SocketAddr::new(addr, port))
+	/// return txhashset_requests_per_min
+	pub fn txhashset_requests_per_min(&self) -> u32 {
+		match self.txhashset_requests_per_min {
+			Some(n) => n,
+			None => TXHASHSET_REQUESTS_PER_MIN,
+		}
 	}
 
-	/// If the ip is loopback then our key is "ip:port" (mainly for local usernet testing).
-	/// Otherwise we only care about the ip (we disallow multiple peers on the same ip address).
-	pub fn as_key(&self) -> String {
-		if self.0.ip().is_loopback() {
-			format!("{}:{}", self.0.ip(), self.0.port())
-		} else {
-			format!("{}", self.0.ip())
+	/// return keepalive_interval
+	pub fn keepalive_interval(&self) -> Duration {
+		match self.keepalive_interval {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(KEEPALIVE_INTERVAL),
 		}
 	}
-}
-
-/// Configuration for the peer-to-peer server.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct P2PConfig {
-	pub host: IpAddr,
-	pub port: u16,
 
-	/// Method used to get the list of seed nodes for initial bootstrap.
-	#[serde(default)]
-	pub seeding_type: Seeding,
+	/// return idle_timeout
+	pub fn idle_timeout(&self) -> Duration {
+		match self.idle_timeout {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(IDLE_TIMEOUT),
+		}
+	}
 
-	/// The list of seed nodes, if using Seeding as a seed type
-	pub seeds: Option<Vec<PeerAddr>>,
+	/// return monitor_interval
+	pub fn monitor_interval(&self) -> Duration {
+		match self.monitor_interval {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(MONITOR_INTERVAL),
+		}
+	}
 
-	/// Capabilities expose by this node, also conditions which other peers this
-	/// node will have an affinity toward when connection.
-	pub capabilities: Capabilities,
+	/// return block_request_timeout
+	pub fn block_request_timeout(&self) -> Duration {
+		match self.block_request_timeout {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(BLOCK_REQUEST_TIMEOUT),
+		}
+	}
 
-	pub peers_allow: Option<Vec<PeerAddr>>,
+	/// return outbound_target
+	pub fn outbound_target(&self) -> u32 {
+		match self.outbound_target {
+			Some(n) => n,
+			None => OUTBOUND_TARGET,
+		}
+	}
 
-	pub peers_deny: Option<Vec<PeerAddr>>,
+	/// return max_concurrent_dials
+	pub fn max_concurrent_dials(&self) -> u32 {
+		match self.max_concurrent_dials {
+			Some(n) => n,
+			None => MAX_CONCURRENT_DIALS,
+		}
+	}
 
-	/// The list of preferred peers that we will try to connect to
-	pub peers_preferred: Option<Vec<PeerAddr>>,
+	/// return gossip_interval
+	pub fn gossip_interval(&self) -> Duration {
+		match self.gossip_interval {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(GOSSIP_INTERVAL),
+		}
+	}
 
-	pub ban_window: Option<i64>,
+	/// return preferred_redial_interval
+	pub fn preferred_redial_interval(&self) -> Duration {
+		match self.preferred_redial_interval {
+			Some(n) => Duration::from_secs(n),
+			None => Duration::from_secs(PREFERRED_REDIAL_INTERVAL),
+		}
+	}
 
-	pub peer_max_count: Option<u32>,
+	/// return listen_addrs
+	pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+		match self.listen_addrs {
+			Some(ref addrs) => addrs.clone(),
+			None => vec![SocketAddr::new(self.host, self.port)],
+		}
+	}
 
-	pub peer_min_preferred_count: Option<u32>,
+	/// return advertise_addr
+	pub fn advertise_addr(&self) -> PeerAddr {
+		match self.advertise_addr {
+			Some(ref addr) => addr.clone(),
+			None => PeerAddr::Ip(SocketAddr::new(self.host, self.port)),
+		}
+	}
 
-	pub dandelion_peer: Option<PeerAddr>,
-}
+	/// return port_mapping
+	pub fn port_mapping(&self) -> bool {
+		self.port_mapping.unwrap_or(false)
+	}
 
-/// Default address for peer-to-peer connections.
-impl Default for P2PConfig {
-	fn default() -> P2PConfig {
-		let ipaddr = "0.0.0.0".parse().unwrap();
-		P2PConfig {
-			host: ipaddr,
-			port: 3414,
-			capabilities: Capabilities::FULL_NODE,
-			seeding_type: Seeding::default(),
-			seeds: None,
-			peers_allow: None,
-			peers_deny: None,
-			peers_preferred: None,
-			ban_window: None,
-			peer_max_count: None,
-			peer_min_preferred_count: None,
-			dandelion_peer: None,
+	/// return peer_demote_days
+	pub fn peer_demote_days(&self) -> u64 {
+		match self.peer_demote_days {
+			Some(n) => n,
+			None => PEER_DEMOTE_DAYS,
 		}
 	}
-}
 
-/// Note certain fields are options just so they don't have to be
-/// included in grin-server.toml, but we don't want them to ever return none
-impl P2PConfig {
-	/// return ban window
-	pub fn ban_window(&self) -> i64 {
-		match self.ban_window {
+	/// return peer_expire_days
+	pub fn peer_expire_days(&self) -> u64 {
+		match self.peer_expire_days {
 			Some(n) => n,
-			None => BAN_WINDOW,
+			None => PEER_EXPIRE_DAYS,
 		}
 	}
 
-	/// return peer_max_count
-	pub fn peer_max_count(&self) -> u32 {
-		match self.peer_max_count {
+	/// return peer_store_max
+	pub fn peer_store_max(&self) -> usize {
+		match self.peer_store_max {
 			Some(n) => n,
-			None => PEER_MAX_COUNT,
+			None => PEER_STORE_MAX,
 		}
 	}
 
-	/// return peer_preferred_count
-	pub fn peer_min_preferred_count(&self) -> u32 {
-		match self.peer_min_preferred_count {
-			Some(n) => n,
-			None => PEER_MIN_PREFERRED_COUNT,
+	/// Whether the address is one of our preferred peers.
+	pub fn is_preferred(&self, addr: &PeerAddr) -> bool {
+		self.peers_preferred
+			.as_ref()
+			.map_or(false, |peers| peers.contains(addr))
+	}
+
+	/// The limits on the requests a peer may send us, by msg type. The
+	/// others (pings, block requests) aren't limited.
+	pub fn rate_limits(&self) -> Vec<RateLimit> {
+		let sec = Duration::from_secs(1);
+		let min = Duration::from_secs(60);
+		vec![
+			RateLimit::new(Type::GetHeaders, self.header_requests_per_sec(), sec),
+			RateLimit::new(Type::GetPeerAddrs, self.peer_addr_requests_per_min(), min),
+			RateLimit::new(
+				Type::TxHashSetRequest,
+				self.txhashset_requests_per_min(),
+				min,
+			),
+			RateLimit::new(
+				Type::KernelDataRequest,
+				self.txhashset_requests_per_min(),
+				min,
+			),
+		]
+	}
+
+	/// return the dialer for our outbound connections, through our SOCKS5
+	/// proxy if we have one
+	pub fn dialer(&self) -> Box<dyn Dialer> {
+		match self.socks5_proxy {
+			Some(ref proxy) => Box::new(proxy.clone()),
+			None => Box::new(Direct),
+		}
+	}
+
+	/// The limits on our connections we start with, see `PeerLimits`.
+	pub fn limits(&self) -> PeerLimits {
+		PeerLimits {
+			peer_max_count: self.peer_max_count(),
+			max_inbound: self.max_inbound(),
+			max_outbound: self.max_outbound(),
+			outbound_target: self.outbound_target(),
+			max_inbound_per_ip: self.max_inbound_per_ip(),
+			max_inbound_per_subnet: self.max_inbound_per_subnet(),
+			relay_fanout: self.relay_fanout(),
 		}
 	}
 }
 
+/// The limits on our connections, from our config at first and then
+/// changed as we run (see `Server::update_limits`), no restart needed. Our
+/// preferred peers aren't held to them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct PeerLimits {
+	/// Most peers we stay connected to, both directions.
+	pub peer_max_count: u32,
+	/// Most peers connected to us we take.
+	pub max_inbound: u32,
+	/// Most peers we connect to.
+	pub max_outbound: u32,
+	/// How many of the peers we connect to we keep up.
+	pub outbound_target: u32,
+	/// Most inbound connections from a single ip.
+	pub max_inbound_per_ip: u32,
+	/// Most inbound connections from a single network.
+	pub max_inbound_per_subnet: u32,
+	/// How many peers we relay a block or tx to, at most.
+	pub relay_fanout: u32,
+}
+
+impl PeerLimits {
+	/// Whether we hold fewer connections than before under these limits,
+	/// the peers we already have possibly over them.
+	pub fn lowers(&self, before: &PeerLimits) -> bool {
+		self.peer_max_count < before.peer_max_count
+			|| self.max_inbound < before.max_inbound
+			|| self.max_outbound < before.max_outbound
+	}
+}
+
 /// Type of seeding the server will use to find other peers on the network.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum Seeding {
@@ -305,6 +1621,26 @@ impl Default for Seeding {
 	}
 }
 
+/// What we do with a new inbound peer once we have as many as we take, see
+/// `Peers::make_inbound_room`. Our preferred peers are never evicted.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum InboundEviction {
+	/// Evict the youngest peer of our most represented network group, our
+	/// oldest and fastest peers set aside
+	Netgroup,
+	/// Evict a random peer, our fastest ones and the ones serving one of our
+	/// requests set aside
+	Random,
+	/// Refuse the newcomer
+	Reject,
+}
+
+impl Default for InboundEviction {
+	fn default() -> InboundEviction {
+		InboundEviction::Netgroup
+	}
+}
+
 bitflags! {
 	/// Options for what type of interaction a peer supports
 	#[derive(Serialize, Deserialize)]
@@ -321,10 +1657,23 @@ bitflags! {
 		const PEER_LIST = 0b00000100;
 		/// Can broadcast and request txs by kernel hash.
 		const TX_KERNEL_HASH = 0b00001000;
+		/// Can rebuild blocks announced in compact form from its own pool.
+		const COMPACT_BLOCKS = 0b00010000;
+		/// Can encrypt the connection, used when both sides advertise it.
+		const ENCRYPTED = 0b00100000;
+		/// Can read compressed msg bodies, large msgs are compressed when both
+		/// sides advertise it.
+		const COMPRESSION = 0b01000000;
+		/// Can be announced blocks by hash, asking for the ones it doesn't
+		/// have.
+		const BLOCK_INV = 0b10000000;
+		/// Can provide all the full blocks back to genesis, running in
+		/// archival mode.
+		const FULL_HIST = 0b1_00000000;
 
 		/// All nodes right now are "full nodes".
-		/// Some nodes internally may maintain longer block histories (archival_mode)
-		/// but we do not advertise this to other nodes.
+		/// Nodes maintaining longer block histories (archival_mode) advertise
+		/// FULL_HIST on top.
 		/// All nodes by default will accept lightweight "kernel first" tx broadcast.
 		const FULL_NODE = Capabilities::HEADER_HIST.bits
 			| Capabilities::TXHASHSET_HIST.bits
@@ -333,6 +1682,14 @@ bitflags! {
 	}
 }
 
+impl Capabilities {
+	/// Builds capabilities from raw bits, keeping the bits we don't know
+	/// about (yet) instead of truncating them.
+	pub fn from_bits_preserve(bits: u32) -> Capabilities {
+		Capabilities { bits }
+	}
+}
+
 // Types of connection
 enum_from_primitive! {
 	#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -354,9 +1711,69 @@ enum_from_primitive! {
 		ManualBan = 5,
 		FraudHeight = 6,
 		BadHandshake = 7,
+		ProtocolViolation = 8,
+		WrongNetwork = 9,
+		TooManyPeers = 10,
+		// claimed more work than it could send us headers for
+		FraudDifficulty = 11,
+	}
+}
+
+/// Something a peer did wrong that isn't worth a ban on its own. Each adds
+/// its weight to the peer's misbehavior score, which decays over time, and
+/// the peer is banned once the score goes over `ban_score_threshold`. Bad
+/// blocks are also counted as strikes, which get the peer banned for sending
+/// us a bad block once there are enough of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offense {
+	/// A msg we couldn't deserialize
+	MalformedMessage,
+	/// A payload too large for anything we could have asked for
+	UnsolicitedPayload,
+	/// Data that failed our checks, like a block far ahead of any tip
+	FailedValidation,
+	/// Kept sending msgs past their rate limit
+	RateLimited,
+	/// A pong to no ping we sent
+	UnsolicitedPong,
+	/// A block failing full validation, against consensus
+	InvalidBlock,
+	/// A block we refused for what may be our own fault, like a timestamp
+	/// ahead of our clock
+	BorderlineBlock,
+}
+
+impl Offense {
+	/// How much the offense adds to the peer's misbehavior score.
+	pub fn weight(&self) -> u32 {
+		match self {
+			Offense::MalformedMessage => 20,
+			Offense::UnsolicitedPayload => 25,
+			Offense::FailedValidation => 40,
+			Offense::RateLimited => 10,
+			Offense::UnsolicitedPong => 10,
+			Offense::InvalidBlock => 50,
+			Offense::BorderlineBlock => 20,
+		}
+	}
+
+	/// Whether the offense is a bad block, counted as a strike.
+	pub fn is_bad_block(&self) -> bool {
+		match self {
+			Offense::InvalidBlock | Offense::BorderlineBlock => true,
+			_ => false,
+		}
 	}
 }
 
+/// How many of our pings we keep track of until the peer answers them, the
+/// oldest forgotten past that.
+const MAX_PINGS_TRACKED: usize = 8;
+
+/// Weight of each new round-trip time sample in the estimate, in eighths
+/// (the same as TCP's).
+const RTT_SAMPLE_EIGHTHS: u32 = 1;
+
 #[derive(Clone, Debug)]
 pub struct PeerLiveInfo {
 	pub total_difficulty: Difficulty,
@@ -364,29 +1781,90 @@ pub struct PeerLiveInfo {
 	pub last_seen: DateTime<Utc>,
 	pub stuck_detector: DateTime<Utc>,
 	pub first_seen: DateTime<Utc>,
+	pub handshake_rtt: Option<Duration>,
+	/// Bytes sent and received during the handshake, the key exchange
+	/// included.
+	pub handshake_bytes: (u64, u64),
+	/// Pings sent since the last pong we received.
+	pub unanswered_pings: u32,
+	/// Nonces of the pings waiting for their pong, with when we sent them,
+	/// oldest first.
+	pub pings_sent: VecDeque<(u64, Instant)>,
+	/// Round-trip time of the last ping the peer answered.
+	pub ping_rtt: Option<Duration>,
+	/// Moving average of the round-trip times of the pings the peer
+	/// answered, each new one weighing RTT_SAMPLE_EIGHTHS.
+	pub rtt_estimate: Option<Duration>,
+	/// Longest round-trip time of the pings the peer answered.
+	pub rtt_max: Option<Duration>,
+}
+
+// How long from one instant to the other, none if it's earlier.
+fn elapsed(from: Instant, to: Instant) -> Duration {
+	if to > from {
+		to - from
+	} else {
+		Duration::from_secs(0)
+	}
 }
 
 /// General information about a connected peer that's useful to other modules.
 #[derive(Clone, Debug)]
 pub struct PeerInfo {
+	/// Capabilities as advertised by the peer, including unknown bits.
 	pub capabilities: Capabilities,
+	/// Capabilities supported by both the peer and us, check these before
+	/// asking the peer for anything.
+	pub negotiated: Capabilities,
 	pub user_agent: String,
 	pub version: ProtocolVersion,
 	pub addr: PeerAddr,
 	pub direction: Direction,
+	/// Our address as reported by the peer in its Shake, only for outbound
+	/// connections. Single, unverified claim: aggregate over several peers
+	/// before trusting it.
+	pub our_addr_as_seen: Option<PeerAddr>,
+	/// Node id the peer sent in its Hand or Shake, older peers don't.
+	pub node_id: Option<NodeId>,
+	/// Our node id, as we sent it to the peer.
+	pub our_node_id: NodeId,
+	/// When we sent our shake, only for inbound connections. The handshake
+	/// round-trip completes with the first message the peer sends after it.
+	pub shake_sent: Option<Instant>,
 	pub live_info: Arc<RwLock<PeerLiveInfo>>,
+	/// Counts the connection against the inbound limits of the peer's ip and
+	/// network, only for inbound connections. Released once the connection
+	/// and every copy of this info are gone.
+	pub inbound_slot: Option<Arc<InboundSlot>>,
 }
 
 impl PeerLiveInfo {
-	pub fn new(difficulty: Difficulty) -> PeerLiveInfo {
+	pub fn new(difficulty: Difficulty, height: u64) -> PeerLiveInfo {
 		PeerLiveInfo {
 			total_difficulty: difficulty,
-			height: 0,
+			height,
 			first_seen: Utc::now(),
 			last_seen: Utc::now(),
 			stuck_detector: Utc::now(),
+			handshake_rtt: None,
+			handshake_bytes: (0, 0),
+			unanswered_pings: 0,
+			pings_sent: VecDeque::new(),
+			ping_rtt: None,
+			rtt_estimate: None,
+			rtt_max: None,
 		}
 	}
+
+	// Takes the round-trip time of a ping the peer answered into account.
+	fn rtt_sample(&mut self, rtt: Duration) {
+		self.ping_rtt = Some(rtt);
+		self.rtt_estimate = Some(match self.rtt_estimate {
+			Some(estimate) => (estimate * (8 - RTT_SAMPLE_EIGHTHS) + rtt * RTT_SAMPLE_EIGHTHS) / 8,
+			None => rtt,
+		});
+		self.rtt_max = Some(cmp::max(self.rtt_max.unwrap_or(rtt), rtt));
+	}
 }
 
 impl PeerInfo {
@@ -414,6 +1892,87 @@ impl PeerInfo {
 		self.live_info.read().first_seen
 	}
 
+	/// Round-trip time of the handshake with this peer. Known as soon as we
+	/// receive the shake for outbound peers, once the first message after
+	/// our shake arrives for inbound ones.
+	pub fn handshake_rtt(&self) -> Option<Duration> {
+		self.live_info.read().handshake_rtt
+	}
+
+	/// Set the handshake round-trip time of the peer.
+	pub fn set_handshake_rtt(&self, rtt: Duration) {
+		self.live_info.write().handshake_rtt = Some(rtt);
+	}
+
+	/// Number of pings sent to the peer since it last answered one.
+	pub fn unanswered_pings(&self) -> u32 {
+		self.live_info.read().unanswered_pings
+	}
+
+	/// Current round-trip time estimate of the peer, from the pings it
+	/// answered, the handshake's until then.
+	pub fn rtt(&self) -> Option<Duration> {
+		let live_info = self.live_info.read();
+		live_info
+			.rtt_estimate
+			.or(live_info.ping_rtt)
+			.or(live_info.handshake_rtt)
+	}
+
+	/// Longest round-trip time of the pings the peer answered.
+	pub fn rtt_max(&self) -> Option<Duration> {
+		self.live_info.read().rtt_max
+	}
+
+	/// We're sending a ping to the peer, with the nonce returned.
+	pub fn ping_sent(&self) -> u64 {
+		self.ping_sent_at(Instant::now())
+	}
+
+	/// Same as `ping_sent`, at the provided time.
+	pub fn ping_sent_at(&self, at: Instant) -> u64 {
+		let nonce = thread_rng().gen();
+		let mut live_info = self.live_info.write();
+		if live_info.pings_sent.len() >= MAX_PINGS_TRACKED {
+			live_info.pings_sent.pop_front();
+		}
+		live_info.pings_sent.push_back((nonce, at));
+		live_info.unanswered_pings += 1;
+		nonce
+	}
+
+	/// The peer answered one of our pings, false if we didn't send it a
+	/// ping with that nonce (or got its pong already). Older peers don't
+	/// echo our nonce, the round-trip time is then from the oldest ping, an
+	/// upper bound when the pong is for a later one.
+	pub fn pong_received(&self, nonce: Option<u64>) -> bool {
+		self.pong_received_at(nonce, Instant::now())
+	}
+
+	/// Same as `pong_received`, at the provided time.
+	pub fn pong_received_at(&self, nonce: Option<u64>, at: Instant) -> bool {
+		let mut live_info = self.live_info.write();
+		match nonce {
+			Some(nonce) => {
+				let sent = match live_info.pings_sent.iter().position(|p| p.0 == nonce) {
+					Some(i) => live_info.pings_sent.remove(i).map(|p| p.1),
+					None => return false,
+				};
+				if let Some(sent) = sent {
+					live_info.rtt_sample(elapsed(sent, at));
+				}
+			}
+			None => {
+				if let Some((_, sent)) = live_info.pings_sent.front().cloned() {
+					live_info.ping_rtt = Some(elapsed(sent, at));
+				}
+				live_info.pings_sent.clear();
+			}
+		}
+		live_info.unanswered_pings = 0;
+		true
+	}
+
 	/// Update the total_difficulty, height and last_seen of the peer.
 	/// Takes a write lock on the live_info.
 	pub fn update(&self, height: u64, total_difficulty: Difficulty) {
@@ -427,6 +1986,124 @@ impl PeerInfo {
 	}
 }
 
+/// Bandwidth used with a peer over the life of the connection, or with all our
+/// peers summed up. Rates are in bytes per second over the last minute.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthStats {
+	pub sent_bytes: u64,
+	pub received_bytes: u64,
+	pub sent_rate: u64,
+	pub recv_rate: u64,
+	/// Last time we heard from the peer (the latest of them all).
+	pub last_seen: DateTime<Utc>,
+}
+
+/// A snapshot of everything we know about a connected peer, from the
+/// handshake, its pings and our end of the connection. Assembled without
+/// getting in the way of the connection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerStats {
+	pub addr: PeerAddr,
+	pub version: ProtocolVersion,
+	pub capabilities: Capabilities,
+	pub direction: Direction,
+	pub user_agent: String,
+	pub total_difficulty: Difficulty,
+	pub height: u64,
+	pub sent_bytes: u64,
+	pub received_bytes: u64,
+	pub sent_rate: u64,
+	pub recv_rate: u64,
+	/// Msgs sent by type (by name), handshake left out.
+	pub msgs_sent: BTreeMap<String, u64>,
+	/// Msgs received by type (by name), handshake left out.
+	pub msgs_received: BTreeMap<String, u64>,
+	/// Requests sent to the peer and not answered yet.
+	pub outstanding_requests: usize,
+	/// Bytes waiting to be written out to the peer.
+	pub queued_bytes: usize,
+	/// Misbehavior score, discouraged past `discourage_score_threshold` and
+	/// banned past `ban_score_threshold`.
+	pub misbehavior: u32,
+	/// Whether the peer is currently discouraged, see
+	/// `Peers::discourage_peer`.
+	pub discouraged: bool,
+	/// Last time we heard from the peer.
+	pub last_seen: DateTime<Utc>,
+	/// Whether it's one of our preferred peers, always kept connected.
+	pub preferred: bool,
+	/// Current round-trip time estimate, from the pings the peer answered.
+	pub rtt_ms: Option<u64>,
+	/// Longest round-trip time of the pings the peer answered.
+	pub max_rtt_ms: Option<u64>,
+}
+
+impl PeerStats {
+	/// The bandwidth part of the stats.
+	pub fn bandwidth(&self) -> BandwidthStats {
+		BandwidthStats {
+			sent_bytes: self.sent_bytes,
+			received_bytes: self.received_bytes,
+			sent_rate: self.sent_rate,
+			recv_rate: self.recv_rate,
+			last_seen: self.last_seen,
+		}
+	}
+}
+
+/// How many peers we're connected to, broken down by the capabilities they
+/// advertise, who opened the connection and the protocol version we speak
+/// with them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerCounts {
+	pub total: u32,
+	pub inbound: u32,
+	pub outbound: u32,
+	/// Peers advertising each capability, by its name. Capabilities no peer
+	/// advertises are left out.
+	pub capabilities: BTreeMap<String, u32>,
+	/// Peers on each protocol version.
+	pub versions: BTreeMap<u32, u32>,
+}
+
+impl PeerCounts {
+	/// Counts from a snapshot of our connected peers, see
+	/// `Peers::connected_stats`.
+	pub fn from_stats(stats: &[PeerStats]) -> PeerCounts {
+		let names = [
+			(Capabilities::HEADER_HIST, "HEADER_HIST"),
+			(Capabilities::TXHASHSET_HIST, "TXHASHSET_HIST"),
+			(Capabilities::PEER_LIST, "PEER_LIST"),
+			(Capabilities::TX_KERNEL_HASH, "TX_KERNEL_HASH"),
+			(Capabilities::COMPACT_BLOCKS, "COMPACT_BLOCKS"),
+			(Capabilities::ENCRYPTED, "ENCRYPTED"),
+			(Capabilities::COMPRESSION, "COMPRESSION"),
+			(Capabilities::BLOCK_INV, "BLOCK_INV"),
+			(Capabilities::FULL_HIST, "FULL_HIST"),
+		];
+		let mut counts = PeerCounts::default();
+		for s in stats {
+			counts.total += 1;
+			match s.direction {
+				Direction::Inbound => counts.inbound += 1,
+				Direction::Outbound => counts.outbound += 1,
+			}
+			for bit in (0..32).map(|i| 1u32 << i) {
+				if s.capabilities.bits() & bit == 0 {
+					continue;
+				}
+				let name = match names.iter().find(|(c, _)| c.bits() == bit) {
+					Some((_, name)) => name.to_string(),
+					None => format!("{:#x}", bit),
+				};
+				*counts.capabilities.entry(name).or_insert(0) += 1;
+			}
+			*counts.versions.entry(s.version.into()).or_insert(0) += 1;
+		}
+		counts
+	}
+}
+
 /// Flatten out a PeerInfo and nested PeerLiveInfo (taking a read lock on it)
 /// so we can serialize/deserialize the data for the API and the TUI.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -438,6 +2115,14 @@ pub struct PeerInfoDisplay {
 	pub direction: Direction,
 	pub total_difficulty: Difficulty,
 	pub height: u64,
+	/// Handshake round-trip time in milliseconds, if known yet.
+	pub handshake_rtt_ms: Option<u64>,
+	/// Bandwidth used with the peer, when connected.
+	#[serde(default)]
+	pub stats: Option<BandwidthStats>,
+	/// How long (in seconds) we've been connected to the peer.
+	#[serde(default)]
+	pub connected_secs: u64,
 }
 
 impl From<PeerInfo> for PeerInfoDisplay {
@@ -450,6 +2135,9 @@ impl From<PeerInfo> for PeerInfoDisplay {
 			direction: info.direction.clone(),
 			total_difficulty: info.total_difficulty(),
 			height: info.height(),
+			handshake_rtt_ms: info.handshake_rtt().map(|rtt| rtt.as_millis() as u64),
+			stats: None,
+			connected_secs: (Utc::now() - info.first_seen()).num_seconds().max(0) as u64,
 		}
 	}
 }
@@ -476,8 +2164,12 @@ pub trait ChainAdapter: Sync + Send {
 	fn total_height(&self) -> Result<u64, chain::Error>;
 
 	/// A valid transaction has been received from one of our peers
-	fn transaction_received(&self, tx: core::Transaction, stem: bool)
-		-> Result<bool, chain::Error>;
+	fn transaction_received(
+		&self,
+		tx: core::Transaction,
+		stem: bool,
+		peer_info: &PeerInfo,
+	) -> Result<bool, chain::Error>;
 
 	fn get_transaction(&self, kernel_hash: Hash) -> Option<core::Transaction>;
 
@@ -487,6 +2179,15 @@ pub trait ChainAdapter: Sync + Send {
 		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error>;
 
+	/// A block has been announced by hash, ask the peer for it if we don't
+	/// have it.
+	fn block_inv_received(
+		&self,
+		hash: Hash,
+		height: u64,
+		peer_info: &PeerInfo,
+	) -> Result<bool, chain::Error>;
+
 	/// A block has been received from one of our peers. Returns true if the
 	/// block could be handled properly and is not deemed defective by the
 	/// chain. Returning false means the block will never be valid and
@@ -527,6 +2228,10 @@ pub trait ChainAdapter: Sync + Send {
 	/// Gets a full block by its hash.
 	fn get_block(&self, h: Hash) -> Option<core::Block>;
 
+	/// Gets the full block at the provided height on our current main chain,
+	/// if we have one that high.
+	fn get_block_by_height(&self, height: u64) -> Option<core::Block>;
+
 	fn kernel_data_read(&self) -> Result<File, chain::Error>;
 
 	fn kernel_data_write(&self, reader: &mut Read) -> Result<bool, chain::Error>;
@@ -572,16 +2277,348 @@ pub trait ChainAdapter: Sync + Send {
 /// Additional methods required by the protocol that don't need to be
 /// externally implemented.
 pub trait NetAdapter: ChainAdapter {
-	/// Find good peers we know with the provided capability and return their
-	/// addresses.
-	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr>;
+	/// Find good peers we know with any of the provided capabilities and
+	/// return their addresses, the requester's own aside.
+	fn find_peer_addrs(&self, from: PeerAddr, capab: Capabilities) -> Vec<PeerAddr>;
 
 	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, _: Vec<PeerAddr>);
+	fn peer_addrs_received(&self, from: PeerAddr, _: Vec<PeerAddr>);
+
+	/// Addresses of peers we recently connected to, to gossip to a peer
+	/// without it asking.
+	fn gossip_addrs(&self, to: PeerAddr) -> Vec<PeerAddr>;
 
 	/// Heard total_difficulty from a connected peer (via ping/pong).
 	fn peer_difficulty(&self, _: PeerAddr, _: Difficulty, _: u64);
 
+	/// A peer told us why it's closing the connection on us.
+	fn ban_reason_received(&self, addr: PeerAddr, ban_reason: ReasonForBan);
+
+	/// A peer told us how we broke the protocol before disconnecting us.
+	fn peer_error_received(&self, addr: PeerAddr, error: PeerError);
+
+	/// A peer is closing the connection in good order, telling us why.
+	fn disconnect_received(&self, addr: PeerAddr, reason: DisconnectReason);
+
 	/// Is this peer currently banned?
 	fn is_banned(&self, addr: PeerAddr) -> bool;
+
+	/// A peer did something wrong, counted against it.
+	fn report_misbehavior(&self, addr: PeerAddr, offense: Offense);
+}
+
+/// Observes the msgs exchanged with our peers, for monitoring. Lengths are
+/// in bytes, header included. The callbacks are called from the connection
+/// threads (or whoever sends the msg) and must return quickly, anything slow
+/// is better handed over to another thread. A callback panicking is caught
+/// and logged, see `notify`. They all do nothing by default.
+pub trait ProtocolObserver: Send + Sync {
+	/// A msg was handed over to the connection with the peer.
+	fn on_send(&self, _msg_type: Type, _len: u64, _peer: &PeerAddr) {}
+
+	/// A msg came from the peer, before it's handled.
+	fn on_receive(&self, _msg_type: Type, _len: u64, _peer: &PeerAddr) {}
+
+	/// Handling a msg from the peer (or sending one to it) failed.
+	fn on_error(&self, _peer: &PeerAddr, _error: &Error) {}
+}
+
+/// Observes nothing.
+pub struct NoopObserver;
+
+impl ProtocolObserver for NoopObserver {}
+
+/// Calls the observer, if there's one. A panic in the observer is caught,
+/// it doesn't get to take the connection down.
+pub fn notify<F>(observer: &Option<Arc<dyn ProtocolObserver>>, f: F)
+where
+	F: FnOnce(&dyn ProtocolObserver),
+{
+	if let Some(observer) = observer {
+		let res = panic::catch_unwind(panic::AssertUnwindSafe(|| f(observer.as_ref())));
+		if res.is_err() {
+			error!("Protocol observer panicked, ignored.");
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn connected(capabilities: Capabilities, direction: Direction, version: u32) -> PeerStats {
+		PeerStats {
+			addr: PeerAddr::Ip("1.2.3.4:3414".parse().unwrap()),
+			version: ProtocolVersion(version),
+			capabilities,
+			direction,
+			user_agent: "test".to_string(),
+			total_difficulty: Difficulty::min(),
+			height: 0,
+			sent_bytes: 0,
+			received_bytes: 0,
+			sent_rate: 0,
+			recv_rate: 0,
+			msgs_sent: BTreeMap::new(),
+			msgs_received: BTreeMap::new(),
+			outstanding_requests: 0,
+			queued_bytes: 0,
+			misbehavior: 0,
+			discouraged: false,
+			last_seen: Utc::now(),
+			preferred: false,
+			rtt_ms: None,
+			max_rtt_ms: None,
+		}
+	}
+
+	#[test]
+	fn peer_counts() {
+		assert_eq!(PeerCounts::from_stats(&[]), PeerCounts::default());
+
+		let stats = vec![
+			connected(Capabilities::FULL_NODE, Direction::Outbound, 2),
+			connected(
+				Capabilities::FULL_NODE | Capabilities::FULL_HIST,
+				Direction::Outbound,
+				3,
+			),
+			connected(Capabilities::PEER_LIST, Direction::Inbound, 3),
+			connected(
+				Capabilities::from_bits_preserve(Capabilities::PEER_LIST.bits() | 1 << 20),
+				Direction::Inbound,
+				3,
+			),
+			connected(Capabilities::UNKNOWN, Direction::Inbound, 1),
+		];
+		let counts = PeerCounts::from_stats(&stats);
+		assert_eq!(counts.total, 5);
+		assert_eq!(counts.inbound, 3);
+		assert_eq!(counts.outbound, 2);
+		let capabilities = counts
+			.capabilities
+			.iter()
+			.map(|(name, count)| (name.as_str(), *count))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			capabilities,
+			vec![
+				("0x100000", 1),
+				("FULL_HIST", 1),
+				("HEADER_HIST", 2),
+				("PEER_LIST", 4),
+				("TXHASHSET_HIST", 2),
+				("TX_KERNEL_HASH", 2),
+			]
+		);
+		assert_eq!(
+			counts.versions.into_iter().collect::<Vec<_>>(),
+			vec![(1, 1), (2, 1), (3, 3)]
+		);
+	}
+
+	fn routable(addr: &str) -> bool {
+		is_routable(&addr.parse().unwrap())
+	}
+
+	#[test]
+	fn routable_addrs() {
+		// public, private and loopback are all reachable by someone
+		assert!(routable("1.2.3.4:3414"));
+		assert!(routable("10.0.0.2:3414"));
+		assert!(routable("127.0.0.1:3414"));
+		assert!(routable("[2a00:1450::1]:3414"));
+		assert!(routable("[fe80::1]:3414"));
+		assert!(routable("[::1]:3414"));
+
+		// port 0
+		assert!(!routable("1.2.3.4:0"));
+		assert!(!routable("[2a00:1450::1]:0"));
+
+		// unspecified and 0.0.0.0/8
+		assert!(!routable("0.0.0.0:3414"));
+		assert!(!routable("0.1.2.3:3414"));
+		assert!(!routable("[::]:3414"));
+
+		// multicast and broadcast
+		assert!(!routable("224.0.0.1:3414"));
+		assert!(!routable("255.255.255.255:3414"));
+		assert!(!routable("[ff02::1]:3414"));
+
+		// documentation ranges
+		assert!(!routable("192.0.2.1:3414"));
+		assert!(!routable("198.51.100.1:3414"));
+		assert!(!routable("203.0.113.1:3414"));
+		assert!(!routable("[2001:db8::1]:3414"));
+
+		// ipv4-mapped follow the ipv4 rules
+		assert!(routable("[::ffff:1.2.3.4]:3414"));
+		assert!(!routable("[::ffff:224.0.0.1]:3414"));
+
+		// names can't be checked
+		assert!(PeerAddr::Dns("seed.grin.mw".to_string(), 3414).is_routable());
+		assert!(!PeerAddr::Ip("1.2.3.4:0".parse().unwrap()).is_routable());
+	}
+
+	#[test]
+	fn ip_ranges() {
+		let range = "10.1.0.0/16".parse::<IpRange>().unwrap();
+		assert!(range.contains(&"10.1.2.3".parse().unwrap()));
+		assert!(range.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+		assert!(!range.contains(&"10.2.0.1".parse().unwrap()));
+		assert!(!range.contains(&"::1".parse().unwrap()));
+		// host bits don't matter
+		assert_eq!("10.1.2.3/16".parse::<IpRange>().unwrap(), range);
+
+		let range = "2a00:1450::/32".parse::<IpRange>().unwrap();
+		assert!(range.contains(&"2a00:1450:4001::1".parse().unwrap()));
+		assert!(!range.contains(&"2a00:1451::1".parse().unwrap()));
+
+		let all = "0.0.0.0/0".parse::<IpRange>().unwrap();
+		assert!(all.contains(&"1.2.3.4".parse().unwrap()));
+		assert!(!all.contains(&"2a00:1450::1".parse().unwrap()));
+
+		assert!("10.0.0.0".parse::<IpRange>().is_err());
+		assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+		assert!("seed.grin.mw/24".parse::<IpRange>().is_err());
+
+		assert_eq!(
+			subnet(&"1.2.3.4".parse().unwrap()).to_string(),
+			"1.2.3.0/24"
+		);
+		assert_eq!(
+			subnet(&"::ffff:1.2.3.4".parse().unwrap()).to_string(),
+			"1.2.3.0/24"
+		);
+		assert_eq!(
+			subnet(&"2a00:1450:4001:80b::200e".parse().unwrap()).to_string(),
+			"2a00:1450:4001::/48"
+		);
+		assert_eq!(
+			netgroup(&"1.2.3.4".parse().unwrap()).to_string(),
+			"1.2.0.0/16"
+		);
+		assert_eq!(
+			netgroup(&"2a00:1450:4001:80b::200e".parse().unwrap()).to_string(),
+			"2a00:1450::/32"
+		);
+	}
+
+	#[test]
+	fn redial_schedule() {
+		let secs = (0..8)
+			.map(|n| redial_backoff(n).as_secs())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			secs,
+			vec![0, 30, 120, 600, 3600, 6 * 3600, 24 * 3600, 24 * 3600]
+		);
+		assert_eq!(redial_backoff(u32::max_value()).as_secs(), 24 * 3600);
+
+		assert_eq!(redial_failures(REDIAL_BACKOFF), 1);
+		assert_eq!(redial_failures(Duration::from_secs(60)), 2);
+		assert_eq!(redial_failures(REDIAL_VERSION_MISMATCH), 6);
+		assert_eq!(redial_failures(Duration::from_secs(7 * 24 * 3600)), 6);
+	}
+
+	fn peer_info() -> PeerInfo {
+		PeerInfo {
+			capabilities: Capabilities::UNKNOWN,
+			negotiated: Capabilities::UNKNOWN,
+			user_agent: "test".to_string(),
+			version: ProtocolVersion::default(),
+			addr: PeerAddr::Ip("1.2.3.4:3414".parse().unwrap()),
+			direction: Direction::Outbound,
+			our_addr_as_seen: None,
+			node_id: None,
+			our_node_id: NodeId([0; 16]),
+			shake_sent: None,
+			live_info: Arc::new(RwLock::new(PeerLiveInfo::new(Difficulty::min(), 0))),
+			inbound_slot: None,
+		}
+	}
+
+	fn ms(n: u64) -> Duration {
+		Duration::from_millis(n)
+	}
+
+	#[test]
+	fn rtt_converges() {
+		let info = peer_info();
+		let mut now = Instant::now();
+		for _ in 0..50 {
+			let nonce = info.ping_sent_at(now);
+			assert!(info.pong_received_at(Some(nonce), now + ms(100)));
+			now += ms(1000);
+		}
+		assert_eq!(info.rtt(), Some(ms(100)));
+
+		// slower, the estimate gets there within a few pings but not at once
+		let nonce = info.ping_sent_at(now);
+		assert!(info.pong_received_at(Some(nonce), now + ms(300)));
+		let after_one = info.rtt().unwrap();
+		assert!(after_one > ms(100) && after_one < ms(200));
+		for _ in 0..50 {
+			now += ms(1000);
+			let nonce = info.ping_sent_at(now);
+			assert!(info.pong_received_at(Some(nonce), now + ms(300)));
+		}
+		let estimate = info.rtt().unwrap();
+		assert!(estimate > ms(295) && estimate <= ms(300));
+		assert_eq!(info.rtt_max(), Some(ms(300)));
+		assert_eq!(info.unanswered_pings(), 0);
+	}
+
+	#[test]
+	fn rtt_bogus_pongs() {
+		let info = peer_info();
+		let start = Instant::now();
+		let first = info.ping_sent_at(start);
+		let second = info.ping_sent_at(start + ms(1000));
+		assert_eq!(info.unanswered_pings(), 2);
+
+		// out of order, each matched with its own ping
+		assert!(info.pong_received_at(Some(second), start + ms(1100)));
+		assert_eq!(info.rtt(), Some(ms(100)));
+		assert!(info.pong_received_at(Some(first), start + ms(1200)));
+		assert_eq!(info.rtt_max(), Some(ms(1200)));
+		let estimate = info.rtt();
+
+		// answered already, or never sent
+		assert!(!info.pong_received_at(Some(second), start + ms(1300)));
+		assert!(!info.pong_received_at(Some(first ^ second ^ 1), start + ms(1300)));
+		assert_eq!(info.rtt(), estimate);
+
+		// older peers don't echo the nonce
+		let info = peer_info();
+		info.ping_sent_at(start);
+		info.ping_sent_at(start + ms(500));
+		assert!(info.pong_received_at(None, start + ms(600)));
+		assert_eq!(info.rtt(), Some(ms(600)));
+		assert_eq!(info.unanswered_pings(), 0);
+	}
+
+	#[test]
+	fn limits_lowered() {
+		let before = P2PConfig::default().limits();
+		assert!(!before.lowers(&before));
+		let raised = PeerLimits {
+			max_inbound: before.max_inbound + 1,
+			..before
+		};
+		assert!(!raised.lowers(&before));
+		assert!(before.lowers(&raised));
+		// dialing or relaying less drops no one
+		let fewer_dials = PeerLimits {
+			outbound_target: 0,
+			relay_fanout: 1,
+			..before
+		};
+		assert!(!fewer_dials.lowers(&before));
+		let fewer_outbound = PeerLimits {
+			max_outbound: 1,
+			..raised
+		};
+		assert!(fewer_outbound.lowers(&raised));
+	}
 }
@@ -0,0 +1,152 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::dialer::Dialer;
+use crate::p2p::types::PeerAddr;
+use crate::p2p::Server;
+use crate::util::Mutex;
+
+// Dials for real, keeping track of who was dialed.
+struct RecordingDialer {
+	dialed: Arc<Mutex<Vec<PeerAddr>>>,
+}
+
+impl Dialer for RecordingDialer {
+	fn dial(
+		&self,
+		addr: &PeerAddr,
+		timeout: time::Duration,
+	) -> Result<(TcpStream, PeerAddr), p2p::Error> {
+		self.dialed.lock().push(addr.clone());
+		let stream = TcpStream::connect_timeout(&addr.ip_addr().unwrap(), timeout)?;
+		Ok((stream, addr.clone()))
+	}
+}
+
+fn decoy(port: u16) -> p2p::PeerData {
+	p2p::PeerData {
+		addr: PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap()),
+		capabilities: p2p::Capabilities::FULL_NODE,
+		user_agent: "decoy".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: 0,
+		last_direction: None,
+	}
+}
+
+// The outbound peers we had when we stopped are dialed first when we start
+// again, even while backing off from them, the ones that dialed us never.
+// An anchor failing us is left to normal selection.
+#[test]
+fn anchors_dialed_first() {
+	util::init_test_logger();
+	let db_root = ".grin_anchors_a";
+	let _ = fs::remove_dir_all(db_root);
+
+	let mut others = vec![];
+	let mut addrs = vec![];
+	for i in 0..3 {
+		let adapter = Arc::new(PoolAdapter::new(vec![], None));
+		let (server, addr) = start_node(
+			&format!(".grin_anchors_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			adapter,
+		);
+		others.push(server);
+		addrs.push(addr);
+	}
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, a_addr) = start_node(db_root, p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(addrs[0].clone()).unwrap();
+	a_server.connect(addrs[1].clone()).unwrap();
+	others[2].connect(a_addr).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(a_server.peers.peer_outbound_count(), 2);
+	assert_eq!(a_server.peers.peer_inbound_count(), 1);
+
+	assert!(a_server.stop().wait(time::Duration::from_secs(5)));
+	drop(a_server);
+	thread::sleep(time::Duration::from_millis(500));
+	// the second anchor went away while we were down
+	others[1].stop();
+
+	let dialed = Arc::new(Mutex::new(vec![]));
+	let dialer = RecordingDialer {
+		dialed: dialed.clone(),
+	};
+	let config = p2p::P2PConfig {
+		outbound_target: Some(2),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node_dialer(
+		db_root,
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+		Box::new(dialer),
+	);
+	for port in 1..4 {
+		a_server.peers.save_peer(&decoy(port)).unwrap();
+	}
+	a_server
+		.peers
+		.connect_failed(addrs[0].clone(), &p2p::Error::Timeout);
+	assert!(!a_server.peers.can_dial(&addrs[0]));
+	thread::sleep(time::Duration::from_secs(1));
+
+	assert_eq!(Server::maintain_outbound(&a_server), 2);
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(
+		dialed.lock().iter().cloned().collect::<HashSet<_>>(),
+		addrs[..2].iter().cloned().collect::<HashSet<_>>()
+	);
+	assert!(a_server
+		.peers
+		.get_connected_peer(addrs[0].clone())
+		.is_some());
+	assert_eq!(a_server.peers.peer_outbound_count(), 1);
+
+	// the failed anchor isn't dialed again, someone else is instead
+	assert_eq!(Server::maintain_outbound(&a_server), 1);
+	thread::sleep(time::Duration::from_millis(500));
+	let dialed = dialed.lock().clone();
+	assert_eq!(dialed.len(), 3);
+	assert_eq!(dialed.iter().filter(|a| **a == addrs[1]).count(), 1);
+
+	a_server.stop();
+	for other in others {
+		other.stop();
+	}
+}
@@ -0,0 +1,152 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::fs;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader};
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{write_message, Ping, Pong, ProtocolVersion, Type};
+use crate::p2p::types::PeerAddr;
+
+// A raw peer on the provided port, ahead of us.
+fn ahead(addr: &PeerAddr, port: u16) -> (TcpStream, ProtocolVersion) {
+	let (mut conn, version) = connect_raw_from(addr, p2p::Capabilities::FULL_NODE, port);
+	pong(&mut conn, version);
+	(conn, version)
+}
+
+// Pings and waits for the pong, everything sent before handled.
+fn pong(conn: &mut TcpStream, version: ProtocolVersion) {
+	let ping = Ping {
+		total_difficulty: Difficulty::from_num(1000),
+		height: 10,
+		nonce: None,
+	};
+	write_message(conn, ping, version, Type::Ping).unwrap();
+	let _: Pong = read_until(conn, version, Type::Pong).unwrap();
+}
+
+fn local(port: u16) -> PeerAddr {
+	PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap())
+}
+
+// A block our chain refuses, as what it makes of the peer sending it.
+fn refused(adapter: &PoolAdapter, height: u64, offense: p2p::Offense) -> Block {
+	let block = Block::with_header(BlockHeader {
+		height,
+		..BlockHeader::default()
+	});
+	adapter.refused.write().push((block.hash(), offense));
+	block
+}
+
+// A peer sending us a block failing validation is banned for it right away,
+// and sync goes to the other peer.
+#[test]
+fn bad_block_banned() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_bad_block");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_bad_block",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut bad_conn, version) = ahead(&addr, 5001);
+	let _good_conn = ahead(&addr, 5002);
+	let behind = Difficulty::min();
+	assert_eq!(
+		server
+			.peers
+			.more_work_peers(behind, p2p::Capabilities::UNKNOWN)
+			.len(),
+		2
+	);
+
+	let block = refused(&adapter, 1, p2p::Offense::InvalidBlock);
+	write_message(&mut bad_conn, &block, version, Type::Block).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(server.peers.is_banned(local(5001)));
+	let stored = server.peers.get_peer(local(5001)).unwrap();
+	assert_eq!(stored.ban_reason, p2p::ReasonForBan::BadBlock);
+	assert!(server.peers.get_connected_peer(local(5001)).is_none());
+
+	let more_work = server
+		.peers
+		.more_work_peers(behind, p2p::Capabilities::UNKNOWN);
+	assert_eq!(more_work.len(), 1);
+	assert_eq!(more_work[0].info.addr, local(5002));
+	let best = server
+		.peers
+		.most_work_peer(p2p::Capabilities::UNKNOWN)
+		.unwrap();
+	assert_eq!(best.info.addr, local(5002));
+
+	server.stop();
+}
+
+// A block we refuse for what may be our own fault is a lighter strike, the
+// peer is only banned once it's sent us enough of them.
+#[test]
+fn borderline_block_strikes() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_borderline_block");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		borderline_block_strikes: Some(2),
+		..p2p::P2PConfig::default()
+	};
+	let (server, addr) = start_node_with(
+		".grin_borderline_block",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = ahead(&addr, 5001);
+
+	let block = refused(&adapter, 1, p2p::Offense::BorderlineBlock);
+	write_message(&mut conn, &block, version, Type::Block).unwrap();
+	pong(&mut conn, version);
+	assert!(server.peers.get_connected_peer(local(5001)).is_some());
+	assert!(!server.peers.is_banned(local(5001)));
+	assert_eq!(
+		server.peers.misbehavior(&local(5001)),
+		p2p::Offense::BorderlineBlock.weight()
+	);
+
+	let block = refused(&adapter, 2, p2p::Offense::BorderlineBlock);
+	write_message(&mut conn, &block, version, Type::Block).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(server.peers.is_banned(local(5001)));
+	let stored = server.peers.get_peer(local(5001)).unwrap();
+	assert_eq!(stored.ban_reason, p2p::ReasonForBan::BadBlock);
+	assert!(server.peers.get_connected_peer(local(5001)).is_none());
+
+	server.stop();
+}
@@ -0,0 +1,124 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::fs;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+// A banned peer is disconnected, can't be dialed nor reach us while the ban
+// lasts, and is welcome again once it's over.
+#[test]
+fn ban_window_respected() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, a_addr) = start_node(".grin_ban_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_ban_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr.clone()).unwrap();
+
+	a_server.peers.ban_peer(
+		b_addr.clone(),
+		p2p::ReasonForBan::ManualBan,
+		Duration::seconds(3),
+	);
+	assert!(a_server.peers.get_connected_peer(b_addr.clone()).is_none());
+	assert!(a_server.peers.is_banned(b_addr.clone()));
+	let stored = a_server.peers.get_peer(b_addr.clone()).unwrap();
+	assert_eq!(stored.ban_reason, p2p::ReasonForBan::ManualBan);
+	thread::sleep(time::Duration::from_millis(500));
+
+	// neither way while banned
+	match a_server.connect(b_addr.clone()) {
+		Err(p2p::Error::Banned) => {}
+		res => panic!("expected banned, got {:?}", res.map(|_| ())),
+	}
+	assert!(!a_server.peers.can_dial(&b_addr));
+	assert!(b_server.connect(a_addr.clone()).is_err());
+	assert_eq!(a_server.peers.peer_count(), 0);
+
+	// over, lifted on the next lookup
+	thread::sleep(time::Duration::from_secs(3));
+	assert!(a_server.peers.can_dial(&b_addr));
+	assert_eq!(
+		a_server.peers.get_peer(b_addr.clone()).unwrap().flags,
+		p2p::State::Healthy
+	);
+	a_server.connect(b_addr.clone()).unwrap();
+	assert!(a_server.peers.get_connected_peer(b_addr).is_some());
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// Addresses we never heard of can be banned too, and unbanned early.
+#[test]
+fn ban_unknown_addr() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node(".grin_ban_unknown", p2p::Capabilities::FULL_NODE, adapter);
+	let addr = PeerAddr::Ip("10.1.2.3:3414".parse().unwrap());
+	assert!(server.peers.get_peer(addr.clone()).is_err());
+
+	server.peers.ban_peer(
+		addr.clone(),
+		p2p::ReasonForBan::BadBlock,
+		Duration::hours(1),
+	);
+	assert!(server.peers.is_banned(addr.clone()));
+	server.peers.unban_peer(addr.clone());
+	assert!(!server.peers.is_banned(addr.clone()));
+	assert_eq!(
+		server.peers.get_peer(addr).unwrap().flags,
+		p2p::State::Healthy
+	);
+
+	server.stop();
+}
+
+// Bans, their reason and expiry are still there when the store is opened
+// anew.
+#[test]
+fn ban_persisted() {
+	util::init_test_logger();
+
+	let db_root = ".grin_ban_persisted";
+	let _ = fs::remove_dir_all(db_root);
+	let addr = PeerAddr::Ip("10.1.2.3:3414".parse().unwrap());
+	let until = (Utc::now() + Duration::hours(1)).timestamp();
+	{
+		let store = p2p::PeerStore::new(db_root).unwrap();
+		store
+			.ban(addr.clone(), p2p::ReasonForBan::FraudDifficulty, until)
+			.unwrap();
+	}
+
+	let store = p2p::PeerStore::new(db_root).unwrap();
+	let peer = store.get_peer(addr).unwrap();
+	assert_eq!(peer.flags, p2p::State::Banned);
+	assert_eq!(peer.ban_reason, p2p::ReasonForBan::FraudDifficulty);
+	assert_eq!(peer.banned_until, until);
+}
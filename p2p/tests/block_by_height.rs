@@ -0,0 +1,137 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader, TxKernel};
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{
+	read_discard, read_header, write_message, MsgHeaderWrapper, Ping, ProtocolVersion, Type,
+	BODY_TIMEOUT,
+};
+use crate::p2p::types::PeerAddr;
+
+fn block_at(height: u64, fee: u64) -> Block {
+	let mut block = Block::with_header(BlockHeader {
+		height,
+		..BlockHeader::default()
+	});
+	*block.kernels_mut() = vec![TxKernel {
+		fee,
+		..TxKernel::empty()
+	}];
+	block
+}
+
+// Pings and waits for the pong, making sure no block came first.
+fn no_block_before_pong(conn: &mut TcpStream, version: ProtocolVersion) {
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	write_message(conn, ping, version, Type::Ping).unwrap();
+	loop {
+		let deadline = Instant::now() + BODY_TIMEOUT;
+		match read_header(conn, version, None).unwrap() {
+			MsgHeaderWrapper::Known(header) => match header.msg_type {
+				Type::Pong => return,
+				Type::Block => panic!("expected no block"),
+				_ => read_discard(header.msg_len, conn, deadline).unwrap(),
+			},
+			MsgHeaderWrapper::Unknown(msg_len, _) => read_discard(msg_len, conn, deadline).unwrap(),
+		}
+	}
+}
+
+// Blocks are served from our current main chain, nothing past its tip.
+#[test]
+fn block_by_height_served() {
+	util::init_test_logger();
+
+	let block = block_at(3, 1);
+	let adapter = Arc::new(PoolAdapter::new(vec![], Some(block.clone())));
+	let (server, addr) = start_node(
+		".grin_by_height_served",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	write_message(&mut conn, 3u64, version, Type::GetBlockByHeight).unwrap();
+	let b: Block = read_until(&mut conn, version, Type::Block).unwrap();
+	assert_eq!(b.hash(), block.hash());
+
+	write_message(&mut conn, 4u64, version, Type::GetBlockByHeight).unwrap();
+	no_block_before_pong(&mut conn, version);
+
+	// reorged since, we answer with the new block at that height
+	let reorged = block_at(3, 2);
+	*adapter.block.write() = Some(reorged.clone());
+	write_message(&mut conn, 3u64, version, Type::GetBlockByHeight).unwrap();
+	let b: Block = read_until(&mut conn, version, Type::Block).unwrap();
+	assert_eq!(b.hash(), reorged.hash());
+
+	server.stop();
+}
+
+// Whatever the peer sends back, only a block at the height we asked for
+// answers the request, and only once.
+#[test]
+fn block_by_height_checked() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_by_height_checked",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let peer = server
+		.peers
+		.get_connected_peer(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()))
+		.unwrap();
+	peer.send_block_request_by_height(3).unwrap();
+	let height: u64 = read_until(&mut conn, version, Type::GetBlockByHeight).unwrap();
+	assert_eq!(height, 3);
+
+	// reorged in between, the peer's height 3 is now elsewhere
+	let wrong = block_at(2, 1);
+	write_message(&mut conn, &wrong, version, Type::Block).unwrap();
+	let right = block_at(3, 2);
+	write_message(&mut conn, &right, version, Type::Block).unwrap();
+	write_message(&mut conn, &block_at(3, 3), version, Type::Block).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+
+	assert_eq!(adapter.received.lock().len(), 3);
+	assert_eq!(*adapter.requested.lock(), vec![right.hash()]);
+
+	server.stop();
+}
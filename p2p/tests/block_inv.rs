@@ -0,0 +1,79 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader, TxKernel};
+
+// A hub relaying a block pushes it to its fanout only, the other spokes are
+// announced its hash and fetch it.
+#[test]
+fn block_inv_star() {
+	util::init_test_logger();
+
+	let mut block = Block::with_header(BlockHeader::default());
+	*block.kernels_mut() = vec![TxKernel {
+		fee: 1,
+		..TxKernel::empty()
+	}];
+	let kernels = block.kernels().clone();
+
+	let capab = p2p::Capabilities::FULL_NODE | p2p::Capabilities::BLOCK_INV;
+	let hub = Arc::new(PoolAdapter::new(vec![], Some(block.clone())));
+	let (hub_server, _) = start_node(".grin_inv_hub", capab, hub.clone());
+
+	let spokes: Vec<_> = (0..5)
+		.map(|i| {
+			let adapter = Arc::new(PoolAdapter::new(vec![], None));
+			let node = start_node(&format!(".grin_inv_spoke_{}", i), capab, adapter.clone());
+			(node, adapter)
+		})
+		.collect();
+	thread::sleep(time::Duration::from_secs(1));
+	for ((_, addr), _) in &spokes {
+		hub_server.connect(addr.clone()).unwrap();
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	hub_server.peers.broadcast_block(&block, None);
+	thread::sleep(time::Duration::from_secs(2));
+
+	let hash = block.hash();
+	let (mut pushed, mut fetched) = (0, 0);
+	for (_, adapter) in &spokes {
+		let received = adapter.received.lock();
+		if *received == vec![Received::Block(kernels.clone())] {
+			pushed += 1;
+		} else {
+			assert_eq!(
+				*received,
+				vec![Received::Inv(hash), Received::Block(kernels.clone())]
+			);
+			assert_eq!(*adapter.requested.lock(), vec![hash]);
+			fetched += 1;
+		}
+	}
+	let fanout = p2p::P2PConfig::default().block_fanout() as usize;
+	assert_eq!(pushed, fanout);
+	assert_eq!(fetched, spokes.len() - fanout);
+}
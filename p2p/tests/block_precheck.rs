@@ -0,0 +1,121 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::BlockHeader;
+use crate::core::pow::Difficulty;
+use crate::core::ser::{self, Writeable, Writer};
+use crate::p2p::msg::{max_block_size, write_message, Ping, Pong, Type};
+use crate::p2p::types::PeerAddr;
+
+// Whatever bytes, as a msg body.
+struct Raw(Vec<u8>);
+
+impl Writeable for Raw {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&self.0)
+	}
+}
+
+// A header, then garbage for the rest of the block.
+fn block_body(header: BlockHeader) -> Raw {
+	let mut body = ser::ser_vec(&header).unwrap();
+	body.extend_from_slice(&[0xff; 1000]);
+	Raw(body)
+}
+
+fn ping() -> Ping {
+	Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	}
+}
+
+// A block too large for consensus, or too far ahead of anything we know of,
+// is skipped without being deserialized, a strike against the peer.
+#[test]
+fn implausible_block_skipped() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_block_precheck_skipped",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr).unwrap();
+
+	// over the consensus max, still within what the msg header allows
+	let oversized = Raw(vec![0xff; 2 * max_block_size() as usize]);
+	write_message(&mut conn, oversized, version, Type::Block).unwrap();
+	let far_ahead = block_body(BlockHeader {
+		height: 1_000,
+		..BlockHeader::default()
+	});
+	write_message(&mut conn, far_ahead, version, Type::Block).unwrap();
+
+	// the garbage following both headers was never read as a block
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+	let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	assert!(peer.is_connected());
+	assert_eq!(peer.request_strikes(), 2);
+	assert!(adapter.received.lock().is_empty());
+
+	server.stop();
+}
+
+// Way over what a block can be, or not even a header to begin with, and the
+// peer is gone.
+#[test]
+fn garbage_block_dropped() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_block_precheck_dropped",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let bodies = vec![
+		Raw(vec![0xff; 10 * max_block_size() as usize]),
+		Raw(vec![0xff; 1000]),
+	];
+	for (port, body) in (5001..).zip(bodies) {
+		let (mut conn, version) = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, port);
+		let peer_addr = PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap());
+		let peer = server.peers.get_connected_peer(peer_addr).unwrap();
+		// closed on before it's all written, most likely
+		let _ = write_message(&mut conn, body, version, Type::Block);
+		thread::sleep(time::Duration::from_millis(500));
+		assert!(!peer.is_connected());
+	}
+	assert!(adapter.received.lock().is_empty());
+
+	server.stop();
+}
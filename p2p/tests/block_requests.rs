@@ -0,0 +1,107 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::{Hash, Hashed};
+use crate::core::core::{Block, BlockHeader};
+use crate::p2p::msg::{write_message, BlockInv, ProtocolVersion, Type};
+
+// Whether the node asked for the block on this connection since last time.
+fn asked(conn: &mut TcpStream, version: ProtocolVersion, h: Hash) -> bool {
+	conn.set_read_timeout(Some(time::Duration::from_millis(500)))
+		.unwrap();
+	match read_until::<Hash>(conn, version, Type::GetBlock) {
+		Ok(requested) => requested == h,
+		Err(_) => false,
+	}
+}
+
+// A block announced by three peers is asked to one of them only, and to the
+// next one when the first never sends it.
+#[test]
+fn block_request_coalesced() {
+	util::init_test_logger();
+
+	let block = Block::with_header(BlockHeader {
+		height: 1,
+		..BlockHeader::default()
+	});
+	let h = block.hash();
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		block_request_timeout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let (server, addr) = start_node_with(
+		".grin_block_requests",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let mut conns = (0..3)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	thread::sleep(time::Duration::from_millis(500));
+
+	for (conn, version) in conns.iter_mut() {
+		let inv = BlockInv { hash: h, height: 1 };
+		write_message(conn, inv, *version, Type::BlockInv).unwrap();
+	}
+	thread::sleep(time::Duration::from_millis(500));
+	let first = conns
+		.iter_mut()
+		.map(|(conn, version)| asked(conn, *version, h))
+		.collect::<Vec<_>>();
+	assert_eq!(first.iter().filter(|a| **a).count(), 1);
+
+	// still within the timeout, nobody else is asked
+	server.peers.reissue_block_requests();
+	for (conn, version) in conns.iter_mut() {
+		assert!(!asked(conn, *version, h));
+	}
+
+	// the first one never answers
+	thread::sleep(time::Duration::from_secs(1));
+	server.peers.reissue_block_requests();
+	let second = conns
+		.iter_mut()
+		.map(|(conn, version)| asked(conn, *version, h))
+		.collect::<Vec<_>>();
+	assert_eq!(second.iter().filter(|a| **a).count(), 1);
+	let next = second.iter().position(|a| *a).unwrap();
+	assert!(!first[next]);
+
+	// the block comes, we stop asking
+	let (conn, version) = &mut conns[next];
+	write_message(conn, &block, *version, Type::Block).unwrap();
+	thread::sleep(time::Duration::from_millis(1500));
+	assert_eq!(*adapter.requested.lock(), vec![h]);
+	server.peers.reissue_block_requests();
+	for (conn, version) in conns.iter_mut() {
+		assert!(!asked(conn, *version, h));
+	}
+
+	server.stop();
+}
@@ -0,0 +1,394 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-process nodes relaying blocks and txs through a minimal pool, for tests
+//! spanning several nodes.
+
+// not every test uses every helper
+#![allow(dead_code)]
+
+use self::chain::Error;
+use self::core::core::hash::{Hash, Hashed};
+use self::core::core::id::ShortIdentifiable;
+use self::core::core::{Block, BlockHeader, CompactBlock, Transaction};
+use self::core::pow::Difficulty;
+use self::core::ser::Readable;
+use self::p2p::dialer::Dialer;
+use self::p2p::handshake::Handshake;
+use self::p2p::msg::{
+	read_body, read_discard, read_header, MsgHeaderWrapper, ProtocolVersion, Type, BODY_TIMEOUT,
+};
+use self::p2p::types::{ChainAdapter, PeerAddr, PeerInfo, TxHashSetRead};
+use self::util::{Mutex, RwLock, StopState};
+use chrono::prelude::{DateTime, Utc};
+use grin_chain as chain;
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a node got from its peers, in order.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+	/// A compact block, rebuilt from the pool.
+	Hydrated(Vec<core::core::TxKernel>),
+	/// A compact block with a tx missing from the pool.
+	Missing,
+	/// A full block.
+	Block(Vec<core::core::TxKernel>),
+	/// A header, announcing a block.
+	Header(Hash),
+	/// A tx kernel hash announcement.
+	TxKernel(Hash),
+	/// A full tx, by kernel hash.
+	Transaction(Hash),
+	/// A block hash announcement.
+	Inv(Hash),
+}
+
+/// Serves its block (its whole main chain) and pool txs to whoever asks,
+/// rebuilds compact blocks from its pool and asks for whatever it's missing.
+/// New txs are added to the pool and relayed.
+pub struct PoolAdapter {
+	pub pool: RwLock<Vec<Transaction>>,
+	pub block: RwLock<Option<Block>>,
+	pub peers: RwLock<Option<Arc<p2p::Peers>>>,
+	pub received: Mutex<Vec<Received>>,
+	/// Blocks received that we asked for, by hash or height.
+	pub requested: Mutex<Vec<Hash>>,
+	/// The txhashset archive we serve, if any.
+	pub txhashset: RwLock<Option<Vec<u8>>>,
+	/// Txhashset archives received, by block hash.
+	pub txhashsets: Mutex<Vec<(Hash, Vec<u8>)>>,
+	/// Headers served to whoever asks, whatever the locator.
+	pub headers: RwLock<Vec<BlockHeader>>,
+	/// Blocks our chain refuses, with what it makes of their sender.
+	pub refused: RwLock<Vec<(Hash, p2p::Offense)>>,
+}
+
+impl PoolAdapter {
+	pub fn new(pool: Vec<Transaction>, block: Option<Block>) -> PoolAdapter {
+		PoolAdapter {
+			pool: RwLock::new(pool),
+			block: RwLock::new(block),
+			peers: RwLock::new(None),
+			received: Mutex::new(vec![]),
+			requested: Mutex::new(vec![]),
+			txhashset: RwLock::new(None),
+			txhashsets: Mutex::new(vec![]),
+			headers: RwLock::new(vec![]),
+			refused: RwLock::new(vec![]),
+		}
+	}
+
+	fn peers(&self) -> Arc<p2p::Peers> {
+		self.peers.read().as_ref().unwrap().clone()
+	}
+
+	fn peer(&self, peer_info: &PeerInfo) -> Arc<p2p::Peer> {
+		self.peers()
+			.get_connected_peer(peer_info.addr.clone())
+			.unwrap()
+	}
+}
+
+impl ChainAdapter for PoolAdapter {
+	fn total_difficulty(&self) -> Result<Difficulty, Error> {
+		Ok(Difficulty::min())
+	}
+	fn total_height(&self) -> Result<u64, Error> {
+		Ok(0)
+	}
+	fn get_transaction(&self, h: Hash) -> Option<Transaction> {
+		self.pool
+			.read()
+			.iter()
+			.find(|tx| tx.kernels()[0].hash() == h)
+			.cloned()
+	}
+	fn tx_kernel_received(&self, h: Hash, peer_info: &PeerInfo) -> Result<bool, Error> {
+		self.received.lock().push(Received::TxKernel(h));
+		if self.get_transaction(h).is_none() {
+			self.peer(peer_info).send_tx_request(h).unwrap();
+		}
+		Ok(true)
+	}
+	fn block_inv_received(
+		&self,
+		h: Hash,
+		_height: u64,
+		peer_info: &PeerInfo,
+	) -> Result<bool, Error> {
+		self.received.lock().push(Received::Inv(h));
+		if self.get_block(h).is_none() {
+			let peer = self.peer(peer_info);
+			self.peers().request_block(h, &peer, false).unwrap();
+		}
+		Ok(true)
+	}
+	fn transaction_received(
+		&self,
+		tx: Transaction,
+		_stem: bool,
+		peer_info: &PeerInfo,
+	) -> Result<bool, Error> {
+		let h = tx.kernels()[0].hash();
+		self.received.lock().push(Received::Transaction(h));
+		if self.get_transaction(h).is_none() {
+			self.pool.write().push(tx.clone());
+			self.peers()
+				.broadcast_transaction(&tx, Some(&peer_info.addr));
+		}
+		Ok(true)
+	}
+	fn compact_block_received(
+		&self,
+		cb: CompactBlock,
+		peer_info: &PeerInfo,
+	) -> Result<bool, Error> {
+		let hash = cb.hash();
+		let mut txs = vec![];
+		for id in cb.kern_ids() {
+			let tx = self.pool.read().iter().cloned().find(|tx| {
+				tx.kernels()
+					.iter()
+					.any(|k| k.short_id(&hash, cb.nonce) == *id)
+			});
+			match tx {
+				Some(tx) => txs.push(tx),
+				None => {
+					self.received.lock().push(Received::Missing);
+					self.peer(peer_info).send_block_request(hash).unwrap();
+					return Ok(true);
+				}
+			}
+		}
+		let block = Block::hydrate_from(cb, txs).unwrap();
+		self.received
+			.lock()
+			.push(Received::Hydrated(block.kernels().clone()));
+		Ok(true)
+	}
+	fn header_received(&self, bh: BlockHeader, _peer_info: &PeerInfo) -> Result<bool, Error> {
+		self.received.lock().push(Received::Header(bh.hash()));
+		Ok(true)
+	}
+	fn block_received(
+		&self,
+		b: Block,
+		peer_info: &PeerInfo,
+		was_requested: bool,
+	) -> Result<bool, Error> {
+		let refused = self
+			.refused
+			.read()
+			.iter()
+			.find(|r| r.0 == b.hash())
+			.cloned();
+		match refused {
+			Some((_, p2p::Offense::InvalidBlock)) => return Ok(false),
+			Some((_, offense)) => {
+				self.peers()
+					.report_misbehavior(peer_info.addr.clone(), offense);
+				return Ok(true);
+			}
+			None => (),
+		}
+		if was_requested {
+			self.requested.lock().push(b.hash());
+		}
+		self.received
+			.lock()
+			.push(Received::Block(b.kernels().clone()));
+		Ok(true)
+	}
+	fn headers_received(&self, _: &[BlockHeader], _: &PeerInfo) -> Result<bool, Error> {
+		Ok(true)
+	}
+	fn locate_headers(&self, _: &[Hash]) -> Result<Vec<BlockHeader>, Error> {
+		Ok(self.headers.read().clone())
+	}
+	fn get_block(&self, h: Hash) -> Option<Block> {
+		self.block.read().clone().filter(|b| b.hash() == h)
+	}
+	fn get_block_by_height(&self, height: u64) -> Option<Block> {
+		self.block
+			.read()
+			.clone()
+			.filter(|b| b.header.height == height)
+	}
+	fn kernel_data_read(&self) -> Result<File, Error> {
+		unimplemented!()
+	}
+	fn kernel_data_write(&self, _reader: &mut dyn Read) -> Result<bool, Error> {
+		unimplemented!()
+	}
+	fn txhashset_read(&self, _h: Hash) -> Option<TxHashSetRead> {
+		let archive = self.txhashset.read().clone()?;
+		let mut reader = tempfile::tempfile().unwrap();
+		reader.write_all(&archive).unwrap();
+		reader.seek(SeekFrom::Start(0)).unwrap();
+		Some(TxHashSetRead {
+			output_index: 0,
+			kernel_index: 0,
+			reader,
+		})
+	}
+	fn txhashset_receive_ready(&self) -> bool {
+		true
+	}
+	fn txhashset_write(
+		&self,
+		h: Hash,
+		mut txhashset_data: File,
+		_peer_info: &PeerInfo,
+	) -> Result<bool, Error> {
+		let mut archive = vec![];
+		txhashset_data.read_to_end(&mut archive).unwrap();
+		self.txhashsets.lock().push((h, archive));
+		Ok(true)
+	}
+	fn txhashset_download_update(
+		&self,
+		_start_time: DateTime<Utc>,
+		_downloaded_size: u64,
+		_total_size: u64,
+	) -> bool {
+		false
+	}
+	fn get_tmp_dir(&self) -> PathBuf {
+		unimplemented!()
+	}
+	fn get_tmpfile_pathname(&self, tmpfile_name: String) -> PathBuf {
+		env::temp_dir().join(tmpfile_name)
+	}
+}
+
+fn open_port() -> u16 {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	listener.local_addr().unwrap().port()
+}
+
+/// Starts a node listening on localhost with the provided adapter.
+pub fn start_node(
+	db_root: &str,
+	capab: p2p::Capabilities,
+	adapter: Arc<PoolAdapter>,
+) -> (Arc<p2p::Server>, PeerAddr) {
+	start_node_with(db_root, capab, adapter, p2p::P2PConfig::default())
+}
+
+/// Same as `start_node` with the provided config, its host and port aside.
+pub fn start_node_with(
+	db_root: &str,
+	capab: p2p::Capabilities,
+	adapter: Arc<PoolAdapter>,
+	config: p2p::P2PConfig,
+) -> (Arc<p2p::Server>, PeerAddr) {
+	let dialer = config.dialer();
+	start_node_dialer(db_root, capab, adapter, config, dialer)
+}
+
+/// Same as `start_node_with`, dialing peers with the provided dialer.
+pub fn start_node_dialer(
+	db_root: &str,
+	capab: p2p::Capabilities,
+	adapter: Arc<PoolAdapter>,
+	config: p2p::P2PConfig,
+	dialer: Box<dyn Dialer>,
+) -> (Arc<p2p::Server>, PeerAddr) {
+	let config = p2p::P2PConfig {
+		host: "127.0.0.1".parse().unwrap(),
+		port: open_port(),
+		..config
+	};
+	let addr = PeerAddr::Ip(SocketAddr::new(config.host, config.port));
+	let server = Arc::new(
+		p2p::Server::new(
+			db_root,
+			capab,
+			config,
+			adapter.clone(),
+			Hash::from_vec(&vec![]),
+			Arc::new(StopState::new()),
+		)
+		.unwrap()
+		.with_dialer(dialer),
+	);
+	*adapter.peers.write() = Some(server.peers.clone());
+	let server_inner = server.clone();
+	let _ = thread::spawn(move || server_inner.listen());
+	(server, addr)
+}
+
+/// Handshakes with a node as a peer at 127.0.0.1:5000, leaving the raw
+/// connection to the test.
+pub fn connect_raw(addr: &PeerAddr) -> (TcpStream, ProtocolVersion) {
+	connect_raw_as(addr, p2p::Capabilities::UNKNOWN)
+}
+
+/// Same as `connect_raw`, advertising the provided capabilities.
+pub fn connect_raw_as(addr: &PeerAddr, capab: p2p::Capabilities) -> (TcpStream, ProtocolVersion) {
+	connect_raw_from(addr, capab, 5000)
+}
+
+/// Same as `connect_raw_as`, as a peer at 127.0.0.1 on the provided port.
+pub fn connect_raw_from(
+	addr: &PeerAddr,
+	capab: p2p::Capabilities,
+	port: u16,
+) -> (TcpStream, ProtocolVersion) {
+	let socket_addr = addr.ip_addr().unwrap();
+	let mut conn = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(10)).unwrap();
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let info = hs
+		.initiate(
+			capab,
+			Difficulty::min(),
+			0,
+			PeerAddr::Ip(SocketAddr::new("127.0.0.1".parse().unwrap(), port)),
+			addr.clone(),
+			&mut conn,
+			&|_| false,
+		)
+		.unwrap();
+	conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	(conn, info.version)
+}
+
+/// Reads msgs from a raw connection until one of the provided type, the
+/// others are skipped.
+pub fn read_until<T: Readable>(
+	conn: &mut TcpStream,
+	version: ProtocolVersion,
+	msg_type: Type,
+) -> Result<T, p2p::Error> {
+	loop {
+		let deadline = Instant::now() + BODY_TIMEOUT;
+		match read_header(conn, version, None)? {
+			MsgHeaderWrapper::Known(header) if header.msg_type == msg_type => {
+				return read_body(&header, conn, deadline);
+			}
+			MsgHeaderWrapper::Known(header) => read_discard(header.msg_len, conn, deadline)?,
+			MsgHeaderWrapper::Unknown(msg_len, _) => read_discard(msg_len, conn, deadline)?,
+		}
+	}
+}
@@ -0,0 +1,84 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader, Transaction, TxKernel};
+
+// A relayed block goes out compact to peers supporting it, which rebuild it
+// from their pool or fall back to the full block when a tx is missing, and
+// header first to the others.
+#[test]
+fn compact_block_relay() {
+	util::init_test_logger();
+
+	let txs: Vec<_> = (1..6)
+		.map(|fee| {
+			Transaction::empty().with_kernel(TxKernel {
+				fee,
+				..TxKernel::empty()
+			})
+		})
+		.collect();
+	let mut kernels: Vec<_> = txs.iter().map(|tx| tx.kernels()[0].clone()).collect();
+	kernels.sort_unstable();
+	let mut block = Block::with_header(BlockHeader::default());
+	*block.kernels_mut() = kernels.clone();
+
+	let compact = p2p::Capabilities::FULL_NODE | p2p::Capabilities::COMPACT_BLOCKS;
+	let a = Arc::new(PoolAdapter::new(txs.clone(), Some(block.clone())));
+	let (a_server, _) = start_node(".grin_compact_a", compact, a.clone());
+
+	// all txs pooled, one missing, and no compact blocks at all
+	let all = Arc::new(PoolAdapter::new(txs.clone(), None));
+	let one_missing = Arc::new(PoolAdapter::new(txs[1..].to_vec(), None));
+	let legacy = Arc::new(PoolAdapter::new(txs.clone(), None));
+	let (_b, b_addr) = start_node(".grin_compact_b", compact, all.clone());
+	let (_c, c_addr) = start_node(".grin_compact_c", compact, one_missing.clone());
+	let (_d, d_addr) = start_node(
+		".grin_compact_d",
+		p2p::Capabilities::FULL_NODE,
+		legacy.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	for addr in vec![b_addr, c_addr, d_addr] {
+		a_server.connect(addr).unwrap();
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	a_server.peers.broadcast_block(&block, None);
+	thread::sleep(time::Duration::from_secs(2));
+
+	assert_eq!(
+		*all.received.lock(),
+		vec![Received::Hydrated(kernels.clone())]
+	);
+	assert_eq!(
+		*one_missing.received.lock(),
+		vec![Received::Missing, Received::Block(kernels.clone())]
+	);
+	assert_eq!(
+		*legacy.received.lock(),
+		vec![Received::Header(block.hash())]
+	);
+}
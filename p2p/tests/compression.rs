@@ -0,0 +1,83 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hash;
+use crate::core::core::BlockHeader;
+use crate::p2p::msg::{
+	read_body, read_header, write_message, Headers, Locator, MsgHeaderWrapper, ProtocolVersion,
+	Type, BODY_TIMEOUT,
+};
+use crate::p2p::Capabilities;
+
+// Asks for headers, returns whether they came compressed and how many.
+fn get_headers(conn: &mut TcpStream, version: ProtocolVersion) -> (bool, usize) {
+	let loc = Locator {
+		hashes: vec![Hash::from_vec(&vec![1])],
+	};
+	write_message(conn, loc, version, Type::GetHeaders).unwrap();
+	match read_header(conn, version, None).unwrap() {
+		MsgHeaderWrapper::Known(header) => {
+			assert_eq!(header.msg_type, Type::Headers);
+			let headers: Headers = read_body(&header, conn, Instant::now() + BODY_TIMEOUT).unwrap();
+			(header.compressed, headers.headers.len())
+		}
+		MsgHeaderWrapper::Unknown(..) => panic!("expected headers"),
+	}
+}
+
+fn start(db_root: &str) -> (Arc<p2p::Server>, p2p::PeerAddr) {
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	*adapter.headers.write() = vec![BlockHeader::default(); 100];
+	let node = start_node(
+		db_root,
+		Capabilities::FULL_NODE | Capabilities::COMPRESSION,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	node
+}
+
+// A batch of headers goes out compressed when both sides support it.
+#[test]
+fn compressed_when_negotiated() {
+	util::init_test_logger();
+
+	let (server, addr) = start(".grin_compression_negotiated");
+	let (mut conn, version) = connect_raw_as(&addr, Capabilities::COMPRESSION);
+	assert_eq!(get_headers(&mut conn, version), (true, 100));
+	server.stop();
+}
+
+// Peers that didn't advertise compression never get a compressed msg.
+#[test]
+fn uncompressed_to_legacy_peers() {
+	util::init_test_logger();
+
+	let (server, addr) = start(".grin_compression_legacy");
+	let (mut conn, version) = connect_raw(&addr);
+	assert_eq!(get_headers(&mut conn, version), (false, 100));
+	server.stop();
+}
@@ -0,0 +1,149 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::dialer::Dialer;
+use crate::p2p::types::PeerAddr;
+
+// Opens connections slowly, counting them.
+struct CountingDialer {
+	opened: Arc<AtomicUsize>,
+}
+
+impl Dialer for CountingDialer {
+	fn dial(
+		&self,
+		addr: &PeerAddr,
+		timeout: time::Duration,
+	) -> Result<(TcpStream, PeerAddr), p2p::Error> {
+		self.opened.fetch_add(1, Ordering::SeqCst);
+		thread::sleep(time::Duration::from_millis(300));
+		let stream = TcpStream::connect_timeout(&addr.ip_addr().unwrap(), timeout)?;
+		Ok((stream, addr.clone()))
+	}
+}
+
+fn counting_node(
+	db_root: &str,
+	opened: &Arc<AtomicUsize>,
+	config: p2p::P2PConfig,
+) -> Arc<p2p::Server> {
+	let dialer = CountingDialer {
+		opened: opened.clone(),
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node_dialer(
+		db_root,
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+		Box::new(dialer),
+	);
+	server
+}
+
+// Asked to connect to the same peer three times at once, we only dial it
+// once, the other requests told so.
+#[test]
+fn concurrent_dial_once() {
+	util::init_test_logger();
+
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, b_addr) = start_node(".grin_concurrent_dial_b", p2p::Capabilities::FULL_NODE, b);
+	let opened = Arc::new(AtomicUsize::new(0));
+	let a_server = counting_node(
+		".grin_concurrent_dial_a",
+		&opened,
+		p2p::P2PConfig::default(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let handles = (0..3)
+		.map(|_| {
+			let server = a_server.clone();
+			let addr = b_addr.clone();
+			thread::spawn(move || server.connect(addr))
+		})
+		.collect::<Vec<_>>();
+	let results = handles
+		.into_iter()
+		.map(|h| h.join().unwrap())
+		.collect::<Vec<_>>();
+
+	assert_eq!(opened.load(Ordering::SeqCst), 1);
+	assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+	for r in &results {
+		match r {
+			Ok(_) | Err(p2p::Error::AlreadyConnecting) => {}
+			Err(e) => panic!("unexpected error {:?}", e),
+		}
+	}
+	assert!(!a_server.peers.is_attempting(&b_addr));
+	assert_eq!(a_server.peers.peer_count(), 1);
+	thread::sleep(time::Duration::from_millis(200));
+	assert_eq!(b_server.peers.peer_count(), 1);
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// However an attempt ends, failing to connect or timing out on the
+// handshake, the peer can be dialed again.
+#[test]
+fn concurrent_dial_released() {
+	util::init_test_logger();
+
+	let opened = Arc::new(AtomicUsize::new(0));
+	let config = p2p::P2PConfig {
+		handshake_timeout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let server = counting_node(".grin_concurrent_dial_released", &opened, config);
+	thread::sleep(time::Duration::from_secs(1));
+
+	// nothing listening
+	let closed = {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		PeerAddr::Ip(listener.local_addr().unwrap())
+	};
+	assert!(server.connect(closed.clone()).is_err());
+	assert!(!server.peers.is_attempting(&closed));
+	assert!(server.connect(closed.clone()).is_err());
+	assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+	// listening but never answering the handshake
+	let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+	let silent_addr = PeerAddr::Ip(silent.local_addr().unwrap());
+	let res = {
+		let server = server.clone();
+		let addr = silent_addr.clone();
+		thread::spawn(move || server.connect(addr))
+	};
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(server.peers.is_attempting(&silent_addr));
+	assert!(res.join().unwrap().is_err());
+	assert!(!server.peers.is_attempting(&silent_addr));
+
+	server.stop();
+}
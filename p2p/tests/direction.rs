@@ -0,0 +1,85 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::Direction;
+
+// Both ends of a connection know who opened it, in their stats and in what
+// they store about the other.
+#[test]
+fn direction_both_sides() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node(".grin_direction_a", p2p::Capabilities::FULL_NODE, a);
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, b_addr) = start_node(".grin_direction_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+
+	let a_stats = a_server.peers.connected_stats();
+	assert_eq!(a_stats.len(), 1);
+	assert_eq!(a_stats[0].direction, Direction::Outbound);
+	let stored = a_server.peers.get_peer(b_addr).unwrap();
+	assert_eq!(stored.last_direction, Some(Direction::Outbound));
+
+	let b_stats = b_server.peers.connected_stats();
+	assert_eq!(b_stats.len(), 1);
+	assert_eq!(b_stats[0].direction, Direction::Inbound);
+	let stored = b_server.peers.get_peer(b_stats[0].addr.clone()).unwrap();
+	assert_eq!(stored.last_direction, Some(Direction::Inbound));
+	assert_eq!(b_server.peers.peer_inbound_count(), 1);
+	assert_eq!(b_server.peers.peer_outbound_count(), 0);
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// With too many peers, the ones that dialed us go first.
+#[test]
+fn direction_excess_inbound_first() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, a_addr) =
+		start_node(".grin_direction_excess_a", p2p::Capabilities::FULL_NODE, a);
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, b_addr) =
+		start_node(".grin_direction_excess_b", p2p::Capabilities::FULL_NODE, b);
+	let c = Arc::new(PoolAdapter::new(vec![], None));
+	let (c_server, _) = start_node(".grin_direction_excess_c", p2p::Capabilities::FULL_NODE, c);
+	thread::sleep(time::Duration::from_secs(1));
+	c_server.connect(a_addr).unwrap();
+	a_server.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(a_server.peers.peer_count(), 2);
+
+	a_server.peers.clean_peers(1);
+	assert_eq!(a_server.peers.peer_count(), 1);
+	assert_eq!(a_server.peers.peer_inbound_count(), 0);
+	assert!(a_server.peers.get_connected_peer(b_addr).is_some());
+
+	a_server.stop();
+	b_server.stop();
+	c_server.stop();
+}
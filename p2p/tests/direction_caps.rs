@@ -0,0 +1,224 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+// Past the inbound cap, with none of our inbound peers to evict for them,
+// newcomers are closed on once through the handshake. We can still dial out,
+// up to the outbound cap.
+#[test]
+fn inbound_capped_apart() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		max_inbound: Some(2),
+		max_outbound: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node_with(
+		".grin_direction_caps",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	let mut others = vec![];
+	for i in 0..2 {
+		let other = Arc::new(PoolAdapter::new(vec![], None));
+		others.push(start_node(
+			&format!(".grin_direction_caps_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			other,
+		));
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _conns = (0..2)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	// closed on, the disconnect msg aside
+	let (mut conn, _) = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5003);
+	let mut buf = [0; 64];
+	loop {
+		match conn.read(&mut buf) {
+			Ok(0) => break,
+			Ok(_) => {}
+			Err(ref e) if e.kind() == ErrorKind::ConnectionReset => break,
+			res => panic!("expected the connection closed, got {:?}", res),
+		}
+	}
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	// dialing out is another matter
+	server.connect(others[0].1.clone()).unwrap();
+	assert_eq!(server.peers.peer_outbound_count(), 1);
+	match server.connect(others[1].1.clone()) {
+		Err(p2p::Error::TooManyPeers) => {}
+		res => panic!("expected too many peers, got {:?}", res.map(|_| ())),
+	}
+	assert_eq!(server.peers.peer_outbound_count(), 1);
+	assert_eq!(server.peers.peer_count(), 3);
+
+	server.stop();
+	for (other, _) in others {
+		other.stop();
+	}
+}
+
+// Past the inbound cap a newcomer takes the slot of the youngest of our
+// inbound peers we don't protect, all of them from the same network here.
+#[test]
+fn inbound_eviction() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		max_inbound: Some(10),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node_with(
+		".grin_direction_evict",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let mut conns = vec![];
+	for i in 0..10 {
+		conns.push(connect_raw_from(
+			&addr,
+			p2p::Capabilities::UNKNOWN,
+			5001 + i,
+		));
+		thread::sleep(time::Duration::from_millis(50));
+	}
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 10);
+
+	let _newcomer = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5100);
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 10);
+	let peer = |port: u16| PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap());
+	assert!(server.peers.get_connected_peer(peer(5100)).is_some());
+	assert!(server.peers.get_connected_peer(peer(5010)).is_none());
+	for port in 5001..5010 {
+		assert!(server.peers.get_connected_peer(peer(port)).is_some());
+	}
+
+	server.stop();
+}
+
+// Fills our 3 inbound slots and connects a newcomer, with the eviction
+// policy provided. Returns whether the newcomer got in and how many of the
+// first ones did stay.
+fn evict_with(db_root: &str, policy: p2p::InboundEviction) -> (bool, usize) {
+	let config = p2p::P2PConfig {
+		max_inbound: Some(3),
+		inbound_eviction: Some(policy),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node_with(db_root, p2p::Capabilities::FULL_NODE, adapter, config);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _conns = (0..3)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 3);
+
+	let _newcomer = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5100);
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 3);
+	let peer = |port: u16| PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap());
+	let admitted = server.peers.get_connected_peer(peer(5100)).is_some();
+	let stayed = (5001..5004)
+		.filter(|port| server.peers.get_connected_peer(peer(*port)).is_some())
+		.count();
+	server.stop();
+	(admitted, stayed)
+}
+
+// With too few peers to set any aside by network group, the random policy
+// still makes room for a newcomer, the reject one never does.
+#[test]
+fn inbound_eviction_policies() {
+	util::init_test_logger();
+
+	// no round-trip time known for any of them, none protected
+	assert_eq!(
+		evict_with(".grin_direction_random", p2p::InboundEviction::Random),
+		(true, 2)
+	);
+	assert_eq!(
+		evict_with(".grin_direction_reject", p2p::InboundEviction::Reject),
+		(false, 3)
+	);
+	// all the oldest for the default one
+	assert_eq!(
+		evict_with(".grin_direction_netgroup", p2p::InboundEviction::Netgroup),
+		(false, 3)
+	);
+}
+
+// Our preferred peers are never evicted, with all of them preferred the
+// newcomer is refused, policy or not.
+#[test]
+fn inbound_eviction_all_protected() {
+	util::init_test_logger();
+
+	let peer = |port: u16| PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap());
+	let config = p2p::P2PConfig {
+		max_inbound: Some(2),
+		inbound_eviction: Some(p2p::InboundEviction::Random),
+		peers_preferred: Some(vec![peer(5001), peer(5002)]),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node_with(
+		".grin_direction_protected",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _conns = (0..2)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	let _newcomer = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5100);
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(server.peers.get_connected_peer(peer(5100)).is_none());
+	assert!(server.peers.get_connected_peer(peer(5001)).is_some());
+	assert!(server.peers.get_connected_peer(peer(5002)).is_some());
+
+	server.stop();
+}
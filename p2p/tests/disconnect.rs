@@ -0,0 +1,194 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::{Block, BlockHeader, TxKernel};
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{
+	read_body, read_discard, read_header, Disconnect, DisconnectReason, MsgHeaderWrapper,
+	ProtocolVersion, Type, BODY_TIMEOUT,
+};
+use crate::p2p::types::{PeerAddr, RECONNECT_DELAY};
+
+// Types of everything read up to the Disconnect, which is returned as well.
+fn read_to_disconnect(conn: &mut TcpStream, version: ProtocolVersion) -> (Vec<Type>, Disconnect) {
+	let mut types = vec![];
+	loop {
+		let deadline = Instant::now() + BODY_TIMEOUT;
+		match read_header(conn, version, None).unwrap() {
+			MsgHeaderWrapper::Known(header) if header.msg_type == Type::Disconnect => {
+				return (types, read_body(&header, conn, deadline).unwrap());
+			}
+			MsgHeaderWrapper::Known(header) => {
+				types.push(header.msg_type);
+				read_discard(header.msg_len, conn, deadline).unwrap();
+			}
+			MsgHeaderWrapper::Unknown(msg_len, _) => read_discard(msg_len, conn, deadline).unwrap(),
+		}
+	}
+}
+
+// What's queued goes out first, then the Disconnect and its reason, then the
+// connection is closed.
+#[test]
+fn disconnect_flushes_queue() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_disconnect_flush",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr).unwrap();
+
+	peer.send_ping(Difficulty::min(), 0).unwrap();
+	peer.disconnect(DisconnectReason::TooManyPeers);
+	// closing, nothing more gets in
+	assert!(peer.send_ping(Difficulty::min(), 0).is_err());
+
+	let (types, disconnect) = read_to_disconnect(&mut conn, version);
+	assert_eq!(types.iter().filter(|t| **t == Type::Ping).count(), 1);
+	assert_eq!(disconnect.reason(), DisconnectReason::TooManyPeers);
+	assert!(read_header(&mut conn, version, None).is_err());
+	thread::sleep(time::Duration::from_millis(100));
+	assert!(!peer.is_connected());
+
+	server.stop();
+}
+
+// Shutting down we tell our peers so and free our port right away, the
+// peers being all gone shortly after.
+#[test]
+fn disconnect_on_shutdown() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_disconnect_shutdown",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(100));
+	assert_eq!(server.peers.peer_count(), 1);
+
+	let stopping = server.stop();
+	let (_, disconnect) = read_to_disconnect(&mut conn, version);
+	assert_eq!(disconnect.reason(), DisconnectReason::Shutdown);
+	assert!(stopping.wait(time::Duration::from_secs(5)));
+	assert_eq!(server.peers.peer_count(), 0);
+
+	let started = Instant::now();
+	let socket_addr = addr.ip_addr().unwrap();
+	loop {
+		match TcpListener::bind(socket_addr) {
+			Ok(_) => break,
+			Err(e) => {
+				assert!(started.elapsed() < time::Duration::from_secs(1), "{:?}", e);
+				thread::sleep(time::Duration::from_millis(50));
+			}
+		}
+	}
+}
+
+// Told why by a peer closing on us, we wait before dialing it again.
+#[test]
+fn disconnect_received_backs_off() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, a_addr) = start_node(".grin_disconnect_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_disconnect_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+	let peer = a_server.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(a_server.peers.can_dial(&b_addr));
+
+	let from_b = b_server.peers.get_connected_peer(a_addr).unwrap();
+	from_b.disconnect(DisconnectReason::TooManyPeers);
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(!peer.is_connected());
+	assert!(!a_server.peers.can_dial(&b_addr));
+
+	// only for a while, b did nothing wrong
+	let peer_data = a_server.peers.get_peer(b_addr.clone()).unwrap();
+	assert_eq!(peer_data.flags, p2p::State::Healthy);
+	assert_eq!(peer_data.failures, 0);
+	let redial_at = a_server.peers.redial_at(&b_addr).unwrap();
+	assert!(redial_at <= Utc::now() + Duration::seconds(RECONNECT_DELAY.as_secs() as i64));
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// A peer that doesn't read anymore can't hold the close up for longer than
+// the flush deadline.
+#[test]
+fn disconnect_stuck_writer() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_disconnect_stuck",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	// never read from
+	let (_conn, _) = connect_raw(&addr);
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr).unwrap();
+
+	// way more than the socket buffers hold
+	for height in 1..=8 {
+		let mut block = Block::with_header(BlockHeader {
+			height,
+			..BlockHeader::default()
+		});
+		*block.kernels_mut() = vec![TxKernel::empty(); 20_000];
+		let _ = peer.send_block(&block);
+	}
+	thread::sleep(time::Duration::from_millis(500));
+
+	let closing = Instant::now();
+	peer.disconnect(DisconnectReason::Shutdown);
+	while peer.is_connected() {
+		assert!(
+			closing.elapsed() < time::Duration::from_secs(4),
+			"stuck peer never closed"
+		);
+		thread::sleep(time::Duration::from_millis(100));
+	}
+
+	server.stop();
+}
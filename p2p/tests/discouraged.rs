@@ -0,0 +1,155 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+fn addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414))
+}
+
+fn local(port: u16) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(
+		IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+		port,
+	))
+}
+
+fn stored(addr: PeerAddr, flags: p2p::State) -> p2p::PeerData {
+	p2p::PeerData {
+		addr,
+		capabilities: p2p::Capabilities::FULL_NODE,
+		user_agent: "test".to_string(),
+		flags,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: Utc::now().timestamp(),
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: Utc::now().timestamp(),
+		last_direction: None,
+	}
+}
+
+// Discouraged peers are dialed only once the healthy and unverified ones run
+// out, banned ones never, and an expired discouragement is lifted.
+#[test]
+fn discouraged_dialed_last() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node(
+		".grin_discouraged_dial",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	let peers = &server.peers;
+	peers
+		.save_peer(&stored(addr(1), p2p::State::Healthy))
+		.unwrap();
+	peers
+		.save_peer(&stored(addr(2), p2p::State::Unverified))
+		.unwrap();
+	peers
+		.save_peer(&stored(addr(3), p2p::State::Healthy))
+		.unwrap();
+	peers.discourage_peer(addr(3), Duration::hours(1));
+	peers.ban_peer(addr(4), p2p::ReasonForBan::ManualBan, Duration::hours(1));
+	peers.discourage_peer(addr(4), Duration::hours(1));
+	assert!(peers.is_discouraged(addr(3)));
+	assert!(!peers.is_discouraged(addr(4)));
+	assert!(peers.is_banned(addr(4)));
+	assert_eq!(peers.store_stats().discouraged, 1);
+
+	assert_eq!(peers.dial_candidates(2), vec![addr(1), addr(2)]);
+	assert_eq!(peers.dial_candidates(5), vec![addr(1), addr(2), addr(3)]);
+
+	// over, a healthy peer again
+	peers.discourage_peer(addr(3), Duration::seconds(0));
+	assert!(!peers.is_discouraged(addr(3)));
+	assert_eq!(peers.get_peer(addr(3)).unwrap().flags, p2p::State::Healthy);
+	let candidates = peers.dial_candidates(5);
+	assert_eq!(candidates.len(), 3);
+	assert!(candidates[..2].contains(&addr(1)) && candidates[..2].contains(&addr(3)));
+
+	server.stop();
+}
+
+// A discouraged peer connecting to us is only taken while we have fewer
+// inbound peers than half our maximum, others up to the maximum.
+#[test]
+fn discouraged_inbound_admission() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		max_inbound: Some(4),
+		..p2p::P2PConfig::default()
+	};
+	let (server, server_addr) = start_node_with(
+		".grin_discouraged_inbound",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	server
+		.peers
+		.discourage_peer(local(5008), Duration::hours(1));
+	server
+		.peers
+		.discourage_peer(local(5009), Duration::hours(1));
+	thread::sleep(time::Duration::from_secs(1));
+
+	let connect = |port| {
+		let conn = connect_raw_from(&server_addr, p2p::Capabilities::UNKNOWN, port);
+		thread::sleep(time::Duration::from_millis(500));
+		conn
+	};
+
+	// no inbound peer yet, taken
+	let _first = connect(5008);
+	assert!(server.peers.get_connected_peer(local(5008)).is_some());
+	let _second = connect(5001);
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	// half full, refused
+	let _third = connect(5009);
+	assert!(server.peers.get_connected_peer(local(5009)).is_none());
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	// while others are still taken
+	let _fourth = connect(5002);
+	assert_eq!(server.peers.peer_inbound_count(), 3);
+
+	// connected, still discouraged
+	let stats = server.peers.connected_stats();
+	assert!(stats.iter().any(|s| s.addr == local(5008) && s.discouraged));
+	assert_eq!(stats.iter().filter(|s| s.discouraged).count(), 1);
+	assert!(server.peers.is_discouraged(local(5008)));
+
+	server.stop();
+}
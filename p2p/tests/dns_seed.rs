@@ -0,0 +1,202 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::dialer::Dialer;
+use crate::p2p::seeds::{DnsSeeder, Resolver};
+use crate::p2p::types::PeerAddr;
+use crate::util::Mutex;
+
+// Answers for the names it knows, fails for the others.
+struct StubResolver {
+	records: HashMap<String, Vec<IpAddr>>,
+	lookups: Arc<AtomicUsize>,
+}
+
+impl Resolver for StubResolver {
+	fn resolve(&self, name: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+		self.lookups.fetch_add(1, Ordering::SeqCst);
+		match self.records.get(name) {
+			Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+			None => Err(io::Error::new(io::ErrorKind::Other, "no such name")),
+		}
+	}
+}
+
+// Reaches the nodes of the test under the addresses the seeds give.
+struct FakeDialer {
+	nodes: HashMap<PeerAddr, PeerAddr>,
+	dialed: Arc<Mutex<Vec<PeerAddr>>>,
+}
+
+impl Dialer for FakeDialer {
+	fn dial(
+		&self,
+		addr: &PeerAddr,
+		timeout: time::Duration,
+	) -> Result<(TcpStream, PeerAddr), p2p::Error> {
+		self.dialed.lock().push(addr.clone());
+		let stream = TcpStream::connect_timeout(&self.nodes[addr].ip_addr().unwrap(), timeout)?;
+		Ok((stream, addr.clone()))
+	}
+}
+
+fn ip(i: u8) -> IpAddr {
+	IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+}
+
+fn seeder(lookups: Arc<AtomicUsize>) -> DnsSeeder {
+	let mut records = HashMap::new();
+	records.insert(
+		"seed1".to_string(),
+		vec![ip(0), ip(1), IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))],
+	);
+	records.insert("seed2".to_string(), vec![ip(1), ip(2)]);
+	let names = vec!["seed1", "broken", "seed2"];
+	DnsSeeder::new(
+		names.into_iter().map(|n| n.to_string()).collect(),
+		3414,
+		Box::new(StubResolver { records, lookups }),
+	)
+}
+
+fn stored(addr: PeerAddr) -> p2p::PeerData {
+	p2p::PeerData {
+		addr,
+		capabilities: p2p::Capabilities::UNKNOWN,
+		user_agent: "".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: 0,
+		last_direction: None,
+	}
+}
+
+// With an empty store, everything routable the seeds give is saved and dialed,
+// the seed failing to resolve notwithstanding.
+#[test]
+fn seeds_dialed_when_short() {
+	util::init_test_logger();
+
+	let mut nodes = HashMap::new();
+	let mut servers = vec![];
+	for i in 0..3 {
+		let adapter = Arc::new(PoolAdapter::new(vec![], None));
+		let (server, addr) = start_node(
+			&format!(".grin_dns_seed_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			adapter,
+		);
+		nodes.insert(PeerAddr::Ip(SocketAddr::new(ip(i), 3414)), addr);
+		servers.push(server);
+	}
+	let seeds = nodes.keys().cloned().collect::<Vec<_>>();
+	let dialed = Arc::new(Mutex::new(vec![]));
+	let dialer = FakeDialer {
+		nodes,
+		dialed: dialed.clone(),
+	};
+	let _ = fs::remove_dir_all(".grin_dns_seed_hub");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (hub, _) = start_node_dialer(
+		".grin_dns_seed_hub",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		p2p::P2PConfig::default(),
+		Box::new(dialer),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let lookups = Arc::new(AtomicUsize::new(0));
+	let seeder = seeder(lookups.clone());
+	assert_eq!(seeder.seed(&hub), 3);
+	assert_eq!(lookups.load(Ordering::SeqCst), 3);
+	for addr in &seeds {
+		assert!(hub.peers.exists_peer(addr.clone()).unwrap());
+	}
+	let unroutable = PeerAddr::Ip("0.0.0.0:3414".parse().unwrap());
+	assert!(!hub.peers.exists_peer(unroutable).unwrap());
+
+	// proven once connected to
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(hub.peers.peer_outbound_count(), 3);
+	for addr in &seeds {
+		assert_eq!(
+			hub.peers.get_peer(addr.clone()).unwrap().flags,
+			p2p::State::Healthy
+		);
+	}
+	assert_eq!(dialed.lock().len(), 3);
+
+	// nothing left to dial, not resolving again so soon
+	assert_eq!(seeder.seed(&hub), 0);
+	assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+	hub.stop();
+	for server in servers {
+		server.stop();
+	}
+}
+
+// With enough healthy peers in the store the seeds are left alone.
+#[test]
+fn seeds_skipped_when_enough_peers() {
+	util::init_test_logger();
+
+	let dialed = Arc::new(Mutex::new(vec![]));
+	let dialer = FakeDialer {
+		nodes: HashMap::new(),
+		dialed: dialed.clone(),
+	};
+	let _ = fs::remove_dir_all(".grin_dns_seed_enough");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node_dialer(
+		".grin_dns_seed_enough",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		p2p::P2PConfig::default(),
+		Box::new(dialer),
+	);
+	for i in 0..p2p::seeds::DNS_SEED_MIN_PEERS as u8 {
+		let addr = PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414));
+		server.peers.save_peer(&stored(addr)).unwrap();
+	}
+
+	let lookups = Arc::new(AtomicUsize::new(0));
+	let seeder = seeder(lookups.clone());
+	assert_eq!(seeder.seed(&server), 0);
+	assert_eq!(lookups.load(Ordering::SeqCst), 0);
+	assert!(dialed.lock().is_empty());
+
+	server.stop();
+}
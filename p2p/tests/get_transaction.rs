@@ -0,0 +1,78 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::{Hash, Hashed};
+use crate::core::core::{Transaction, TxKernel};
+use crate::p2p::msg::{write_message, PeerError, PeerErrorCode, Type, MAX_TX_REQUESTS_PER_SEC};
+
+fn pool_tx() -> Transaction {
+	Transaction::empty().with_kernel(TxKernel {
+		fee: 1,
+		..TxKernel::empty()
+	})
+}
+
+// Pool txs are served by kernel hash, unknown ones get a not found.
+#[test]
+fn get_transaction_found_or_not() {
+	util::init_test_logger();
+
+	let tx = pool_tx();
+	let kernel_hash = tx.kernels()[0].hash();
+	let adapter = Arc::new(PoolAdapter::new(vec![tx.clone()], None));
+	let (server, addr) = start_node(".grin_get_tx", p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	write_message(&mut conn, kernel_hash, version, Type::GetTransaction).unwrap();
+	let found: Transaction = read_until(&mut conn, version, Type::Transaction).unwrap();
+	assert_eq!(found.hash(), tx.hash());
+
+	let unknown = Hash::from_vec(&vec![1]);
+	write_message(&mut conn, unknown, version, Type::GetTransaction).unwrap();
+	let h: Hash = read_until(&mut conn, version, Type::TransactionNotFound).unwrap();
+	assert_eq!(h, unknown);
+
+	server.stop();
+}
+
+// A peer asking for more txs a second than we serve gets disconnected.
+#[test]
+fn get_transaction_rate_limited() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![pool_tx()], None));
+	let (server, addr) = start_node(".grin_get_tx_limit", p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	let unknown = Hash::from_vec(&vec![1]);
+	for _ in 0..=MAX_TX_REQUESTS_PER_SEC {
+		write_message(&mut conn, unknown, version, Type::GetTransaction).unwrap();
+	}
+	let err: PeerError = read_until(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::RateLimited));
+
+	server.stop();
+}
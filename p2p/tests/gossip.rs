@@ -0,0 +1,199 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{write_message, GetPeerAddrs, PeerAddrs, Ping, Pong, ProtocolVersion, Type};
+use crate::p2p::types::PeerAddr;
+
+fn config() -> p2p::P2PConfig {
+	p2p::P2PConfig {
+		gossip_interval: Some(1),
+		..p2p::P2PConfig::default()
+	}
+}
+
+fn addr(a: u8, b: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, a, b)), 3414))
+}
+
+fn stored(addr: PeerAddr, last_connected: i64) -> p2p::PeerData {
+	p2p::PeerData {
+		addr,
+		capabilities: p2p::Capabilities::UNKNOWN,
+		user_agent: "".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: last_connected,
+		last_direction: None,
+	}
+}
+
+// Waits for the node to go through what we sent it so far.
+fn round_trip(conn: &mut TcpStream, version: ProtocolVersion) {
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	write_message(conn, ping, version, Type::Ping).unwrap();
+	let _: Pong = read_until(conn, version, Type::Pong).unwrap();
+}
+
+// C only ever talks to B, and hears about A from it.
+#[test]
+fn gossip_spreads() {
+	util::init_test_logger();
+
+	let mut nodes = vec![];
+	for name in &["a", "b", "c"] {
+		let db_root = format!(".grin_gossip_{}", name);
+		let _ = fs::remove_dir_all(&db_root);
+		let adapter = Arc::new(PoolAdapter::new(vec![], None));
+		nodes.push(start_node_with(
+			&db_root,
+			p2p::Capabilities::FULL_NODE,
+			adapter,
+			config(),
+		));
+	}
+	let (a_server, a_addr) = &nodes[0];
+	let (b_server, b_addr) = &nodes[1];
+	let (c_server, _) = &nodes[2];
+	thread::sleep(time::Duration::from_secs(1));
+
+	b_server.connect(a_addr.clone()).unwrap();
+	c_server.connect(b_addr.clone()).unwrap();
+	assert!(c_server.peers.get_peer(a_addr.clone()).is_err());
+
+	thread::sleep(time::Duration::from_secs(3));
+	let heard = c_server.peers.get_peer(a_addr.clone()).unwrap();
+	assert_eq!(heard.flags, p2p::State::Unverified);
+	assert!(c_server.peers.get_connected_peer(a_addr.clone()).is_none());
+	assert_eq!(
+		c_server.peers.get_peer(b_addr.clone()).unwrap().flags,
+		p2p::State::Healthy
+	);
+
+	a_server.stop();
+	b_server.stop();
+	c_server.stop();
+}
+
+// What we gossip is a small sample of the peers we recently connected to,
+// never the recipient or an unroutable address.
+#[test]
+fn gossip_sample() {
+	util::init_test_logger();
+
+	let _ = fs::remove_dir_all(".grin_gossip_sample");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, node_addr) = start_node_with(
+		".grin_gossip_sample",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config(),
+	);
+	let now = Utc::now().timestamp();
+	let month_ago = (Utc::now() - Duration::days(30)).timestamp();
+	let mut stale = vec![];
+	for i in 0..30 {
+		server.peers.save_peer(&stored(addr(3, i), now)).unwrap();
+	}
+	for i in 0..5 {
+		server
+			.peers
+			.save_peer(&stored(addr(4, i), month_ago))
+			.unwrap();
+		stale.push(addr(4, i));
+	}
+	let unroutable = PeerAddr::Ip("0.0.0.0:3414".parse().unwrap());
+	server
+		.peers
+		.save_peer(&stored(unroutable.clone(), now))
+		.unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	let (mut conn, version) = connect_raw(&node_addr);
+	let gossip: PeerAddrs = read_until(&mut conn, version, Type::PeerAddrs).unwrap();
+	assert!(!gossip.peers.is_empty());
+	assert!(gossip.peers.len() <= 16);
+	let requester = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	assert!(!gossip.peers.contains(&requester));
+	assert!(!gossip.peers.contains(&unroutable));
+	assert!(gossip.peers.iter().all(|a| !stale.contains(a)));
+
+	server.stop();
+}
+
+// A peer gets to send us addresses unasked once in a while only, answers to
+// our requests are always taken.
+#[test]
+fn unsolicited_addrs_limited() {
+	util::init_test_logger();
+
+	let _ = fs::remove_dir_all(".grin_gossip_unsolicited");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, node_addr) = start_node(
+		".grin_gossip_unsolicited",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&node_addr);
+
+	for a in &[addr(5, 0), addr(5, 1)] {
+		let msg = PeerAddrs {
+			peers: vec![a.clone()],
+		};
+		write_message(&mut conn, msg, version, Type::PeerAddrs).unwrap();
+	}
+	round_trip(&mut conn, version);
+	let heard = server.peers.get_peer(addr(5, 0)).unwrap();
+	assert_eq!(heard.flags, p2p::State::Unverified);
+	assert!(!server.peers.exists_peer(addr(5, 1)).unwrap());
+
+	let requester = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(requester).unwrap();
+	peer.send_peer_request(p2p::Capabilities::PEER_LIST)
+		.unwrap();
+	let _: GetPeerAddrs = read_until(&mut conn, version, Type::GetPeerAddrs).unwrap();
+	let msg = PeerAddrs {
+		peers: vec![addr(5, 2)],
+	};
+	write_message(&mut conn, msg, version, Type::PeerAddrs).unwrap();
+	round_trip(&mut conn, version);
+	assert!(server.peers.exists_peer(addr(5, 2)).unwrap());
+
+	server.stop();
+}
@@ -0,0 +1,105 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::msg::{Ping, Type};
+use crate::p2p::types::PeerAddr;
+
+fn config() -> p2p::P2PConfig {
+	p2p::P2PConfig {
+		keepalive_interval: Some(1),
+		idle_timeout: Some(3),
+		..p2p::P2PConfig::default()
+	}
+}
+
+// A peer that goes silent is pinged, then closed on as unresponsive (not
+// banned) once the idle timeout is over.
+#[test]
+fn silent_peer_evicted() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node_with(
+		".grin_keepalive_silent",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	let connected = Instant::now();
+
+	// frozen from here, we neither read nor write
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr.clone()).unwrap();
+	while peer.is_connected() {
+		assert!(
+			connected.elapsed() < time::Duration::from_secs(5),
+			"silent peer never closed"
+		);
+		thread::sleep(time::Duration::from_millis(100));
+	}
+	assert!(connected.elapsed() >= time::Duration::from_secs(3));
+	assert!(peer.is_unresponsive());
+
+	server.peers.clean_peers(8);
+	assert!(server.peers.get_connected_peer(peer_addr.clone()).is_none());
+	assert!(!server.peers.is_banned(peer_addr));
+
+	// it was pinged while silent
+	let _: Ping = read_until(&mut conn, version, Type::Ping).unwrap();
+
+	server.stop();
+}
+
+// Idle peers keep each other alive, well past the idle timeout.
+#[test]
+fn idle_peers_kept_alive() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node_with(
+		".grin_keepalive_a",
+		p2p::Capabilities::FULL_NODE,
+		a,
+		config(),
+	);
+	let (b_server, b_addr) = start_node_with(
+		".grin_keepalive_b",
+		p2p::Capabilities::FULL_NODE,
+		b,
+		config(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let peer = a_server.connect(b_addr).unwrap();
+
+	thread::sleep(time::Duration::from_secs(6));
+	assert!(peer.is_connected());
+	assert!(!peer.is_unresponsive());
+	assert_eq!(b_server.peers.peer_count(), 1);
+
+	a_server.stop();
+	b_server.stop();
+}
@@ -0,0 +1,123 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hash;
+use crate::p2p::types::PeerAddr;
+
+// A free port on the provided loopback address.
+fn free_addr(ip: &str) -> SocketAddr {
+	let listener = TcpListener::bind(SocketAddr::new(ip.parse().unwrap(), 0)).unwrap();
+	listener.local_addr().unwrap()
+}
+
+// Peers can connect to us on any of our listen addresses, ipv4 and ipv6
+// alike, one we can't bind aside.
+#[test]
+fn listen_dual_stack() {
+	util::init_test_logger();
+
+	let v4 = free_addr("127.0.0.1");
+	let v6 = free_addr("::1");
+	let config = p2p::P2PConfig {
+		// not one of ours
+		listen_addrs: Some(vec![v4, "192.0.2.1:3414".parse().unwrap(), v6]),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node_with(
+		".grin_listen_dual",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let (_v4_conn, _) = connect_raw(&PeerAddr::Ip(v4));
+	let (_v6_conn, _) = connect_raw(&PeerAddr::Ip(v6));
+	thread::sleep(time::Duration::from_millis(500));
+
+	let v4_peer = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let v6_peer = PeerAddr::Ip("[::1]:5000".parse().unwrap());
+	assert!(server.peers.get_connected_peer(v4_peer).is_some());
+	assert!(server.peers.get_connected_peer(v6_peer).is_some());
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+
+	server.stop();
+}
+
+// Our peers are given the address we advertise, not the one we listen on.
+#[test]
+fn listen_advertise_addr() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		advertise_addr: Some(PeerAddr::Ip("127.0.0.1:4242".parse().unwrap())),
+		..p2p::P2PConfig::default()
+	};
+	let (a_server, _) = start_node_with(
+		".grin_listen_advertise_a",
+		p2p::Capabilities::FULL_NODE,
+		a,
+		config,
+	);
+	let (b_server, b_addr) =
+		start_node(".grin_listen_advertise_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+
+	a_server.connect(b_addr).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	let seen = b_server.peers.connected_peers();
+	assert_eq!(seen.len(), 1);
+	assert_eq!(
+		seen[0].info.addr,
+		PeerAddr::Ip("127.0.0.1:4242".parse().unwrap())
+	);
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// Without an address to listen on we don't start at all.
+#[test]
+fn listen_none_bound() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		listen_addrs: Some(vec!["192.0.2.1:3414".parse().unwrap()]),
+		..p2p::P2PConfig::default()
+	};
+	let server = p2p::Server::new(
+		".grin_listen_none",
+		p2p::Capabilities::FULL_NODE,
+		config,
+		Arc::new(p2p::DummyAdapter {}),
+		Hash::from_vec(&vec![]),
+		Arc::new(util::StopState::new()),
+	)
+	.unwrap();
+	assert!(server.listen().is_err());
+}
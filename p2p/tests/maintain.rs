@@ -0,0 +1,181 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::dialer::Dialer;
+use crate::p2p::msg::{GetPeerAddrs, Type};
+use crate::p2p::types::PeerAddr;
+use crate::p2p::Server;
+use crate::util::Mutex;
+
+// Reaches the nodes of the test under made up addresses, slowly, keeping
+// track of how many dials are in progress at once.
+struct FakeDialer {
+	nodes: HashMap<PeerAddr, PeerAddr>,
+	dialed: Arc<Mutex<Vec<PeerAddr>>>,
+	in_flight: Arc<AtomicUsize>,
+	most_in_flight: Arc<Mutex<usize>>,
+}
+
+impl Dialer for FakeDialer {
+	fn dial(
+		&self,
+		addr: &PeerAddr,
+		timeout: time::Duration,
+	) -> Result<(TcpStream, PeerAddr), p2p::Error> {
+		self.dialed.lock().push(addr.clone());
+		let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+		{
+			let mut most = self.most_in_flight.lock();
+			*most = cmp::max(*most, now);
+		}
+		thread::sleep(time::Duration::from_millis(500));
+		let res = TcpStream::connect_timeout(&self.nodes[addr].ip_addr().unwrap(), timeout);
+		self.in_flight.fetch_sub(1, Ordering::SeqCst);
+		Ok((res?, addr.clone()))
+	}
+}
+
+fn fake_addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(
+		IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)),
+		3414,
+	))
+}
+
+fn stored(addr: PeerAddr) -> p2p::PeerData {
+	p2p::PeerData {
+		addr,
+		capabilities: p2p::Capabilities::UNKNOWN,
+		user_agent: "".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: 0,
+		last_direction: None,
+	}
+}
+
+// Peers from the store are dialed until we have the outbound connections we
+// want, never more dials in progress at once than allowed.
+#[test]
+fn outbound_converges() {
+	util::init_test_logger();
+
+	let mut nodes = HashMap::new();
+	let mut servers = vec![];
+	for i in 0..5 {
+		let adapter = Arc::new(PoolAdapter::new(vec![], None));
+		let (server, addr) = start_node(
+			&format!(".grin_maintain_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			adapter,
+		);
+		nodes.insert(fake_addr(i), addr);
+		servers.push(server);
+	}
+	let dialed = Arc::new(Mutex::new(vec![]));
+	let most_in_flight = Arc::new(Mutex::new(0));
+	let dialer = FakeDialer {
+		nodes,
+		dialed: dialed.clone(),
+		in_flight: Arc::new(AtomicUsize::new(0)),
+		most_in_flight: most_in_flight.clone(),
+	};
+	let config = p2p::P2PConfig {
+		outbound_target: Some(3),
+		max_concurrent_dials: Some(2),
+		..p2p::P2PConfig::default()
+	};
+	let _ = fs::remove_dir_all(".grin_maintain_hub");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (hub, _) = start_node_dialer(
+		".grin_maintain_hub",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+		Box::new(dialer),
+	);
+	for i in 0..5 {
+		hub.peers.save_peer(&stored(fake_addr(i))).unwrap();
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	// as many as we can dial at once, then nothing until they're done
+	assert_eq!(Server::maintain_outbound(&hub), 2);
+	assert_eq!(Server::maintain_outbound(&hub), 0);
+	thread::sleep(time::Duration::from_millis(1500));
+	assert_eq!(hub.peers.peer_outbound_count(), 2);
+
+	// the one left to get there
+	assert_eq!(Server::maintain_outbound(&hub), 1);
+	thread::sleep(time::Duration::from_millis(1500));
+	assert_eq!(hub.peers.peer_outbound_count(), 3);
+	assert_eq!(Server::maintain_outbound(&hub), 0);
+
+	let dialed = dialed.lock().clone();
+	assert_eq!(dialed.len(), 3);
+	assert_eq!(dialed.iter().collect::<HashSet<_>>().len(), 3);
+	assert!(*most_in_flight.lock() <= 2);
+	for addr in dialed {
+		assert!(hub.peers.get_connected_peer(addr).is_some());
+	}
+
+	hub.stop();
+	for server in servers {
+		server.stop();
+	}
+}
+
+// With no one in the store to dial, we ask the peers we have for more.
+#[test]
+fn no_candidates_asks_peers() {
+	util::init_test_logger();
+
+	let _ = fs::remove_dir_all(".grin_maintain_empty");
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_maintain_empty",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	assert_eq!(Server::maintain_outbound(&server), 0);
+	let req: GetPeerAddrs = read_until(&mut conn, version, Type::GetPeerAddrs).unwrap();
+	assert!(req.capabilities.contains(p2p::Capabilities::PEER_LIST));
+	assert_eq!(server.peers.peer_outbound_count(), 0);
+
+	server.stop();
+}
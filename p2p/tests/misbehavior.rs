@@ -0,0 +1,105 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::BlockHeader;
+use crate::core::pow::Difficulty;
+use crate::core::ser::{self, Writeable, Writer};
+use crate::p2p::msg::{write_message, Ping, Pong, Type};
+use crate::p2p::types::PeerAddr;
+
+// A header far ahead of any tip, then garbage for the rest of the block.
+struct FarAhead;
+
+impl Writeable for FarAhead {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let header = BlockHeader {
+			height: 1_000,
+			..BlockHeader::default()
+		};
+		header.write(writer)?;
+		writer.write_fixed_bytes(&[0xff; 1000].to_vec())
+	}
+}
+
+// Implausible blocks are skipped, failing validation each time. Below the
+// threshold the peer stays, its score in its stats, discouraged first, once
+// over it's banned.
+#[test]
+fn misbehavior_escalates_to_ban() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		ban_score_threshold: Some(100),
+		ban_window: Some(60),
+		..p2p::P2PConfig::default()
+	};
+	let (server, addr) = start_node_with(
+		".grin_misbehavior",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr.clone()).unwrap();
+	let ping = || Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+
+	// twice the weight of a failed validation, under the threshold
+	for _ in 0..2 {
+		write_message(&mut conn, FarAhead, version, Type::Block).unwrap();
+	}
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+	let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	assert!(peer.is_connected());
+	assert!(!server.peers.is_banned(peer_addr.clone()));
+	let stats = server.peers.connected_stats();
+	assert_eq!(stats.len(), 1);
+	assert_eq!(
+		stats[0].misbehavior,
+		2 * p2p::Offense::FailedValidation.weight()
+	);
+	// past the discourage threshold already
+	assert!(stats[0].discouraged);
+	assert!(server.peers.is_discouraged(peer_addr.clone()));
+
+	// once more and it's over
+	write_message(&mut conn, FarAhead, version, Type::Block).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(!peer.is_connected());
+	assert!(server.peers.is_banned(peer_addr.clone()));
+	let stored = server.peers.get_peer(peer_addr.clone()).unwrap();
+	assert_eq!(stored.ban_reason, p2p::ReasonForBan::ProtocolViolation);
+	let expected = Utc::now().timestamp() + 60;
+	assert!(stored.banned_until > expected - 5 && stored.banned_until <= expected);
+	assert_eq!(server.peers.misbehavior(&peer_addr), 0);
+
+	server.stop();
+}
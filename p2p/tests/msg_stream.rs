@@ -0,0 +1,156 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use grin_core as core;
+use grin_p2p as p2p;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::core::core::{Block, BlockHeader, TxKernel};
+use crate::core::pow::Difficulty;
+use crate::core::ser;
+use crate::p2p::msg::{
+	write_message, write_streamed, write_to_buf, Ping, ProtocolVersion, Type, STREAM_CHUNK_SIZE,
+};
+
+// Keeps track of the largest allocation made while tracking is on, on the
+// thread that turned it on only: the other tests run alongside.
+struct MaxAlloc;
+
+thread_local! {
+	static TRACKING: Cell<bool> = Cell::new(false);
+}
+static MAX_ALLOC: AtomicUsize = AtomicUsize::new(0);
+
+fn tracking() -> bool {
+	TRACKING.try_with(|t| t.get()).unwrap_or(false)
+}
+
+unsafe impl GlobalAlloc for MaxAlloc {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if tracking() && layout.size() > MAX_ALLOC.load(Ordering::SeqCst) {
+			MAX_ALLOC.store(layout.size(), Ordering::SeqCst);
+		}
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOC: MaxAlloc = MaxAlloc;
+
+// A block of about `size` bytes, all kernels.
+fn synthetic_block(size: usize) -> Block {
+	let kernel_len = ser::ser_vec(&TxKernel::empty()).unwrap().len();
+	let mut block = Block::with_header(BlockHeader::default());
+	*block.kernels_mut() = vec![TxKernel::empty(); size / kernel_len];
+	block
+}
+
+// A 4MB block goes out without any allocation larger than a chunk.
+#[test]
+fn streamed_block_allocations() {
+	let block = synthetic_block(4 * 1024 * 1024);
+
+	TRACKING.with(|t| t.set(true));
+	let sent = write_streamed(
+		&mut io::sink(),
+		&block,
+		ProtocolVersion::default(),
+		Type::Block,
+	);
+	TRACKING.with(|t| t.set(false));
+
+	assert!(sent.unwrap() > 4 * 1024 * 1024 - 1024);
+	let max_alloc = MAX_ALLOC.load(Ordering::SeqCst);
+	assert!(
+		max_alloc <= STREAM_CHUNK_SIZE,
+		"allocated {} bytes at once",
+		max_alloc
+	);
+}
+
+// Streamed or not, the bytes on the wire are the same.
+#[test]
+fn streamed_block_same_bytes() {
+	let block = synthetic_block(STREAM_CHUNK_SIZE * 3 + 100);
+	for version in vec![ProtocolVersion(1), ProtocolVersion::default()] {
+		let mut streamed = vec![];
+		let sent = write_streamed(&mut streamed, &block, version, Type::Block).unwrap();
+		assert_eq!(sent, streamed.len() as u64);
+		assert_eq!(
+			streamed,
+			write_to_buf(&block, version, Type::Block).unwrap()
+		);
+	}
+}
+
+// Framed in one pass, to a stream or a buffer, a msg is the same bytes.
+#[test]
+fn write_message_same_bytes() {
+	let block = synthetic_block(1024 * 1024);
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 42,
+		nonce: None,
+	};
+	for version in vec![ProtocolVersion(1), ProtocolVersion::default()] {
+		let mut written = vec![];
+		let sent = write_message(&mut written, &block, version, Type::Block).unwrap();
+		assert_eq!(sent, written.len() as u64);
+		assert_eq!(written, write_to_buf(&block, version, Type::Block).unwrap());
+		let mut streamed = vec![];
+		write_streamed(&mut streamed, &block, version, Type::Block).unwrap();
+		assert_eq!(written, streamed);
+
+		let mut written = vec![];
+		let sent = write_message(&mut written, &ping, version, Type::Ping).unwrap();
+		assert_eq!(sent, written.len() as u64);
+		assert_eq!(written, write_to_buf(&ping, version, Type::Ping).unwrap());
+	}
+}
+
+// Single pass framing against the two passes of streaming, on a 1MB block.
+// Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_write_1mb() {
+	let block = synthetic_block(1024 * 1024);
+	let version = ProtocolVersion::default();
+	let rounds = 100;
+
+	let start = Instant::now();
+	for _ in 0..rounds {
+		write_streamed(&mut io::sink(), &block, version, Type::Block).unwrap();
+	}
+	let two_passes = start.elapsed();
+
+	let start = Instant::now();
+	for _ in 0..rounds {
+		write_message(&mut io::sink(), &block, version, Type::Block).unwrap();
+	}
+	let one_pass = start.elapsed();
+
+	println!(
+		"1MB block, {} rounds: two passes {:?}, one pass {:?}",
+		rounds, two_passes, one_pass
+	);
+}
@@ -0,0 +1,136 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{write_message, Ping, Pong, Type};
+use crate::p2p::types::PeerAddr;
+use crate::util::Mutex;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+	Send(Type),
+	Receive(Type),
+}
+
+// Records what it sees, in order.
+struct Transcript {
+	events: Mutex<Vec<(Event, u64, PeerAddr)>>,
+}
+
+impl p2p::ProtocolObserver for Transcript {
+	fn on_send(&self, msg_type: Type, len: u64, peer: &PeerAddr) {
+		self.events
+			.lock()
+			.push((Event::Send(msg_type), len, peer.clone()));
+	}
+
+	fn on_receive(&self, msg_type: Type, len: u64, peer: &PeerAddr) {
+		self.events
+			.lock()
+			.push((Event::Receive(msg_type), len, peer.clone()));
+	}
+}
+
+struct Panicking;
+
+impl p2p::ProtocolObserver for Panicking {
+	fn on_receive(&self, _: Type, _: u64, _: &PeerAddr) {
+		panic!("observer panic");
+	}
+}
+
+// Both ends of a ping and its pong, as seen by the observers on each side.
+#[test]
+fn observer_transcript() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, a_addr) = start_node(".grin_observer_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_observer_b", p2p::Capabilities::FULL_NODE, b);
+	let a_seen = Arc::new(Transcript {
+		events: Mutex::new(vec![]),
+	});
+	let b_seen = Arc::new(Transcript {
+		events: Mutex::new(vec![]),
+	});
+	a_server.set_observer(Some(a_seen.clone()));
+	b_server.set_observer(Some(b_seen.clone()));
+	thread::sleep(time::Duration::from_secs(1));
+
+	let peer = a_server.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+	peer.send_ping(Difficulty::min(), 0).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+
+	let a_events = a_seen.events.lock();
+	let types = a_events.iter().map(|(e, _, _)| e).collect::<Vec<_>>();
+	assert_eq!(
+		types,
+		vec![&Event::Send(Type::Ping), &Event::Receive(Type::Pong)]
+	);
+	assert!(a_events
+		.iter()
+		.all(|(_, len, addr)| *len > 0 && *addr == b_addr));
+
+	// the other end sees it the other way around, from a's listening address
+	let b_events = b_seen.events.lock();
+	let types = b_events.iter().map(|(e, _, _)| e).collect::<Vec<_>>();
+	assert_eq!(
+		types,
+		vec![&Event::Receive(Type::Ping), &Event::Send(Type::Pong)]
+	);
+	assert_eq!(b_events[0].1, a_events[0].1);
+	assert_eq!(b_events[1].1, a_events[1].1);
+	assert!(b_events.iter().all(|(_, _, addr)| *addr == a_addr));
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// An observer panicking doesn't get in the way of the protocol.
+#[test]
+fn observer_panic_caught() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_observer_panic",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	server.set_observer(Some(Arc::new(Panicking)));
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	write_message(&mut conn, ping, version, Type::Ping).unwrap();
+	let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+
+	server.stop();
+}
@@ -0,0 +1,114 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::msg::{write_message, GetPeerAddrs, PeerAddrs, Type};
+use crate::p2p::types::PeerAddr;
+
+fn stored(addr: PeerAddr, last_connected: i64) -> p2p::PeerData {
+	p2p::PeerData {
+		addr,
+		capabilities: p2p::Capabilities::PEER_LIST,
+		user_agent: "test".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: last_connected,
+		last_direction: None,
+	}
+}
+
+// Our live peers make up most of a GetPeerAddrs reply, stale stored peers
+// only top it up, and the requester never gets its own or an unroutable
+// address back.
+#[test]
+fn peer_addrs_live_first() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_peer_addrs_live",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	let month_ago = (Utc::now() - Duration::days(30)).timestamp();
+	let mut stale = vec![];
+	for i in 0..20 {
+		let addr = PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414));
+		server
+			.peers
+			.save_peer(&stored(addr.clone(), month_ago))
+			.unwrap();
+		stale.push(addr);
+	}
+	let unroutable = PeerAddr::Ip("0.0.0.0:3414".parse().unwrap());
+	server
+		.peers
+		.save_peer(&stored(unroutable.clone(), Utc::now().timestamp()))
+		.unwrap();
+
+	let mut live = vec![];
+	let mut others = vec![];
+	for i in 0..3 {
+		let other = Arc::new(PoolAdapter::new(vec![], None));
+		let (other_server, other_addr) = start_node(
+			&format!(".grin_peer_addrs_live_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			other,
+		);
+		others.push(other_server);
+		live.push(other_addr);
+	}
+	thread::sleep(time::Duration::from_secs(1));
+	for other_addr in &live {
+		server.connect(other_addr.clone()).unwrap();
+	}
+
+	let (mut conn, version) = connect_raw(&addr);
+	let msg = GetPeerAddrs {
+		capabilities: p2p::Capabilities::UNKNOWN,
+	};
+	write_message(&mut conn, msg, version, Type::GetPeerAddrs).unwrap();
+	let reply: PeerAddrs = read_until(&mut conn, version, Type::PeerAddrs).unwrap();
+
+	let requester = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	assert!(!reply.peers.contains(&requester));
+	assert!(!reply.peers.contains(&unroutable));
+	let live_count = reply.peers.iter().filter(|a| live.contains(a)).count();
+	let stale_count = reply.peers.iter().filter(|a| stale.contains(a)).count();
+	assert_eq!(live_count + stale_count, reply.peers.len());
+	assert!(live_count > stale_count);
+	assert!(stale_count > 0);
+
+	server.stop();
+	for other_server in others {
+		other_server.stop();
+	}
+}
@@ -0,0 +1,140 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::msg::DisconnectReason;
+use crate::p2p::{PeerEvent, PeerEventKind, PeerEventReceiver};
+
+// All the events until none comes for a while.
+fn drain(rx: &PeerEventReceiver) -> Vec<PeerEvent> {
+	let mut events = vec![];
+	while let Some(event) = rx.recv_timeout(time::Duration::from_millis(500)) {
+		events.push(event);
+	}
+	events
+}
+
+// A connect and disconnect cycle, then a ban and its lifting, as seen from
+// both sides.
+#[test]
+fn peer_events_cycle() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node(".grin_peer_events_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_peer_events_b", p2p::Capabilities::FULL_NODE, b);
+	let a_events = a_server.subscribe(100);
+	let b_events = b_server.subscribe(100);
+	thread::sleep(time::Duration::from_secs(1));
+
+	a_server.connect(b_addr.clone()).unwrap();
+	let events = drain(&a_events);
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].addr, b_addr);
+	match events[0].kind {
+		PeerEventKind::Connected(ref info) => assert!(info.is_outbound()),
+		ref kind => panic!("expected connected, got {:?}", kind),
+	}
+	let events = drain(&b_events);
+	assert_eq!(events.len(), 1);
+	let inbound_addr = events[0].addr.clone();
+	match events[0].kind {
+		PeerEventKind::Connected(ref info) => assert!(!info.is_outbound()),
+		ref kind => panic!("expected connected, got {:?}", kind),
+	}
+
+	// b leaves, telling a, which notices once it cleans up
+	b_server.stop();
+	let events = drain(&b_events);
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].addr, inbound_addr);
+	match events[0].kind {
+		PeerEventKind::Disconnected(DisconnectReason::Shutdown) => {}
+		ref kind => panic!("expected disconnected on shutdown, got {:?}", kind),
+	}
+	assert!(drain(&a_events).is_empty());
+	a_server.peers.clean_peers(8);
+	let events = drain(&a_events);
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].addr, b_addr);
+	match events[0].kind {
+		PeerEventKind::Disconnected(DisconnectReason::None) => {}
+		ref kind => panic!("expected disconnected, got {:?}", kind),
+	}
+
+	// banned while not connected, then unbanned
+	a_server.peers.ban_peer(
+		b_addr.clone(),
+		p2p::ReasonForBan::ManualBan,
+		Duration::hours(1),
+	);
+	a_server.peers.unban_peer(b_addr.clone());
+	let events = drain(&a_events);
+	assert_eq!(events.len(), 2);
+	match events[0].kind {
+		PeerEventKind::Banned(p2p::ReasonForBan::ManualBan, until) => {
+			assert!(until > Utc::now().timestamp())
+		}
+		ref kind => panic!("expected banned, got {:?}", kind),
+	}
+	match events[1].kind {
+		PeerEventKind::Healthy => {}
+		ref kind => panic!("expected healthy, got {:?}", kind),
+	}
+	assert!(events.iter().all(|e| e.addr == b_addr));
+	assert_eq!(a_events.dropped(), 0);
+
+	a_server.stop();
+}
+
+// A subscriber that doesn't keep up loses the oldest events, nothing else.
+#[test]
+fn peer_events_bounded() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_peer_events_bounded",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	let events = server.subscribe(2);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _conns = (0..4)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 4);
+	assert_eq!(events.dropped(), 2);
+	let events = drain(&events);
+	assert_eq!(events.len(), 2);
+	assert!(events.iter().all(|e| match e.kind {
+		PeerEventKind::Connected(_) => true,
+		_ => false,
+	}));
+
+	server.stop();
+}
@@ -0,0 +1,164 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+fn addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414))
+}
+
+fn stored(i: u8, flags: p2p::State, last_seen: i64) -> p2p::PeerData {
+	p2p::PeerData {
+		addr: addr(i),
+		capabilities: p2p::Capabilities::PEER_LIST,
+		user_agent: "test".to_string(),
+		flags,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: last_seen,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen,
+		last_direction: None,
+	}
+}
+
+fn state(server: &p2p::Server, i: u8) -> p2p::State {
+	server.peers.get_peer(addr(i)).unwrap().flags
+}
+
+// Every state makes it through a file to another node's store as it was.
+#[test]
+fn peer_file_roundtrip() {
+	util::init_test_logger();
+	let path = ".grin_peer_file_roundtrip.txt";
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node(".grin_peer_file_a", p2p::Capabilities::FULL_NODE, a);
+	let states = vec![
+		p2p::State::Healthy,
+		p2p::State::Defunct,
+		p2p::State::Incompatible,
+		p2p::State::Unverified,
+	];
+	for (i, flags) in states.iter().enumerate() {
+		a_server
+			.peers
+			.save_peer(&stored(i as u8, *flags, 1000 + i as i64))
+			.unwrap();
+	}
+	a_server.peers.ban_peer(
+		addr(9),
+		p2p::ReasonForBan::ManualBan,
+		chrono::Duration::hours(1),
+	);
+	let exported = a_server.peers.save_to_file(path).unwrap();
+	assert_eq!(exported, a_server.peers.all_peers().len());
+
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, _) = start_node(".grin_peer_file_b", p2p::Capabilities::FULL_NODE, b);
+	let import = b_server.peers.load_from_file(path).unwrap();
+	assert_eq!(import.imported, exported);
+	assert_eq!(import.malformed, 0);
+	for (i, flags) in states.iter().enumerate() {
+		let peer = b_server.peers.get_peer(addr(i as u8)).unwrap();
+		assert_eq!(peer.flags, *flags);
+		assert_eq!(peer.capabilities, p2p::Capabilities::PEER_LIST);
+		assert_eq!(peer.last_seen, 1000 + i as i64);
+	}
+	assert_eq!(state(&b_server, 9), p2p::State::Banned);
+	assert!(!b_server.peers.can_dial(&addr(9)));
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// What we banned stays banned, what we heard from last wins, unroutable
+// addresses are left out and malformed lines are counted.
+#[test]
+fn peer_file_merge() {
+	util::init_test_logger();
+	let path = ".grin_peer_file_merge.txt";
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node(
+		".grin_peer_file_merge",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	server.peers.ban_peer(
+		addr(1),
+		p2p::ReasonForBan::BadBlock,
+		chrono::Duration::hours(1),
+	);
+	server
+		.peers
+		.save_peer(&stored(2, p2p::State::Healthy, 2000))
+		.unwrap();
+	server
+		.peers
+		.save_peer(&stored(3, p2p::State::Healthy, 2000))
+		.unwrap();
+
+	let mut file = fs::File::create(path).unwrap();
+	writeln!(file, "# address capabilities state last_seen").unwrap();
+	writeln!(file, "{} 15 Healthy 3000", addr(1)).unwrap();
+	writeln!(file, "{} 15 Defunct 1000", addr(2)).unwrap();
+	writeln!(file, "{} 15 Defunct 3000", addr(3)).unwrap();
+	writeln!(file, "{} 15 Banned 1000", addr(4)).unwrap();
+	writeln!(file, "0.0.0.0:3414 15 Healthy 3000").unwrap();
+	writeln!(file, "{} 15 Sleepy 3000", addr(5)).unwrap();
+	writeln!(file, "not an address 15 Healthy 3000").unwrap();
+	writeln!(file, "{} 15 Healthy", addr(6)).unwrap();
+	drop(file);
+
+	let import = server.peers.load_from_file(path).unwrap();
+	assert_eq!(
+		import,
+		p2p::PeersImport {
+			imported: 3,
+			ignored: 2,
+			malformed: 3,
+		}
+	);
+	assert_eq!(state(&server, 1), p2p::State::Banned);
+	assert_eq!(
+		server.peers.get_peer(addr(1)).unwrap().ban_reason,
+		p2p::ReasonForBan::BadBlock
+	);
+	assert_eq!(state(&server, 2), p2p::State::Healthy);
+	assert_eq!(state(&server, 3), p2p::State::Defunct);
+	assert_eq!(state(&server, 4), p2p::State::Banned);
+	assert!(server.peers.get_peer(addr(5)).is_err());
+	assert!(server.peers.get_peer(addr(6)).is_err());
+	assert!(server
+		.peers
+		.get_peer(PeerAddr::Ip("0.0.0.0:3414".parse().unwrap()))
+		.is_err());
+
+	server.stop();
+}
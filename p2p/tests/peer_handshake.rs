@@ -18,14 +18,29 @@ use grin_p2p as p2p;
 use grin_util as util;
 use grin_util::StopState;
 
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use chrono::prelude::Utc;
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::sync::Arc;
 use std::{thread, time};
 
 use crate::core::core::hash::Hash;
+use crate::core::core::{Block, BlockHeader, TxKernel};
 use crate::core::pow::Difficulty;
-use crate::p2p::types::PeerAddr;
-use crate::p2p::Peer;
+use crate::core::ser;
+use crate::p2p::handshake::{
+	check_chain_state, negotiate_capabilities, negotiate_version, Handshake, HandshakeCounts,
+};
+use crate::p2p::msg::{
+	magic, read_message, write_message, write_to_buf, BanReason, Checksum, Hand, Headers, Locator,
+	MsgHeader, PeerError, PeerErrorCode, Ping, Pong, ProtocolVersion, Shake, Type, FLOONET_MAGIC,
+};
+use crate::p2p::types::{
+	redial_backoff, NetAdapter, PeerAddr, RetryPolicy, SelfAddrs, REDIAL_BACKOFF,
+};
+use crate::p2p::{Peer, PeerInfo, Protocol, RequestTracker};
 
 fn open_port() -> u16 {
 	// use port 0 to allow the OS to assign an open port
@@ -35,6 +50,41 @@ fn open_port() -> u16 {
 	listener.local_addr().unwrap().port()
 }
 
+// Runs the accepting side of a handshake on a background thread and returns
+// the address to dial along with a handle to retrieve the handshake result.
+fn accept_handshake(
+	hs: Handshake,
+) -> (SocketAddr, thread::JoinHandle<Result<PeerInfo, p2p::Error>>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let handle = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		hs.accept(
+			p2p::Capabilities::UNKNOWN,
+			Difficulty::min(),
+			0,
+			conn.peer_addr().ok(),
+			&mut conn,
+			&|_| false,
+		)
+	});
+	(addr, handle)
+}
+
+fn initiate_handshake(hs: &Handshake, addr: SocketAddr) -> Result<PeerInfo, p2p::Error> {
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	hs.initiate(
+		p2p::Capabilities::UNKNOWN,
+		Difficulty::min(),
+		0,
+		my_addr,
+		PeerAddr::Ip(addr),
+		&mut conn,
+		&|_| false,
+	)
+}
+
 // Starts a server and connects a client peer to it to check handshake,
 // followed by a ping/pong exchange to make sure the connection is live.
 #[test]
@@ -69,12 +119,14 @@ fn peer_handshake() {
 	let addr = SocketAddr::new(p2p_config.host, p2p_config.port);
 	let socket = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
 
-	let my_addr = PeerAddr("127.0.0.1:5000".parse().unwrap());
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
 	let peer = Peer::connect(
 		socket,
+		PeerAddr::Ip(addr),
 		p2p::Capabilities::UNKNOWN,
 		Difficulty::min(),
-		my_addr,
+		0,
+		my_addr.clone(),
 		&p2p::handshake::Handshake::new(Hash::from_vec(&vec![]), p2p_config.clone()),
 		net_adapter,
 	)
@@ -89,5 +141,1598 @@ fn peer_handshake() {
 
 	let server_peer = server.peers.get_connected_peer(my_addr).unwrap();
 	assert_eq!(server_peer.info.total_difficulty(), Difficulty::min());
+	assert!(peer.info.handshake_rtt().is_some());
+	assert!(server_peer.info.handshake_rtt().is_some());
 	assert!(server.peers.peer_count() > 0);
 }
+
+// Both sides agree on the genesis hash so the handshake succeeds.
+#[test]
+fn handshake_genesis_match() {
+	util::init_test_logger();
+
+	let genesis = Hash::from_vec(&vec![1]);
+	let config = p2p::P2PConfig::default();
+	let (addr, server) = accept_handshake(Handshake::new(genesis, config.clone()));
+
+	let client = initiate_handshake(&Handshake::new(genesis, config), addr);
+	assert!(client.is_ok());
+	assert!(server.join().unwrap().is_ok());
+}
+
+// Peers on different chains must be rejected with a dedicated error.
+#[test]
+fn handshake_genesis_mismatch() {
+	util::init_test_logger();
+
+	let ours = Hash::from_vec(&vec![1]);
+	let theirs = Hash::from_vec(&vec![2]);
+	let config = p2p::P2PConfig::default();
+	let (addr, server) = accept_handshake(Handshake::new(ours, config.clone()));
+
+	let client = initiate_handshake(&Handshake::new(theirs, config), addr);
+	assert!(client.is_err());
+	match server.join().unwrap() {
+		Err(p2p::Error::GenesisMismatch { us, peer }) => {
+			assert_eq!(us, ours);
+			assert_eq!(peer, theirs);
+		}
+		res => panic!("expected genesis mismatch, got {:?}", res),
+	}
+}
+
+#[test]
+fn handshake_negotiate_version() {
+	let v = |n| ProtocolVersion(n);
+
+	// remote supports a higher version than us, we pick ours
+	assert_eq!(negotiate_version((v(1), v(2)), (v(1), v(3))).unwrap(), v(2));
+
+	// remote max is lower but still within our range, we pick theirs
+	assert_eq!(negotiate_version((v(1), v(3)), (v(1), v(2))).unwrap(), v(2));
+
+	// disjoint ranges, the error names both of them
+	match negotiate_version((v(1), v(2)), (v(3), v(4))) {
+		Err(p2p::Error::ProtocolMismatch { ours, theirs }) => {
+			assert_eq!(ours, (v(1), v(2)));
+			assert_eq!(theirs, (v(3), v(4)));
+		}
+		res => panic!("expected protocol mismatch, got {:?}", res),
+	}
+}
+
+#[test]
+fn handshake_negotiate_capabilities() {
+	let ours = p2p::Capabilities::FULL_NODE;
+
+	// bits we don't know about are kept as advertised but never negotiated
+	let theirs =
+		p2p::Capabilities::from_bits_preserve(p2p::Capabilities::PEER_LIST.bits() | 0b1000_0000);
+	assert_eq!(theirs.bits() & 0b1000_0000, 0b1000_0000);
+	assert_eq!(
+		negotiate_capabilities(ours, theirs),
+		p2p::Capabilities::PEER_LIST
+	);
+
+	// a peer advertising nothing gets nothing
+	assert_eq!(
+		negotiate_capabilities(ours, p2p::Capabilities::UNKNOWN),
+		p2p::Capabilities::UNKNOWN
+	);
+}
+
+#[test]
+fn handshake_check_chain_state() {
+	assert!(check_chain_state(0, Difficulty::min()).is_ok());
+	assert!(check_chain_state(1_000, Difficulty::from_num(1_000_000)).is_ok());
+
+	// height 0 with an enormous difficulty
+	match check_chain_state(0, Difficulty::from_num(u64::max_value())) {
+		Err(p2p::Error::ImplausibleChain { height, .. }) => assert_eq!(height, 0),
+		res => panic!("expected implausible chain, got {:?}", res),
+	}
+	// millions of blocks with difficulty 1
+	match check_chain_state(5_000_000, Difficulty::from_num(1)) {
+		Err(p2p::Error::ImplausibleChain { height, .. }) => assert_eq!(height, 5_000_000),
+		res => panic!("expected implausible chain, got {:?}", res),
+	}
+}
+
+// A peer that accepts our connection but never answers must not hold the
+// handshake open past the configured timeout.
+#[test]
+fn handshake_timeout() {
+	util::init_test_logger();
+
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _ = thread::spawn(move || {
+		let (_conn, _) = listener.accept().unwrap();
+		thread::sleep(time::Duration::from_secs(10));
+	});
+
+	let config = p2p::P2PConfig {
+		handshake_timeout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let hs = Handshake::new(Hash::from_vec(&vec![]), config);
+
+	let start = time::Instant::now();
+	match initiate_handshake(&hs, addr) {
+		Err(p2p::Error::Timeout) => {}
+		res => panic!("expected timeout, got {:?}", res),
+	}
+	assert!(start.elapsed() < time::Duration::from_secs(5));
+}
+
+// Connecting to ourselves is detected through the handshake nonce and reported
+// with a dedicated error, our address is recorded so we stop dialing it.
+#[test]
+fn handshake_peer_with_self() {
+	util::init_test_logger();
+
+	let hs = Arc::new(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let hs_inner = hs.clone();
+	let server = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		hs_inner.accept(
+			p2p::Capabilities::UNKNOWN,
+			Difficulty::min(),
+			0,
+			conn.peer_addr().ok(),
+			&mut conn,
+			&|_| false,
+		)
+	});
+
+	assert!(initiate_handshake(&hs, addr).is_err());
+	match server.join().unwrap() {
+		Err(p2p::Error::PeerWithSelf) => {}
+		res => panic!("expected peer with self, got {:?}", res),
+	}
+	assert_eq!(hs.addrs.len(), 1);
+}
+
+// A Shake that does not echo our nonce (a replayed or stale reply) must be
+// rejected by the dialing side.
+#[test]
+fn handshake_nonce_echo_mismatch() {
+	util::init_test_logger();
+
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _ = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		let hand: Hand = read_message(&mut conn, ProtocolVersion::handshake(), Type::Hand).unwrap();
+		let shake = Shake {
+			version: hand.version,
+			min_version: hand.min_version,
+			capabilities: p2p::Capabilities::UNKNOWN,
+			nonce: Some(hand.nonce.wrapping_add(1)),
+			genesis: hand.genesis,
+			total_difficulty: Difficulty::min(),
+			height: Some(0),
+			observed_addr: Some(hand.sender_addr),
+			user_agent: "test".to_string(),
+			node_id: None,
+			extensions: vec![],
+		};
+		write_message(&mut conn, shake, ProtocolVersion::handshake(), Type::Shake).unwrap();
+		thread::sleep(time::Duration::from_secs(1));
+	});
+
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	match initiate_handshake(&hs, addr) {
+		Err(p2p::Error::BadMessage) => {}
+		res => panic!("expected bad message, got {:?}", res),
+	}
+}
+
+// The dialing side measures the time between its hand and the shake, the
+// accepting side only knows once the next message arrives.
+#[test]
+fn handshake_rtt() {
+	util::init_test_logger();
+
+	let delay = time::Duration::from_millis(300);
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _ = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		let hand: Hand = read_message(&mut conn, ProtocolVersion::handshake(), Type::Hand).unwrap();
+		thread::sleep(delay);
+		let shake = Shake {
+			version: hand.version,
+			min_version: hand.min_version,
+			capabilities: p2p::Capabilities::UNKNOWN,
+			nonce: Some(hand.nonce),
+			genesis: hand.genesis,
+			total_difficulty: Difficulty::min(),
+			height: Some(0),
+			observed_addr: Some(hand.sender_addr),
+			user_agent: "test".to_string(),
+			node_id: None,
+			extensions: vec![],
+		};
+		write_message(&mut conn, shake, ProtocolVersion::handshake(), Type::Shake).unwrap();
+		thread::sleep(time::Duration::from_secs(1));
+	});
+
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let info = initiate_handshake(&hs, addr).unwrap();
+	let rtt = info.handshake_rtt().unwrap();
+	assert!(rtt >= delay, "rtt {:?} shorter than the delay", rtt);
+	assert!(rtt < delay * 5, "rtt {:?} way longer than the delay", rtt);
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	initiate_handshake(&hs, addr).unwrap();
+	let info = server.join().unwrap().unwrap();
+	assert!(info.shake_sent.is_some());
+	assert_eq!(info.handshake_rtt(), None);
+}
+
+// Every version we may negotiate has a protocol implementation, anything else
+// is an error and not a silent fallback to some other version.
+#[test]
+fn handshake_protocol_for_version() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let info = initiate_handshake(&hs, addr).unwrap();
+	server.join().unwrap().unwrap();
+
+	let adapter = Arc::new(p2p::DummyAdapter {});
+	let requests = Arc::new(RequestTracker::new());
+	let limits = p2p::P2PConfig::default().rate_limits();
+	for v in ProtocolVersion::min_supported().0..=ProtocolVersion::default().0 {
+		let version = ProtocolVersion(v);
+		let protocol = Protocol::for_version(
+			version,
+			adapter.clone(),
+			info.clone(),
+			requests.clone(),
+			limits.clone(),
+			None,
+		);
+		assert!(protocol.is_ok());
+	}
+	let unsupported = ProtocolVersion(ProtocolVersion::default().0 + 1);
+	match Protocol::for_version(unsupported, adapter, info, requests, limits, None) {
+		Err(p2p::Error::UnsupportedProtocol(v)) => assert_eq!(v, unsupported),
+		Err(e) => panic!("expected unsupported protocol, got {:?}", e),
+		Ok(_) => panic!("expected unsupported protocol"),
+	}
+}
+
+// A hand from a floonet node is rejected by a mainnet node (our tests run on
+// mainnet) before even looking at the message.
+#[test]
+fn handshake_wrong_network() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hand = Hand {
+		version: ProtocolVersion::default(),
+		min_version: ProtocolVersion::min_supported(),
+		capabilities: p2p::Capabilities::UNKNOWN,
+		nonce: 42,
+		genesis: Hash::from_vec(&vec![]),
+		total_difficulty: Difficulty::min(),
+		height: Some(0),
+		sender_addr: PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()),
+		receiver_addr: PeerAddr::Ip(addr),
+		user_agent: "test".to_string(),
+		node_id: None,
+		extensions: vec![],
+	};
+	let mut buf = write_to_buf(hand, ProtocolVersion::handshake(), Type::Hand).unwrap();
+	buf[..2].copy_from_slice(&FLOONET_MAGIC);
+
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	conn.write_all(&buf).unwrap();
+	match server.join().unwrap() {
+		Err(p2p::Error::WrongNetwork) => {}
+		res => panic!("expected wrong network, got {:?}", res),
+	}
+}
+
+// The shake goes out with the version we picked, older peers get the layout
+// they can read, with the height where they expect it.
+#[test]
+fn handshake_shake_layout_for_version() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hand = Hand {
+		version: ProtocolVersion(2),
+		min_version: ProtocolVersion::min_supported(),
+		capabilities: p2p::Capabilities::UNKNOWN,
+		nonce: 42,
+		genesis: Hash::from_vec(&vec![]),
+		total_difficulty: Difficulty::min(),
+		height: Some(0),
+		sender_addr: PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()),
+		receiver_addr: PeerAddr::Ip(addr),
+		user_agent: "test".to_string(),
+		node_id: None,
+		extensions: vec![],
+	};
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	write_message(&mut conn, hand, ProtocolVersion::handshake(), Type::Hand).unwrap();
+	let shake: Shake = read_message(&mut conn, ProtocolVersion::handshake(), Type::Shake).unwrap();
+	assert_eq!(shake.version, ProtocolVersion(2));
+	assert!(!shake.version.has_extensions());
+	assert!(shake.node_id.is_some());
+	assert_eq!(server.join().unwrap().unwrap().version, ProtocolVersion(2));
+}
+
+// A msg body written as it is.
+struct RawBody(Vec<u8>);
+
+impl ser::Writeable for RawBody {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&self.0)
+	}
+}
+
+// A peer running version 1 sends the Hand of version 1, nothing after the
+// genesis, and gets the Shake of version 1 back. Its height unknown, its
+// difficulty is taken as it is.
+#[test]
+fn handshake_v1_peer() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let mut body = ser::ser_vec(&ProtocolVersion(1)).unwrap();
+	body.extend(ser::ser_vec(&p2p::Capabilities::FULL_NODE.bits()).unwrap());
+	body.extend(ser::ser_vec(&42u64).unwrap());
+	body.extend(ser::ser_vec(&Difficulty::from_num(1 << 40)).unwrap());
+	body.extend(ser::ser_vec(&PeerAddr::Ip("127.0.0.1:5000".parse().unwrap())).unwrap());
+	body.extend(ser::ser_vec(&PeerAddr::Ip(addr)).unwrap());
+	let user_agent = b"MW/Grin 1.0.0".to_vec();
+	body.extend(ser::ser_vec(&(user_agent.len() as u64)).unwrap());
+	body.extend(user_agent);
+	body.extend(ser::ser_vec(&Hash::from_vec(&vec![])).unwrap());
+
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	write_message(
+		&mut conn,
+		RawBody(body),
+		ProtocolVersion::handshake(),
+		Type::Hand,
+	)
+	.unwrap();
+	let shake: Shake = read_message(&mut conn, ProtocolVersion::handshake(), Type::Shake).unwrap();
+	assert_eq!(shake.version, ProtocolVersion(1));
+	assert_eq!(shake.nonce, None);
+	assert_eq!(shake.height, None);
+
+	let info = server.join().unwrap().unwrap();
+	assert_eq!(info.version, ProtocolVersion(1));
+	assert_eq!(info.height(), 0);
+	assert_eq!(info.total_difficulty(), Difficulty::from_num(1 << 40));
+}
+
+// A hand declaring an absurd body length is refused from its header alone.
+#[test]
+fn handshake_hand_too_large() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let head = ser::ser_vec(&MsgHeader::new(Type::Hand, u64::max_value())).unwrap();
+
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	conn.write_all(&head).unwrap();
+	match server.join().unwrap() {
+		Err(p2p::Error::MsgLen) => {}
+		res => panic!("expected msg len error, got {:?}", res),
+	}
+}
+
+// Cancelling interrupts a handshake stuck on a peer that never replies, and
+// refuses any new one.
+#[test]
+fn handshake_cancel() {
+	util::init_test_logger();
+
+	// accepts the connection (or leaves it in the backlog) but never replies
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+
+	let hs = Arc::new(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hs_c = hs.clone();
+	let client = thread::spawn(move || initiate_handshake(&hs_c, addr));
+
+	thread::sleep(time::Duration::from_millis(200));
+	let cancelled_at = time::Instant::now();
+	hs.cancel();
+	match client.join().unwrap() {
+		Err(p2p::Error::Cancelled) => {}
+		res => panic!("expected cancelled, got {:?}", res),
+	}
+	assert!(cancelled_at.elapsed() < time::Duration::from_millis(100));
+
+	match initiate_handshake(&hs, addr) {
+		Err(p2p::Error::Cancelled) => {}
+		res => panic!("expected cancelled, got {:?}", res),
+	}
+	assert_eq!(hs.stats().other_errors, 2);
+	drop(listener);
+}
+
+// A banned peer is refused before we reply with our shake, even when only the
+// address it advertises (and not the address it connects from) is banned.
+#[test]
+fn handshake_banned_advertised_addr() {
+	util::init_test_logger();
+
+	let banned = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let server = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		hs.accept(
+			p2p::Capabilities::UNKNOWN,
+			Difficulty::min(),
+			0,
+			conn.peer_addr().ok(),
+			&mut conn,
+			&|addr| *addr == banned,
+		)
+	});
+
+	let client = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	assert!(initiate_handshake(&client, addr).is_err());
+	match server.join().unwrap() {
+		Err(p2p::Error::Banned) => {}
+		res => panic!("expected banned, got {:?}", res),
+	}
+}
+
+// Only the configured number of handshakes are let through to the Hand read,
+// the others are dropped right away. Slots are released once handshakes end,
+// here by timing out.
+#[test]
+fn handshake_inflight_limit() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		handshake_timeout: Some(1),
+		max_inflight_handshakes: Some(2),
+		..p2p::P2PConfig::default()
+	};
+	let hs = Arc::new(Handshake::new(Hash::from_vec(&vec![]), config));
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+
+	// raw connections that never send their Hand
+	let clients: Vec<TcpStream> = (0..4)
+		.map(|_| TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap())
+		.collect();
+
+	let handles: Vec<_> = (0..4)
+		.map(|_| {
+			let (mut conn, _) = listener.accept().unwrap();
+			let hs = hs.clone();
+			thread::spawn(move || {
+				hs.accept(
+					p2p::Capabilities::UNKNOWN,
+					Difficulty::min(),
+					0,
+					conn.peer_addr().ok(),
+					&mut conn,
+					&|_| false,
+				)
+			})
+		})
+		.collect();
+
+	let (mut timeouts, mut refused) = (0, 0);
+	for handle in handles {
+		match handle.join().unwrap() {
+			Err(p2p::Error::Timeout) => timeouts += 1,
+			Err(p2p::Error::TooManyHandshakes) => refused += 1,
+			res => panic!("unexpected handshake result {:?}", res),
+		}
+	}
+	assert_eq!(timeouts, 2);
+	assert_eq!(refused, 2);
+	assert_eq!(hs.in_flight(), 0);
+	drop(clients);
+}
+
+fn start_server(db_root: &str) -> Arc<p2p::Server> {
+	start_server_with(db_root, p2p::Capabilities::UNKNOWN)
+}
+
+fn start_server_with(db_root: &str, capab: p2p::Capabilities) -> Arc<p2p::Server> {
+	let config = p2p::P2PConfig {
+		host: "127.0.0.1".parse().unwrap(),
+		port: open_port(),
+		..p2p::P2PConfig::default()
+	};
+	start_server_config(db_root, capab, config)
+}
+
+fn start_server_config(
+	db_root: &str,
+	capab: p2p::Capabilities,
+	config: p2p::P2PConfig,
+) -> Arc<p2p::Server> {
+	let server = Arc::new(
+		p2p::Server::new(
+			db_root,
+			capab,
+			config,
+			Arc::new(p2p::DummyAdapter {}),
+			Hash::from_vec(&vec![]),
+			Arc::new(StopState::new()),
+		)
+		.unwrap(),
+	);
+	let server_inner = server.clone();
+	let _ = thread::spawn(move || server_inner.listen());
+	server
+}
+
+// Two nodes advertising encryption encrypt their connection and keep talking
+// through it, a node without it still connects in the clear.
+#[test]
+fn handshake_encrypted() {
+	util::init_test_logger();
+
+	let encrypted = p2p::Capabilities::PEER_LIST | p2p::Capabilities::ENCRYPTED;
+	let a = start_server_with(".grin_encrypted_a", encrypted);
+	let b = start_server_with(".grin_encrypted_b", encrypted);
+	let c = start_server_with(".grin_encrypted_c", p2p::Capabilities::PEER_LIST);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let a_addr = PeerAddr::Ip(SocketAddr::new(a.config.host, a.config.port));
+	let b_addr = PeerAddr::Ip(SocketAddr::new(b.config.host, b.config.port));
+	let c_addr = PeerAddr::Ip(SocketAddr::new(c.config.host, c.config.port));
+
+	let peer = b.connect(a_addr.clone()).unwrap();
+	assert!(peer.info.negotiated.contains(p2p::Capabilities::ENCRYPTED));
+	thread::sleep(time::Duration::from_secs(1));
+
+	// the ping gets through, and so does the pong
+	peer.send_ping(Difficulty::from_num(42), 7).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+	let a_peer = a.peers.get_connected_peer(b_addr).unwrap();
+	assert!(a_peer
+		.info
+		.negotiated
+		.contains(p2p::Capabilities::ENCRYPTED));
+	assert_eq!(a_peer.info.total_difficulty(), Difficulty::from_num(42));
+	assert_eq!(a_peer.info.height(), 7);
+	assert!(peer.is_connected());
+	assert!(a_peer.is_connected());
+
+	let peer = c.connect(a_addr).unwrap();
+	assert!(!peer.info.negotiated.contains(p2p::Capabilities::ENCRYPTED));
+	thread::sleep(time::Duration::from_secs(1));
+	peer.send_ping(Difficulty::from_num(42), 7).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+	let a_peer = a.peers.get_connected_peer(c_addr).unwrap();
+	assert_eq!(a_peer.info.height(), 7);
+
+	a.stop();
+	b.stop();
+	c.stop();
+}
+
+// Two nodes dialing each other at the same time end up agreeing on a single
+// connection, the other one is dropped on both sides.
+#[test]
+fn handshake_simultaneous_dial() {
+	util::init_test_logger();
+
+	let a = start_server(".grin_dup_a");
+	let b = start_server(".grin_dup_b");
+	thread::sleep(time::Duration::from_secs(1));
+
+	let a_sock = SocketAddr::new(a.config.host, a.config.port);
+	let b_sock = SocketAddr::new(b.config.host, b.config.port);
+	let a_addr = PeerAddr::Ip(a_sock);
+	let b_addr = PeerAddr::Ip(b_sock);
+
+	// a dials b
+	a.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	// and b dials a, as if it hadn't seen the connection from a yet
+	let res = dial_back(&a, &b, a_addr.clone());
+	thread::sleep(time::Duration::from_secs(1));
+	check_single_connection(res, &a, &b, a_addr, b_addr);
+
+	a.stop();
+	b.stop();
+}
+
+// Same when the two sides see different addresses, as with a node behind a
+// NAT advertising its public address: b knows a by the public address a
+// advertises, a only knows its local one.
+#[test]
+fn handshake_simultaneous_dial_nat() {
+	util::init_test_logger();
+
+	let port = open_port();
+	let a_public = PeerAddr::Ip(SocketAddr::new("1.2.3.4".parse().unwrap(), port));
+	let config = p2p::P2PConfig {
+		host: "127.0.0.1".parse().unwrap(),
+		port,
+		advertise_addr: Some(a_public.clone()),
+		..p2p::P2PConfig::default()
+	};
+	let a = start_server_config(".grin_dup_nat_a", p2p::Capabilities::UNKNOWN, config);
+	let b = start_server(".grin_dup_nat_b");
+	thread::sleep(time::Duration::from_secs(1));
+
+	let b_addr = PeerAddr::Ip(SocketAddr::new(b.config.host, b.config.port));
+	a.connect(b_addr.clone()).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+	assert!(b.peers.get_connected_peer(a_public.clone()).is_some());
+
+	let res = dial_back(&a, &b, a_public.clone());
+	thread::sleep(time::Duration::from_secs(1));
+	check_single_connection(res, &a, &b, a_public, b_addr);
+
+	a.stop();
+	b.stop();
+}
+
+// b dials a, known to b as `a_addr`, and adds the connection to its peers.
+fn dial_back(a: &p2p::Server, b: &p2p::Server, a_addr: PeerAddr) -> Result<(), p2p::Error> {
+	let a_sock = SocketAddr::new(a.config.host, a.config.port);
+	let b_addr = PeerAddr::Ip(SocketAddr::new(b.config.host, b.config.port));
+	let handshake = Handshake::new(Hash::from_vec(&vec![]), b.config.clone());
+	handshake.set_node_id(b.node_id());
+	let socket = TcpStream::connect_timeout(&a_sock, time::Duration::from_secs(10)).unwrap();
+	let peer = Peer::connect(
+		socket,
+		a_addr,
+		p2p::Capabilities::UNKNOWN,
+		Difficulty::min(),
+		0,
+		b_addr,
+		&handshake,
+		b.peers.clone(),
+	)
+	.unwrap();
+	b.peers.add_connected(Arc::new(peer))
+}
+
+// Both sides kept the same connection, b its outbound one only if it has the
+// smaller node id.
+fn check_single_connection(
+	res: Result<(), p2p::Error>,
+	a: &p2p::Server,
+	b: &p2p::Server,
+	a_addr: PeerAddr,
+	b_addr: PeerAddr,
+) {
+	if b.node_id() < a.node_id() {
+		assert!(res.is_ok());
+	} else {
+		match res {
+			Err(p2p::Error::DuplicateConnection) => {}
+			res => panic!("expected duplicate connection, got {:?}", res),
+		}
+	}
+
+	assert_eq!(a.peers.peer_count(), 1);
+	assert_eq!(b.peers.peer_count(), 1);
+	let a_peer = a.peers.get_connected_peer(b_addr).unwrap();
+	let b_peer = b.peers.get_connected_peer(a_addr).unwrap();
+	assert_ne!(a_peer.info.direction, b_peer.info.direction);
+}
+
+// Nodes sharing a node id (copied data directory) are not mistaken for a
+// self connection, the responder drops them and picks a new id once a few
+// distinct peers used its own.
+#[test]
+fn handshake_node_id_collision() {
+	util::init_test_logger();
+
+	let responder = Arc::new(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let node_id = hs.node_id();
+	responder.set_node_id(node_id);
+
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let responder_inner = responder.clone();
+	let server = thread::spawn(move || {
+		let mut results = vec![];
+		for _ in 0..4 {
+			let (mut conn, _) = listener.accept().unwrap();
+			results.push(responder_inner.accept(
+				p2p::Capabilities::UNKNOWN,
+				Difficulty::min(),
+				0,
+				conn.peer_addr().ok(),
+				&mut conn,
+				&|_| false,
+			));
+		}
+		results
+	});
+
+	// local peers are told apart by port, each attempt is another one, the
+	// first few fail, the next one goes through
+	for _ in 0..3 {
+		assert!(initiate_handshake(&hs, addr).is_err());
+	}
+	assert!(initiate_handshake(&hs, addr).is_ok());
+
+	let mut results = server.join().unwrap();
+	assert!(results.pop().unwrap().is_ok());
+	for res in results {
+		match res {
+			Err(p2p::Error::NodeIdCollision) => {}
+			res => panic!("expected node id collision, got {:?}", res),
+		}
+	}
+	assert_ne!(responder.node_id(), node_id);
+	assert!(responder.addrs.is_empty());
+}
+
+// Our node id survives a restart.
+#[test]
+fn handshake_node_id_persisted() {
+	util::init_test_logger();
+
+	let new_server = || {
+		p2p::Server::new(
+			".grin_node_id",
+			p2p::Capabilities::UNKNOWN,
+			p2p::P2PConfig::default(),
+			Arc::new(p2p::DummyAdapter {}),
+			Hash::from_vec(&vec![]),
+			Arc::new(StopState::new()),
+		)
+		.unwrap()
+	};
+	let node_id = new_server().node_id();
+	assert_eq!(new_server().node_id(), node_id);
+}
+
+// Once we connected to ourselves we neither dial that address again nor save
+// it from gossip, even after a restart.
+#[test]
+fn handshake_self_addr_remembered() {
+	util::init_test_logger();
+
+	let a = start_server(".grin_self_addr");
+	thread::sleep(time::Duration::from_secs(1));
+	let a_addr = PeerAddr::Ip(SocketAddr::new(a.config.host, a.config.port));
+	assert!(a.peers.can_dial(&a_addr));
+
+	// both ends of the connection are us, the accepting end notices
+	assert!(a.connect(a_addr.clone()).is_err());
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(a.handshake_stats().self_connections, 1);
+	assert!(!a.peers.can_dial(&a_addr));
+
+	// refused before dialing
+	let attempts = a.handshake_stats().attempts;
+	match a.connect(a_addr.clone()) {
+		Err(p2p::Error::PeerWithSelf) => {}
+		res => panic!("expected peer with self, got {:?}", res),
+	}
+	assert_eq!(a.handshake_stats().attempts, attempts);
+
+	a.peers
+		.peer_addrs_received(a_addr.clone(), vec![a_addr.clone()]);
+	assert!(!a.peers.exists_peer(a_addr.clone()).unwrap());
+
+	a.stop();
+	thread::sleep(time::Duration::from_secs(1));
+	let restarted = p2p::Server::new(
+		".grin_self_addr",
+		p2p::Capabilities::UNKNOWN,
+		p2p::P2PConfig::default(),
+		Arc::new(p2p::DummyAdapter {}),
+		Hash::from_vec(&vec![]),
+		Arc::new(StopState::new()),
+	)
+	.unwrap();
+	assert!(!restarted.peers.can_dial(&a_addr));
+}
+
+// A GetPeerAddrs filter picks the peers having any of the capabilities asked
+// for, too few of them get topped up with other healthy peers.
+#[test]
+fn peer_addrs_capabilities_filter() {
+	util::init_test_logger();
+
+	let server = p2p::Server::new(
+		".grin_peer_addrs_filter",
+		p2p::Capabilities::UNKNOWN,
+		p2p::P2PConfig::default(),
+		Arc::new(p2p::DummyAdapter {}),
+		Hash::from_vec(&vec![]),
+		Arc::new(StopState::new()),
+	)
+	.unwrap();
+	let peer = |i: u8, capabilities| p2p::PeerData {
+		addr: PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414)),
+		capabilities,
+		user_agent: "test".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: Utc::now().timestamp(),
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: Utc::now().timestamp(),
+		last_direction: None,
+	};
+	for i in 0..20 {
+		let full = p2p::Capabilities::HEADER_HIST | p2p::Capabilities::PEER_LIST;
+		server.peers.save_peer(&peer(i, full)).unwrap();
+		server
+			.peers
+			.save_peer(&peer(100 + i, p2p::Capabilities::PEER_LIST))
+			.unwrap();
+	}
+	server
+		.peers
+		.save_peer(&peer(200, p2p::Capabilities::TX_KERNEL_HASH))
+		.unwrap();
+
+	let from = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let addrs = server
+		.peers
+		.find_peer_addrs(from.clone(), p2p::Capabilities::HEADER_HIST);
+	assert_eq!(addrs.len(), 20);
+	for addr in addrs {
+		let data = server.peers.get_peer(addr).unwrap();
+		assert!(data.capabilities.contains(p2p::Capabilities::HEADER_HIST));
+	}
+
+	// a single match, the others make up for it
+	let addrs = server
+		.peers
+		.find_peer_addrs(from.clone(), p2p::Capabilities::TX_KERNEL_HASH);
+	assert_eq!(addrs.len(), 41);
+	assert!(addrs.contains(&peer(200, p2p::Capabilities::UNKNOWN).addr));
+
+	// no filter at all
+	assert_eq!(
+		server
+			.peers
+			.find_peer_addrs(from, p2p::Capabilities::UNKNOWN)
+			.len(),
+		41
+	);
+}
+
+// An oversized, duplicate heavy PeerAddrs only ever adds a capped number of
+// unique, routable addresses to our store, and the sender's allowance for the
+// hour runs out.
+#[test]
+fn peer_addrs_received_capped() {
+	util::init_test_logger();
+
+	// counts would be off with the addresses of a previous run
+	let _ = fs::remove_dir_all(".grin_peer_addrs_capped");
+	let server = p2p::Server::new(
+		".grin_peer_addrs_capped",
+		p2p::Capabilities::UNKNOWN,
+		p2p::P2PConfig::default(),
+		Arc::new(p2p::DummyAdapter {}),
+		Hash::from_vec(&vec![]),
+		Arc::new(StopState::new()),
+	)
+	.unwrap();
+	let addr =
+		|a: u8, b: u8| PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(5, 6, a, b)), 3414));
+	let from = addr(0, 0);
+
+	let mut addrs = vec![];
+	for i in 0..200 {
+		addrs.push(addr(1, i));
+		addrs.push(addr(1, i));
+		addrs.push(PeerAddr::Ip(SocketAddr::new(
+			IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)),
+			3414,
+		)));
+	}
+	for i in 0..800 {
+		addrs.push(addr(2 + (i / 250) as u8, (i % 250) as u8));
+	}
+	// never encoded either
+	let msg = p2p::msg::PeerAddrs {
+		peers: addrs.clone(),
+	};
+	assert!(write_to_buf(&msg, ProtocolVersion::default(), Type::PeerAddrs).is_err());
+
+	server.peers.peer_addrs_received(from.clone(), addrs);
+	let saved = server.peers.all_peers();
+	assert!(saved.len() <= 256);
+	// the first 256 entries hold 86 unique, routable addresses
+	assert_eq!(saved.len(), 86);
+	for data in &saved {
+		assert!(data.addr.is_routable());
+	}
+
+	// the allowance is per hour, duplicates didn't count against it
+	let more: Vec<_> = (0..250).map(|i| addr(20, i)).collect();
+	server.peers.peer_addrs_received(from.clone(), more);
+	assert_eq!(server.peers.all_peers().len(), 256);
+
+	// another peer has its own allowance
+	server
+		.peers
+		.peer_addrs_received(addr(0, 1), vec![addr(21, 0), addr(21, 0)]);
+	assert_eq!(server.peers.all_peers().len(), 257);
+}
+
+#[test]
+fn self_addrs_expiry_and_cap() {
+	let addr = |port| PeerAddr::Ip(SocketAddr::new("127.0.0.1".parse().unwrap(), port));
+	let self_addrs = SelfAddrs::new();
+	self_addrs.load(vec![
+		p2p::SelfAddr {
+			addr: addr(1),
+			expires: Utc::now().timestamp() - 1,
+		},
+		p2p::SelfAddr {
+			addr: addr(2),
+			expires: Utc::now().timestamp() + 3600,
+		},
+	]);
+	assert!(!self_addrs.contains(&addr(1)));
+	assert!(self_addrs.contains(&addr(2)));
+	assert!(self_addrs.changed().is_none());
+
+	for port in 3..20 {
+		self_addrs.insert(addr(port));
+	}
+	assert_eq!(self_addrs.len(), 10);
+	assert!(!self_addrs.contains(&addr(2)));
+	assert!(self_addrs.contains(&addr(19)));
+	assert_eq!(self_addrs.changed().unwrap().len(), 10);
+	assert!(self_addrs.changed().is_none());
+}
+
+// The responder reports the address it sees us at, with our advertised port
+// rather than the ephemeral port we dialed from.
+#[test]
+fn handshake_observed_addr() {
+	util::init_test_logger();
+
+	let (addr, server) = accept_handshake(Handshake::new(
+		Hash::from_vec(&vec![]),
+		p2p::P2PConfig::default(),
+	));
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let info = initiate_handshake(&hs, addr).unwrap();
+	assert_eq!(
+		info.our_addr_as_seen,
+		Some(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()))
+	);
+	assert_eq!(server.join().unwrap().unwrap().our_addr_as_seen, None);
+}
+
+// A DNS name is resolved when dialing, and each address it resolves to is
+// tried in turn ("localhost" usually resolves to both ::1 and 127.0.0.1 while
+// the server only listens on the latter).
+#[test]
+fn handshake_dial_dns() {
+	util::init_test_logger();
+
+	let a = start_server(".grin_dns_a");
+	let b = start_server(".grin_dns_b");
+	thread::sleep(time::Duration::from_secs(1));
+
+	let dns_addr = PeerAddr::Dns("localhost".to_string(), a.config.port);
+	let resolved = dns_addr.resolve().unwrap();
+	assert!(!resolved.is_empty());
+	assert!(resolved.iter().all(|addr| addr.ip().is_loopback()));
+
+	let peer = b.connect(dns_addr).unwrap();
+	assert_eq!(
+		peer.info.addr,
+		PeerAddr::Ip(SocketAddr::new(a.config.host, a.config.port))
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(b.peers.peer_count(), 1);
+
+	a.stop();
+	b.stop();
+}
+
+// Runs a mock responder on a background thread, reading the hand and then
+// replying whatever `reply` writes back (if anything).
+fn mock_responder<F>(reply: F) -> SocketAddr
+where
+	F: FnOnce(&mut TcpStream, Hand) + Send + 'static,
+{
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _ = thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		let hand: Hand = read_message(&mut conn, ProtocolVersion::handshake(), Type::Hand).unwrap();
+		reply(&mut conn, hand);
+		thread::sleep(time::Duration::from_secs(3));
+	});
+	addr
+}
+
+fn reply_shake(hand: &Hand, version: ProtocolVersion) -> Shake {
+	Shake {
+		version,
+		min_version: version,
+		capabilities: p2p::Capabilities::UNKNOWN,
+		nonce: Some(hand.nonce),
+		genesis: hand.genesis,
+		total_difficulty: Difficulty::min(),
+		height: Some(0),
+		observed_addr: Some(hand.sender_addr.clone()),
+		user_agent: "test".to_string(),
+		node_id: None,
+		extensions: vec![],
+	}
+}
+
+// Each handshake is counted once, as a success or under its failure class.
+#[test]
+fn handshake_stats() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		handshake_timeout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let hs = Handshake::new(Hash::from_vec(&vec![]), config);
+	assert_eq!(hs.stats(), HandshakeCounts::default());
+
+	// success
+	let addr = mock_responder(|conn, hand| {
+		let shake = reply_shake(&hand, ProtocolVersion::default());
+		write_message(conn, shake, ProtocolVersion::handshake(), Type::Shake).unwrap();
+	});
+	initiate_handshake(&hs, addr).unwrap();
+
+	// no reply at all
+	let addr = mock_responder(|_, _| {});
+	assert!(initiate_handshake(&hs, addr).is_err());
+
+	// no version in common
+	let addr = mock_responder(|conn, hand| {
+		let shake = reply_shake(&hand, ProtocolVersion(ProtocolVersion::default().0 + 1));
+		write_message(conn, shake, ProtocolVersion::handshake(), Type::Shake).unwrap();
+	});
+	assert!(initiate_handshake(&hs, addr).is_err());
+
+	// a shake which is a lot shorter than it should be
+	let addr = mock_responder(|conn, _| {
+		let buf = write_to_buf(
+			ProtocolVersion::default(),
+			ProtocolVersion::handshake(),
+			Type::Shake,
+		)
+		.unwrap();
+		conn.write_all(&buf).unwrap();
+	});
+	assert!(initiate_handshake(&hs, addr).is_err());
+
+	// banned, refused before sending anything
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let mut conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	let res = hs.initiate(
+		p2p::Capabilities::UNKNOWN,
+		Difficulty::min(),
+		0,
+		PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()),
+		PeerAddr::Ip(listener.local_addr().unwrap()),
+		&mut conn,
+		&|_| true,
+	);
+	assert!(res.is_err());
+
+	assert_eq!(
+		hs.stats(),
+		HandshakeCounts {
+			attempts: 5,
+			successes: 1,
+			timeouts: 1,
+			version_mismatches: 1,
+			serialization_errors: 1,
+			banned: 1,
+			..HandshakeCounts::default()
+		}
+	);
+}
+
+// A peer on another network is never dialed again, one timing out is dialed
+// again after a backoff growing with each failure.
+#[test]
+fn handshake_retry_policy() {
+	util::init_test_logger();
+
+	let config = p2p::P2PConfig {
+		host: "127.0.0.1".parse().unwrap(),
+		port: open_port(),
+		handshake_timeout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let server = p2p::Server::new(
+		".grin_retry",
+		p2p::Capabilities::UNKNOWN,
+		config,
+		Arc::new(p2p::DummyAdapter {}),
+		Hash::from_vec(&vec![]),
+		Arc::new(StopState::new()),
+	)
+	.unwrap();
+
+	let addr = mock_responder(|conn, hand| {
+		let shake = reply_shake(&hand, ProtocolVersion::default());
+		let mut buf = write_to_buf(shake, ProtocolVersion::handshake(), Type::Shake).unwrap();
+		buf[..2].copy_from_slice(&FLOONET_MAGIC);
+		conn.write_all(&buf).unwrap();
+	});
+	let wrong_network = PeerAddr::Ip(addr);
+	let e = server.connect(wrong_network.clone()).unwrap_err();
+	match e {
+		p2p::Error::WrongNetwork => {}
+		e => panic!("expected wrong network, got {:?}", e),
+	}
+	assert_eq!(e.retry_policy(), RetryPolicy::Never);
+	server.peers.connect_failed(wrong_network.clone(), &e);
+	assert!(!server.peers.can_dial(&wrong_network));
+	assert_eq!(
+		server.peers.get_peer(wrong_network).unwrap().flags,
+		p2p::State::Incompatible
+	);
+
+	let addr = mock_responder(|_, _| {});
+	let timed_out = PeerAddr::Ip(addr);
+	let e = server.connect(timed_out.clone()).unwrap_err();
+	match e {
+		p2p::Error::Timeout => {}
+		e => panic!("expected timeout, got {:?}", e),
+	}
+	let backoff = REDIAL_BACKOFF.as_secs() as i64;
+	server.peers.connect_failed(timed_out.clone(), &e);
+	assert!(!server.peers.can_dial(&timed_out));
+	let wait = server.peers.redial_at(&timed_out).unwrap() - Utc::now();
+	assert!(wait.num_seconds() > backoff - 5 && wait.num_seconds() <= backoff);
+
+	// failing again gets us to the next step of the schedule
+	server.peers.connect_failed(timed_out.clone(), &e);
+	let backoff = redial_backoff(2).as_secs() as i64;
+	let wait = server.peers.redial_at(&timed_out).unwrap() - Utc::now();
+	assert!(wait.num_seconds() > backoff - 5 && wait.num_seconds() <= backoff);
+	assert_eq!(
+		server.peers.get_peer(timed_out).unwrap().flags,
+		p2p::State::Defunct
+	);
+}
+
+// Handshakes with the server by hand, leaving us with the raw connection and
+// the negotiated version.
+fn connect_raw(server: &p2p::Server) -> (TcpStream, ProtocolVersion) {
+	let addr = SocketAddr::new(server.config.host, server.config.port);
+	let mut conn = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	let hs = Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default());
+	let info = hs
+		.initiate(
+			p2p::Capabilities::UNKNOWN,
+			Difficulty::min(),
+			0,
+			PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()),
+			PeerAddr::Ip(addr),
+			&mut conn,
+			&|_| false,
+		)
+		.unwrap();
+	conn.set_read_timeout(Some(time::Duration::from_secs(5)))
+		.unwrap();
+	(conn, info.version)
+}
+
+fn ping() -> Ping {
+	Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	}
+}
+
+// Writes a message of a type nobody knows (yet), with a body of `len` bytes.
+fn write_unknown(conn: &mut TcpStream, version: ProtocolVersion, len: u64) {
+	let body = vec![0u8; len as usize];
+	let mut msg = magic().to_vec();
+	msg.push(200);
+	msg.extend_from_slice(&ser::ser_vec(&len).unwrap());
+	if version.has_checksum() {
+		msg.extend_from_slice(&Checksum::of(&body));
+	}
+	msg.extend_from_slice(&body);
+	conn.write_all(&msg).unwrap();
+}
+
+// An unknown message is skipped whole, the stream stays in sync.
+#[test]
+fn unknown_msg_skipped() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_unknown_msg");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+	write_unknown(&mut conn, version, 5);
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+
+	for _ in 0..2 {
+		let pong: Pong = read_message(&mut conn, version, Type::Pong).unwrap();
+		assert_eq!(pong.height, 0);
+	}
+
+	server.stop();
+}
+
+// A peer sending us nothing but unknown messages gets disconnected.
+#[test]
+fn unknown_msg_limit() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_unknown_msg_limit");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+
+	for _ in 0..=p2p::MAX_UNKNOWN_MSGS_PER_MIN {
+		write_unknown(&mut conn, version, 5);
+	}
+	let _ = write_message(&mut conn, ping(), version, Type::Ping);
+	let err: PeerError = read_message(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::RateLimited));
+	match read_message::<Pong>(&mut conn, version, Type::Pong) {
+		Err(p2p::Error::Connection(ref e)) if e.kind() != io::ErrorKind::WouldBlock => {}
+		Err(e) => panic!("expected the connection closed, got {:?}", e),
+		Ok(_) => panic!("expected the connection closed, got a pong"),
+	}
+
+	server.stop();
+}
+
+// A peer hanging up on us isn't connected anymore, and gets cleaned up.
+#[test]
+fn hung_up_peer_cleaned() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_hung_up");
+	thread::sleep(time::Duration::from_secs(1));
+	let (conn, _) = connect_raw(&server);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let server_peer = server.peers.get_connected_peer(my_addr.clone()).unwrap();
+	assert!(server_peer.is_connected());
+
+	drop(conn);
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(!server_peer.is_connected());
+	server.peers.clean_peers(10);
+	assert!(server.peers.get_connected_peer(my_addr).is_none());
+
+	server.stop();
+}
+
+// A body not matching its checksum closes the connection, without a ban.
+#[test]
+fn corrupted_msg_disconnects() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_corrupted_msg");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+	assert!(version.has_checksum());
+
+	let mut buf = write_to_buf(ping(), version, Type::Ping).unwrap();
+	let last = buf.len() - 1;
+	buf[last] ^= 1;
+	conn.write_all(&buf).unwrap();
+	let err: PeerError = read_message(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::ChecksumMismatch));
+	match read_message::<Pong>(&mut conn, version, Type::Pong) {
+		Err(p2p::Error::Connection(ref e)) if e.kind() != io::ErrorKind::WouldBlock => {}
+		Err(e) => panic!("expected the connection closed, got {:?}", e),
+		Ok(_) => panic!("expected the connection closed, got a pong"),
+	}
+	thread::sleep(time::Duration::from_millis(100));
+	assert!(!server
+		.peers
+		.is_banned(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap())));
+
+	server.stop();
+}
+
+// A msg header announcing more than the max of its type gets a BadLength
+// error back, before we read any of the body.
+#[test]
+fn msg_over_max_len_disconnects() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_msg_over_max_len");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+
+	let body = vec![0u8; 1024];
+	let header = MsgHeader::for_body(version, Type::Ping, &body);
+	conn.write_all(&ser::ser_vec(&header).unwrap()).unwrap();
+	let err: PeerError = read_message(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::BadLength));
+	assert!(!err.message.is_empty());
+	match read_message::<Pong>(&mut conn, version, Type::Pong) {
+		Err(p2p::Error::Connection(ref e)) if e.kind() != io::ErrorKind::WouldBlock => {}
+		Err(e) => panic!("expected the connection closed, got {:?}", e),
+		Ok(_) => panic!("expected the connection closed, got a pong"),
+	}
+
+	server.stop();
+}
+
+// The error a peer disconnects us with is remembered, codes we don't know
+// of included.
+#[test]
+fn peer_error_persisted() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_peer_error");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let err = PeerError {
+		code: 1000,
+		message: "from the future".to_owned(),
+	};
+	write_message(&mut conn, &err, version, Type::PeerError).unwrap();
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+	let _: Pong = read_message(&mut conn, version, Type::Pong).unwrap();
+
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let last_error = server.peers.get_peer(my_addr).unwrap().last_error;
+	assert_eq!(last_error, Some(err));
+	assert_eq!(last_error.unwrap().code(), None);
+
+	server.stop();
+}
+
+// A Headers message counting more than a full batch is refused from its count
+// alone and gets the connection closed.
+#[test]
+fn headers_over_limit_disconnects() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_headers_over_limit");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+
+	let body = ser::ser_vec(&(p2p::MAX_BLOCK_HEADERS as u16 + 1)).unwrap();
+	let mut buf = ser::ser_vec(&MsgHeader::for_body(version, Type::Headers, &body)).unwrap();
+	buf.extend_from_slice(&body);
+	conn.write_all(&buf).unwrap();
+	write_message(&mut conn, ping(), version, Type::Ping).unwrap();
+	// told why first
+	let ban: BanReason = read_message(&mut conn, version, Type::BanReason).unwrap();
+	assert_eq!(ban.ban_reason, p2p::ReasonForBan::ProtocolViolation);
+	assert!(ban.message.is_some());
+	match read_message::<Pong>(&mut conn, version, Type::Pong) {
+		Err(p2p::Error::Connection(ref e)) if e.kind() != io::ErrorKind::WouldBlock => {}
+		Err(e) => panic!("expected the connection closed, got {:?}", e),
+		Ok(_) => panic!("expected the connection closed, got a pong"),
+	}
+
+	server.stop();
+}
+
+// A banned peer gets the reason right before the connection closes.
+#[test]
+fn ban_reason_sent_before_close() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_ban_reason");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	server.peers.ban_peer(
+		my_addr.clone(),
+		p2p::ReasonForBan::BadBlock,
+		server.peers.ban_window(),
+	);
+	let ban: BanReason = read_message(&mut conn, version, Type::BanReason).unwrap();
+	assert_eq!(ban.ban_reason, p2p::ReasonForBan::BadBlock);
+	match read_message::<Pong>(&mut conn, version, Type::Pong) {
+		Err(p2p::Error::Connection(ref e)) if e.kind() != io::ErrorKind::WouldBlock => {}
+		Err(e) => panic!("expected the connection closed, got {:?}", e),
+		Ok(_) => panic!("expected the connection closed, got a pong"),
+	}
+	assert!(server.peers.is_banned(my_addr));
+
+	server.stop();
+}
+
+// A peer that stopped reading can't hold up its own eviction, the ban
+// reason is dropped when it can't be written in time.
+#[test]
+fn ban_stalled_peer() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_ban_stalled");
+	thread::sleep(time::Duration::from_secs(1));
+	// we never read from it
+	let (_conn, _) = connect_raw(&server);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let server_peer = server.peers.get_connected_peer(my_addr.clone()).unwrap();
+
+	// way more than the socket buffers hold
+	let sender = server_peer.clone();
+	let _ = thread::spawn(move || {
+		for height in 0..10 {
+			let mut block = Block::with_header(BlockHeader {
+				height,
+				..BlockHeader::default()
+			});
+			*block.kernels_mut() = vec![TxKernel::empty(); 20_000];
+			if sender.send_block(&block).is_err() {
+				break;
+			}
+		}
+	});
+	thread::sleep(time::Duration::from_secs(2));
+
+	let start = time::Instant::now();
+	server.peers.ban_peer(
+		my_addr.clone(),
+		p2p::ReasonForBan::BadBlock,
+		server.peers.ban_window(),
+	);
+	assert!(start.elapsed() < time::Duration::from_secs(1));
+	assert!(server.peers.get_connected_peer(my_addr).is_none());
+
+	let mut waited = 0;
+	while server_peer.is_connected() {
+		assert!(waited < 150, "stalled peer still connected");
+		thread::sleep(time::Duration::from_millis(100));
+		waited += 1;
+	}
+
+	server.stop();
+}
+
+// Asking for headers from a locator we know nothing of gets an empty batch
+// rather than no answer at all.
+#[test]
+fn headers_unknown_locator_empty() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_headers_unknown_locator");
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&server);
+
+	let locator = Locator {
+		hashes: vec![Hash::from_vec(&vec![7; 32])],
+	};
+	write_message(&mut conn, locator, version, Type::GetHeaders).unwrap();
+	let headers: Headers = read_message(&mut conn, version, Type::Headers).unwrap();
+	assert!(headers.headers.is_empty());
+
+	server.stop();
+}
+
+// Pings carry the chain state of the sender, picked up by the other side
+// every time, and get answered with a pong carrying the state of the other.
+#[test]
+fn ping_updates_chain_state() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_ping");
+	thread::sleep(time::Duration::from_secs(1));
+
+	let addr = SocketAddr::new(server.config.host, server.config.port);
+	let socket = TcpStream::connect_timeout(&addr, time::Duration::from_secs(10)).unwrap();
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = Peer::connect(
+		socket,
+		PeerAddr::Ip(addr),
+		p2p::Capabilities::UNKNOWN,
+		Difficulty::min(),
+		0,
+		my_addr.clone(),
+		&Handshake::new(Hash::from_vec(&vec![]), p2p::P2PConfig::default()),
+		Arc::new(p2p::DummyAdapter {}),
+	)
+	.unwrap();
+
+	peer.send_ping(Difficulty::min(), 0).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+	let server_peer = server.peers.get_connected_peer(my_addr).unwrap();
+	assert_eq!(server_peer.info.total_difficulty(), Difficulty::min());
+	assert_eq!(server_peer.info.height(), 0);
+
+	// our chain advanced
+	peer.send_ping(Difficulty::from_num(10), 5).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(
+		server_peer.info.total_difficulty(),
+		Difficulty::from_num(10)
+	);
+	assert_eq!(server_peer.info.height(), 5);
+
+	// both pings got their pong
+	assert_eq!(peer.info.unanswered_pings(), 0);
+	assert!(!peer.is_unresponsive());
+
+	peer.stop();
+	server.stop();
+}
+
+// A peer leaving our pings unanswered gets dropped.
+#[test]
+fn ping_unanswered() {
+	util::init_test_logger();
+
+	let server = start_server(".grin_ping_unanswered");
+	thread::sleep(time::Duration::from_secs(1));
+	let (conn, _) = connect_raw(&server);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let my_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let server_peer = server.peers.get_connected_peer(my_addr.clone()).unwrap();
+	server.peers.check_all(Difficulty::min(), 0);
+	server.peers.check_all(Difficulty::min(), 0);
+	assert!(!server_peer.is_unresponsive());
+	server.peers.check_all(Difficulty::min(), 0);
+	assert_eq!(server_peer.info.unanswered_pings(), 3);
+	assert!(server_peer.is_unresponsive());
+
+	server.peers.clean_peers(10);
+	assert!(server.peers.get_connected_peer(my_addr).is_none());
+
+	drop(conn);
+	server.stop();
+}
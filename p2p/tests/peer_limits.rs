@@ -0,0 +1,151 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+use crate::p2p::Server;
+
+fn start(db_root: &str) -> (Arc<Server>, PeerAddr) {
+	let config = p2p::P2PConfig {
+		max_inbound: Some(1),
+		max_outbound: Some(1),
+		outbound_target: Some(0),
+		inbound_eviction: Some(p2p::InboundEviction::Reject),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	start_node_with(db_root, p2p::Capabilities::FULL_NODE, adapter, config)
+}
+
+fn assert_closed(conn: &mut TcpStream) {
+	let mut buf = [0; 64];
+	loop {
+		match conn.read(&mut buf) {
+			Ok(0) => break,
+			Ok(_) => {}
+			Err(ref e) if e.kind() == ErrorKind::ConnectionReset => break,
+			res => panic!("expected the connection closed, got {:?}", res),
+		}
+	}
+}
+
+// Raised limits let in the peers the old ones refused, both ways, no
+// restart needed.
+#[test]
+fn limits_raised_at_runtime() {
+	util::init_test_logger();
+
+	let (server, addr) = start(".grin_limits_raised");
+	let mut others = vec![];
+	for i in 0..2 {
+		let other = Arc::new(PoolAdapter::new(vec![], None));
+		others.push(start_node(
+			&format!(".grin_limits_raised_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			other,
+		));
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _first = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001);
+	let (mut second, _) = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5002);
+	assert_closed(&mut second);
+	server.connect(others[0].1.clone()).unwrap();
+	match server.connect(others[1].1.clone()) {
+		Err(p2p::Error::TooManyPeers) => {}
+		res => panic!("expected too many peers, got {:?}", res.map(|_| ())),
+	}
+
+	server.update_limits(p2p::PeerLimits {
+		max_inbound: 2,
+		max_outbound: 2,
+		..server.peers.limits()
+	});
+	let _third = connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5003);
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 2);
+	server.connect(others[1].1.clone()).unwrap();
+	assert_eq!(server.peers.peer_outbound_count(), 2);
+
+	server.stop();
+	for (other, _) in others {
+		other.stop();
+	}
+}
+
+// Lowered limits leave our peers alone until the next maintenance tick,
+// which drops the ones over them.
+#[test]
+fn limits_lowered_next_tick() {
+	util::init_test_logger();
+
+	let (server, addr) = start(".grin_limits_lowered");
+	server.update_limits(p2p::PeerLimits {
+		max_inbound: 3,
+		max_outbound: 2,
+		..server.peers.limits()
+	});
+	let mut others = vec![];
+	for i in 0..2 {
+		let other = Arc::new(PoolAdapter::new(vec![], None));
+		others.push(start_node(
+			&format!(".grin_limits_lowered_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			other,
+		));
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	let _conns = (0..3)
+		.map(|i| connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, 5001 + i))
+		.collect::<Vec<_>>();
+	for (_, other_addr) in &others {
+		server.connect(other_addr.clone()).unwrap();
+	}
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 3);
+	assert_eq!(server.peers.peer_outbound_count(), 2);
+
+	server.update_limits(p2p::PeerLimits {
+		max_inbound: 1,
+		max_outbound: 1,
+		..server.peers.limits()
+	});
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.peer_inbound_count(), 3);
+	assert_eq!(server.peers.peer_outbound_count(), 2);
+
+	Server::maintain_outbound(&server);
+	assert_eq!(server.peers.peer_inbound_count(), 1);
+	assert_eq!(server.peers.peer_outbound_count(), 1);
+
+	// nothing more to drop on the next one
+	Server::maintain_outbound(&server);
+	assert_eq!(server.peers.peer_count(), 2);
+
+	server.stop();
+	for (other, _) in others {
+		other.stop();
+	}
+}
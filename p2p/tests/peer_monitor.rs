@@ -0,0 +1,84 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+fn config() -> p2p::P2PConfig {
+	p2p::P2PConfig {
+		keepalive_interval: Some(1),
+		idle_timeout: Some(3),
+		monitor_interval: Some(1),
+		..p2p::P2PConfig::default()
+	}
+}
+
+// A peer frozen on its end of the connection, never closing it, is gone from
+// our peers within two sweeps of the idle timeout being over, while a live
+// peer stays.
+#[test]
+fn monitor_drops_frozen_peer() {
+	util::init_test_logger();
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) =
+		start_node_with(".grin_monitor_a", p2p::Capabilities::FULL_NODE, a, config());
+	let (live_server, _) =
+		start_node_with(".grin_monitor_b", p2p::Capabilities::FULL_NODE, b, config());
+	let _ = p2p::Server::monitor_peers(server.clone()).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	let live = live_server.connect(addr.clone()).unwrap();
+	// frozen from here, we neither read nor write
+	let (_conn, _) = connect_raw_from(&addr, p2p::Capabilities::FULL_NODE, 5000);
+	let connected = Instant::now();
+	let frozen = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	assert!(server.peers.is_known(frozen.clone()));
+
+	while server.peers.is_known(frozen.clone()) {
+		assert!(
+			connected.elapsed() < time::Duration::from_secs(3 + 2 + 1),
+			"frozen peer never dropped"
+		);
+		thread::sleep(time::Duration::from_millis(100));
+	}
+	assert!(!server.peers.is_banned(frozen));
+
+	assert!(live.is_connected());
+	assert_eq!(server.peers.peer_count(), 1);
+	let sweep = p2p::Server::sweep_peers(&server);
+	assert_eq!(
+		sweep,
+		p2p::Sweep {
+			connected: 1,
+			inbound: 1,
+			outbound: 0,
+			banned: 0,
+			dropped: 0,
+		}
+	);
+
+	server.stop();
+	live_server.stop();
+}
@@ -0,0 +1,148 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::pow::Difficulty;
+use crate::p2p::types::PeerAddr;
+
+fn addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414))
+}
+
+fn stored(i: u8, flags: p2p::State, days_ago: i64) -> p2p::PeerData {
+	let seen = (Utc::now() - Duration::days(days_ago)).timestamp();
+	p2p::PeerData {
+		addr: addr(i),
+		capabilities: p2p::Capabilities::PEER_LIST,
+		user_agent: "test".to_string(),
+		flags,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: seen,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: seen,
+		last_direction: None,
+	}
+}
+
+// Healthy peers we haven't heard from in a while are demoted to defunct, and
+// defunct or unverified ones unheard of for longer are forgotten.
+#[test]
+fn peer_prune_stale() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_peer_prune");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		peer_demote_days: Some(2),
+		peer_expire_days: Some(10),
+		..p2p::P2PConfig::default()
+	};
+	let (server, _) = start_node_with(
+		".grin_peer_prune",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	let peers = &server.peers;
+	peers.save_peer(&stored(0, p2p::State::Healthy, 1)).unwrap();
+	peers.save_peer(&stored(1, p2p::State::Healthy, 5)).unwrap();
+	peers.save_peer(&stored(2, p2p::State::Defunct, 5)).unwrap();
+	peers
+		.save_peer(&stored(3, p2p::State::Defunct, 20))
+		.unwrap();
+	peers
+		.save_peer(&stored(4, p2p::State::Unverified, 20))
+		.unwrap();
+	peers
+		.save_peer(&stored(5, p2p::State::Incompatible, 20))
+		.unwrap();
+	assert_eq!(peers.store_stats().healthy, 2);
+
+	peers.remove_expired();
+	assert_eq!(peers.get_peer(addr(0)).unwrap().flags, p2p::State::Healthy);
+	assert_eq!(peers.get_peer(addr(1)).unwrap().flags, p2p::State::Defunct);
+	assert_eq!(peers.get_peer(addr(2)).unwrap().flags, p2p::State::Defunct);
+	assert!(!peers.exists_peer(addr(3)).unwrap());
+	assert!(!peers.exists_peer(addr(4)).unwrap());
+	assert!(peers.exists_peer(addr(5)).unwrap());
+	assert_eq!(
+		peers.store_stats(),
+		p2p::StoreStats {
+			healthy: 1,
+			banned: 0,
+			defunct: 2,
+			incompatible: 1,
+			unverified: 0,
+		}
+	);
+
+	server.stop();
+}
+
+// A connected peer is seen when we connect to it and then as it keeps
+// talking to us, so it's never demoted.
+#[test]
+fn peer_prune_connected() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_peer_prune_a");
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node(".grin_peer_prune_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_peer_prune_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let before = Utc::now().timestamp();
+	a_server.connect(b_addr.clone()).unwrap();
+	let peer = a_server.peers.get_peer(b_addr.clone()).unwrap();
+	assert!(peer.last_seen >= before);
+	assert!(peer.last_connected >= before);
+
+	// as if the connection had been up for days
+	let mut peer = peer;
+	peer.last_seen -= Duration::days(5).num_seconds();
+	peer.last_connected = peer.last_seen;
+	a_server.peers.save_peer(&peer).unwrap();
+	a_server.peers.check_all(Difficulty::min(), 0);
+	thread::sleep(time::Duration::from_millis(500));
+	let peer = a_server.peers.get_peer(b_addr.clone()).unwrap();
+	assert!(peer.last_seen >= before);
+	assert!(peer.last_connected >= before);
+
+	a_server.peers.remove_expired();
+	assert_eq!(
+		a_server.peers.get_peer(b_addr).unwrap().flags,
+		p2p::State::Healthy
+	);
+
+	a_server.stop();
+	b_server.stop();
+}
@@ -0,0 +1,260 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hash;
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{write_message, write_to_buf, Ping, Pong, Type};
+use crate::p2p::types::PeerAddr;
+
+const PINGS: u64 = 10;
+
+// Every byte exchanged with a peer is accounted for, from the handshake on.
+#[test]
+fn peer_stats_count_bytes() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(".grin_peer_stats", p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let peer = server
+		.peers
+		.get_connected_peer(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()))
+		.unwrap();
+
+	// our hand and its shake, nothing else yet
+	let before = peer.stats();
+	assert!(before.received_bytes > 0);
+	assert!(before.sent_bytes > 0);
+
+	let mut ping_bytes = 0;
+	for height in 0..PINGS {
+		let ping = Ping {
+			total_difficulty: Difficulty::min(),
+			height,
+			nonce: None,
+		};
+		ping_bytes += write_message(&mut conn, ping, version, Type::Ping).unwrap();
+	}
+	for _ in 0..PINGS {
+		let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	}
+	thread::sleep(time::Duration::from_millis(500));
+
+	let pong = Pong {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	let pong_bytes = PINGS * write_to_buf(pong, version, Type::Pong).unwrap().len() as u64;
+	let after = peer.stats();
+	assert_eq!(after.received_bytes - before.received_bytes, ping_bytes);
+	assert_eq!(after.sent_bytes - before.sent_bytes, pong_bytes);
+	assert!(after.recv_rate >= (before.received_bytes + ping_bytes) / 60);
+	assert!(after.sent_rate >= (before.sent_bytes + pong_bytes) / 60);
+
+	// a single peer, the server wide stats are its own
+	assert_eq!(server.peers.stats(), after.bandwidth());
+
+	server.stop();
+}
+
+// The snapshot of a peer reflects the exchange with it, msg by msg.
+#[test]
+fn peer_stats_snapshot() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_peer_stats_snapshot",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr.clone()).unwrap();
+	let stats = peer.stats();
+	assert_eq!(stats.addr, peer_addr);
+	assert_eq!(stats.version, version);
+	assert_eq!(stats.capabilities, p2p::Capabilities::UNKNOWN);
+	assert_eq!(stats.direction, p2p::Direction::Inbound);
+	assert!(stats.msgs_sent.is_empty());
+	assert!(stats.msgs_received.is_empty());
+
+	for height in 1..=3 {
+		let ping = Ping {
+			total_difficulty: Difficulty::from_num(10 * height),
+			height,
+			nonce: None,
+		};
+		write_message(&mut conn, ping, version, Type::Ping).unwrap();
+		let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	}
+	let h = Hash::from_vec(&[1; 32]);
+	peer.send_block_request(h).unwrap();
+	let requested: Hash = read_until(&mut conn, version, Type::GetBlock).unwrap();
+	assert_eq!(requested, h);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let stats = peer.stats();
+	assert_eq!(stats.height, 3);
+	assert_eq!(stats.total_difficulty, Difficulty::from_num(30));
+	assert_eq!(stats.msgs_received.len(), 1);
+	assert_eq!(stats.msgs_received.get("Ping"), Some(&3));
+	assert_eq!(stats.msgs_sent.len(), 2);
+	assert_eq!(stats.msgs_sent.get("Pong"), Some(&3));
+	assert_eq!(stats.msgs_sent.get("GetBlock"), Some(&1));
+	assert_eq!(stats.outstanding_requests, 1);
+	assert_eq!(stats.queued_bytes, 0);
+	assert!(stats.last_seen >= peer.info.first_seen());
+
+	// and the server hands the same over, for all its peers
+	assert_eq!(server.peers.connected_stats(), vec![stats]);
+
+	server.stop();
+}
+
+// The API snapshot has every connected peer and what we know of it, and
+// taking it while peers come and go neither blocks nor fails.
+#[test]
+fn peer_stats_info_snapshot() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_peer_stats_info",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let mut conns = vec![];
+	for port in 5001..5005 {
+		conns.push(connect_raw_from(&addr, p2p::Capabilities::UNKNOWN, port));
+	}
+	let (mut conn, version) = connect_raw(&addr);
+	let ping = Ping {
+		total_difficulty: Difficulty::from_num(10),
+		height: 1,
+		nonce: None,
+	};
+	write_message(&mut conn, ping, version, Type::Ping).unwrap();
+	let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	thread::sleep(time::Duration::from_millis(500));
+
+	let info = server.peers.connected_peers_info();
+	assert_eq!(info.len(), 5);
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = info.iter().find(|p| p.addr == peer_addr).unwrap();
+	assert_eq!(peer.version, version);
+	assert_eq!(peer.capabilities, p2p::Capabilities::UNKNOWN);
+	assert_eq!(peer.direction, p2p::Direction::Inbound);
+	assert_eq!(peer.height, 1);
+	assert_eq!(peer.total_difficulty, Difficulty::from_num(10));
+	let bandwidth = peer.stats.as_ref().unwrap();
+	assert!(bandwidth.sent_bytes > 0);
+	assert!(bandwidth.received_bytes > 0);
+	assert!(peer.connected_secs < 60);
+
+	// peers dropping us while we're at it
+	let snapshots = thread::spawn({
+		let peers = server.peers.clone();
+		move || {
+			let mut counts = vec![];
+			for _ in 0..100 {
+				counts.push(peers.connected_peers_info().len());
+			}
+			counts
+		}
+	});
+	for conn in conns.drain(..) {
+		drop(conn);
+		thread::sleep(time::Duration::from_millis(10));
+	}
+	let counts = snapshots.join().unwrap();
+	assert!(counts.iter().all(|&n| n <= 5));
+	thread::sleep(time::Duration::from_millis(500));
+	server.peers.clean_peers(8);
+	assert_eq!(server.peers.connected_peers_info().len(), 1);
+
+	server.stop();
+}
+
+// Our pings carry a nonce the peer echoes, our round-trip time estimate of
+// the peer follows its pongs. A pong to no ping of ours is a strike.
+#[test]
+fn peer_stats_rtt() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_peer_stats_rtt",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr.clone()).unwrap();
+
+	for _ in 0..3 {
+		server.peers.check_all(Difficulty::min(), 0);
+		let ping: Ping = read_until(&mut conn, version, Type::Ping).unwrap();
+		assert!(ping.nonce.is_some());
+		thread::sleep(time::Duration::from_millis(200));
+		let pong = Pong {
+			total_difficulty: Difficulty::min(),
+			height: 0,
+			nonce: ping.nonce,
+		};
+		write_message(&mut conn, pong, version, Type::Pong).unwrap();
+	}
+	thread::sleep(time::Duration::from_millis(200));
+	let stats = peer.stats();
+	assert!(stats.rtt_ms.unwrap() >= 200);
+	assert!(stats.max_rtt_ms.unwrap() >= stats.rtt_ms.unwrap());
+	assert_eq!(peer.info.unanswered_pings(), 0);
+	assert_eq!(server.peers.misbehavior(&peer_addr), 0);
+
+	// the last ping was answered already
+	let pong = Pong {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: Some(42),
+	};
+	write_message(&mut conn, pong, version, Type::Pong).unwrap();
+	thread::sleep(time::Duration::from_millis(200));
+	assert_eq!(
+		server.peers.misbehavior(&peer_addr),
+		p2p::Offense::UnsolicitedPong.weight()
+	);
+	assert_eq!(peer.stats().rtt_ms, stats.rtt_ms);
+
+	server.stop();
+}
@@ -0,0 +1,229 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::core::ser::{self, Writeable, Writer};
+use crate::p2p::msg::{PeerError, PeerErrorCode};
+use crate::p2p::types::PeerAddr;
+
+fn addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, i)), 3414))
+}
+
+fn peer(i: u8) -> p2p::PeerData {
+	p2p::PeerData {
+		addr: addr(i),
+		capabilities: p2p::Capabilities::PEER_LIST,
+		user_agent: "test".to_string(),
+		flags: p2p::State::Healthy,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures: 0,
+		banned_until: 0,
+		last_seen: 0,
+		last_direction: None,
+	}
+}
+
+fn find_all(store: &p2p::PeerStore) -> Vec<p2p::PeerData> {
+	store
+		.find_peers(p2p::State::Healthy, p2p::Capabilities::UNKNOWN, 100)
+		.unwrap()
+}
+
+// PeerData as written before the extended fields, nothing after the last
+// connection time.
+struct Legacy(p2p::PeerData);
+
+impl Writeable for Legacy {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.0.addr.write(writer)?;
+		writer.write_u32(self.0.capabilities.bits())?;
+		writer.write_bytes(&self.0.user_agent)?;
+		writer.write_u8(self.0.flags as u8)?;
+		writer.write_i64(self.0.last_banned)?;
+		writer.write_i32(self.0.ban_reason as i32)?;
+		writer.write_i64(self.0.last_connected)
+	}
+}
+
+#[test]
+fn peer_data_roundtrip() {
+	let mut p = peer(1);
+	p.last_attempted = 42;
+	p.failures = 3;
+	p.last_seen = 99;
+	p.last_error = Some(PeerError::new(PeerErrorCode::TooSlow, "slow".to_string()));
+	let vec = ser::ser_vec(&p).unwrap();
+	let read: p2p::PeerData = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(read.last_attempted, 42);
+	assert_eq!(read.failures, 3);
+	assert_eq!(read.last_error, p.last_error);
+	assert_eq!(read.last_seen, 99);
+
+	p.last_error = None;
+	let vec = ser::ser_vec(&p).unwrap();
+	let read: p2p::PeerData = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(read.failures, 3);
+	assert_eq!(read.last_error, None);
+}
+
+// Records saved by older versions still read, with no failures on them and
+// last seen when last connected.
+#[test]
+fn peer_data_legacy() {
+	let mut p = peer(1);
+	p.last_connected = 1234;
+	let vec = ser::ser_vec(&Legacy(p.clone())).unwrap();
+	let read: p2p::PeerData = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(read.last_connected, 1234);
+	assert_eq!(read.last_attempted, 0);
+	assert_eq!(read.failures, 0);
+	assert_eq!(read.last_error, None);
+	assert_eq!(read.last_seen, 1234);
+}
+
+// A record cut anywhere in the extended fields doesn't read, nor does one
+// with anything else after the last connection time.
+#[test]
+fn peer_data_partial() {
+	let mut p = peer(1);
+	p.last_error = Some(PeerError::new(PeerErrorCode::TooSlow, "slow".to_string()));
+	p.last_direction = Some(p2p::Direction::Outbound);
+	let vec = ser::ser_vec(&p).unwrap();
+	let legacy_len = ser::ser_vec(&Legacy(p.clone())).unwrap().len();
+	for len in legacy_len + 1..vec.len() {
+		let res: Result<p2p::PeerData, _> = ser::deserialize(&mut &vec[..len]);
+		assert!(res.is_err(), "read cut at {} of {}", len, vec.len());
+	}
+
+	let mut vec = ser::ser_vec(&Legacy(p)).unwrap();
+	vec.push(0);
+	let res: Result<p2p::PeerData, _> = ser::deserialize(&mut &vec[..]);
+	assert!(res.is_err());
+}
+
+// Saved peers and their failures are found again, the ones failing the
+// least first, and still there once the store is opened anew.
+#[test]
+fn peer_store_failures() {
+	util::init_test_logger();
+
+	let db_root = ".grin_peer_store";
+	let _ = fs::remove_dir_all(db_root);
+	{
+		let store = p2p::PeerStore::new(db_root).unwrap();
+		for i in 0..4 {
+			store.save_peer(&peer(i)).unwrap();
+		}
+		assert_eq!(find_all(&store).len(), 4);
+
+		let before = Utc::now().timestamp();
+		store.record_failure(addr(0)).unwrap();
+		store.record_failure(addr(0)).unwrap();
+		store.record_failure(addr(1)).unwrap();
+		let failing = store.get_peer(addr(0)).unwrap();
+		assert_eq!(failing.failures, 2);
+		assert!(failing.last_attempted >= before);
+		assert!(store.record_failure(addr(9)).is_err());
+
+		for _ in 0..10 {
+			let found = find_all(&store);
+			let failures = found.iter().map(|p| p.failures).collect::<Vec<_>>();
+			assert_eq!(failures, vec![0, 0, 1, 2]);
+		}
+		let found = store
+			.find_peers(p2p::State::Healthy, p2p::Capabilities::UNKNOWN, 2)
+			.unwrap();
+		assert!(found.iter().all(|p| p.failures == 0));
+
+		// connected again, failures are over
+		let mut refreshed = peer(0);
+		refreshed.last_attempted = Utc::now().timestamp();
+		store.save_peer(&refreshed).unwrap();
+		assert_eq!(store.get_peer(addr(0)).unwrap().failures, 0);
+	}
+
+	// as after a restart
+	let store = p2p::PeerStore::new(db_root).unwrap();
+	let found = find_all(&store);
+	assert_eq!(found.len(), 4);
+	assert_eq!(found.last().unwrap().addr, addr(1));
+	assert_eq!(found.last().unwrap().failures, 1);
+	assert!(found.last().unwrap().last_attempted > 0);
+}
+
+// Among the peers failing the least, the ones we heard from lately are
+// picked first, and only news fresh enough is saved.
+#[test]
+fn peer_store_recency() {
+	util::init_test_logger();
+
+	let db_root = ".grin_peer_store_recency";
+	let _ = fs::remove_dir_all(db_root);
+	let store = p2p::PeerStore::new(db_root).unwrap();
+	let now = Utc::now().timestamp();
+	let day = 24 * 3600;
+	for i in 0..10 {
+		let mut p = peer(i);
+		p.last_seen = if i % 2 == 0 { now - 10 * day } else { now - 60 };
+		store.save_peer(&p).unwrap();
+	}
+	let mut failing = peer(10);
+	failing.last_seen = now;
+	failing.failures = 1;
+	store.save_peer(&failing).unwrap();
+
+	for _ in 0..10 {
+		let found = store
+			.find_peers(p2p::State::Healthy, p2p::Capabilities::UNKNOWN, 5)
+			.unwrap();
+		assert!(found.iter().all(|p| p.last_seen == now - 60));
+		let found = find_all(&store);
+		assert_eq!(found.last().unwrap().addr, addr(10));
+		assert!(found[5..10].iter().all(|p| p.last_seen == now - 10 * day));
+	}
+
+	// too close to what we have to bother
+	store.update_last_seen(addr(1), now).unwrap();
+	assert_eq!(store.get_peer(addr(1)).unwrap().last_seen, now - 60);
+	store.update_last_seen(addr(0), now).unwrap();
+	let seen = store.get_peer(addr(0)).unwrap();
+	assert_eq!(seen.last_seen, now);
+	assert_eq!(seen.last_connected, now);
+	assert!(store.update_last_seen(addr(20), now).is_err());
+
+	store.update_state(addr(2), p2p::State::Defunct).unwrap();
+	store.update_state(addr(4), p2p::State::Banned).unwrap();
+	assert_eq!(
+		store.stats().unwrap(),
+		p2p::StoreStats {
+			healthy: 9,
+			banned: 1,
+			defunct: 1,
+			incompatible: 0,
+			unverified: 0,
+		}
+	);
+}
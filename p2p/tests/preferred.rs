@@ -0,0 +1,134 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::Duration;
+use std::fs;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+
+// A preferred peer is dialed even while we back off from it or have it
+// banned, on top of our outbound target, and again shortly after it drops us.
+#[test]
+fn preferred_redialed() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_preferred_a");
+
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, b_addr) = start_node(".grin_preferred_b", p2p::Capabilities::FULL_NODE, b);
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		peers_preferred: Some(vec![b_addr.clone()]),
+		preferred_redial_interval: Some(1),
+		outbound_target: Some(0),
+		..p2p::P2PConfig::default()
+	};
+	let (a_server, _) =
+		start_node_with(".grin_preferred_a", p2p::Capabilities::FULL_NODE, a, config);
+	thread::sleep(time::Duration::from_secs(1));
+
+	for _ in 0..5 {
+		a_server
+			.peers
+			.connect_failed(b_addr.clone(), &p2p::Error::Timeout);
+	}
+	a_server.peers.ban_peer(
+		b_addr.clone(),
+		p2p::ReasonForBan::ManualBan,
+		Duration::hours(1),
+	);
+	assert!(!a_server.peers.can_dial(&b_addr));
+
+	assert_eq!(p2p::Server::maintain_outbound(&a_server), 1);
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(a_server.peers.get_connected_peer(b_addr.clone()).is_some());
+	assert_eq!(a_server.peers.unpreferred_outbound_count(), 0);
+
+	// connected already, nothing to do
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(p2p::Server::maintain_outbound(&a_server), 0);
+
+	// it drops us, we're back shortly after
+	for p in b_server.peers.connected_peers() {
+		p.stop();
+	}
+	thread::sleep(time::Duration::from_millis(500));
+	a_server.peers.clean_peers(8);
+	assert!(a_server.peers.get_connected_peer(b_addr.clone()).is_none());
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(p2p::Server::maintain_outbound(&a_server), 1);
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(a_server.peers.get_connected_peer(b_addr.clone()).is_some());
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// With too many peers the ones dropped are never our preferred ones, which
+// don't count against our maximum either.
+#[test]
+fn preferred_never_evicted() {
+	util::init_test_logger();
+
+	let mut others = vec![];
+	let mut addrs = vec![];
+	for i in 0..3 {
+		let adapter = Arc::new(PoolAdapter::new(vec![], None));
+		let (server, addr) = start_node(
+			&format!(".grin_preferred_evict_{}", i),
+			p2p::Capabilities::FULL_NODE,
+			adapter,
+		);
+		others.push(server);
+		addrs.push(addr);
+	}
+	let preferred = addrs[1].clone();
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		peers_preferred: Some(vec![preferred.clone()]),
+		..p2p::P2PConfig::default()
+	};
+	let (server, _) = start_node_with(
+		".grin_preferred_evict",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	for addr in &addrs {
+		server.connect(addr.clone()).unwrap();
+	}
+	let stats = server.peers.connected_stats();
+	assert_eq!(stats.iter().filter(|s| s.preferred).count(), 1);
+	assert!(stats.iter().any(|s| s.preferred && s.addr == preferred));
+
+	server.peers.clean_peers(1);
+	assert_eq!(server.peers.peer_count(), 2);
+	assert!(server.peers.get_connected_peer(preferred.clone()).is_some());
+
+	server.peers.clean_peers(0);
+	assert_eq!(server.peers.peer_count(), 1);
+	assert!(server.peers.get_connected_peer(preferred).is_some());
+
+	server.stop();
+	for other in others {
+		other.stop();
+	}
+}
@@ -0,0 +1,160 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader};
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{
+	read_discard, read_header, write_message, GetPeerAddrs, Locator, MsgHeaderWrapper, PeerError,
+	PeerErrorCode, Ping, ProtocolVersion, Type, BODY_TIMEOUT,
+};
+use crate::p2p::types::PeerAddr;
+use crate::p2p::MAX_DROPPED_MSGS_PER_MIN;
+
+fn get_peer_addrs(conn: &mut TcpStream, version: ProtocolVersion) {
+	let msg = GetPeerAddrs {
+		capabilities: p2p::Capabilities::UNKNOWN,
+	};
+	write_message(conn, msg, version, Type::GetPeerAddrs).unwrap();
+}
+
+// Pings and counts the msgs of the provided type coming before the pong.
+fn count_before_pong(conn: &mut TcpStream, version: ProtocolVersion, msg_type: Type) -> usize {
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	write_message(conn, ping, version, Type::Ping).unwrap();
+	let mut count = 0;
+	loop {
+		let deadline = Instant::now() + BODY_TIMEOUT;
+		match read_header(conn, version, None).unwrap() {
+			MsgHeaderWrapper::Known(header) => {
+				match header.msg_type {
+					Type::Pong => return count,
+					Type::PeerError => panic!("expected no error"),
+					t if t == msg_type => count += 1,
+					_ => {}
+				}
+				read_discard(header.msg_len, conn, deadline).unwrap();
+			}
+			MsgHeaderWrapper::Unknown(msg_len, _) => read_discard(msg_len, conn, deadline).unwrap(),
+		}
+	}
+}
+
+// Requests over the limit are dropped, the peer isn't disconnected for a
+// burst.
+#[test]
+fn rate_limit_burst_dropped() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_rate_limit_burst",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	let limit = p2p::P2PConfig::default().peer_addr_requests_per_min() as usize;
+	for _ in 0..limit + 3 {
+		get_peer_addrs(&mut conn, version);
+	}
+	assert_eq!(
+		count_before_pong(&mut conn, version, Type::PeerAddrs),
+		limit
+	);
+
+	server.stop();
+}
+
+// A peer that keeps going over the limit is disconnected, with a strike.
+#[test]
+fn rate_limit_abuse_disconnected() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_rate_limit_abuse",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+	let peer = server
+		.peers
+		.get_connected_peer(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()))
+		.unwrap();
+
+	let limit = p2p::P2PConfig::default().peer_addr_requests_per_min() as u64;
+	for _ in 0..=limit + MAX_DROPPED_MSGS_PER_MIN {
+		get_peer_addrs(&mut conn, version);
+	}
+	let err: PeerError = read_until(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::RateLimited));
+	assert_eq!(peer.request_strikes(), 1);
+
+	server.stop();
+}
+
+// Requests at the pace of a syncing peer all get answered.
+#[test]
+fn rate_limit_sync_unaffected() {
+	util::init_test_logger();
+
+	let block = Block::with_header(BlockHeader::default());
+	let adapter = Arc::new(PoolAdapter::new(vec![], Some(block.clone())));
+	let (server, addr) = start_node(
+		".grin_rate_limit_sync",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	// a batch of headers at a time, processed before asking for the next
+	let per_sec = p2p::P2PConfig::default().header_requests_per_sec();
+	for _ in 0..per_sec * 2 {
+		let locator = Locator {
+			hashes: vec![block.hash()],
+		};
+		write_message(&mut conn, locator, version, Type::GetHeaders).unwrap();
+		let _: p2p::msg::Headers = read_until(&mut conn, version, Type::Headers).unwrap();
+		thread::sleep(time::Duration::from_millis(1000 / per_sec as u64));
+	}
+
+	// block requests aren't limited
+	for _ in 0..50 {
+		write_message(&mut conn, block.hash(), version, Type::GetBlock).unwrap();
+	}
+	assert_eq!(count_before_pong(&mut conn, version, Type::Block), 50);
+
+	server.stop();
+}
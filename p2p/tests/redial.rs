@@ -0,0 +1,146 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::{TimeZone, Utc};
+use std::fs;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::p2p::msg::ProtocolVersion;
+use crate::p2p::types::{redial_backoff, PeerAddr};
+
+fn failing(server: &p2p::Server, addr: &PeerAddr, times: u32) {
+	for _ in 0..times {
+		server
+			.peers
+			.connect_failed(addr.clone(), &p2p::Error::Timeout);
+	}
+}
+
+// How long after its last attempt we'll dial a peer again.
+fn wait(server: &p2p::Server, addr: &PeerAddr) -> i64 {
+	let peer = server.peers.get_peer(addr.clone()).unwrap();
+	(server.peers.redial_at(addr).unwrap() - Utc.timestamp(peer.last_attempted, 0)).num_seconds()
+}
+
+// Every consecutive failure gets us further along the schedule, up to a day,
+// and a peer we're backing off from isn't a dial candidate until its wait is
+// over.
+#[test]
+fn redial_schedule() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_redial_schedule");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node(
+		".grin_redial_schedule",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+	);
+	let addr = PeerAddr::Ip("1.2.3.4:3414".parse().unwrap());
+	assert!(server.peers.redial_at(&addr).is_none());
+
+	let mut waits = vec![];
+	for _ in 0..7 {
+		failing(&server, &addr, 1);
+		assert!(!server.peers.can_dial(&addr));
+		waits.push(wait(&server, &addr));
+	}
+	assert_eq!(
+		waits,
+		vec![30, 120, 600, 3600, 6 * 3600, 24 * 3600, 24 * 3600]
+	);
+	let peer = server.peers.get_peer(addr.clone()).unwrap();
+	assert_eq!(peer.failures, 7);
+	assert_eq!(peer.flags, p2p::State::Defunct);
+
+	// healthy again but still backing off
+	server
+		.peers
+		.update_state(addr.clone(), p2p::State::Healthy)
+		.unwrap();
+	assert!(!server.peers.dial_candidates(8).contains(&addr));
+
+	// the wait is over
+	let mut peer = server.peers.get_peer(addr.clone()).unwrap();
+	peer.last_attempted -= redial_backoff(peer.failures).as_secs() as i64;
+	server.peers.save_peer(&peer).unwrap();
+	assert!(server.peers.can_dial(&addr));
+	assert!(server.peers.dial_candidates(8).contains(&addr));
+
+	// no protocol version in common, straight to the longest wait
+	let other = PeerAddr::Ip("1.2.3.5:3414".parse().unwrap());
+	server.peers.connect_failed(
+		other.clone(),
+		&p2p::Error::UnsupportedProtocol(ProtocolVersion(0)),
+	);
+	assert_eq!(wait(&server, &other), 24 * 3600);
+
+	server.stop();
+}
+
+// A peer we used to fail to connect to is dialed right away again once we
+// managed to connect to it.
+#[test]
+fn redial_reset_outbound() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_redial_out_a");
+
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (a_server, _) = start_node(".grin_redial_out_a", p2p::Capabilities::FULL_NODE, a);
+	let (b_server, b_addr) = start_node(".grin_redial_out_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+
+	failing(&a_server, &b_addr, 3);
+	assert_eq!(wait(&a_server, &b_addr), 600);
+	assert!(!a_server.peers.can_dial(&b_addr));
+
+	a_server.connect(b_addr.clone()).unwrap();
+	assert_eq!(a_server.peers.get_peer(b_addr.clone()).unwrap().failures, 0);
+	assert!(a_server.peers.redial_at(&b_addr).is_none());
+	assert!(a_server.peers.can_dial(&b_addr));
+
+	a_server.stop();
+	b_server.stop();
+}
+
+// So does a peer that connects to us.
+#[test]
+fn redial_reset_inbound() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_redial_in");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(".grin_redial_in", p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let inbound = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	failing(&server, &inbound, 4);
+	assert_eq!(wait(&server, &inbound), 3600);
+
+	let _conn = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+	assert_eq!(server.peers.get_peer(inbound.clone()).unwrap().failures, 0);
+	assert!(server.peers.redial_at(&inbound).is_none());
+	assert!(server.peers.can_dial(&inbound));
+
+	server.stop();
+}
@@ -0,0 +1,179 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::{Hash, Hashed};
+use crate::core::core::{Transaction, TxKernel};
+use crate::p2p::msg::{write_message, Type};
+
+fn config(relay_fanout: u32) -> p2p::P2PConfig {
+	p2p::P2PConfig {
+		relay_fanout: Some(relay_fanout),
+		..p2p::P2PConfig::default()
+	}
+}
+
+fn tx(fee: u64) -> Transaction {
+	Transaction::empty().with_kernel(TxKernel {
+		fee,
+		..TxKernel::empty()
+	})
+}
+
+// A tx relayed to 4 peers at most by each node still reaches every node of a
+// random graph. A ring in random order, so no node is ever cut off by a
+// single peer not relaying to it, with chords on top, up to 5 peers a node.
+#[test]
+fn relay_fanout_graph_coverage() {
+	util::init_test_logger();
+
+	let nodes: Vec<_> = (0..12)
+		.map(|i| {
+			let adapter = Arc::new(PoolAdapter::new(vec![], None));
+			let (server, addr) = start_node_with(
+				&format!(".grin_relay_fanout_{}", i),
+				p2p::Capabilities::FULL_NODE,
+				adapter.clone(),
+				config(4),
+			);
+			(server, addr, adapter)
+		})
+		.collect();
+	thread::sleep(time::Duration::from_secs(1));
+
+	let mut rng = thread_rng();
+	let mut ring: Vec<usize> = (0..nodes.len()).collect();
+	ring.shuffle(&mut rng);
+	let mut edges = HashSet::new();
+	for i in 0..ring.len() {
+		let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+		edges.insert((a.min(b), a.max(b)));
+	}
+	let degree = |edges: &HashSet<(usize, usize)>, n: usize| {
+		edges.iter().filter(|(a, b)| *a == n || *b == n).count()
+	};
+	for _ in 0..50 {
+		let pair: Vec<_> = (0..nodes.len())
+			.collect::<Vec<_>>()
+			.choose_multiple(&mut rng, 2)
+			.cloned()
+			.collect();
+		let (a, b) = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+		if degree(&edges, a) < 5 && degree(&edges, b) < 5 {
+			edges.insert((a, b));
+		}
+	}
+	for (a, b) in &edges {
+		nodes[*a].0.connect(nodes[*b].1.clone()).unwrap();
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	let tx = tx(1);
+	let h = tx.kernels()[0].hash();
+	let (origin, _, origin_adapter) = &nodes[ring[0]];
+	origin_adapter.pool.write().push(tx.clone());
+	origin.peers.broadcast_transaction(&tx, None);
+
+	let reached =
+		|adapter: &PoolAdapter| adapter.received.lock().contains(&Received::Transaction(h));
+	let start = Instant::now();
+	while !nodes
+		.iter()
+		.filter(|(_, _, adapter)| !Arc::ptr_eq(adapter, origin_adapter))
+		.all(|(_, _, adapter)| reached(adapter))
+	{
+		assert!(
+			start.elapsed() < time::Duration::from_secs(10),
+			"tx didn't reach every node"
+		);
+		thread::sleep(time::Duration::from_millis(100));
+	}
+
+	for (server, _, _) in &nodes {
+		server.stop();
+	}
+}
+
+// Out of more peers than the fanout, the outbound ones get a tx, as does a
+// peer that asked for it before we had it, even inbound.
+#[test]
+fn relay_fanout_outbound_and_wanted() {
+	util::init_test_logger();
+
+	let hub = Arc::new(PoolAdapter::new(vec![], None));
+	let (hub_server, hub_addr) = start_node_with(
+		".grin_relay_fanout_hub",
+		p2p::Capabilities::FULL_NODE,
+		hub.clone(),
+		config(4),
+	);
+	let spokes: Vec<_> = (0..6)
+		.map(|i| {
+			let adapter = Arc::new(PoolAdapter::new(vec![], None));
+			let (server, addr) = start_node(
+				&format!(".grin_relay_fanout_spoke_{}", i),
+				p2p::Capabilities::FULL_NODE,
+				adapter.clone(),
+			);
+			(server, addr, adapter)
+		})
+		.collect();
+	thread::sleep(time::Duration::from_secs(1));
+	for (_, addr, _) in &spokes {
+		hub_server.connect(addr.clone()).unwrap();
+	}
+	let (mut conn, version) = connect_raw(&hub_addr);
+	let (mut later, later_version) = connect_raw_from(&hub_addr, p2p::Capabilities::UNKNOWN, 5001);
+
+	let tx = tx(1);
+	let h = tx.kernels()[0].hash();
+	write_message(&mut conn, h, version, Type::GetTransaction).unwrap();
+	let not_found: Hash = read_until(&mut conn, version, Type::TransactionNotFound).unwrap();
+	assert_eq!(not_found, h);
+
+	hub.pool.write().push(tx.clone());
+	hub_server.peers.broadcast_transaction(&tx, None);
+	thread::sleep(time::Duration::from_secs(2));
+
+	let received: Transaction = read_until(&mut conn, version, Type::Transaction).unwrap();
+	assert_eq!(received.kernels()[0].hash(), h);
+	let reached = spokes
+		.iter()
+		.filter(|(_, _, adapter)| adapter.received.lock().contains(&Received::Transaction(h)))
+		.count();
+	assert_eq!(reached, 4);
+	// inbound and not asking, left out
+	later
+		.set_read_timeout(Some(time::Duration::from_millis(500)))
+		.unwrap();
+	assert!(read_until::<Transaction>(&mut later, later_version, Type::Transaction).is_err());
+
+	hub_server.stop();
+	for (server, _, _) in &spokes {
+		server.stop();
+	}
+}
@@ -0,0 +1,125 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader, Transaction, TxKernel};
+
+fn tx(fee: u64) -> Transaction {
+	Transaction::empty().with_kernel(TxKernel {
+		fee,
+		..TxKernel::empty()
+	})
+}
+
+// Whatever we relay is never sent back to the peer we got it from, even
+// when our peer never told us it has it.
+#[test]
+fn relay_excludes_origin() {
+	util::init_test_logger();
+
+	let mut block = Block::with_header(BlockHeader::default());
+	*block.kernels_mut() = vec![TxKernel {
+		fee: 1,
+		..TxKernel::empty()
+	}];
+	let capab = p2p::Capabilities::HEADER_HIST | p2p::Capabilities::PEER_LIST;
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], Some(block.clone())));
+	let (a_server, _) = start_node(".grin_relay_origin_a", capab, a.clone());
+	let (b_server, b_addr) = start_node(".grin_relay_origin_b", capab, b.clone());
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	let a_addr = b_server.peers.connected_peers()[0].info.addr.clone();
+	b_server.peers.broadcast_block(&block, Some(&a_addr));
+	b_server.peers.broadcast_transaction(&tx(2), Some(&a_addr));
+	thread::sleep(time::Duration::from_secs(1));
+	assert!(a.received.lock().is_empty());
+
+	// from anywhere else, it does get there
+	let other = tx(3);
+	b_server.peers.broadcast_transaction(&other, None);
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(
+		*a.received.lock(),
+		vec![Received::Transaction(other.kernels()[0].hash())]
+	);
+}
+
+// Around a triangle relaying to a single peer at a time, each tx goes all
+// the way around and no node gets any of them twice.
+#[test]
+fn relay_triangle_once() {
+	util::init_test_logger();
+
+	let txs: Vec<_> = (1..6).map(tx).collect();
+	let capab = p2p::Capabilities::HEADER_HIST | p2p::Capabilities::PEER_LIST;
+	let config = p2p::P2PConfig {
+		relay_fanout: Some(1),
+		..p2p::P2PConfig::default()
+	};
+	let adapters: Vec<_> = (0..3)
+		.map(|i| {
+			let pool = if i == 0 { txs.clone() } else { vec![] };
+			Arc::new(PoolAdapter::new(pool, None))
+		})
+		.collect();
+	let nodes: Vec<_> = adapters
+		.iter()
+		.enumerate()
+		.map(|(i, adapter)| {
+			start_node_with(
+				&format!(".grin_relay_triangle_{}", i),
+				capab,
+				adapter.clone(),
+				config.clone(),
+			)
+		})
+		.collect();
+	thread::sleep(time::Duration::from_secs(1));
+	for i in 0..3 {
+		nodes[i].0.connect(nodes[(i + 1) % 3].1.clone()).unwrap();
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	for tx in &txs {
+		nodes[0].0.peers.broadcast_transaction(tx, None);
+		thread::sleep(time::Duration::from_millis(500));
+	}
+	thread::sleep(time::Duration::from_secs(1));
+
+	for adapter in &adapters {
+		let received = adapter.received.lock();
+		assert_eq!(received.len(), txs.len());
+		for tx in &txs {
+			let h = tx.kernels()[0].hash();
+			let count = received
+				.iter()
+				.filter(|r| **r == Received::Transaction(h))
+				.count();
+			assert_eq!(count, 1);
+		}
+	}
+}
@@ -0,0 +1,81 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader};
+use crate::p2p::msg::Type;
+use crate::p2p::types::PeerAddr;
+use crate::p2p::Requested;
+
+// A peer leaving our block request unanswered has it handed back for retry
+// and is picked after the peers that answer.
+#[test]
+fn silent_peer_deprioritized() {
+	util::init_test_logger();
+
+	let block = Block::with_header(BlockHeader::default());
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	let b = Arc::new(PoolAdapter::new(vec![], Some(block.clone())));
+	let (a_server, a_addr) = start_node(".grin_requests_a", p2p::Capabilities::FULL_NODE, a);
+	let (_b_server, b_addr) = start_node(".grin_requests_b", p2p::Capabilities::FULL_NODE, b);
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr.clone()).unwrap();
+	// never answers anything
+	let (_conn, _) = connect_raw(&a_addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let silent_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let silent = a_server
+		.peers
+		.get_connected_peer(silent_addr.clone())
+		.unwrap();
+	let responsive = a_server.peers.get_connected_peer(b_addr.clone()).unwrap();
+	silent.send_block_request(block.hash()).unwrap();
+	responsive.send_block_request(block.hash()).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	let ttl = time::Duration::from_millis(500);
+	assert!(responsive.expired_requests(ttl).is_empty());
+	let expired = silent.expired_requests(ttl);
+	assert_eq!(expired.len(), 1);
+	assert_eq!(expired[0].msg_type, Type::GetBlock);
+	assert_eq!(expired[0].requested, Requested::Block(block.hash()));
+	assert_eq!(silent.request_strikes(), 1);
+	assert_eq!(responsive.request_strikes(), 0);
+
+	for _ in 0..10 {
+		let peers = a_server.peers.most_work_peers(p2p::Capabilities::UNKNOWN);
+		assert_eq!(peers.len(), 2);
+		assert_eq!(peers[1].info.addr, silent_addr);
+		assert_eq!(
+			a_server
+				.peers
+				.most_work_peer(p2p::Capabilities::UNKNOWN)
+				.unwrap()
+				.info
+				.addr,
+			b_addr
+		);
+	}
+}
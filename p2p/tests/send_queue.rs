@@ -0,0 +1,130 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Block, BlockHeader, TxKernel};
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{PeerError, PeerErrorCode, Type};
+use crate::p2p::types::PeerAddr;
+use crate::p2p::Error;
+
+fn block_at(height: u64) -> Block {
+	let mut block = Block::with_header(BlockHeader {
+		height,
+		..BlockHeader::default()
+	});
+	*block.kernels_mut() = vec![TxKernel::empty(); 2_000];
+	block
+}
+
+// A peer not reading what we send gets its announcements dropped once its
+// queue is full, and is disconnected when we can't even send it a request.
+#[test]
+fn slow_peer_queue_full() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		max_queued_bytes: Some(1_000_000),
+		..p2p::P2PConfig::default()
+	};
+	let (server, addr) = start_node_with(
+		".grin_send_queue",
+		p2p::Capabilities::FULL_NODE,
+		adapter,
+		config,
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let peer = server
+		.peers
+		.get_connected_peer(PeerAddr::Ip("127.0.0.1:5000".parse().unwrap()))
+		.unwrap();
+
+	// we don't read, blocks pile up once the socket buffers are full
+	let mut height = 1;
+	loop {
+		match peer.send_block(&block_at(height)) {
+			Ok(_) => height += 1,
+			Err(Error::QueueFull) => break,
+			Err(e) => panic!("expected a full queue, got {:?}", e),
+		}
+		assert!(height < 1_000, "queue never filled up");
+	}
+	assert!(peer.queued_bytes() > 0);
+
+	match peer.send_ping(Difficulty::min(), 0) {
+		Err(Error::QueueFull) => {}
+		res => panic!("expected the ping dropped, got {:?}", res),
+	}
+	assert!(peer.is_connected());
+
+	match peer.send_block_request(block_at(height).hash()) {
+		Err(Error::QueueFull) => {}
+		res => panic!("expected a full queue, got {:?}", res),
+	}
+
+	// told why once we read again, right after what was being written
+	let err: PeerError = read_until(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::TooSlow));
+	thread::sleep(time::Duration::from_millis(500));
+	assert!(!peer.is_connected());
+
+	server.stop();
+}
+
+// Relaying to a peer whose queue is full skips it, as does pinging it, it
+// stays one of our peers.
+#[test]
+fn broadcast_skips_full_queue() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let config = p2p::P2PConfig {
+		max_queued_bytes: Some(1_000_000),
+		..p2p::P2PConfig::default()
+	};
+	let capab = p2p::Capabilities::FULL_NODE | p2p::Capabilities::BLOCK_INV;
+	let (server, addr) = start_node_with(".grin_send_queue_broadcast", capab, adapter, config);
+	thread::sleep(time::Duration::from_secs(1));
+	let (_conn, _) = connect_raw_from(&addr, capab, 5000);
+	thread::sleep(time::Duration::from_millis(500));
+
+	let peer_addr = PeerAddr::Ip("127.0.0.1:5000".parse().unwrap());
+	let peer = server.peers.get_connected_peer(peer_addr.clone()).unwrap();
+
+	// we don't read, way more than the queue and the socket buffers hold
+	for height in 1..200 {
+		server.peers.broadcast_block(&block_at(height), None);
+	}
+	assert!(peer.queued_bytes() > 0);
+	server.peers.check_all(Difficulty::min(), 0);
+
+	assert!(peer.is_connected());
+	assert!(server.peers.is_known(peer_addr));
+
+	server.stop();
+}
@@ -12,9 +12,60 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use grin_core as core;
 use grin_p2p as p2p;
 
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use num::FromPrimitive;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::{SocketAddr, SocketAddrV6};
+
+use crate::core::core::hash::Hash;
+use crate::core::core::BlockHeader;
+use crate::core::pow::Difficulty;
+use crate::core::ser;
+use crate::p2p::msg::{
+	read_header, read_message, write_to_buf, write_to_buf_compressed, BanReason, Hand, Headers,
+	Locator, MsgHeader, MsgHeaderWrapper, PeerAddrs, PeerError, PeerErrorCode, Ping,
+	ProtocolVersion, Shake, Type, EXT_HEIGHT, FLOONET_MAGIC, MAX_BAN_MESSAGE_LEN,
+	MAX_EXTENSIONS_LEN, MAX_PEER_ERROR_LEN,
+};
+use crate::p2p::types::{Capabilities, NodeId, PeerAddr, ReasonForBan};
+
+fn test_hand() -> Hand {
+	Hand {
+		version: ProtocolVersion::default(),
+		min_version: ProtocolVersion::min_supported(),
+		capabilities: Capabilities::FULL_NODE,
+		nonce: 42,
+		genesis: Hash::from_vec(&vec![1]),
+		total_difficulty: Difficulty::min(),
+		height: Some(0),
+		sender_addr: PeerAddr::Ip("127.0.0.1:3414".parse().unwrap()),
+		receiver_addr: PeerAddr::Ip("127.0.0.1:13414".parse().unwrap()),
+		user_agent: p2p::msg::USER_AGENT.to_string(),
+		node_id: None,
+		extensions: vec![],
+	}
+}
+
+fn test_shake() -> Shake {
+	Shake {
+		version: ProtocolVersion::default(),
+		min_version: ProtocolVersion::min_supported(),
+		capabilities: Capabilities::FULL_NODE,
+		nonce: Some(42),
+		genesis: Hash::from_vec(&vec![1]),
+		total_difficulty: Difficulty::min(),
+		height: Some(0),
+		observed_addr: Some(PeerAddr::Ip("10.0.0.1:3414".parse().unwrap())),
+		user_agent: p2p::msg::USER_AGENT.to_string(),
+		node_id: None,
+		extensions: vec![],
+	}
+}
 
 // Test that Healthy == 0.
 #[test]
@@ -78,3 +129,624 @@ fn test_capabilities() {
 			.contains(p2p::types::Capabilities::TX_KERNEL_HASH)
 	);
 }
+
+#[test]
+fn test_hand_shake_genesis() {
+	let vec = ser::ser_vec(&test_hand()).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.genesis, Hash::from_vec(&vec![1]));
+
+	let vec = ser::ser_vec(&test_shake()).unwrap();
+	let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(shake.genesis, Hash::from_vec(&vec![1]));
+	assert_eq!(
+		shake.observed_addr,
+		Some(PeerAddr::Ip("10.0.0.1:3414".parse().unwrap()))
+	);
+}
+
+// A Hand as peers running version 1 send it, nothing after the genesis.
+fn v1_hand() -> Vec<u8> {
+	let user_agent = b"MW/Grin 1.0.0".to_vec();
+	let mut vec = ser::ser_vec(&ProtocolVersion(1)).unwrap();
+	vec.extend(ser::ser_vec(&Capabilities::FULL_NODE.bits()).unwrap());
+	vec.extend(ser::ser_vec(&42u64).unwrap());
+	vec.extend(ser::ser_vec(&Difficulty::min()).unwrap());
+	vec.extend(ser::ser_vec(&PeerAddr::Ip("127.0.0.1:3414".parse().unwrap())).unwrap());
+	vec.extend(ser::ser_vec(&PeerAddr::Ip("127.0.0.1:13414".parse().unwrap())).unwrap());
+	vec.extend(ser::ser_vec(&(user_agent.len() as u64)).unwrap());
+	vec.extend(user_agent);
+	vec.extend(ser::ser_vec(&Hash::from_vec(&vec![1])).unwrap());
+	vec
+}
+
+// Version 1 Hands and Shakes read, and ours are readable by peers running
+// version 1: the fields they know come first, as they expect them.
+#[test]
+fn test_hand_shake_v1() {
+	let hand: Hand = ser::deserialize(&mut &v1_hand()[..]).unwrap();
+	assert_eq!(hand.version, ProtocolVersion(1));
+	assert_eq!(hand.min_version, ProtocolVersion(1));
+	assert_eq!(hand.capabilities, Capabilities::FULL_NODE);
+	assert_eq!(hand.nonce, 42);
+	assert_eq!(hand.user_agent, "MW/Grin 1.0.0");
+	assert_eq!(hand.genesis, Hash::from_vec(&vec![1]));
+	assert_eq!(hand.height, None);
+	assert_eq!(hand.node_id, None);
+
+	let mut ours = test_hand();
+	ours.version = ProtocolVersion(1);
+	ours.user_agent = "MW/Grin 1.0.0".to_string();
+	let vec = ser::ser_vec(&ours).unwrap();
+	assert!(vec.starts_with(&v1_hand()));
+
+	let mut shake = test_shake();
+	shake.version = ProtocolVersion(1);
+	let vec = ser::ser_vec(&shake).unwrap();
+	let mut v1 = ser::ser_vec(&ProtocolVersion(1)).unwrap();
+	v1.extend(ser::ser_vec(&Capabilities::FULL_NODE.bits()).unwrap());
+	v1.extend(ser::ser_vec(&Difficulty::min()).unwrap());
+	v1.extend(ser::ser_vec(&(p2p::msg::USER_AGENT.len() as u64)).unwrap());
+	v1.extend(p2p::msg::USER_AGENT.as_bytes());
+	v1.extend(ser::ser_vec(&Hash::from_vec(&vec![1])).unwrap());
+	assert_eq!(vec, v1);
+	let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(shake.min_version, ProtocolVersion(1));
+	assert_eq!(shake.nonce, None);
+	assert_eq!(shake.height, None);
+	assert_eq!(shake.observed_addr, None);
+}
+
+#[test]
+fn test_hand_shake_height() {
+	let mut hand = test_hand();
+	hand.height = Some(1_000);
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.height, Some(1_000));
+
+	let mut shake = test_shake();
+	shake.height = Some(1_000);
+	let vec = ser::ser_vec(&shake).unwrap();
+	let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(shake.height, Some(1_000));
+}
+
+// Capability bits we don't know about survive deserialization.
+#[test]
+fn test_hand_unknown_capabilities() {
+	let bits = Capabilities::FULL_NODE.bits() | 1 << 31;
+	let mut hand = test_hand();
+	hand.capabilities = Capabilities::from_bits_preserve(bits);
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.capabilities.bits(), bits);
+}
+
+// Reading stopped short of the end of the msg, with an error rather than a
+// panic.
+fn assert_ended_early<T>(res: Result<T, ser::Error>) {
+	match res {
+		Err(ser::Error::IOErr(_, kind)) => assert_eq!(kind, io::ErrorKind::UnexpectedEof),
+		Err(e) => panic!("expected the msg to end early, got {:?}", e),
+		Ok(_) => panic!("expected the msg to end early, read it whole"),
+	}
+}
+
+// An old peer that does not send the genesis hash must fail to deserialize
+// cleanly (and not panic). Its Hand and Shake are laid out as they were
+// before the genesis was added, field by field.
+#[test]
+fn test_hand_shake_missing_genesis() {
+	let user_agent = b"MW/Grin 0.4.0".to_vec();
+	let mut hand = ser::ser_vec(&ProtocolVersion(1)).unwrap();
+	hand.extend(ser::ser_vec(&Capabilities::FULL_NODE.bits()).unwrap());
+	hand.extend(ser::ser_vec(&42u64).unwrap());
+	hand.extend(ser::ser_vec(&Difficulty::min()).unwrap());
+	hand.extend(ser::ser_vec(&PeerAddr::Ip("127.0.0.1:3414".parse().unwrap())).unwrap());
+	hand.extend(ser::ser_vec(&PeerAddr::Ip("127.0.0.1:13414".parse().unwrap())).unwrap());
+	hand.extend(ser::ser_vec(&(user_agent.len() as u64)).unwrap());
+	hand.extend(user_agent.clone());
+	assert_ended_early(ser::deserialize::<Hand>(&mut &hand[..]));
+
+	let mut shake = ser::ser_vec(&ProtocolVersion(1)).unwrap();
+	shake.extend(ser::ser_vec(&Capabilities::FULL_NODE.bits()).unwrap());
+	shake.extend(ser::ser_vec(&Difficulty::min()).unwrap());
+	shake.extend(ser::ser_vec(&(user_agent.len() as u64)).unwrap());
+	shake.extend(user_agent);
+	assert_ended_early(ser::deserialize::<Shake>(&mut &shake[..]));
+}
+
+fn round_trip(addr: &PeerAddr) -> PeerAddr {
+	let vec = ser::ser_vec(addr).unwrap();
+	ser::deserialize(&mut &vec[..]).unwrap()
+}
+
+// Ip addresses keep the legacy encoding (tag 0 for v4, 1 for v6), new kinds of
+// addresses get their own tag.
+#[test]
+fn test_peer_addr_ip() {
+	let addr = PeerAddr::Ip("127.0.0.1:3414".parse().unwrap());
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert_eq!(vec, vec![0, 127, 0, 0, 1, 0x0d, 0x56]);
+	assert_eq!(round_trip(&addr), addr);
+
+	let addr = PeerAddr::Ip("[2001:db8::1]:3414".parse().unwrap());
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert_eq!(vec[0], 1);
+	assert_eq!(round_trip(&addr), addr);
+}
+
+// Ipv6 addresses are written as their 16 bytes and port only, while
+// ipv4-mapped ones become plain ipv4 addresses.
+#[test]
+fn test_peer_addr_ip_canonical() {
+	let global: SocketAddrV6 = "[2a01:4f8::1]:3414".parse().unwrap();
+	let link_local: SocketAddrV6 = "[fe80::1]:3414".parse().unwrap();
+	for ip in vec![global.ip(), link_local.ip()] {
+		let addr = PeerAddr::Ip(SocketAddr::V6(SocketAddrV6::new(*ip, 3414, 7, 3)));
+		let vec = ser::ser_vec(&addr).unwrap();
+		assert_eq!(vec.len(), 1 + 16 + 2);
+		assert_eq!(vec[0], 1);
+		assert_eq!(&vec[1..17], &ip.octets()[..]);
+		assert_eq!(
+			round_trip(&addr).ip_addr(),
+			Some(SocketAddr::V6(SocketAddrV6::new(*ip, 3414, 0, 0)))
+		);
+	}
+
+	let v4: SocketAddr = "1.2.3.4:3414".parse().unwrap();
+	let mapped = PeerAddr::Ip("[::ffff:1.2.3.4]:3414".parse().unwrap());
+	let vec = ser::ser_vec(&mapped).unwrap();
+	assert_eq!(vec, ser::ser_vec(&PeerAddr::Ip(v4)).unwrap());
+	assert_eq!(round_trip(&mapped).ip_addr(), Some(v4));
+	// also when sent as ipv6 by an older peer
+	let mut vec = vec![1];
+	vec.extend_from_slice(&[0; 10]);
+	vec.extend_from_slice(&[0xff, 0xff, 1, 2, 3, 4, 0x0d, 0x56]);
+	let addr: PeerAddr = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(addr.ip_addr(), Some(v4));
+
+	// mapped and plain are the same peer
+	assert_eq!(mapped, PeerAddr::Ip(v4));
+	assert_eq!(mapped.as_key(), PeerAddr::Ip(v4).as_key());
+	let mut set = HashSet::new();
+	set.insert(mapped);
+	assert!(!set.insert(PeerAddr::Ip(v4)));
+	let loopback = PeerAddr::Ip("[::ffff:127.0.0.1]:3414".parse().unwrap());
+	assert_eq!(loopback, PeerAddr::Ip("127.0.0.1:3414".parse().unwrap()));
+	assert_ne!(loopback, PeerAddr::Ip("127.0.0.1:3415".parse().unwrap()));
+
+	// a hand from a v6 only host reads fine anywhere
+	let mut hand = test_hand();
+	hand.sender_addr = PeerAddr::Ip(SocketAddr::V6(global));
+	hand.receiver_addr = PeerAddr::Ip(SocketAddr::V6(SocketAddrV6::new(
+		*link_local.ip(),
+		13414,
+		0,
+		2,
+	)));
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.sender_addr.ip_addr(), Some(SocketAddr::V6(global)));
+	assert_eq!(
+		hand.receiver_addr.ip_addr(),
+		Some("[fe80::1]:13414".parse().unwrap())
+	);
+}
+
+#[test]
+fn test_peer_addr_dns() {
+	let addr = PeerAddr::Dns("seed.grin-tech.org".to_string(), 3414);
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert_eq!(vec[0], 2);
+	assert_eq!(round_trip(&addr), addr);
+
+	let mut hand = test_hand();
+	hand.sender_addr = addr.clone();
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.sender_addr, addr);
+}
+
+#[test]
+fn test_peer_addr_onion() {
+	let addr = PeerAddr::Onion(
+		"2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion".to_string(),
+		3414,
+	);
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert_eq!(vec[0], 3);
+	assert_eq!(round_trip(&addr), addr);
+	assert!(addr.resolve().is_err());
+}
+
+#[test]
+fn test_peer_addr_corrupted() {
+	// unknown tag
+	assert!(ser::deserialize::<PeerAddr>(&mut &[4u8, 0, 0][..]).is_err());
+
+	// host name too long
+	let addr = PeerAddr::Dns("a".repeat(300), 3414);
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert!(ser::deserialize::<PeerAddr>(&mut &vec[..]).is_err());
+
+	// empty host name
+	let addr = PeerAddr::Dns(String::new(), 3414);
+	let vec = ser::ser_vec(&addr).unwrap();
+	assert!(ser::deserialize::<PeerAddr>(&mut &vec[..]).is_err());
+}
+
+// Host names compare case insensitively and are never equal to an ip address.
+#[test]
+fn test_peer_addr_eq() {
+	let a: PeerAddr = "Seed.Example.com:3414".parse().unwrap();
+	let b: PeerAddr = "seed.example.com:3414".parse().unwrap();
+	assert_eq!(a, PeerAddr::Dns("Seed.Example.com".to_string(), 3414));
+	assert_eq!(a, b);
+	assert_eq!(a.as_key(), b.as_key());
+	assert_ne!(a, PeerAddr::Dns("seed.example.com".to_string(), 13414));
+	assert_ne!(a, PeerAddr::Onion("seed.example.com".to_string(), 3414));
+
+	let onion: PeerAddr = "abc.onion:3414".parse().unwrap();
+	assert_eq!(onion, PeerAddr::Onion("abc.onion".to_string(), 3414));
+
+	let ip: PeerAddr = "127.0.0.1:3414".parse().unwrap();
+	assert_eq!(ip, PeerAddr::Ip("127.0.0.1:3414".parse().unwrap()));
+	assert_ne!(ip, PeerAddr::Dns("127.0.0.1".to_string(), 3414));
+
+	assert!("seed.example.com".parse::<PeerAddr>().is_err());
+	assert!(":3414".parse::<PeerAddr>().is_err());
+}
+
+#[test]
+fn test_msg_header_magic() {
+	let vec = ser::ser_vec(&MsgHeader::new(Type::Ping, 16)).unwrap();
+	assert_eq!(vec[..2], p2p::msg::magic());
+	match read_header(&mut &vec[..], ProtocolVersion(1), None).unwrap() {
+		MsgHeaderWrapper::Known(header) => {
+			assert_eq!(header.msg_type, Type::Ping);
+			assert_eq!(header.msg_len, 16);
+		}
+		MsgHeaderWrapper::Unknown(..) => panic!("expected a known msg type"),
+	}
+
+	// a floonet header on our mainnet test process
+	let mut vec = vec;
+	vec[..2].copy_from_slice(&FLOONET_MAGIC);
+	match read_header(&mut &vec[..], ProtocolVersion(1), None) {
+		Err(p2p::Error::WrongNetwork) => {}
+		_ => panic!("expected wrong network"),
+	}
+}
+
+// The magic is checked on every message, a stream that gets out of sync after
+// a good message is caught on the next header.
+#[test]
+fn test_msg_header_desync() {
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	let mut vec = write_to_buf(ping, ProtocolVersion::default(), Type::Ping).unwrap();
+	let first_len = vec.len();
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 0,
+		nonce: None,
+	};
+	vec.append(&mut write_to_buf(ping, ProtocolVersion::default(), Type::Ping).unwrap());
+	// drop a byte of the second message
+	vec.remove(first_len);
+
+	let mut stream = &vec[..];
+	let ping: Ping = read_message(&mut stream, ProtocolVersion::default(), Type::Ping).unwrap();
+	assert_eq!(ping.height, 0);
+	match read_message::<Ping>(&mut stream, ProtocolVersion::default(), Type::Ping) {
+		Err(p2p::Error::WrongNetwork) => {}
+		_ => panic!("expected wrong network"),
+	}
+}
+
+// The node id comes last and is optional, older peers don't send it.
+#[test]
+fn test_hand_shake_node_id() {
+	let node_id = NodeId([7; 16]);
+
+	let mut hand = test_hand();
+	hand.node_id = Some(node_id);
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.node_id, Some(node_id));
+
+	let legacy = ser::ser_vec(&test_hand()).unwrap();
+	assert_eq!(legacy.len(), vec.len() - 3 - 16);
+	let hand: Hand = ser::deserialize(&mut &legacy[..]).unwrap();
+	assert_eq!(hand.node_id, None);
+	assert_eq!(hand.genesis, Hash::from_vec(&vec![1]));
+
+	let mut shake = test_shake();
+	shake.node_id = Some(node_id);
+	let vec = ser::ser_vec(&shake).unwrap();
+	let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(shake.node_id, Some(node_id));
+
+	let legacy = ser::ser_vec(&test_shake()).unwrap();
+	let shake: Shake = ser::deserialize(&mut &legacy[..]).unwrap();
+	assert_eq!(shake.node_id, None);
+}
+
+// A full batch of headers goes through, one more is refused both ways.
+// Raw extension area, bypassing the checks on write.
+fn raw_extensions(count: u8, entries: &[(u8, Vec<u8>)]) -> Vec<u8> {
+	let mut vec = vec![count];
+	for (tag, value) in entries {
+		vec.push(*tag);
+		vec.extend_from_slice(&ser::ser_vec(&(value.len() as u16)).unwrap());
+		vec.extend_from_slice(value);
+	}
+	vec
+}
+
+// Extensions we don't know of go through as they are, after the node id.
+#[test]
+fn test_hand_shake_extensions() {
+	let extensions = vec![(200, vec![1, 2, 3]), (201, vec![])];
+	let mut hand = test_hand();
+	hand.node_id = Some(NodeId([7; 16]));
+	hand.extensions = extensions.clone();
+	let vec = ser::ser_vec(&hand).unwrap();
+	let hand: Hand = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(hand.extensions, extensions);
+	assert_eq!(hand.node_id, Some(NodeId([7; 16])));
+
+	for version in vec![ProtocolVersion(2), ProtocolVersion::default()] {
+		let mut shake = test_shake();
+		shake.version = version;
+		shake.height = Some(1_000);
+		shake.node_id = Some(NodeId([7; 16]));
+		shake.extensions = extensions.clone();
+		let vec = ser::ser_vec(&shake).unwrap();
+		let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(shake.extensions, extensions);
+		assert_eq!(shake.height, Some(1_000));
+		assert_eq!(shake.node_id, Some(NodeId([7; 16])));
+	}
+
+	// an empty extension area
+	let mut shake = test_shake();
+	shake.version = ProtocolVersion(2);
+	shake.node_id = Some(NodeId([7; 16]));
+	let mut vec = ser::ser_vec(&shake).unwrap();
+	vec.push(0);
+	let shake: Shake = ser::deserialize(&mut &vec[..]).unwrap();
+	assert!(shake.extensions.is_empty());
+}
+
+// From version 3 the height of the Shake is only sent as an extension, it
+// can't go missing.
+#[test]
+fn test_shake_height_extension() {
+	let mut shake = test_shake();
+	shake.height = Some(1_000);
+	shake.version = ProtocolVersion(2);
+	let legacy = ser::ser_vec(&shake).unwrap();
+	shake.version = ProtocolVersion::default();
+	let vec = ser::ser_vec(&shake).unwrap();
+	// count, tag and length on top of the height
+	assert_eq!(vec.len(), legacy.len() + 1 + 3);
+	assert_eq!(&vec[vec.len() - 8..], &ser::ser_vec(&1_000u64).unwrap()[..]);
+
+	let mut no_height = vec[..vec.len() - 12].to_vec();
+	no_height.extend_from_slice(&raw_extensions(0, &[]));
+	assert!(ser::deserialize::<Shake>(&mut &no_height[..]).is_err());
+
+	// nor be sent on top of ours
+	shake.extensions = vec![(EXT_HEIGHT, vec![0; 8])];
+	assert!(ser::ser_vec(&shake).is_err());
+}
+
+// Duplicate tags, truncated entries and extension areas over the max are
+// rejected.
+#[test]
+fn test_hand_extensions_invalid() {
+	let mut hand = test_hand();
+	hand.node_id = Some(NodeId([7; 16]));
+	let base = v1_hand();
+	let with = |ext: Vec<u8>| {
+		let mut vec = base.clone();
+		vec.extend_from_slice(&ext);
+		ser::deserialize::<Hand>(&mut &vec[..])
+	};
+
+	let dup = raw_extensions(2, &[(200, vec![1]), (200, vec![2])]);
+	assert!(with(dup).is_err());
+	hand.extensions = vec![(200, vec![1]), (200, vec![2])];
+	assert!(ser::ser_vec(&hand).is_err());
+
+	let mut truncated = raw_extensions(1, &[(200, vec![1, 2, 3])]);
+	truncated.pop();
+	assert!(with(truncated).is_err());
+	// announcing more entries than there are
+	assert!(with(raw_extensions(2, &[(200, vec![1])])).is_err());
+
+	let max = MAX_EXTENSIONS_LEN - 1 - 3;
+	assert!(with(raw_extensions(1, &[(200, vec![0; max])])).is_ok());
+	assert!(with(raw_extensions(1, &[(200, vec![0; max + 1])])).is_err());
+	hand.extensions = vec![(200, vec![0; max + 1])];
+	assert!(ser::ser_vec(&hand).is_err());
+}
+
+#[test]
+fn test_headers_count_limit() {
+	let full = Headers {
+		headers: vec![BlockHeader::default(); p2p::MAX_BLOCK_HEADERS as usize],
+	};
+	let vec = ser::ser_vec(&full).unwrap();
+	let headers: Headers = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(headers.headers.len(), p2p::MAX_BLOCK_HEADERS as usize);
+
+	let mut over = full;
+	over.headers.push(BlockHeader::default());
+	assert!(ser::ser_vec(&over).is_err());
+
+	// as much as another peer could send us
+	let mut vec = ser::ser_vec(&(p2p::MAX_BLOCK_HEADERS as u16 + 1)).unwrap();
+	vec.extend_from_slice(&ser::ser_vec(&BlockHeader::default()).unwrap());
+	assert!(ser::deserialize::<Headers>(&mut &vec[..]).is_err());
+
+	let empty = ser::ser_vec(&Headers { headers: vec![] }).unwrap();
+	let headers: Headers = ser::deserialize(&mut &empty[..]).unwrap();
+	assert!(headers.headers.is_empty());
+}
+
+#[test]
+fn test_ban_reason_message() {
+	let msg = BanReason {
+		ban_reason: ReasonForBan::ProtocolViolation,
+		message: Some("é".repeat(MAX_BAN_MESSAGE_LEN)),
+	};
+	let buf = write_to_buf(msg, ProtocolVersion::default(), Type::BanReason).unwrap();
+	let ban: BanReason =
+		read_message(&mut &buf[..], ProtocolVersion::default(), Type::BanReason).unwrap();
+	assert_eq!(ban.ban_reason, ReasonForBan::ProtocolViolation);
+	assert_eq!(ban.message, Some("é".repeat(MAX_BAN_MESSAGE_LEN / 2)));
+
+	// as sent by older peers
+	let vec = ser::ser_vec(&(ReasonForBan::BadBlock as i32)).unwrap();
+	let ban: BanReason = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(ban.ban_reason, ReasonForBan::BadBlock);
+	assert_eq!(ban.message, None);
+
+	let mut vec = ser::ser_vec(&(ReasonForBan::BadBlock as i32)).unwrap();
+	let len = MAX_BAN_MESSAGE_LEN as u64 + 1;
+	vec.extend_from_slice(&ser::ser_vec(&len).unwrap());
+	vec.extend_from_slice(&vec![b'a'; len as usize]);
+	assert!(ser::deserialize::<BanReason>(&mut &vec[..]).is_err());
+}
+
+// A PeerError message is capped, and never carries control characters.
+#[test]
+fn test_peer_error_message() {
+	let err = PeerError::new(PeerErrorCode::BadLength, "é".repeat(MAX_PEER_ERROR_LEN));
+	let buf = write_to_buf(err, ProtocolVersion::default(), Type::PeerError).unwrap();
+	let err: PeerError =
+		read_message(&mut &buf[..], ProtocolVersion::default(), Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::BadLength));
+	assert_eq!(err.message, "é".repeat(MAX_PEER_ERROR_LEN / 2));
+
+	let mut vec = ser::ser_vec(&1000u32).unwrap();
+	vec.extend_from_slice(&ser::ser_vec(&5u64).unwrap());
+	vec.extend_from_slice(b"a\nb\x1bc");
+	let err: PeerError = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(err.code, 1000);
+	assert_eq!(err.code(), None);
+	assert_eq!(err.message, "abc");
+
+	let mut vec = ser::ser_vec(&1u32).unwrap();
+	let len = MAX_PEER_ERROR_LEN as u64 + 1;
+	vec.extend_from_slice(&ser::ser_vec(&len).unwrap());
+	vec.extend_from_slice(&vec![b'a'; len as usize]);
+	assert!(ser::deserialize::<PeerError>(&mut &vec[..]).is_err());
+}
+
+// A locator never has more than MAX_LOCATORS hashes, nor the same one twice.
+#[test]
+fn test_locator_limits() {
+	let hashes: Vec<_> = (0..=p2p::MAX_LOCATORS)
+		.map(|i| Hash::from_vec(&vec![i as u8; 32]))
+		.collect();
+	let full = Locator {
+		hashes: hashes[..p2p::MAX_LOCATORS as usize].to_vec(),
+	};
+	let vec = ser::ser_vec(&full).unwrap();
+	let loc: Locator = ser::deserialize(&mut &vec[..]).unwrap();
+	assert_eq!(loc.hashes, full.hashes);
+
+	let over = Locator {
+		hashes: hashes.clone(),
+	};
+	assert!(ser::ser_vec(&over).is_err());
+	let mut vec = vec![p2p::MAX_LOCATORS as u8 + 1];
+	for h in &hashes {
+		vec.extend_from_slice(h.as_bytes());
+	}
+	assert!(ser::deserialize::<Locator>(&mut &vec[..]).is_err());
+
+	let twice = Locator {
+		hashes: vec![hashes[1], hashes[0], hashes[1]],
+	};
+	let vec = ser::ser_vec(&twice).unwrap();
+	assert!(ser::deserialize::<Locator>(&mut &vec[..]).is_err());
+}
+
+// Large compressible bodies go compressed and read back the same, small ones
+// and blocks are left alone.
+#[test]
+fn test_compressed_round_trip() {
+	let version = ProtocolVersion::default();
+	let headers = Headers {
+		headers: vec![BlockHeader::default(); 100],
+	};
+	let plain = write_to_buf(&headers, version, Type::Headers).unwrap();
+	let buf = write_to_buf_compressed(&headers, version, Type::Headers).unwrap();
+	assert!(buf.len() < plain.len());
+	match read_header(&mut &buf[..], version, None).unwrap() {
+		MsgHeaderWrapper::Known(header) => assert!(header.compressed),
+		MsgHeaderWrapper::Unknown(..) => panic!("expected a known header"),
+	}
+	let read: Headers = read_message(&mut &buf[..], version, Type::Headers).unwrap();
+	assert_eq!(read.headers.len(), 100);
+
+	let ping = Ping {
+		total_difficulty: Difficulty::min(),
+		height: 42,
+		nonce: None,
+	};
+	let buf = write_to_buf_compressed(ping, version, Type::Ping).unwrap();
+	match read_header(&mut &buf[..], version, None).unwrap() {
+		MsgHeaderWrapper::Known(header) => assert!(!header.compressed),
+		MsgHeaderWrapper::Unknown(..) => panic!("expected a known header"),
+	}
+}
+
+// Builds a compressed msg declaring the provided uncompressed length.
+fn compressed_msg(msg_type: Type, declared: u64, body: &[u8]) -> Vec<u8> {
+	let mut packed = ser::ser_vec(&declared).unwrap();
+	let mut encoder = DeflateEncoder::new(&mut packed, Compression::best());
+	encoder.write_all(body).unwrap();
+	encoder.finish().unwrap();
+	let mut header = MsgHeader::for_body(ProtocolVersion::default(), msg_type, &packed);
+	header.compressed = true;
+	let mut buf = ser::ser_vec(&header).unwrap();
+	buf.extend_from_slice(&packed);
+	buf
+}
+
+// A compressed body can't declare more than the max of its msg type, nor
+// inflate past what it declared.
+#[test]
+fn test_compressed_bomb() {
+	let version = ProtocolVersion::default();
+	let zeros = vec![0u8; 10_000_000];
+
+	let buf = compressed_msg(Type::PeerAddrs, zeros.len() as u64, &zeros);
+	match read_message::<PeerAddrs>(&mut &buf[..], version, Type::PeerAddrs) {
+		Err(p2p::Error::MsgLen) => {}
+		r => panic!("expected a msg len error, got {:?}", r.map(|_| ())),
+	}
+
+	let buf = compressed_msg(Type::PeerAddrs, 100, &zeros);
+	match read_message::<PeerAddrs>(&mut &buf[..], version, Type::PeerAddrs) {
+		Err(p2p::Error::Serialization(ser::Error::CorruptedData)) => {}
+		r => panic!("expected corrupted data, got {:?}", r.map(|_| ())),
+	}
+
+	// streamed msgs are never compressed
+	let buf = compressed_msg(Type::Block, 100, &zeros[..100]);
+	match read_header(&mut &buf[..], version, None) {
+		Err(p2p::Error::BadMessage) => {}
+		r => panic!("expected a bad msg, got {:?}", r.map(|_| ())),
+	}
+}
@@ -0,0 +1,322 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use grin_core as core;
+use grin_p2p as p2p;
+
+use grin_util as util;
+use grin_util::StopState;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use crate::core::core::hash::Hash;
+use crate::p2p::dialer::{Dialer, Socks5, Socks5Auth};
+use crate::p2p::PeerAddr;
+
+const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// A minimal SOCKS5 proxy, only supporting CONNECT. Host names are looked up
+// in `hosts`, every requested host is recorded.
+struct MockProxy {
+	addr: SocketAddr,
+	requested: Arc<Mutex<Vec<String>>>,
+}
+
+fn mock_proxy(
+	auth: Option<(&'static str, &'static str)>,
+	hosts: Vec<(&str, SocketAddr)>,
+) -> MockProxy {
+	let hosts: HashMap<String, SocketAddr> =
+		hosts.into_iter().map(|(h, a)| (h.to_string(), a)).collect();
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let requested = Arc::new(Mutex::new(vec![]));
+	let requested_c = requested.clone();
+	let _ = thread::spawn(move || {
+		for conn in listener.incoming() {
+			let conn = conn.unwrap();
+			let hosts = hosts.clone();
+			let requested = requested_c.clone();
+			let _ = thread::spawn(move || {
+				let _ = proxy_conn(conn, auth, &hosts, &requested);
+			});
+		}
+	});
+	MockProxy { addr, requested }
+}
+
+fn read_bytes(conn: &mut TcpStream, len: usize) -> io::Result<Vec<u8>> {
+	let mut buf = vec![0u8; len];
+	conn.read_exact(&mut buf)?;
+	Ok(buf)
+}
+
+fn proxy_conn(
+	mut conn: TcpStream,
+	auth: Option<(&str, &str)>,
+	hosts: &HashMap<String, SocketAddr>,
+	requested: &Mutex<Vec<String>>,
+) -> io::Result<()> {
+	let head = read_bytes(&mut conn, 2)?;
+	assert_eq!(head[0], 5);
+	let methods = read_bytes(&mut conn, head[1] as usize)?;
+
+	match auth {
+		Some((user, pass)) => {
+			if !methods.contains(&2) {
+				return conn.write_all(&[5, 0xff]);
+			}
+			conn.write_all(&[5, 2])?;
+			let head = read_bytes(&mut conn, 2)?;
+			let u = read_bytes(&mut conn, head[1] as usize)?;
+			let len = read_bytes(&mut conn, 1)?;
+			let p = read_bytes(&mut conn, len[0] as usize)?;
+			if u != user.as_bytes() || p != pass.as_bytes() {
+				return conn.write_all(&[1, 1]);
+			}
+			conn.write_all(&[1, 0])?;
+		}
+		None => conn.write_all(&[5, 0])?,
+	}
+
+	let req = read_bytes(&mut conn, 4)?;
+	assert_eq!(&req[..3], &[5, 1, 0]);
+	let host = match req[3] {
+		1 => {
+			let ip = read_bytes(&mut conn, 4)?;
+			Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string()
+		}
+		4 => {
+			let mut ip = [0u8; 16];
+			conn.read_exact(&mut ip)?;
+			Ipv6Addr::from(ip).to_string()
+		}
+		_ => {
+			let len = read_bytes(&mut conn, 1)?;
+			String::from_utf8(read_bytes(&mut conn, len[0] as usize)?).unwrap()
+		}
+	};
+	let port = read_bytes(&mut conn, 2)?;
+	let port = u16::from_be_bytes([port[0], port[1]]);
+	requested.lock().unwrap().push(host.clone());
+
+	let target = match host.parse() {
+		Ok(ip) => Some(SocketAddr::new(ip, port)),
+		Err(_) => hosts.get(&host).cloned(),
+	};
+	let upstream = match target.map(|t| TcpStream::connect_timeout(&t, TIMEOUT)) {
+		Some(Ok(upstream)) => upstream,
+		// host unreachable
+		_ => return conn.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]),
+	};
+	conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+
+	let mut down = conn.try_clone()?;
+	let mut up = upstream.try_clone()?;
+	let mut conn = conn;
+	let mut upstream = upstream;
+	let _ = thread::spawn(move || io::copy(&mut up, &mut down));
+	io::copy(&mut conn, &mut upstream)?;
+	Ok(())
+}
+
+// Echoes back whatever it receives.
+fn echo_server() -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _ = thread::spawn(move || {
+		for conn in listener.incoming() {
+			let mut conn = conn.unwrap();
+			let _ = thread::spawn(move || {
+				let mut out = conn.try_clone().unwrap();
+				let _ = io::copy(&mut conn, &mut out);
+			});
+		}
+	});
+	addr
+}
+
+fn check_echo(conn: &mut TcpStream) {
+	conn.write_all(b"hello").unwrap();
+	let mut buf = [0u8; 5];
+	conn.read_exact(&mut buf).unwrap();
+	assert_eq!(&buf, b"hello");
+}
+
+// Onion and DNS names are resolved by the proxy, not by us.
+#[test]
+fn socks5_connect_hostname() {
+	util::init_test_logger();
+
+	let echo = echo_server();
+	let proxy = mock_proxy(None, vec![("abcdef.onion", echo), ("peer.example", echo)]);
+	let dialer = Socks5 {
+		proxy_addr: proxy.addr,
+		auth: None,
+	};
+
+	let onion = PeerAddr::Onion("abcdef.onion".to_string(), echo.port());
+	let (mut conn, addr) = dialer.dial(&onion, TIMEOUT).unwrap();
+	assert_eq!(addr, onion);
+	check_echo(&mut conn);
+
+	let dns = PeerAddr::Dns("peer.example".to_string(), echo.port());
+	let (mut conn, addr) = dialer.dial(&dns, TIMEOUT).unwrap();
+	assert_eq!(addr, dns);
+	check_echo(&mut conn);
+
+	let ip = PeerAddr::Ip(echo);
+	let (mut conn, addr) = dialer.dial(&ip, TIMEOUT).unwrap();
+	assert_eq!(addr, ip);
+	check_echo(&mut conn);
+
+	assert_eq!(
+		*proxy.requested.lock().unwrap(),
+		vec!["abcdef.onion", "peer.example", "127.0.0.1"]
+	);
+}
+
+#[test]
+fn socks5_auth() {
+	util::init_test_logger();
+
+	let echo = echo_server();
+	let proxy = mock_proxy(Some(("grin", "secret")), vec![]);
+	let auth = |password: &str| Socks5Auth {
+		username: "grin".to_string(),
+		password: password.to_string(),
+	};
+
+	let dialer = Socks5 {
+		proxy_addr: proxy.addr,
+		auth: Some(auth("secret")),
+	};
+	let (mut conn, _) = dialer.dial(&PeerAddr::Ip(echo), TIMEOUT).unwrap();
+	check_echo(&mut conn);
+
+	let dialer = Socks5 {
+		proxy_addr: proxy.addr,
+		auth: Some(auth("wrong")),
+	};
+	match dialer.dial(&PeerAddr::Ip(echo), TIMEOUT) {
+		Err(p2p::Error::Proxy(_)) => {}
+		res => panic!("expected proxy error, got {:?}", res.map(|(_, a)| a)),
+	}
+
+	// no credentials at all, the proxy doesn't accept any of our methods
+	let dialer = Socks5 {
+		proxy_addr: proxy.addr,
+		auth: None,
+	};
+	match dialer.dial(&PeerAddr::Ip(echo), TIMEOUT) {
+		Err(p2p::Error::Proxy(_)) => {}
+		res => panic!("expected proxy error, got {:?}", res.map(|(_, a)| a)),
+	}
+}
+
+// The proxy failing is our problem, the proxy not reaching the peer is the
+// peer's.
+#[test]
+fn socks5_errors() {
+	util::init_test_logger();
+
+	let proxy = mock_proxy(None, vec![]);
+	let dialer = Socks5 {
+		proxy_addr: proxy.addr,
+		auth: None,
+	};
+	let unknown = PeerAddr::Onion("unknown.onion".to_string(), 3414);
+	match dialer.dial(&unknown, TIMEOUT) {
+		Err(p2p::Error::Connection(_)) => {}
+		res => panic!("expected connection error, got {:?}", res.map(|(_, a)| a)),
+	}
+
+	// nothing listening where the proxy should be
+	let closed = TcpListener::bind("127.0.0.1:0")
+		.unwrap()
+		.local_addr()
+		.unwrap();
+	let dialer = Socks5 {
+		proxy_addr: closed,
+		auth: None,
+	};
+	let err = dialer.dial(&unknown, TIMEOUT).err().unwrap();
+	match err {
+		p2p::Error::Proxy(_) => {}
+		e => panic!("expected proxy error, got {:?}", e),
+	}
+	assert_eq!(err.retry_policy(), p2p::types::RetryPolicy::RetrySoon);
+}
+
+// A server configured with a proxy connects and handshakes through it.
+#[test]
+fn socks5_server_connect() {
+	util::init_test_logger();
+
+	let open_port = || {
+		TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port()
+	};
+	let start_server = |db_root: &str, socks5_proxy: Option<Socks5>| {
+		let config = p2p::P2PConfig {
+			host: "127.0.0.1".parse().unwrap(),
+			port: open_port(),
+			socks5_proxy,
+			..p2p::P2PConfig::default()
+		};
+		let server = Arc::new(
+			p2p::Server::new(
+				db_root,
+				p2p::Capabilities::UNKNOWN,
+				config,
+				Arc::new(p2p::DummyAdapter {}),
+				Hash::from_vec(&vec![]),
+				Arc::new(StopState::new()),
+			)
+			.unwrap(),
+		);
+		let server_inner = server.clone();
+		let _ = thread::spawn(move || server_inner.listen());
+		server
+	};
+
+	let a = start_server(".grin_socks5_a", None);
+	let a_sock = SocketAddr::new(a.config.host, a.config.port);
+	let proxy = mock_proxy(None, vec![("node-a.onion", a_sock)]);
+	let b = start_server(
+		".grin_socks5_b",
+		Some(Socks5 {
+			proxy_addr: proxy.addr,
+			auth: None,
+		}),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let onion = PeerAddr::Onion("node-a.onion".to_string(), a.config.port);
+	let peer = b.connect(onion.clone()).unwrap();
+	assert_eq!(peer.info.addr, onion);
+	assert_eq!(*proxy.requested.lock().unwrap(), vec!["node-a.onion"]);
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(b.peers.peer_count(), 1);
+
+	a.stop();
+	b.stop();
+}
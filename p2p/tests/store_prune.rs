@@ -0,0 +1,148 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_p2p as p2p;
+use grin_util as util;
+
+use chrono::prelude::Utc;
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::p2p::types::PeerAddr;
+
+fn addr(i: u8) -> PeerAddr {
+	PeerAddr::Ip(format!("10.3.0.{}:3414", i).parse().unwrap())
+}
+
+fn stored(i: u8, flags: p2p::State, failures: u32, last_seen: i64) -> p2p::PeerData {
+	p2p::PeerData {
+		addr: addr(i),
+		capabilities: p2p::Capabilities::UNKNOWN,
+		user_agent: "".to_string(),
+		flags,
+		last_banned: 0,
+		ban_reason: p2p::ReasonForBan::None,
+		last_connected: 0,
+		last_error: None,
+		last_attempted: 0,
+		failures,
+		banned_until: 0,
+		last_seen,
+		last_direction: None,
+	}
+}
+
+// the peers left in the store, by the last byte of their address
+fn left(server: &p2p::Server) -> HashSet<u8> {
+	server
+		.peers
+		.all_peers()
+		.iter()
+		.map(|p| match p.addr.ip_addr().unwrap().ip() {
+			IpAddr::V4(ip) => ip.octets()[3],
+			IpAddr::V6(_) => unreachable!(),
+		})
+		.collect()
+}
+
+// Past its maximum our store loses the banned peers whose ban is over first,
+// then the ones we only heard about, then the defunct ones, a few at a time.
+// The peers we prefer, anchor to or recently connected to stay, as do the
+// healthy ones.
+#[test]
+fn store_pruned_worst_first() {
+	util::init_test_logger();
+
+	let db_root = ".grin_store_prune";
+	let _ = fs::remove_dir_all(db_root);
+	let config = p2p::P2PConfig {
+		peer_store_max: Some(6),
+		peers_preferred: Some(vec![addr(11)]),
+		..p2p::P2PConfig::default()
+	};
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, _) = start_node_with(db_root, p2p::Capabilities::FULL_NODE, adapter, config);
+
+	let now = Utc::now().timestamp();
+	let mut peers = vec![
+		p2p::PeerData {
+			banned_until: now - 10,
+			..stored(1, p2p::State::Banned, 0, now)
+		},
+		p2p::PeerData {
+			banned_until: now - 20,
+			..stored(2, p2p::State::Banned, 0, now)
+		},
+		// still banned
+		p2p::PeerData {
+			banned_until: now + 3600,
+			..stored(20, p2p::State::Banned, 0, now)
+		},
+		stored(3, p2p::State::Unverified, 0, now - 400),
+		stored(4, p2p::State::Unverified, 0, now - 100),
+		stored(5, p2p::State::Unverified, 0, now - 300),
+		stored(6, p2p::State::Unverified, 0, now - 200),
+		stored(7, p2p::State::Defunct, 1, now),
+		stored(8, p2p::State::Defunct, 9, now),
+		stored(9, p2p::State::Defunct, 5, now),
+		stored(10, p2p::State::Defunct, 2, now),
+		// preferred, anchor and recently connected, the worst otherwise
+		stored(11, p2p::State::Defunct, 99, now),
+		stored(12, p2p::State::Defunct, 99, now),
+		p2p::PeerData {
+			last_connected: now - 3600,
+			last_direction: Some(p2p::Direction::Outbound),
+			..stored(13, p2p::State::Defunct, 99, now)
+		},
+		// connected to before we saved in which direction
+		p2p::PeerData {
+			last_connected: now - 3600,
+			..stored(19, p2p::State::Defunct, 99, now)
+		},
+	];
+	for i in 14..18 {
+		peers.push(stored(i, p2p::State::Healthy, 0, now));
+	}
+	for p in &peers {
+		server.peers.save_peer(p).unwrap();
+	}
+	let anchors = vec![addr(12)];
+
+	let mut expected = left(&server);
+	assert_eq!(expected.len(), peers.len());
+	assert_eq!(server.peers.prune_store(4, &anchors), 4);
+	for i in &[1, 2, 3, 5] {
+		expected.remove(i);
+	}
+	assert_eq!(left(&server), expected);
+
+	assert_eq!(server.peers.prune_store(4, &anchors), 4);
+	for i in &[6, 4, 8, 9] {
+		expected.remove(i);
+	}
+	assert_eq!(left(&server), expected);
+
+	// nothing left that may go, still over
+	assert_eq!(server.peers.prune_store(4, &anchors), 2);
+	assert_eq!(server.peers.prune_store(4, &anchors), 0);
+	let kept = [11, 12, 13, 14, 15, 16, 17, 19, 20];
+	assert_eq!(left(&server), kept.iter().cloned().collect());
+
+	server.stop();
+}
@@ -0,0 +1,144 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::fs;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::pow::Difficulty;
+use crate::p2p::msg::{write_message, GetPeerAddrs, Ping, Pong, ProtocolVersion, Type};
+use crate::p2p::types::PeerAddr;
+
+fn archival() -> p2p::Capabilities {
+	p2p::Capabilities::FULL_NODE | p2p::Capabilities::FULL_HIST
+}
+
+// A raw peer on the provided port, ahead of us.
+fn ahead(addr: &PeerAddr, capab: p2p::Capabilities, port: u16) -> (TcpStream, ProtocolVersion) {
+	let (mut conn, version) = connect_raw_from(addr, capab, port);
+	let ping = Ping {
+		total_difficulty: Difficulty::from_num(1000),
+		height: 10,
+		nonce: None,
+	};
+	write_message(&mut conn, ping, version, Type::Ping).unwrap();
+	let _: Pong = read_until(&mut conn, version, Type::Pong).unwrap();
+	(conn, version)
+}
+
+fn local(port: u16) -> PeerAddr {
+	PeerAddr::Ip(format!("127.0.0.1:{}", port).parse().unwrap())
+}
+
+// Deep sync only ever goes to archival peers. With none of them connected we
+// dial the ones we know of first and ask our peers for more.
+#[test]
+fn sync_capab_full_hist() {
+	util::init_test_logger();
+	let _ = fs::remove_dir_all(".grin_sync_capab");
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(".grin_sync_capab", p2p::Capabilities::FULL_NODE, adapter);
+	thread::sleep(time::Duration::from_secs(1));
+
+	let mut pruned = vec![];
+	for port in 5001..5004 {
+		pruned.push(ahead(&addr, p2p::Capabilities::FULL_NODE, port));
+	}
+	let archival_conn = ahead(&addr, archival(), 5004);
+	thread::sleep(time::Duration::from_millis(200));
+
+	let peers = &server.peers;
+	let behind = Difficulty::min();
+	assert_eq!(
+		peers
+			.more_work_peers(behind, p2p::Capabilities::UNKNOWN)
+			.len(),
+		4
+	);
+	for _ in 0..20 {
+		let deep = peers.more_work_peers(behind, p2p::Capabilities::FULL_HIST);
+		assert_eq!(deep.len(), 1);
+		assert_eq!(deep[0].info.addr, local(5004));
+		let best = peers.most_work_peer(p2p::Capabilities::FULL_HIST).unwrap();
+		assert_eq!(best.info.addr, local(5004));
+	}
+	assert!(peers.wanted_capabilities().is_empty());
+
+	// the archival peer is gone
+	drop(archival_conn);
+	thread::sleep(time::Duration::from_millis(500));
+	peers.clean_peers(8);
+	assert!(peers
+		.more_work_peers(behind, p2p::Capabilities::FULL_HIST)
+		.is_empty());
+	assert!(peers.most_work_peer(p2p::Capabilities::FULL_HIST).is_none());
+	assert_eq!(peers.wanted_capabilities(), p2p::Capabilities::FULL_HIST);
+
+	// the archival peers we know of are dialed first
+	for i in 1..5 {
+		let mut peer = p2p::PeerData {
+			addr: PeerAddr::Ip(format!("1.2.3.{}:3414", i).parse().unwrap()),
+			capabilities: p2p::Capabilities::FULL_NODE,
+			user_agent: "test".to_string(),
+			flags: p2p::State::Healthy,
+			last_banned: 0,
+			ban_reason: p2p::ReasonForBan::None,
+			last_connected: 0,
+			last_error: None,
+			last_attempted: 0,
+			failures: 0,
+			banned_until: 0,
+			last_seen: 0,
+			last_direction: None,
+		};
+		if i == 3 {
+			peer.capabilities = archival();
+		}
+		peers.save_peer(&peer).unwrap();
+	}
+	// the one that just left too
+	let candidates = peers.dial_candidates(2);
+	assert_eq!(candidates.len(), 2);
+	assert!(candidates.contains(&local(5004)));
+	assert!(candidates.contains(&PeerAddr::Ip("1.2.3.3:3414".parse().unwrap())));
+
+	// and our peers asked for more
+	p2p::Server::maintain_outbound(&server);
+	for (conn, version) in pruned.iter_mut() {
+		let req: GetPeerAddrs = read_until(conn, *version, Type::GetPeerAddrs).unwrap();
+		assert_eq!(req.capabilities, p2p::Capabilities::FULL_HIST);
+	}
+
+	// until one is with us again
+	let _archival_conn = ahead(&addr, archival(), 5005);
+	thread::sleep(time::Duration::from_millis(200));
+	assert_eq!(
+		peers
+			.more_work_peers(behind, p2p::Capabilities::FULL_HIST)
+			.len(),
+		1
+	);
+	assert!(peers.wanted_capabilities().is_empty());
+
+	server.stop();
+}
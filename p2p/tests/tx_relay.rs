@@ -0,0 +1,112 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hashed;
+use crate::core::core::{Transaction, TxKernel};
+
+// On a line of nodes a tx is announced by its kernel hash at each hop and
+// only sent in full when asked for, peers without kernel hashes get the full
+// tx directly. A tx is never announced twice to the same peer.
+#[test]
+fn tx_kernel_first_relay() {
+	util::init_test_logger();
+
+	let tx = Transaction::empty().with_kernel(TxKernel {
+		fee: 2,
+		..TxKernel::empty()
+	});
+	let h = tx.kernels()[0].hash();
+
+	let a = Arc::new(PoolAdapter::new(vec![tx.clone()], None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let c = Arc::new(PoolAdapter::new(vec![], None));
+	let legacy = Arc::new(PoolAdapter::new(vec![], None));
+	let full = p2p::Capabilities::FULL_NODE;
+	let (a_server, _) = start_node(".grin_tx_relay_a", full, a.clone());
+	let (b_server, b_addr) = start_node(".grin_tx_relay_b", full, b.clone());
+	let (_c, c_addr) = start_node(".grin_tx_relay_c", full, c.clone());
+	let (_d, d_addr) = start_node(
+		".grin_tx_relay_d",
+		p2p::Capabilities::HEADER_HIST | p2p::Capabilities::PEER_LIST,
+		legacy.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr).unwrap();
+	b_server.connect(c_addr).unwrap();
+	b_server.connect(d_addr).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	a_server.peers.broadcast_transaction(&tx, None);
+	thread::sleep(time::Duration::from_secs(2));
+
+	let announced = vec![Received::TxKernel(h), Received::Transaction(h)];
+	assert_eq!(*b.received.lock(), announced);
+	assert_eq!(*c.received.lock(), announced);
+	assert_eq!(*legacy.received.lock(), vec![Received::Transaction(h)]);
+	// nothing came back the way it came
+	assert!(a.received.lock().is_empty());
+
+	// the middle node relayed the tx once already
+	b_server.peers.broadcast_transaction(&tx, None);
+	thread::sleep(time::Duration::from_secs(1));
+	assert_eq!(*c.received.lock(), announced);
+	assert_eq!(*legacy.received.lock(), vec![Received::Transaction(h)]);
+	assert!(a.received.lock().is_empty());
+}
+
+// Many txs relayed at once, in full as kernel hashes would get them asked
+// for faster than served, each one only goes one way.
+#[test]
+fn tx_relay_no_echo() {
+	util::init_test_logger();
+
+	let txs: Vec<_> = (1..101)
+		.map(|fee| {
+			Transaction::empty().with_kernel(TxKernel {
+				fee,
+				..TxKernel::empty()
+			})
+		})
+		.collect();
+	let a = Arc::new(PoolAdapter::new(txs.clone(), None));
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let capab = p2p::Capabilities::HEADER_HIST | p2p::Capabilities::PEER_LIST;
+	let (a_server, _) = start_node(".grin_tx_no_echo_a", capab, a.clone());
+	let (_b_server, b_addr) = start_node(".grin_tx_no_echo_b", capab, b.clone());
+	thread::sleep(time::Duration::from_secs(1));
+	a_server.connect(b_addr).unwrap();
+	thread::sleep(time::Duration::from_secs(1));
+
+	for tx in &txs {
+		a_server.peers.broadcast_transaction(tx, None);
+	}
+	thread::sleep(time::Duration::from_secs(3));
+
+	let received = b.received.lock();
+	for tx in &txs {
+		let h = tx.kernels()[0].hash();
+		assert!(received.contains(&Received::Transaction(h)));
+	}
+	assert!(a.received.lock().is_empty());
+}
@@ -0,0 +1,87 @@
+// Copyright 2018 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod common;
+
+use grin_core as core;
+use grin_p2p as p2p;
+use grin_util as util;
+
+use std::sync::Arc;
+use std::{thread, time};
+
+use crate::common::*;
+use crate::core::core::hash::Hash;
+use crate::p2p::msg::{
+	write_message, PeerError, PeerErrorCode, TxHashSetArchive, Type, MAX_TXHASHSET_ARCHIVE_SIZE,
+};
+
+// A txhashset archive streams from a node keeping it to the node asking for
+// it, which gets it whole from the temp file it was written to.
+#[test]
+fn txhashset_archive_transfer() {
+	util::init_test_logger();
+
+	// several chunks worth
+	let archive: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+	let a = Arc::new(PoolAdapter::new(vec![], None));
+	*a.txhashset.write() = Some(archive.clone());
+	let (_a_server, a_addr) = start_node(".grin_txhashset_a", p2p::Capabilities::FULL_NODE, a);
+	let b = Arc::new(PoolAdapter::new(vec![], None));
+	let (b_server, _) = start_node(".grin_txhashset_b", p2p::Capabilities::FULL_NODE, b.clone());
+	thread::sleep(time::Duration::from_secs(1));
+
+	let peer = b_server.connect(a_addr).unwrap();
+	assert!(peer
+		.info
+		.capabilities
+		.contains(p2p::Capabilities::TXHASHSET_HIST));
+	let hash = Hash::from_vec(&vec![7; 32]);
+	peer.send_txhashset_request(12, hash).unwrap();
+	for _ in 0..50 {
+		if !b.txhashsets.lock().is_empty() {
+			break;
+		}
+		thread::sleep(time::Duration::from_millis(100));
+	}
+	assert_eq!(*b.txhashsets.lock(), vec![(hash, archive)]);
+}
+
+// An archive announcing more than we'd ever download is refused from its
+// size alone.
+#[test]
+fn txhashset_archive_over_max_size() {
+	util::init_test_logger();
+
+	let adapter = Arc::new(PoolAdapter::new(vec![], None));
+	let (server, addr) = start_node(
+		".grin_txhashset_max",
+		p2p::Capabilities::FULL_NODE,
+		adapter.clone(),
+	);
+	thread::sleep(time::Duration::from_secs(1));
+	let (mut conn, version) = connect_raw(&addr);
+
+	let archive = TxHashSetArchive {
+		hash: Hash::from_vec(&vec![7; 32]),
+		height: 12,
+		bytes: MAX_TXHASHSET_ARCHIVE_SIZE + 1,
+	};
+	write_message(&mut conn, &archive, version, Type::TxHashSetArchive).unwrap();
+	let err: PeerError = read_until(&mut conn, version, Type::PeerError).unwrap();
+	assert_eq!(err.code(), Some(PeerErrorCode::BadLength));
+	assert!(adapter.txhashsets.lock().is_empty());
+
+	server.stop();
+}
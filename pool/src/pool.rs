@@ -81,38 +81,50 @@ impl Pool {
 	/// from the provided compact_block.
 	/// Note: does not validate that we return the full set of required txs.
 	/// The caller will need to validate that themselves.
+	/// Short_ids matching more than one of our txs are returned as missing.
 	pub fn retrieve_transactions(
 		&self,
 		hash: Hash,
 		nonce: u64,
 		kern_ids: &[ShortId],
 	) -> (Vec<Transaction>, Vec<ShortId>) {
-		let mut txs = vec![];
-		let mut found_ids = vec![];
+		let mut found: HashMap<ShortId, &Transaction> = HashMap::new();
+		let mut collisions = HashSet::new();
 
 		// Rehash all entries in the pool using short_ids based on provided hash and nonce.
-		'outer: for x in &self.entries {
+		// We can't stop at the first match, a short_id matching kernels of more than
+		// one tx is a collision and we can't tell which tx is in the block.
+		for x in &self.entries {
 			for k in x.tx.kernels() {
 				// rehash each kernel to calculate the block specific short_id
 				let short_id = k.short_id(&hash, nonce);
-				if kern_ids.contains(&short_id) {
-					txs.push(x.tx.clone());
-					found_ids.push(short_id);
+				if !kern_ids.contains(&short_id) {
+					continue;
+				}
+				match found.get(&short_id) {
+					Some(tx) if **tx != x.tx => {
+						collisions.insert(short_id);
+					}
+					_ => {
+						found.insert(short_id, &x.tx);
+					}
 				}
-				if found_ids.len() == kern_ids.len() {
-					break 'outer;
+			}
+		}
+
+		let mut txs: Vec<Transaction> = vec![];
+		let mut missing = vec![];
+		for id in kern_ids {
+			match found.get(id) {
+				Some(tx) if !collisions.contains(id) => {
+					if !txs.contains(*tx) {
+						txs.push((*tx).clone());
+					}
 				}
+				_ => missing.push(id.clone()),
 			}
 		}
-		txs.dedup();
-		(
-			txs,
-			kern_ids
-				.into_iter()
-				.filter(|id| !found_ids.contains(id))
-				.cloned()
-				.collect(),
-		)
+		(txs, missing)
 	}
 
 	/// Take pool transactions, filtering and ordering them in a way that's
@@ -157,7 +157,8 @@ pub struct PoolEntry {
 pub struct TxSource {
 	/// Human-readable name used for logging and errors.
 	pub debug_name: String,
-	/// Unique identifier used to distinguish this peer from others.
+	/// Unique identifier used to distinguish this peer from others, the
+	/// address of the peer for the txs relayed to us.
 	pub identifier: String,
 }
 
@@ -249,8 +250,9 @@ pub trait BlockChain: Sync + Send {
 /// downstream processing of valid transactions by the rest of the system, most
 /// importantly the broadcasting of transactions to our peers.
 pub trait PoolAdapter: Send + Sync {
-	/// The transaction pool has accepted this transaction as valid.
-	fn tx_accepted(&self, tx: &transaction::Transaction);
+	/// The transaction pool has accepted this transaction as valid, from the
+	/// source of the entry.
+	fn tx_accepted(&self, entry: &PoolEntry);
 
 	/// The stem transaction pool has accepted this transactions as valid.
 	fn stem_tx_accepted(&self, tx: &transaction::Transaction) -> Result<(), PoolError>;
@@ -261,7 +263,7 @@ pub trait PoolAdapter: Send + Sync {
 pub struct NoopAdapter {}
 
 impl PoolAdapter for NoopAdapter {
-	fn tx_accepted(&self, _tx: &transaction::Transaction) {}
+	fn tx_accepted(&self, _entry: &PoolEntry) {}
 	fn stem_tx_accepted(&self, _tx: &transaction::Transaction) -> Result<(), PoolError> {
 		Ok(())
 	}
@@ -26,7 +26,7 @@ use std::time::Instant;
 use crate::chain::{self, BlockStatus, ChainAdapter, Options};
 use crate::common::hooks::{ChainEvents, NetEvents};
 use crate::common::types::{
-	self, ChainValidationMode, DandelionEpoch, ServerConfig, SyncState, SyncStatus,
+	self, BlockOrigins, ChainValidationMode, DandelionEpoch, ServerConfig, SyncState, SyncStatus,
 };
 use crate::core::core::hash::{Hash, Hashed};
 use crate::core::core::transaction::Transaction;
@@ -34,6 +34,7 @@ use crate::core::core::verifier_cache::VerifierCache;
 use crate::core::core::{BlockHeader, BlockSums, CompactBlock};
 use crate::core::pow::Difficulty;
 use crate::core::{core, global};
+use crate::grin::sync::{Batch, HeaderPipeline};
 use crate::p2p;
 use crate::p2p::types::PeerInfo;
 use crate::pool;
@@ -48,6 +49,8 @@ use rand::prelude::*;
 /// implementations.
 pub struct NetToChainAdapter {
 	sync_state: Arc<SyncState>,
+	header_pipeline: Arc<HeaderPipeline>,
+	block_origins: Arc<BlockOrigins>,
 	chain: Weak<chain::Chain>,
 	tx_pool: Arc<RwLock<pool::TransactionPool>>,
 	verifier_cache: Arc<RwLock<dyn VerifierCache>>,
@@ -87,10 +90,29 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 		Ok(true)
 	}
 
+	fn block_inv_received(
+		&self,
+		hash: Hash,
+		height: u64,
+		peer_info: &PeerInfo,
+	) -> Result<bool, chain::Error> {
+		debug!(
+			"Received block inv {} at {} from {}",
+			hash, height, peer_info.addr
+		);
+		// blocks come through sync until we're caught up
+		if self.sync_state.is_syncing() {
+			return Ok(true);
+		}
+		self.request_block_by_hash(hash, peer_info);
+		Ok(true)
+	}
+
 	fn transaction_received(
 		&self,
 		tx: core::Transaction,
 		stem: bool,
+		peer_info: &PeerInfo,
 	) -> Result<bool, chain::Error> {
 		// nothing much we can do with a new transaction while syncing
 		if self.sync_state.is_syncing() {
@@ -99,7 +121,7 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 
 		let source = pool::TxSource {
 			debug_name: "p2p".to_string(),
-			identifier: "?.?.?.?".to_string(),
+			identifier: peer_info.addr.to_string(),
 		};
 
 		let header = self.chain().head_header()?;
@@ -154,6 +176,13 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 			cb.kern_ids().len(),
 		);
 
+		// the full block may have made it here before its compact form (or
+		// the other way around), only one of them goes through the pipeline
+		if let Ok(true) = self.chain().block_exists(bhash) {
+			debug!("compact block {} already known, skipping it", bhash);
+			return Ok(true);
+		}
+
 		let cb_hash = cb.hash();
 		if cb.kern_ids().is_empty() {
 			// push the freshly hydrated block through the chain pipeline
@@ -178,6 +207,10 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 				.process_block_header(&cb.header, self.chain_opts(false))
 			{
 				debug!("Invalid compact block header {}: {:?}", cb_hash, e.kind());
+				if e.is_borderline() {
+					self.report_borderline(peer_info);
+					return Ok(true);
+				}
 				return Ok(!e.is_bad_data());
 			}
 
@@ -193,10 +226,12 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 				missing_short_ids.len(),
 			);
 
-			// TODO - 3 scenarios here -
-			// 1) we hydrate a valid block (good to go)
-			// 2) we hydrate an invalid block (txs legit missing from our pool)
-			// 3) we hydrate an invalid block (peer sent us a "bad" compact block) - [TBD]
+			// missing from our pool or matching more than one of our txs, no
+			// point hydrating anything
+			if !missing_short_ids.is_empty() {
+				self.request_full_block(&cb.header, peer_info);
+				return Ok(true);
+			}
 
 			let block = match core::Block::hydrate_from(cb.clone(), txs) {
 				Ok(block) => {
@@ -208,8 +243,11 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 					block
 				}
 				Err(e) => {
+					// one of our txs may collide with a kernel of the block,
+					// which isn't the peer's fault
 					debug!("Invalid hydrated block {}: {:?}", cb.hash(), e);
-					return Ok(false);
+					self.request_full_block(&cb.header, peer_info);
+					return Ok(true);
 				}
 			};
 
@@ -221,14 +259,9 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 					debug!("successfully hydrated block from tx pool!");
 					self.process_block(block, peer_info, false)
 				} else {
-					if self.sync_state.status() == SyncStatus::NoSync {
-						debug!("adapter: block invalid after hydration, requesting full block");
-						self.request_block(&cb.header, peer_info);
-						Ok(true)
-					} else {
-						debug!("block invalid after hydration, ignoring it, cause still syncing");
-						Ok(true)
-					}
+					debug!("adapter: block invalid after hydration");
+					self.request_full_block(&cb.header, peer_info);
+					Ok(true)
 				}
 			} else {
 				debug!("failed to retrieve previous block header (still syncing?)");
@@ -284,6 +317,20 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 			peer_info.addr
 		);
 
+		// answers to our sync requests are validated by the sync thread, the
+		// next batch asked for first, a bad batch getting its peer banned
+		// there (see `HeaderSync::apply_batches`)
+		match self.header_pipeline.received(bhs, &peer_info.addr) {
+			Batch::NotOurs => (),
+			Batch::Cancelled | Batch::Queued => return Ok(true),
+			Batch::AskNext(locator) => {
+				if let Some(peer) = self.peers().get_connected_peer(peer_info.addr.clone()) {
+					let _ = peer.send_header_request(locator);
+				}
+				return Ok(true);
+			}
+		}
+
 		if bhs.len() == 0 {
 			return Ok(false);
 		}
@@ -305,7 +352,7 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 	fn locate_headers(&self, locator: &[Hash]) -> Result<Vec<core::BlockHeader>, chain::Error> {
 		debug!("locator: {:?}", locator);
 
-		let header = match self.find_common_header(locator) {
+		let header = match self.find_common_header(locator)? {
 			Some(header) => header,
 			None => return Ok(vec![]),
 		};
@@ -345,6 +392,16 @@ impl p2p::ChainAdapter for NetToChainAdapter {
 		}
 	}
 
+	fn get_block_by_height(&self, height: u64) -> Option<core::Block> {
+		let chain = self.chain();
+		// the header chain may be ahead of our blocks
+		if height > chain.head().ok()?.height {
+			return None;
+		}
+		let header = chain.get_header_by_height(height).ok()?;
+		chain.get_block(&header.hash()).ok()
+	}
+
 	fn kernel_data_read(&self) -> Result<File, chain::Error> {
 		self.chain().kernel_data_read()
 	}
@@ -449,6 +506,8 @@ impl NetToChainAdapter {
 	/// Construct a new NetToChainAdapter instance
 	pub fn new(
 		sync_state: Arc<SyncState>,
+		header_pipeline: Arc<HeaderPipeline>,
+		block_origins: Arc<BlockOrigins>,
 		chain: Arc<chain::Chain>,
 		tx_pool: Arc<RwLock<pool::TransactionPool>>,
 		verifier_cache: Arc<RwLock<dyn VerifierCache>>,
@@ -457,6 +516,8 @@ impl NetToChainAdapter {
 	) -> NetToChainAdapter {
 		NetToChainAdapter {
 			sync_state,
+			header_pipeline,
+			block_origins,
 			chain: Arc::downgrade(&chain),
 			tx_pool,
 			verifier_cache,
@@ -486,20 +547,27 @@ impl NetToChainAdapter {
 	}
 
 	// Find the first locator hash that refers to a known header on our main chain.
-	fn find_common_header(&self, locator: &[Hash]) -> Option<BlockHeader> {
+	fn find_common_header(&self, locator: &[Hash]) -> Result<Option<BlockHeader>, chain::Error> {
 		let txhashset = self.chain().txhashset();
 		let txhashset = txhashset.read();
 
-		for hash in locator {
-			if let Ok(header) = self.chain().get_block_header(&hash) {
-				if let Ok(header_at_height) = txhashset.get_header_by_height(header.height) {
-					if header.hash() == header_at_height.hash() {
-						return Some(header);
-					}
-				}
-			}
+		let locator = p2p::msg::Locator {
+			hashes: locator.to_vec(),
+		};
+		let fork_point = locator
+			.fork_point(|hash| {
+				let header = self.chain().get_block_header(hash).ok()?;
+				let on_main_chain = txhashset
+					.get_header_by_height(header.height)
+					.map(|h| h.hash() == header.hash())
+					.unwrap_or(false);
+				Some((header.height, on_main_chain))
+			})
+			.map_err(|e| chain::ErrorKind::Other(format!("bad locator: {:?}", e)))?;
+		match fork_point {
+			Some(i) => Ok(Some(self.chain().get_block_header(&locator.hashes[i])?)),
+			None => Ok(None),
 		}
-		None
 	}
 
 	// pushing the new block through the chain pipeline
@@ -523,6 +591,7 @@ impl NetToChainAdapter {
 
 		let bhash = b.hash();
 		let previous = self.chain().get_previous_header(&b.header);
+		self.block_origins.record(bhash, peer_info.addr.clone());
 
 		match self
 			.chain()
@@ -533,6 +602,11 @@ impl NetToChainAdapter {
 				self.check_compact();
 				Ok(true)
 			}
+			Err(ref e) if e.is_borderline() => {
+				debug!("process_block: block {} refused by chain: {}", bhash, e);
+				self.report_borderline(peer_info);
+				Ok(true)
+			}
 			Err(ref e) if e.is_bad_data() => {
 				self.validate_chain(bhash);
 				Ok(false)
@@ -564,6 +638,13 @@ impl NetToChainAdapter {
 		}
 	}
 
+	// The block may only be wrong by our own standards, a lighter strike
+	// against the peer than for an invalid one.
+	fn report_borderline(&self, peer_info: &PeerInfo) {
+		self.peers()
+			.report_misbehavior(peer_info.addr.clone(), p2p::Offense::BorderlineBlock);
+	}
+
 	fn validate_chain(&self, bhash: Hash) {
 		// If we are running in "validate the full chain every block" then
 		// panic here if validation fails for any reason.
@@ -625,24 +706,33 @@ impl NetToChainAdapter {
 		self.request_block_by_hash(bh.hash(), peer_info)
 	}
 
+	// Hydration failed, ask for the full block unless we're still syncing
+	// (and will get it through sync).
+	fn request_full_block(&self, bh: &BlockHeader, peer_info: &PeerInfo) {
+		if self.sync_state.status() == SyncStatus::NoSync {
+			debug!("requesting full block {}", bh.hash());
+			self.request_block(bh, peer_info);
+		} else {
+			debug!("not requesting full block {}, still syncing", bh.hash());
+		}
+	}
+
 	fn request_block_by_hash(&self, h: Hash, peer_info: &PeerInfo) {
-		self.send_block_request_to_peer(h, peer_info, |peer, h| peer.send_block_request(h))
+		self.send_block_request_to_peer(h, peer_info, false)
 	}
 
 	// After we have received a block header in "header first" propagation
 	// we need to go request the block (compact representation) from the
 	// same peer that gave us the header (unless we have already accepted the block)
 	fn request_compact_block(&self, bh: &BlockHeader, peer_info: &PeerInfo) {
-		self.send_block_request_to_peer(bh.hash(), peer_info, |peer, h| {
-			peer.send_compact_block_request(h)
-		})
+		self.send_block_request_to_peer(bh.hash(), peer_info, true)
 	}
 
 	fn send_tx_request_to_peer<F>(&self, h: Hash, peer_info: &PeerInfo, f: F)
 	where
 		F: Fn(&p2p::Peer, Hash) -> Result<(), p2p::Error>,
 	{
-		match self.peers().get_connected_peer(peer_info.addr) {
+		match self.peers().get_connected_peer(peer_info.addr.clone()) {
 			None => debug!(
 				"send_tx_request_to_peer: can't send request to peer {:?}, not connected",
 				peer_info.addr
@@ -655,18 +745,17 @@ impl NetToChainAdapter {
 		}
 	}
 
-	fn send_block_request_to_peer<F>(&self, h: Hash, peer_info: &PeerInfo, f: F)
-	where
-		F: Fn(&p2p::Peer, Hash) -> Result<(), p2p::Error>,
-	{
+	// Other peers announcing the same block are only asked if this one
+	// (or whoever was asked first) doesn't send it, see Peers::request_block.
+	fn send_block_request_to_peer(&self, h: Hash, peer_info: &PeerInfo, compact: bool) {
 		match self.chain().block_exists(h) {
-			Ok(false) => match self.peers().get_connected_peer(peer_info.addr) {
+			Ok(false) => match self.peers().get_connected_peer(peer_info.addr.clone()) {
 				None => debug!(
 					"send_block_request_to_peer: can't send request to peer {:?}, not connected",
 					peer_info.addr
 				),
 				Some(peer) => {
-					if let Err(e) = f(&peer, h) {
+					if let Err(e) = self.peers().request_block(h, &peer, compact) {
 						error!("send_block_request_to_peer: failed: {:?}", e)
 					}
 				}
@@ -695,27 +784,32 @@ impl NetToChainAdapter {
 /// the network to broadcast the block
 pub struct ChainToPoolAndNetAdapter {
 	tx_pool: Arc<RwLock<pool::TransactionPool>>,
+	block_origins: Arc<BlockOrigins>,
 	peers: OneTime<Weak<p2p::Peers>>,
 	hooks: Vec<Box<dyn ChainEvents + Send + Sync>>,
 }
 
 impl ChainAdapter for ChainToPoolAndNetAdapter {
 	fn block_accepted(&self, b: &core::Block, status: BlockStatus, opts: Options) {
+		let from = self.block_origins.take(b.hash());
 		// not broadcasting blocks received through sync
 		if !opts.contains(chain::Options::SYNC) {
 			for hook in &self.hooks {
 				hook.on_block_accepted(b, &status);
 			}
 			// If we mined the block then we want to broadcast the compact block.
-			// If we received the block from another node then broadcast "header first"
-			// to minimize network traffic.
+			// If we received the block from another node then broadcast it compact to
+			// peers supporting it and "header first" to the others to minimize network
+			// traffic.
 			if opts.contains(Options::MINE) {
 				// propagate compact block out if we mined the block
 				let cb: CompactBlock = b.clone().into();
 				self.peers().broadcast_compact_block(&cb);
 			} else {
-				// "header first" propagation if we are not the originator of this block
-				self.peers().broadcast_header(&b.header);
+				// compact block to the peers that can rebuild it, "header first"
+				// propagation to the others if we are not the originator of this block,
+				// never back to the peer that sent it to us
+				self.peers().broadcast_block(b, from.as_ref());
 			}
 		}
 
@@ -747,10 +841,12 @@ impl ChainToPoolAndNetAdapter {
 	/// Construct a ChainToPoolAndNetAdapter instance.
 	pub fn new(
 		tx_pool: Arc<RwLock<pool::TransactionPool>>,
+		block_origins: Arc<BlockOrigins>,
 		hooks: Vec<Box<dyn ChainEvents + Send + Sync>>,
 	) -> ChainToPoolAndNetAdapter {
 		ChainToPoolAndNetAdapter {
 			tx_pool,
+			block_origins,
 			peers: OneTime::new(),
 			hooks: hooks,
 		}
@@ -804,8 +900,10 @@ impl DandelionAdapter for PoolToNetAdapter {
 }
 
 impl pool::PoolAdapter for PoolToNetAdapter {
-	fn tx_accepted(&self, tx: &core::Transaction) {
-		self.peers().broadcast_transaction(tx);
+	fn tx_accepted(&self, entry: &pool::PoolEntry) {
+		// the source of a tx relayed to us is the address of the peer
+		let from = entry.src.identifier.parse::<p2p::PeerAddr>().ok();
+		self.peers().broadcast_transaction(&entry.tx, from.as_ref());
 	}
 
 	fn stem_tx_accepted(&self, tx: &core::Transaction) -> Result<(), pool::PoolError> {
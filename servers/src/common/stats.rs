@@ -49,6 +49,8 @@ impl Default for ServerStateInfo {
 pub struct ServerStats {
 	/// Number of peers
 	pub peer_count: u32,
+	/// Connected peers by capability, direction and protocol version
+	pub peer_counts: p2p::PeerCounts,
 	/// Chain head
 	pub head: chain::Tip,
 	/// sync header head
@@ -61,6 +63,10 @@ pub struct ServerStats {
 	pub peer_stats: Vec<PeerStats>,
 	/// Difficulty calculation statistics
 	pub diff_stats: DiffStats,
+	/// Handshake outcome counters
+	pub handshake_stats: p2p::handshake::HandshakeCounts,
+	/// Bandwidth used with all our connected peers
+	pub bandwidth_stats: p2p::BandwidthStats,
 }
 
 /// Struct to return relevant information about stratum workers
@@ -158,10 +164,22 @@ pub struct PeerStats {
 	pub direction: String,
 	/// Last time we saw a ping/pong from this peer.
 	pub last_seen: DateTime<Utc>,
+	/// Handshake round-trip time in milliseconds, if known yet.
+	pub handshake_rtt_ms: Option<u64>,
+	/// Current round-trip time estimate in milliseconds, from its pongs.
+	pub rtt_ms: Option<u64>,
 	/// Number of bytes we've sent to the peer.
 	pub sent_bytes_per_sec: u64,
 	/// Number of bytes we've received from the peer.
 	pub received_bytes_per_sec: u64,
+	/// Number of bytes waiting to be sent to the peer.
+	pub queued_bytes: u64,
+	/// Bytes sent to the peer since we connected.
+	pub sent_bytes: u64,
+	/// Bytes received from the peer since we connected.
+	pub received_bytes: u64,
+	/// Whether it's one of our preferred peers, always kept connected.
+	pub preferred: bool,
 }
 
 impl StratumStats {
@@ -183,22 +201,29 @@ impl PeerStats {
 		if peer.is_banned() {
 			state = "Banned";
 		}
-		let addr = peer.info.addr.to_string();
-		let direction = match peer.info.direction {
+		let stats = peer.stats();
+		let direction = match stats.direction {
 			p2p::types::Direction::Inbound => "Inbound",
 			p2p::types::Direction::Outbound => "Outbound",
 		};
 		PeerStats {
 			state: state.to_string(),
-			addr: addr,
-			version: peer.info.version,
-			user_agent: peer.info.user_agent.clone(),
-			total_difficulty: peer.info.total_difficulty().to_num(),
-			height: peer.info.height(),
+			addr: stats.addr.to_string(),
+			version: stats.version,
+			user_agent: stats.user_agent,
+			total_difficulty: stats.total_difficulty.to_num(),
+			height: stats.height,
 			direction: direction.to_string(),
-			last_seen: peer.info.last_seen(),
-			sent_bytes_per_sec: peer.last_min_sent_bytes().unwrap_or(0) / 60,
-			received_bytes_per_sec: peer.last_min_received_bytes().unwrap_or(0) / 60,
+			last_seen: stats.last_seen,
+			handshake_rtt_ms: peer.info.handshake_rtt().map(|rtt| rtt.as_millis() as u64),
+			rtt_ms: stats.rtt_ms,
+			sent_bytes_per_sec: stats.sent_rate,
+			received_bytes_per_sec: stats.recv_rate,
+			queued_bytes: stats.queued_bytes as u64,
+			sent_bytes: stats.sent_bytes,
+			received_bytes: stats.received_bytes,
+			// known to the server, see Server::get_server_stats
+			preferred: false,
 		}
 	}
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 //! Server types
+use std::collections::VecDeque;
 use std::convert::From;
 use std::sync::Arc;
 
@@ -21,6 +22,7 @@ use rand::prelude::*;
 
 use crate::api;
 use crate::chain;
+use crate::core::core::hash::Hash;
 use crate::core::global::ChainTypes;
 use crate::core::{core, libtx, pow};
 use crate::keychain;
@@ -466,6 +468,46 @@ impl chain::TxHashsetWriteStatus for SyncState {
 	}
 }
 
+/// How many of the blocks we received last we remember the origin of, plenty
+/// for the ones still going through the chain (orphans included).
+const BLOCK_ORIGINS_COUNT: usize = 64;
+
+/// Which peer sent us the blocks we're processing, for the chain to not
+/// relay a block back to the peer we got it from once it's accepted.
+pub struct BlockOrigins {
+	origins: RwLock<VecDeque<(Hash, p2p::PeerAddr)>>,
+}
+
+impl BlockOrigins {
+	/// No origin known yet.
+	pub fn new() -> BlockOrigins {
+		BlockOrigins {
+			origins: RwLock::new(VecDeque::new()),
+		}
+	}
+
+	/// We got the block with the provided hash from the provided peer,
+	/// forgetting about the oldest origin if we know too many of them.
+	pub fn record(&self, h: Hash, addr: p2p::PeerAddr) {
+		let mut origins = self.origins.write();
+		if origins.iter().any(|(oh, _)| *oh == h) {
+			return;
+		}
+		if origins.len() >= BLOCK_ORIGINS_COUNT {
+			origins.pop_front();
+		}
+		origins.push_back((h, addr));
+	}
+
+	/// The peer we got the block with the provided hash from, forgetting
+	/// about it.
+	pub fn take(&self, h: Hash) -> Option<p2p::PeerAddr> {
+		let mut origins = self.origins.write();
+		let idx = origins.iter().position(|(oh, _)| *oh == h)?;
+		origins.remove(idx).map(|(_, addr)| addr)
+	}
+}
+
 /// A node is either "stem" of "fluff" for the duration of a single epoch.
 /// A node also maintains an outbound relay peer for the epoch.
 #[derive(Debug)]
@@ -517,7 +559,7 @@ impl DandelionEpoch {
 			.expect("stem_probability config missing");
 		self.is_stem = rng.gen_range(0, 100) < stem_probability;
 
-		let addr = self.relay_peer.clone().map(|p| p.info.addr);
+		let addr = self.relay_peer.clone().map(|p| p.info.addr.clone());
 		info!(
 			"DandelionEpoch: next_epoch: is_stem: {} ({}%), relay: {:?}",
 			self.is_stem, stem_probability, addr
@@ -549,7 +591,7 @@ impl DandelionEpoch {
 			self.relay_peer = peers.outgoing_connected_peers().first().cloned();
 			info!(
 				"DandelionEpoch: relay_peer: new peer chosen: {:?}",
-				self.relay_peer.clone().map(|p| p.info.addr)
+				self.relay_peer.clone().map(|p| p.info.addr.clone())
 			);
 		}
 
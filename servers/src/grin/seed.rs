@@ -22,7 +22,6 @@ use chrono::{Duration, MIN_DATE};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use std::collections::HashMap;
-use std::net::ToSocketAddrs;
 use std::sync::{mpsc, Arc};
 use std::{cmp, str, thread, time};
 
@@ -53,7 +52,6 @@ pub fn connect_and_monitor(
 	p2p_server: Arc<p2p::Server>,
 	capabilities: p2p::Capabilities,
 	seed_list: Box<dyn Fn() -> Vec<PeerAddr> + Send>,
-	preferred_peers: Option<Vec<PeerAddr>>,
 	stop_state: Arc<StopState>,
 ) -> std::io::Result<thread::JoinHandle<()>> {
 	thread::Builder::new()
@@ -66,16 +64,13 @@ pub fn connect_and_monitor(
 			let (tx, rx) = mpsc::channel();
 
 			// check seeds first
-			connect_to_seeds_and_preferred_peers(
-				peers.clone(),
-				tx.clone(),
-				seed_list,
-				preferred_peers.clone(),
-			);
+			connect_to_seeds(peers.clone(), tx.clone(), seed_list);
 
 			let mut prev = MIN_DATE.and_hms(0, 0, 0);
 			let mut prev_expire_check = MIN_DATE.and_hms(0, 0, 0);
 			let mut prev_ping = Utc::now();
+			let ping_interval = Duration::from_std(p2p_server.config.ping_interval())
+				.unwrap_or(Duration::seconds(10));
 			let mut start_attempt = 0;
 			let mut connecting_history: HashMap<PeerAddr, DateTime<Utc>> = HashMap::new();
 
@@ -110,19 +105,14 @@ pub fn connect_and_monitor(
 					);
 
 					// monitor additional peers if we need to add more
-					monitor_peers(
-						peers.clone(),
-						p2p_server.config.clone(),
-						tx.clone(),
-						preferred_peers.clone(),
-					);
+					monitor_peers(peers.clone(), p2p_server.config.clone());
 
 					prev = Utc::now();
 					start_attempt = cmp::min(6, start_attempt + 1);
 				}
 
-				// Ping connected peers on every 10s to monitor peers.
-				if Utc::now() - prev_ping > Duration::seconds(10) {
+				// Ping connected peers regularly to monitor peers.
+				if Utc::now() - prev_ping > ping_interval {
 					let total_diff = peers.total_difficulty();
 					let total_height = peers.total_height();
 					if total_diff.is_ok() && total_height.is_ok() {
@@ -133,59 +123,68 @@ pub fn connect_and_monitor(
 					}
 				}
 
+				// Ask someone else for the blocks not sent in time.
+				peers.reissue_block_requests();
+
 				thread::sleep(time::Duration::from_secs(1));
 			}
 		})
 }
 
-fn monitor_peers(
-	peers: Arc<p2p::Peers>,
-	config: p2p::P2PConfig,
-	tx: mpsc::Sender<PeerAddr>,
-	preferred_peers_list: Option<Vec<PeerAddr>>,
-) {
+fn monitor_peers(peers: Arc<p2p::Peers>, config: p2p::P2PConfig) {
 	// regularly check if we need to acquire more peers  and if so, gets
 	// them from db
 	let total_count = peers.all_peers().len();
 	let mut healthy_count = 0;
 	let mut banned_count = 0;
+	let mut unverified_count = 0;
+	let mut discouraged_count = 0;
 	let mut defuncts = vec![];
 
 	for x in peers.all_peers() {
 		match x.flags {
 			p2p::State::Banned => {
-				let interval = Utc::now().timestamp() - x.last_banned;
-				// Unban peer
-				if interval >= config.ban_window() {
-					peers.unban_peer(x.addr);
-					debug!(
-						"monitor_peers: unbanned {} after {} seconds",
-						x.addr, interval
-					);
-				} else {
+				// lifts the ban if it's over
+				if peers.is_banned(x.addr.clone()) {
 					banned_count += 1;
 				}
 			}
 			p2p::State::Healthy => healthy_count += 1,
 			p2p::State::Defunct => defuncts.push(x),
+			p2p::State::Unverified => unverified_count += 1,
+			p2p::State::Discouraged => {
+				// lifts the discouragement if it's over
+				if peers.is_discouraged(x.addr.clone()) {
+					discouraged_count += 1;
+				}
+			}
+			p2p::State::Incompatible => {}
 		}
 	}
 
+	let counts = peers.peer_counts();
 	debug!(
-		"monitor_peers: on {}:{}, {} connected ({} most_work). \
-		 all {} = {} healthy + {} banned + {} defunct",
+		"monitor_peers: on {}:{}, {} connected ({} in, {} out, {} most_work, \
+		 by capability {:?}, by version {:?}). \
+		 all {} = {} healthy + {} banned + {} defunct + {} unverified + {} discouraged",
 		config.host,
 		config.port,
-		peers.peer_count(),
-		peers.most_work_peers().len(),
+		counts.total,
+		counts.inbound,
+		counts.outbound,
+		peers.most_work_peers(p2p::Capabilities::UNKNOWN).len(),
+		counts.capabilities,
+		counts.versions,
 		total_count,
 		healthy_count,
 		banned_count,
 		defuncts.len(),
+		unverified_count,
+		discouraged_count,
 	);
 
 	// maintenance step first, clean up p2p server peers
-	peers.clean_peers(config.peer_max_count() as usize);
+	peers.clean_peers(peers.limits().peer_max_count as usize);
 
 	if peers.healthy_peers_mix() {
 		return;
@@ -193,7 +192,6 @@ fn monitor_peers(
 
 	// loop over connected peers
 	// ask them for their list of peers
-	let mut connected_peers: Vec<PeerAddr> = vec![];
 	for p in peers.connected_peers() {
 		trace!(
 			"monitor_peers: {}:{} ask {} for more peers",
@@ -202,73 +200,37 @@ fn monitor_peers(
 			p.info.addr,
 		);
 		let _ = p.send_peer_request(p2p::Capabilities::PEER_LIST);
-		connected_peers.push(p.info.addr)
-	}
-
-	// Attempt to connect to preferred peers if there is some
-	if let Some(preferred_peers) = preferred_peers_list {
-		for p in preferred_peers {
-			if !connected_peers.is_empty() {
-				if !connected_peers.contains(&p) {
-					tx.send(p).unwrap();
-				}
-			} else {
-				tx.send(p).unwrap();
-			}
-		}
 	}
 
 	// take a random defunct peer and mark it healthy: over a long period any
 	// peer will see another as defunct eventually, gives us a chance to retry
 	if defuncts.len() > 0 {
 		defuncts.shuffle(&mut thread_rng());
-		let _ = peers.update_state(defuncts[0].addr, p2p::State::Healthy);
+		let _ = peers.update_state(defuncts[0].addr.clone(), p2p::State::Healthy);
 	}
 
-	// find some peers from our db
-	// and queue them up for a connection attempt
-	let new_peers = peers.find_peers(
-		p2p::State::Healthy,
-		p2p::Capabilities::UNKNOWN,
-		config.peer_max_count() as usize,
-	);
-
-	for p in new_peers.iter().filter(|p| !peers.is_known(p.addr)) {
-		trace!(
-			"monitor_peers: on {}:{}, queue to soon try {}",
-			config.host,
-			config.port,
-			p.addr,
-		);
-		tx.send(p.addr).unwrap();
-	}
+	// the peers from our db and our preferred peers are dialed by the p2p
+	// server itself, see p2p::Server::maintain_connections
 }
 
 // Check if we have any pre-existing peer in db. If so, start with those,
 // otherwise use the seeds provided.
-fn connect_to_seeds_and_preferred_peers(
+fn connect_to_seeds(
 	peers: Arc<p2p::Peers>,
 	tx: mpsc::Sender<PeerAddr>,
 	seed_list: Box<dyn Fn() -> Vec<PeerAddr>>,
-	peers_preferred_list: Option<Vec<PeerAddr>>,
 ) {
 	// check if we have some peers in db
 	// look for peers that are able to give us other peers (via PEER_LIST capability)
 	let peers = peers.find_peers(p2p::State::Healthy, p2p::Capabilities::PEER_LIST, 100);
 
 	// if so, get their addresses, otherwise use our seeds
-	let mut peer_addrs = if peers.len() > 3 {
-		peers.iter().map(|p| p.addr).collect::<Vec<_>>()
+	let peer_addrs = if peers.len() > 3 {
+		peers.iter().map(|p| p.addr.clone()).collect::<Vec<_>>()
 	} else {
 		seed_list()
 	};
 
-	// If we have preferred peers add them to the connection
-	match peers_preferred_list {
-		Some(mut peers_preferred) => peer_addrs.append(&mut peers_preferred),
-		None => trace!("No preferred peers"),
-	};
-
 	if peer_addrs.len() == 0 {
 		warn!("No seeds were retrieved.");
 	}
@@ -304,7 +266,13 @@ fn listen_for_addrs(
 	// Note: We drained the rx queue earlier to keep it under control.
 	// Even if there are many addresses to try we will only try a bounded number of them.
 	let connect_min_interval = 30;
-	for addr in addrs.into_iter().take(p2p.config.peer_max_count() as usize) {
+	for addr in addrs.into_iter().take(peers.limits().peer_max_count as usize) {
+		// skip peers previous attempts told us to back off from, or to give up on
+		if !peers.can_dial(&addr) {
+			trace!("peer_connect: not dialing {} yet", addr);
+			continue;
+		}
+
 		// ignore the duplicate connecting to same peer within 30 seconds
 		let now = Utc::now();
 		if let Some(last_connect_time) = connecting_history.get(&addr) {
@@ -321,21 +289,20 @@ fn listen_for_addrs(
 				}
 			}
 		}
-		connecting_history.insert(addr, now);
+		connecting_history.insert(addr.clone(), now);
 
 		let peers_c = peers.clone();
 		let p2p_c = p2p.clone();
 		thread::Builder::new()
 			.name("peer_connect".to_string())
-			.spawn(move || match p2p_c.connect(addr) {
+			.spawn(move || match p2p_c.connect(addr.clone()) {
 				Ok(p) => {
 					if p.send_peer_request(capab).is_ok() {
 						let _ = peers_c.update_state(addr, p2p::State::Healthy);
 					}
 				}
-				Err(_) => {
-					let _ = peers_c.update_state(addr, p2p::State::Defunct);
-				}
+				// back off, give up on or ban the peer depending on why we failed
+				Err(e) => peers_c.connect_failed(addr, &e),
 			})
 			.expect("failed to launch peer_connect thread");
 	}
@@ -355,33 +322,22 @@ fn listen_for_addrs(
 	}
 }
 
-pub fn dns_seeds() -> Box<dyn Fn() -> Vec<PeerAddr> + Send> {
-	Box::new(|| {
-		let mut addresses: Vec<PeerAddr> = vec![];
-		let net_seeds = if global::is_floonet() {
-			FLOONET_DNS_SEEDS
-		} else {
-			MAINNET_DNS_SEEDS
-		};
-		for dns_seed in net_seeds {
-			let temp_addresses = addresses.clone();
-			debug!("Retrieving seed nodes from dns {}", dns_seed);
-			match (dns_seed.to_owned(), 0).to_socket_addrs() {
-				Ok(addrs) => addresses.append(
-					&mut (addrs
-						.map(|mut addr| {
-							addr.set_port(if global::is_floonet() { 13414 } else { 3414 });
-							PeerAddr(addr)
-						})
-						.filter(|addr| !temp_addresses.contains(addr))
-						.collect()),
-				),
-				Err(e) => debug!("Failed to resolve seed {:?} got error {:?}", dns_seed, e),
-			}
+/// Seeder for the DNS seeds in our config, the ones of the network we're on
+/// if none are.
+pub fn dns_seeder(config: &p2p::P2PConfig) -> p2p::seeds::DnsSeeder {
+	let names = match config.dns_seeds {
+		Some(ref names) => names.clone(),
+		None => {
+			let net_seeds = if global::is_floonet() {
+				FLOONET_DNS_SEEDS
+			} else {
+				MAINNET_DNS_SEEDS
+			};
+			net_seeds.iter().map(|s| s.to_string()).collect()
 		}
-		debug!("Retrieved seed addresses: {:?}", addresses);
-		addresses
-	})
+	};
+	let port = if global::is_floonet() { 13414 } else { 3414 };
+	p2p::seeds::DnsSeeder::new(names, port, Box::new(p2p::seeds::SystemResolver))
 }
 
 /// Convenience function when the seed list is immediately known. Mostly used
@@ -36,7 +36,9 @@ use crate::common::adapters::{
 };
 use crate::common::hooks::{init_chain_hooks, init_net_hooks};
 use crate::common::stats::{DiffBlock, DiffStats, PeerStats, ServerStateInfo, ServerStats};
-use crate::common::types::{Error, ServerConfig, StratumServerConfig, SyncState, SyncStatus};
+use crate::common::types::{
+	BlockOrigins, Error, ServerConfig, StratumServerConfig, SyncState, SyncStatus,
+};
 use crate::core::core::hash::{Hashed, ZERO_HASH};
 use crate::core::core::verifier_cache::{LruVerifierCache, VerifierCache};
 use crate::core::{consensus, genesis, global, pow};
@@ -49,6 +51,10 @@ use crate::pool;
 use crate::util::file::get_first_line;
 use crate::util::{RwLock, StopState};
 
+/// How long we give our peers to get what we still had for them when
+/// shutting down.
+const P2P_STOP_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
 /// Grin server holding internal structures.
 pub struct Server {
 	/// server config
@@ -165,8 +171,10 @@ impl Server {
 
 		let sync_state = Arc::new(SyncState::new());
 
+		let block_origins = Arc::new(BlockOrigins::new());
 		let chain_adapter = Arc::new(ChainToPoolAndNetAdapter::new(
 			tx_pool.clone(),
+			block_origins.clone(),
 			init_chain_hooks(&config),
 		));
 
@@ -190,8 +198,12 @@ impl Server {
 
 		pool_adapter.set_chain(shared_chain.clone());
 
+		let header_pipeline = Arc::new(sync::HeaderPipeline::new());
+
 		let net_adapter = Arc::new(NetToChainAdapter::new(
 			sync_state.clone(),
+			header_pipeline.clone(),
+			block_origins,
 			shared_chain.clone(),
 			tx_pool.clone(),
 			verifier_cache.clone(),
@@ -199,9 +211,15 @@ impl Server {
 			init_net_hooks(&config),
 		));
 
+		// archival nodes can serve all the blocks, let our peers know
+		let capabilities = if archive_mode {
+			config.p2p_config.capabilities | p2p::Capabilities::FULL_HIST
+		} else {
+			config.p2p_config.capabilities
+		};
 		let p2p_server = Arc::new(p2p::Server::new(
 			&config.db_root,
-			config.p2p_config.capabilities,
+			capabilities,
 			config.p2p_config.clone(),
 			net_adapter.clone(),
 			genesis.hash(),
@@ -229,15 +247,22 @@ impl Server {
 						));
 					}
 				},
-				p2p::Seeding::DNSSeed => seed::dns_seeds(),
+				p2p::Seeding::DNSSeed => {
+					p2p::Server::seed_from_dns(
+						p2p_server.clone(),
+						seed::dns_seeder(&config.p2p_config),
+					)?;
+					seed::predefined_seeds(vec![])
+				}
 				_ => unreachable!(),
 			};
 
+			p2p::Server::maintain_connections(p2p_server.clone())?;
+			p2p::Server::monitor_peers(p2p_server.clone())?;
 			connect_thread = Some(seed::connect_and_monitor(
 				p2p_server.clone(),
-				config.p2p_config.capabilities,
+				capabilities,
 				seeder,
-				config.p2p_config.peers_preferred.clone(),
 				stop_state.clone(),
 			)?);
 		}
@@ -251,6 +276,7 @@ impl Server {
 			sync_state.clone(),
 			p2p_server.peers.clone(),
 			shared_chain.clone(),
+			header_pipeline,
 			stop_state.clone(),
 		)?;
 
@@ -490,16 +516,30 @@ impl Server {
 			.peers
 			.connected_peers()
 			.into_iter()
-			.map(|p| PeerStats::from_peer(&p))
+			.map(|p| {
+				let mut stats = PeerStats {
+					preferred: self.p2p.config.is_preferred(&p.info.addr),
+					..PeerStats::from_peer(&p)
+				};
+				// kept in the peer store, see Peers::discourage_peer
+				if stats.state == "Connected" && self.p2p.peers.is_discouraged(p.info.addr.clone())
+				{
+					stats.state = "Discouraged".to_string();
+				}
+				stats
+			})
 			.collect();
 		Ok(ServerStats {
 			peer_count: self.peer_count(),
+			peer_counts: self.p2p.peers.peer_counts(),
 			head: self.head()?,
 			header_head: self.header_head()?,
 			sync_status: self.sync_state.status(),
 			stratum_stats: stratum_stats,
 			peer_stats: peer_stats,
 			diff_stats: diff_stats,
+			handshake_stats: self.p2p.handshake_stats(),
+			bandwidth_stats: self.p2p.peers.stats(),
 		})
 	}
 
@@ -528,9 +568,11 @@ impl Server {
 				Ok(_) => info!("dandelion_monitor thread stopped"),
 			}
 		}
-		// this call is blocking and makes sure all peers stop, however
-		// we can't be sure that we stoped a listener blocked on accept, so we don't join the p2p thread
-		self.p2p.stop();
+		// closes our listener and tells all our peers we're leaving, we don't
+		// join the p2p thread but wait a moment for the peers to be gone
+		if !self.p2p.stop().wait(P2P_STOP_TIMEOUT) {
+			warn!("Some peers still not disconnected, shutting down anyway.");
+		}
 		let _ = self.lock_file.unlock();
 	}
 
@@ -19,4 +19,5 @@ mod header_sync;
 mod state_sync;
 mod syncer;
 
+pub use self::header_sync::{Batch, HeaderPipeline};
 pub use self::syncer::run_sync;
@@ -16,12 +16,27 @@ use chrono::prelude::{DateTime, Utc};
 use chrono::Duration;
 use std::cmp;
 use std::sync::Arc;
+use std::time;
 
 use crate::chain;
 use crate::common::types::{SyncState, SyncStatus};
 use crate::core::core::hash::Hash;
+use crate::core::global;
 use crate::p2p;
 
+/// How long a peer gets to send a block we asked for before we ask another.
+const BLOCK_REQUEST_TTL: time::Duration = time::Duration::from_secs(6);
+
+// Our peers prune the blocks past the cut-through horizon, the ones that far
+// behind the head we're syncing to can only come from archival peers.
+fn required_capabilities(height: u64, header_height: u64) -> p2p::Capabilities {
+	if header_height.saturating_sub(height) > global::cut_through_horizon() as u64 {
+		p2p::Capabilities::FULL_HIST
+	} else {
+		p2p::Capabilities::UNKNOWN
+	}
+}
+
 pub struct BodySync {
 	chain: Arc<chain::Chain>,
 	peers: Arc<p2p::Peers>,
@@ -100,7 +115,16 @@ impl BodySync {
 
 		hashes.reverse();
 
-		let peers = self.peers.more_work_peers()?;
+		let body_head = self.chain.head()?;
+		let header_head = self.chain.header_head()?;
+		let peers = self.peers.more_work_peers(
+			body_head.total_difficulty,
+			required_capabilities(body_head.height, header_head.height),
+		);
+
+		// blocks a peer kept us waiting for go to another one, the silent
+		// peer gets a strike and is picked last from now on
+		self.reissue_expired(&peers);
 
 		// if we have 5 peers to sync from then ask for 50 blocks total (peer_count *
 		// 10) max will be 80 if all 8 peers are advertising more work
@@ -121,9 +145,6 @@ impl BodySync {
 			.collect::<Vec<_>>();
 
 		if hashes_to_get.len() > 0 {
-			let body_head = self.chain.head()?;
-			let header_head = self.chain.header_head()?;
-
 			debug!(
 				"block_sync: {}/{} requesting blocks {:?} from {} peers",
 				body_head.height,
@@ -151,6 +172,30 @@ impl BodySync {
 		return Ok(false);
 	}
 
+	// Asks another peer for the blocks a peer left unanswered for too long.
+	fn reissue_expired(&self, peers: &[Arc<p2p::Peer>]) {
+		for peer in peers {
+			for req in peer.expired_requests(BLOCK_REQUEST_TTL) {
+				let h = match req.requested {
+					p2p::Requested::Block(h) => h,
+					_ => continue,
+				};
+				if self.chain.get_block(&h).is_ok() || self.chain.is_orphan(&h) {
+					continue;
+				}
+				if let Some(other) = peers.iter().find(|p| p.info.addr != peer.info.addr) {
+					debug!(
+						"block_sync: no block {} from {}, asking {}",
+						h, peer.info.addr, other.info.addr
+					);
+					if let Err(e) = other.send_block_request(h) {
+						debug!("Skipped request to {}: {:?}", other.info.addr, e);
+					}
+				}
+			}
+		}
+	}
+
 	// Should we run block body sync and ask for more full blocks?
 	fn body_sync_due(&mut self) -> Result<bool, chain::Error> {
 		let blocks_received = self.blocks_received()?;
@@ -14,17 +14,25 @@
 
 use chrono::prelude::{DateTime, Utc};
 use chrono::Duration;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use crate::chain;
 use crate::common::types::{Error, SyncState, SyncStatus};
 use crate::core::core::hash::{Hash, Hashed};
-use crate::p2p::{self, types::ReasonForBan, Peer};
+use crate::core::core::BlockHeader;
+use crate::p2p::{self, types::ReasonForBan, Peer, PeerAddr};
+use crate::util::Mutex;
+
+/// Max number of header batches we get ahead of validation, counting the
+/// requests in flight and the batches waiting their turn to be applied.
+const MAX_BATCHES_AHEAD: usize = 4;
 
 pub struct HeaderSync {
 	sync_state: Arc<SyncState>,
 	peers: Arc<p2p::Peers>,
 	chain: Arc<chain::Chain>,
+	pipeline: Arc<HeaderPipeline>,
 
 	history_locator: Vec<(u64, Hash)>,
 	prev_header_sync: (DateTime<Utc>, u64, u64),
@@ -38,11 +46,13 @@ impl HeaderSync {
 		sync_state: Arc<SyncState>,
 		peers: Arc<p2p::Peers>,
 		chain: Arc<chain::Chain>,
+		pipeline: Arc<HeaderPipeline>,
 	) -> HeaderSync {
 		HeaderSync {
 			sync_state,
 			peers,
 			chain,
+			pipeline,
 			history_locator: vec![],
 			prev_header_sync: (Utc::now(), 0, 0),
 			syncing_peer: None,
@@ -55,6 +65,8 @@ impl HeaderSync {
 		header_head: &chain::Tip,
 		highest_height: u64,
 	) -> Result<bool, chain::Error> {
+		self.apply_batches()?;
+
 		if !self.header_sync_due(header_head) {
 			return Ok(false);
 		}
@@ -108,9 +120,10 @@ impl HeaderSync {
 		let now = Utc::now();
 		let (timeout, latest_height, prev_height) = self.prev_header_sync;
 
-		// received all necessary headers, can ask for more
-		let all_headers_received =
-			header_head.height >= prev_height + (p2p::MAX_BLOCK_HEADERS as u64) - 4;
+		// received all necessary headers, can ask for more (unless the pipeline
+		// is still at it, asking on its own)
+		let all_headers_received = !self.pipeline.is_busy()
+			&& header_head.height >= prev_height + (p2p::MAX_BLOCK_HEADERS as u64) - 4;
 		// no headers processed and we're past timeout, need to ask for more
 		let stalling = header_head.height <= latest_height && now > timeout;
 
@@ -148,8 +161,11 @@ impl HeaderSync {
 								if now > *stalling_ts + Duration::seconds(120)
 									&& header_head.total_difficulty < peer.info.total_difficulty()
 								{
-									self.peers
-										.ban_peer(peer.info.addr, ReasonForBan::FraudHeight);
+									self.peers.ban_peer(
+										peer.info.addr.clone(),
+										ReasonForBan::FraudDifficulty,
+										self.peers.ban_window(),
+									);
 									info!(
 										"sync: ban a fraud peer: {}, claimed height: {}, total difficulty: {}",
 										peer.info.addr,
@@ -179,7 +195,11 @@ impl HeaderSync {
 		if let Ok(header_head) = self.chain.header_head() {
 			let difficulty = header_head.total_difficulty;
 
-			if let Some(peer) = self.peers.most_work_peer() {
+			// spread over the peers with the most work, not always the same one
+			if let Some(peer) = self
+				.peers
+				.random_most_work_peer(p2p::Capabilities::HEADER_HIST)
+			{
 				if peer.info.total_difficulty() > difficulty {
 					return self.request_headers(peer);
 				}
@@ -196,12 +216,53 @@ impl HeaderSync {
 				peer.info.addr, locator,
 			);
 
+			self.pipeline.start(peer.info.addr.clone(), locator.clone());
 			let _ = peer.send_header_request(locator);
 			return Some(peer.clone());
 		}
 		return None;
 	}
 
+	/// Validates the batches pipelined so far, in order, asking for the next
+	/// one if the pipeline had to hold off. The peer that sent us a batch
+	/// with bad headers is banned, and we stop syncing from it.
+	fn apply_batches(&mut self) -> Result<(), chain::Error> {
+		let chain = self.chain.clone();
+		let next = self
+			.pipeline
+			.apply(|headers| chain.sync_block_headers(headers, chain::Options::SYNC));
+		match next {
+			Ok(Some((addr, locator))) => {
+				if let Some(peer) = self.peers.get_connected_peer(addr) {
+					let _ = peer.send_header_request(locator);
+				}
+			}
+			Ok(None) => (),
+			Err((addr, e)) => {
+				debug!(
+					"sync: pipelined headers from {} refused by chain: {:?}",
+					addr, e
+				);
+				if !e.is_bad_data() {
+					return Err(e);
+				}
+				self.peers.ban_peer(
+					addr.clone(),
+					ReasonForBan::BadBlockHeader,
+					self.peers.ban_window(),
+				);
+				if self
+					.syncing_peer
+					.as_ref()
+					.map_or(false, |p| p.info.addr == addr)
+				{
+					self.syncing_peer = None;
+				}
+			}
+		}
+		Ok(())
+	}
+
 	/// We build a locator based on sync_head.
 	/// Even if sync_head is significantly out of date we will "reset" it once we
 	/// start getting headers back from a peer.
@@ -244,6 +305,171 @@ impl HeaderSync {
 	}
 }
 
+/// What became of a batch of headers handed over to the pipeline.
+#[derive(Debug, PartialEq)]
+pub enum Batch {
+	/// Doesn't answer one of our sync requests, up to the caller.
+	NotOurs,
+	/// Answers a request cancelled since, dropped. Not the peer's fault, we
+	/// cancelled it.
+	Cancelled,
+	/// Queued to be applied in turn, nothing more to ask for now.
+	Queued,
+	/// Queued, the next batch should be asked right away with this locator.
+	AskNext(Vec<Hash>),
+}
+
+/// Pipelines the header requests of the sync. A full batch from the peer
+/// we're syncing from gets the next one asked right away, following its last
+/// header, while it waits to be validated by the sync thread. Locators only
+/// name headers we already have so a single request is on the wire at a
+/// time, it's the validation that overlaps the round trips.
+///
+/// Batches are matched to the requests they answer by the header they follow
+/// and applied in the order asked, whatever the order they came in. A batch
+/// failing validation cancels everything asked after it.
+pub struct HeaderPipeline {
+	state: Mutex<PipelineState>,
+}
+
+#[derive(Default)]
+struct PipelineState {
+	peer: Option<PeerAddr>,
+	// requests sent and not answered yet, by sequence, with the hashes the
+	// answer can follow
+	in_flight: Vec<(u64, Vec<Hash>)>,
+	// answers waiting for their turn, by sequence, with who sent them
+	queued: BTreeMap<u64, (PeerAddr, Vec<BlockHeader>)>,
+	// last hash of a full batch we held off asking past, no room left
+	held: Option<Hash>,
+	// requests cancelled after a batch failed, their answers are dropped
+	cancelled: Vec<Vec<Hash>>,
+	next_seq: u64,
+	apply_seq: u64,
+}
+
+impl PipelineState {
+	// The locator to ask past the held batch, if there's room for it.
+	fn ask_held(&mut self) -> Option<Vec<Hash>> {
+		if self.in_flight.len() + self.queued.len() >= MAX_BATCHES_AHEAD {
+			return None;
+		}
+		let locator = vec![self.held.take()?];
+		self.in_flight.push((self.next_seq, locator.clone()));
+		self.next_seq += 1;
+		Some(locator)
+	}
+}
+
+impl HeaderPipeline {
+	pub fn new() -> HeaderPipeline {
+		HeaderPipeline {
+			state: Mutex::new(PipelineState::default()),
+		}
+	}
+
+	/// Starts over with a request to the provided peer, whatever was in
+	/// flight or queued is forgotten.
+	pub fn start(&self, peer: PeerAddr, locator: Vec<Hash>) {
+		*self.state.lock() = PipelineState {
+			peer: Some(peer),
+			in_flight: vec![(0, locator)],
+			next_seq: 1,
+			..PipelineState::default()
+		};
+	}
+
+	/// Hands over a batch of headers received from a peer.
+	pub fn received(&self, headers: &[BlockHeader], from: &PeerAddr) -> Batch {
+		let mut state = self.state.lock();
+		if state.peer.as_ref() != Some(from) {
+			return Batch::NotOurs;
+		}
+		let prev = match headers.first() {
+			Some(header) => header.prev_hash,
+			None => {
+				// nothing more to give us, answering the oldest request
+				if !state.in_flight.is_empty() {
+					state.in_flight.remove(0);
+				}
+				return Batch::NotOurs;
+			}
+		};
+		if let Some(pos) = state.cancelled.iter().position(|l| l.contains(&prev)) {
+			state.cancelled.remove(pos);
+			return Batch::Cancelled;
+		}
+		let seq = match state.in_flight.iter().position(|(_, l)| l.contains(&prev)) {
+			Some(pos) => state.in_flight.remove(pos).0,
+			None => return Batch::NotOurs,
+		};
+		state.queued.insert(seq, (from.clone(), headers.to_vec()));
+
+		// only a full batch, answering our latest request, has more after it
+		if headers.len() < p2p::MAX_BLOCK_HEADERS as usize || seq + 1 != state.next_seq {
+			return Batch::Queued;
+		}
+		state.held = headers.last().map(|h| h.hash());
+		match state.ask_held() {
+			Some(locator) => Batch::AskNext(locator),
+			None => Batch::Queued,
+		}
+	}
+
+	/// Applies the queued batches in order, up to the first one still
+	/// missing. A failure cancels everything asked after the failed batch,
+	/// and comes with the peer that sent it. Returns who to ask next and with
+	/// what locator, when a held batch now has room to be followed.
+	pub fn apply<F>(
+		&self,
+		mut apply: F,
+	) -> Result<Option<(PeerAddr, Vec<Hash>)>, (PeerAddr, chain::Error)>
+	where
+		F: FnMut(&[BlockHeader]) -> Result<(), chain::Error>,
+	{
+		loop {
+			// not holding the lock while validating
+			let (from, batch) = {
+				let mut state = self.state.lock();
+				let seq = state.apply_seq;
+				match state.queued.remove(&seq) {
+					Some(batch) => {
+						state.apply_seq += 1;
+						batch
+					}
+					None => break,
+				}
+			};
+			if let Err(e) = apply(&batch) {
+				self.cancel();
+				return Err((from, e));
+			}
+		}
+		let mut state = self.state.lock();
+		let peer = state.peer.clone();
+		Ok(state.ask_held().and_then(|l| peer.map(|p| (p, l))))
+	}
+
+	/// Whether we're still waiting on batches asked ahead, or have some to
+	/// apply.
+	pub fn is_busy(&self) -> bool {
+		let state = self.state.lock();
+		!state.in_flight.is_empty() || !state.queued.is_empty() || state.held.is_some()
+	}
+
+	fn cancel(&self) {
+		let mut state = self.state.lock();
+		let in_flight = state
+			.in_flight
+			.drain(..)
+			.map(|(_, l)| l)
+			.collect::<Vec<_>>();
+		state.cancelled.extend(in_flight);
+		state.queued.clear();
+		state.held = None;
+	}
+}
+
 // Whether we have a value close enough to the provided height in the locator
 fn close_enough(locator: &Vec<(u64, Hash)>, height: u64) -> Option<(u64, Hash)> {
 	if locator.len() == 0 {
@@ -289,6 +515,156 @@ fn get_locator_heights(height: u64) -> Vec<u64> {
 mod test {
 	use super::*;
 	use crate::core::core::hash;
+	use std::sync::mpsc;
+	use std::{thread, time};
+
+	// Full batches of headers, chained to each other from the zero hash.
+	fn batches(n: usize) -> Vec<Vec<BlockHeader>> {
+		let mut prev_hash = hash::ZERO_HASH;
+		let mut batches = vec![];
+		for i in 0..n {
+			let mut batch = vec![];
+			for j in 0..p2p::MAX_BLOCK_HEADERS as usize {
+				let header = BlockHeader {
+					height: (i * p2p::MAX_BLOCK_HEADERS as usize + j + 1) as u64,
+					prev_hash,
+					..BlockHeader::default()
+				};
+				prev_hash = header.hash();
+				batch.push(header);
+			}
+			batches.push(batch);
+		}
+		batches
+	}
+
+	fn peer_addr() -> PeerAddr {
+		PeerAddr::Ip("127.0.0.1:3414".parse().unwrap())
+	}
+
+	// A peer answering the header requests sent to it after some latency,
+	// with the next request pipelined as the adapter does it. Tells which
+	// batch it's asked for as the requests come.
+	fn mock_peer(
+		batches: Vec<Vec<BlockHeader>>,
+		latency: time::Duration,
+		pipeline: Arc<HeaderPipeline>,
+		asked: mpsc::Sender<usize>,
+	) -> mpsc::Sender<Vec<Hash>> {
+		let (tx, rx) = mpsc::channel::<Vec<Hash>>();
+		let requests = tx.clone();
+		thread::spawn(move || {
+			for locator in rx {
+				let batch = match batches
+					.iter()
+					.position(|b| locator.contains(&b[0].prev_hash))
+				{
+					Some(i) => {
+						let _ = asked.send(i);
+						batches[i].clone()
+					}
+					None => vec![],
+				};
+				thread::sleep(latency);
+				if let Batch::AskNext(locator) = pipeline.received(&batch, &peer_addr()) {
+					let _ = requests.send(locator);
+				}
+			}
+		});
+		tx
+	}
+
+	// With header requests pipelined, the next batch is asked for while the
+	// one before it is still being validated rather than once it's done, so
+	// validation doesn't add up to the round trips.
+	#[test]
+	fn header_pipeline_asks_while_validating() {
+		let n = 6;
+		let timeout = time::Duration::from_secs(10);
+		let pipeline = Arc::new(HeaderPipeline::new());
+		let (asked_tx, asked) = mpsc::channel();
+		let latency = time::Duration::from_millis(20);
+		let peer = mock_peer(batches(n), latency, pipeline.clone(), asked_tx);
+
+		let start = time::Instant::now();
+		pipeline.start(peer_addr(), vec![hash::ZERO_HASH]);
+		peer.send(vec![hash::ZERO_HASH]).unwrap();
+
+		// the sync loop
+		let mut applied = vec![];
+		let mut last_asked = None;
+		while applied.len() < n * p2p::MAX_BLOCK_HEADERS as usize {
+			assert!(start.elapsed() < timeout);
+			let next = pipeline
+				.apply(|headers| {
+					// the request for the next batch went out already, or
+					// goes out while we're still at this one
+					let batch = headers[0].height as usize / p2p::MAX_BLOCK_HEADERS as usize;
+					while batch + 1 < n && last_asked.map_or(true, |i| i <= batch) {
+						last_asked = Some(asked.recv_timeout(timeout).unwrap());
+					}
+					applied.extend(headers.iter().map(|h| h.height));
+					Ok(())
+				})
+				.unwrap();
+			if let Some((_, locator)) = next {
+				peer.send(locator).unwrap();
+			}
+			thread::sleep(time::Duration::from_millis(10));
+		}
+
+		assert_eq!(applied, (1..=applied.len() as u64).collect::<Vec<_>>());
+	}
+
+	// The pipeline stops asking ahead once full, resumes as batches get
+	// applied, and cancels what's in flight when one fails validation.
+	#[test]
+	fn header_pipeline_bounded_and_cancelled() {
+		let batches = batches(MAX_BATCHES_AHEAD + 2);
+		let last = |i: usize| batches[i].last().unwrap().hash();
+		let pipeline = HeaderPipeline::new();
+		pipeline.start(peer_addr(), vec![hash::ZERO_HASH]);
+
+		let other = PeerAddr::Ip("127.0.0.1:3415".parse().unwrap());
+		assert_eq!(pipeline.received(&batches[0], &other), Batch::NotOurs);
+
+		for i in 0..MAX_BATCHES_AHEAD - 1 {
+			assert_eq!(
+				pipeline.received(&batches[i], &peer_addr()),
+				Batch::AskNext(vec![last(i)])
+			);
+		}
+		// full, held off
+		let held = MAX_BATCHES_AHEAD - 1;
+		assert_eq!(
+			pipeline.received(&batches[held], &peer_addr()),
+			Batch::Queued
+		);
+		assert!(pipeline.is_busy());
+
+		let mut applied = 0;
+		let next = pipeline
+			.apply(|_| {
+				applied += 1;
+				Ok(())
+			})
+			.unwrap();
+		assert_eq!(applied, MAX_BATCHES_AHEAD);
+		assert_eq!(next, Some((peer_addr(), vec![last(held)])));
+
+		// the next batch fails, the one asked after it is dropped when it comes
+		assert_eq!(
+			pipeline.received(&batches[held + 1], &peer_addr()),
+			Batch::AskNext(vec![last(held + 1)])
+		);
+		let res = pipeline.apply(|_| Err(chain::ErrorKind::InvalidBlockHeight.into()));
+		assert_eq!(res.err().map(|(addr, _)| addr), Some(peer_addr()));
+		assert!(!pipeline.is_busy());
+		assert_eq!(
+			pipeline.received(&batches[held + 2], &peer_addr()),
+			Batch::Cancelled
+		);
+	}
 
 	#[test]
 	fn test_get_locator_heights() {
@@ -20,7 +20,7 @@ use crate::chain;
 use crate::common::types::{Error, SyncState, SyncStatus};
 use crate::core::core::hash::Hashed;
 use crate::core::global;
-use crate::p2p::{self, Peer};
+use crate::p2p::{self, Capabilities, Peer};
 
 /// Fast sync has 3 "states":
 /// * syncing headers
@@ -87,6 +87,9 @@ impl StateSync {
 						"state_sync: peer connection lost: {:?}. restart",
 						peer.info.addr,
 					);
+					// most likely stalled, try another one
+					self.peers
+						.connect_failed(peer.info.addr.clone(), &p2p::Error::Timeout);
 				}
 			}
 		}
@@ -121,6 +124,11 @@ impl StateSync {
 					error!("state_sync: TxHashsetDownload status timeout in 10 minutes!");
 					self.sync_state
 						.set_sync_error(Error::P2P(p2p::Error::Timeout));
+					if let Some(ref peer) = self.state_sync_peer {
+						peer.stop();
+						self.peers
+							.connect_failed(peer.info.addr.clone(), &p2p::Error::Timeout);
+					}
 				}
 			}
 
@@ -161,7 +169,9 @@ impl StateSync {
 	fn request_state(&self, header_head: &chain::Tip) -> Result<Arc<Peer>, p2p::Error> {
 		let threshold = global::state_sync_threshold() as u64;
 
-		if let Some(peer) = self.peers.most_work_peer() {
+		// only peers advertising they keep the txhashset can serve it
+		let peer = self.peers.most_work_peer(Capabilities::TXHASHSET_HIST);
+		if let Some(peer) = peer {
 			// ask for txhashset at state_sync_threshold
 			let mut txhashset_head = self
 				.chain
@@ -214,7 +224,9 @@ impl StateSync {
 				(true, download_timeout)
 			}
 			Some(prev) => {
-				if now - prev > Duration::minutes(10) {
+				let timeout =
+					Duration::seconds(p2p::msg::TXHASHSET_ARCHIVE_TIMEOUT.as_secs() as i64);
+				if now - prev > timeout {
 					download_timeout = true;
 				}
 				(false, download_timeout)
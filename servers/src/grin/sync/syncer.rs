@@ -21,7 +21,7 @@ use crate::common::types::{SyncState, SyncStatus};
 use crate::core::global;
 use crate::core::pow::Difficulty;
 use crate::grin::sync::body_sync::BodySync;
-use crate::grin::sync::header_sync::HeaderSync;
+use crate::grin::sync::header_sync::{HeaderPipeline, HeaderSync};
 use crate::grin::sync::state_sync::StateSync;
 use crate::p2p;
 use crate::util::StopState;
@@ -30,12 +30,13 @@ pub fn run_sync(
 	sync_state: Arc<SyncState>,
 	peers: Arc<p2p::Peers>,
 	chain: Arc<chain::Chain>,
+	header_pipeline: Arc<HeaderPipeline>,
 	stop_state: Arc<StopState>,
 ) -> std::io::Result<std::thread::JoinHandle<()>> {
 	thread::Builder::new()
 		.name("sync".to_string())
 		.spawn(move || {
-			let runner = SyncRunner::new(sync_state, peers, chain, stop_state);
+			let runner = SyncRunner::new(sync_state, peers, chain, header_pipeline, stop_state);
 			runner.sync_loop();
 		})
 }
@@ -44,6 +45,7 @@ pub struct SyncRunner {
 	sync_state: Arc<SyncState>,
 	peers: Arc<p2p::Peers>,
 	chain: Arc<chain::Chain>,
+	header_pipeline: Arc<HeaderPipeline>,
 	stop_state: Arc<StopState>,
 }
 
@@ -52,12 +54,14 @@ impl SyncRunner {
 		sync_state: Arc<SyncState>,
 		peers: Arc<p2p::Peers>,
 		chain: Arc<chain::Chain>,
+		header_pipeline: Arc<HeaderPipeline>,
 		stop_state: Arc<StopState>,
 	) -> SyncRunner {
 		SyncRunner {
 			sync_state,
 			peers,
 			chain,
+			header_pipeline,
 			stop_state,
 		}
 	}
@@ -125,6 +129,7 @@ impl SyncRunner {
 			self.sync_state.clone(),
 			self.peers.clone(),
 			self.chain.clone(),
+			self.header_pipeline.clone(),
 		);
 		let mut body_sync = BodySync::new(
 			self.sync_state.clone(),
@@ -227,7 +232,7 @@ impl SyncRunner {
 	fn needs_syncing(&self) -> Result<(bool, u64), chain::Error> {
 		let local_diff = self.chain.head()?.total_difficulty;
 		let mut is_syncing = self.sync_state.is_syncing();
-		let peer = self.peers.most_work_peer();
+		let peer = self.peers.most_work_peer(p2p::Capabilities::UNKNOWN);
 
 		let peer_info = if let Some(p) = peer {
 			p.info.clone()
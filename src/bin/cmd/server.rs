@@ -111,13 +111,18 @@ pub fn server_command(
 		}
 
 		if let Some(seeds) = a.values_of("seed") {
-			let seed_addrs = seeds
-				.filter_map(|x| x.parse().ok())
-				.map(|x| PeerAddr(x))
-				.collect();
+			let seed_addrs = seeds.filter_map(|x| x.parse::<PeerAddr>().ok()).collect();
 			server_config.p2p_config.seeding_type = Seeding::List;
 			server_config.p2p_config.seeds = Some(seed_addrs);
 		}
+
+		if let Some(path) = a.value_of("import_peers") {
+			server_config.p2p_config.peers_import_file = Some(path.to_string());
+		}
+
+		if let Some(path) = a.value_of("export_peers") {
+			server_config.p2p_config.peers_export_file = Some(path.to_string());
+		}
 	}
 
 	if let Some(a) = server_args {
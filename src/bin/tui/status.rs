@@ -242,7 +242,12 @@ impl TUIStatusListener for TUIStatusView {
 			t.set_content(basic_status);
 		});
 		c.call_on_id("connected_peers", |t: &mut TextView| {
-			t.set_content(stats.peer_count.to_string());
+			let counts = &stats.peer_counts;
+			let archival = counts.capabilities.get("FULL_HIST").cloned().unwrap_or(0);
+			t.set_content(format!(
+				"{} ({} in, {} out, {} archival)",
+				stats.peer_count, counts.inbound, counts.outbound, archival
+			));
 		});
 		c.call_on_id("tip_hash", |t: &mut TextView| {
 			t.set_content(stats.head.last_block_h.to_string() + "...");
@@ -45,6 +45,9 @@ pub enum Error {
 	/// Wraps a serialization error for Writeable or Readable
 	#[fail(display = "Serialization Error")]
 	SerErr(String),
+	/// The data was written in a version we don't know how to read
+	#[fail(display = "DB Version Error: {}", _0)]
+	VersionErr(String),
 }
 
 impl From<lmdb::error::Error> for Error {
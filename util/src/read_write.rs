@@ -17,7 +17,7 @@
 use std::io;
 use std::io::prelude::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// The default implementation of read_exact is useless with an async stream (TcpStream) as
 /// it will return as soon as something has been read, regardless of
@@ -78,10 +78,11 @@ pub fn read_exact(
 	Ok(())
 }
 
-/// Same as `read_exact` but for writing.
+/// Same as `read_exact` but for writing. The timeout is wall clock time, so
+/// short ones can be relied upon not to hold the caller up for much longer.
 pub fn write_all(stream: &mut dyn Write, mut buf: &[u8], timeout: Duration) -> io::Result<()> {
 	let sleep_time = Duration::from_micros(10);
-	let mut count = Duration::new(0, 0);
+	let deadline = Instant::now() + timeout;
 
 	while !buf.is_empty() {
 		match stream.write(buf) {
@@ -98,11 +99,10 @@ pub fn write_all(stream: &mut dyn Write, mut buf: &[u8], timeout: Duration) -> i
 		}
 		if !buf.is_empty() {
 			thread::sleep(sleep_time);
-			count += sleep_time;
 		} else {
 			break;
 		}
-		if count > timeout {
+		if Instant::now() > deadline {
 			return Err(io::Error::new(io::ErrorKind::TimedOut, "writing to stream"));
 		}
 	}